@@ -0,0 +1,295 @@
+//! misbehavior scores and banned node IP addresses
+
+use bincode::{deserialize, serialize};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+
+/// BANLIST_FILE 网络数据目录中保存封禁列表的文件
+pub const BANLIST_FILE: &str = "banlist.dat";
+/// 封禁列表文件的格式版本，写在文件的第一个字节
+const BANLIST_FILE_VERSION: u8 = 2;
+/// 按 host:port 封禁节点时的文件版本，读取时只保留其中的 IP 地址
+const LEGACY_BANLIST_FILE_VERSION: u8 = 1;
+/// BAN_DURATION 一次封禁的秒数
+pub const BAN_DURATION: u64 = 24 * 60 * 60;
+/// DEFAULT_BAN_SCORE 不当行为分数达到这个值的节点被断开并封禁
+pub const DEFAULT_BAN_SCORE: u32 = 100;
+
+/// Misbehavior 节点的不当行为，越浪费本节点资源、越不可能是无心之失的行为分数越高
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Misbehavior {
+    /// 区块不满足工作量证明或使用了错误的难度
+    InvalidProofOfWork,
+    /// 区块未通过验证，例如含有签名错误的交易
+    InvalidBlock,
+    /// 交易未通过验证，例如签名错误
+    InvalidTransaction,
+    /// 校验和正确但内容无法解析的消息
+    MalformedMessage,
+    /// 未知的命令
+    UnknownMessage,
+}
+
+impl Misbehavior {
+    pub const ALL: [Misbehavior; 5] = [
+        Misbehavior::InvalidProofOfWork,
+        Misbehavior::InvalidBlock,
+        Misbehavior::InvalidTransaction,
+        Misbehavior::MalformedMessage,
+        Misbehavior::UnknownMessage,
+    ];
+
+    /// Name 这类行为在 --misbehavior 参数中的名字
+    pub fn name(self) -> &'static str {
+        match self {
+            Misbehavior::InvalidProofOfWork => "invalid-pow",
+            Misbehavior::InvalidBlock => "invalid-block",
+            Misbehavior::InvalidTransaction => "invalid-tx",
+            Misbehavior::MalformedMessage => "malformed-message",
+            Misbehavior::UnknownMessage => "unknown-message",
+        }
+    }
+
+    /// DefaultScore 这类行为默认增加的分数
+    pub fn default_score(self) -> u32 {
+        match self {
+            Misbehavior::InvalidProofOfWork => 100,
+            Misbehavior::InvalidBlock => 50,
+            Misbehavior::InvalidTransaction => 10,
+            Misbehavior::MalformedMessage => 10,
+            Misbehavior::UnknownMessage => 1,
+        }
+    }
+}
+
+impl FromStr for Misbehavior {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> crate::errors::Result<Self> {
+        Misbehavior::ALL
+            .into_iter()
+            .find(|offense| offense.name() == s)
+            .ok_or_else(|| format_err!("Unknown misbehavior: {}", s))
+    }
+}
+/// BanScores 封禁节点的分数，以及各类不当行为增加的分数
+#[derive(Clone, Debug)]
+pub struct BanScores {
+    /// 不当行为分数达到这个值的节点被断开并封禁
+    pub ban_score: u32,
+    /// 分数不是默认值的行为 -> 它增加的分数
+    pub scores: HashMap<Misbehavior, u32>,
+}
+
+impl Default for BanScores {
+    fn default() -> Self {
+        BanScores {
+            ban_score: DEFAULT_BAN_SCORE,
+            scores: HashMap::new(),
+        }
+    }
+}
+
+impl BanScores {
+    /// Score 行为 offense 增加的分数，没有另外设置时为默认值
+    pub fn score(&self, offense: Misbehavior) -> u32 {
+        self.scores
+            .get(&offense)
+            .copied()
+            .unwrap_or_else(|| offense.default_score())
+    }
+}
+
+/// BanEntry 一个被封禁的 IP 地址和封禁结束的时间，见 mempool::unix_time
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BanEntry {
+    pub addr: IpAddr,
+    pub banned_until: u64,
+}
+
+/// BanList 被封禁的节点 IP 地址，封禁期间不连接这些地址上的节点，也不接受来自它们的连接
+///
+/// 消息中的发送方地址是对方自己声明的，封禁的是连接另一端的 IP 地址
+#[derive(Default)]
+pub struct BanList {
+    /// IP 地址 -> 封禁结束的时间
+    bans: HashMap<IpAddr, u64>,
+}
+
+impl BanList {
+    /// Ban 从 now 开始封禁 addr BAN_DURATION 秒，同时忘记已经到期的封禁
+    pub fn ban(&mut self, addr: IpAddr, now: u64) {
+        self.bans.retain(|_, until| *until > now);
+        self.bans.insert(addr, now + BAN_DURATION);
+    }
+
+    /// Unban 解除对 addr 的封禁，返回它是否被封禁过
+    pub fn unban(&mut self, addr: IpAddr) -> bool {
+        self.bans.remove(&addr).is_some()
+    }
+
+    /// Clear 解除全部封禁，返回解除的地址数
+    pub fn clear(&mut self) -> usize {
+        let count = self.bans.len();
+        self.bans.clear();
+        count
+    }
+
+    pub fn is_banned(&self, addr: IpAddr, now: u64) -> bool {
+        self.bans.get(&addr).is_some_and(|until| *until > now)
+    }
+
+    /// List 返回在 now 时仍然有效的封禁，按地址排列
+    pub fn list(&self, now: u64) -> Vec<BanEntry> {
+        let mut bans: Vec<BanEntry> = self
+            .bans
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(addr, until)| BanEntry {
+                addr: *addr,
+                banned_until: *until,
+            })
+            .collect();
+        bans.sort_by_key(|ban| ban.addr);
+        bans
+    }
+
+    /// Save 把封禁列表写入 path，返回写入的地址数
+    ///
+    /// 文件的第一个字节是格式版本，之后是封禁列表。先写入临时文件再改名，
+    /// 写到一半中断时保留上一次保存的文件
+    pub fn save(&self, path: &Path) -> crate::errors::Result<usize> {
+        let mut bans: Vec<(&IpAddr, &u64)> = self.bans.iter().collect();
+        bans.sort();
+        let mut data = vec![BANLIST_FILE_VERSION];
+        data.extend(serialize(&bans)?);
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(bans.len())
+    }
+
+    /// Load 读取 save 写出的文件，返回读取的地址数；文件不存在时返回 0
+    pub fn load(&mut self, path: &Path) -> crate::errors::Result<usize> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        let corrupt = |e| format_err!("Ban list {} is corrupt: {}", path.display(), e);
+        let bans: Vec<(IpAddr, u64)> = match data.first() {
+            Some(&BANLIST_FILE_VERSION) => deserialize(&data[1..]).map_err(corrupt)?,
+            Some(&LEGACY_BANLIST_FILE_VERSION) => {
+                let bans: Vec<(String, u64)> = deserialize(&data[1..]).map_err(corrupt)?;
+                bans.into_iter()
+                    .filter_map(|(addr, until)| {
+                        Some((addr.parse::<SocketAddr>().ok()?.ip(), until))
+                    })
+                    .collect()
+            }
+            Some(version) => {
+                return Err(format_err!(
+                    "Ban list {} has unknown version {}",
+                    path.display(),
+                    version
+                ));
+            }
+            None => return Err(format_err!("Ban list {} is empty", path.display())),
+        };
+        let count = bans.len();
+        self.bans.extend(bans);
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ban_and_expire() {
+        let mut bans = BanList::default();
+        let now = 1_000_000;
+        let ip = |addr: &str| addr.parse::<IpAddr>().unwrap();
+        bans.ban(ip("10.0.0.1"), now);
+        bans.ban(ip("10.0.0.2"), now + 10);
+        assert!(bans.is_banned(ip("10.0.0.1"), now + BAN_DURATION - 1));
+        assert!(!bans.is_banned(ip("10.0.0.1"), now + BAN_DURATION));
+        assert!(!bans.is_banned(ip("10.0.0.3"), now));
+        assert_eq!(
+            bans.list(now + BAN_DURATION),
+            vec![BanEntry {
+                addr: ip("10.0.0.2"),
+                banned_until: now + 10 + BAN_DURATION,
+            }]
+        );
+
+        // 到期的封禁在下一次封禁时被忘记
+        bans.ban(ip("2001:db8::3"), now + BAN_DURATION);
+        assert!(!bans.unban(ip("10.0.0.1")));
+        assert!(bans.unban(ip("10.0.0.2")));
+        assert!(!bans.is_banned(ip("10.0.0.2"), now));
+        assert_eq!(bans.clear(), 1);
+        assert!(bans.list(now).is_empty());
+    }
+
+    #[test]
+    fn test_save_load() {
+        let mut bans = BanList::default();
+        bans.ban("10.0.0.1".parse().unwrap(), 100);
+        bans.ban("2001:db8::2".parse().unwrap(), 200);
+        let path = std::env::temp_dir().join(format!("rustchain-banlist-{}", std::process::id()));
+        assert_eq!(bans.save(&path).unwrap(), 2);
+
+        let mut loaded = BanList::default();
+        assert_eq!(loaded.load(&path).unwrap(), 2);
+        assert_eq!(loaded.list(0), bans.list(0));
+
+        // 旧版本的文件中按 host:port 封禁，只保留其中的 IP 地址
+        let legacy = vec![
+            ("10.0.0.1:3000".to_string(), 100u64),
+            ("node2:3000".to_string(), 200),
+        ];
+        let mut data = vec![LEGACY_BANLIST_FILE_VERSION];
+        data.extend(serialize(&legacy).unwrap());
+        std::fs::write(&path, &data).unwrap();
+        let mut loaded = BanList::default();
+        assert_eq!(loaded.load(&path).unwrap(), 1);
+        assert!(loaded.is_banned("10.0.0.1".parse().unwrap(), 0));
+
+        data[0] = BANLIST_FILE_VERSION + 1;
+        std::fs::write(&path, data).unwrap();
+        assert!(BanList::default().load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(BanList::default().load(&path).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_misbehavior_names() {
+        for offense in Misbehavior::ALL {
+            assert_eq!(offense.name().parse::<Misbehavior>().unwrap(), offense);
+        }
+        assert!("lying".parse::<Misbehavior>().is_err());
+        // 工作量证明无效比未知的命令严重得多
+        assert!(
+            Misbehavior::InvalidProofOfWork.default_score()
+                > Misbehavior::UnknownMessage.default_score()
+        );
+        assert_eq!(
+            Misbehavior::InvalidProofOfWork.default_score(),
+            DEFAULT_BAN_SCORE
+        );
+        // 只有另外设置的行为改变分数
+        let mut bans = BanScores::default();
+        bans.scores.insert(Misbehavior::UnknownMessage, 7);
+        assert_eq!(bans.score(Misbehavior::UnknownMessage), 7);
+        assert_eq!(
+            bans.score(Misbehavior::InvalidBlock),
+            Misbehavior::InvalidBlock.default_score()
+        );
+    }
+}
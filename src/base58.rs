@@ -0,0 +1,112 @@
+//! base58check encoding
+
+use super::*;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use failure::format_err;
+
+/// Base58 字母表，按数值从小到大排列
+pub const ALPHABET: &str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const CHECKSUM_LEN: usize = 4;
+
+/// Encode 将数据编码为 Base58，开头的每个零字节编码为一个 '1'
+pub fn encode(data: &[u8]) -> String {
+    let alphabet = ALPHABET.as_bytes();
+    // 小端存放的 58 进制数字
+    let mut digits: Vec<u8> = Vec::new();
+    for byte in data {
+        let mut carry = u32::from(*byte);
+        for digit in digits.iter_mut() {
+            carry += u32::from(*digit) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let zeros = data.iter().take_while(|b| **b == 0).count();
+    std::iter::repeat_n('1', zeros)
+        .chain(digits.iter().rev().map(|d| alphabet[*d as usize] as char))
+        .collect()
+}
+
+/// Decode 解码 Base58 字符串
+pub fn decode(s: &str) -> Result<Vec<u8>> {
+    // 小端存放的字节
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in s.chars() {
+        let mut carry = ALPHABET
+            .find(c)
+            .ok_or_else(|| format_err!("invalid base58 character '{}'", c))?
+            as u32;
+        for byte in bytes.iter_mut() {
+            carry += u32::from(*byte) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = s.chars().take_while(|c| *c == '1').count();
+    Ok(std::iter::repeat_n(0, zeros)
+        .chain(bytes.into_iter().rev())
+        .collect())
+}
+
+/// EncodeCheck 在数据后附加 4 字节双 SHA-256 校验和后编码
+pub fn encode_check(data: &[u8]) -> String {
+    let mut payload = data.to_vec();
+    payload.extend_from_slice(&checksum(data));
+    encode(&payload)
+}
+
+/// DecodeCheck 解码并校验 encode_check 生成的字符串，返回去掉校验和的数据
+pub fn decode_check(s: &str) -> Result<Vec<u8>> {
+    let mut payload = decode(s)?;
+    if payload.len() < CHECKSUM_LEN {
+        return Err(format_err!("base58check string too short"));
+    }
+    let expected = payload.split_off(payload.len() - CHECKSUM_LEN);
+    if checksum(&payload) != expected {
+        return Err(format_err!("invalid base58check checksum"));
+    }
+    Ok(payload)
+}
+
+/// Checksum 返回 data 两次 SHA-256 哈希的前 CHECKSUM_LEN 个字节
+pub fn checksum(data: &[u8]) -> Vec<u8> {
+    let mut digest = [0u8; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    hasher.result(&mut digest);
+    let mut hasher = Sha256::new();
+    hasher.input(&digest);
+    hasher.result(&mut digest);
+    digest[..CHECKSUM_LEN].to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_base58() {
+        assert_eq!(encode(b""), "");
+        assert_eq!(encode(b"hello world"), "StV1DL6CwTryKyV");
+        assert_eq!(encode(&[0, 0, 1]), "112");
+        assert_eq!(decode("StV1DL6CwTryKyV").unwrap(), b"hello world");
+        assert_eq!(decode("112").unwrap(), [0, 0, 1]);
+        assert!(decode("0OIl").is_err());
+
+        // 比特币创世区块奖励地址
+        let address = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+        let payload = decode_check(address).unwrap();
+        assert_eq!(payload.len(), 21);
+        assert_eq!(encode_check(&payload), address);
+        assert!(decode_check("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNb").is_err());
+    }
+}
@@ -0,0 +1,163 @@
+//! bech32 encoding (BIP-173)
+
+use super::*;
+use failure::format_err;
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+const CHECKSUM_LEN: usize = 6;
+/// bech32 字符串的最大长度
+const MAX_LEN: usize = 90;
+
+/// Encode 将 hrp 和数据编码为小写 bech32 字符串
+pub fn encode(hrp: &str, data: &[u8]) -> String {
+    let mut values = convert_bits(data, 8, 5, true).unwrap_or_default();
+    let mut checked = hrp_expand(hrp);
+    checked.extend_from_slice(&values);
+    checked.extend_from_slice(&[0; CHECKSUM_LEN]);
+    let checksum = polymod(&checked) ^ 1;
+    for i in 0..CHECKSUM_LEN {
+        values.push(((checksum >> (5 * (CHECKSUM_LEN - 1 - i))) & 31) as u8);
+    }
+
+    let mut encoded = String::from(hrp);
+    encoded.push('1');
+    encoded.extend(values.iter().map(|v| CHARSET[*v as usize] as char));
+    encoded
+}
+
+/// Decode 解码 bech32 字符串，返回小写的 hrp 和数据
+///
+/// 拒绝大小写混用、非法字符、校验和错误和非零的填充位
+pub fn decode(s: &str) -> Result<(String, Vec<u8>)> {
+    if s.len() < CHECKSUM_LEN + 2 || s.len() > MAX_LEN {
+        return Err(format_err!("invalid bech32 length {}", s.len()));
+    }
+    if s.bytes().any(|b| b.is_ascii_lowercase()) && s.bytes().any(|b| b.is_ascii_uppercase()) {
+        return Err(format_err!("mixed-case bech32 string"));
+    }
+    let s = s.to_ascii_lowercase();
+    let sep = match s.rfind('1') {
+        Some(sep) if sep >= 1 && sep + CHECKSUM_LEN < s.len() => sep,
+        _ => return Err(format_err!("missing bech32 separator")),
+    };
+    let (hrp, data) = (&s[..sep], &s[sep + 1..]);
+    if !hrp.bytes().all(|b| (33..=126).contains(&b)) {
+        return Err(format_err!("invalid bech32 prefix"));
+    }
+
+    let mut values = Vec::new();
+    for c in data.bytes() {
+        let value = CHARSET
+            .iter()
+            .position(|x| *x == c)
+            .ok_or_else(|| format_err!("invalid bech32 character '{}'", c as char))?;
+        values.push(value as u8);
+    }
+    let mut checked = hrp_expand(hrp);
+    checked.extend_from_slice(&values);
+    if polymod(&checked) != 1 {
+        return Err(format_err!("invalid bech32 checksum"));
+    }
+
+    values.truncate(values.len() - CHECKSUM_LEN);
+    let data = convert_bits(&values, 5, 8, false)?;
+    Ok((hrp.to_string(), data))
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    let mut chk: u32 = 1;
+    for v in values {
+        let top = chk >> 25;
+        chk = ((chk & 0x1ffffff) << 5) ^ u32::from(*v);
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= generator;
+            }
+        }
+    }
+    chk
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut expanded: Vec<u8> = hrp.bytes().map(|b| b >> 5).collect();
+    expanded.push(0);
+    expanded.extend(hrp.bytes().map(|b| b & 31));
+    expanded
+}
+
+/// ConvertBits 在 from 位和 to 位分组之间转换，pad 为 false 时拒绝不完整或非零的填充位
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Result<Vec<u8>> {
+    let mut acc: u32 = 0;
+    let mut bits: u32 = 0;
+    let max_value = (1 << to) - 1;
+    let max_acc = (1 << (from + to - 1)) - 1;
+    let mut converted = Vec::new();
+    for value in data {
+        let value = u32::from(*value);
+        if value >> from != 0 {
+            return Err(format_err!("invalid bech32 data value {}", value));
+        }
+        acc = ((acc << from) | value) & max_acc;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            converted.push(((acc >> bits) & max_value) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            converted.push(((acc << (to - bits)) & max_value) as u8);
+        }
+    } else if bits >= from || ((acc << (to - bits)) & max_value) != 0 {
+        return Err(format_err!("invalid bech32 padding"));
+    }
+    Ok(converted)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bip173_vectors() {
+        // BIP-173 中的合法字符串
+        for valid in [
+            "A12UEL5L",
+            "a12uel5l",
+            "an83characterlonghumanreadablepartthatcontainsthenumber1andtheexcludedcharactersbio1tt5tgs",
+            "abcdef1qpzry9x8gf2tvdw0s3jn54khce6mua7lmqqqxw",
+            "split1checkupstagehandshakeupstreamerranterredcaperred2y9e3w",
+        ] {
+            let (hrp, _) = decode(valid).unwrap();
+            assert_eq!(hrp, valid[..valid.rfind('1').unwrap()].to_ascii_lowercase());
+        }
+
+        for invalid in [
+            "pzry9x0s0muk",
+            "1pzry9x0s0muk",
+            "x1b4n0q5v",
+            "li1dgmt3",
+            "A1G7SGD8",
+            "a12UEL5L",
+        ] {
+            assert!(decode(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data: Vec<u8> = (0..20).collect();
+        let encoded = encode("rtc", &data);
+        assert!(encoded.starts_with("rtc1"));
+        assert_eq!(
+            decode(&encoded).unwrap(),
+            (String::from("rtc"), data.clone())
+        );
+        assert_eq!(
+            decode(&encoded.to_ascii_uppercase()).unwrap(),
+            (String::from("rtc"), data)
+        );
+        assert_eq!(decode(&encode("trtc", &[])).unwrap().1, Vec::<u8>::new());
+    }
+}
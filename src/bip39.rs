@@ -0,0 +1,175 @@
+//! bip39 mnemonic
+
+use super::*;
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::pbkdf2::pbkdf2;
+use crypto::sha2::{Sha256, Sha512};
+use failure::format_err;
+
+/// BIP-39 英文词表，共 2048 个单词
+const WORDLIST: &str = include_str!("bip39_english.txt");
+const WORD_COUNT: usize = 2048;
+/// 每个单词编码 11 位
+const BITS_PER_WORD: usize = 11;
+const PBKDF2_ROUNDS: u32 = 2048;
+const SEED_LEN: usize = 64;
+
+fn words() -> Vec<&'static str> {
+    let words: Vec<&str> = WORDLIST.lines().collect();
+    debug_assert_eq!(words.len(), WORD_COUNT);
+    words
+}
+
+/// EntropyToMnemonic 将熵编码为助记词，熵长度必须为 16 到 32 字节且是 4 的倍数
+pub fn entropy_to_mnemonic(entropy: &[u8]) -> Result<String> {
+    if !(16..=32).contains(&entropy.len()) || !entropy.len().is_multiple_of(4) {
+        return Err(format_err!(
+            "Invalid mnemonic entropy length {}",
+            entropy.len()
+        ));
+    }
+    let words = words();
+    let mut bits = to_bits(entropy);
+    bits.extend(
+        to_bits(&checksum(entropy))
+            .into_iter()
+            .take(entropy.len() / 4),
+    );
+    let phrase: Vec<&str> = bits
+        .chunks(BITS_PER_WORD)
+        .map(|chunk| words[chunk.iter().fold(0, |acc, bit| (acc << 1) | *bit as usize)])
+        .collect();
+    Ok(phrase.join(" "))
+}
+
+/// MnemonicToEntropy 解码助记词并校验其校验和，返回熵
+///
+/// 单词不在词表中和校验和错误分别返回不同的错误
+pub fn mnemonic_to_entropy(phrase: &str) -> Result<Vec<u8>> {
+    let phrase: Vec<String> = phrase
+        .split_whitespace()
+        .map(|word| word.to_ascii_lowercase())
+        .collect();
+    if !matches!(phrase.len(), 12 | 15 | 18 | 21 | 24) {
+        return Err(format_err!(
+            "Invalid mnemonic: expected 12, 15, 18, 21 or 24 words, got {}",
+            phrase.len()
+        ));
+    }
+
+    let words = words();
+    let mut bits = Vec::with_capacity(phrase.len() * BITS_PER_WORD);
+    for (position, word) in phrase.iter().enumerate() {
+        let index = words.binary_search(&word.as_str()).map_err(|_| {
+            format_err!(
+                "Invalid mnemonic: unknown word '{}' at position {}",
+                word,
+                position + 1
+            )
+        })?;
+        bits.extend((0..BITS_PER_WORD).rev().map(|i| ((index >> i) & 1) as u8));
+    }
+
+    let checksum_bits = bits.len() / 33;
+    let (entropy_bits, checksum_part) = bits.split_at(bits.len() - checksum_bits);
+    let entropy: Vec<u8> = entropy_bits
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0, |acc, bit| (acc << 1) | bit))
+        .collect();
+    if to_bits(&checksum(&entropy))[..checksum_bits] != *checksum_part {
+        return Err(format_err!("Invalid mnemonic: checksum mismatch"));
+    }
+    Ok(entropy)
+}
+
+/// MnemonicToSeed 由助记词和口令计算 64 字节种子
+///
+/// 口令按原样使用，没有做 NFKD 规范化，非 ASCII 口令可能与其他钱包不兼容
+pub fn mnemonic_to_seed(phrase: &str, passphrase: &str) -> Vec<u8> {
+    let phrase: Vec<&str> = phrase.split_whitespace().collect();
+    let phrase = phrase.join(" ").to_ascii_lowercase();
+    let mut mac = Hmac::new(Sha512::new(), phrase.as_bytes());
+    let salt = format!("mnemonic{}", passphrase);
+    let mut seed = vec![0u8; SEED_LEN];
+    pbkdf2(&mut mac, salt.as_bytes(), PBKDF2_ROUNDS, &mut seed);
+    seed
+}
+
+fn checksum(entropy: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(entropy);
+    let mut digest = vec![0u8; 32];
+    hasher.result(&mut digest);
+    digest
+}
+
+fn to_bits(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .iter()
+        .flat_map(|byte| (0..8).rev().map(move |i| (byte >> i) & 1))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bip39_vectors() {
+        // BIP-39 测试向量，口令为 TREZOR
+        let phrase = entropy_to_mnemonic(&[0; 16]).unwrap();
+        assert_eq!(
+            phrase,
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
+        );
+        assert_eq!(
+            hex::encode(mnemonic_to_seed(&phrase, "TREZOR")),
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04"
+        );
+
+        for (entropy, expected) in [
+            (
+                [0x00; 32],
+                "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon art",
+            ),
+            (
+                [0x7f; 32],
+                "legal winner thank year wave sausage worth useful legal winner thank year wave sausage worth useful legal winner thank year wave sausage worth title",
+            ),
+            (
+                [0x80; 32],
+                "letter advice cage absurd amount doctor acoustic avoid letter advice cage absurd amount doctor acoustic avoid letter advice cage absurd amount doctor acoustic bless",
+            ),
+        ] {
+            assert_eq!(entropy_to_mnemonic(&entropy).unwrap(), expected);
+            assert_eq!(mnemonic_to_entropy(expected).unwrap(), entropy);
+        }
+        assert!(entropy_to_mnemonic(&[0; 17]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_mnemonic() {
+        let phrase = entropy_to_mnemonic(&[0x7f; 32]).unwrap();
+        let mut words: Vec<&str> = phrase.split(' ').collect();
+
+        // 交换两个单词后校验和不再匹配
+        words.swap(0, 1);
+        let err = mnemonic_to_entropy(&words.join(" ")).unwrap_err();
+        assert!(err.to_string().contains("checksum"), "{}", err);
+
+        words[3] = "satoshis";
+        let err = mnemonic_to_entropy(&words.join(" ")).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("unknown word 'satoshis' at position 4")
+        );
+
+        assert!(mnemonic_to_entropy(&words[..23].join(" ")).is_err());
+        // 大小写和多余空白不影响解码
+        assert_eq!(
+            mnemonic_to_entropy(&format!("  {}  ", phrase.to_ascii_uppercase())).unwrap(),
+            [0x7f; 32]
+        );
+    }
+}
@@ -1,36 +1,491 @@
 //! Block
 
 use super::*;
-use crate::transaction::Transaction;
-use bincode::serialize;
+use crate::merkle::merkle_root;
+#[cfg(test)]
+use crate::network::Network;
+use crate::transaction::{LegacyTransaction, Transaction};
+use bincode::{serialize, serialized_size, DefaultOptions, Options};
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use merkle_cbt::merkle_tree::Merge;
 use merkle_cbt::merkle_tree::CBMT;
+use failure::format_err;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
 use std::time::SystemTime;
 use log::info;
 
+/// 记录难度之前的区块要求哈希以这么多个十六进制 0 开头
 const TARGET_HEXS: usize = 4;
+/// INITIAL_BITS 创世区块的难度，与哈希以 TARGET_HEXS 个十六进制 0 开头大致相同
+pub const INITIAL_BITS: u32 = 0x1f00_ffff;
+/// POW_LIMIT_BITS 难度调整的下限，目标值不超过哈希以 2 个十六进制 0 开头
+pub const POW_LIMIT_BITS: u32 = 0x2000_ffff;
+/// MEDIAN_TIME_SPAN 区块的时间戳必须晚于此前这么多个区块时间戳的中位数
+pub const MEDIAN_TIME_SPAN: usize = 11;
+/// MAX_FUTURE_BLOCK_TIME 区块的时间戳最多比本地时钟超前这么多毫秒
+pub const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
+/// MAX_BLOCK_SIZE 区块序列化后的最大字节数
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+/// MAX_BLOCK_TXS 区块最多包含的交易数，包括创币交易
+pub const MAX_BLOCK_TXS: usize = 2_000;
+/// REGTEST_BITS 回归测试网的固定难度，大约一半的哈希都满足
+pub const REGTEST_BITS: u32 = 0x207f_ffff;
+/// RETARGET_INTERVAL 每隔这么多个区块调整一次难度
+pub const RETARGET_INTERVAL: i32 = 20;
+/// TARGET_BLOCK_TIME 期望的出块间隔（毫秒）
+pub const TARGET_BLOCK_TIME: u128 = 10_000;
+/// BLOCK_VERSION 区块头的版本
+pub const BLOCK_VERSION: u32 = 1;
 
-/// Block 保存区块头信息
+/// DefaultMiningThreads 返回默认的挖矿线程数，即可用的 CPU 数
+pub fn default_mining_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// BlockHeader 区块头，工作量证明只覆盖区块头，不需要交易即可验证
+///
+/// 交易通过默克尔根与区块头绑定；高度不参与哈希，由父区块推出
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    /// 区块格式的版本，目前只有 BLOCK_VERSION，不参与哈希
+    pub version: u32,
+    pub prev_hash: String,
+    /// 交易 id 的默克尔根，见 merkle::merkle_root；记录默克尔根之前创建的区块为空
+    pub merkle_root: String,
+    pub timestamp: u128,
+    /// 紧凑格式的难度，见 target_from_bits；记录难度之前创建的区块为 0
+    pub bits: u32,
+    pub nonce: i32,
+    pub height: i32,
+    /// 区块所基于的 UTXO 集合的承诺哈希，见 UTXOSet::commitment
+    #[cfg(feature = "utxo-commitment")]
+    pub utxo_commitment: Option<[u8; 32]>,
+}
+
+/// Block 区块头及其交易
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
+    header: BlockHeader,
+    hash: String,
+    transactions: Vec<Transaction>,
+}
+
+/// UncommittedHeader 不含 UTXO 承诺的区块头格式
+#[cfg(feature = "utxo-commitment")]
+#[derive(Deserialize)]
+struct UncommittedHeader {
+    version: u32,
+    prev_hash: String,
+    merkle_root: String,
+    timestamp: u128,
+    bits: u32,
+    nonce: i32,
+    height: i32,
+}
+
+/// UncommittedBlock 区块头不含 UTXO 承诺的区块格式
+#[cfg(feature = "utxo-commitment")]
+#[derive(Deserialize)]
+struct UncommittedBlock {
+    header: UncommittedHeader,
+    hash: String,
+    transactions: Vec<Transaction>,
+}
+
+#[cfg(feature = "utxo-commitment")]
+impl From<UncommittedBlock> for Block {
+    fn from(block: UncommittedBlock) -> Self {
+        let header = block.header;
+        Block {
+            header: BlockHeader {
+                version: header.version,
+                prev_hash: header.prev_hash,
+                merkle_root: header.merkle_root,
+                timestamp: header.timestamp,
+                bits: header.bits,
+                nonce: header.nonce,
+                height: header.height,
+                utxo_commitment: None,
+            },
+            hash: block.hash,
+            transactions: block.transactions,
+        }
+    }
+}
+
+/// PreHeaderBlock 区块头独立成类型之前的区块格式
+#[derive(Deserialize)]
+struct PreHeaderBlock {
+    timestamp: u128,
+    transactions: Vec<Transaction>,
+    prev_block_hash: String,
+    hash: String,
+    nonce: i32,
+    height: i32,
+    merkle_root: String,
+    bits: u32,
+    #[cfg(feature = "utxo-commitment")]
+    utxo_commitment: Option<[u8; 32]>,
+}
+
+impl From<PreHeaderBlock> for Block {
+    fn from(block: PreHeaderBlock) -> Self {
+        Block {
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                prev_hash: block.prev_block_hash,
+                merkle_root: block.merkle_root,
+                timestamp: block.timestamp,
+                bits: block.bits,
+                nonce: block.nonce,
+                height: block.height,
+                #[cfg(feature = "utxo-commitment")]
+                utxo_commitment: block.utxo_commitment,
+            },
+            hash: block.hash,
+            transactions: block.transactions,
+        }
+    }
+}
+
+/// PreHeaderUncommittedBlock 区块头独立成类型之前、不含 UTXO 承诺的区块格式
+#[cfg(feature = "utxo-commitment")]
+#[derive(Deserialize)]
+struct PreHeaderUncommittedBlock {
     timestamp: u128,
     transactions: Vec<Transaction>,
     prev_block_hash: String,
     hash: String,
     nonce: i32,
     height: i32,
+    merkle_root: String,
+    bits: u32,
+}
+
+#[cfg(feature = "utxo-commitment")]
+impl From<PreHeaderUncommittedBlock> for Block {
+    fn from(block: PreHeaderUncommittedBlock) -> Self {
+        PreHeaderBlock {
+            timestamp: block.timestamp,
+            transactions: block.transactions,
+            prev_block_hash: block.prev_block_hash,
+            hash: block.hash,
+            nonce: block.nonce,
+            height: block.height,
+            merkle_root: block.merkle_root,
+            bits: block.bits,
+            utxo_commitment: None,
+        }
+        .into()
+    }
+}
+
+/// PreBitsBlock 记录难度之前的区块格式
+#[derive(Deserialize)]
+struct PreBitsBlock {
+    timestamp: u128,
+    transactions: Vec<Transaction>,
+    prev_block_hash: String,
+    hash: String,
+    nonce: i32,
+    height: i32,
+    merkle_root: String,
+}
+
+impl From<PreBitsBlock> for Block {
+    fn from(block: PreBitsBlock) -> Self {
+        PreHeaderBlock {
+            timestamp: block.timestamp,
+            transactions: block.transactions,
+            prev_block_hash: block.prev_block_hash,
+            hash: block.hash,
+            nonce: block.nonce,
+            height: block.height,
+            merkle_root: block.merkle_root,
+            bits: 0,
+            #[cfg(feature = "utxo-commitment")]
+            utxo_commitment: None,
+        }
+        .into()
+    }
+}
+
+/// PreBitsCommittedBlock 记录难度之前、含 UTXO 承诺的区块格式
+#[cfg(feature = "utxo-commitment")]
+#[derive(Deserialize)]
+struct PreBitsCommittedBlock {
+    timestamp: u128,
+    transactions: Vec<Transaction>,
+    prev_block_hash: String,
+    hash: String,
+    nonce: i32,
+    height: i32,
+    merkle_root: String,
+    utxo_commitment: Option<[u8; 32]>,
+}
+
+#[cfg(feature = "utxo-commitment")]
+impl From<PreBitsCommittedBlock> for Block {
+    fn from(block: PreBitsCommittedBlock) -> Self {
+        PreHeaderBlock {
+            timestamp: block.timestamp,
+            transactions: block.transactions,
+            prev_block_hash: block.prev_block_hash,
+            hash: block.hash,
+            nonce: block.nonce,
+            height: block.height,
+            merkle_root: block.merkle_root,
+            bits: 0,
+            utxo_commitment: block.utxo_commitment,
+        }
+        .into()
+    }
+}
+
+/// PreMerkleBlock 记录默克尔根之前的区块格式
+#[derive(Deserialize)]
+struct PreMerkleBlock {
+    timestamp: u128,
+    transactions: Vec<Transaction>,
+    prev_block_hash: String,
+    hash: String,
+    nonce: i32,
+    height: i32,
+}
+
+impl From<PreMerkleBlock> for Block {
+    fn from(block: PreMerkleBlock) -> Self {
+        PreHeaderBlock {
+            timestamp: block.timestamp,
+            transactions: block.transactions,
+            prev_block_hash: block.prev_block_hash,
+            hash: block.hash,
+            nonce: block.nonce,
+            height: block.height,
+            merkle_root: String::new(),
+            bits: 0,
+            #[cfg(feature = "utxo-commitment")]
+            utxo_commitment: None,
+        }
+        .into()
+    }
+}
+
+/// PreMerkleCommittedBlock 记录默克尔根之前、含 UTXO 承诺的区块格式
+#[cfg(feature = "utxo-commitment")]
+#[derive(Deserialize)]
+struct PreMerkleCommittedBlock {
+    timestamp: u128,
+    transactions: Vec<Transaction>,
+    prev_block_hash: String,
+    hash: String,
+    nonce: i32,
+    height: i32,
+    utxo_commitment: Option<[u8; 32]>,
+}
+
+#[cfg(feature = "utxo-commitment")]
+impl From<PreMerkleCommittedBlock> for Block {
+    fn from(block: PreMerkleCommittedBlock) -> Self {
+        PreHeaderBlock {
+            timestamp: block.timestamp,
+            transactions: block.transactions,
+            prev_block_hash: block.prev_block_hash,
+            hash: block.hash,
+            nonce: block.nonce,
+            height: block.height,
+            merkle_root: String::new(),
+            bits: 0,
+            utxo_commitment: block.utxo_commitment,
+        }
+        .into()
+    }
+}
+
+/// LegacyBlock 交易版本号引入之前的区块格式
+#[derive(Deserialize)]
+struct LegacyBlock {
+    timestamp: u128,
+    transactions: Vec<LegacyTransaction>,
+    prev_block_hash: String,
+    hash: String,
+    nonce: i32,
+    height: i32,
+}
+
+impl TryFrom<LegacyBlock> for Block {
+    type Error = failure::Error;
+
+    fn try_from(block: LegacyBlock) -> Result<Self> {
+        let mut transactions = Vec::new();
+        for tx in block.transactions {
+            transactions.push(Transaction::try_from(tx)?);
+        }
+
+        Ok(PreHeaderBlock {
+            timestamp: block.timestamp,
+            transactions,
+            prev_block_hash: block.prev_block_hash,
+            hash: block.hash,
+            nonce: block.nonce,
+            height: block.height,
+            merkle_root: String::new(),
+            bits: 0,
+            #[cfg(feature = "utxo-commitment")]
+            utxo_commitment: None,
+        }
+        .into())
+    }
+}
+
+impl BlockHeader {
+    /// Hash 返回区块头的哈希，即区块哈希；记录默克尔根之前的区块头需要交易才能算出哈希
+    pub fn hash(&self) -> Result<String> {
+        if self.merkle_root.is_empty() {
+            return Err(format_err!(
+                "Header at height {} has no merkle root, its hash needs the transactions",
+                self.height
+            ));
+        }
+        Ok(sha256_hex(
+            &self.hash_data(hex::decode(&self.merkle_root)?)?,
+        ))
+    }
+
+    /// GetBits 返回区块的难度，记录难度之前创建的区块为 INITIAL_BITS
+    pub fn get_bits(&self) -> u32 {
+        if self.bits == 0 {
+            INITIAL_BITS
+        } else {
+            self.bits
+        }
+    }
+
+    /// RecordsBits 区块头是否记录了难度，记录难度之前创建的区块使用固定难度
+    pub fn records_bits(&self) -> bool {
+        self.bits != 0
+    }
+
+    /// CheckProofOfWork 检查区块头的版本和工作量证明，返回区块头的哈希
+    pub fn check_proof_of_work(&self) -> Result<String> {
+        let hash = self.hash()?;
+        self.check_version(&hash)?;
+        if !self.meets_target(&hex::decode(&self.merkle_root)?)? {
+            return Err(format_err!(
+                "Header {} does not meet its difficulty {:08x}",
+                hash,
+                self.get_bits()
+            ));
+        }
+        Ok(hash)
+    }
+
+    fn check_version(&self, hash: &str) -> Result<()> {
+        if self.version != BLOCK_VERSION {
+            return Err(format_err!(
+                "Block {} has unsupported version {}",
+                hash,
+                self.version
+            ));
+        }
+        Ok(())
+    }
+
+    /// 区块头的编码，依次为父区块哈希、交易部分、时间戳、难度和 nonce
+    ///
+    /// 交易部分为默克尔根，旧区块为 hash_transactions 的结果；
+    /// 难度为 bits，记录难度之前的区块为 TARGET_HEXS
+    fn hash_data(&self, transactions_hash: Vec<u8>) -> Result<Vec<u8>> {
+        // bincode 编码元组即依次编码各字段
+        let mut bytes = serialize(&(&self.prev_hash, transactions_hash, self.timestamp))?;
+        if self.records_bits() {
+            bytes.extend(serialize(&self.bits)?);
+        } else {
+            bytes.extend(serialize(&TARGET_HEXS)?);
+        }
+        bytes.extend(serialize(&self.nonce)?);
+        #[cfg(feature = "utxo-commitment")]
+        bytes.extend(serialize(&self.utxo_commitment)?);
+        Ok(bytes)
+    }
+
+    /// 检查区块头的哈希不大于难度对应的目标值，记录难度之前的区块要求以 TARGET_HEXS 个 0 开头
+    fn meets_target(&self, transactions_hash: &[u8]) -> Result<bool> {
+        let data = self.hash_data(transactions_hash.to_vec())?;
+        let mut hasher = Sha256::new();
+        hasher.input(&data[..]);
+        if !self.records_bits() {
+            let mut vec1: Vec<u8> = Vec::new();
+            vec1.resize(TARGET_HEXS, b'0');
+            return Ok(hasher.result_str()[0..TARGET_HEXS] == String::from_utf8(vec1)?);
+        }
+        let mut hash = [0u8; 32];
+        hasher.result(&mut hash);
+        Ok(hash <= target_from_bits(self.bits))
+    }
 }
 
 impl Block {
+    /// Decode 反序列化数据库中的区块，兼容旧格式的区块
+    ///
+    /// bincode 不记录字段信息，无法对缺失字段使用默认值，
+    /// 因此先按当前格式严格解码，失败后再按旧格式解码
+    pub fn decode(bytes: &[u8]) -> Result<Block> {
+        let options = DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes();
+        match options.deserialize::<Block>(bytes) {
+            Ok(block) => Ok(block),
+            Err(err) => {
+                #[cfg(feature = "utxo-commitment")]
+                if let Ok(block) = options.deserialize::<UncommittedBlock>(bytes) {
+                    return Ok(block.into());
+                }
+                if let Ok(block) = options.deserialize::<PreHeaderBlock>(bytes) {
+                    return Ok(block.into());
+                }
+                #[cfg(feature = "utxo-commitment")]
+                if let Ok(block) = options.deserialize::<PreHeaderUncommittedBlock>(bytes) {
+                    return Ok(block.into());
+                }
+                #[cfg(feature = "utxo-commitment")]
+                if let Ok(block) = options.deserialize::<PreBitsCommittedBlock>(bytes) {
+                    return Ok(block.into());
+                }
+                if let Ok(block) = options.deserialize::<PreBitsBlock>(bytes) {
+                    return Ok(block.into());
+                }
+                #[cfg(feature = "utxo-commitment")]
+                if let Ok(block) = options.deserialize::<PreMerkleCommittedBlock>(bytes) {
+                    return Ok(block.into());
+                }
+                if let Ok(block) = options.deserialize::<PreMerkleBlock>(bytes) {
+                    return Ok(block.into());
+                }
+                match options.deserialize::<LegacyBlock>(bytes) {
+                    Ok(block) => Block::try_from(block),
+                    Err(_) => Err(err.into()),
+                }
+            }
+        }
+    }
+
     pub fn get_hash(&self) -> String {
         self.hash.clone()
     }
 
+    /// Header 返回区块头
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
+    }
+
     pub fn get_prev_hash(&self) -> String {
-        self.prev_block_hash.clone()
+        self.header.prev_hash.clone()
     }
 
     pub fn get_transaction(&self) -> &Vec<Transaction> {
@@ -38,50 +493,318 @@ impl Block {
     }
 
     pub fn get_height(&self) -> i32 {
-        self.height
+        self.header.height
+    }
+
+    pub fn get_timestamp(&self) -> u128 {
+        self.header.timestamp
+    }
+
+    pub fn get_nonce(&self) -> i32 {
+        self.header.nonce
+    }
+
+    /// GetMerkleRoot 返回区块记录的默克尔根，记录默克尔根之前创建的区块返回空字符串
+    pub fn get_merkle_root(&self) -> String {
+        self.header.merkle_root.clone()
     }
 
-    /// NewBlock 创建并返回区块
+    /// TransactionIds 按区块中的顺序返回交易 id
+    pub fn transaction_ids(&self) -> Vec<&str> {
+        self.transactions.iter().map(|tx| tx.id.as_str()).collect()
+    }
 
+    /// Size 返回区块序列化后的字节数
+    pub fn size(&self) -> Result<usize> {
+        Ok(serialized_size(self)? as usize)
+    }
+
+    /// BaseSize 返回只包含创币交易 coinbase 的区块序列化后的最大字节数
+    ///
+    /// 区块每多一笔交易，序列化后的大小增加该交易序列化后的字节数
+    pub fn base_size(coinbase: &Transaction) -> Result<usize> {
+        let hash = "0".repeat(64);
+        let block = Block {
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                prev_hash: hash.clone(),
+                merkle_root: hash.clone(),
+                timestamp: 0,
+                bits: 0,
+                nonce: 0,
+                height: 0,
+                #[cfg(feature = "utxo-commitment")]
+                utxo_commitment: Some([0; 32]),
+            },
+            hash,
+            transactions: vec![coinbase.clone()],
+        };
+        block.size()
+    }
+
+    /// CheckSize 检查区块的交易数不超过 MAX_BLOCK_TXS，序列化后不超过 MAX_BLOCK_SIZE 字节
+    pub fn check_size(&self) -> Result<()> {
+        if self.transactions.len() > MAX_BLOCK_TXS {
+            return Err(format_err!(
+                "Block {} has {} transactions, more than the limit {}",
+                self.hash,
+                self.transactions.len(),
+                MAX_BLOCK_TXS
+            ));
+        }
+        let size = self.size()?;
+        if size > MAX_BLOCK_SIZE {
+            return Err(format_err!(
+                "Block {} is {} bytes, larger than the limit {}",
+                self.hash,
+                size,
+                MAX_BLOCK_SIZE
+            ));
+        }
+        Ok(())
+    }
+
+    /// VerifyMerkleRoot 检查区块记录的默克尔根与交易一致，并拒绝重复的交易 id
+    ///
+    /// 默克尔树复制奇数层的最后一个节点，重复最后几笔交易得到的默克尔根不变，
+    /// 不检查重复时同一区块头可以对应不同的交易列表
+    pub fn verify_merkle_root(&self) -> Result<()> {
+        let txids = self.transaction_ids();
+        let mut seen = HashSet::new();
+        if let Some(txid) = txids.iter().find(|txid| !seen.insert(**txid)) {
+            return Err(format_err!(
+                "Block {} contains transaction {} more than once",
+                self.hash,
+                txid
+            ));
+        }
+        let root = &self.header.merkle_root;
+        if !root.is_empty() && *root != merkle_root(&txids) {
+            return Err(format_err!(
+                "Merkle root of block {} does not match its transactions",
+                self.hash
+            ));
+        }
+        Ok(())
+    }
+
+    /// GetUtxoCommitment 返回区块头中的 UTXO 承诺哈希，未包含时返回 None
+    #[cfg(feature = "utxo-commitment")]
+    pub fn get_utxo_commitment(&self) -> Option<[u8; 32]> {
+        self.header.utxo_commitment
+    }
+
+    /// NewBlock 按难度 bits 挖出并返回区块
     pub fn new_block(
         transactions: Vec<Transaction>,
         prev_block_hash: String,
         height: i32,
+        bits: u32,
     ) -> Result<Block> {
-        let timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)?
-            .as_millis();
-        let mut block = Block {
-            timestamp,
+        let mut block = Block::new_template(transactions, prev_block_hash, height, bits, 0)?;
+        block.run_proof_of_work()?;
+        Ok(block)
+    }
+
+    /// NewTemplate 创建尚未做工作量证明的区块，由 mine_with_abort 挖出
+    ///
+    /// 时间戳为当前时间，本地时钟早于 min_timestamp 时取 min_timestamp
+    pub fn new_template(
+        transactions: Vec<Transaction>,
+        prev_block_hash: String,
+        height: i32,
+        bits: u32,
+        min_timestamp: u128,
+    ) -> Result<Block> {
+        let timestamp = now_millis()?.max(min_timestamp);
+        Ok(Block::unhashed(
             transactions,
             prev_block_hash,
-            hash: String::new(),
+            height,
+            timestamp,
+            bits,
+        ))
+    }
+
+    /// 创建哈希为空、nonce 为 0 的区块，默克尔根由交易算出
+    fn unhashed(
+        transactions: Vec<Transaction>,
+        prev_block_hash: String,
+        height: i32,
+        timestamp: u128,
+        bits: u32,
+    ) -> Block {
+        let header = BlockHeader {
+            version: BLOCK_VERSION,
+            prev_hash: prev_block_hash,
+            merkle_root: String::new(),
+            timestamp,
+            bits,
             nonce: 0,
             height,
+            #[cfg(feature = "utxo-commitment")]
+            utxo_commitment: None,
         };
-        block.run_proof_of_work()?;
+        let mut block = Block {
+            header,
+            hash: String::new(),
+            transactions,
+        };
+        block.header.merkle_root = merkle_root(&block.transaction_ids());
+        block
+    }
+
+    /// NewCommittedTemplate 创建区块头包含 UTXO 承诺哈希、尚未做工作量证明的区块，承诺参与工作量证明
+    #[cfg(feature = "utxo-commitment")]
+    pub fn new_committed_template(
+        transactions: Vec<Transaction>,
+        prev_block_hash: String,
+        height: i32,
+        bits: u32,
+        min_timestamp: u128,
+        utxo_commitment: [u8; 32],
+    ) -> Result<Block> {
+        let mut block =
+            Block::new_template(transactions, prev_block_hash, height, bits, min_timestamp)?;
+        block.header.utxo_commitment = Some(utxo_commitment);
+        Ok(block)
+    }
+
+    /// NewUnminedBlock 创建不做工作量证明的区块，用于不检查工作量证明、需要大量区块的测试
+    #[cfg(test)]
+    pub fn new_unmined_block(
+        transactions: Vec<Transaction>,
+        prev_block_hash: String,
+        height: i32,
+    ) -> Result<Block> {
+        Block::new_unmined_block_at(transactions, prev_block_hash, height, 0, INITIAL_BITS)
+    }
+
+    /// NewUnminedBlockAt 创建指定时间戳和难度、不做工作量证明的区块
+    #[cfg(test)]
+    pub fn new_unmined_block_at(
+        transactions: Vec<Transaction>,
+        prev_block_hash: String,
+        height: i32,
+        timestamp: u128,
+        bits: u32,
+    ) -> Result<Block> {
+        let mut block = Block::unhashed(transactions, prev_block_hash, height, timestamp, bits);
+        let mut hasher = Sha256::new();
+        hasher.input(&block.prepare_hash_data()?);
+        block.hash = hasher.result_str();
         Ok(block)
     }
 
-    /// NewGenesisBlock 创建并返回创世区块
+    /// NewGenesisBlock 按主网的初始难度创建并返回创世区块
+    #[cfg(test)]
     pub fn new_genesis_block(coinbase: Transaction) -> Block {
-        Block::new_block(vec![coinbase], String::new(), 0).unwrap()
+        let bits = Network::Mainnet.initial_bits();
+        Block::new_block(vec![coinbase], String::new(), 0, bits).unwrap()
     }
 
-    /// 执行工作量证明（PoW）
+    /// Mine 对区块做工作量证明，用于需要指定时间戳且满足难度的测试
+    #[cfg(test)]
+    pub fn mine(self) -> Result<Block> {
+        self.mine_with_abort(default_mining_threads(), &AtomicBool::new(false))
+    }
+
+    /// MineWithAbort 用 threads 个线程对区块做工作量证明，abort 被置位时放弃并返回错误
+    pub fn mine_with_abort(mut self, threads: usize, abort: &AtomicBool) -> Result<Block> {
+        self.header.nonce = 0;
+        self.run_proof_of_work_with(threads, abort)?;
+        Ok(self)
+    }
+
+    /// GetBits 返回区块的难度，见 BlockHeader::get_bits
+    pub fn get_bits(&self) -> u32 {
+        self.header.get_bits()
+    }
+
+    /// CheckProofOfWork 检查区块的版本，区块哈希由区块头算出且满足区块的难度
+    pub fn check_proof_of_work(&self) -> Result<()> {
+        self.header.check_version(&self.hash)?;
+        if self.hash != sha256_hex(&self.prepare_hash_data()?) {
+            return Err(format_err!(
+                "Hash of block {} does not match its header",
+                self.hash
+            ));
+        }
+        if !self.validate()? {
+            return Err(format_err!(
+                "Block {} does not meet its difficulty {:08x}",
+                self.hash,
+                self.get_bits()
+            ));
+        }
+        Ok(())
+    }
+
+    /// 执行工作量证明（PoW），使用全部 CPU
     fn run_proof_of_work(&mut self) -> Result<()> {
-        info!("Mining the block");
-        while !self.validate()? {
-            self.nonce += 1;
+        self.run_proof_of_work_with(default_mining_threads(), &AtomicBool::new(false))
+    }
+
+    /// RunProofOfWorkWith 把 nonce 空间平均分成 threads 段，每个线程在区块头的副本上依次尝试一段
+    ///
+    /// 任一线程找到满足难度的 nonce 后其余线程随即停止；abort 被置位时全部线程停止并返回错误
+    fn run_proof_of_work_with(&mut self, threads: usize, abort: &AtomicBool) -> Result<()> {
+        info!("Mining the block with {} threads", threads);
+        let transactions_hash = self.transactions_hash()?;
+        let threads = threads.max(1) as u64;
+        let span = (1u64 << 32) / threads;
+        let stop = AtomicBool::new(false);
+        let found: Mutex<Option<i32>> = Mutex::new(None);
+        thread::scope(|s| {
+            let workers: Vec<_> = (0..threads)
+                .map(|worker| {
+                    let mut header = self.header.clone();
+                    let (stop, found, transactions_hash) = (&stop, &found, &transactions_hash);
+                    s.spawn(move || -> Result<()> {
+                        for offset in 0..span {
+                            if stop.load(Ordering::Relaxed) || abort.load(Ordering::Relaxed) {
+                                break;
+                            }
+                            // 第 0 段从 0 开始，单线程时与逐个递增 nonce 相同
+                            header.nonce = (worker * span + offset) as u32 as i32;
+                            let valid = header.meets_target(transactions_hash);
+                            if !matches!(valid, Ok(false)) {
+                                stop.store(true, Ordering::Relaxed);
+                            }
+                            if valid? {
+                                found.lock().unwrap().get_or_insert(header.nonce);
+                                break;
+                            }
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().unwrap())
+        })?;
+
+        match found.into_inner().unwrap() {
+            Some(nonce) => self.header.nonce = nonce,
+            None if abort.load(Ordering::Relaxed) => {
+                return Err(format_err!(
+                    "Mining of the block at height {} was aborted",
+                    self.header.height
+                ));
+            }
+            None => {
+                return Err(format_err!(
+                    "No nonce meets difficulty {:08x}",
+                    self.get_bits()
+                ));
+            }
         }
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        self.hash = hasher.result_str();
+        self.hash = sha256_hex(&self.prepare_hash_data()?);
         Ok(())
     }
 
-    /// HashTransactions 返回区块中交易的哈希
+    /// HashTransactions 返回记录默克尔根之前的区块在区块头中使用的交易哈希
     fn hash_transactions(&self) -> Result<Vec<u8>> {
         let mut transactions = Vec::new();
         for tx in &self.transactions {
@@ -92,26 +815,97 @@ impl Block {
         Ok(tree.root())
     }
 
+    /// 区块头编码中的交易部分，为默克尔根，旧区块为 hash_transactions 的结果
+    fn transactions_hash(&self) -> Result<Vec<u8>> {
+        if self.header.merkle_root.is_empty() {
+            self.hash_transactions()
+        } else {
+            Ok(hex::decode(&self.header.merkle_root)?)
+        }
+    }
+
+    /// 区块头的编码，见 BlockHeader::hash_data
     fn prepare_hash_data(&self) -> Result<Vec<u8>> {
-        let content = (
-            self.prev_block_hash.clone(),
-            self.hash_transactions()?,
-            self.timestamp,
-            TARGET_HEXS,
-            self.nonce,
-        );
-        let bytes = serialize(&content)?;
-        Ok(bytes)
+        self.header.hash_data(self.transactions_hash()?)
     }
 
-    /// HashTransactions 返回区块中交易的哈希
+    /// 检查区块头的哈希满足难度，见 BlockHeader::meets_target
     fn validate(&self) -> Result<bool> {
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        let mut vec1: Vec<u8> = Vec::new();
-        vec1.resize(TARGET_HEXS, '0' as u8);
-        Ok(&hasher.result_str()[0..TARGET_HEXS] == String::from_utf8(vec1)?)
+        self.header.meets_target(&self.transactions_hash()?)
+    }
+}
+
+/// NowMillis 返回本地时钟自 UNIX 纪元以来的毫秒数，即区块时间戳的单位
+pub fn now_millis() -> Result<u128> {
+    Ok(SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis())
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    hasher.result_str()
+}
+
+/// TargetFromBits 把紧凑格式的难度展开为 256 位大端序目标值
+///
+/// bits 的最高字节为指数 e，低 3 字节为尾数 m，目标值为 m * 256^(e - 3)。
+/// 区块头的哈希按大端序不大于目标值时满足工作量证明
+pub fn target_from_bits(bits: u32) -> [u8; 32] {
+    let exponent = (bits >> 24) as i32;
+    let mut target = [0u8; 32];
+    for (i, byte) in bits.to_be_bytes()[1..].iter().enumerate() {
+        // 尾数第 i 个字节在目标值中是从低位数第 exponent - 1 - i 个字节
+        let position = exponent - 1 - i as i32;
+        if (0..32).contains(&position) {
+            target[31 - position as usize] = *byte;
+        }
+    }
+    target
+}
+
+/// Difficulty 返回难度相对 INITIAL_BITS 的倍数，即两者目标值之比
+pub fn difficulty(bits: u32) -> f64 {
+    let value = |target: [u8; 32]| {
+        target
+            .iter()
+            .fold(0.0, |acc, &byte| acc * 256.0 + byte as f64)
+    };
+    value(target_from_bits(INITIAL_BITS)) / value(target_from_bits(bits))
+}
+
+/// BlockWork 返回满足难度 bits 平均需要的哈希次数，用于比较分支的累计工作量
+///
+/// 取目标值的高 128 位 high，工作量为 u128::MAX / (high + 1)，目标值越小工作量越大
+pub fn block_work(bits: u32) -> u128 {
+    let target = target_from_bits(bits);
+    let high = u128::from_be_bytes(target[..16].try_into().unwrap());
+    u128::MAX / high.saturating_add(1)
+}
+
+/// Retarget 按上一周期的实际用时 actual 与期望用时 expected（毫秒）之比缩放目标值
+///
+/// 实际用时先限制在期望用时的 1/4 到 4 倍之间，单次调整最多改变 4 倍；
+/// 结果不低于 POW_LIMIT_BITS 对应的难度
+pub fn retarget(bits: u32, actual: u128, expected: u128) -> u32 {
+    let actual = actual.clamp(expected / 4, expected * 4);
+    let mut exponent = bits >> 24;
+    let mut mantissa = u128::from(bits & 0x00ff_ffff) * actual / expected;
+    // 尾数保持在 3 个字节内且最高位为 0，右移只会让目标值变小
+    while mantissa > 0x007f_ffff {
+        mantissa >>= 8;
+        exponent += 1;
+    }
+    while mantissa != 0 && mantissa <= 0x7fff && exponent > 3 {
+        mantissa <<= 8;
+        exponent -= 1;
+    }
+    let bits = (exponent << 24) | mantissa as u32;
+    if target_from_bits(bits) > target_from_bits(POW_LIMIT_BITS) {
+        POW_LIMIT_BITS
+    } else {
+        bits
     }
 }
 
@@ -129,3 +923,320 @@ impl Merge for MergeVu8 {
         re.to_vec()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::merkle::MerkleProof;
+    use crate::wallets::Wallets;
+    use crate::walletstorage::memory::MemoryStorage;
+
+    #[test]
+    fn test_retarget() {
+        assert_eq!(
+            target_from_bits(3 << 24 | 0x12_3456)[29..],
+            [0x12, 0x34, 0x56]
+        );
+        let initial = target_from_bits(INITIAL_BITS);
+        assert_eq!(initial[..4], [0, 0, 0xff, 0xff]);
+        assert!(initial[4..].iter().all(|&byte| byte == 0));
+        assert_eq!(target_from_bits(POW_LIMIT_BITS)[..3], [0, 0xff, 0xff]);
+        assert_eq!(difficulty(INITIAL_BITS), 1.0);
+
+        let expected = 1_000_000;
+        let close = |bits: u32, want: f64| (difficulty(bits) / want - 1.0).abs() < 1e-4;
+        assert_eq!(retarget(INITIAL_BITS, expected, expected), INITIAL_BITS);
+        // 出块快时难度升高，慢时降低
+        assert!(close(retarget(INITIAL_BITS, expected / 2, expected), 2.0));
+        assert!(close(retarget(INITIAL_BITS, expected * 2, expected), 0.5));
+        // 单次调整最多 4 倍
+        assert!(close(retarget(INITIAL_BITS, 0, expected), 4.0));
+        assert!(close(
+            retarget(INITIAL_BITS, expected * 100, expected),
+            0.25
+        ));
+        let mut bits = INITIAL_BITS;
+        for _ in 0..10 {
+            let next = retarget(bits, 0, expected);
+            assert!(close(next, difficulty(bits) * 4.0));
+            bits = next;
+        }
+        // 难度不低于下限
+        assert_eq!(
+            retarget(POW_LIMIT_BITS, expected * 4, expected),
+            POW_LIMIT_BITS
+        );
+        let easier = retarget(
+            retarget(INITIAL_BITS, expected * 4, expected),
+            expected * 4,
+            expected,
+        );
+        assert!(close(easier, 1.0 / 16.0));
+        let mut bits = INITIAL_BITS;
+        for _ in 0..5 {
+            bits = retarget(bits, expected * 4, expected);
+        }
+        assert_eq!(bits, POW_LIMIT_BITS);
+
+        // 工作量与难度成正比
+        assert_eq!(block_work(INITIAL_BITS), 65_537);
+        let harder = retarget(INITIAL_BITS, expected / 4, expected);
+        let ratio = block_work(harder) as f64 / block_work(INITIAL_BITS) as f64;
+        assert!((ratio / difficulty(harder) - 1.0).abs() < 1e-4);
+        assert!(block_work(REGTEST_BITS) < block_work(POW_LIMIT_BITS));
+        assert_eq!(block_work(0x0300_0001), u128::MAX);
+
+        // 工作量证明检查难度和区块头的哈希
+        let block = Block::new_unmined_block_at(Vec::new(), String::new(), 0, 0, POW_LIMIT_BITS)
+            .unwrap()
+            .mine()
+            .unwrap();
+        block.check_proof_of_work().unwrap();
+        let mut tampered = block.clone();
+        tampered.header.nonce += 1;
+        let err = tampered.check_proof_of_work().unwrap_err();
+        assert!(err.to_string().contains("does not match"), "{}", err);
+    }
+
+    #[test]
+    fn test_parallel_proof_of_work() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let block = |n: u128, bits: u32| {
+            let cbtx =
+                Transaction::new_coinbase(address.clone(), String::new(), 1, 0, Network::Mainnet)
+                    .unwrap();
+            Block::new_unmined_block_at(vec![cbtx], "prev".into(), 1, n, bits).unwrap()
+        };
+        for threads in [1, 4] {
+            let mut mined = block(0, REGTEST_BITS);
+            mined
+                .run_proof_of_work_with(threads, &AtomicBool::new(false))
+                .unwrap();
+            mined.check_proof_of_work().unwrap();
+        }
+        let mut aborted = block(0, 0x0300_0001);
+        let err = aborted
+            .run_proof_of_work_with(4, &AtomicBool::new(true))
+            .unwrap_err();
+        assert!(err.to_string().contains("aborted"), "{}", err);
+
+        // 只有至少 4 个 CPU 时多线程才可能更快；挖多个区块减小运气的影响
+        if thread::available_parallelism().map_or(1, |n| n.get()) < 4 {
+            return;
+        }
+        let bits = 0x1f07_ffff;
+        let elapsed = |threads: usize| {
+            let start = std::time::Instant::now();
+            for n in 0..8 {
+                let mut mined = block(n, bits);
+                mined
+                    .run_proof_of_work_with(threads, &AtomicBool::new(false))
+                    .unwrap();
+                mined.check_proof_of_work().unwrap();
+            }
+            start.elapsed()
+        };
+        let one = elapsed(1);
+        let four = elapsed(4);
+        assert!(four < one, "4 threads took {:?}, 1 thread {:?}", four, one);
+    }
+
+    #[test]
+    fn test_verify_merkle_root() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let txs: Vec<Transaction> = (0..4)
+            .map(|n| {
+                Transaction::new_coinbase(
+                    address.clone(),
+                    format!("tx {}", n),
+                    0,
+                    0,
+                    Network::Mainnet,
+                )
+                .unwrap()
+            })
+            .collect();
+        for count in 1..=4 {
+            let block = Block::new_unmined_block(txs[..count].to_vec(), String::new(), 0).unwrap();
+            block.verify_merkle_root().unwrap();
+            let root = block.get_merkle_root();
+            assert_eq!(root, merkle_root(&block.transaction_ids()));
+            for tx in &txs[..count] {
+                let index = block
+                    .transaction_ids()
+                    .iter()
+                    .position(|id| *id == tx.id)
+                    .unwrap();
+                let proof =
+                    MerkleProof::new(&block.get_hash(), &block.transaction_ids(), index).unwrap();
+                assert!(proof.verify(&root, &tx.id));
+            }
+        }
+
+        // 重复最后一笔交易不改变默克尔根，但区块被拒绝
+        let block = Block::new_unmined_block(txs[..3].to_vec(), String::new(), 0).unwrap();
+        let mut txs_dup = txs[..3].to_vec();
+        txs_dup.push(txs[2].clone());
+        let dup = Block::new_unmined_block(txs_dup, String::new(), 0).unwrap();
+        assert_eq!(dup.get_merkle_root(), block.get_merkle_root());
+        let err = dup.verify_merkle_root().unwrap_err();
+        assert!(err.to_string().contains("more than once"), "{}", err);
+
+        let mut tampered = block.clone();
+        tampered.transactions.pop();
+        let err = tampered.verify_merkle_root().unwrap_err();
+        assert!(err.to_string().contains("does not match"), "{}", err);
+
+        // 记录默克尔根之前的区块仍用原来的交易哈希计算区块哈希
+        let legacy = (
+            7u128,
+            txs.clone(),
+            String::new(),
+            String::from("hash"),
+            3i32,
+            0i32,
+        );
+        let decoded = Block::decode(&serialize(&legacy).unwrap()).unwrap();
+        assert!(decoded.get_merkle_root().is_empty());
+        decoded.verify_merkle_root().unwrap();
+        let content = (
+            String::new(),
+            decoded.hash_transactions().unwrap(),
+            7u128,
+            TARGET_HEXS,
+            3i32,
+        );
+        #[cfg(feature = "utxo-commitment")]
+        let content = (content, None::<[u8; 32]>);
+        assert_eq!(
+            decoded.prepare_hash_data().unwrap(),
+            serialize(&content).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_block_header() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let cbtx =
+            Transaction::new_coinbase(address, String::new(), 0, 0, Network::Mainnet).unwrap();
+        let block = Block::new_unmined_block_at(vec![cbtx], String::new(), 0, 5, POW_LIMIT_BITS)
+            .unwrap()
+            .mine()
+            .unwrap();
+        let header = block.header().clone();
+        assert_eq!(header.hash().unwrap(), block.get_hash());
+        assert_eq!(header.check_proof_of_work().unwrap(), block.get_hash());
+
+        let mut unknown = header.clone();
+        unknown.version = BLOCK_VERSION + 1;
+        let err = unknown.check_proof_of_work().unwrap_err();
+        assert!(err.to_string().contains("unsupported version"), "{}", err);
+        let mut tampered = block.clone();
+        tampered.header.version = 0;
+        assert!(tampered.check_proof_of_work().is_err());
+        let mut legacy = header.clone();
+        legacy.merkle_root = String::new();
+        assert!(legacy.hash().is_err());
+
+        // 区块头独立成类型之前的格式解码后区块头相同
+        let flat = (
+            header.timestamp,
+            block.get_transaction().clone(),
+            header.prev_hash.clone(),
+            block.get_hash(),
+            header.nonce,
+            header.height,
+            header.merkle_root.clone(),
+            header.bits,
+        );
+        #[cfg(feature = "utxo-commitment")]
+        let flat = (flat, header.utxo_commitment);
+        let decoded = Block::decode(&serialize(&flat).unwrap()).unwrap();
+        assert_eq!(decoded.header(), &header);
+        assert_eq!(decoded.get_hash(), block.get_hash());
+        let decoded = Block::decode(&serialize(&block).unwrap()).unwrap();
+        assert_eq!(decoded.header(), &header);
+    }
+
+    #[test]
+    fn test_check_size() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let cbtx = || {
+            Transaction::new_coinbase(address.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap()
+        };
+        let block = |txs| Block::new_unmined_block(txs, String::new(), 0).unwrap();
+
+        let txs: Vec<Transaction> = (0..MAX_BLOCK_TXS).map(|_| cbtx()).collect();
+        let full = block(txs.clone());
+        full.check_size().unwrap();
+        // base_size 按最长的父区块哈希计算，是大小的上界
+        let estimate =
+            Block::base_size(&txs[0]).unwrap() + (MAX_BLOCK_TXS - 1) * txs[1].size().unwrap();
+        assert!(full.size().unwrap() <= estimate);
+        let mut txs = txs;
+        txs.push(cbtx());
+        let err = block(txs).check_size().unwrap_err();
+        assert!(err.to_string().contains("more than the limit"), "{}", err);
+
+        // 交易数未超限，但序列化后超过 MAX_BLOCK_SIZE 字节
+        let mut huge = cbtx();
+        huge.vin[0].pub_key = vec![0; MAX_BLOCK_SIZE];
+        let err = block(vec![huge]).check_size().unwrap_err();
+        assert!(err.to_string().contains("larger than the limit"), "{}", err);
+    }
+
+    #[test]
+    fn test_decode_legacy_block() {
+        // 旧格式：交易没有版本号和备注，输出没有数据字段
+        let output = (7i32, vec![1u8; 20]);
+        let input = (String::new(), -1i32, Vec::<u8>::new(), vec![2u8; 4]);
+        let tx = (String::from("txid"), vec![input], vec![output]);
+        let legacy = (
+            42u128,
+            vec![tx],
+            String::new(),
+            String::from("hash"),
+            3i32,
+            0i32,
+        );
+        let bytes = serialize(&legacy).unwrap();
+
+        let block = Block::decode(&bytes).unwrap();
+        assert_eq!(block.get_hash(), "hash");
+        let tx = &block.get_transaction()[0];
+        assert_eq!(tx.id, "txid");
+        assert_eq!(tx.version, 0);
+        assert!(tx.memo.is_none());
+        assert_eq!(tx.vout[0].value, 7);
+        assert!(!tx.vout[0].is_data());
+
+        let mut tx = tx.clone();
+        tx.memo = Some(String::from("memo"));
+        let block = Block::new_genesis_block(tx);
+        let decoded = Block::decode(&serialize(&block).unwrap()).unwrap();
+        assert_eq!(decoded.get_hash(), block.get_hash());
+        assert_eq!(decoded.get_transaction()[0].memo.as_deref(), Some("memo"));
+
+        assert!(Block::decode(&bytes[..bytes.len() - 1]).is_err());
+
+        // 旧格式中的负数金额无法转换为 u64
+        let output = (-5i32, vec![1u8; 20]);
+        let input = (String::new(), -1i32, Vec::<u8>::new(), vec![2u8; 4]);
+        let tx = (String::from("txid"), vec![input], vec![output]);
+        let legacy = (
+            42u128,
+            vec![tx],
+            String::new(),
+            String::from("hash"),
+            3i32,
+            0i32,
+        );
+        let err = Block::decode(&serialize(&legacy).unwrap()).unwrap_err();
+        assert!(err.to_string().contains("negative output value"));
+    }
+}
@@ -2,21 +2,71 @@
 
 use super::*;
 use crate::block::*;
+use crate::merkle::{MerkleProof, merkle_root};
+use crate::network::Network;
+#[cfg(test)]
+use crate::storage::MemoryStore;
+use crate::storage::{Batch, DEFAULT_TREE, KvStore, SledStore};
 use crate::transaction::*;
+use crate::utxoset::UTXOSet;
 use bincode::{deserialize, serialize};
 use failure::format_err;
-use sled;
-use std::collections::HashMap;
-use log::{debug, info};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use log::{debug, error, info};
 
-const GENESIS_COINBASE_DATA: &str =
-    "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
+/// 区块中待验证签名不少于该数量时并行验证
+const PARALLEL_VERIFY_MIN: usize = 16;
+/// 主链高度索引：大端序的高度 -> 区块哈希
+const HEIGHT_TREE: &str = "heights";
+/// 累计工作量：区块哈希 -> 从创世区块到该区块的工作量之和，大端序 u128
+const WORK_TREE: &str = "chainwork";
+/// 区块头：区块哈希 -> 区块头，包括只收到了区块头、区块尚未下载的区块
+const HEADER_TREE: &str = "headers";
+/// 创建参数：network、genesis、genesis_message -> 创建时的网络、创世区块哈希和创世消息；
+/// best_header -> 只有区块头的分支中累计工作量最大的区块头哈希
+const META_TREE: &str = "meta";
+/// BEST_HEADER_KEY 最好的区块头在 META_TREE 中的键
+const BEST_HEADER_KEY: &[u8] = b"best_header";
+/// 交易索引：交易 id -> (区块哈希, 交易在区块中的下标)，只有运行过 reindex-tx 的节点维护
+const TX_TREE: &str = "txindex";
+/// TX_INDEX_KEY 交易索引建成后在 META_TREE 中的标记，重建过程中不存在
+const TX_INDEX_KEY: &[u8] = b"txindex";
+/// 重建交易索引时每个批次包含的区块数
+const TX_INDEX_BATCH_BLOCKS: usize = 1000;
+/// 区块链导出文件开头的魔数
+const EXPORT_MAGIC: [u8; 8] = *b"RCCHAIN\0";
+/// 区块链导出文件的格式版本
+const EXPORT_VERSION: u32 = 1;
+
+/// 网络编入程序的检查点
+fn network_checkpoints(network: Network) -> BTreeMap<i32, String> {
+    network
+        .checkpoints()
+        .iter()
+        .map(|(height, hash)| (*height, hash.to_string()))
+        .collect()
+}
 
 /// Blockchain 实现与数据库的交互
-#[derive(Debug)]
 pub struct Blockchain {
     pub tip: String,
-    pub db: sled::Db,
+    pub db: Arc<dyn KvStore>,
+    /// 高度 -> 该高度上主链区块必须具有的哈希，默认为网络编入程序的检查点，清空后不使用检查点
+    pub checkpoints: BTreeMap<i32, String>,
+    /// 从该高度起创币交易必须记录区块高度
+    pub coinbase_height_activation: i32,
+    /// 从该高度起区块头必须记录难度
+    pub bits_activation: i32,
+    /// 挖矿时搜索 nonce 的线程数，默认为可用的 CPU 数
+    pub mining_threads: usize,
+    /// 区块链所属的网络
+    pub network: Network,
 }
 
 /// BlockchainIterator 用于遍历区块链区块
@@ -25,194 +75,1588 @@ pub struct BlockchainIterator<'a> {
     bc: &'a Blockchain,
 }
 
+/// BlockValidationError 收到的区块未通过完整验证的具体原因，index 为交易在区块中的下标
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockValidationError {
+    UtxoTipMismatch {
+        prev: String,
+        utxo_tip: String,
+    },
+    BadHeight {
+        expected: i32,
+        found: i32,
+    },
+    NoCoinbase,
+    MisplacedCoinbase {
+        index: usize,
+    },
+    CoinbaseHeight {
+        height: i32,
+        found: Option<i32>,
+    },
+    NotFinal {
+        txid: Txid,
+        lock_until: i32,
+    },
+    DoubleSpend {
+        txid: Txid,
+        outpoint: OutPoint,
+    },
+    MissingInput {
+        txid: Txid,
+        outpoint: OutPoint,
+    },
+    ImmatureInput {
+        txid: Txid,
+        outpoint: OutPoint,
+    },
+    InvalidTransaction {
+        txid: Txid,
+        error: TxVerifyError,
+    },
+    ValueOverflow,
+    ExcessiveCoinbase {
+        reward: u64,
+        subsidy: u64,
+        fees: u64,
+    },
+    Storage {
+        message: String,
+    },
+}
+
+impl fmt::Display for BlockValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockValidationError::UtxoTipMismatch { prev, utxo_tip } => write!(
+                f,
+                "the UTXO set is at block {}, not at the parent {}",
+                utxo_tip, prev
+            ),
+            BlockValidationError::BadHeight { expected, found } => write!(
+                f,
+                "block claims height {}, but follows its parent at height {}",
+                found, expected
+            ),
+            BlockValidationError::NoCoinbase => write!(f, "block does not start with a coinbase"),
+            BlockValidationError::MisplacedCoinbase { index } => {
+                write!(f, "unexpected coinbase at index {}", index)
+            }
+            BlockValidationError::CoinbaseHeight {
+                height,
+                found: Some(found),
+            } => write!(
+                f,
+                "coinbase claims height {}, but the block is at height {}",
+                found, height
+            ),
+            BlockValidationError::CoinbaseHeight {
+                height,
+                found: None,
+            } => write!(f, "coinbase does not record the block height {}", height),
+            BlockValidationError::NotFinal { txid, lock_until } => write!(
+                f,
+                "transaction {} is locked until height {}",
+                txid, lock_until
+            ),
+            BlockValidationError::DoubleSpend { txid, outpoint } => write!(
+                f,
+                "transaction {} spends {}, which is already spent in the block",
+                txid, outpoint
+            ),
+            BlockValidationError::MissingInput { txid, outpoint } => write!(
+                f,
+                "transaction {} spends {}, which is not an unspent output",
+                txid, outpoint
+            ),
+            BlockValidationError::ImmatureInput { txid, outpoint } => write!(
+                f,
+                "transaction {} spends the immature coinbase output {}",
+                txid, outpoint
+            ),
+            BlockValidationError::InvalidTransaction { txid, error } => {
+                write!(f, "invalid transaction {}: {}", txid, error)
+            }
+            BlockValidationError::ValueOverflow => write!(f, "block value overflows"),
+            BlockValidationError::ExcessiveCoinbase {
+                reward,
+                subsidy,
+                fees,
+            } => write!(
+                f,
+                "coinbase reward {} exceeds subsidy {} plus fees {}",
+                reward, subsidy, fees
+            ),
+            BlockValidationError::Storage { message } => {
+                write!(f, "cannot read the chain state: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BlockValidationError {}
+
+impl From<failure::Error> for BlockValidationError {
+    fn from(err: failure::Error) -> Self {
+        BlockValidationError::Storage {
+            message: err.to_string(),
+        }
+    }
+}
+
+/// ChainFault verify_chain 发现的问题种类
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainFault {
+    /// 区块缺失或无法解码
+    Unreadable,
+    /// 区块的哈希、高度或父区块与它在主链上的位置不符
+    Linkage,
+    /// 区块头的哈希或难度不正确
+    ProofOfWork,
+    /// 交易 id 或默克尔根与区块中的交易不符
+    Transactions,
+    /// 重放时区块未通过完整验证
+    Validation,
+    /// 存储的UTXO集合或其索引与重放得到的集合不同
+    UtxoSet,
+}
+
+impl fmt::Display for ChainFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ChainFault::Unreadable => "unreadable block",
+            ChainFault::Linkage => "broken linkage",
+            ChainFault::ProofOfWork => "invalid proof of work",
+            ChainFault::Transactions => "transactions do not match",
+            ChainFault::Validation => "invalid block",
+            ChainFault::UtxoSet => "UTXO set mismatch",
+        };
+        f.write_str(name)
+    }
+}
+
+/// ChainFailure verify_chain 发现的第一个问题
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainFailure {
+    /// 出问题的区块高度，UTXO集合的问题记在最新区块上
+    pub height: i32,
+    pub fault: ChainFault,
+    pub message: String,
+}
+
+/// ChainReport verify_chain 的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainReport {
+    /// 检查级别
+    pub level: u8,
+    /// 通过检查的区块数
+    pub verified: usize,
+    /// 第一个问题，全部通过时为 None
+    pub failure: Option<ChainFailure>,
+}
+
 impl Blockchain {
-    /// NewBlockchain 创建新区块链数据库
-    pub fn new() -> Result<Blockchain> {
+    /// Open 打开数据目录 data_dir 中 network 的区块链
+    ///
+    /// 区块链不存在时返回错误，不会创建空数据库；数据库属于其他网络时拒绝打开
+    pub fn open(network: Network, data_dir: &Path) -> Result<Blockchain> {
         info!("open blockchain");
 
-        let db = sled::open("data/blocks")?;
-        let hash = match db.get("LAST")? {
-            Some(l) => l.to_vec(),
-            None => Vec::new(),
+        let path = network.data_path(data_dir, "blocks");
+        let not_found = || {
+            format_err!(
+                "No blockchain found in {}, run createblockchain first",
+                path.display()
+            )
         };
+        if !path.is_dir() {
+            return Err(not_found());
+        }
+        let db = Network::open_db_at(&path)?;
+        let bc = Blockchain::with_store(Arc::new(SledStore::new(db)), network)?;
+        if bc.tip.is_empty() {
+            return Err(not_found());
+        }
+        Ok(bc)
+    }
+
+    /// NewBlockchainWithStore 使用指定的存储后端打开 network 的区块链，存储为空时得到空区块链
+    ///
+    /// 存储中记录的网络与 network 不同，或创世区块与记录不符时返回错误
+    pub fn with_store(db: Arc<dyn KvStore>, network: Network) -> Result<Blockchain> {
+        let hash = db.get(DEFAULT_TREE, b"LAST")?.unwrap_or_default();
         info!("Found block database");
         let lasthash = if hash.is_empty() {
             String::new()
         } else {
-            String::from_utf8(hash.to_vec())?
+            String::from_utf8(hash)?
+        };
+        let bc = Blockchain {
+            tip: lasthash,
+            db,
+            checkpoints: network_checkpoints(network),
+            coinbase_height_activation: network.coinbase_height_activation(),
+            bits_activation: network.bits_activation(),
+            mining_threads: default_mining_threads(),
+            network,
         };
-        Ok(Blockchain { tip: lasthash, db })
+        // 旧版本创建的数据库没有高度索引，打开时补齐
+        if !bc.tip.is_empty() {
+            let batch = bc.index_heights(&bc.tip)?;
+            bc.db.batch(batch)?;
+            bc.check_params(network)?;
+        }
+        Ok(bc)
     }
 
-    /// CreateBlockchain 创建新区块链
-    pub fn create_blockchain(address: String) -> Result<Blockchain> {
+    /// 检查数据库记录的创建参数属于 network
+    ///
+    /// 旧版本创建的数据库没有记录，按 network 和主链上的创世区块补齐
+    fn check_params(&self, network: Network) -> Result<()> {
+        let genesis = self
+            .db
+            .get(HEIGHT_TREE, &height_key(0))?
+            .map(String::from_utf8)
+            .transpose()?
+            .ok_or_else(|| format_err!("The block database has no genesis block"))?;
+        let recorded = match self.db.get(META_TREE, b"network")? {
+            Some(name) => String::from_utf8(name)?.parse::<Network>()?,
+            None => {
+                let mut batch = Batch::default();
+                batch.put(META_TREE, b"network", network.to_string());
+                batch.put(META_TREE, b"genesis", &genesis);
+                self.db.batch(batch)?;
+                return Ok(());
+            }
+        };
+        if recorded != network {
+            return Err(format_err!(
+                "The block database belongs to {}, not {}, check --network and --datadir",
+                recorded,
+                network
+            ));
+        }
+        let expected = self.db.get(META_TREE, b"genesis")?.unwrap_or_default();
+        if expected != genesis.as_bytes() {
+            return Err(format_err!(
+                "The block database was created with genesis block {} but its chain starts at {}",
+                String::from_utf8_lossy(&expected),
+                genesis
+            ));
+        }
+        Ok(())
+    }
+
+    /// GenesisMessage 返回创建区块链时创世区块携带的消息，旧版本创建的数据库没有记录
+    pub fn genesis_message(&self) -> Result<Option<String>> {
+        Ok(self
+            .db
+            .get(META_TREE, b"genesis_message")?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    /// InMemory 创建保存在内存中的空主网区块链
+    #[cfg(test)]
+    pub fn in_memory() -> Blockchain {
+        let network = Network::Mainnet;
+        Blockchain {
+            tip: String::new(),
+            db: Arc::new(MemoryStore::default()),
+            checkpoints: BTreeMap::new(),
+            coinbase_height_activation: network.coinbase_height_activation(),
+            bits_activation: network.bits_activation(),
+            mining_threads: default_mining_threads(),
+            network,
+        }
+    }
+
+    /// Create 在数据目录 data_dir 中创建 network 的新区块链，创世奖励发往 genesis_address
+    ///
+    /// genesis_message 为创世区块携带的消息，为空时使用网络默认的消息。
+    /// 数据目录中已有该网络的区块链时返回错误，不会覆盖
+    pub fn create(
+        genesis_address: String,
+        genesis_message: &str,
+        network: Network,
+        data_dir: &Path,
+    ) -> Result<Blockchain> {
         info!("Creating new blockchain");
 
-        std::fs::remove_dir_all("data/blocks").ok();
-        let db = sled::open("data/blocks")?;
+        let db = open_new_store(network, data_dir)?;
+        Blockchain::create_blockchain_with_store(genesis_address, genesis_message, network, db)
+    }
+
+    /// CreateBlockchainWithStore 在指定的空存储中创建 network 的新区块链
+    pub fn create_blockchain_with_store(
+        genesis_address: String,
+        genesis_message: &str,
+        network: Network,
+        db: Arc<dyn KvStore>,
+    ) -> Result<Blockchain> {
         debug!("Creating new block database");
-        let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
-        let genesis: Block = Block::new_genesis_block(cbtx);
-        db.insert(genesis.get_hash(), serialize(&genesis)?)?;
-        db.insert("LAST", genesis.get_hash().as_bytes())?;
+        let message = if genesis_message.is_empty() {
+            network.genesis_coinbase_data()
+        } else {
+            genesis_message
+        };
+        let cbtx = Transaction::new_coinbase(genesis_address, message.to_string(), 0, 0, network)?;
+        let genesis = Block::new_block(vec![cbtx], String::new(), 0, network.initial_bits())?;
+        let hash = genesis.get_hash();
+        let mut batch = params_batch(network, &hash, message);
+        batch.put(DEFAULT_TREE, &hash, serialize(&genesis)?);
+        batch.put(HEADER_TREE, &hash, serialize(genesis.header())?);
+        batch.put(DEFAULT_TREE, b"LAST", &hash);
+        batch.put(HEIGHT_TREE, height_key(0), &hash);
+        db.batch(batch)?;
         let bc = Blockchain {
-            tip: genesis.get_hash(),
+            tip: hash,
             db,
+            checkpoints: network_checkpoints(network),
+            coinbase_height_activation: network.coinbase_height_activation(),
+            bits_activation: network.bits_activation(),
+            mining_threads: default_mining_threads(),
+            network,
         };
         bc.db.flush()?;
         Ok(bc)
     }
 
+    /// Export 把主链上的区块按高度顺序写入文件，返回写入的区块数
+    ///
+    /// 文件以魔数、格式版本、网络名称和区块数开头，之后每个区块前是 4 字节小端序的长度。
+    /// 先写入临时文件再改名，每写完一个区块调用 progress(已写入的区块数, 区块总数)
+    pub fn export(&self, path: &Path, progress: impl Fn(usize, usize)) -> Result<usize> {
+        let total = (self.get_best_height()? + 1) as usize;
+        let tmp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        writer.write_all(&EXPORT_MAGIC)?;
+        writer.write_all(&EXPORT_VERSION.to_le_bytes())?;
+        let network = self.network.to_string();
+        writer.write_all(&[network.len() as u8])?;
+        writer.write_all(network.as_bytes())?;
+        writer.write_all(&(total as u32).to_le_bytes())?;
+        progress(0, total);
+        for height in 0..total {
+            let data = serialize(&self.get_block_by_height(height as i32)?)?;
+            writer.write_all(&(data.len() as u32).to_le_bytes())?;
+            writer.write_all(&data)?;
+            progress(height + 1, total);
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+        std::fs::rename(&tmp, path)?;
+        Ok(total)
+    }
+
+    /// Import 在数据目录 data_dir 中由 export 写出的文件重建 network 的区块链和UTXO集合
+    ///
+    /// 数据目录中不能已有区块链。每个区块都像收到的区块一样检查大小、默克尔根、难度、
+    /// 时间戳和检查点，对照UTXO集合完整验证后连接；某个区块未通过时停止并报告其高度，
+    /// 之前的区块保留。use_checkpoints 为 false 时不检查检查点。
+    /// 每连接一个区块调用 progress(已导入的区块数, 区块总数)
+    pub fn import(
+        path: &Path,
+        network: Network,
+        data_dir: &Path,
+        use_checkpoints: bool,
+        progress: impl Fn(usize, usize),
+    ) -> Result<UTXOSet> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let total = read_export_header(&mut reader, network)
+            .map_err(|e| format_err!("Cannot import {}: {}", path.display(), e))?;
+        if total == 0 {
+            return Err(format_err!("{} contains no blocks", path.display()));
+        }
+
+        let mut blocks = Blockchain::with_store(open_new_store(network, data_dir)?, network)?;
+        if !use_checkpoints {
+            blocks.checkpoints.clear();
+        }
+        let utxo_db = Network::open_db_at(&network.data_path(data_dir, "utxos"))?;
+        let mut utxo = UTXOSet::with_store(blocks, Arc::new(SledStore::new(utxo_db)));
+        utxo.reindex()?;
+        progress(0, total);
+        for height in 0..total {
+            let block = read_export_block(&mut reader)
+                .and_then(|block| import_block(&mut utxo, &block, height).map(|()| block))
+                .map_err(|e| {
+                    format_err!(
+                        "Block at height {} in {} is invalid: {}",
+                        height,
+                        path.display(),
+                        e
+                    )
+                })?;
+            if height == 0 {
+                let message = block.get_transaction()[0].coinbase_message(0);
+                let hash = block.get_hash();
+                let batch = params_batch(network, &hash, &String::from_utf8_lossy(message));
+                utxo.blockchain.db.batch(batch)?;
+            }
+            progress(height + 1, total);
+        }
+        if reader.read(&mut [0])? != 0 {
+            return Err(format_err!(
+                "{} has data after its last block",
+                path.display()
+            ));
+        }
+        utxo.blockchain.db.flush()?;
+        Ok(utxo)
+    }
+
+    /// VerifyChain 按高度顺序重新检查存储的主链，用于发现本地数据库的损坏
+    ///
+    /// 级别 1 检查每个区块的位置、父区块和工作量证明；级别 2 另外重新计算交易 id 和默克尔根；
+    /// 级别 3 另外把区块依次完整验证并重放到临时的UTXO集合中，最后与 utxo 及其索引比较。
+    /// 遇到第一个问题时停止并在报告中记录，读取存储出错时返回错误
+    pub fn verify_chain(&self, check_level: u8, utxo: &UTXOSet) -> Result<ChainReport> {
+        if !(1..=3).contains(&check_level) {
+            return Err(format_err!(
+                "Check level must be 1, 2 or 3, got {}",
+                check_level
+            ));
+        }
+        let replay = if check_level >= 3 {
+            let chain = Blockchain {
+                tip: String::new(),
+                db: self.db.clone(),
+                checkpoints: self.checkpoints.clone(),
+                coinbase_height_activation: self.coinbase_height_activation,
+                bits_activation: self.bits_activation,
+                mining_threads: self.mining_threads,
+                network: self.network,
+            };
+            let store = sled::Config::new().temporary(true).open()?;
+            Some(UTXOSet::with_store(chain, Arc::new(SledStore::new(store))))
+        } else {
+            None
+        };
+        let mut report = ChainReport {
+            level: check_level,
+            verified: 0,
+            failure: None,
+        };
+        let mut prev_hash = String::new();
+        let mut height = 0;
+        while let Some(hash) = self.db.get(HEIGHT_TREE, &height_key(height))? {
+            let hash = String::from_utf8(hash)?;
+            let checked = self.verify_stored_block(&hash, height, &prev_hash, check_level);
+            let checked = match (checked, &replay) {
+                (Ok(block), Some(replay)) => replay_block(replay, &block),
+                (checked, _) => checked.map(|_| ()),
+            };
+            if let Err((fault, err)) = checked {
+                report.failure = Some(ChainFailure {
+                    height,
+                    fault,
+                    message: err.to_string(),
+                });
+                return Ok(report);
+            }
+            report.verified += 1;
+            prev_hash = hash;
+            height += 1;
+        }
+
+        let failure = |fault, message: String| ChainFailure {
+            height: height - 1,
+            fault,
+            message,
+        };
+        if prev_hash != self.tip {
+            let message = format!(
+                "the height index ends at {}, not at the best block {}",
+                prev_hash, self.tip
+            );
+            report.failure = Some(failure(ChainFault::Linkage, message));
+        } else if let Some(replay) = &replay {
+            report.failure = compare_utxo(utxo, replay, &self.tip, height - 1)?
+                .map(|message| failure(ChainFault::UtxoSet, message));
+        }
+        Ok(report)
+    }
+
+    /// 按 check_level 的级别 1 和 2 检查主链上高度为 height 的区块 hash，返回读出的区块
+    fn verify_stored_block(
+        &self,
+        hash: &str,
+        height: i32,
+        prev_hash: &str,
+        check_level: u8,
+    ) -> std::result::Result<Block, (ChainFault, failure::Error)> {
+        let block = self
+            .get_block(hash)
+            .map_err(|e| (ChainFault::Unreadable, e))?;
+        let linkage = |err| Err((ChainFault::Linkage, err));
+        if block.get_hash() != hash {
+            return linkage(format_err!(
+                "block stored as {} has hash {}",
+                hash,
+                block.get_hash()
+            ));
+        }
+        if block.get_height() != height {
+            return linkage(format_err!(
+                "block {} records height {}",
+                hash,
+                block.get_height()
+            ));
+        }
+        if block.get_prev_hash() != prev_hash {
+            return linkage(format_err!(
+                "block {} follows {}, not {}",
+                hash,
+                block.get_prev_hash(),
+                prev_hash
+            ));
+        }
+        self.check_block_difficulty(&block)
+            .map_err(|e| (ChainFault::ProofOfWork, e))?;
+        if check_level >= 2 {
+            for tx in block.get_transaction() {
+                tx.validate_id().map_err(|e| {
+                    (
+                        ChainFault::Transactions,
+                        format_err!("transaction {}: {}", tx.id, e),
+                    )
+                })?;
+            }
+            block
+                .verify_merkle_root()
+                .map_err(|e| (ChainFault::Transactions, e))?;
+        }
+        Ok(block)
+    }
+
     /// MineBlock 挖矿生成新区块
     pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
+        let template = self.block_template(transactions)?;
+        self.mine_template(template)
+    }
+
+    /// MineCommittedBlock 挖矿生成区块头包含 UTXO 承诺哈希的新区块
+    #[cfg(feature = "utxo-commitment")]
+    pub fn mine_committed_block(
+        &mut self,
+        transactions: Vec<Transaction>,
+        utxo_commitment: [u8; 32],
+    ) -> Result<Block> {
+        let template = self.committed_block_template(transactions, utxo_commitment)?;
+        self.mine_template(template)
+    }
+
+    /// 对区块模板做工作量证明并保存为最新区块
+    fn mine_template(&mut self, template: Block) -> Result<Block> {
         info!("mine a new block");
+        let newblock = template.mine_with_abort(self.mining_threads, &AtomicBool::new(false))?;
+        let hash = newblock.get_hash();
+        let mut batch = Batch::default();
+        batch.put(DEFAULT_TREE, &hash, serialize(&newblock)?);
+        batch.put(HEADER_TREE, &hash, serialize(newblock.header())?);
+        self.db.batch(batch)?;
+        let mut batch = self.index_heights(&hash)?;
+        batch.put(DEFAULT_TREE, b"LAST", &hash);
+        self.db.batch(batch)?;
+        self.db.flush()?;
+
+        self.tip = newblock.get_hash();
+        Ok(newblock)
+    }
+
+    /// BlockTemplate 校验交易并返回接在最新区块之后、尚未做工作量证明的区块
+    ///
+    /// 挖出后用 add_block 保存，此前最新区块已改变时区块不再接在最新区块之后
+    pub fn block_template(&self, transactions: Vec<Transaction>) -> Result<Block> {
+        self.build_block(transactions, Block::new_template)
+    }
+
+    /// CommittedBlockTemplate 与 block_template 相同，区块头包含 UTXO 承诺哈希
+    #[cfg(feature = "utxo-commitment")]
+    pub fn committed_block_template(
+        &self,
+        transactions: Vec<Transaction>,
+        utxo_commitment: [u8; 32],
+    ) -> Result<Block> {
+        self.build_block(
+            transactions,
+            |transactions, prev_block_hash, height, bits, min_timestamp| {
+                Block::new_committed_template(
+                    transactions,
+                    prev_block_hash,
+                    height,
+                    bits,
+                    min_timestamp,
+                    utxo_commitment,
+                )
+            },
+        )
+    }
+
+    /// 校验交易后用 new_template 生成接在最新区块之后的区块模板
+    ///
+    /// 交易可以花费区块内前面交易的输出。模板的时间戳不早于最新区块的 median_time_past 加 1，满足 check_block_timestamp
+    fn build_block(
+        &self,
+        transactions: Vec<Transaction>,
+        new_template: impl FnOnce(Vec<Transaction>, String, i32, u32, u128) -> Result<Block>,
+    ) -> Result<Block> {
+        // 区块以唯一的创币交易开头
+        match transactions.first() {
+            Some(tx) if tx.is_coinbase() => {}
+            Some(tx) => {
+                return Err(format_err!(
+                    "ERROR: The first transaction {} is not a coinbase",
+                    tx.id
+                ));
+            }
+            None => return Err(format_err!("ERROR: Block has no coinbase")),
+        }
+        if let Some((index, tx)) = transactions
+            .iter()
+            .enumerate()
+            .skip(1)
+            .find(|(_, tx)| tx.is_coinbase())
+        {
+            return Err(format_err!(
+                "ERROR: Unexpected coinbase {} at index {}",
+                tx.id,
+                index
+            ));
+        }
 
+        let height = self.get_best_height()? + 1;
+        let mut fees: u64 = 0;
+        let mut reward: u64 = 0;
+        let mut spent = HashSet::new();
+        let mut checks = Vec::new();
+        // 区块内前面的交易，后面的交易可以花费它们的输出
+        let mut earlier: HashMap<&str, &Transaction> = HashMap::new();
         for tx in &transactions {
-            if !self.verify_transacton(tx)? {
-                return Err(format_err!("ERROR: Invalid transaction"));
+            if !tx.is_final(height) {
+                return Err(format_err!(
+                    "ERROR: Transaction {} is locked until height {}",
+                    tx.id,
+                    tx.lock_until
+                ));
+            }
+            let mut prev_txs = if tx.is_coinbase() {
+                HashMap::new()
+            } else {
+                self.get_prev_txs(tx)?
+            };
+            for outpoint in tx.outpoints() {
+                if let Some(prev_tx) = earlier.get(outpoint.txid.as_str()) {
+                    prev_txs.insert(prev_tx.id.clone(), (*prev_tx).clone());
+                }
+            }
+            match tx.prepare_verify(&prev_txs) {
+                Ok(tx_checks) => checks.extend(tx_checks),
+                Err(err) => {
+                    error!("reject transaction {}: {}", tx.id, err);
+                    return Err(format_err!("ERROR: Invalid transaction {}: {}", tx.id, err));
+                }
             }
+            for outpoint in tx.outpoints() {
+                if !spent.insert(outpoint) {
+                    return Err(format_err!(
+                        "ERROR: Transaction {} double spends {} in the block",
+                        tx.id,
+                        outpoint
+                    ));
+                }
+            }
+
+            let overflow = || format_err!("ERROR: Block value overflows");
+            if tx.is_coinbase() {
+                reward = reward
+                    .checked_add(tx.output_value()?)
+                    .ok_or_else(overflow)?;
+            } else {
+                fees = fees.checked_add(tx.fee(&prev_txs)?).ok_or_else(overflow)?;
+            }
+            earlier.insert(&tx.id, tx);
         }
 
-        let lasthash = self.db.get("LAST")?.unwrap();
+        if let Err(check) = verify_signatures(&checks, checks.len() >= PARALLEL_VERIFY_MIN) {
+            error!("reject transaction {}: {}", check.txid(), check.error());
+            return Err(format_err!(
+                "ERROR: Invalid transaction {}: {}",
+                check.txid(),
+                check.error()
+            ));
+        }
 
-        let newblock = Block::new_block(
-            transactions,
-            String::from_utf8(lasthash.to_vec())?,
-            self.get_best_height()? + 1,
-        )?;
-        self.db.insert(newblock.get_hash(), serialize(&newblock)?)?;
-        self.db.insert("LAST", newblock.get_hash().as_bytes())?;
-        self.db.flush()?;
+        // 创币交易最多领取区块补贴加上区块内交易的手续费
+        let subsidy = block_subsidy(height);
+        if reward > subsidy.saturating_add(fees) {
+            return Err(format_err!(
+                "ERROR: Coinbase reward {} exceeds subsidy {} plus fees {}",
+                reward,
+                subsidy,
+                fees
+            ));
+        }
 
-        self.tip = newblock.get_hash();
-        Ok(newblock)
+        let lasthash = String::from_utf8(self.db.get(DEFAULT_TREE, b"LAST")?.unwrap())?;
+        let bits = self.next_bits(&lasthash)?;
+        let min_timestamp = self
+            .median_time_past(&lasthash)?
+            .map_or(0, |median| median + 1);
+        let template = new_template(transactions, lasthash, height, bits, min_timestamp)?;
+        template.check_size()?;
+        Ok(template)
     }
 
     /// Iterator 返回区块链迭代器
-    pub fn iter(&self) -> BlockchainIterator {
+    pub fn iter(&self) -> BlockchainIterator<'_> {
         BlockchainIterator {
             current_hash: self.tip.clone(),
-            bc: &self,
+            bc: self,
         }
     }
 
     /// FindUTXO 查找所有未使用的交易输出
-    pub fn find_utxo(&self) -> HashMap<String, TXOutputs> {
-        let mut utxos: HashMap<String, TXOutputs> = HashMap::new();
-        let mut spend_txos: HashMap<String, Vec<i32>> = HashMap::new();
+    pub fn find_utxo(&self) -> HashMap<OutPoint, UTXOEntry> {
+        let mut utxos: HashMap<OutPoint, UTXOEntry> = HashMap::new();
+        let mut spend_txos: HashSet<OutPoint> = HashSet::new();
 
         for block in self.iter() {
-            for tx in block.get_transaction() {
+            // 倒序处理，同一区块内后面的交易花费前面交易的输出时也能排除
+            for tx in block.get_transaction().iter().rev() {
                 for index in 0..tx.vout.len() {
-                    if let Some(ids) = spend_txos.get(&tx.id) {
-                        if ids.contains(&(index as i32)) {
-                            continue;
-                        }
+                    if tx.vout[index].is_data() {
+                        continue;
                     }
-
-                    match utxos.get_mut(&tx.id) {
-                        Some(v) => {
-                            v.outputs.push(tx.vout[index].clone());
-                        }
-                        None => {
-                            utxos.insert(
-                                tx.id.clone(),
-                                TXOutputs {
-                                    outputs: vec![tx.vout[index].clone()],
-                                },
-                            );
-                        }
+                    let outpoint = OutPoint::new(&tx.id, index as u32);
+                    if spend_txos.contains(&outpoint) {
+                        continue;
                     }
-                }
 
-                if !tx.is_coinbase() {
-                    for i in &tx.vin {
-                        match spend_txos.get_mut(&i.txid) {
-                            Some(v) => {
-                                v.push(i.vout);
-                            }
-                            None => {
-                                spend_txos.insert(i.txid.clone(), vec![i.vout]);
-                            }
-                        }
-                    }
+                    utxos.insert(
+                        outpoint,
+                        UTXOEntry {
+                            output: tx.vout[index].clone(),
+                            coinbase: tx.is_coinbase(),
+                            height: block.get_height(),
+                        },
+                    );
                 }
+
+                spend_txos.extend(tx.outpoints().cloned());
             }
         }
 
         utxos
     }
 
-    /// FindTransaction 通过ID查找交易
+    /// FindTransaction 通过ID查找主链上的交易
     pub fn find_transacton(&self, id: &str) -> Result<Transaction> {
-        for b in self.iter() {
-            for tx in b.get_transaction() {
-                if tx.id == id {
-                    return Ok(tx.clone());
-                }
+        self.get_transaction(id)?
+            .map(|(tx, _, _)| tx)
+            .ok_or_else(|| format_err!("Transaction is not found"))
+    }
+
+    /// GetTransaction 查找主链上的交易，返回交易、所在区块的哈希和高度
+    ///
+    /// 有交易索引时直接读取所在区块；索引中没有或指向已不在主链上的区块时，
+    /// 与没有索引时一样从最新区块向前逐个扫描
+    pub fn get_transaction(&self, txid: &str) -> Result<Option<(Transaction, String, i32)>> {
+        if self.has_tx_index()?
+            && let Some(found) = self.indexed_transaction(txid)?
+        {
+            return Ok(Some(found));
+        }
+        for block in self.iter() {
+            if let Some(tx) = block.get_transaction().iter().find(|tx| tx.id == txid) {
+                return Ok(Some((tx.clone(), block.get_hash(), block.get_height())));
             }
         }
-        Err(format_err!("Transaction is not found"))
+        Ok(None)
+    }
+
+    fn indexed_transaction(&self, txid: &str) -> Result<Option<(Transaction, String, i32)>> {
+        let Some(value) = self.db.get(TX_TREE, txid.as_bytes())? else {
+            return Ok(None);
+        };
+        let (hash, index): (String, u32) = deserialize(&value).map_err(|e| {
+            format_err!(
+                "Invalid transaction index entry {}, run reindex-tx: {}",
+                txid,
+                e
+            )
+        })?;
+        let block = self.get_block(&hash)?;
+        let height = block.get_height();
+        if self.db.get(HEIGHT_TREE, &height_key(height))?.as_deref() != Some(hash.as_bytes()) {
+            return Ok(None);
+        }
+        Ok(block
+            .get_transaction()
+            .get(index as usize)
+            .filter(|tx| tx.id == txid)
+            .map(|tx| (tx.clone(), hash, height)))
+    }
+
+    /// HasTxIndex 是否已建立交易索引
+    pub fn has_tx_index(&self) -> Result<bool> {
+        Ok(self.db.get(META_TREE, TX_INDEX_KEY)?.is_some())
+    }
+
+    /// ReindexTransactions 按主链重建交易索引，返回索引的交易数，progress 报告已处理的区块数
+    ///
+    /// 重建期间去掉索引标记，中途中断时查找退回扫描，直到下一次重建完成
+    pub fn reindex_transactions(&self, progress: impl Fn(usize, usize)) -> Result<usize> {
+        self.db.delete(META_TREE, TX_INDEX_KEY)?;
+        self.db.clear(TX_TREE)?;
+        let total = (self.get_best_height()? + 1) as usize;
+        progress(0, total);
+        let mut indexed = 0;
+        let mut batch = Batch::default();
+        for height in 0..total {
+            let block = self.get_block_by_height(height as i32)?;
+            for (index, tx) in block.get_transaction().iter().enumerate() {
+                batch.put(
+                    TX_TREE,
+                    &tx.id,
+                    serialize(&(block.get_hash(), index as u32))?,
+                );
+                indexed += 1;
+            }
+            if (height + 1) % TX_INDEX_BATCH_BLOCKS == 0 {
+                self.db.batch(std::mem::take(&mut batch))?;
+            }
+            progress(height + 1, total);
+        }
+        batch.put(META_TREE, TX_INDEX_KEY, b"");
+        self.db.batch(batch)?;
+        self.db.flush()?;
+        Ok(indexed)
+    }
+
+    /// IndexTransactions 把连接到主链的区块中的交易加入交易索引，没有索引时什么也不做
+    pub fn index_transactions(&self, block: &Block) -> Result<()> {
+        if !self.has_tx_index()? {
+            return Ok(());
+        }
+        let hash = block.get_hash();
+        let mut batch = Batch::default();
+        for (index, tx) in block.get_transaction().iter().enumerate() {
+            batch.put(TX_TREE, &tx.id, serialize(&(&hash, index as u32))?);
+        }
+        self.db.batch(batch)
+    }
+
+    /// UnindexTransactions 从交易索引中删除断开的区块中的交易，没有索引时什么也不做
+    ///
+    /// 只删除指向该区块的条目，同一笔交易已被另一个区块重新索引时保留
+    pub fn unindex_transactions(&self, block: &Block) -> Result<()> {
+        if !self.has_tx_index()? {
+            return Ok(());
+        }
+        let hash = block.get_hash();
+        let mut batch = Batch::default();
+        for tx in block.get_transaction() {
+            let Some(value) = self.db.get(TX_TREE, tx.id.as_bytes())? else {
+                continue;
+            };
+            if deserialize::<(String, u32)>(&value).is_ok_and(|(indexed, _)| indexed == hash) {
+                batch.delete(TX_TREE, &tx.id);
+            }
+        }
+        self.db.batch(batch)
     }
     /// GetPrevTXs 获取前序交易
-    fn get_prev_txs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
+    ///
+    /// 链上找不到的前序交易不放入结果，留给验证报告具体缺少哪一笔
+    pub fn get_prev_txs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
         let mut prev_txs = HashMap::new();
         for vin in &tx.vin {
-            let prev_tx = self.find_transacton(&vin.txid)?;
-            prev_txs.insert(prev_tx.id.clone(), prev_tx);
+            if let Ok(prev_tx) = self.find_transacton(&vin.outpoint.txid) {
+                prev_txs.insert(prev_tx.id.clone(), prev_tx);
+            }
         }
         Ok(prev_txs)
     }
 
-    /// SignTransaction 对交易输入进行签名
-    pub fn sign_transacton(&self, tx: &mut Transaction, private_key: &[u8]) -> Result<()> {
+    /// VerifyTransaction 验证交易，失败原因为 TxVerifyError
+    pub fn verify_transacton(&self, tx: &Transaction) -> Result<()> {
+        if tx.is_coinbase() {
+            return Ok(());
+        }
         let prev_txs = self.get_prev_txs(tx)?;
-        tx.sign(private_key, prev_txs)?;
+        tx.verify(prev_txs)?;
         Ok(())
     }
 
-    /// VerifyTransaction 验证交易签名
-    pub fn verify_transacton(&self, tx: &Transaction) -> Result<bool> {
+    /// GetTxFee 计算交易支付的手续费
+    pub fn get_tx_fee(&self, tx: &Transaction) -> Result<u64> {
         if tx.is_coinbase() {
-            return Ok(true);
+            return Ok(0);
         }
         let prev_txs = self.get_prev_txs(tx)?;
-        tx.verify(prev_txs)
+        tx.fee(&prev_txs)
     }
 
-    /// AddBlock 将区块添加到区块链
+    /// AddBlock 将区块添加到区块链，区块所在分支的累计工作量超过当前最新区块时成为最新区块
+    ///
+    /// 工作量相同时保留先收到的分支
     pub fn add_block(&mut self, block: Block) -> Result<()> {
-        let data = serialize(&block)?;
-        if let Some(_) = self.db.get(block.get_hash())? {
+        self.store_block(&block)?;
+        if self.chain_work(&block.get_hash())? > self.chain_work(&self.tip)? {
+            self.set_tip(&block.get_hash())?;
+        }
+        Ok(())
+    }
+
+    /// StoreBlock 保存区块及其累计工作量，不改变最新区块；区块已存在时什么也不做
+    ///
+    /// 侧链上的区块也被保存，侧链的工作量超过主链时可以切换过去
+    pub fn store_block(&self, block: &Block) -> Result<()> {
+        let hash = block.get_hash();
+        if self.db.get(DEFAULT_TREE, hash.as_bytes())?.is_some() {
             return Ok(());
         }
-        self.db.insert(block.get_hash(), data)?;
+        let work = self
+            .chain_work(&block.get_prev_hash())?
+            .saturating_add(block_work(block.get_bits()));
+        let mut batch = Batch::default();
+        batch.put(DEFAULT_TREE, &hash, serialize(block)?);
+        batch.put(HEADER_TREE, &hash, serialize(block.header())?);
+        batch.put(WORK_TREE, &hash, work.to_be_bytes());
+        self.db.batch(batch)
+    }
+
+    /// AddHeader 验证并保存收到的区块头，返回区块头的哈希；区块头已知时什么也不做
+    ///
+    /// 区块头须接在已知的区块头之后，并满足工作量证明、难度、时间戳和检查点的规则，
+    /// 验证不需要区块中的交易。所在分支的累计工作量超过最好的区块头时成为最好的区块头
+    pub fn add_header(&self, header: &BlockHeader, now: u128) -> Result<String> {
+        let hash = header.check_proof_of_work()?;
+        if self.get_header(&hash).is_ok() {
+            return Ok(hash);
+        }
+        if header.prev_hash.is_empty() {
+            return Err(format_err!("Header {} is a different genesis block", hash));
+        }
+        let prev = self.get_header(&header.prev_hash).map_err(|_| {
+            format_err!("Header {} follows unknown block {}", hash, header.prev_hash)
+        })?;
+        if header.height != prev.height + 1 {
+            return Err(format_err!(
+                "Header {} has height {}, expected {}",
+                hash,
+                header.height,
+                prev.height + 1
+            ));
+        }
+        self.check_header_difficulty(header, &hash)?;
+        self.check_header_timestamp(header, &hash, now)?;
+        self.check_checkpoint(header.height, &hash)?;
+
+        let work = self
+            .chain_work(&header.prev_hash)?
+            .saturating_add(block_work(header.get_bits()));
+        let mut batch = Batch::default();
+        batch.put(HEADER_TREE, &hash, serialize(header)?);
+        batch.put(WORK_TREE, &hash, work.to_be_bytes());
+        if work > self.chain_work(&self.best_header()?.0)? {
+            batch.put(META_TREE, BEST_HEADER_KEY, &hash);
+        }
+        self.db.batch(batch)?;
+        Ok(hash)
+    }
+
+    /// GetHeader 通过哈希查找区块头，没有单独保存区块头的旧区块从区块中读出
+    pub fn get_header(&self, hash: &str) -> Result<BlockHeader> {
+        if let Some(data) = self.db.get(HEADER_TREE, hash.as_bytes())?
+            && let Ok(header) = deserialize(&data)
+        {
+            return Ok(header);
+        }
+        match self.get_block(hash) {
+            Ok(block) => Ok(block.header().clone()),
+            Err(_) => Err(format_err!("Header {} not found", hash)),
+        }
+    }
+
+    /// BestHeader 返回累计工作量最大的区块头及其哈希，工作量不超过最新区块时即最新区块
+    pub fn best_header(&self) -> Result<(String, BlockHeader)> {
+        let hash = match self.db.get(META_TREE, BEST_HEADER_KEY)? {
+            Some(best) => {
+                let best = String::from_utf8(best)?;
+                if self.chain_work(&best)? > self.chain_work(&self.tip)? {
+                    best
+                } else {
+                    self.tip.clone()
+                }
+            }
+            None => self.tip.clone(),
+        };
+        let header = self.get_header(&hash)?;
+        Ok((hash, header))
+    }
 
-        let lastheight = self.get_best_height()?;
-        if block.get_height() > lastheight {
-            self.db.insert("LAST", block.get_hash().as_bytes())?;
-            self.tip = block.get_hash();
-            self.db.flush()?;
+    /// HeaderLocator 从最好的区块头回溯，返回向其他节点请求后续区块头的定位哈希
+    ///
+    /// 最近的 10 个区块头逐个列出，之后间隔逐次加倍，最后一个是创世区块；
+    /// 对方从其中第一个在它主链上的区块之后开始发送
+    pub fn header_locator(&self) -> Result<Vec<String>> {
+        let (mut hash, mut header) = self.best_header()?;
+        let mut locator = Vec::new();
+        let mut step = 1;
+        loop {
+            locator.push(hash.clone());
+            if header.prev_hash.is_empty() {
+                return Ok(locator);
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            for _ in 0..step {
+                if header.prev_hash.is_empty() {
+                    break;
+                }
+                hash = header.prev_hash.clone();
+                header = self.get_header(&hash)?;
+            }
+        }
+    }
+
+    /// HeadersAfter 返回主链上接在 locator 中第一个主链区块之后的至多 max 个区块头
+    ///
+    /// locator 中没有主链上的区块时从创世区块开始
+    pub fn headers_after(&self, locator: &[String], max: usize) -> Result<Vec<BlockHeader>> {
+        let mut start = 0;
+        for hash in locator {
+            if let Ok(header) = self.get_header(hash)
+                && self
+                    .db
+                    .get(HEIGHT_TREE, &height_key(header.height))?
+                    .as_deref()
+                    == Some(hash.as_bytes())
+            {
+                start = header.height + 1;
+                break;
+            }
+        }
+        let mut headers = Vec::new();
+        for height in (start..=self.get_best_height()?).take(max) {
+            let hash = self
+                .db
+                .get(HEIGHT_TREE, &height_key(height))?
+                .ok_or_else(|| format_err!("No block at height {} in the height index", height))?;
+            headers.push(self.get_header(&String::from_utf8(hash)?)?);
+        }
+        Ok(headers)
+    }
+
+    /// MissingBlocks 返回最好的区块头所在分支上尚未下载的区块哈希，按高度从低到高排列
+    pub fn missing_blocks(&self) -> Result<Vec<String>> {
+        let (mut hash, mut header) = self.best_header()?;
+        let mut missing = Vec::new();
+        while self.db.get(DEFAULT_TREE, hash.as_bytes())?.is_none() {
+            missing.push(hash);
+            hash = header.prev_hash.clone();
+            header = self.get_header(&hash)?;
+        }
+        missing.reverse();
+        Ok(missing)
+    }
+
+    /// CheckAgainstHeader 检查收到的区块的交易与已验证的同一哈希的区块头的默克尔根一致
+    ///
+    /// 没有收到过区块头时不检查
+    pub fn check_against_header(&self, block: &Block) -> Result<()> {
+        let hash = block.get_hash();
+        let Ok(header) = self.get_header(&hash) else {
+            return Ok(());
+        };
+        if header.merkle_root != merkle_root(&block.transaction_ids()) {
+            return Err(format_err!(
+                "Block {} does not match the merkle root {} of its header",
+                hash,
+                header.merkle_root
+            ));
         }
         Ok(())
     }
 
+    /// ChainWork 返回从创世区块到区块 hash 的累计工作量，hash 为空时为 0
+    ///
+    /// 旧版本保存的区块没有记录，从最近有记录的祖先开始补齐
+    pub fn chain_work(&self, hash: &str) -> Result<u128> {
+        let mut missing = Vec::new();
+        let mut current = hash.to_string();
+        let mut work = 0;
+        while !current.is_empty() {
+            if let Some(value) = self.db.get(WORK_TREE, current.as_bytes())? {
+                let bytes = value
+                    .try_into()
+                    .map_err(|_| format_err!("Invalid chain work of block {}", current))?;
+                work = u128::from_be_bytes(bytes);
+                break;
+            }
+            let header = self.get_header(&current)?;
+            let prev_hash = header.prev_hash.clone();
+            missing.push((current, header));
+            current = prev_hash;
+        }
+        if missing.is_empty() {
+            return Ok(work);
+        }
+        let mut batch = Batch::default();
+        for (hash, header) in missing.iter().rev() {
+            work = work.saturating_add(block_work(header.get_bits()));
+            batch.put(WORK_TREE, hash, work.to_be_bytes());
+        }
+        self.db.batch(batch)?;
+        Ok(work)
+    }
+
+    /// SetTip 把已保存的区块 hash 设为最新区块
+    ///
+    /// 新的最新区块可能在另一条分支上，高度索引一起切换；比它高的旧索引被删除
+    pub fn set_tip(&mut self, hash: &str) -> Result<()> {
+        let height = self.get_block(hash)?.get_height();
+        let mut batch = self.index_heights(hash)?;
+        for above in height + 1..=self.get_best_height()? {
+            batch.delete(HEIGHT_TREE, height_key(above));
+        }
+        batch.put(DEFAULT_TREE, b"LAST", hash);
+        self.db.batch(batch)?;
+        self.tip = hash.to_string();
+        self.db.flush()
+    }
+
     /// GetBlock 通过哈希查找区块
     pub fn get_block(&self, block_hash: &str) -> Result<Block> {
-        let data = self.db.get(block_hash)?.unwrap();
-        let block = deserialize(&data.to_vec())?;
-        Ok(block)
+        let data = self
+            .db
+            .get(DEFAULT_TREE, block_hash.as_bytes())?
+            .ok_or_else(|| format_err!("Block {} not found", block_hash))?;
+        Block::decode(&data)
+    }
+
+    /// GetBlockByHeight 通过高度查找主链上的区块
+    pub fn get_block_by_height(&self, height: i32) -> Result<Block> {
+        let best = self.get_best_height()?;
+        if height < 0 || height > best {
+            return Err(format_err!(
+                "Block height {} is out of range, the best height is {}",
+                height,
+                best
+            ));
+        }
+        let hash = self
+            .db
+            .get(HEIGHT_TREE, &height_key(height))?
+            .ok_or_else(|| format_err!("No block at height {} in the height index", height))?;
+        self.get_block(&String::from_utf8(hash)?)
+    }
+
+    /// NextBits 返回接在 prev_hash 之后的区块应使用的难度，prev_hash 为空时返回创世区块的难度
+    ///
+    /// 高度为 RETARGET_INTERVAL 的倍数时，按此前 RETARGET_INTERVAL 个区块从第一个到最后一个的
+    /// 时间间隔调整父区块的难度，其余区块沿用父区块的难度；回归测试网从不调整难度
+    pub fn next_bits(&self, prev_hash: &str) -> Result<u32> {
+        let network = self.network;
+        if prev_hash.is_empty() {
+            return Ok(network.initial_bits());
+        }
+        let prev = self.get_header(prev_hash)?;
+        if !network.retargets() || (prev.height + 1) % RETARGET_INTERVAL != 0 {
+            return Ok(prev.get_bits());
+        }
+        let first = self
+            .header_ancestors(prev_hash, RETARGET_INTERVAL as usize)?
+            .pop()
+            .filter(|header| header.height == prev.height + 1 - RETARGET_INTERVAL)
+            .ok_or_else(|| format_err!("Missing ancestors of block {}", prev_hash))?;
+        let actual = prev.timestamp.saturating_sub(first.timestamp);
+        let expected = (RETARGET_INTERVAL as u128 - 1) * TARGET_BLOCK_TIME;
+        Ok(retarget(prev.get_bits(), actual, expected))
+    }
+
+    /// MedianTimePast 返回 prev_hash 及其之前共 MEDIAN_TIME_SPAN 个区块时间戳的中位数
+    ///
+    /// 不足 MEDIAN_TIME_SPAN 个区块时取已有区块的中位数，偶数个时取较大的一个；prev_hash 为空时返回 None
+    pub fn median_time_past(&self, prev_hash: &str) -> Result<Option<u128>> {
+        if prev_hash.is_empty() {
+            return Ok(None);
+        }
+        let mut timestamps: Vec<u128> = self
+            .header_ancestors(prev_hash, MEDIAN_TIME_SPAN)?
+            .iter()
+            .map(|header| header.timestamp)
+            .collect();
+        timestamps.sort_unstable();
+        Ok(Some(timestamps[timestamps.len() / 2]))
+    }
+
+    /// CheckBlockTimestamp 检查收到的区块的时间戳晚于父区块的 median_time_past，
+    /// 且比本地时钟 now 超前不超过 MAX_FUTURE_BLOCK_TIME
+    pub fn check_block_timestamp(&self, block: &Block, now: u128) -> Result<()> {
+        self.check_header_timestamp(block.header(), &block.get_hash(), now)
+    }
+
+    fn check_header_timestamp(&self, header: &BlockHeader, hash: &str, now: u128) -> Result<()> {
+        let timestamp = header.timestamp;
+        if let Some(median) = self.median_time_past(&header.prev_hash)?
+            && timestamp <= median
+        {
+            return Err(format_err!(
+                "Block {} has timestamp {}, not after the median time {} of the previous blocks",
+                hash,
+                timestamp,
+                median
+            ));
+        }
+        if timestamp > now.saturating_add(MAX_FUTURE_BLOCK_TIME) {
+            return Err(format_err!(
+                "Block {} has timestamp {}, more than two hours ahead of the local clock {}",
+                hash,
+                timestamp,
+                now
+            ));
+        }
+        Ok(())
+    }
+
+    /// CheckBlockDifficulty 检查收到的区块使用了接在父区块之后应有的难度，并满足工作量证明
+    ///
+    /// 父区块必须已经在本地，区块的高度须比父区块高一；激活高度之前，
+    /// 记录难度之前的旧区块只能接在旧区块之后
+    pub fn check_block_difficulty(&self, block: &Block) -> Result<()> {
+        let prev_hash = block.get_prev_hash();
+        if !prev_hash.is_empty() {
+            let prev = self.get_header(&prev_hash)?;
+            if block.get_height() != prev.height + 1 {
+                return Err(format_err!(
+                    "Block {} has height {}, expected {}",
+                    block.get_hash(),
+                    block.get_height(),
+                    prev.height + 1
+                ));
+            }
+        }
+        self.check_header_difficulty(block.header(), &block.get_hash())?;
+        block.check_proof_of_work()
+    }
+
+    /// 检查区块头使用了接在父区块之后应有的难度，不检查工作量证明
+    fn check_header_difficulty(&self, header: &BlockHeader, hash: &str) -> Result<()> {
+        let prev_hash = &header.prev_hash;
+        if !header.records_bits() {
+            if header.height >= self.bits_activation {
+                return Err(format_err!(
+                    "Block {} at height {} does not record its difficulty, required from height {}",
+                    hash,
+                    header.height,
+                    self.bits_activation
+                ));
+            }
+            if !prev_hash.is_empty() && self.get_header(prev_hash)?.records_bits() {
+                return Err(format_err!(
+                    "Block {} without difficulty follows a block with one",
+                    hash
+                ));
+            }
+        } else {
+            let expected = self.next_bits(prev_hash)?;
+            if header.get_bits() != expected {
+                return Err(format_err!(
+                    "Block {} has difficulty {:08x}, expected {:08x}",
+                    hash,
+                    header.get_bits(),
+                    expected
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// ValidateBlock 对照父区块上的UTXO集合完整验证区块中的交易，通过后区块才能连接
+    ///
+    /// utxo 必须位于区块的父区块上，区块的高度须比父区块高一。区块以唯一的创币交易开头，激活高度起创币交易须记录
+    /// 区块的实际高度；其余交易的输入须为集合中
+    /// 已成熟的未花费输出或区块内前面交易的输出，激活高度起不能花费区块自己的创币交易，同一输出最多花费一次，签名和金额
+    /// 由 Transaction::prepare_verify 检查；创币交易最多领取补贴加上区块内的手续费。
+    /// 不高于最后一个检查点的区块不验证签名
+    pub fn validate_block(
+        &self,
+        block: &Block,
+        utxo: &UTXOSet,
+    ) -> std::result::Result<(), BlockValidationError> {
+        self.validate_block_with(block, utxo, PARALLEL_VERIFY_MIN)
+    }
+
+    /// 同 validate_block，待验证签名不少于 parallel_min 个时并行验证
+    fn validate_block_with(
+        &self,
+        block: &Block,
+        utxo: &UTXOSet,
+        parallel_min: usize,
+    ) -> std::result::Result<(), BlockValidationError> {
+        let prev = block.get_prev_hash();
+        let (utxo_tip, tip_height) = utxo.tip()?;
+        if utxo_tip != prev {
+            return Err(BlockValidationError::UtxoTipMismatch { prev, utxo_tip });
+        }
+        // 补贴、成熟度、锁定时间和签名检查都依赖区块的高度，不能相信区块自己声明的高度
+        let height = block.get_height();
+        if height != tip_height + 1 {
+            return Err(BlockValidationError::BadHeight {
+                expected: tip_height + 1,
+                found: height,
+            });
+        }
+
+        let transactions = block.get_transaction();
+        if !transactions.first().is_some_and(Transaction::is_coinbase) {
+            return Err(BlockValidationError::NoCoinbase);
+        }
+        if let Some(index) = transactions
+            .iter()
+            .skip(1)
+            .position(Transaction::is_coinbase)
+        {
+            return Err(BlockValidationError::MisplacedCoinbase { index: index + 1 });
+        }
+        if height >= self.coinbase_height_activation {
+            let found = transactions[0].coinbase_height();
+            if found != Some(height) {
+                return Err(BlockValidationError::CoinbaseHeight { height, found });
+            }
+        }
+
+        // 区块内前面的交易，后面的交易可以花费它们的输出
+        let mut earlier: HashMap<&str, &Transaction> = HashMap::new();
+        let mut spent = HashSet::new();
+        // 被花费的集合中输出所属的交易
+        let mut needed: HashSet<&str> = HashSet::new();
+        for tx in transactions {
+            if !tx.is_final(height) {
+                return Err(BlockValidationError::NotFinal {
+                    txid: tx.id.clone(),
+                    lock_until: tx.lock_until,
+                });
+            }
+            for outpoint in tx.outpoints() {
+                if !spent.insert(outpoint) {
+                    return Err(BlockValidationError::DoubleSpend {
+                        txid: tx.id.clone(),
+                        outpoint: outpoint.clone(),
+                    });
+                }
+                // 同一区块的创币交易输出尚未成熟；旧版本的节点挖出的区块会花费自己的创币交易，
+                // 与创币交易记录高度同时开始拒绝
+                if let Some(prev_tx) = earlier.get(outpoint.txid.as_str()) {
+                    if prev_tx.is_coinbase() && height >= self.coinbase_height_activation {
+                        return Err(BlockValidationError::ImmatureInput {
+                            txid: tx.id.clone(),
+                            outpoint: outpoint.clone(),
+                        });
+                    }
+                    continue;
+                }
+                let Some(entry) = utxo.get_entry(outpoint)? else {
+                    return Err(BlockValidationError::MissingInput {
+                        txid: tx.id.clone(),
+                        outpoint: outpoint.clone(),
+                    });
+                };
+                if !entry.is_mature(height - 1) {
+                    return Err(BlockValidationError::ImmatureInput {
+                        txid: tx.id.clone(),
+                        outpoint: outpoint.clone(),
+                    });
+                }
+                needed.insert(outpoint.txid.as_str());
+            }
+            earlier.insert(&tx.id, tx);
+        }
+
+        // 前序交易来自区块内或父区块及其祖先，侧链上的区块也能找到
+        let mut prev_txs: HashMap<String, Transaction> = HashMap::new();
+        let ancestors = BlockchainIterator {
+            current_hash: prev,
+            bc: self,
+        };
+        for ancestor in ancestors {
+            if needed.is_empty() {
+                break;
+            }
+            for tx in ancestor.get_transaction() {
+                if needed.remove(tx.id.as_str()) {
+                    prev_txs.insert(tx.id.clone(), tx.clone());
+                }
+            }
+        }
+
+        let mut checks = Vec::new();
+        let mut fees: u64 = 0;
+        let mut reward: u64 = 0;
+        for tx in transactions {
+            let invalid = |error| BlockValidationError::InvalidTransaction {
+                txid: tx.id.clone(),
+                error,
+            };
+            for outpoint in tx.outpoints() {
+                if let Some(prev_tx) = earlier.get(outpoint.txid.as_str()) {
+                    prev_txs.insert(prev_tx.id.clone(), (*prev_tx).clone());
+                }
+            }
+            checks.extend(tx.prepare_verify(&prev_txs).map_err(invalid)?);
+
+            let outputs = tx.output_value().map_err(invalid)?;
+            if tx.is_coinbase() {
+                reward = outputs;
+                continue;
+            }
+            let inputs = tx.vin.iter().try_fold(0u64, |sum, vin| {
+                let outpoint = &vin.outpoint;
+                let value = prev_txs[&outpoint.txid].vout[outpoint.vout as usize].value;
+                sum.checked_add(value)
+                    .ok_or(BlockValidationError::ValueOverflow)
+            })?;
+            let fee =
+                inputs
+                    .checked_sub(outputs)
+                    .ok_or(invalid(TxVerifyError::OutputsExceedInputs {
+                        inputs,
+                        outputs,
+                    }))?;
+            fees = fees
+                .checked_add(fee)
+                .ok_or(BlockValidationError::ValueOverflow)?;
+        }
+
+        // 最后一个检查点之前的区块由检查点担保，同步时跳过最耗时的签名验证
+        if height > self.last_checkpoint()
+            && let Err(check) = verify_signatures(&checks, checks.len() >= parallel_min)
+        {
+            return Err(BlockValidationError::InvalidTransaction {
+                txid: check.txid().to_string(),
+                error: check.error(),
+            });
+        }
+
+        let subsidy = block_subsidy(height);
+        if reward > subsidy.saturating_add(fees) {
+            return Err(BlockValidationError::ExcessiveCoinbase {
+                reward,
+                subsidy,
+                fees,
+            });
+        }
+        Ok(())
+    }
+
+    /// LastCheckpoint 返回最高的检查点的高度，没有检查点时为 -1
+    pub fn last_checkpoint(&self) -> i32 {
+        self.checkpoints.keys().next_back().copied().unwrap_or(-1)
+    }
+
+    /// CheckCheckpoints 检查收到的区块与同一高度的检查点一致，且不在主链已经到达的
+    /// 最后一个检查点处或之前分叉；已保存的区块不算分叉
+    pub fn check_checkpoints(&self, block: &Block) -> Result<()> {
+        self.check_checkpoint(block.get_height(), &block.get_hash())
+    }
+
+    fn check_checkpoint(&self, height: i32, hash: &str) -> Result<()> {
+        if let Some(checkpoint) = self.checkpoints.get(&height)
+            && *checkpoint != hash
+        {
+            return Err(format_err!(
+                "Block {} at height {} does not match the checkpoint {}",
+                hash,
+                height,
+                checkpoint
+            ));
+        }
+        let best = self.get_best_height()?;
+        if let Some((&checkpoint, _)) = self.checkpoints.range(..=best).next_back()
+            && height <= checkpoint
+            && self.db.get(DEFAULT_TREE, hash.as_bytes())?.is_none()
+        {
+            return Err(format_err!(
+                "Block {} at height {} forks the chain below the checkpoint at height {}",
+                hash,
+                height,
+                checkpoint
+            ));
+        }
+        Ok(())
+    }
+
+    /// GetMerkleProof 生成交易 txid 包含在区块 block_hash 中的默克尔证明
+    pub fn get_merkle_proof(&self, block_hash: &str, txid: &str) -> Result<MerkleProof> {
+        let block = self.get_block(block_hash)?;
+        if block.get_merkle_root().is_empty() {
+            return Err(format_err!(
+                "Block {} was created before merkle roots were recorded",
+                block_hash
+            ));
+        }
+        let txids = block.transaction_ids();
+        let index = txids
+            .iter()
+            .position(|id| *id == txid)
+            .ok_or_else(|| format_err!("Transaction {} is not in block {}", txid, block_hash))?;
+        MerkleProof::new(block_hash, &txids, index)
+    }
+
+    /// 从 hash 开始沿父区块回溯，返回包括它在内的至多 count 个区块头
+    ///
+    /// 沿父区块回溯而不是查高度索引，hash 可能不在主链上，也可能只有区块头
+    fn header_ancestors(&self, hash: &str, count: usize) -> Result<Vec<BlockHeader>> {
+        let mut headers = Vec::new();
+        let mut current = hash.to_string();
+        while headers.len() < count && !current.is_empty() {
+            let header = self.get_header(&current)?;
+            current = header.prev_hash.clone();
+            headers.push(header);
+        }
+        Ok(headers)
+    }
+
+    /// 从 tip 回溯主链到与高度索引一致的区块为止，返回更新高度索引的批次
+    ///
+    /// 新的最新区块低于原来的最新区块时，更高的旧分支索引由调用方删除
+    fn index_heights(&self, tip: &str) -> Result<Batch> {
+        let mut batch = Batch::default();
+        let iter = BlockchainIterator {
+            current_hash: tip.to_string(),
+            bc: self,
+        };
+        for block in iter {
+            let key = height_key(block.get_height());
+            let hash = block.get_hash();
+            if self.db.get(HEIGHT_TREE, &key)?.as_deref() == Some(hash.as_bytes()) {
+                break;
+            }
+            batch.put(HEIGHT_TREE, key, hash);
+        }
+        Ok(batch)
+    }
+
+    /// Flush 把尚未写入磁盘的区块和索引写入磁盘
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()
     }
 
     /// GetBestHeight 获取最新区块高度
     pub fn get_best_height(&self) -> Result<i32> {
-        let lasthash = if let Some(h) = self.db.get("LAST")? {
+        let lasthash = if let Some(h) = self.db.get(DEFAULT_TREE, b"LAST")? {
             h
         } else {
             return Ok(-1);
         };
-        let last_data = self.db.get(lasthash)?.unwrap();
-        let last_block: Block = deserialize(&last_data.to_vec())?;
+        let last_data = self.db.get(DEFAULT_TREE, &lasthash)?.unwrap();
+        let last_block = Block::decode(&last_data)?;
         Ok(last_block.get_height())
     }
 
@@ -226,14 +1670,164 @@ impl Blockchain {
     }
 }
 
+/// 打开数据目录 data_dir 中 network 的区块数据库，其中已有区块链时返回错误
+fn open_new_store(network: Network, data_dir: &Path) -> Result<Arc<dyn KvStore>> {
+    let path = network.data_path(data_dir, "blocks");
+    let db = Network::open_db_at(&path)?;
+    if db.contains_key(b"LAST")? {
+        return Err(format_err!(
+            "A blockchain already exists in {}, remove it or choose another --datadir",
+            path.display()
+        ));
+    }
+    Ok(Arc::new(SledStore::new(db)))
+}
+
+/// 记录创建参数的批次
+fn params_batch(network: Network, genesis: &str, message: &str) -> Batch {
+    let mut batch = Batch::default();
+    batch.put(META_TREE, b"network", network.to_string());
+    batch.put(META_TREE, b"genesis", genesis);
+    batch.put(META_TREE, b"genesis_message", message);
+    batch
+}
+
+/// 读取并检查导出文件的文件头，返回其中的区块数
+fn read_export_header(reader: &mut impl Read, network: Network) -> Result<usize> {
+    let mut magic = [0; 8];
+    read_exported(reader, &mut magic)?;
+    if magic != EXPORT_MAGIC {
+        return Err(format_err!("not a blockchain export"));
+    }
+    let mut word = [0; 4];
+    read_exported(reader, &mut word)?;
+    let version = u32::from_le_bytes(word);
+    if version != EXPORT_VERSION {
+        return Err(format_err!(
+            "unsupported export format version {}, expected {}",
+            version,
+            EXPORT_VERSION
+        ));
+    }
+    let mut len = [0; 1];
+    read_exported(reader, &mut len)?;
+    let mut name = vec![0; len[0] as usize];
+    read_exported(reader, &mut name)?;
+    let exported: Network = String::from_utf8(name)?.parse()?;
+    if exported != network {
+        return Err(format_err!(
+            "the export belongs to {}, not {}",
+            exported,
+            network
+        ));
+    }
+    read_exported(reader, &mut word)?;
+    Ok(u32::from_le_bytes(word) as usize)
+}
+
+/// 读取导出文件中带长度前缀的一个区块，长度超过区块大小上限时不分配内存
+fn read_export_block(reader: &mut impl Read) -> Result<Block> {
+    let mut word = [0; 4];
+    read_exported(reader, &mut word)?;
+    let len = u32::from_le_bytes(word) as usize;
+    if len > MAX_BLOCK_SIZE {
+        return Err(format_err!(
+            "length {} exceeds the block size limit {}",
+            len,
+            MAX_BLOCK_SIZE
+        ));
+    }
+    let mut data = vec![0; len];
+    read_exported(reader, &mut data)?;
+    Block::decode(&data)
+}
+
+fn read_exported(reader: &mut impl Read, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => format_err!("the export is truncated"),
+        _ => e.into(),
+    })
+}
+
+/// 像收到的区块一样检查导入的第 height 个区块，对照 utxo 完整验证后连接为新的最新区块
+fn import_block(utxo: &mut UTXOSet, block: &Block, height: usize) -> Result<()> {
+    if block.get_height() != height as i32 || block.get_prev_hash() != utxo.blockchain.tip {
+        return Err(format_err!(
+            "block {} does not extend the imported chain",
+            block.get_hash()
+        ));
+    }
+    block.check_size()?;
+    block.verify_merkle_root()?;
+    utxo.blockchain.check_block_difficulty(block)?;
+    utxo.blockchain
+        .check_block_timestamp(block, now_millis()?)?;
+    utxo.blockchain.check_checkpoints(block)?;
+    utxo.blockchain.validate_block(block, utxo)?;
+    utxo.blockchain.store_block(block)?;
+    utxo.connect_block(block)?;
+    utxo.blockchain.set_tip(&block.get_hash())
+}
+
+/// 完整验证 block 后把它连接到重放的UTXO集合
+fn replay_block(
+    replay: &UTXOSet,
+    block: &Block,
+) -> std::result::Result<(), (ChainFault, failure::Error)> {
+    replay
+        .blockchain
+        .validate_block(block, replay)
+        .map_err(|e| (ChainFault::Validation, e.into()))?;
+    replay
+        .connect_block(block)
+        .map_err(|e| (ChainFault::Validation, e))
+}
+
+/// 比较存储的UTXO集合与重放到最新区块 tip 的集合，返回第一个差异的说明
+fn compare_utxo(
+    stored: &UTXOSet,
+    replay: &UTXOSet,
+    tip: &str,
+    height: i32,
+) -> Result<Option<String>> {
+    let (stored_tip, stored_height) = stored.tip()?;
+    if stored_tip != tip || stored_height != height {
+        return Ok(Some(format!(
+            "the UTXO set is at block {} height {}, not at the best block",
+            stored_tip, stored_height
+        )));
+    }
+    let diff = stored.diff_against(replay)?;
+    if let Some(outpoint) = diff
+        .missing
+        .first()
+        .or(diff.stale.first())
+        .or(diff.mismatched.first())
+    {
+        return Ok(Some(format!(
+            "{} missing, {} stale and {} mismatched outputs, first {}",
+            diff.missing.len(),
+            diff.stale.len(),
+            diff.mismatched.len(),
+            outpoint
+        )));
+    }
+    Ok(stored.check_index().err().map(|e| e.to_string()))
+}
+
+/// 高度索引的键，大端序使键的顺序与高度一致
+fn height_key(height: i32) -> [u8; 4] {
+    (height as u32).to_be_bytes()
+}
+
 impl<'a> Iterator for BlockchainIterator<'a> {
     type Item = Block;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Ok(encoded_block) = self.bc.db.get(&self.current_hash) {
+        if let Ok(encoded_block) = self.bc.db.get(DEFAULT_TREE, self.current_hash.as_bytes()) {
             return match encoded_block {
                 Some(b) => {
-                    if let Ok(block) = deserialize::<Block>(&b) {
+                    if let Ok(block) = Block::decode(&b) {
                         self.current_hash = block.get_prev_hash();
                         Some(block)
                     } else {
@@ -246,3 +1840,1098 @@ impl<'a> Iterator for BlockchainIterator<'a> {
         None
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wallets::Wallets;
+    use crate::walletstorage::memory::MemoryStorage;
+
+    /// 在 prev 之后追加一个只有创币交易的未挖矿区块
+    fn push_block(bc: &mut Blockchain, to: &str, prev: &Block) -> Block {
+        let height = prev.get_height() + 1;
+        let cbtx =
+            Transaction::new_coinbase(to.to_string(), String::new(), height, 0, Network::Mainnet)
+                .unwrap();
+        let block = Block::new_unmined_block(vec![cbtx], prev.get_hash(), height).unwrap();
+        bc.add_block(block.clone()).unwrap();
+        block
+    }
+
+    #[test]
+    fn test_block_by_height() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let mut bc = Blockchain::create_blockchain_with_store(
+            address.clone(),
+            "",
+            Network::Mainnet,
+            Arc::new(MemoryStore::default()),
+        )
+        .unwrap();
+        let genesis = bc.get_block(&bc.tip).unwrap();
+        let mut main = vec![genesis.clone()];
+        for _ in 0..3 {
+            let block = push_block(&mut bc, &address, main.last().unwrap());
+            main.push(block);
+        }
+        for (height, block) in main.iter().enumerate() {
+            let found = bc.get_block_by_height(height as i32).unwrap();
+            assert_eq!(found.get_hash(), block.get_hash());
+        }
+
+        // 从高度 1 分叉的分支超过主链后，索引切换到新分支
+        let mut fork = vec![main[1].clone()];
+        for _ in 0..3 {
+            let block = push_block(&mut bc, &address, fork.last().unwrap());
+            fork.push(block);
+        }
+        assert_eq!(bc.get_best_height().unwrap(), 4);
+        assert_eq!(
+            bc.get_block_by_height(1).unwrap().get_hash(),
+            main[1].get_hash()
+        );
+        for (block, height) in fork.iter().zip(1..) {
+            assert_eq!(
+                bc.get_block_by_height(height).unwrap().get_hash(),
+                block.get_hash()
+            );
+        }
+
+        let err = bc.get_block_by_height(5).unwrap_err();
+        assert!(err.to_string().contains("out of range"), "{}", err);
+        assert!(bc.get_block_by_height(-1).is_err());
+        let err = bc.get_block("unknown").unwrap_err();
+        assert!(err.to_string().contains("not found"), "{}", err);
+
+        // 没有高度索引的旧数据库在打开时补齐索引
+        bc.db.clear(HEIGHT_TREE).unwrap();
+        let bc = Blockchain::with_store(bc.db.clone(), Network::Mainnet).unwrap();
+        assert_eq!(
+            bc.get_block_by_height(0).unwrap().get_hash(),
+            genesis.get_hash()
+        );
+        assert_eq!(bc.get_block_by_height(4).unwrap().get_hash(), bc.tip);
+    }
+
+    #[test]
+    fn test_difficulty_adjustment() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let mut bc = Blockchain::in_memory();
+        let cbtx = |height| {
+            Transaction::new_coinbase(address.clone(), String::new(), height, 0, Network::Mainnet)
+                .unwrap()
+        };
+        let genesis =
+            Block::new_unmined_block_at(vec![cbtx(0)], String::new(), 0, 0, INITIAL_BITS).unwrap();
+        bc.add_block(genesis).unwrap();
+        // 以固定的时间间隔接上区块，直到下一个区块需要调整难度，返回它的难度
+        let extend = |bc: &mut Blockchain, spacing: u128| -> u32 {
+            loop {
+                let prev = bc.get_block(&bc.tip).unwrap();
+                let height = prev.get_height() + 1;
+                let bits = bc.next_bits(&bc.tip).unwrap();
+                if height % RETARGET_INTERVAL != 0 {
+                    assert_eq!(bits, prev.get_bits());
+                }
+                let timestamp = prev.get_timestamp() + spacing;
+                let block = Block::new_unmined_block_at(
+                    vec![cbtx(height)],
+                    bc.tip.clone(),
+                    height,
+                    timestamp,
+                    bits,
+                )
+                .unwrap();
+                bc.add_block(block).unwrap();
+                if (height + 1) % RETARGET_INTERVAL == 0 {
+                    return bc.next_bits(&bc.tip).unwrap();
+                }
+            }
+        };
+        let close = |bits: u32, want: f64| (difficulty(bits) / want - 1.0).abs() < 1e-4;
+
+        // 出块间隔为期望的一半，难度翻倍
+        let bits = extend(&mut bc, TARGET_BLOCK_TIME / 2);
+        assert_eq!(bc.get_best_height().unwrap(), RETARGET_INTERVAL - 1);
+        assert!(close(bits, 2.0), "{:08x}", bits);
+
+        // 以错误难度挖出的区块被拒绝，即使它满足自己记录的难度
+        let prev = bc.get_block(&bc.tip).unwrap();
+        let wrong = Block::new_unmined_block_at(
+            vec![cbtx(RETARGET_INTERVAL)],
+            bc.tip.clone(),
+            RETARGET_INTERVAL,
+            prev.get_timestamp() + 1,
+            POW_LIMIT_BITS,
+        )
+        .unwrap()
+        .mine()
+        .unwrap();
+        wrong.check_proof_of_work().unwrap();
+        let err = bc.check_block_difficulty(&wrong).unwrap_err();
+        assert!(err.to_string().contains("expected"), "{}", err);
+
+        // 主网拒绝回归测试网难度的区块，创世区块也不例外
+        let regtest = Block::new_unmined_block_at(vec![cbtx(0)], String::new(), 0, 0, REGTEST_BITS)
+            .unwrap()
+            .mine()
+            .unwrap();
+        regtest.check_proof_of_work().unwrap();
+        let err = bc.check_block_difficulty(&regtest).unwrap_err();
+        assert!(err.to_string().contains("expected"), "{}", err);
+
+        // 出块过快时每次最多升高 4 倍
+        let bits = extend(&mut bc, 1);
+        assert!(close(bits, 8.0), "{:08x}", bits);
+
+        // 出块过慢时每次最多降低 4 倍，直到难度下限
+        let mut want = 8.0;
+        let mut bits = bits;
+        while bits != POW_LIMIT_BITS {
+            bits = extend(&mut bc, TARGET_BLOCK_TIME * 100);
+            want /= 4.0;
+            assert!(bits == POW_LIMIT_BITS || close(bits, want), "{:08x}", bits);
+        }
+        assert!(difficulty(POW_LIMIT_BITS) <= want * 4.0);
+
+        // 按要求的难度挖出的区块通过检查
+        let prev = bc.get_block(&bc.tip).unwrap();
+        let height = prev.get_height() + 1;
+        let block = Block::new_unmined_block_at(
+            vec![cbtx(height)],
+            bc.tip.clone(),
+            height,
+            prev.get_timestamp() + 1,
+            bits,
+        )
+        .unwrap()
+        .mine()
+        .unwrap();
+        bc.check_block_difficulty(&block).unwrap();
+        // 区块声明的高度与父区块不相连
+        let skipped = Block::new_unmined_block_at(
+            vec![cbtx(height + 5)],
+            bc.tip.clone(),
+            height + 5,
+            prev.get_timestamp() + 1,
+            bits,
+        )
+        .unwrap()
+        .mine()
+        .unwrap();
+        let err = bc.check_block_difficulty(&skipped).unwrap_err();
+        assert!(err.to_string().contains("height"), "{}", err);
+        let orphan = Block::new_unmined_block_at(vec![cbtx(1)], "unknown".into(), 1, 0, bits)
+            .unwrap()
+            .mine()
+            .unwrap();
+        assert!(bc.check_block_difficulty(&orphan).is_err());
+    }
+
+    #[test]
+    fn test_legacy_bits_activation() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let mut bc = Blockchain::in_memory();
+        bc.bits_activation = 2;
+        let cbtx = |height| {
+            Transaction::new_coinbase(address.clone(), String::new(), height, 0, Network::Mainnet)
+                .unwrap()
+        };
+        let check = |bc: &Blockchain, block: &Block| {
+            bc.check_header_difficulty(block.header(), &block.get_hash())
+        };
+
+        // 激活高度之前不记录难度的旧区块接在旧区块之后
+        let genesis = Block::new_unmined_block_at(vec![cbtx(0)], String::new(), 0, 0, 0).unwrap();
+        check(&bc, &genesis).unwrap();
+        bc.add_block(genesis).unwrap();
+        let legacy = Block::new_unmined_block_at(vec![cbtx(1)], bc.tip.clone(), 1, 1, 0).unwrap();
+        check(&bc, &legacy).unwrap();
+        bc.add_block(legacy).unwrap();
+
+        // 从激活高度起必须记录接在父区块之后应有的难度
+        let legacy = Block::new_unmined_block_at(vec![cbtx(2)], bc.tip.clone(), 2, 2, 0).unwrap();
+        let err = check(&bc, &legacy).unwrap_err();
+        assert!(err.to_string().contains("does not record"), "{}", err);
+        let bits = bc.next_bits(&bc.tip).unwrap();
+        let block = Block::new_unmined_block_at(vec![cbtx(2)], bc.tip.clone(), 2, 2, bits).unwrap();
+        check(&bc, &block).unwrap();
+        let wrong =
+            Block::new_unmined_block_at(vec![cbtx(2)], bc.tip.clone(), 2, 2, REGTEST_BITS).unwrap();
+        assert!(check(&bc, &wrong).is_err());
+    }
+
+    #[test]
+    fn test_block_timestamp() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let mut bc = Blockchain::in_memory();
+        let cbtx = |height| {
+            Transaction::new_coinbase(address.clone(), String::new(), height, 0, Network::Mainnet)
+                .unwrap()
+        };
+        let block_at = |bc: &Blockchain, timestamp: u128| {
+            let height = bc.get_best_height().unwrap() + 1;
+            let prev = if height == 0 {
+                String::new()
+            } else {
+                bc.tip.clone()
+            };
+            Block::new_unmined_block_at(vec![cbtx(height)], prev, height, timestamp, INITIAL_BITS)
+                .unwrap()
+        };
+        assert_eq!(bc.median_time_past("").unwrap(), None);
+
+        // 时间戳乱序的区块，中位数只看最近 MEDIAN_TIME_SPAN 个
+        let timestamps = [5_000, 1_000, 9_000, 3_000, 7_000];
+        for timestamp in timestamps {
+            let block = block_at(&bc, timestamp);
+            bc.add_block(block).unwrap();
+        }
+        assert_eq!(bc.median_time_past(&bc.tip).unwrap(), Some(5_000));
+        for timestamp in (10..22).map(|n| n * 1_000) {
+            let block = block_at(&bc, timestamp);
+            bc.add_block(block).unwrap();
+        }
+        // 最近 11 个区块为 11_000 到 21_000
+        let median = bc.median_time_past(&bc.tip).unwrap().unwrap();
+        assert_eq!(median, 16_000);
+
+        let now = 100_000;
+        let err = bc
+            .check_block_timestamp(&block_at(&bc, median), now)
+            .unwrap_err();
+        assert!(err.to_string().contains("not after the median"), "{}", err);
+        assert!(
+            bc.check_block_timestamp(&block_at(&bc, 1_000), now)
+                .is_err()
+        );
+        bc.check_block_timestamp(&block_at(&bc, median + 1), now)
+            .unwrap();
+        // 早于父区块但晚于中位数的时间戳可以接受
+        bc.check_block_timestamp(&block_at(&bc, 20_000), now)
+            .unwrap();
+
+        let limit = now + MAX_FUTURE_BLOCK_TIME;
+        bc.check_block_timestamp(&block_at(&bc, limit), now)
+            .unwrap();
+        let err = bc
+            .check_block_timestamp(&block_at(&bc, limit + 1), now)
+            .unwrap_err();
+        assert!(err.to_string().contains("two hours ahead"), "{}", err);
+
+        // 创世区块只检查未来时间
+        let empty = Blockchain::in_memory();
+        empty
+            .check_block_timestamp(&block_at(&empty, 0), now)
+            .unwrap();
+
+        // 之前的区块时间戳超前于本地时钟时，新区块的时间戳取中位数加 1
+        let future = now_millis().unwrap() + MAX_FUTURE_BLOCK_TIME / 2;
+        for _ in 0..MEDIAN_TIME_SPAN {
+            let block = block_at(&bc, future);
+            bc.add_block(block).unwrap();
+        }
+        let height = bc.get_best_height().unwrap() + 1;
+        let template = bc.block_template(vec![cbtx(height)]).unwrap();
+        assert_eq!(template.get_timestamp(), future + 1);
+        bc.check_block_timestamp(&template, now_millis().unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let mut bc = Blockchain::in_memory();
+        let txs: Vec<Transaction> = (0..3)
+            .map(|n| {
+                Transaction::new_coinbase(
+                    address.clone(),
+                    format!("tx {}", n),
+                    0,
+                    0,
+                    Network::Mainnet,
+                )
+                .unwrap()
+            })
+            .collect();
+        let block = Block::new_unmined_block(txs.clone(), String::new(), 0).unwrap();
+        bc.add_block(block.clone()).unwrap();
+        for tx in &txs {
+            let proof = bc.get_merkle_proof(&block.get_hash(), &tx.id).unwrap();
+            assert_eq!(proof.block_hash, block.get_hash());
+            assert!(proof.verify(&block.get_merkle_root(), &tx.id));
+        }
+        let err = bc
+            .get_merkle_proof(&block.get_hash(), "missing")
+            .unwrap_err();
+        assert!(err.to_string().contains("is not in block"), "{}", err);
+        assert!(bc.get_merkle_proof("unknown", &txs[0].id).is_err());
+    }
+
+    #[test]
+    fn test_tx_index() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let mut bc = Blockchain::in_memory();
+        let coinbase =
+            Transaction::new_coinbase(address.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        let genesis = Block::new_unmined_block(vec![coinbase], String::new(), 0).unwrap();
+        bc.add_block(genesis.clone()).unwrap();
+        let mut utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        let a = push_block(&mut utxo_set.blockchain, &address, &genesis);
+        utxo_set.connect_block(&a).unwrap();
+
+        let txid = |block: &Block| block.get_transaction()[0].id.clone();
+        let lookup = |bc: &Blockchain, txid: &str| {
+            bc.get_transaction(txid)
+                .unwrap()
+                .map(|(tx, hash, height)| (tx.id, hash, height))
+        };
+        let indexed = |bc: &Blockchain, txid: &str| bc.db.get(TX_TREE, txid.as_bytes()).unwrap();
+
+        // 没有索引时扫描主链，连接区块也不写入索引
+        let bc = &utxo_set.blockchain;
+        assert!(!bc.has_tx_index().unwrap());
+        assert!(indexed(bc, &txid(&a)).is_none());
+        assert_eq!(lookup(bc, &txid(&a)), Some((txid(&a), a.get_hash(), 1)));
+        assert_eq!(lookup(bc, "missing"), None);
+        assert!(bc.find_transacton("missing").is_err());
+
+        assert_eq!(bc.reindex_transactions(|_, _| {}).unwrap(), 2);
+        assert!(bc.has_tx_index().unwrap());
+        assert!(indexed(bc, &txid(&genesis)).is_some());
+        assert_eq!(
+            lookup(bc, &txid(&genesis)),
+            Some((txid(&genesis), genesis.get_hash(), 0))
+        );
+
+        // 连接的区块加入索引，断开后删除
+        let b = push_block(&mut utxo_set.blockchain, &address, &a);
+        utxo_set.connect_block(&b).unwrap();
+        let bc = &utxo_set.blockchain;
+        assert!(indexed(bc, &txid(&b)).is_some());
+        assert_eq!(lookup(bc, &txid(&b)), Some((txid(&b), b.get_hash(), 2)));
+        let undo = utxo_set.get_block_undo(&b.get_hash()).unwrap();
+        utxo_set.disconnect_block(&b, &undo).unwrap();
+        utxo_set.blockchain.set_tip(&a.get_hash()).unwrap();
+        let bc = &utxo_set.blockchain;
+        assert!(indexed(bc, &txid(&b)).is_none());
+        assert_eq!(lookup(bc, &txid(&b)), None);
+
+        // 指向不在主链上的区块的条目被忽略，退回扫描
+        let stale = serialize(&(b.get_hash(), 0u32)).unwrap();
+        bc.db.put(TX_TREE, txid(&a).as_bytes(), &stale).unwrap();
+        assert_eq!(lookup(bc, &txid(&a)), Some((txid(&a), a.get_hash(), 1)));
+    }
+
+    /// 在 5000 个区块的链上比较有无交易索引时查找创世区块中交易的耗时，
+    /// 使用 cargo test --release -- --ignored --nocapture bench_tx_index 运行
+    #[test]
+    #[ignore]
+    fn bench_tx_index() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let store = sled::Config::new().temporary(true).open().unwrap();
+        let mut bc = Blockchain::create_blockchain_with_store(
+            address.clone(),
+            "",
+            Network::Mainnet,
+            Arc::new(SledStore::new(store)),
+        )
+        .unwrap();
+        let genesis = bc.get_block(&bc.tip).unwrap();
+        let mut prev = genesis.clone();
+        for _ in 1..5000 {
+            prev = push_block(&mut bc, &address, &prev);
+        }
+        let txid = genesis.get_transaction()[0].id.clone();
+        const LOOKUPS: u32 = 20;
+        let time = |bc: &Blockchain| {
+            let start = std::time::Instant::now();
+            for _ in 0..LOOKUPS {
+                assert!(bc.get_transaction(&txid).unwrap().is_some());
+            }
+            start.elapsed() / LOOKUPS
+        };
+
+        let scan = time(&bc);
+        let start = std::time::Instant::now();
+        assert_eq!(bc.reindex_transactions(|_, _| {}).unwrap(), 5000);
+        let reindex = start.elapsed();
+        let indexed = time(&bc);
+        println!(
+            "lookup in 5000 blocks: scan {:?}, indexed {:?}, building the index took {:?}",
+            scan, indexed, reindex
+        );
+    }
+
+    #[test]
+    fn test_most_work_chain() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let mut bc = Blockchain::in_memory();
+        let block = |prev: &Block, data: &str, bits: u32| {
+            let height = prev.get_height() + 1;
+            let cbtx = Transaction::new_coinbase(
+                address.clone(),
+                data.to_string(),
+                height,
+                0,
+                Network::Mainnet,
+            )
+            .unwrap();
+            Block::new_unmined_block_at(vec![cbtx], prev.get_hash(), height, 0, bits).unwrap()
+        };
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        let genesis =
+            Block::new_unmined_block_at(vec![cbtx], String::new(), 0, 0, INITIAL_BITS).unwrap();
+        bc.add_block(genesis.clone()).unwrap();
+        let work = block_work(INITIAL_BITS);
+        assert_eq!(bc.chain_work("").unwrap(), 0);
+        assert_eq!(bc.chain_work(&genesis.get_hash()).unwrap(), work);
+
+        // 工作量相同时保留先收到的分支
+        let a1 = block(&genesis, "a1", INITIAL_BITS);
+        let b1 = block(&genesis, "b1", INITIAL_BITS);
+        bc.add_block(a1.clone()).unwrap();
+        bc.add_block(b1.clone()).unwrap();
+        assert_eq!(bc.tip, a1.get_hash());
+        let a2 = block(&a1, "a2", INITIAL_BITS);
+        bc.add_block(a2.clone()).unwrap();
+        assert_eq!(bc.chain_work(&a2.get_hash()).unwrap(), work * 3);
+
+        // 更短但工作量更大的分支成为主链
+        let harder = retarget(INITIAL_BITS, TARGET_BLOCK_TIME, TARGET_BLOCK_TIME * 4);
+        let c1 = block(&genesis, "c1", harder);
+        bc.add_block(c1.clone()).unwrap();
+        assert_eq!(bc.tip, c1.get_hash());
+        assert_eq!(bc.get_best_height().unwrap(), 1);
+        assert_eq!(bc.get_block_by_height(1).unwrap().get_hash(), c1.get_hash());
+        assert!(bc.get_block_by_height(2).is_err());
+        assert!(bc.get_block(&a2.get_hash()).is_ok());
+
+        // 旧版本的数据库没有累计工作量，查询时补齐
+        let expected = bc.chain_work(&c1.get_hash()).unwrap();
+        bc.db.clear(WORK_TREE).unwrap();
+        assert_eq!(bc.chain_work(&c1.get_hash()).unwrap(), expected);
+        assert_eq!(bc.chain_work(&a2.get_hash()).unwrap(), work * 3);
+    }
+
+    #[test]
+    fn test_create_and_open() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let dir = std::env::temp_dir().join(format!("rustchain-chain-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let error = |result: Result<Blockchain>| result.err().unwrap().to_string();
+
+        // 没有区块链时不创建空数据库
+        assert!(error(Blockchain::open(Network::Mainnet, &dir)).contains("No blockchain found"));
+        assert!(!dir.join("blocks").exists());
+
+        let message = "Rustchain test genesis";
+        let created = Blockchain::create(address.clone(), message, Network::Mainnet, &dir).unwrap();
+        let tip = created.tip.clone();
+        drop(created);
+        let err = error(Blockchain::create(
+            address.clone(),
+            "",
+            Network::Mainnet,
+            &dir,
+        ));
+        assert!(err.contains("already exists"), "{}", err);
+
+        let bc = Blockchain::open(Network::Mainnet, &dir).unwrap();
+        assert_eq!(bc.tip, tip);
+        assert_eq!(bc.genesis_message().unwrap().as_deref(), Some(message));
+        let genesis = bc.get_block(&tip).unwrap();
+        assert_eq!(
+            genesis.get_transaction()[0].coinbase_message(0),
+            message.as_bytes()
+        );
+        drop(bc);
+
+        // 测试网的数据目录不能当作主网打开，测试网的创世奖励发往测试网地址
+        assert!(Blockchain::create(address.clone(), "", Network::Testnet, &dir).is_err());
+        let testnet = ws
+            .get_wallet(&address)
+            .unwrap()
+            .get_address(Network::Testnet);
+        Blockchain::create(testnet, "", Network::Testnet, &dir).unwrap();
+        let err = error(Blockchain::open(Network::Mainnet, &dir.join("testnet")));
+        assert!(err.contains("belongs to testnet, not mainnet"), "{}", err);
+        assert!(Blockchain::open(Network::Mainnet, &dir).is_ok());
+        assert!(Blockchain::open(Network::Testnet, &dir).is_ok());
+
+        let err = error(Blockchain::create(address, "x", Network::Regtest, &dir));
+        assert!(err.contains("Coinbase data"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_export_header() {
+        let header = |magic: &[u8; 8], version: u32, network: &str| {
+            let mut data = magic.to_vec();
+            data.extend_from_slice(&version.to_le_bytes());
+            data.push(network.len() as u8);
+            data.extend_from_slice(network.as_bytes());
+            data.extend_from_slice(&7u32.to_le_bytes());
+            data
+        };
+        let read = |data: Vec<u8>| read_export_header(&mut data.as_slice(), Network::Testnet);
+        let error = |data: Vec<u8>| read(data).unwrap_err().to_string();
+
+        assert_eq!(
+            read(header(&EXPORT_MAGIC, EXPORT_VERSION, "testnet")).unwrap(),
+            7
+        );
+        assert!(
+            error(header(b"RCUTXO\0\x01", EXPORT_VERSION, "testnet")).contains("not a blockchain")
+        );
+        assert!(error(header(&EXPORT_MAGIC, 2, "testnet")).contains("version 2"));
+        assert!(
+            error(header(&EXPORT_MAGIC, EXPORT_VERSION, "mainnet")).contains("belongs to mainnet")
+        );
+        let mut short = header(&EXPORT_MAGIC, EXPORT_VERSION, "testnet");
+        short.pop();
+        assert!(error(short).contains("truncated"));
+    }
+
+    // 开启 UTXO 承诺时区块哈希包含承诺字段，fixture 是不带该特性的旧节点挖出的
+    #[cfg(not(feature = "utxo-commitment"))]
+    #[test]
+    fn test_validate_legacy_chain() {
+        // 版本号引入之前的节点挖出的链，创世区块之后的每个区块都花费了之前的输出
+        let fixture = include_str!("../tests/fixtures/legacy-chain.hex");
+        let chain: Vec<Block> = fixture
+            .lines()
+            .map(|line| Block::decode(&hex::decode(line).unwrap()).unwrap())
+            .collect();
+        let mut utxo_set = UTXOSet::in_memory(Blockchain::in_memory());
+        let (last, earlier) = chain.split_last().unwrap();
+        for block in earlier {
+            utxo_set
+                .blockchain
+                .validate_block(block, &utxo_set)
+                .unwrap();
+            utxo_set.blockchain.add_block(block.clone()).unwrap();
+            utxo_set.connect_block(block).unwrap();
+        }
+
+        // 旧交易的签名照样验证
+        let mut txs = last.get_transaction().to_vec();
+        assert!(txs.iter().all(|tx| tx.version == 0));
+        assert!(!txs[1].is_coinbase());
+        txs[1].vin[0].signature[0] ^= 1;
+        let forged =
+            Block::new_unmined_block(txs, last.get_prev_hash(), last.get_height()).unwrap();
+        assert!(matches!(
+            utxo_set.blockchain.validate_block(&forged, &utxo_set),
+            Err(BlockValidationError::InvalidTransaction {
+                error: TxVerifyError::BadSignature { input: 0 },
+                ..
+            })
+        ));
+
+        utxo_set.blockchain.validate_block(last, &utxo_set).unwrap();
+        utxo_set.blockchain.add_block(last.clone()).unwrap();
+        utxo_set.connect_block(last).unwrap();
+        let report = utxo_set.blockchain.verify_chain(3, &utxo_set).unwrap();
+        assert!(report.failure.is_none(), "{:?}", report.failure);
+        assert_eq!(report.verified, chain.len());
+    }
+
+    // 开启 UTXO 承诺时区块哈希包含承诺字段，fixture 是不带该特性的旧节点挖出的
+    #[cfg(not(feature = "utxo-commitment"))]
+    #[test]
+    fn test_verify_legacy_chain() {
+        // 版本号引入之前的节点挖出的链，交易 id 按当时的规则计算
+        let fixture = include_str!("../tests/fixtures/legacy-chain.hex");
+        let mut bc = Blockchain::in_memory();
+        let mut spends = 0;
+        for line in fixture.lines() {
+            let block = Block::decode(&hex::decode(line).unwrap()).unwrap();
+            let coinbase = &block.get_transaction()[0];
+            assert_eq!(coinbase.version, 0);
+            assert_eq!(coinbase.compute_id(), coinbase.id);
+            spends += block.get_transaction().len() - 1;
+            bc.add_block(block).unwrap();
+        }
+        // 级别 3 重放旧交易，验证它们的签名
+        assert!(spends > 0);
+        let utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        for level in 1..=3 {
+            let report = utxo_set.blockchain.verify_chain(level, &utxo_set).unwrap();
+            assert!(
+                report.failure.is_none(),
+                "level {}: {:?}",
+                level,
+                report.failure
+            );
+            assert_eq!(report.verified, fixture.lines().count());
+        }
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let receiver = ws.create_wallet();
+        let wallet = ws.get_wallet(&miner).unwrap().clone();
+        let mut bc = Blockchain::in_memory();
+        let coinbase =
+            Transaction::new_coinbase(miner.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        let genesis = Block::new_unmined_block(vec![coinbase.clone()], String::new(), 0)
+            .unwrap()
+            .mine()
+            .unwrap();
+        bc.add_block(genesis.clone()).unwrap();
+        let store: Arc<dyn KvStore> = Arc::new(MemoryStore::default());
+        let mut utxo_set = UTXOSet::with_store(bc, store.clone());
+        utxo_set.reindex().unwrap();
+        let tx = Transaction::new_utxo_from_inputs(
+            &wallet,
+            &[OutPoint::new(&coinbase.id, 0)],
+            &receiver,
+            3,
+            &TxOptions::default(),
+            &utxo_set,
+        )
+        .unwrap();
+        let fee = 10 - 3 - tx.vout[1].value;
+        let cbtx =
+            Transaction::new_coinbase(miner.clone(), String::new(), 1, fee, Network::Mainnet)
+                .unwrap();
+        let block = Block::new_unmined_block(vec![cbtx, tx.clone()], genesis.get_hash(), 1)
+            .unwrap()
+            .mine()
+            .unwrap();
+        utxo_set.blockchain.add_block(block.clone()).unwrap();
+        utxo_set.connect_block(&block).unwrap();
+
+        let verify = |level| utxo_set.blockchain.verify_chain(level, &utxo_set).unwrap();
+        for level in 1..=3 {
+            let report = verify(level);
+            assert_eq!(report.verified, 2);
+            assert_eq!(report.failure, None);
+        }
+        assert!(utxo_set.blockchain.verify_chain(0, &utxo_set).is_err());
+        assert!(utxo_set.blockchain.verify_chain(4, &utxo_set).is_err());
+
+        // 依次改动区块中的一个字节，返回能发现问题的最低级别及问题种类
+        let db = utxo_set.blockchain.db.clone();
+        let stored = db
+            .get(DEFAULT_TREE, block.get_hash().as_bytes())
+            .unwrap()
+            .unwrap();
+        let find = |needle: &[u8]| {
+            stored
+                .windows(needle.len())
+                .position(|w| w == needle)
+                .unwrap()
+        };
+        let first_caught = |data: &[u8]| {
+            db.put(DEFAULT_TREE, block.get_hash().as_bytes(), data)
+                .unwrap();
+            let caught = (1..=3).find_map(|level| {
+                let failure = verify(level).failure?;
+                assert_eq!(failure.height, 1);
+                Some((level, failure.fault))
+            });
+            db.put(DEFAULT_TREE, block.get_hash().as_bytes(), &stored)
+                .unwrap();
+            caught
+        };
+        let flipped = |at: usize| {
+            let mut data = stored.clone();
+            data[at] ^= 1;
+            data
+        };
+        // 时间戳在区块头中紧接默克尔根，哈希随之改变
+        let timestamp = find(block.get_merkle_root().as_bytes()) + 64;
+        assert_eq!(
+            first_caught(&flipped(timestamp)),
+            Some((1, ChainFault::ProofOfWork))
+        );
+        let output = find(&tx.vout[0].pub_key_hash);
+        assert_eq!(
+            first_caught(&flipped(output)),
+            Some((2, ChainFault::Transactions))
+        );
+        // 交易 id 不包含签名，只有完整验证才能发现
+        let signature = find(&tx.vin[0].signature);
+        assert_eq!(
+            first_caught(&flipped(signature)),
+            Some((3, ChainFault::Validation))
+        );
+        assert_eq!(first_caught(&stored), None);
+
+        // UTXO 集合中的记录被改动时只有级别 3 能发现
+        let key = OutPoint::new(&tx.id, 0).to_string();
+        let mut entry = store.get(DEFAULT_TREE, key.as_bytes()).unwrap().unwrap();
+        entry[0] ^= 1;
+        store.put(DEFAULT_TREE, key.as_bytes(), &entry).unwrap();
+        assert_eq!(verify(2).failure, None);
+        let failure = verify(3).failure.unwrap();
+        assert_eq!((failure.height, failure.fault), (1, ChainFault::UtxoSet));
+        assert!(failure.message.contains(&key), "{}", failure.message);
+    }
+
+    #[test]
+    fn test_headers() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let mine = |prev: &str, height: i32, timestamp: u128| {
+            let cbtx = Transaction::new_coinbase(
+                address.clone(),
+                format!("block {}", height),
+                height,
+                0,
+                Network::Mainnet,
+            )
+            .unwrap();
+            Block::new_unmined_block_at(
+                vec![cbtx],
+                prev.to_string(),
+                height,
+                timestamp,
+                POW_LIMIT_BITS,
+            )
+            .unwrap()
+            .mine()
+            .unwrap()
+        };
+        let mut source = Blockchain::in_memory();
+        let mut chain = vec![mine("", 0, 0)];
+        source.add_block(chain[0].clone()).unwrap();
+        for height in 1..=12 {
+            let block = mine(&chain.last().unwrap().get_hash(), height, height as u128);
+            source.add_block(block.clone()).unwrap();
+            chain.push(block);
+        }
+
+        // 只有创世区块的节点先收到全部区块头，再按顺序下载区块
+        let mut fresh = Blockchain::in_memory();
+        fresh.add_block(chain[0].clone()).unwrap();
+        let locator = fresh.header_locator().unwrap();
+        assert_eq!(locator, [chain[0].get_hash()]);
+        let headers = source.headers_after(&locator, 5).unwrap();
+        assert_eq!(headers.len(), 5);
+        assert_eq!(headers[0], *chain[1].header());
+        for header in &headers {
+            fresh.add_header(header, 100).unwrap();
+        }
+        let mut locator = fresh.header_locator().unwrap();
+        assert_eq!(locator.len(), 6);
+        assert_eq!(locator[0], chain[5].get_hash());
+        let headers = source.headers_after(&locator, 2000).unwrap();
+        assert_eq!(headers.len(), 7);
+        for header in &headers {
+            fresh.add_header(header, 100).unwrap();
+        }
+        assert_eq!(fresh.best_header().unwrap().0, chain[12].get_hash());
+        assert_eq!(fresh.get_best_height().unwrap(), 0);
+        let missing: Vec<String> = chain[1..].iter().map(Block::get_hash).collect();
+        assert_eq!(fresh.missing_blocks().unwrap(), missing);
+        // 定位哈希前 10 个逐个列出，之后间隔加倍，最后是创世区块
+        locator = fresh.header_locator().unwrap();
+        let heights: Vec<i32> = locator
+            .iter()
+            .map(|hash| fresh.get_header(hash).unwrap().height)
+            .collect();
+        assert_eq!(heights, [12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 1, 0]);
+        assert!(source.headers_after(&locator, 2000).unwrap().is_empty());
+        assert_eq!(source.headers_after(&[], 3).unwrap()[0], *chain[0].header());
+
+        // 区块的交易须与已验证的区块头一致
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), "other".into(), 1, 0, Network::Mainnet)
+                .unwrap();
+        let other =
+            Block::new_unmined_block_at(vec![cbtx], chain[0].get_hash(), 1, 1, POW_LIMIT_BITS)
+                .unwrap();
+        let forged: Block = deserialize(
+            &serialize(&(other.header(), chain[1].get_hash(), other.get_transaction())).unwrap(),
+        )
+        .unwrap();
+        let err = fresh.check_against_header(&forged).unwrap_err();
+        assert!(err.to_string().contains("merkle root"), "{}", err);
+        for block in &chain[1..] {
+            fresh.check_against_header(block).unwrap();
+            fresh.add_block(block.clone()).unwrap();
+        }
+        assert_eq!(fresh.tip, chain[12].get_hash());
+        assert!(fresh.missing_blocks().unwrap().is_empty());
+
+        // 不接在已知区块头之后、高度或难度不对、时间戳不晚于中位数的区块头被拒绝
+        let tip = chain[12].get_hash();
+        let reject = |header: &BlockHeader, expected: &str| {
+            let err = fresh.add_header(header, 100).unwrap_err();
+            assert!(err.to_string().contains(expected), "{}", err);
+        };
+        let mut header = mine("unknown", 13, 13).header().clone();
+        reject(&header, "unknown block");
+        header = mine(&tip, 14, 13).header().clone();
+        reject(&header, "expected 13");
+        header = mine(&tip, 13, 7).header().clone();
+        reject(&header, "median time");
+        header = mine(&tip, 13, 100 + MAX_FUTURE_BLOCK_TIME + 1)
+            .header()
+            .clone();
+        reject(&header, "ahead of the local clock");
+        reject(mine("", 0, 1).header(), "different genesis");
+        let easy = Block::new_unmined_block_at(Vec::new(), tip.clone(), 13, 13, REGTEST_BITS)
+            .unwrap()
+            .mine()
+            .unwrap();
+        reject(easy.header(), "difficulty");
+        let mut header = mine(&tip, 13, 13).header().clone();
+        fresh.add_header(&header, 100).unwrap();
+        while header.check_proof_of_work().is_ok() {
+            header.nonce += 1;
+        }
+        reject(&header, "does not meet");
+    }
+
+    #[test]
+    fn test_validate_block_parallel() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let receiver = ws.create_wallet();
+        let wallet = ws.get_wallet(&miner).unwrap().clone();
+        let mut bc = Blockchain::in_memory();
+        bc.coinbase_height_activation = 0;
+        let inputs = PARALLEL_VERIFY_MIN + 4;
+        let cbtx = |height, fees| {
+            Transaction::new_coinbase(miner.clone(), String::new(), height, fees, Network::Mainnet)
+                .unwrap()
+        };
+        // 创世奖励足够拆成 inputs 个输出
+        let genesis =
+            Block::new_unmined_block(vec![cbtx(0, 10 * inputs as u64)], String::new(), 0).unwrap();
+        bc.add_block(genesis.clone()).unwrap();
+        let mut utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        let outputs = vec![(miner.clone(), 10); inputs];
+        let split =
+            Transaction::new_utxo_multi(&wallet, &outputs, &TxOptions::default(), &utxo_set)
+                .unwrap();
+        let block =
+            Block::new_unmined_block(vec![cbtx(1, 0), split.clone()], genesis.get_hash(), 1)
+                .unwrap();
+        utxo_set.blockchain.add_block(block.clone()).unwrap();
+        utxo_set.connect_block(&block).unwrap();
+
+        // 一笔交易花费全部拆出的输出，签名数超过并行验证的下限
+        let outpoints: Vec<OutPoint> = (0..inputs)
+            .map(|vout| OutPoint::new(&split.id, vout as u32))
+            .collect();
+        let tx = Transaction::new_utxo_from_inputs(
+            &wallet,
+            &outpoints,
+            &receiver,
+            5 * inputs as u64,
+            &TxOptions::default(),
+            &utxo_set,
+        )
+        .unwrap();
+        assert_eq!(tx.vin.len(), inputs);
+        let valid =
+            Block::new_unmined_block(vec![cbtx(2, 0), tx.clone()], block.get_hash(), 2).unwrap();
+        let mut forged = tx.clone();
+        forged.vin[13].signature[0] ^= 1;
+        let forged =
+            Block::new_unmined_block(vec![cbtx(2, 0), forged], block.get_hash(), 2).unwrap();
+        let bad_signature = Err(BlockValidationError::InvalidTransaction {
+            txid: tx.id.clone(),
+            error: TxVerifyError::BadSignature { input: 13 },
+        });
+
+        // 并行与串行验证同一区块得到相同的结果
+        let bc = &utxo_set.blockchain;
+        for (block, expected) in [(&valid, Ok(())), (&forged, bad_signature)] {
+            let parallel = bc.validate_block_with(block, &utxo_set, PARALLEL_VERIFY_MIN);
+            let serial = bc.validate_block_with(block, &utxo_set, usize::MAX);
+            assert_eq!(parallel, expected);
+            assert_eq!(serial, expected);
+        }
+    }
+
+    #[test]
+    fn test_validate_block() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let receiver = ws.create_wallet();
+        let wallet = ws.get_wallet(&miner).unwrap().clone();
+        let mut bc = Blockchain::in_memory();
+        bc.coinbase_height_activation = 0;
+        let coinbase =
+            Transaction::new_coinbase(miner.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        let genesis = Block::new_unmined_block(vec![coinbase.clone()], String::new(), 0).unwrap();
+        bc.add_block(genesis.clone()).unwrap();
+        let utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        let bc = &utxo_set.blockchain;
+
+        let input = [OutPoint::new(&coinbase.id, 0)];
+        let spend = |amount| {
+            Transaction::new_utxo_from_inputs(
+                &wallet,
+                &input,
+                &receiver,
+                amount,
+                &TxOptions::default(),
+                &utxo_set,
+            )
+            .unwrap()
+        };
+        let cbtx = |fees| {
+            Transaction::new_coinbase(miner.clone(), String::new(), 1, fees, Network::Mainnet)
+                .unwrap()
+        };
+        let block = |txs| Block::new_unmined_block(txs, genesis.get_hash(), 1).unwrap();
+        let validate = |block: &Block| bc.validate_block(block, &utxo_set);
+
+        let tx = spend(3);
+        let fee = 10 - 3 - tx.vout[1].value;
+        let valid = block(vec![cbtx(fee), tx.clone()]);
+        validate(&valid).unwrap();
+
+        let mut forged = tx.clone();
+        forged.vin[0].signature[0] ^= 1;
+        let err = validate(&block(vec![cbtx(fee), forged])).unwrap_err();
+        assert_eq!(
+            err,
+            BlockValidationError::InvalidTransaction {
+                txid: tx.id.clone(),
+                error: TxVerifyError::BadSignature { input: 0 },
+            }
+        );
+
+        // 同一区块内两笔交易花费同一个输出
+        let other = spend(4);
+        let err = validate(&block(vec![cbtx(0), tx.clone(), other.clone()])).unwrap_err();
+        assert_eq!(
+            err,
+            BlockValidationError::DoubleSpend {
+                txid: other.id.clone(),
+                outpoint: input[0].clone(),
+            }
+        );
+
+        // 花费同一区块的创币交易输出，签名之前就因未成熟被拒绝
+        let coinbase = cbtx(0);
+        let mut early = tx.clone();
+        early.vin[0].outpoint = OutPoint::new(&coinbase.id, 0);
+        early.id = early.hash().unwrap();
+        assert_eq!(
+            validate(&block(vec![coinbase.clone(), early.clone()])).unwrap_err(),
+            BlockValidationError::ImmatureInput {
+                txid: early.id.clone(),
+                outpoint: OutPoint::new(&coinbase.id, 0),
+            }
+        );
+
+        let err = validate(&block(vec![cbtx(fee + 1), tx.clone()])).unwrap_err();
+        assert!(
+            matches!(err, BlockValidationError::ExcessiveCoinbase { .. }),
+            "{}",
+            err
+        );
+        assert_eq!(
+            validate(&block(vec![tx.clone(), cbtx(0)])).unwrap_err(),
+            BlockValidationError::NoCoinbase
+        );
+        let orphan = Block::new_unmined_block(vec![cbtx(0)], "unknown".into(), 1).unwrap();
+        assert!(matches!(
+            validate(&orphan).unwrap_err(),
+            BlockValidationError::UtxoTipMismatch { .. }
+        ));
+
+        // 创币交易记录的高度与区块不符
+        let wrong = Transaction::new_coinbase(miner.clone(), String::new(), 2, 0, Network::Mainnet)
+            .unwrap();
+        assert_eq!(
+            validate(&block(vec![wrong])).unwrap_err(),
+            BlockValidationError::CoinbaseHeight {
+                height: 1,
+                found: Some(2),
+            }
+        );
+        // 旧版本的创币交易不记录高度，只在激活高度之前有效
+        let mut legacy = cbtx(0);
+        legacy.vin[0].pub_key = b"legacy coinbase".to_vec();
+        legacy.id = legacy.hash().unwrap();
+        let legacy = block(vec![legacy]);
+        assert!(matches!(
+            validate(&legacy).unwrap_err(),
+            BlockValidationError::CoinbaseHeight { height: 1, .. }
+        ));
+        let before_activation = Blockchain {
+            tip: bc.tip.clone(),
+            db: bc.db.clone(),
+            checkpoints: BTreeMap::new(),
+            coinbase_height_activation: 2,
+            bits_activation: bc.bits_activation,
+            mining_threads: bc.mining_threads,
+            network: bc.network,
+        };
+        before_activation
+            .validate_block(&legacy, &utxo_set)
+            .unwrap();
+
+        // 连接之后再次花费同一个输出
+        utxo_set.connect_block(&valid).unwrap();
+        let cbtx = Transaction::new_coinbase(miner.clone(), String::new(), 2, 0, Network::Mainnet)
+            .unwrap();
+        let again = Block::new_unmined_block(vec![cbtx, other], valid.get_hash(), 2).unwrap();
+        let err = validate(&again).unwrap_err();
+        assert!(
+            matches!(err, BlockValidationError::MissingInput { .. }),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_validate_block_height() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let mut bc = Blockchain::in_memory();
+        bc.coinbase_height_activation = 0;
+        let cbtx = |height| {
+            Transaction::new_coinbase(miner.clone(), String::new(), height, 0, Network::Mainnet)
+                .unwrap()
+        };
+        let genesis = Block::new_unmined_block(vec![cbtx(0)], String::new(), 0).unwrap();
+        bc.add_block(genesis.clone()).unwrap();
+        let utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        let bc = &utxo_set.blockchain;
+
+        // 创币交易与区块声明同一个虚假的高度，仍然不能接在创世区块之后
+        let fake = Block::new_unmined_block(vec![cbtx(5000)], genesis.get_hash(), 5000).unwrap();
+        assert_eq!(
+            bc.validate_block(&fake, &utxo_set).unwrap_err(),
+            BlockValidationError::BadHeight {
+                expected: 1,
+                found: 5000,
+            }
+        );
+        let real = Block::new_unmined_block(vec![cbtx(1)], genesis.get_hash(), 1).unwrap();
+        bc.validate_block(&real, &utxo_set).unwrap();
+    }
+}
@@ -0,0 +1,464 @@
+//! scheduling of parallel block downloads during the initial sync
+
+use crate::block::Block;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// MAX_BLOCKS_IN_FLIGHT_PER_PEER 每个节点同时在请求中的区块数，收到一个就补上一个
+pub const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 16;
+/// 只请求队列最前面这么多个区块，慢的节点拖住最前面的区块时，等待连接的区块不会无限增多
+const DOWNLOAD_WINDOW: usize = 512;
+/// BLOCK_DOWNLOAD_TIMEOUT 请求后这么久没有收到的区块改向其他节点请求；
+/// 没有送来或送来无效区块的节点在这段时间内不再被分配这个区块
+pub const BLOCK_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 下载队列中的一个区块
+struct Download {
+    height: i32,
+    state: State,
+}
+
+enum State {
+    /// 等待请求，avoid 是上一次没有送来它的节点和那一次失败的时间
+    Queued { avoid: Option<(String, Instant)> },
+    /// 已在 since 时向 peer 请求
+    InFlight { peer: String, since: Instant },
+    /// 已从 from 收到，等待排在前面的区块
+    Received { block: Block, from: String },
+}
+
+/// ReadyBlock 轮到连接的区块和送来它的节点
+pub struct ReadyBlock {
+    pub height: i32,
+    pub block: Block,
+    pub from: String,
+}
+
+/// Progress 一轮下载的进度
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// 已经交给调用者连接的区块数
+    pub connected: usize,
+    /// 还没有交给调用者连接的区块数
+    pub remaining: usize,
+    /// 从这一轮开始到现在的时间
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Rate 平均每秒连接的区块数
+    pub fn rate(&self) -> f64 {
+        self.connected as f64 / self.elapsed.as_secs_f64().max(0.001)
+    }
+
+    /// RemainingTime 按平均速度估计的剩余时间，还没有连接过区块时为 None
+    pub fn remaining_time(&self) -> Option<Duration> {
+        if self.connected == 0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(self.remaining as f64 / self.rate()))
+    }
+}
+
+/// BlockFetcher 初始同步时要下载的区块，分给多个节点同时下载，收到后按高度顺序交给调用者连接
+///
+/// 区块头已经验证过，区块可以按任意顺序到达；排在最前面的区块收到之前，后面的区块留在队列中
+#[derive(Default)]
+pub struct BlockFetcher {
+    /// 还没有交给调用者连接的区块哈希，按高度从低到高排列
+    order: VecDeque<String>,
+    blocks: HashMap<String, Download>,
+    /// 节点 -> 向它请求中的区块数
+    in_flight: HashMap<String, usize>,
+    /// 这一轮下载开始的时间和已交给调用者连接的区块数，没有在下载时为 None
+    round: Option<(Instant, usize)>,
+}
+
+impl BlockFetcher {
+    /// Add 把从 first_height 开始按高度排列的 hashes 中不在队列里的区块加入队列末尾，返回加入的个数
+    pub fn add(&mut self, first_height: i32, hashes: Vec<String>, now: Instant) -> usize {
+        let mut added = 0;
+        for (height, hash) in (first_height..).zip(hashes) {
+            if self.blocks.contains_key(&hash) {
+                continue;
+            }
+            let state = State::Queued { avoid: None };
+            self.blocks.insert(hash.clone(), Download { height, state });
+            self.order.push_back(hash);
+            added += 1;
+        }
+        if added > 0 && self.round.is_none() {
+            self.round = Some((now, 0));
+        }
+        added
+    }
+
+    /// Contains hash 是否在下载队列中
+    pub fn contains(&self, hash: &str) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    /// Schedule 给 peers 中的节点分配要请求的区块，返回 (节点, 区块哈希)
+    ///
+    /// peers 是握手完成的节点和它们的高度。每个节点依次从队列前面取走它有的、还没有请求的区块，
+    /// 直到有 MAX_BLOCKS_IN_FLIGHT_PER_PEER 个在请求中，各节点因此下载不同的区块段
+    pub fn schedule(&mut self, peers: &[(String, i32)], now: Instant) -> Vec<(String, String)> {
+        let mut requests = Vec::new();
+        for (peer, best_height) in peers {
+            let in_flight = self.in_flight.get(peer).copied().unwrap_or(0);
+            let mut free = MAX_BLOCKS_IN_FLIGHT_PER_PEER.saturating_sub(in_flight);
+            for hash in self.order.iter().take(DOWNLOAD_WINDOW) {
+                if free == 0 {
+                    break;
+                }
+                let Some(download) = self.blocks.get_mut(hash) else {
+                    continue;
+                };
+                let State::Queued { avoid } = &download.state else {
+                    continue;
+                };
+                let avoided = avoid.as_ref().is_some_and(|(avoid, at)| {
+                    avoid == peer && now.duration_since(*at) < BLOCK_DOWNLOAD_TIMEOUT
+                });
+                if download.height > *best_height || avoided {
+                    continue;
+                }
+                download.state = State::InFlight {
+                    peer: peer.clone(),
+                    since: now,
+                };
+                *self.in_flight.entry(peer.clone()).or_default() += 1;
+                requests.push((peer.clone(), hash.clone()));
+                free -= 1;
+            }
+        }
+        requests
+    }
+
+    /// Receive 记录从 from 收到了队列中的区块，返回 false 表示区块不在队列中或已经收到过
+    pub fn receive(&mut self, block: Block, from: &str) -> bool {
+        let Some(download) = self.blocks.get_mut(&block.get_hash()) else {
+            return false;
+        };
+        let requested = match &download.state {
+            State::Received { .. } => return false,
+            State::InFlight { peer, .. } => Some(peer.clone()),
+            State::Queued { .. } => None,
+        };
+        download.state = State::Received {
+            block,
+            from: from.to_string(),
+        };
+        if let Some(peer) = requested {
+            self.request_done(&peer);
+        }
+        true
+    }
+
+    /// TakeReady 从队列最前面取出连续的已经收到的区块，调用者按顺序连接它们
+    pub fn take_ready(&mut self) -> Vec<ReadyBlock> {
+        let mut ready = Vec::new();
+        while let Some(hash) = self.order.pop_front() {
+            match self.blocks.remove(&hash) {
+                Some(Download {
+                    height,
+                    state: State::Received { block, from },
+                }) => ready.push(ReadyBlock {
+                    height,
+                    block,
+                    from,
+                }),
+                Some(download) => {
+                    self.blocks.insert(hash.clone(), download);
+                    self.order.push_front(hash);
+                    break;
+                }
+                None => {}
+            }
+        }
+        if let Some((_, connected)) = &mut self.round {
+            *connected += ready.len();
+        }
+        ready
+    }
+
+    /// Reject 交给调用者的区块 hash 没有通过验证：放回队列最前面，BLOCK_DOWNLOAD_TIMEOUT 内
+    /// 不再向送来它的 from 请求；同一批中排在它之后、还没有连接的 rest 放回它之后
+    pub fn reject(
+        &mut self,
+        hash: String,
+        height: i32,
+        from: String,
+        rest: Vec<ReadyBlock>,
+        now: Instant,
+    ) {
+        if let Some((_, connected)) = &mut self.round {
+            *connected = connected.saturating_sub(rest.len() + 1);
+        }
+        for ready in rest.into_iter().rev() {
+            let hash = ready.block.get_hash();
+            let state = State::Received {
+                block: ready.block,
+                from: ready.from,
+            };
+            let height = ready.height;
+            self.blocks.insert(hash.clone(), Download { height, state });
+            self.order.push_front(hash);
+        }
+        let state = State::Queued {
+            avoid: Some((from, now)),
+        };
+        self.blocks.insert(hash.clone(), Download { height, state });
+        self.order.push_front(hash);
+    }
+
+    /// Failed 向 peer 请求的区块 hash 没有送到时放回队列，例如对方回复没有或送来的区块与区块头不符；
+    /// 返回 hash 是否正在向 peer 请求
+    pub fn failed(&mut self, hash: &str, peer: &str, now: Instant) -> bool {
+        let requested = self.blocks.get(hash).is_some_and(
+            |download| matches!(&download.state, State::InFlight { peer: p, .. } if p == peer),
+        );
+        if requested {
+            self.requeue(hash, Some((peer.to_string(), now)));
+        }
+        requested
+    }
+
+    /// TimedOut 把请求后超过 BLOCK_DOWNLOAD_TIMEOUT 还没有收到的区块放回队列，返回 (节点, 区块哈希)
+    pub fn timed_out(&mut self, now: Instant) -> Vec<(String, String)> {
+        let expired: Vec<(String, String)> = self
+            .blocks
+            .iter()
+            .filter_map(|(hash, download)| match &download.state {
+                State::InFlight { peer, since }
+                    if now.duration_since(*since) >= BLOCK_DOWNLOAD_TIMEOUT =>
+                {
+                    Some((peer.clone(), hash.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        for (peer, hash) in &expired {
+            self.requeue(hash, Some((peer.clone(), now)));
+        }
+        expired
+    }
+
+    /// Release 与 peer 断开时把向它请求中的区块放回队列，由其他节点下载
+    pub fn release(&mut self, peer: &str) {
+        for download in self.blocks.values_mut() {
+            if matches!(&download.state, State::InFlight { peer: p, .. } if p == peer) {
+                download.state = State::Queued { avoid: None };
+            }
+        }
+        self.in_flight.remove(peer);
+    }
+
+    /// Progress 这一轮下载的进度，没有在下载时为 None
+    pub fn progress(&self, now: Instant) -> Option<Progress> {
+        let (start, connected) = self.round?;
+        Some(Progress {
+            connected,
+            remaining: self.order.len(),
+            elapsed: now.duration_since(start),
+        })
+    }
+
+    /// Finish 队列已空时结束这一轮下载并返回它的进度；正在下载的区块还没有全部交给调用者时返回 None
+    pub fn finish(&mut self, now: Instant) -> Option<Progress> {
+        if !self.order.is_empty() {
+            return None;
+        }
+        let progress = self.progress(now);
+        self.round = None;
+        progress
+    }
+
+    fn requeue(&mut self, hash: &str, avoid: Option<(String, Instant)>) {
+        let Some(download) = self.blocks.get_mut(hash) else {
+            return;
+        };
+        let State::InFlight { peer, .. } =
+            std::mem::replace(&mut download.state, State::Queued { avoid })
+        else {
+            return;
+        };
+        self.request_done(&peer);
+    }
+
+    fn request_done(&mut self, peer: &str) {
+        if let Some(count) = self.in_flight.get_mut(peer) {
+            *count -= 1;
+            if *count == 0 {
+                self.in_flight.remove(peer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// 一条从 "genesis" 开始、高度从 1 开始的链
+    fn chain(n: usize) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for height in 1..=n as i32 {
+            let prev = blocks
+                .last()
+                .map_or("genesis".to_string(), |block| block.get_hash());
+            blocks.push(Block::new_unmined_block(Vec::new(), prev, height).unwrap());
+        }
+        blocks
+    }
+
+    fn fetcher(blocks: &[Block], now: Instant) -> BlockFetcher {
+        let mut fetcher = BlockFetcher::default();
+        let hashes = blocks.iter().map(Block::get_hash).collect();
+        assert_eq!(fetcher.add(1, hashes, now), blocks.len());
+        fetcher
+    }
+
+    fn peers(heights: &[i32]) -> Vec<(String, i32)> {
+        heights
+            .iter()
+            .enumerate()
+            .map(|(n, height)| (format!("node{}:3000", n), *height))
+            .collect()
+    }
+
+    #[test]
+    fn test_schedule_ranges() {
+        let blocks = chain(100);
+        let now = Instant::now();
+        let mut fetcher = fetcher(&blocks, now);
+        let nodes = peers(&[100, 100, 20]);
+        let requests = fetcher.schedule(&nodes, now);
+
+        // 前两个节点各下载一段，第三个节点只有前 20 个区块，已被分完
+        assert_eq!(requests.len(), 2 * MAX_BLOCKS_IN_FLIGHT_PER_PEER);
+        let n = MAX_BLOCKS_IN_FLIGHT_PER_PEER;
+        for (i, (peer, hash)) in requests.iter().enumerate() {
+            assert_eq!(*peer, nodes[i / n].0);
+            assert_eq!(*hash, blocks[i].get_hash());
+        }
+        assert!(fetcher.schedule(&nodes, now).is_empty());
+
+        // 收到一个区块后补上一个，重复的区块和不在队列中的区块被忽略
+        assert!(fetcher.receive(blocks[0].clone(), &nodes[0].0));
+        assert!(!fetcher.receive(blocks[0].clone(), &nodes[0].0));
+        let other = Block::new_unmined_block(Vec::new(), "other".to_string(), 1).unwrap();
+        assert!(!fetcher.receive(other, &nodes[0].0));
+        assert_eq!(
+            fetcher.schedule(&nodes, now),
+            vec![(nodes[0].0.clone(), blocks[2 * n].get_hash())]
+        );
+        // 新加入的节点分到下一段，已在队列中的区块不再加入
+        let requests = fetcher.schedule(&peers(&[100, 100, 20, 100]), now);
+        assert_eq!(requests.len(), n);
+        assert_eq!(requests[0].1, blocks[2 * n + 1].get_hash());
+        assert_eq!(fetcher.add(6, vec![blocks[5].get_hash()], now), 0);
+    }
+
+    #[test]
+    fn test_reassemble_in_height_order() {
+        let blocks = chain(5);
+        let now = Instant::now();
+        let mut fetcher = fetcher(&blocks, now);
+        fetcher.schedule(&peers(&[5]), now);
+        for block in blocks[1..].iter().rev() {
+            assert!(fetcher.receive(block.clone(), "node0:3000"));
+            assert!(fetcher.take_ready().is_empty());
+        }
+        assert!(fetcher.finish(now).is_none());
+
+        assert!(fetcher.receive(blocks[0].clone(), "node0:3000"));
+        let ready = fetcher.take_ready();
+        let hashes: Vec<String> = ready.iter().map(|ready| ready.block.get_hash()).collect();
+        let heights: Vec<i32> = ready.iter().map(|ready| ready.height).collect();
+        assert_eq!(
+            hashes,
+            blocks.iter().map(Block::get_hash).collect::<Vec<_>>()
+        );
+        assert_eq!(heights, vec![1, 2, 3, 4, 5]);
+        assert!(!fetcher.contains(&blocks[0].get_hash()));
+
+        let later = now + Duration::from_secs(2);
+        let progress = fetcher.finish(later).unwrap();
+        assert_eq!(progress.connected, 5);
+        assert_eq!(progress.remaining, 0);
+        assert_eq!(progress.rate(), 2.5);
+        assert_eq!(progress.remaining_time(), Some(Duration::ZERO));
+        assert!(fetcher.progress(later).is_none());
+    }
+
+    #[test]
+    fn test_timeout_and_disconnect() {
+        let blocks = chain(3);
+        let now = Instant::now();
+        let mut fetcher = fetcher(&blocks, now);
+        let peers = peers(&[3, 3]);
+        let slow = &peers[0].0;
+        let fast = &peers[1].0;
+        assert_eq!(fetcher.schedule(&peers[..1], now).len(), 3);
+        assert!(fetcher.schedule(&peers, now).is_empty());
+        assert!(fetcher.timed_out(now + Duration::from_secs(1)).is_empty());
+
+        // 超时的区块改由另一个节点下载，不再分给没有送来它的节点
+        let later = now + BLOCK_DOWNLOAD_TIMEOUT;
+        assert_eq!(fetcher.timed_out(later).len(), 3);
+        assert!(fetcher.schedule(&peers[..1], later).is_empty());
+        assert!(!fetcher.failed(&blocks[0].get_hash(), slow, later));
+        let requests = fetcher.schedule(&peers, later);
+        assert!(requests.iter().all(|(peer, _)| peer == fast));
+        assert_eq!(requests.len(), 3);
+
+        // 对方没有这个区块，或者断开时，区块回到队列
+        assert!(fetcher.failed(&blocks[0].get_hash(), fast, later));
+        assert!(!fetcher.failed(&blocks[0].get_hash(), fast, later));
+        fetcher.release(fast);
+        let retry = later + BLOCK_DOWNLOAD_TIMEOUT;
+        let requests = fetcher.schedule(&peers, retry);
+        assert_eq!(requests.len(), 3);
+        assert!(requests.iter().all(|(peer, _)| peer == slow));
+        // 旧节点晚到的区块仍然被接受
+        assert!(fetcher.receive(blocks[0].clone(), fast));
+        assert_eq!(fetcher.schedule(&peers[..1], retry).len(), 0);
+    }
+
+    #[test]
+    fn test_reject_invalid_block() {
+        let blocks = chain(3);
+        let now = Instant::now();
+        let mut fetcher = fetcher(&blocks, now);
+        let peers = peers(&[3, 3]);
+        let bad = &peers[0].0;
+        fetcher.schedule(&peers[..1], now);
+        for block in &blocks {
+            fetcher.receive(block.clone(), bad);
+        }
+        let mut ready = fetcher.take_ready().into_iter();
+        let first = ready.next().unwrap();
+        fetcher.reject(
+            first.block.get_hash(),
+            first.height,
+            first.from,
+            ready.collect(),
+            now,
+        );
+        assert_eq!(fetcher.progress(now).unwrap().connected, 0);
+        assert!(fetcher.take_ready().is_empty());
+
+        // 无效的区块改向另一个节点请求，收到后连同等待它的区块一起交出
+        assert_eq!(
+            fetcher.schedule(&peers, now),
+            vec![(peers[1].0.clone(), blocks[0].get_hash())]
+        );
+        fetcher.receive(blocks[0].clone(), &peers[1].0);
+        let ready = fetcher.take_ready();
+        assert_eq!(ready.len(), 3);
+        assert_eq!(ready[0].from, peers[1].0);
+        assert_eq!(ready[2].from, *bad);
+        assert!(fetcher.finish(now).is_some());
+    }
+}
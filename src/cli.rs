@@ -1,14 +1,46 @@
 // !Cli
 
+use std::cell::Cell;
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, Read, Write};
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use bitcoincash_addr::Address;
-use clap::{arg, Command};
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use base64ct::{Base64, Encoding};
+use clap::{arg, ArgAction, ArgMatches, Command};
+use failure::format_err;
+use crate::block::{
+    Block, MAX_BLOCK_SIZE, MAX_BLOCK_TXS, RETARGET_INTERVAL, default_mining_threads, difficulty,
+};
 use crate::blockchain::Blockchain;
 use crate::errors::Result;
-use crate::server::Server;
-use crate::transaction::Transaction;
-use crate::utxoset::UTXOSet;
-use crate::wallets::Wallets;
+use crate::datadir::{self, DATADIR_ENV};
+use crate::banlist::{BANLIST_FILE, BanScores, Misbehavior};
+use crate::mempool::{MEMPOOL_FILE, MempoolOptions, unix_time};
+use crate::merkle::MerkleProof;
+use crate::network::{Network, NETWORK_ENV};
+use crate::peerdb::PEERS_FILE;
+use crate::server::{ConnectionOptions, LocalNode, Server};
+use crate::signer::{ExternalSigner, Signer};
+use crate::transaction::{LockingCondition, OutPoint, SigHashType, TXOutput, Transaction, TransactionJson, TxOptions, UnsignedBundle};
+use crate::utxoset::{CoinSelection, ConsistencyReport, SnapshotMeta, UTXOSet};
+use crate::bech32;
+use crate::walletstorage::FileStorage;
+use crate::wallets::{DEFAULT_GAP_LIMIT, address_from_pub_key_hash, decode_address, hash_pub_key, validate_address, verify_message, Wallets};
+
+/// 设置后从这些环境变量读取钱包口令和新口令，不再提示输入
+const WALLET_PASSPHRASE_ENV: &str = "RUSTCHAIN_WALLET_PASSPHRASE";
+const NEW_WALLET_PASSPHRASE_ENV: &str = "RUSTCHAIN_NEW_WALLET_PASSPHRASE";
+/// 设置后从该环境变量读取 JSON 密钥文件的口令
+const KEYSTORE_PASSPHRASE_ENV: &str = "RUSTCHAIN_KEYSTORE_PASSPHRASE";
+/// createvanitywallet 默认的搜索时间
+const DEFAULT_VANITY_TIMEOUT_SECS: u64 = 60;
+/// consolidate 未指定 --max-inputs 时最多合并的输出数量
+const DEFAULT_CONSOLIDATION_INPUTS: usize = 50;
 
 pub struct Cli {}
 
@@ -21,28 +53,295 @@ impl Cli {
             .version("0.1")
             .author("machinaexdues@gmail.com")
             .about("rustchain: a simple blockchain for learning")
-            .subcommand(Command::new("printchain").about("print all the chain blocks"))
+            .arg(arg!(--network <NETWORK> " 'mainnet, testnet or regtest, defaults to $RUSTCHAIN_NETWORK or mainnet'").global(true))
+            .arg(arg!(--datadir <DIR> " 'the directory holding blocks, UTXOs and wallets, defaults to $RUSTCHAIN_DATADIR or the user data directory'").global(true))
+            .arg(arg!(--"mining-threads" <N> " 'the number of threads searching for a nonce, defaults to the number of CPUs'").global(true))
+            .arg(arg!(--nocheckpoints " 'ignore the compiled-in checkpoints, for development'").global(true))
+            .arg(arg!(--maxmempool <MB> " 'the most megabytes of transactions the node keeps in its mempool, default 5'").global(true))
+            .arg(arg!(--mempoolexpiry <HOURS> " 'drop transactions of other wallets from the mempool after this many hours, default 72'").global(true))
+            .arg(arg!(--banscore <N> " 'ban a peer for 24 hours once its misbehavior score reaches N, default 100'").global(true))
+            .arg(arg!(--misbehavior <SCORE> " 'the score of an offense, as name=score: invalid-pow, invalid-block, invalid-tx, malformed-message or unknown-message; repeatable'").action(ArgAction::Append).global(true))
+            .arg(arg!(--bind <HOST> " 'the host the node listens on and tells its peers, and the other commands connect to, default localhost'").global(true))
+            .arg(arg!(--port <PORT> " 'the port the other commands connect to, and startnode listens on without its PORT argument; defaults to the network's port'").global(true))
+            .arg(arg!(--maxinbound <N> " 'accept handshakes from at most N peers, default 32'").global(true))
+            .arg(arg!(--maxoutbound <N> " 'keep up to N connections to peers from the peer addresses, default 8'").global(true))
+            .arg(arg!(--maxconnections <N> " 'handle at most N accepted connections at once and close the others, default 125'").global(true))
+            .arg(arg!(--connect <ADDR> " 'only connect to and accept this peer, as host:port; repeatable'").action(ArgAction::Append).global(true))
+            .arg(arg!(--blockdelay <MS> " 'wait MS milliseconds before sending each requested block, to simulate a slow link in tests, default 0'").global(true))
+            .subcommand(Command::new("printchain")
+                .about("print all the chain blocks")
+                .arg(arg!(--json " 'print the chain as JSON'"))
+            )
+            .subcommand(Command::new("getblockchaininfo").about("print the best block, the current difficulty and the next block's difficulty"))
+            .subcommand(Command::new("getblock")
+                .about("print the header and transaction ids of a block")
+                .arg(arg!([HASH]"'the block hash'").required_unless_present("height").conflicts_with("height"))
+                .arg(arg!(--height <N> " 'look the block up by its height on the main chain instead'"))
+            )
+            .subcommand(Command::new("gettxproof")
+                .about("print a hex proof that a transaction is included in a block")
+                .arg(arg!(<BLOCKHASH>"'the block hash'"))
+                .arg(arg!(<TXID>"'the transaction id'"))
+            )
+            .subcommand(Command::new("verifytxproof")
+                .about("check a proof from gettxproof against the local block header, exit with status 1 when it does not match")
+                .arg(arg!(<PROOF>"'the hex proof'"))
+            )
             .subcommand(Command::new("createwallet").about("create a wallet"))
-            .subcommand(Command::new("listaddresses").about("list all addresses"))
+            .subcommand(Command::new("encryptwallet")
+                .about("encrypt the keys in the wallet file with a passphrase")
+            )
+            .subcommand(Command::new("changepassphrase")
+                .about("re-encrypt the wallet file with a new passphrase")
+            )
+            .subcommand(Command::new("createhdseed")
+                .about("derive all new addresses from a seed; prints the seed and its mnemonic, back them up")
+            )
+            .subcommand(Command::new("exportseed").about("print the 24-word mnemonic of the HD seed"))
+            .subcommand(Command::new("restoreseed")
+                .about("restore an HD wallet from its mnemonic")
+                .arg(arg!(<MNEMONIC>... "'the mnemonic words'"))
+                .arg(arg!(--passphrase <PASSPHRASE> " 'the optional passphrase used with the mnemonic'"))
+                .arg(arg!(--"gap-limit" <COUNT> " 'stop after this many unused addresses in a row, defaults to 20'"))
+                .arg(arg!(--force " 'replace the existing wallet file'"))
+            )
+            .subcommand(Command::new("dumphdseed").about("print the HD seed in hex"))
+            .subcommand(Command::new("restorehdseed")
+                .about("restore the addresses derived from a seed that hold funds")
+                .arg(arg!(<SEED>"'the HD seed in hex'"))
+                .arg(arg!(--"gap-limit" <COUNT> " 'stop after this many unused addresses in a row, defaults to 20'"))
+            )
+            .subcommand(Command::new("createvanitywallet")
+                .about("create a wallet whose address starts with a prefix")
+                .arg(arg!(<PREFIX>"'the Base58 prefix, including the network's leading character'"))
+                .arg(arg!(--timeout <SECONDS> " 'give up after this many seconds, defaults to 60'"))
+                .arg(arg!(-i --"ignore-case" " 'match the prefix case-insensitively'"))
+            )
+            .subcommand(Command::new("listaddresses")
+                .about("list all addresses")
+                .arg(arg!(--bech32 " 'print the addresses in bech32 format'"))
+                .arg(arg!(--"watch-only" " 'also list watch-only addresses'"))
+                .arg(arg!(--balances " 'also print the balance of each address'"))
+            )
+            .subcommand(Command::new("listbalances")
+                .about("list the balance of every address in the wallet and the total")
+                .arg(arg!(--"watch-only" " 'also list watch-only addresses'"))
+                .arg(arg!(--json " 'print the balances as JSON'"))
+            )
+            .subcommand(Command::new("setlabel")
+                .about("label an address in the wallet, an empty label removes it")
+                .arg(arg!(<ADDRESS>"'the address to label'"))
+                .arg(arg!(<LABEL>"'the label'"))
+            )
+            .subcommand(Command::new("addcontact")
+                .about("add an external address to the address book")
+                .arg(arg!(<NAME>"'the contact name, usable as the destination of send'"))
+                .arg(arg!(<ADDRESS>"'the contact address'"))
+            )
+            .subcommand(Command::new("listcontacts").about("list the address book"))
+            .subcommand(Command::new("addwatchonly")
+                .about("track the balance of an address without holding its key")
+                .arg(arg!(<ADDRESS>"'the address to watch'"))
+                .arg(arg!(--label <LABEL> " 'a note to show next to the address'"))
+            )
+            .subcommand(Command::new("dumpprivkey")
+                .about("print the private key of an address in WIF")
+                .arg(arg!(<ADDRESS>"'the address whose key to print'"))
+            )
+            .subcommand(Command::new("importprivkey")
+                .about("add a private key exported with dumpprivkey")
+                .arg(arg!(<WIF>"'the private key in WIF'"))
+                .arg(arg!(--rescan " 'rebuild the UTXO set and print the balance of the key'"))
+            )
+            .subcommand(Command::new("exportkeystore")
+                .about("write the key of an address to a passphrase-protected JSON keystore file")
+                .arg(arg!(<ADDRESS>"'the address whose key is exported'"))
+                .arg(arg!(<FILE>"'the keystore file to create'"))
+            )
+            .subcommand(Command::new("importkeystore")
+                .about("add the key in a JSON keystore file")
+                .arg(arg!(<FILE>"'the keystore file'"))
+            )
             .subcommand(Command::new("reindex").about("reindex UTXO"))
+            .subcommand(Command::new("reindex-tx").about("build the transaction index from the main chain and keep it up to date from now on"))
+            .subcommand(Command::new("getutxostats").about("print the number of unspent outputs, the circulating supply and the size of the UTXO set"))
+            .subcommand(Command::new("lockunspent")
+                .about("exclude an unspent output from automatic coin selection")
+                .arg(arg!(<OUTPOINT>"'the output to freeze, as txid:n'"))
+                .arg(arg!(--unlock " 'unfreeze the output instead'"))
+            )
+            .subcommand(Command::new("listlockunspent").about("list the frozen unspent outputs"))
+            .subcommand(Command::new("checkutxoindex").about("cross-check the UTXO set against its index by public key hash"))
+            .subcommand(Command::new("gettxout")
+                .about("print an unspent transaction output")
+                .arg(arg!(<TXID>"'the transaction id'"))
+                .arg(arg!(<N>"'the output index'"))
+            )
+            .subcommand(Command::new("getutxocommitment").about("print a hash committing to the whole UTXO set, equal on nodes whose sets agree"))
+            .subcommand(Command::new("verifyutxo")
+                .about("compare the UTXO set with the blockchain, exit with status 1 when they differ")
+                .arg(arg!(--repair " 'rewrite the differing outputs instead of only reporting them'"))
+            )
+            .subcommand(Command::new("compactutxo")
+                .about("remove empty records and dangling index entries from the UTXO database")
+            )
+            .subcommand(Command::new("verifychain")
+                .about("recheck the stored blocks, exit with status 1 at the first problem")
+                .arg(arg!([LEVEL]"'1 checks linkage and proof of work, 2 also transaction ids and merkle roots, 3 also replays the chain and compares the UTXO set; defaults to 3'"))
+            )
+            .subcommand(Command::new("exportchain")
+                .about("write the main chain's blocks in height order to a portable file")
+                .arg(arg!(<FILE>"'the file to write'"))
+            )
+            .subcommand(Command::new("importchain")
+                .about("rebuild the blockchain and UTXO set in an empty data directory from an exportchain file, validating every block")
+                .arg(arg!(<FILE>"'the file to read'"))
+            )
+            .subcommand(Command::new("dumputxoset")
+                .about("write a snapshot of the UTXO set to a file")
+                .arg(arg!(<FILE>"'the snapshot file to write'"))
+            )
+            .subcommand(Command::new("loadutxoset")
+                .about("replace the UTXO set with a snapshot, sync continues from the snapshot's tip")
+                .arg(arg!(<FILE>"'the snapshot file to load'"))
+                .arg(arg!(--force " 'replace a UTXO set that is not empty'"))
+            )
+            .subcommand(Command::new("gettransaction")
+                .about("print a transaction on the main chain with its block and confirmations")
+                .arg(arg!(<TXID>"'the transaction id'"))
+            )
+            .subcommand(Command::new("stopnode").about("ask the local node to save its mempool and exit"))
+            .subcommand(Command::new("getblockcount").about("ask the local node for the height of its best block"))
+            .subcommand(Command::new("getmempoolinfo").about("ask the local node for the size of its mempool and the lowest fee rate it accepts"))
+            .subcommand(Command::new("getrawmempool")
+                .about("print the txids in the local node's mempool as JSON")
+                .arg(arg!(-v --verbose " 'print size, fee, fee rate, time added and the mempool transactions each one spends'")))
+            .subcommand(Command::new("getmempoolentry")
+                .about("print a transaction in the local node's mempool as JSON")
+                .arg(arg!(<TXID>" 'the transaction id'")))
+            .subcommand(Command::new("getpeerinfo").about("print the peers the local node completed a handshake with, their negotiated protocol version and user agent as JSON"))
+            .subcommand(Command::new("setban")
+                .about("ban an IP address on the local node for 24 hours and disconnect the nodes on it, or lift the ban")
+                .arg(arg!(<ADDR>" 'the IP address of the nodes'"))
+                .arg(arg!(<COMMAND>" 'add or remove'")))
+            .subcommand(Command::new("listbanned").about("print the IP addresses banned by the local node and when their bans end as JSON"))
+            .subcommand(Command::new("clearbanned").about("lift all bans on the local node"))
+            .subcommand(Command::new("listpending").about("list the transactions in the local node's mempool with their age, marking the wallet's own"))
+            .subcommand(Command::new("bumpfee")
+                .about("replace an unconfirmed transaction of the wallet with one paying a higher fee")
+                .arg(arg!(<TXID>" 'the transaction in the local node's mempool'"))
+                .arg(arg!(<NEWFEE>" 'the new total fee, taken from the change'")))
+            .subcommand(Command::new("decoderawtransaction")
+                .about("decode a raw transaction")
+                .arg(arg!(<HEX>"'the raw transaction in hex'"))
+            )
+            .subcommand(Command::new("createmultisig")
+                .about("print the address of an m-of-n multisig condition")
+                .arg(arg!(<M>"'the number of signatures required'"))
+                .arg(arg!(<ADDRESSES>... "'the addresses whose keys may sign'"))
+            )
+            .subcommand(Command::new("spendmultisig")
+                .about("build an unsigned raw transaction spending a multisig output")
+                .arg(arg!(<OUTPOINT>"'the multisig output as txid:n'"))
+                .arg(arg!(<TO>"'Destination wallet address'"))
+                .arg(arg!(<AMOUNT>"'the amount to send'"))
+                .arg(arg!(<M>"'the number of signatures required'"))
+                .arg(arg!(<ADDRESSES>... "'the addresses whose keys may sign'"))
+                .arg(arg!(-f --fee <FEE> " 'the fee paid to the miner'"))
+            )
+            .subcommand(Command::new("signrawtransaction")
+                .about("add a wallet's signature to a raw transaction")
+                .arg(arg!(<HEX>"'the raw transaction in hex'"))
+                .arg(arg!(<ADDRESS>"'the wallet address to sign with'"))
+                .arg(arg!(--sighash <TYPE> " 'signature scope: all, single or anyonecanpay'"))
+                .arg(arg!(--signer <COMMAND> " 'sign with an external command holding the key of ADDRESS'"))
+            )
+            .subcommand(Command::new("signer")
+                .about("act as an external signer: print the public key, or sign the message read from stdin")
+                .arg(arg!(<ADDRESS>"'the wallet address to sign with'"))
+                .arg(arg!(<REQUEST>"'pubkey or sign'"))
+            )
+            .subcommand(Command::new("getpubkey")
+                .about("print the public key of a wallet in hex")
+                .arg(arg!(<ADDRESS>"'the wallet address'"))
+            )
+            .subcommand(Command::new("signmessage")
+                .about("sign a message with a wallet key to prove ownership of its address")
+                .arg(arg!(<ADDRESS>"'the wallet address to sign with'"))
+                .arg(arg!(<MESSAGE>"'the message to sign'"))
+            )
+            .subcommand(Command::new("verifymessage")
+                .about("check a message signature produced by signmessage")
+                .arg(arg!(<ADDRESS>"'the address that claims to have signed'"))
+                .arg(arg!(<SIGNATURE>"'the signature in base64'"))
+                .arg(arg!(<MESSAGE>"'the signed message'"))
+            )
+            .subcommand(Command::new("buildunsigned")
+                .about("write an unsigned transaction and its inputs to a file for offline signing")
+                .arg(arg!(<PUBKEY>"'the public key of the source wallet in hex'"))
+                .arg(arg!(<TO>"'Destination wallet address'"))
+                .arg(arg!(<AMOUNT>"'the amount to send'"))
+                .arg(arg!(<FILE>"'the file to write the unsigned transaction to'"))
+                .arg(arg!(-f --fee <FEE> " 'the fee paid to the miner'"))
+            )
+            .subcommand(Command::new("signbundle")
+                .about("sign a transaction file written by buildunsigned and print the raw transaction")
+                .arg(arg!(<FILE>"'the unsigned transaction file'"))
+                .arg(arg!(<ADDRESS>"'the wallet address to sign with'"))
+                .arg(arg!(--sighash <TYPE> " 'signature scope: all, single or anyonecanpay'"))
+                .arg(arg!(--signer <COMMAND> " 'sign with an external command holding the key of ADDRESS'"))
+            )
+            .subcommand(Command::new("sendrawtransaction")
+                .about("broadcast a raw transaction")
+                .arg(arg!(<HEX>"'the raw transaction in hex'"))
+            )
             .subcommand(Command::new("getbalance")
                 .about("get balance in the blochain")
                 .arg(arg!(<ADDRESS>"'The Address it get balance for'"))
+                .arg(arg!(--immature " 'also show coinbase rewards that cannot be spent yet'"))
+                .arg(arg!(--minconf <N> " 'count only outputs with at least N confirmations, default 1'"))
             ).subcommand(Command::new("startnode")
             .about("start the node server")
-            .arg(arg!(<PORT>"'the port server bind to locally'"))
+            .arg(arg!([PORT]"'the port server bind to locally, default 3000, 13000 on testnet and 23000 on regtest'"))
         )
             .subcommand(Command::new("create").about("Create new blochain")
                 .arg(arg!(<ADDRESS>"'The address to send gensis block reqward to' "))
+                .arg(arg!(--message <TEXT> " 'the message carried by the genesis block, defaults to the network's message'"))
+            )
+            .subcommand(Command::new("createblockchain")
+                .about("create a new blockchain in the data directory, fails when one already exists")
+                .arg(arg!(--address <ADDRESS> " 'the address the genesis reward is paid to'").required(true))
+                .arg(arg!(--message <TEXT> " 'the message carried by the genesis block, defaults to the network's message'"))
             )
 
             .subcommand(
                 Command::new("send")
                     .about("send  in the blockchain")
                     .arg(arg!(<FROM>" 'Source wallet address'"))
-                    .arg(arg!(<TO>" 'Destination wallet address'"))
+                    .arg(arg!(<TO>" 'Destination wallet address or contact name'"))
                     .arg(arg!(<AMOUNT>" 'Destination wallet address'"))
-                    .arg(arg!(-m --mine " 'the from address mine immediately'")),
+                    .arg(arg!(-m --mine " 'the from address mine immediately'"))
+                    .arg(arg!(-f --fee <FEE> " 'the fee paid to the miner'"))
+                    .arg(arg!(--feerate <RATE> " 'the fee per byte used by --dry-run'"))
+                    .arg(arg!(--"dry-run" " 'only print the estimated fee'"))
+                    .arg(arg!(-s --strategy <STRATEGY> " 'coin selection: largest, smallest, accumulate or bnb'"))
+                    .arg(arg!(--minconf <N> " 'only spend outputs with at least N confirmations, default 1'"))
+                    .arg(arg!(--input <OUTPOINT> " 'spend this output, as txid:n, instead of selecting coins; repeatable'").action(ArgAction::Append).conflicts_with("dry-run"))
+                    .arg(arg!(--"reuse-address" " 'send change back to the source address'"))
+                    .arg(arg!(-d --data <DATA> " 'embed up to 80 bytes of data in the transaction'"))
+                    .arg(arg!(--memo <MEMO> " 'attach a memo of up to 256 bytes'"))
+                    .arg(arg!(--locktime <HEIGHT> " 'the lowest block height the transaction can be mined into'"))
+                    .arg(arg!(--sighash <TYPE> " 'signature scope: all, single or anyonecanpay'"))
+                    .arg(arg!(--raw " 'print the signed raw transaction instead of sending it'")),
+            )
+            .subcommand(
+                Command::new("consolidate")
+                    .about("merge the smallest outputs of an address into one output back to it")
+                    .arg(arg!(<ADDRESS>" 'the address whose outputs are merged'"))
+                    .arg(arg!(--"max-inputs" <COUNT> " 'merge at most this many outputs, default 50'"))
+                    .arg(arg!(-f --fee <FEE> " 'the fee paid to the miner'"))
+                    .arg(arg!(-m --mine " 'the address mines the transaction immediately'"))
+                    .arg(arg!(--"dry-run" " 'only report how many outputs would be merged'")),
             )
             .subcommand(
                 Command::new("startminer")
@@ -52,8 +351,13 @@ impl Cli {
 
             )
             .get_matches();
+        let network = select_network(&matches)?;
+        let data_dir: &Path = &select_data_dir(&matches)?;
+        let mining_threads = select_mining_threads(&matches)?;
+        let use_checkpoints = !matches.get_flag("nocheckpoints");
+        let node = &select_local_node(&matches, network)?;
 
-        if let Some(ref matches) = matches.subcommand_matches("startminer") {
+        if let Some(matches) = matches.subcommand_matches("startminer") {
             let port = if let Some(port) = matches.get_one::<String>("PORT") {
                 port
             } else {
@@ -67,50 +371,528 @@ impl Cli {
                 println!("ADDRESS not supply!: usage");
                 exit(1)
             };
-            let bc = Blockchain::new()?;
-            let utxo_set = UTXOSet { blockchain: bc };
-            let server = Server::new(port, address, utxo_set)?;
+            validate_address(address, network)?;
+            let mut bc = open_for_validation(network, data_dir, use_checkpoints)?;
+            bc.mining_threads = mining_threads;
+            let utxo_set = UTXOSet::new(bc, data_dir);
+            let mut server = Server::new(port, address, utxo_set)?;
+            configure_server(&mut server, network, data_dir, matches)?;
             server.start_server()?;
         }
 
 
-        if let Some(ref matches) = matches.subcommand_matches("startnode") {
-            if let Some(port) = matches.get_one::<String>("PORT") {
-                let bc = Blockchain::new()?;
-                let utxo_set = UTXOSet { blockchain: bc };
-                let server = Server::new(port, "", utxo_set)?;
-                server.start_server()?;
-            }
+        if let Some(matches) = matches.subcommand_matches("startnode") {
+            let port = matches
+                .get_one::<String>("PORT")
+                .or(matches.get_one::<String>("port"))
+                .map(String::as_str)
+                .unwrap_or(network.default_port());
+            let bc = open_for_validation(network, data_dir, use_checkpoints)?;
+            let utxo_set = UTXOSet::new(bc, data_dir);
+            let mut server = Server::new(port, "", utxo_set)?;
+            configure_server(&mut server, network, data_dir, matches)?;
+            server.start_server()?;
         }
 
-        if let Some(_) = matches.subcommand_matches("createwallet") {
-            println!("address: {}", cmd_create_wallet()?);
+        if matches.subcommand_matches("createwallet").is_some() {
+            println!("address: {}", cmd_create_wallet(network, data_dir)?);
+        }
+        if matches.subcommand_matches("encryptwallet").is_some() {
+            if Wallets::is_file_encrypted(network, data_dir)? {
+                return Err(format_err!("Wallet file is already encrypted, use changepassphrase"));
+            }
+            let mut ws = Wallets::open_for_write(network, data_dir, None)?;
+            ws.encrypt(&read_new_passphrase()?)?;
+            ws.save_all()?;
+            println!("Wallet file encrypted");
+        }
+        if matches.subcommand_matches("changepassphrase").is_some() {
+            if !Wallets::is_file_encrypted(network, data_dir)? {
+                return Err(format_err!("Wallet file is not encrypted, use encryptwallet"));
+            }
+            let mut ws = open_wallets_for_write(network, data_dir)?;
+            ws.change_passphrase(&read_new_passphrase()?)?;
+            ws.save_all()?;
+            println!("Passphrase changed");
+        }
+        if matches.subcommand_matches("createhdseed").is_some() {
+            let mut ws = open_wallets_for_write(network, data_dir)?;
+            let seed = ws.create_hd_seed()?;
+            ws.save_all()?;
+            println!("seed: {}", hex::encode(seed));
+            println!("mnemonic: {}", ws.export_mnemonic()?);
+        }
+        if matches.subcommand_matches("exportseed").is_some() {
+            println!("{}", open_wallets(network, data_dir)?.export_mnemonic()?);
+        }
+        if let Some(matches) = matches.subcommand_matches("restoreseed") {
+            let phrase: Vec<&str> = matches
+                .get_many::<String>("MNEMONIC")
+                .unwrap()
+                .map(|word| word.as_str())
+                .collect();
+            let passphrase = matches
+                .get_one::<String>("passphrase")
+                .map_or("", |p| p.as_str());
+            let restored = cmd_restore_seed(network, data_dir, 
+                &phrase.join(" "),
+                passphrase,
+                parse_gap_limit(matches)?,
+                matches.get_flag("force"),
+            )?;
+            println!("Restored {} addresses", restored);
+        }
+        if matches.subcommand_matches("dumphdseed").is_some() {
+            let ws = open_wallets(network, data_dir)?;
+            let hd = ws
+                .hd_seed()
+                .ok_or_else(|| format_err!("Wallet file has no HD seed"))?;
+            println!("seed: {}", hex::encode(&hd.seed));
+            println!("next index: {}", hd.next_index);
         }
-        if let Some(_) = matches.subcommand_matches("reindex") {
-            let count = cmd_reindex()?;
+        if let Some(matches) = matches.subcommand_matches("restorehdseed") {
+            let seed = matches.get_one::<String>("SEED").unwrap();
+            let seed = hex::decode(seed).map_err(|e| format_err!("Invalid seed: {}", e))?;
+            let gap_limit = parse_gap_limit(matches)?;
+            let bc = Blockchain::open(network, data_dir)?;
+            let utxo_set = UTXOSet::new(bc, data_dir);
+            let mut ws = open_wallets_for_write(network, data_dir)?;
+            let restored = ws.restore_from_seed(seed, &utxo_set, gap_limit)?;
+            println!("Restored {} addresses", restored);
+        }
+        if let Some(matches) = matches.subcommand_matches("createvanitywallet") {
+            let prefix = matches.get_one::<String>("PREFIX").unwrap();
+            let timeout = if let Some(timeout) = matches.get_one::<String>("timeout") {
+                timeout
+                    .parse::<u64>()
+                    .map_err(|e| format_err!("Invalid timeout '{}': {}", timeout, e))?
+            } else {
+                DEFAULT_VANITY_TIMEOUT_SECS
+            };
+            let address = cmd_create_vanity_wallet(network, data_dir, 
+                prefix,
+                matches.get_flag("ignore-case"),
+                Duration::from_secs(timeout),
+            )?;
+            println!("address: {}", address);
+        }
+        if matches.subcommand_matches("reindex").is_some() {
+            let count = cmd_reindex(network, data_dir)?;
             println!("Done! There are {} transactions in the UTXO set.", count);
         }
+        if matches.subcommand_matches("reindex-tx").is_some() {
+            let bc = Blockchain::open(network, data_dir)?;
+            let count = bc.reindex_transactions(progress("Indexing transactions"))?;
+            println!("Done! Indexed {} transactions.", count);
+        }
+
+        if matches.subcommand_matches("getutxostats").is_some() {
+            let stats = UTXOSet::new(Blockchain::open(network, data_dir)?, data_dir).stats()?;
+            println!("outputs: {}", stats.outputs);
+            println!("transactions: {}", stats.transactions);
+            println!("total amount: {}", stats.total_amount);
+            println!("disk size: {} bytes", stats.disk_size);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("lockunspent") {
+            let outpoint: OutPoint = matches.get_one::<String>("OUTPOINT").unwrap().parse()?;
+            let utxo_set = UTXOSet::new(Blockchain::open(network, data_dir)?, data_dir);
+            if matches.get_flag("unlock") {
+                utxo_set.unfreeze(&outpoint)?;
+                println!("unfroze {}", outpoint);
+            } else {
+                utxo_set.freeze(&outpoint)?;
+                println!("froze {}", outpoint);
+            }
+        }
+
+        if matches.subcommand_matches("listlockunspent").is_some() {
+            for (outpoint, out) in UTXOSet::new(Blockchain::open(network, data_dir)?, data_dir).list_frozen()? {
+                println!("{} {} {}", outpoint, address_from_pub_key_hash(&out.pub_key_hash, network), out.value);
+            }
+        }
+
+        if matches.subcommand_matches("checkutxoindex").is_some() {
+            let indexed = UTXOSet::new(Blockchain::open(network, data_dir)?, data_dir).check_index()?;
+            println!("The UTXO index is consistent: {} outputs indexed.", indexed);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("gettxout") {
+            let txid = matches.get_one::<String>("TXID").unwrap();
+            let vout = matches.get_one::<String>("N").unwrap();
+            let vout: u32 = vout.parse().map_err(|_| format_err!("Invalid output index {}", vout))?;
+            cmd_get_tx_out(network, data_dir, &OutPoint::new(txid, vout))?;
+        }
+
+        if matches.subcommand_matches("getblockchaininfo").is_some() {
+            cmd_get_blockchain_info(network, data_dir)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getblock") {
+            let bc = Blockchain::open(network, data_dir)?;
+            let block = match matches.get_one::<String>("height") {
+                Some(height) => {
+                    let height = height.parse().map_err(|e| format_err!("Invalid height '{}': {}", height, e))?;
+                    bc.get_block_by_height(height)?
+                }
+                None => bc.get_block(matches.get_one::<String>("HASH").unwrap())?,
+            };
+            print_block(&block);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("gettxproof") {
+            let block_hash = matches.get_one::<String>("BLOCKHASH").unwrap();
+            let txid = matches.get_one::<String>("TXID").unwrap();
+            let proof = Blockchain::open(network, data_dir)?.get_merkle_proof(block_hash, txid)?;
+            println!("{}", proof.to_hex()?);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("verifytxproof") {
+            let proof = MerkleProof::from_hex(matches.get_one::<String>("PROOF").unwrap())?;
+            let block = Blockchain::open(network, data_dir)?.get_block(&proof.block_hash)?;
+            block.verify_merkle_root()?;
+            if proof.verify(&block.get_merkle_root(), &proof.txid) {
+                println!("Transaction {} is in block {} at height {}", proof.txid, proof.block_hash, block.get_height());
+            } else {
+                println!("The proof of {} does not match block {}", proof.txid, proof.block_hash);
+                exit(1);
+            }
+        }
+
+        if matches.subcommand_matches("getutxocommitment").is_some() {
+            let commitment = UTXOSet::new(Blockchain::open(network, data_dir)?, data_dir).commitment()?;
+            println!("{}", hex::encode(commitment));
+        }
+
+        if let Some(matches) = matches.subcommand_matches("verifyutxo") {
+            let utxo_set = UTXOSet::new(Blockchain::open(network, data_dir)?, data_dir);
+            if matches.get_flag("repair") {
+                let report = utxo_set.repair_against_chain()?;
+                print_consistency_report(&report);
+                if !report.is_consistent() {
+                    println!("Repaired the UTXO set.");
+                }
+            } else {
+                let report = utxo_set.verify_against_chain()?;
+                print_consistency_report(&report);
+                if !report.is_consistent() {
+                    exit(1);
+                }
+            }
+        }
+
+        if matches.subcommand_matches("compactutxo").is_some() {
+            let report = UTXOSet::new(Blockchain::open(network, data_dir)?, data_dir).compact()?;
+            println!("records: {} -> {}", report.before, report.after);
+            println!("dangling index entries removed: {}", report.index_removed);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("verifychain") {
+            let level = match matches.get_one::<String>("LEVEL") {
+                Some(level) => level.parse().map_err(|e| format_err!("Invalid check level '{}': {}", level, e))?,
+                None => 3,
+            };
+            let utxo_set = UTXOSet::new(open_for_validation(network, data_dir, use_checkpoints)?, data_dir);
+            let report = utxo_set.blockchain.verify_chain(level, &utxo_set)?;
+            println!("verified {} blocks at level {}", report.verified, report.level);
+            if let Some(failure) = report.failure {
+                println!("height {}: {}: {}", failure.height, failure.fault, failure.message);
+                exit(1);
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("exportchain") {
+            let path = Path::new(matches.get_one::<String>("FILE").unwrap());
+            let blocks = Blockchain::open(network, data_dir)?.export(path, progress("Exporting blocks"))?;
+            println!("exported {} blocks to {}", blocks, path.display());
+        }
+
+        if let Some(matches) = matches.subcommand_matches("importchain") {
+            let path = Path::new(matches.get_one::<String>("FILE").unwrap());
+            let utxo_set = Blockchain::import(path, network, data_dir, use_checkpoints, progress("Importing blocks"))?;
+            println!("imported {} blocks", utxo_set.blockchain.get_best_height()? + 1);
+            println!("best block: {}", utxo_set.blockchain.tip);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("dumputxoset") {
+            let path = Path::new(matches.get_one::<String>("FILE").unwrap());
+            let meta = UTXOSet::new(Blockchain::open(network, data_dir)?, data_dir).export_snapshot(path)?;
+            print_snapshot_meta(&meta);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("loadutxoset") {
+            let path = Path::new(matches.get_one::<String>("FILE").unwrap());
+            let meta =
+                UTXOSet::new(Blockchain::open(network, data_dir)?, data_dir).import_snapshot(path, matches.get_flag("force"))?;
+            print_snapshot_meta(&meta);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("gettransaction") {
+            cmd_get_transaction(network, data_dir, matches.get_one::<String>("TXID").unwrap())?;
+        }
+
+        if matches.subcommand_matches("stopnode").is_some() {
+            node.stop_node()?;
+            println!("Node stopped");
+        }
 
-        if let Some(_) = matches.subcommand_matches("listaddresses") {
-            cmd_list_address()?;
+        if matches.subcommand_matches("getblockcount").is_some() {
+            println!("{}", node.get_block_count()?);
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("create") {
-            if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                cmd_create_blockchain(address)?;
+        if matches.subcommand_matches("getmempoolinfo").is_some() {
+            cmd_get_mempool_info(node)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getrawmempool") {
+            cmd_get_raw_mempool(node, matches.get_flag("verbose"))?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getmempoolentry") {
+            cmd_get_mempool_entry(node, matches.get_one::<String>("TXID").unwrap())?;
+        }
+
+        if matches.subcommand_matches("getpeerinfo").is_some() {
+            cmd_get_peer_info(node)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("setban") {
+            let addr = matches.get_one::<String>("ADDR").unwrap();
+            let command = matches.get_one::<String>("COMMAND").unwrap();
+            cmd_set_ban(node, addr, command)?;
+        }
+
+        if matches.subcommand_matches("listbanned").is_some() {
+            cmd_list_banned(node)?;
+        }
+
+        if matches.subcommand_matches("clearbanned").is_some() {
+            cmd_clear_banned(node)?;
+        }
+
+        if matches.subcommand_matches("listpending").is_some() {
+            cmd_list_pending(node)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("bumpfee") {
+            let fee = parse_amount(matches.get_one::<String>("NEWFEE").unwrap())?;
+            cmd_bump_fee(network, data_dir, node, matches.get_one::<String>("TXID").unwrap(), fee)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("decoderawtransaction")
+            && let Some(raw) = matches.get_one::<String>("HEX")
+        {
+            cmd_decode_raw_transaction(raw, network)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("createmultisig") {
+            let condition = parse_multisig(matches, network)?;
+            println!("address: {}", condition.address(network));
+        }
+
+        if let Some(matches) = matches.subcommand_matches("spendmultisig") {
+            let condition = parse_multisig(matches, network)?;
+            let outpoint: OutPoint = matches.get_one::<String>("OUTPOINT").unwrap().parse()?;
+            let to = matches.get_one::<String>("TO").unwrap();
+            validate_address(to, network)?;
+            let amount = parse_amount(matches.get_one::<String>("AMOUNT").unwrap())?;
+            let fee = if let Some(fee) = matches.get_one::<String>("fee") {
+                parse_amount(fee)?
+            } else {
+                0
+            };
+            println!("{}", cmd_spend_multisig(network, data_dir, &outpoint, &condition, to, amount, fee)?);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("signrawtransaction")
+            && let Some(raw) = matches.get_one::<String>("HEX")
+            && let Some(address) = matches.get_one::<String>("ADDRESS")
+        {
+            let sighash: SigHashType = if let Some(sighash) = matches.get_one::<String>("sighash") {
+                sighash.parse()?
+            } else {
+                SigHashType::default()
+            };
+            let signer = matches.get_one::<String>("signer").map(String::as_str);
+            println!("{}", cmd_sign_raw_transaction(network, data_dir, raw, address, sighash, signer)?);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("signer") {
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            let request = matches.get_one::<String>("REQUEST").unwrap();
+            println!("{}", cmd_signer(network, data_dir, address, request)?);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getpubkey")
+            && let Some(address) = matches.get_one::<String>("ADDRESS")
+        {
+            println!("{}", cmd_get_pub_key(network, data_dir, address)?);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("signmessage") {
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            let message = matches.get_one::<String>("MESSAGE").unwrap();
+            println!("{}", cmd_sign_message(network, data_dir, address, message)?);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("verifymessage") {
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            let signature = matches.get_one::<String>("SIGNATURE").unwrap();
+            let message = matches.get_one::<String>("MESSAGE").unwrap();
+            if cmd_verify_message(address, signature, message, network)? {
+                println!("Signature is valid");
+            } else {
+                println!("Signature is NOT valid");
+                exit(1);
             }
+        }
 
+        if let Some(matches) = matches.subcommand_matches("buildunsigned") {
+            let pub_key = hex::decode(matches.get_one::<String>("PUBKEY").unwrap())
+                .map_err(|e| format_err!("Invalid public key: {}", e))?;
+            let to = matches.get_one::<String>("TO").unwrap();
+            validate_address(to, network)?;
+            let amount = parse_amount(matches.get_one::<String>("AMOUNT").unwrap())?;
+            let file = matches.get_one::<String>("FILE").unwrap();
+            let fee = if let Some(fee) = matches.get_one::<String>("fee") {
+                parse_amount(fee)?
+            } else {
+                0
+            };
+            let txid = cmd_build_unsigned(network, data_dir, &pub_key, to, amount, fee, file)?;
+            println!("unsigned transaction {} written to {}", txid, file);
         }
 
+        if let Some(matches) = matches.subcommand_matches("signbundle")
+            && let Some(file) = matches.get_one::<String>("FILE")
+            && let Some(address) = matches.get_one::<String>("ADDRESS")
+        {
+            let sighash: SigHashType = if let Some(sighash) = matches.get_one::<String>("sighash") {
+                sighash.parse()?
+            } else {
+                SigHashType::default()
+            };
+            let signer = matches.get_one::<String>("signer").map(String::as_str);
+            println!("{}", cmd_sign_bundle(network, data_dir, file, address, sighash, signer)?);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("sendrawtransaction")
+            && let Some(raw) = matches.get_one::<String>("HEX")
+        {
+            cmd_send_raw_transaction(network, data_dir, node, raw)?;
+        }
 
-        if let Some(ref matches) = matches.subcommand_matches("getbalance") {
-            if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                let balance = cmd_get_balance(address)?;
-                println!("Balance: {}\n", balance);
+        if let Some(matches) = matches.subcommand_matches("listaddresses") {
+            cmd_list_address(network, data_dir, matches.get_flag("bech32"), matches.get_flag("watch-only"), matches.get_flag("balances"))?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("listbalances") {
+            cmd_list_balances(network, data_dir, matches.get_flag("watch-only"), matches.get_flag("json"))?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("setlabel") {
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            let label = matches.get_one::<String>("LABEL").unwrap();
+            let mut ws = open_wallets_for_write(network, data_dir)?;
+            ws.set_label(address, label)?;
+            ws.save_all()?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("addcontact") {
+            let name = matches.get_one::<String>("NAME").unwrap();
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            let mut ws = open_wallets_for_write(network, data_dir)?;
+            ws.add_contact(name, address)?;
+            ws.save_all()?;
+            println!("added contact {}: {}", name, address);
+        }
+
+        if matches.subcommand_matches("listcontacts").is_some() {
+            for (name, address) in open_wallets(network, data_dir)?.get_contacts() {
+                println!("{}: {}", name, address);
             }
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("send") {
+        if let Some(matches) = matches.subcommand_matches("addwatchonly") {
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            cmd_add_watch_only(network, data_dir, address, matches.get_one::<String>("label").cloned())?;
+            println!("watching {}", address);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("dumpprivkey") {
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            println!("{}", open_wallets(network, data_dir)?.get_spending_wallet(address)?.export_wif(network));
+        }
+
+        if let Some(matches) = matches.subcommand_matches("importprivkey") {
+            let wif = matches.get_one::<String>("WIF").unwrap();
+            let address = open_wallets_for_write(network, data_dir)?.import_wif(wif)?;
+            println!("address: {}", address);
+            if matches.get_flag("rescan") {
+                cmd_reindex(network, data_dir)?;
+                println!("Balance: {}", cmd_get_balance(network, data_dir, &address)?);
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("exportkeystore") {
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            let file = matches.get_one::<String>("FILE").unwrap();
+            cmd_export_keystore(network, data_dir, address, file)?;
+            println!("key of {} written to {}", address, file);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("importkeystore") {
+            let file = matches.get_one::<String>("FILE").unwrap();
+            println!("address: {}", cmd_import_keystore(network, data_dir, file)?);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("create")
+            && let Some(address) = matches.get_one::<String>("ADDRESS")
+        {
+            let message = matches.get_one::<String>("message").map_or("", String::as_str);
+            cmd_create_blockchain(network, data_dir, address, message)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("createblockchain") {
+            let address = matches.get_one::<String>("address").unwrap();
+            let message = matches.get_one::<String>("message").map_or("", String::as_str);
+            cmd_create_blockchain(network, data_dir, address, message)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getbalance")
+            && let Some(address) = matches.get_one::<String>("ADDRESS")
+        {
+            let min_conf = parse_min_conf(matches)?;
+            let utxo_set = UTXOSet::new(Blockchain::open(network, data_dir)?, data_dir);
+            let balance = utxo_set.get_address_balance(address)?;
+            let confirmed = utxo_set.get_balance_with_conf(address, min_conf)?;
+            println!("Balance: {}\n", confirmed);
+            if balance.spendable > confirmed {
+                println!("Pending: {}\n", balance.spendable - confirmed);
+            }
+            if balance.frozen > 0 {
+                println!("Frozen: {}\n", balance.frozen);
+            }
+            if matches.get_flag("immature") {
+                println!("Immature: {}\n", cmd_get_immature_balance(network, data_dir, address)?);
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("consolidate") {
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            let max_inputs = match matches.get_one::<String>("max-inputs") {
+                Some(count) => count.parse().map_err(|e| format_err!("Invalid input count '{}': {}", count, e))?,
+                None => DEFAULT_CONSOLIDATION_INPUTS,
+            };
+            let fee = match matches.get_one::<String>("fee") {
+                Some(fee) => parse_amount(fee)?,
+                None => 0,
+            };
+            let mine = matches.get_flag("mine").then_some(mining_threads);
+            cmd_consolidate(network, data_dir, node, address, max_inputs, fee, mine, matches.get_flag("dry-run"))?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("send") {
             let from = if let Some(address) = matches.get_one::<String>("FROM") {
                 address
             } else {
@@ -125,98 +907,1029 @@ impl Cli {
                 exit(1)
             };
 
-            let amount: i32 = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
-                amount.parse()?
+            let amount = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
+                parse_amount(amount)?
             } else {
                 println!("from not supply!: usage");
                 exit(1)
             };
+            // 地址有误时在选币之前报错，TO 也可以是通讯录中的联系人，打开钱包后再解析
+            validate_address(from, network)?;
+
+            let fee = if let Some(fee) = matches.get_one::<String>("fee") {
+                parse_amount(fee)?
+            } else {
+                0
+            };
 
-            if matches.contains_id("mine") {
-                cmd_send(from, to, amount, true)?;
+            let strategy: CoinSelection = if let Some(strategy) = matches.get_one::<String>("strategy") {
+                strategy.parse()?
             } else {
-                cmd_send(from, to, amount, false)?;
+                CoinSelection::default()
+            };
+
+            let min_conf = parse_min_conf(matches)?;
+
+            let sighash: SigHashType = if let Some(sighash) = matches.get_one::<String>("sighash") {
+                sighash.parse()?
+            } else {
+                SigHashType::default()
+            };
+
+            let lock_until: i32 = if let Some(height) = matches.get_one::<String>("locktime") {
+                height.parse()?
+            } else {
+                0
+            };
+
+            let inputs = match matches.get_many::<String>("input") {
+                Some(inputs) => inputs.map(|input| input.parse()).collect::<Result<Vec<OutPoint>>>()?,
+                None => Vec::new(),
+            };
+
+            if matches.get_flag("dry-run") {
+                let fee_rate = if let Some(rate) = matches.get_one::<String>("feerate") {
+                    parse_amount(rate)?
+                } else {
+                    1
+                };
+                let fee = cmd_estimate_fee(network, data_dir, from, amount, fee_rate, min_conf)?;
+                println!("Estimated fee: {} (fee rate {})", fee, fee_rate);
+            } else {
+                let options = TxOptions {
+                    fee,
+                    strategy,
+                    change_address: None,
+                    data: matches.get_one::<String>("data").map(|data| data.as_bytes().to_vec()),
+                    memo: matches.get_one::<String>("memo").cloned(),
+                    lock_until,
+                    sighash,
+                    min_conf,
+                };
+                let fresh_change = !matches.get_flag("reuse-address");
+                let tx = create_send_transaction(network, data_dir, from, &inputs, to, amount, &options, fresh_change)?;
+                if matches.get_flag("raw") {
+                    println!("{}", tx.to_hex()?);
+                } else {
+                    cmd_send(network, data_dir, node, tx, from, matches.contains_id("mine").then_some(mining_threads))?;
+                }
             }
         }
 
-        if let Some(_) = matches.subcommand_matches("printchain") {
-            cmd_print_chain()?;
+        if let Some(matches) = matches.subcommand_matches("printchain") {
+            if matches.get_flag("json") {
+                cmd_print_chain_json(network, data_dir)?;
+            } else {
+                cmd_print_chain(network, data_dir)?;
+            }
         }
 
         Ok(())
     }
 }
 
-fn cmd_send(from: &str, to: &str, amount: i32, mine_now: bool) -> Result<()> {
-    let bc = Blockchain::new()?;
-    let mut utxo_set = UTXOSet { blockchain: bc };
-    let wallets = Wallets::new()?;
-    let wallet = wallets.get_wallet(from).unwrap();
-    let tx = Transaction::new_utxo(wallet, to, amount, &utxo_set)?;
+/// parse_min_conf 读取 --minconf 参数，未指定时为 1
+fn parse_min_conf(matches: &ArgMatches) -> Result<i32> {
+    match matches.get_one::<String>("minconf") {
+        Some(n) => n.parse().map_err(|e| format_err!("Invalid confirmation count '{}': {}", n, e)),
+        None => Ok(1),
+    }
+}
+
+/// select_network 按 --network 参数或 RUSTCHAIN_NETWORK 环境变量选择网络
+fn select_network(matches: &ArgMatches) -> Result<Network> {
+    Ok(match matches.get_one::<String>("network") {
+        Some(network) => network.parse()?,
+        None => match std::env::var(NETWORK_ENV) {
+            Ok(network) if !network.is_empty() => network.parse()?,
+            _ => Network::default(),
+        },
+    })
+}
+
+/// select_data_dir 按 --datadir 参数或 RUSTCHAIN_DATADIR 环境变量选择并准备数据目录
+fn select_data_dir(matches: &ArgMatches) -> Result<PathBuf> {
+    let dir = match matches.get_one::<String>("datadir") {
+        Some(dir) => PathBuf::from(dir),
+        None => match std::env::var_os(DATADIR_ENV) {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => datadir::default_dir(),
+        },
+    };
+    datadir::prepare(&dir)?;
+    Ok(dir)
+}
+
+/// select_mining_threads 返回 --mining-threads 参数指定的挖矿线程数，未指定时使用全部 CPU
+fn select_mining_threads(matches: &ArgMatches) -> Result<usize> {
+    match matches.get_one::<String>("mining-threads") {
+        Some(n) => {
+            let threads: usize = n
+                .parse()
+                .map_err(|e| format_err!("Invalid thread count '{}': {}", n, e))?;
+            if threads == 0 {
+                return Err(format_err!("Mining needs at least one thread"));
+            }
+            Ok(threads)
+        }
+        None => Ok(default_mining_threads()),
+    }
+}
+
+/// mempool_options 读取 --maxmempool 和 --mempoolexpiry 参数，未指定的使用默认值
+fn mempool_options(matches: &ArgMatches) -> Result<MempoolOptions> {
+    let mut options = MempoolOptions::default();
+    if let Some(mb) = matches.get_one::<String>("maxmempool") {
+        let mb: usize = mb
+            .parse()
+            .map_err(|e| format_err!("Invalid mempool size '{}': {}", mb, e))?;
+        if mb == 0 {
+            return Err(format_err!("The mempool needs room for at least one byte"));
+        }
+        options.max_size = mb
+            .checked_mul(1_000_000)
+            .ok_or_else(|| format_err!("Mempool size {} MB is too large", mb))?;
+    }
+    if let Some(hours) = matches.get_one::<String>("mempoolexpiry") {
+        let hours: u64 = hours
+            .parse()
+            .map_err(|e| format_err!("Invalid mempool expiry '{}': {}", hours, e))?;
+        if hours == 0 {
+            return Err(format_err!(
+                "Transactions need to stay in the mempool for at least an hour"
+            ));
+        }
+        options.expiry = hours
+            .checked_mul(60 * 60)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format_err!("Mempool expiry {} hours is too long", hours))?;
+    }
+    Ok(options)
+}
+
+/// ban_scores 读取 --banscore 和 --misbehavior 参数，未指定的使用默认值
+fn ban_scores(matches: &ArgMatches) -> Result<BanScores> {
+    let mut bans = BanScores::default();
+    if let Some(score) = matches.get_one::<String>("banscore") {
+        bans.ban_score = score
+            .parse()
+            .map_err(|e| format_err!("Invalid ban score '{}': {}", score, e))?;
+        if bans.ban_score == 0 {
+            return Err(format_err!("The ban score must be at least 1"));
+        }
+    }
+    let Some(scores) = matches.get_many::<String>("misbehavior") else {
+        return Ok(bans);
+    };
+    bans.scores = scores
+        .map(|arg| {
+            let (name, score) = arg
+                .split_once('=')
+                .ok_or_else(|| format_err!("Invalid misbehavior score '{}', expected name=score", arg))?;
+            let score = score
+                .parse()
+                .map_err(|e| format_err!("Invalid misbehavior score '{}': {}", arg, e))?;
+            Ok((name.parse::<Misbehavior>()?, score))
+        })
+        .collect::<Result<_>>()?;
+    Ok(bans)
+}
+
+/// select_local_node 按 --bind 和 --port 参数选择其他命令连接的本机节点
+fn select_local_node(matches: &ArgMatches, network: Network) -> Result<LocalNode> {
+    let host = matches
+        .get_one::<String>("bind")
+        .map_or("localhost", String::as_str);
+    let port = matches
+        .get_one::<String>("port")
+        .map_or(network.default_port(), String::as_str);
+    port.parse::<u16>()
+        .map_err(|e| format_err!("Invalid port '{}': {}", port, e))?;
+    Ok(LocalNode {
+        addr: format!("{}:{}", host, port),
+        network,
+    })
+}
+
+/// configure_server 按交易池参数创建 startnode 和 startminer 的交易池，让节点保存交易池、地址库和封禁列表，
+/// 并按 --bind 和连接参数监听和连接
+fn configure_server(server: &mut Server, network: Network, data_dir: &Path, matches: &ArgMatches) -> Result<()> {
+    server.set_mempool_options(mempool_options(matches)?);
+    server.persist_mempool(network.data_path(data_dir, MEMPOOL_FILE));
+    server.persist_peers(network.data_path(data_dir, PEERS_FILE));
+    server.persist_bans(network.data_path(data_dir, BANLIST_FILE));
+    if let Some(host) = matches.get_one::<String>("bind") {
+        server.listen_on(host);
+    }
+    server.set_connection_options(connection_options(matches)?);
+    Ok(())
+}
+
+/// connection_options 读取 --maxinbound、--maxoutbound、--maxconnections、--connect、--blockdelay
+/// 和封禁分数的参数，未指定的使用默认值
+fn connection_options(matches: &ArgMatches) -> Result<ConnectionOptions> {
+    let limit = |name: &str, default: usize| -> Result<usize> {
+        match matches.get_one::<String>(name) {
+            Some(n) => n
+                .parse()
+                .map_err(|e| format_err!("Invalid --{} '{}': {}", name, n, e)),
+            None => Ok(default),
+        }
+    };
+    let defaults = ConnectionOptions::default();
+    let connect = match matches.get_many::<String>("connect") {
+        Some(addrs) => addrs
+            .map(|addr| validate_node_address(addr).map(|_| addr.clone()))
+            .collect::<Result<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+    Ok(ConnectionOptions {
+        max_inbound: limit("maxinbound", defaults.max_inbound)?,
+        max_outbound: limit("maxoutbound", defaults.max_outbound)?,
+        max_connections: limit("maxconnections", defaults.max_connections)?,
+        connect,
+        bans: ban_scores(matches)?,
+        block_delay: Duration::from_millis(limit("blockdelay", 0)? as u64),
+    })
+}
+
+/// validate_node_address 检查 addr 是 host:port 形式的节点地址
+fn validate_node_address(addr: &str) -> Result<()> {
+    let valid = addr
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+    if !valid {
+        return Err(format_err!("Invalid node address '{}', expected host:port", addr));
+    }
+    Ok(())
+}
+
+/// parse_multisig 从命令行的 M 和 ADDRESSES 参数构造多签条件
+fn parse_multisig(matches: &ArgMatches, network: Network) -> Result<LockingCondition> {
+    let m: u8 = matches.get_one::<String>("M").unwrap().parse()?;
+    let addresses: Vec<String> = matches.get_many::<String>("ADDRESSES").unwrap().cloned().collect();
+    LockingCondition::new_multisig(m, &addresses, network)
+}
+
+/// parse_amount 解析命令行中的金额，拒绝负数和非数字
+fn parse_amount(s: &str) -> Result<u64> {
+    s.parse::<u64>().map_err(|e| format_err!("Invalid amount '{}': {}", s, e))
+}
+
+fn cmd_send(network: Network, data_dir: &Path, node: &LocalNode, tx: Transaction, from: &str, mine: Option<usize>) -> Result<()> {
+    let utxo_set = open_for_mining(network, data_dir, mine)?;
+    submit_transaction(node, tx, from, mine.is_some(), utxo_set)?;
+
+    println!("success!");
+    Ok(())
+}
+
+/// open_for_validation 打开数据目录中要验证区块的区块链，use_checkpoints 为 false 时
+/// 不使用编入程序的检查点
+fn open_for_validation(network: Network, data_dir: &Path, use_checkpoints: bool) -> Result<Blockchain> {
+    let mut bc = Blockchain::open(network, data_dir)?;
+    if !use_checkpoints {
+        bc.checkpoints.clear();
+    }
+    Ok(bc)
+}
+
+/// open_for_mining 打开数据目录中的UTXO集合，mine 为 Some 时立即挖矿使用这么多个线程
+fn open_for_mining(network: Network, data_dir: &Path, mine: Option<usize>) -> Result<UTXOSet> {
+    let mut bc = Blockchain::open(network, data_dir)?;
+    if let Some(threads) = mine {
+        bc.mining_threads = threads;
+    }
+    Ok(UTXOSet::new(bc, data_dir))
+}
+
+/// SubmitTransaction 由 miner 立即挖矿打包交易，或者发送给节点
+fn submit_transaction(node: &LocalNode, tx: Transaction, miner: &str, mine_now: bool, mut utxo_set: UTXOSet) -> Result<()> {
     if mine_now {
-        let cbtx = Transaction::new_coinbase(from.to_string(), String::from("reward!"))?;
-        let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
+        let fee = utxo_set.blockchain.get_tx_fee(&tx)?;
+        let height = utxo_set.blockchain.get_best_height()? + 1;
+        let cbtx = Transaction::new_coinbase(miner.to_string(), String::new(), height, fee, utxo_set.blockchain.network)?;
+        let new_block = utxo_set.mine_block(vec![cbtx, tx])?;
 
-        utxo_set.update(&new_block)?;
+        utxo_set.connect_block(&new_block)?;
     } else {
-        Server::send_transaction(&tx, utxo_set)?;
+        node.send_transaction(&tx)?;
     }
+    Ok(())
+}
 
-    println!("success!");
+#[allow(clippy::too_many_arguments)]
+fn cmd_consolidate(
+    network: Network,
+    data_dir: &Path,
+    node: &LocalNode,
+    address: &str,
+    max_inputs: usize,
+    fee: u64,
+    mine: Option<usize>,
+    dry_run: bool,
+) -> Result<()> {
+    validate_address(address, network)?;
+    let utxo_set = open_for_mining(network, data_dir, mine)?;
+    let wallets = open_wallets(network, data_dir)?;
+    let wallet = wallets.get_spending_wallet(address)?;
+    let tx = match Transaction::new_consolidation(wallet, &utxo_set, max_inputs, fee)? {
+        Some(tx) => tx,
+        None => {
+            println!("Nothing to consolidate: {} has fewer than 2 spendable outputs", address);
+            return Ok(());
+        }
+    };
+    if dry_run {
+        println!("Would merge {} outputs into one output of {}", tx.vin.len(), tx.vout[0].value);
+        return Ok(());
+    }
+    let merged = (tx.vin.len(), tx.vout[0].value);
+    submit_transaction(node, tx, address, mine.is_some(), utxo_set)?;
+    println!("Merged {} outputs into one output of {}", merged.0, merged.1);
+    Ok(())
+}
+
+fn cmd_get_tx_out(network: Network, data_dir: &Path, outpoint: &OutPoint) -> Result<()> {
+    let entry = UTXOSet::new(Blockchain::open(network, data_dir)?, data_dir)
+        .get_entry(outpoint)?
+        .ok_or_else(|| format_err!("Output {} is spent or does not exist", outpoint))?;
+    println!("value: {}", entry.output.value);
+    println!("address: {}", address_from_pub_key_hash(&entry.output.pub_key_hash, network));
+    println!("height: {}", entry.height);
+    println!("coinbase: {}", entry.coinbase);
+    Ok(())
+}
+
+fn cmd_get_blockchain_info(network: Network, data_dir: &Path) -> Result<()> {
+    let bc = Blockchain::open(network, data_dir)?;
+    println!("network: {}", network);
+    if let Some(message) = bc.genesis_message()? {
+        println!("genesis message: {}", message);
+    }
+    println!("max block size: {} bytes", MAX_BLOCK_SIZE);
+    println!("max block transactions: {}", MAX_BLOCK_TXS);
+    let height = bc.get_best_height()?;
+    println!("blocks: {}", height);
+    if height < 0 {
+        return Ok(());
+    }
+    let tip = bc.get_block(&bc.tip)?;
+    println!("best block: {}", bc.tip);
+    let (best_header, header) = bc.best_header()?;
+    println!("headers: {}", header.height);
+    if best_header != bc.tip {
+        println!("best header: {}", best_header);
+    }
+    println!("bits: {:08x}", tip.get_bits());
+    println!("difficulty: {:.8}", difficulty(tip.get_bits()));
+    println!("chain work: {}", bc.chain_work(&bc.tip)?);
+    let next = bc.next_bits(&bc.tip)?;
+    println!("next block difficulty: {:.8}", difficulty(next));
+    if network.retargets() {
+        let retarget = (height / RETARGET_INTERVAL + 1) * RETARGET_INTERVAL;
+        println!("next retarget at height: {}", retarget);
+    }
+    Ok(())
+}
+
+fn print_block(block: &Block) {
+    println!("hash: {}", block.get_hash());
+    println!("prev hash: {}", block.get_prev_hash());
+    println!("height: {}", block.get_height());
+    println!("timestamp: {}", block.get_timestamp());
+    println!("nonce: {}", block.get_nonce());
+    println!("bits: {:08x}", block.get_bits());
+    println!("merkle root: {}", block.get_merkle_root());
+    println!("transactions:");
+    for tx in block.get_transaction() {
+        println!("  {}", tx.id);
+    }
+}
+
+fn print_consistency_report(report: &ConsistencyReport) {
+    if report.is_consistent() {
+        println!("The UTXO set matches the blockchain.");
+        return;
+    }
+    for outpoint in &report.missing {
+        println!("missing: {}", outpoint);
+    }
+    for outpoint in &report.stale {
+        println!("stale: {}", outpoint);
+    }
+    for outpoint in &report.mismatched {
+        println!("mismatched: {}", outpoint);
+    }
+}
+
+fn print_snapshot_meta(meta: &SnapshotMeta) {
+    println!("tip: {}", meta.tip);
+    println!("height: {}", meta.height);
+    println!("outputs: {}", meta.outputs);
+    println!("hash: {}", meta.hash);
+}
+
+/// create_send_transaction 用 from 的钱包签名付款给 to 的交易，fresh_change 时找零到新地址
+#[allow(clippy::too_many_arguments)]
+fn create_send_transaction(
+    network: Network,
+    data_dir: &Path,
+    from: &str,
+    inputs: &[OutPoint],
+    to: &str,
+    amount: u64,
+    options: &TxOptions,
+    fresh_change: bool,
+) -> Result<Transaction> {
+    let bc = Blockchain::open(network, data_dir)?;
+    let utxo_set = UTXOSet::new(bc, data_dir);
+    let mut wallets = open_wallets_for_write(network, data_dir)?;
+    let to = &wallets.resolve_address(to)?;
+    let tx = if fresh_change {
+        wallets.new_utxo_with_fresh_change(from, inputs, to, amount, options, &utxo_set)?
+    } else if inputs.is_empty() {
+        let wallet = wallets.get_spending_wallet(from)?;
+        Transaction::new_utxo(wallet, to, amount, options, &utxo_set)?
+    } else {
+        let wallet = wallets.get_spending_wallet(from)?;
+        Transaction::new_utxo_from_inputs(wallet, inputs, to, amount, options, &utxo_set)?
+    };
+    Ok(tx)
+}
+
+fn cmd_spend_multisig(
+    network: Network,
+    data_dir: &Path,
+    outpoint: &OutPoint,
+    condition: &LockingCondition,
+    to: &str,
+    amount: u64,
+    fee: u64,
+) -> Result<String> {
+    let bc = Blockchain::open(network, data_dir)?;
+    let prev = bc.find_transacton(&outpoint.txid)?;
+    Transaction::new_multisig_spend(&prev, outpoint.vout, condition, to, amount, fee, network)?.to_hex()
+}
+
+fn cmd_sign_raw_transaction(network: Network, data_dir: &Path, raw: &str, address: &str, sighash: SigHashType, signer: Option<&str>) -> Result<String> {
+    let mut tx = Transaction::from_hex(raw)?;
+    let prev_txs = Blockchain::open(network, data_dir)?.get_prev_txs(&tx)?;
+    with_signer(network, data_dir, address, signer, |signer| tx.sign(signer, prev_txs, sighash))?;
+    tx.to_hex()
+}
+
+/// with_signer 用 command 指定的外部签名者或钱包中 address 的私钥执行 f
+fn with_signer<T>(network: Network, data_dir: &Path, address: &str, command: Option<&str>, f: impl FnOnce(&dyn Signer) -> Result<T>) -> Result<T> {
+    match command {
+        Some(command) => {
+            let signer = ExternalSigner::new(command)?;
+            let mut pub_key_hash = signer.public_key().to_vec();
+            hash_pub_key(&mut pub_key_hash);
+            if pub_key_hash != decode_address(address, network)? {
+                return Err(format_err!("External signer does not hold the key of {}", address));
+            }
+            f(&signer)
+        }
+        None => {
+            let wallets = open_wallets(network, data_dir)?;
+            f(wallets.get_spending_wallet(address)?)
+        }
+    }
+}
+
+/// cmd_signer 实现 ExternalSigner 的协议，使另一个钱包可以作为外部签名者
+///
+/// 先读完标准输入中的消息再打开钱包，钱包已加密时口令只能通过环境变量提供
+fn cmd_signer(network: Network, data_dir: &Path, address: &str, request: &str) -> Result<String> {
+    match request {
+        "pubkey" => Ok(hex::encode(&open_wallets(network, data_dir)?.get_spending_wallet(address)?.public_key)),
+        "sign" => {
+            let mut msg = Vec::new();
+            io::stdin().lock().read_to_end(&mut msg)?;
+            let wallets = open_wallets(network, data_dir)?;
+            Ok(hex::encode(wallets.get_spending_wallet(address)?.sign(&msg)?))
+        }
+        _ => Err(format_err!("Unknown signer request '{}', expected pubkey or sign", request)),
+    }
+}
+
+fn cmd_get_pub_key(network: Network, data_dir: &Path, address: &str) -> Result<String> {
+    let wallets = open_wallets(network, data_dir)?;
+    let wallet = wallets.get_spending_wallet(address)?;
+    Ok(hex::encode(&wallet.public_key))
+}
+
+/// cmd_sign_message 用钱包私钥签名消息，返回 base64 编码的签名
+fn cmd_sign_message(network: Network, data_dir: &Path, address: &str, message: &str) -> Result<String> {
+    let wallets = open_wallets(network, data_dir)?;
+    let wallet = wallets.get_spending_wallet(address)?;
+    Ok(Base64::encode_string(&wallet.sign_message(message.as_bytes())))
+}
+
+fn cmd_verify_message(address: &str, signature: &str, message: &str, network: Network) -> Result<bool> {
+    let signature = Base64::decode_vec(signature.trim())
+        .map_err(|e| format_err!("Invalid base64 signature: {}", e))?;
+    verify_message(address, message.as_bytes(), &signature, network)
+}
+
+/// cmd_build_unsigned 在联网节点上构造未签名交易，不需要钱包私钥
+fn cmd_build_unsigned(network: Network, data_dir: &Path, pub_key: &[u8], to: &str, amount: u64, fee: u64, file: &str) -> Result<String> {
+    let bc = Blockchain::open(network, data_dir)?;
+    let utxo_set = UTXOSet::new(bc, data_dir);
+    let options = TxOptions {
+        fee,
+        ..TxOptions::default()
+    };
+    let (tx, prev_txs) = Transaction::build_unsigned(pub_key, to, amount, &options, &utxo_set)?;
+    let txid = tx.id.clone();
+    UnsignedBundle { tx, prev_txs }.save(file)?;
+    Ok(txid)
+}
+
+/// cmd_sign_bundle 在离线机器上签名，只读取钱包，不访问区块链
+fn cmd_sign_bundle(network: Network, data_dir: &Path, file: &str, address: &str, sighash: SigHashType, signer: Option<&str>) -> Result<String> {
+    let bundle = UnsignedBundle::load(file)?;
+    with_signer(network, data_dir, address, signer, |signer| bundle.sign(signer, sighash))?.to_hex()
+}
+
+fn cmd_send_raw_transaction(network: Network, data_dir: &Path, node: &LocalNode, raw: &str) -> Result<()> {
+    let tx = Transaction::from_hex(raw)?;
+    let bc = Blockchain::open(network, data_dir)?;
+    bc.verify_transacton(&tx)
+        .map_err(|e| format_err!("Invalid raw transaction {}: {}", tx.id, e))?;
+    let utxo_set = UTXOSet::new(bc, data_dir);
+    // 节点的交易池拒绝花费已花费输出的交易，发送之前先说明原因
+    utxo_set
+        .verify_transaction_inputs(&tx)
+        .map_err(|e| format_err!("Invalid raw transaction {}: {}", tx.id, e))?;
+    node.send_transaction(&tx)?;
+    println!("success! txid: {}", tx.id);
+    Ok(())
+}
+
+fn cmd_get_transaction(network: Network, data_dir: &Path, txid: &str) -> Result<()> {
+    let bc = Blockchain::open(network, data_dir)?;
+    let (tx, block_hash, height) = bc
+        .get_transaction(txid)?
+        .ok_or_else(|| format_err!("Transaction {} is not on the main chain", txid))?;
+    println!("block: {}", block_hash);
+    println!("height: {}", height);
+    println!("confirmations: {}", bc.get_best_height()? - height + 1);
+    print_transaction(&tx, network);
+    Ok(())
+}
+
+fn cmd_get_mempool_info(node: &LocalNode) -> Result<()> {
+    let info = node.get_mempool_info()?;
+    println!("transactions: {}", info.transactions);
+    println!("size: {} bytes", info.size);
+    println!("max size: {} bytes", info.max_size);
+    println!("min fee rate: {:.3} per byte", info.min_fee_rate);
+    Ok(())
+}
+
+fn cmd_get_raw_mempool(node: &LocalNode, verbose: bool) -> Result<()> {
+    let entries = node.get_raw_mempool()?;
+    if verbose {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else {
+        let txids: Vec<&str> = entries.iter().map(|entry| entry.txid.as_str()).collect();
+        println!("{}", serde_json::to_string_pretty(&txids)?);
+    }
+    Ok(())
+}
+
+fn cmd_get_mempool_entry(node: &LocalNode, txid: &str) -> Result<()> {
+    let entry = node.get_mempool_entry(txid)?
+        .ok_or_else(|| format_err!("Transaction {} is not in the mempool of the local node", txid))?;
+    println!("{}", serde_json::to_string_pretty(&entry)?);
     Ok(())
 }
 
-fn cmd_create_wallet() -> Result<String> {
-    let mut ws = Wallets::new()?;
+fn cmd_get_peer_info(node: &LocalNode) -> Result<()> {
+    let peers = node.get_peer_info()?;
+    println!("{}", serde_json::to_string_pretty(&peers)?);
+    Ok(())
+}
+
+fn cmd_set_ban(node: &LocalNode, addr: &str, command: &str) -> Result<()> {
+    let addr: IpAddr = addr
+        .parse()
+        .map_err(|_| format_err!("Invalid IP address '{}'", addr))?;
+    match command {
+        "add" => node.set_ban(addr, true)?,
+        "remove" => node.set_ban(addr, false)?,
+        _ => return Err(format_err!("Unknown setban command '{}', expected add or remove", command)),
+    }
+    Ok(())
+}
+
+fn cmd_list_banned(node: &LocalNode) -> Result<()> {
+    let bans = node.list_banned()?;
+    println!("{}", serde_json::to_string_pretty(&bans)?);
+    Ok(())
+}
+
+fn cmd_clear_banned(node: &LocalNode) -> Result<()> {
+    let count = node.clear_banned()?;
+    println!("unbanned {} addresses", count);
+    Ok(())
+}
+
+fn cmd_list_pending(node: &LocalNode) -> Result<()> {
+    let now = unix_time();
+    for pending in node.get_raw_mempool()? {
+        let wallet = if pending.wallet { " wallet" } else { "" };
+        println!(
+            "{} age: {} fee: {} size: {} bytes{}",
+            pending.txid,
+            format_age(now.saturating_sub(pending.time)),
+            pending.fee,
+            pending.size,
+            wallet
+        );
+    }
+    Ok(())
+}
+
+/// format_age 把秒数写成 1h 02m、3m 05s 或 42s
+fn format_age(secs: u64) -> String {
+    if secs >= 60 * 60 {
+        format!("{}h {:02}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+fn cmd_bump_fee(network: Network, data_dir: &Path, node: &LocalNode, txid: &str, fee: u64) -> Result<()> {
+    let tx = node.get_mempool_transaction(txid)?
+        .ok_or_else(|| format_err!("Transaction {} is not in the mempool of the local node", txid))?;
+    let utxo_set = UTXOSet::new(Blockchain::open(network, data_dir)?, data_dir);
+    let bumped = open_wallets(network, data_dir)?.bump_fee(&tx, fee, &utxo_set)?;
+    node.send_transaction(&bumped)?;
+    println!("success! txid: {}", bumped.id);
+    Ok(())
+}
+
+fn cmd_decode_raw_transaction(raw: &str, network: Network) -> Result<()> {
+    print_transaction(&Transaction::from_hex(raw)?, network);
+    Ok(())
+}
+
+fn print_transaction(tx: &Transaction, network: Network) {
+    println!("txid: {}", tx.id);
+    println!("version: {}", tx.version);
+    println!("coinbase: {}", tx.is_coinbase());
+    println!("lock until: {}", tx.lock_until);
+    if let Some(memo) = &tx.memo {
+        println!("memo: {}", memo);
+    }
+    println!("inputs:");
+    for (i, input) in tx.vin.iter().enumerate() {
+        println!("  {}: {}", i, input.outpoint);
+    }
+    println!("outputs:");
+    for (i, out) in tx.vout.iter().enumerate() {
+        match out.get_data() {
+            Some(data) => println!("  {}: data {}", i, String::from_utf8_lossy(data)),
+            None => println!("  {}: {} to {}", i, out.value, address_from_pub_key_hash(&out.pub_key_hash, network)),
+        }
+    }
+}
+
+fn cmd_estimate_fee(network: Network, data_dir: &Path, from: &str, amount: u64, fee_rate: u64, min_conf: i32) -> Result<u64> {
+    let pub_key_hash = decode_address(from, network)?;
+    let bc = Blockchain::open(network, data_dir)?;
+    let utxo_set = UTXOSet::new(bc, data_dir);
+    utxo_set.estimate_fee(&pub_key_hash, amount, fee_rate, min_conf)
+}
+
+fn cmd_create_wallet(network: Network, data_dir: &Path) -> Result<String> {
+    let mut ws = open_wallets_for_write(network, data_dir)?;
     let address = ws.create_wallet();
     ws.save_all()?;
     Ok(address)
 }
 
-fn cmd_reindex() -> Result<i32> {
-    let bc = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain: bc };
-    utxo_set.reindex()?;
-    utxo_set.count_transactions()
+/// OpenWallets 加载钱包文件，已加密时读取口令解锁
+fn open_wallets(network: Network, data_dir: &Path) -> Result<Wallets> {
+    if Wallets::is_file_encrypted(network, data_dir)? {
+        Wallets::unlock(network, data_dir, &read_passphrase(WALLET_PASSPHRASE_ENV, "Wallet passphrase: ")?)
+    } else {
+        Wallets::new(network, data_dir)
+    }
 }
 
-fn cmd_create_blockchain(address: &str) -> Result<()> {
+/// OpenWalletsForWrite 锁定并加载钱包文件，已加密时读取口令解锁，用于会修改钱包的命令
+fn open_wallets_for_write(network: Network, data_dir: &Path) -> Result<Wallets> {
+    if Wallets::is_file_encrypted(network, data_dir)? {
+        Wallets::open_for_write(network, data_dir, Some(&read_passphrase(WALLET_PASSPHRASE_ENV, "Wallet passphrase: ")?))
+    } else {
+        Wallets::open_for_write(network, data_dir, None)
+    }
+}
+
+/// ReadNewPassphrase 读取新口令，提示输入时需要输入两次
+fn read_new_passphrase() -> Result<String> {
+    read_confirmed_passphrase(NEW_WALLET_PASSPHRASE_ENV, "New passphrase: ")
+}
+
+/// ReadConfirmedPassphrase 读取新设置的口令，从标准输入读取时要求重复输入一次
+fn read_confirmed_passphrase(env: &str, prompt: &str) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(env) {
+        return Ok(passphrase);
+    }
+    let passphrase = read_passphrase(env, prompt)?;
+    if read_passphrase(env, &format!("Repeat {}", prompt.to_lowercase()))? != passphrase {
+        return Err(format_err!("Passphrases do not match"));
+    }
+    Ok(passphrase)
+}
+
+/// ReadPassphrase 从环境变量 env 读取口令，未设置时在标准错误输出提示并从标准输入读取一行
+///
+/// 输入的口令会回显在终端上，脚本中应使用环境变量
+fn read_passphrase(env: &str, prompt: &str) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(env) {
+        return Ok(passphrase);
+    }
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        return Err(format_err!("No passphrase given"));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn parse_gap_limit(matches: &ArgMatches) -> Result<u32> {
+    match matches.get_one::<String>("gap-limit") {
+        Some(limit) => limit
+            .parse::<u32>()
+            .map_err(|e| format_err!("Invalid gap limit '{}': {}", limit, e)),
+        None => Ok(DEFAULT_GAP_LIMIT),
+    }
+}
+
+/// restoreseed 先校验助记词，再检查钱包文件，只有 force 时才删除已有的钱包文件
+fn cmd_restore_seed(network: Network, data_dir: &Path, phrase: &str, passphrase: &str, gap_limit: u32, force: bool) -> Result<u32> {
+    let mut ws = Wallets::from_mnemonic(network, Box::new(FileStorage::in_data_dir(network, data_dir)), phrase, passphrase)?;
+    if Wallets::is_file_encrypted(network, data_dir)? || !Wallets::new(network, data_dir)?.is_empty() {
+        if !force {
+            return Err(format_err!(
+                "Wallet file already exists, pass --force to overwrite it"
+            ));
+        }
+        ws.replace_file();
+    }
+    let bc = Blockchain::open(network, data_dir)?;
+    let utxo_set = UTXOSet::new(bc, data_dir);
+    ws.rescan_hd(&utxo_set, gap_limit)
+}
+
+fn cmd_create_vanity_wallet(network: Network, data_dir: &Path, prefix: &str, ignore_case: bool, timeout: Duration) -> Result<String> {
+    let mut ws = open_wallets_for_write(network, data_dir)?;
+    if ignore_case {
+        ws.create_vanity_wallet_ignore_case(prefix, timeout)
+    } else {
+        ws.create_vanity_wallet(prefix, timeout)
+    }
+}
+
+fn cmd_reindex(network: Network, data_dir: &Path) -> Result<usize> {
+    let bc = Blockchain::open(network, data_dir)?;
+    let utxo_set = UTXOSet::new(bc, data_dir);
+    // 没有处理中断信号，进程被终止时集合保留重建标记，下次使用前会从头重建
+    utxo_set.reindex_with(progress("Reindexing UTXO set"), &AtomicBool::new(false))?;
+    Ok(utxo_set.stats()?.transactions)
+}
+
+/// 逐个区块报告进度的回调，进度输出到标准错误并覆盖同一行，百分比变化时才刷新
+fn progress(label: &str) -> impl Fn(usize, usize) + '_ {
+    let shown = Cell::new(None);
+    move |done: usize, total: usize| {
+        let percent = (done * 100).checked_div(total).unwrap_or(100);
+        if shown.replace(Some(percent)) != Some(percent) {
+            eprint!("\r{}: {:>3}% ({}/{} blocks)", label, percent, done, total);
+        }
+        if done == total {
+            eprintln!();
+        }
+    }
+}
+
+fn cmd_create_blockchain(network: Network, data_dir: &Path, address: &str, message: &str) -> Result<()> {
+    validate_address(address, network)?;
     let address = String::from(address);
-    let bc = Blockchain::create_blockchain(address)?;
+    let bc = Blockchain::create(address, message, network, data_dir)?;
 
-    let utxo_set = UTXOSet { blockchain: bc };
+    let utxo_set = UTXOSet::new(bc, data_dir);
     utxo_set.reindex()?;
     println!("create blockchain");
     Ok(())
 }
 
-fn cmd_get_balance(address: &str) -> Result<i32> {
-    let pub_key_hash = Address::decode(address).unwrap().body;
-    let bc = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain: bc };
-    let utxos = utxo_set.find_utxo(&pub_key_hash)?;
+fn cmd_get_balance(network: Network, data_dir: &Path, address: &str) -> Result<u64> {
+    let bc = Blockchain::open(network, data_dir)?;
+    UTXOSet::new(bc, data_dir).get_balance(address)
+}
 
-    let mut balance = 0;
-    for out in utxos.outputs {
-        balance += out.value;
+fn cmd_get_immature_balance(network: Network, data_dir: &Path, address: &str) -> Result<u64> {
+    let pub_key_hash = decode_address(address, network)?;
+    let bc = Blockchain::open(network, data_dir)?;
+    let utxo_set = UTXOSet::new(bc, data_dir);
+    sum_balance(address, utxo_set.find_immature_utxo(&pub_key_hash)?)
+}
+
+fn sum_balance(address: &str, utxos: Vec<TXOutput>) -> Result<u64> {
+    let mut balance: u64 = 0;
+    for out in utxos {
+        balance = balance
+            .checked_add(out.value)
+            .ok_or_else(|| format_err!("Balance of {} overflows", address))?;
     }
     Ok(balance)
 }
 
-fn cmd_print_chain() -> Result<()> {
-    let bc = Blockchain::new()?;
+fn cmd_print_chain(network: Network, data_dir: &Path) -> Result<()> {
+    let bc = Blockchain::open(network, data_dir)?;
     for b in bc.iter() {
         println!("{:#?}", b);
+        for tx in b.get_transaction() {
+            if let Some(memo) = &tx.memo {
+                println!("memo of {}: {}", tx.id, memo);
+            }
+            for out in &tx.vout {
+                if let Some(data) = out.get_data() {
+                    println!("data in {}: {}", tx.id, String::from_utf8_lossy(data));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn cmd_print_chain_json(network: Network, data_dir: &Path) -> Result<()> {
+    let bc = Blockchain::open(network, data_dir)?;
+    let mut blocks = Vec::new();
+    for b in bc.iter() {
+        let transactions: Vec<TransactionJson> = b.get_transaction().iter().map(|tx| TransactionJson::new(tx, network)).collect();
+        blocks.push(serde_json::json!({
+            "hash": b.get_hash(),
+            "prev_block_hash": b.get_prev_hash(),
+            "height": b.get_height(),
+            "transactions": transactions,
+        }));
     }
+    println!("{}", serde_json::to_string_pretty(&blocks)?);
     Ok(())
 }
 
-fn cmd_list_address() -> Result<()> {
-    let ws = Wallets::new()?;
-    let addresses = ws.get_all_addresses();
+fn cmd_list_address(network: Network, data_dir: &Path, bech32: bool, watch_only: bool, balances: bool) -> Result<()> {
+    let ws = open_wallets(network, data_dir)?;
+    let addresses = ws.get_all_addresses_labeled(watch_only);
     println!("addresses: ");
-    for ad in addresses {
-        println!("{}", ad);
+    for (ad, label, is_watch_only) in addresses {
+        let shown = if !bech32 {
+            ad.clone()
+        } else if let Some(wallet) = ws.get_wallet(&ad) {
+            wallet.get_address_bech32(network)
+        } else {
+            bech32::encode(network.bech32_hrp(), &decode_address(&ad, network)?)
+        };
+        let shown = if balances {
+            format!("{} {}", shown, cmd_get_balance(network, data_dir, &ad)?)
+        } else {
+            shown
+        };
+        match (label, is_watch_only) {
+            (Some(label), true) => println!("{} (watch-only: {})", shown, label),
+            (None, true) => println!("{} (watch-only)", shown),
+            (Some(label), false) => println!("{} ({})", shown, label),
+            (None, false) => println!("{}", shown),
+        }
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+fn cmd_list_balances(network: Network, data_dir: &Path, watch_only: bool, json: bool) -> Result<()> {
+    let bc = Blockchain::open(network, data_dir)?;
+    let utxo_set = UTXOSet::new(bc, data_dir);
+    let balances = open_wallets(network, data_dir)?.balances(&utxo_set, watch_only)?;
+    let mut spendable: u64 = 0;
+    let mut immature: u64 = 0;
+    let mut frozen: u64 = 0;
+    for balance in &balances {
+        spendable = spendable
+            .checked_add(balance.spendable)
+            .ok_or_else(|| format_err!("Total balance overflows"))?;
+        immature = immature
+            .checked_add(balance.immature)
+            .ok_or_else(|| format_err!("Total balance overflows"))?;
+        frozen = frozen
+            .checked_add(balance.frozen)
+            .ok_or_else(|| format_err!("Total balance overflows"))?;
+    }
+
+    if json {
+        let output = serde_json::json!({
+            "addresses": balances,
+            "total": { "spendable": spendable, "immature": immature, "frozen": frozen },
+        });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(());
+    }
+
+    let address_width = balances.iter().map(|b| b.address.len()).max().unwrap_or(0).max("ADDRESS".len());
+    let amount_width = spendable.max(immature).max(frozen).to_string().len().max("SPENDABLE".len());
+    println!("{:<aw$}  {:>w$}  {:>w$}  {:>w$}  LABEL", "ADDRESS", "SPENDABLE", "IMMATURE", "FROZEN", aw = address_width, w = amount_width);
+    for balance in &balances {
+        let label = match (&balance.label, balance.watch_only) {
+            (Some(label), true) => format!("watch-only: {}", label),
+            (None, true) => String::from("watch-only"),
+            (Some(label), false) => label.clone(),
+            (None, false) => String::new(),
+        };
+        let line = format!(
+            "{:<aw$}  {:>w$}  {:>w$}  {:>w$}  {}",
+            balance.address,
+            balance.spendable,
+            balance.immature,
+            balance.frozen,
+            label,
+            aw = address_width,
+            w = amount_width
+        );
+        println!("{}", line.trim_end());
+    }
+    println!("{:<aw$}  {:>w$}  {:>w$}  {:>w$}", "TOTAL", spendable, immature, frozen, aw = address_width, w = amount_width);
+    Ok(())
+}
+
+/// exportkeystore 只创建新文件，不覆盖已有的文件，文件只有本用户可读
+fn cmd_export_keystore(network: Network, data_dir: &Path, address: &str, file: &str) -> Result<()> {
+    let ws = open_wallets(network, data_dir)?;
+    let wallet = ws.get_spending_wallet(address)?;
+    let json = wallet.to_keystore_json(
+        &read_confirmed_passphrase(KEYSTORE_PASSPHRASE_ENV, "Keystore passphrase: ")?,
+        network,
+    )?;
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut out = options
+        .open(file)
+        .map_err(|e| format_err!("Cannot create {}: {}", file, e))?;
+    out.write_all(json.as_bytes())?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+fn cmd_import_keystore(network: Network, data_dir: &Path, file: &str) -> Result<String> {
+    let json =
+        std::fs::read_to_string(file).map_err(|e| format_err!("Cannot read {}: {}", file, e))?;
+    let mut ws = open_wallets_for_write(network, data_dir)?;
+    ws.import_keystore(
+        &json,
+        &read_passphrase(KEYSTORE_PASSPHRASE_ENV, "Keystore passphrase: ")?,
+    )
+}
+
+fn cmd_add_watch_only(network: Network, data_dir: &Path, address: &str, label: Option<String>) -> Result<()> {
+    let mut ws = open_wallets_for_write(network, data_dir)?;
+    ws.add_watch_only(address, label)?;
+    ws.save_all()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_amount() {
+        assert_eq!(parse_amount("42").unwrap(), 42);
+        assert_eq!(parse_amount("18446744073709551615").unwrap(), u64::MAX);
+
+        let err = parse_amount("-5").unwrap_err();
+        assert!(err.to_string().contains("Invalid amount '-5'"));
+        assert!(parse_amount("18446744073709551616").is_err());
+        assert!(parse_amount("1.5").is_err());
+    }
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(42), "42s");
+        assert_eq!(format_age(185), "3m 05s");
+        assert_eq!(format_age(73 * 3600 + 120), "73h 02m");
+    }
+
+    #[test]
+    fn test_verify_message_base64() {
+        let err = cmd_verify_message("address", "not base64!", "message", Network::Mainnet).unwrap_err();
+        assert!(err.to_string().contains("Invalid base64 signature"));
+    }
+}
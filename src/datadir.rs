@@ -0,0 +1,84 @@
+//! data directory
+
+use super::*;
+use failure::format_err;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+
+/// 命令行未指定 --datadir 时从该环境变量读取数据目录
+pub const DATADIR_ENV: &str = "RUSTCHAIN_DATADIR";
+
+/// 旧版本固定使用的相对数据目录
+const LEGACY_DATA_DIR: &str = "data";
+const APP_DIR: &str = "rustchain";
+
+/// Prepare 准备数据目录 dir，不存在时创建，不可写时返回错误
+///
+/// 必须在打开数据库之前调用
+pub fn prepare(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)
+        .map_err(|e| format_err!("Cannot create data directory {}: {}", dir.display(), e))?;
+    check_writable(dir)
+}
+
+/// DefaultDir 返回默认数据目录
+///
+/// 当前目录下已有旧版本的 data 目录时继续使用它，否则使用平台的用户数据目录下的 rustchain
+pub fn default_dir() -> PathBuf {
+    if Path::new(LEGACY_DATA_DIR).is_dir() {
+        return PathBuf::from(LEGACY_DATA_DIR);
+    }
+    match platform_data_dir() {
+        Some(dir) => dir.join(APP_DIR),
+        None => PathBuf::from(LEGACY_DATA_DIR),
+    }
+}
+
+/// 各平台的用户数据目录，与 dirs::data_dir() 一致
+fn platform_data_dir() -> Option<PathBuf> {
+    let env_dir = |name: &str| {
+        std::env::var_os(name)
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+    };
+    if cfg!(windows) {
+        env_dir("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        env_dir("HOME").map(|home| home.join("Library/Application Support"))
+    } else {
+        env_dir("XDG_DATA_HOME")
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| env_dir("HOME").map(|home| home.join(".local/share")))
+    }
+}
+
+/// 在目录中创建并删除一个临时文件，确认可以写入
+fn check_writable(dir: &Path) -> Result<()> {
+    let probe = dir.join(format!(".write-test-{}", std::process::id()));
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .map_err(|e| format_err!("Data directory {} is not writable: {}", dir.display(), e))?;
+    fs::remove_file(&probe)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_writable() {
+        let dir = std::env::temp_dir().join(format!("rustchain-datadir-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        check_writable(&dir).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        // 普通文件不能作为数据目录
+        let file = dir.join("file");
+        fs::write(&file, b"").unwrap();
+        assert!(check_writable(&file).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
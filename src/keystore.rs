@@ -0,0 +1,457 @@
+//! wallet encryption
+
+use super::*;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use crypto::aes::{self, KeySize};
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::pbkdf2::pbkdf2;
+use crypto::sha2::Sha256;
+use crypto::sha3::Sha3;
+use crypto::util::fixed_time_eq;
+use failure::format_err;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use scrypt::{Params as ScryptParams, scrypt};
+use serde::{Deserialize, Serialize};
+
+/// 加密格式的版本，同时作为附加认证数据
+const KEYSTORE_VERSION: u8 = 1;
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// scrypt 的 log2(N)，测试中降低以免拖慢测试
+const SCRYPT_LOG_N: u8 = if cfg!(test) { 10 } else { 15 };
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+/// 解密时接受的 scrypt 参数上限，防止损坏的文件耗尽内存
+const SCRYPT_MAX_LOG_N: u8 = 20;
+const SCRYPT_MAX_R: u32 = 32;
+const SCRYPT_MAX_P: u32 = 16;
+/// scrypt 使用的内存 128·r·N 字节的上限
+const SCRYPT_MAX_MEMORY: u64 = 256 * 1024 * 1024;
+/// JSON 密钥文件的格式版本，即 Web3 Secret Storage 第 3 版
+const KEYSTORE_JSON_VERSION: u32 = 3;
+const KEYSTORE_JSON_CIPHER: &str = "aes-128-ctr";
+const KEYSTORE_JSON_PRF: &str = "hmac-sha256";
+const IV_LEN: usize = 16;
+/// 解密时接受的 pbkdf2 迭代次数上限
+const PBKDF2_MAX_ROUNDS: u32 = 10_000_000;
+
+/// EncryptedBlob 加密保存的数据及解密所需的参数
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncryptedBlob {
+    version: u8,
+    log_n: u8,
+    r: u32,
+    p: u32,
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+    tag: Vec<u8>,
+}
+
+/// WalletKey 由口令派生的加密密钥，连同派生它的盐和 scrypt 参数
+///
+/// 解锁后保存在内存中，每次保存时用新的随机 nonce 重新加密，无需再次输入口令
+#[derive(Clone)]
+pub struct WalletKey {
+    key: [u8; KEY_LEN],
+    log_n: u8,
+    r: u32,
+    p: u32,
+    salt: Vec<u8>,
+}
+
+impl WalletKey {
+    /// Derive 用随机盐从口令派生新密钥
+    pub fn derive(passphrase: &str) -> Result<WalletKey> {
+        if passphrase.is_empty() {
+            return Err(format_err!("Passphrase must not be empty"));
+        }
+        let mut salt = vec![0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        WalletKey::derive_with(passphrase, SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P, salt)
+    }
+
+    /// 按给出的参数派生密钥，参数超出 scrypt_params 的限制时返回错误
+    fn derive_with(
+        passphrase: &str,
+        log_n: u8,
+        r: u32,
+        p: u32,
+        salt: Vec<u8>,
+    ) -> Result<WalletKey> {
+        let params = scrypt_params(log_n, r, p)
+            .ok_or_else(|| format_err!("Corrupted wallet encryption header"))?;
+        let mut key = [0u8; KEY_LEN];
+        scrypt(passphrase.as_bytes(), &salt, &params, &mut key)
+            .map_err(|e| format_err!("Cannot derive the wallet key: {}", e))?;
+        Ok(WalletKey {
+            key,
+            log_n,
+            r,
+            p,
+            salt,
+        })
+    }
+
+    /// Seal 加密数据
+    pub fn seal(&self, plaintext: &[u8]) -> Result<EncryptedBlob> {
+        let mut nonce = vec![0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let payload = Payload {
+            msg: plaintext,
+            aad: &[KEYSTORE_VERSION],
+        };
+        let mut ciphertext = Aes256Gcm::new(&self.key.into())
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| format_err!("Cannot encrypt the wallet"))?;
+        // 密文之后是认证标签
+        let tag = ciphertext.split_off(plaintext.len());
+        Ok(EncryptedBlob {
+            version: KEYSTORE_VERSION,
+            log_n: self.log_n,
+            r: self.r,
+            p: self.p,
+            salt: self.salt.clone(),
+            nonce,
+            ciphertext,
+            tag,
+        })
+    }
+}
+
+/// ScryptParams 检查 scrypt 参数，返回 None 表示参数无效或超出上限
+///
+/// 要求 log_n < 16·r，使用的内存 128·r·N 字节不超过 SCRYPT_MAX_MEMORY，
+/// 损坏或恶意的文件不能让派生密钥失败或耗尽内存
+fn scrypt_params(log_n: u8, r: u32, p: u32) -> Option<ScryptParams> {
+    if !(1..=SCRYPT_MAX_LOG_N).contains(&log_n)
+        || !(1..=SCRYPT_MAX_R).contains(&r)
+        || !(1..=SCRYPT_MAX_P).contains(&p)
+        || u32::from(log_n) >= 16 * r
+        || (128 * u64::from(r)) << log_n > SCRYPT_MAX_MEMORY
+    {
+        return None;
+    }
+    ScryptParams::new(log_n, r, p, KEY_LEN).ok()
+}
+
+impl EncryptedBlob {
+    /// Open 用口令解密数据，返回明文和派生出的密钥
+    ///
+    /// 口令错误或数据被篡改时返回错误
+    pub fn open(&self, passphrase: &str) -> Result<(Vec<u8>, WalletKey)> {
+        if self.version != KEYSTORE_VERSION {
+            return Err(format_err!(
+                "Unsupported wallet encryption version {}",
+                self.version
+            ));
+        }
+        if self.nonce.len() != NONCE_LEN || self.tag.len() != TAG_LEN {
+            return Err(format_err!("Corrupted wallet encryption header"));
+        }
+        let key =
+            WalletKey::derive_with(passphrase, self.log_n, self.r, self.p, self.salt.clone())?;
+        let mut ciphertext = self.ciphertext.clone();
+        ciphertext.extend(&self.tag);
+        let payload = Payload {
+            msg: &ciphertext,
+            aad: &[self.version],
+        };
+        let plaintext = Aes256Gcm::new(&key.key.into())
+            .decrypt(Nonce::from_slice(&self.nonce), payload)
+            .map_err(|_| format_err!("Wrong passphrase or corrupted wallet file"))?;
+        Ok((plaintext, key))
+    }
+}
+
+/// KeystoreJson Web3 Secret Storage 第 3 版的单私钥文件，geth 等工具使用同一格式
+///
+/// 派生密钥的前 16 字节用 AES-128-CTR 加密私钥，后 16 字节与密文的 Keccak-256 作为 MAC
+#[derive(Serialize, Deserialize)]
+struct KeystoreJson {
+    version: u32,
+    id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+    #[serde(alias = "Crypto")]
+    crypto: KeystoreCrypto,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeystoreCrypto {
+    cipher: String,
+    cipherparams: CipherParams,
+    ciphertext: String,
+    #[serde(flatten)]
+    kdf: Kdf,
+    mac: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CipherParams {
+    iv: String,
+}
+
+/// Kdf 由口令派生密钥的算法及其参数
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kdf", content = "kdfparams", rename_all = "lowercase")]
+enum Kdf {
+    Scrypt {
+        dklen: usize,
+        n: u64,
+        r: u32,
+        p: u32,
+        salt: String,
+    },
+    Pbkdf2 {
+        dklen: usize,
+        c: u32,
+        prf: String,
+        salt: String,
+    },
+}
+
+impl Kdf {
+    /// Derive 按记录的参数派生 32 字节密钥，参数不受支持或超出上限时返回错误
+    fn derive(&self, passphrase: &str) -> Result<[u8; KEY_LEN]> {
+        let mut key = [0u8; KEY_LEN];
+        match self {
+            Kdf::Scrypt {
+                dklen,
+                n,
+                r,
+                p,
+                salt,
+            } => {
+                let params = u8::try_from(n.trailing_zeros())
+                    .ok()
+                    .filter(|_| *dklen == KEY_LEN && n.is_power_of_two())
+                    .and_then(|log_n| scrypt_params(log_n, *r, *p))
+                    .ok_or_else(|| format_err!("Unsupported keystore scrypt parameters"))?;
+                scrypt(passphrase.as_bytes(), &decode_hex(salt)?, &params, &mut key)
+                    .map_err(|e| format_err!("Cannot derive the keystore key: {}", e))?;
+            }
+            Kdf::Pbkdf2 {
+                dklen,
+                c,
+                prf,
+                salt,
+            } => {
+                if *dklen != KEY_LEN
+                    || prf != KEYSTORE_JSON_PRF
+                    || !(1..=PBKDF2_MAX_ROUNDS).contains(c)
+                {
+                    return Err(format_err!("Unsupported keystore pbkdf2 parameters"));
+                }
+                let mut mac = Hmac::new(Sha256::new(), passphrase.as_bytes());
+                pbkdf2(&mut mac, &decode_hex(salt)?, *c, &mut key);
+            }
+        }
+        Ok(key)
+    }
+}
+
+/// EncryptKeystoreJson 用口令把私钥加密为 JSON 密钥文件，address 记录在文件中便于辨认
+pub fn encrypt_keystore_json(secret: &[u8], address: &str, passphrase: &str) -> Result<String> {
+    if passphrase.is_empty() {
+        return Err(format_err!("Passphrase must not be empty"));
+    }
+    let mut salt = vec![0u8; KEY_LEN];
+    let mut iv = [0u8; IV_LEN];
+    let mut id = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut iv);
+    OsRng.fill_bytes(&mut id);
+    let kdf = Kdf::Scrypt {
+        dklen: KEY_LEN,
+        n: 1 << SCRYPT_LOG_N,
+        r: SCRYPT_R,
+        p: SCRYPT_P,
+        salt: hex::encode(salt),
+    };
+    let key = kdf.derive(passphrase)?;
+    let mut ciphertext = vec![0u8; secret.len()];
+    aes::ctr(KeySize::KeySize128, &key[..16], &iv).process(secret, &mut ciphertext);
+    let keystore = KeystoreJson {
+        version: KEYSTORE_JSON_VERSION,
+        id: format_uuid(id),
+        address: Some(address.to_string()),
+        crypto: KeystoreCrypto {
+            cipher: KEYSTORE_JSON_CIPHER.to_string(),
+            cipherparams: CipherParams {
+                iv: hex::encode(iv),
+            },
+            mac: hex::encode(keystore_mac(&key, &ciphertext)),
+            ciphertext: hex::encode(ciphertext),
+            kdf,
+        },
+    };
+    Ok(serde_json::to_string_pretty(&keystore)?)
+}
+
+/// DecryptKeystoreJson 用口令解密 JSON 密钥文件，返回私钥和文件中记录的地址
+///
+/// 先校验 MAC，口令错误或文件被篡改时返回错误，不会得到错误的私钥
+pub fn decrypt_keystore_json(json: &str, passphrase: &str) -> Result<(Vec<u8>, Option<String>)> {
+    let keystore: KeystoreJson =
+        serde_json::from_str(json).map_err(|e| format_err!("Invalid keystore file: {}", e))?;
+    if keystore.version != KEYSTORE_JSON_VERSION {
+        return Err(format_err!(
+            "Unsupported keystore version {}",
+            keystore.version
+        ));
+    }
+    let crypto = keystore.crypto;
+    if crypto.cipher != KEYSTORE_JSON_CIPHER {
+        return Err(format_err!("Unsupported keystore cipher {}", crypto.cipher));
+    }
+    let iv = decode_hex(&crypto.cipherparams.iv)?;
+    if iv.len() != IV_LEN {
+        return Err(format_err!(
+            "Invalid keystore file: iv must be {} bytes",
+            IV_LEN
+        ));
+    }
+    let ciphertext = decode_hex(&crypto.ciphertext)?;
+    let key = crypto.kdf.derive(passphrase)?;
+    if !fixed_time_eq(&keystore_mac(&key, &ciphertext), &decode_hex(&crypto.mac)?) {
+        return Err(format_err!("Wrong passphrase or corrupted keystore file"));
+    }
+    let mut secret = vec![0u8; ciphertext.len()];
+    aes::ctr(KeySize::KeySize128, &key[..16], &iv).process(&ciphertext, &mut secret);
+    Ok((secret, keystore.address))
+}
+
+/// JSON 密钥文件的 MAC，即派生密钥后 16 字节与密文的 Keccak-256
+fn keystore_mac(key: &[u8; KEY_LEN], ciphertext: &[u8]) -> [u8; 32] {
+    let mut mac = [0u8; 32];
+    let mut hasher = Sha3::keccak256();
+    hasher.input(&key[16..]);
+    hasher.input(ciphertext);
+    hasher.result(&mut mac);
+    mac
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    hex::decode(s.trim_start_matches("0x")).map_err(|e| format_err!("Invalid keystore file: {}", e))
+}
+
+/// 把 16 个随机字节格式化为第 4 版 UUID
+fn format_uuid(mut bytes: [u8; 16]) -> String {
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_open_rust_crypto_blob() {
+        // 由 rust-crypto 的 scrypt 和 AesGcm 加密的数据，口令为 correct horse
+        let fixture = include_str!("../tests/fixtures/wallet-blob-rust-crypto.hex");
+        let blob: EncryptedBlob =
+            bincode::deserialize(&hex::decode(fixture.trim()).unwrap()).unwrap();
+        let (plaintext, _) = blob.open("correct horse").unwrap();
+        assert_eq!(plaintext, b"keys sealed by rust-crypto");
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let key = WalletKey::derive("correct horse").unwrap();
+        let blob = key.seal(b"secret keys").unwrap();
+        assert_ne!(blob.ciphertext, b"secret keys");
+        let (plaintext, reopened) = blob.open("correct horse").unwrap();
+        assert_eq!(plaintext, b"secret keys");
+        assert_eq!(reopened.key, key.key);
+
+        // 同一密钥每次加密使用不同的 nonce
+        assert_ne!(key.seal(b"secret keys").unwrap().nonce, blob.nonce);
+
+        let err = blob.open("wrong horse").err().unwrap();
+        assert!(err.to_string().contains("Wrong passphrase"));
+        let mut tampered = blob.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert!(tampered.open("correct horse").is_err());
+        let mut tampered = blob.clone();
+        tampered.version = 2;
+        assert!(tampered.open("correct horse").is_err());
+        let mut tampered = blob.clone();
+        tampered.log_n = 40;
+        assert!(tampered.open("correct horse").is_err());
+
+        // log_n >= 16·r 的参数无效，打开时返回错误而不是 panic
+        let mut tampered = blob.clone();
+        (tampered.r, tampered.log_n) = (1, 16);
+        let err = tampered.open("correct horse").err().unwrap();
+        assert!(err.to_string().contains("Corrupted"), "{}", err);
+        // 128·r·N 为 4 GiB
+        let mut tampered = blob;
+        (tampered.r, tampered.log_n) = (32, 20);
+        assert!(tampered.open("correct horse").is_err());
+
+        assert!(WalletKey::derive("").is_err());
+    }
+
+    #[test]
+    fn test_keystore_json() {
+        // Web3 Secret Storage 规范中的 pbkdf2 测试向量，口令为 testpassword
+        let fixture = include_str!("../tests/fixtures/keystore-pbkdf2.json");
+        let (secret, address) = decrypt_keystore_json(fixture, "testpassword").unwrap();
+        assert_eq!(
+            hex::encode(secret),
+            "7a28b5ba57c53603b0b07b56bba752f7784bf506fa95edc395f5cf6c7514fe9d"
+        );
+        assert_eq!(address, None);
+
+        let json = encrypt_keystore_json(&[7; 32], "3Address", "pass").unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["version"], 3);
+        assert_eq!(value["crypto"]["cipher"], "aes-128-ctr");
+        assert_eq!(value["crypto"]["kdf"], "scrypt");
+        assert_eq!(value["crypto"]["kdfparams"]["n"], 1 << SCRYPT_LOG_N);
+        assert_eq!(value["id"].as_str().unwrap().len(), 36);
+        let (secret, address) = decrypt_keystore_json(&json, "pass").unwrap();
+        assert_eq!(secret, [7; 32]);
+        assert_eq!(address.as_deref(), Some("3Address"));
+
+        // 口令错误由 MAC 发现
+        let err = decrypt_keystore_json(&json, "wrong").unwrap_err();
+        assert!(err.to_string().contains("Wrong passphrase"), "{}", err);
+        let ciphertext = value["crypto"]["ciphertext"].as_str().unwrap();
+        let mut tampered = value.clone();
+        tampered["crypto"]["ciphertext"] = format!("00{}", &ciphertext[2..]).into();
+        assert!(decrypt_keystore_json(&tampered.to_string(), "pass").is_err());
+        let mut tampered = value.clone();
+        tampered["crypto"]["kdfparams"]["n"] = 1000.into();
+        let err = decrypt_keystore_json(&tampered.to_string(), "pass").unwrap_err();
+        assert!(err.to_string().contains("Unsupported"), "{}", err);
+        // log_n >= 16·r 和使用超过 256 MiB 内存的参数与钱包文件头一样被拒绝
+        for (n, r) in [(65536, 1), (1 << 20, 8)] {
+            let mut tampered = value.clone();
+            tampered["crypto"]["kdfparams"]["n"] = n.into();
+            tampered["crypto"]["kdfparams"]["r"] = r.into();
+            let err = decrypt_keystore_json(&tampered.to_string(), "pass").unwrap_err();
+            assert!(err.to_string().contains("Unsupported"), "{}", err);
+        }
+        let mut tampered = value;
+        tampered["crypto"]["cipher"] = "aes-128-cbc".into();
+        assert!(decrypt_keystore_json(&tampered.to_string(), "pass").is_err());
+        assert!(decrypt_keystore_json("{}", "pass").is_err());
+        assert!(encrypt_keystore_json(&[7; 32], "3Address", "").is_err());
+    }
+}
@@ -2,14 +2,28 @@
 use crate::cli::Cli;
 use crate::errors::Result;
 
+mod banlist;
+mod base58;
+mod bech32;
+mod bip39;
 mod block;
+mod blockfetch;
 mod blockchain;
 mod cli;
+mod datadir;
 mod errors;
+mod keystore;
+mod mempool;
+mod merkle;
+mod network;
+mod peerdb;
 mod server;
+mod signer;
+mod storage;
 mod transaction;
 mod utxoset;
 mod wallets;
+mod walletstorage;
 
 fn main() -> Result<()> {
     let mut cli = Cli::new()?;
@@ -0,0 +1,2032 @@
+//! memory pool of unconfirmed transactions
+
+use crate::block::{Block, MAX_BLOCK_TXS};
+use crate::transaction::*;
+use crate::utxoset::UTXOSet;
+use bincode::{deserialize, serialize};
+use failure::format_err;
+use log::info;
+use rand::seq::IteratorRandom;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+/// DEFAULT_MAX_MEMPOOL_SIZE 交易池中交易总字节数的默认上限
+pub const DEFAULT_MAX_MEMPOOL_SIZE: usize = 5_000_000;
+/// 被逐出或因费率过低被拒绝的交易在这段时间内再收到时不重新验证
+const REJECT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// 拒绝缓存最多记录的交易数
+const MAX_REJECTED: usize = 10_000;
+/// 孤立交易池最多保存的交易数
+const MAX_ORPHAN_TXS: usize = 100;
+/// 每个节点最多在孤立交易池中保存的交易数
+const MAX_ORPHAN_TXS_PER_PEER: usize = 10;
+/// 大于这个字节数的交易不作为孤立交易保存
+const MAX_ORPHAN_TX_SIZE: usize = 100_000;
+/// 前序交易在这段时间内没有出现的孤立交易被丢弃
+const ORPHAN_TX_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// 替换交易的手续费须超过被替换的交易及其后代的手续费之和至少这么多
+const MIN_REPLACEMENT_FEE_INCREMENT: u64 = 1;
+/// 一笔替换交易最多移除的交易数，含被替换的交易及其后代
+const MAX_REPLACED_TXS: usize = 100;
+/// DEFAULT_MEMPOOL_EXPIRY_HOURS 非本机钱包的交易在交易池中保留的默认小时数
+pub const DEFAULT_MEMPOOL_EXPIRY_HOURS: u64 = 72;
+/// MEMPOOL_FILE 网络数据目录中保存交易池的文件
+pub const MEMPOOL_FILE: &str = "mempool.dat";
+/// 交易池文件的格式版本，写在文件的第一个字节；版本 1 只保存交易，版本 2 加上加入的时间
+/// 和是否为本机钱包的交易
+const MEMPOOL_FILE_VERSION: u8 = 2;
+
+/// UnixTime 返回本地时钟自 UNIX 纪元以来的秒数，交易加入交易池的时间以此为单位
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// FeeRate 每字节手续费，以手续费和字节数保存，比较时交叉相乘避免除法的舍入误差
+#[derive(Debug, Clone, Copy)]
+pub struct FeeRate {
+    fee: u64,
+    size: usize,
+}
+
+impl FeeRate {
+    /// PerByte 返回每字节手续费的近似值，用于显示
+    pub fn per_byte(&self) -> f64 {
+        self.fee as f64 / self.size as f64
+    }
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let scaled = |rate: &FeeRate, size: usize| rate.fee as u128 * size as u128;
+        scaled(self, other.size).cmp(&scaled(other, self.size))
+    }
+}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for FeeRate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FeeRate {}
+
+impl fmt::Display for FeeRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.3} per byte", self.per_byte())
+    }
+}
+
+/// MempoolInfo getmempoolinfo 报告的交易池概况
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MempoolInfo {
+    pub transactions: usize,
+    /// 池中交易的总字节数
+    pub size: usize,
+    pub max_size: usize,
+    /// 新交易须超过的每字节手续费，交易池未满时为 0
+    pub min_fee_rate: f64,
+}
+
+/// MempoolEntryInfo getrawmempool、getmempoolentry 和 listpending 报告的池中一笔交易
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MempoolEntryInfo {
+    pub txid: Txid,
+    pub size: usize,
+    pub fee: u64,
+    /// 每字节手续费
+    pub fee_rate: f64,
+    /// 加入交易池的时间，见 unix_time
+    pub time: u64,
+    /// 这笔交易花费其输出的池中交易
+    pub depends: Vec<Txid>,
+    /// 是否为本机钱包广播的交易
+    pub wallet: bool,
+}
+
+/// 交易池文件中的一笔交易
+#[derive(Serialize, Deserialize)]
+struct SavedTx {
+    tx: Transaction,
+    time: u64,
+    wallet: bool,
+}
+
+/// MempoolError 交易未能进入交易池的具体原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    Coinbase {
+        txid: Txid,
+    },
+    AlreadyKnown {
+        txid: Txid,
+    },
+    MissingInput {
+        txid: Txid,
+        outpoint: OutPoint,
+    },
+    ImmatureInput {
+        txid: Txid,
+        outpoint: OutPoint,
+    },
+    InvalidTransaction {
+        txid: Txid,
+        error: TxVerifyError,
+    },
+    RecentlyRejected {
+        txid: Txid,
+    },
+    TooLarge {
+        txid: Txid,
+        size: usize,
+        max_size: usize,
+    },
+    FeeRateTooLow {
+        txid: Txid,
+        fee_rate: FeeRate,
+        min_fee_rate: FeeRate,
+    },
+    Orphan {
+        txid: Txid,
+        parents: Vec<Txid>,
+    },
+    ReplacementMissesInput {
+        txid: Txid,
+        replaced: Txid,
+        outpoint: OutPoint,
+    },
+    ReplacementFeeTooLow {
+        txid: Txid,
+        fee: u64,
+        required: u64,
+    },
+    ReplacementFeeRateTooLow {
+        txid: Txid,
+        fee_rate: FeeRate,
+        replaced_fee_rate: FeeRate,
+    },
+    TooManyReplacements {
+        txid: Txid,
+        count: usize,
+        max: usize,
+    },
+    Storage {
+        message: String,
+    },
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MempoolError::Coinbase { txid } => {
+                write!(f, "coinbase {} cannot enter the mempool", txid)
+            }
+            MempoolError::AlreadyKnown { txid } => {
+                write!(f, "transaction {} is already in the mempool", txid)
+            }
+            MempoolError::MissingInput { txid, outpoint } => write!(
+                f,
+                "transaction {} spends {}, which is not an unspent output",
+                txid, outpoint
+            ),
+            MempoolError::ImmatureInput { txid, outpoint } => write!(
+                f,
+                "transaction {} spends the immature coinbase output {}",
+                txid, outpoint
+            ),
+            MempoolError::InvalidTransaction { txid, error } => {
+                write!(f, "invalid transaction {}: {}", txid, error)
+            }
+            MempoolError::RecentlyRejected { txid } => {
+                write!(f, "transaction {} was rejected recently", txid)
+            }
+            MempoolError::TooLarge {
+                txid,
+                size,
+                max_size,
+            } => write!(
+                f,
+                "transaction {} needs {} bytes with its unconfirmed ancestors, the mempool holds {}",
+                txid, size, max_size
+            ),
+            MempoolError::FeeRateTooLow {
+                txid,
+                fee_rate,
+                min_fee_rate,
+            } => write!(
+                f,
+                "transaction {} pays {}, the full mempool needs more than {}",
+                txid, fee_rate, min_fee_rate
+            ),
+            MempoolError::Orphan { txid, parents } => write!(
+                f,
+                "transaction {} waits for its parents {}",
+                txid,
+                parents.join(", ")
+            ),
+            MempoolError::ReplacementMissesInput {
+                txid,
+                replaced,
+                outpoint,
+            } => write!(
+                f,
+                "transaction {} replaces {} but does not spend its input {}",
+                txid, replaced, outpoint
+            ),
+            MempoolError::ReplacementFeeTooLow {
+                txid,
+                fee,
+                required,
+            } => write!(
+                f,
+                "transaction {} pays a fee of {}, replacing needs at least {}",
+                txid, fee, required
+            ),
+            MempoolError::ReplacementFeeRateTooLow {
+                txid,
+                fee_rate,
+                replaced_fee_rate,
+            } => write!(
+                f,
+                "transaction {} pays {}, the transaction it replaces already pays {}",
+                txid, fee_rate, replaced_fee_rate
+            ),
+            MempoolError::TooManyReplacements { txid, count, max } => write!(
+                f,
+                "transaction {} would replace {} transactions, at most {} can be replaced",
+                txid, count, max
+            ),
+            MempoolError::Storage { message } => {
+                write!(f, "cannot read the chain state: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MempoolError {}
+
+impl From<failure::Error> for MempoolError {
+    fn from(err: failure::Error) -> Self {
+        MempoolError::Storage {
+            message: err.to_string(),
+        }
+    }
+}
+
+/// Mempool 已完整验证、彼此不冲突的未确认交易，按 txid 保存
+///
+/// 交易的输入是UTXO集合中已成熟的未花费输出或池中其他交易的输出，池中任意两笔交易
+/// 不花费同一个输出，手续费更高的交易可以按 plan_replacement 替换与它冲突的交易。
+/// 交易的总字节数不超过上限，超过时逐出费率最低的交易。非本机钱包的交易在交易池中
+/// 停留超过 expiry 后由 expire 删除
+pub struct Mempool {
+    entries: HashMap<Txid, MempoolEntry>,
+    /// 池中交易花费的输出 -> 花费它的交易
+    spent: HashMap<OutPoint, Txid>,
+    /// 下一笔加入的交易的序号
+    next_sequence: u64,
+    /// 池中交易的总字节数
+    total_size: usize,
+    max_size: usize,
+    /// 最近被逐出或因费率过低被拒绝的交易 -> 拒绝的时间
+    rejected: HashMap<Txid, Instant>,
+    /// 交易池满过之后，直到总字节数降到上限的一半以下，新交易的费率须高于池中最低的费率
+    full: bool,
+    orphans: OrphanTxPool,
+    expiry: Duration,
+    /// 本机钱包广播、尚未确认的交易，不会过期，节点定期重新广播
+    wallet_txs: HashSet<Txid>,
+}
+
+struct MempoolEntry {
+    tx: Transaction,
+    fee: u64,
+    size: usize,
+    /// 加入交易池的顺序，前序交易总是先于花费它的交易加入
+    sequence: u64,
+    /// 加入交易池的时间，见 unix_time
+    time: u64,
+}
+
+impl MempoolEntry {
+    fn fee_rate(&self) -> FeeRate {
+        FeeRate {
+            fee: self.fee,
+            size: self.size,
+        }
+    }
+}
+
+/// SharedMempool 在服务器的连接线程和挖矿之间共享的交易池
+///
+/// 同时需要UTXO集合时先获取集合的锁再获取交易池的锁，两者的顺序固定才不会死锁
+#[derive(Clone, Default)]
+pub struct SharedMempool(Arc<Mutex<Mempool>>);
+
+/// MempoolOptions 交易池的容量，以及非本机钱包的交易保留的时间
+#[derive(Clone, Debug)]
+pub struct MempoolOptions {
+    /// 池中交易总字节数的上限，超过时逐出费率最低的交易
+    pub max_size: usize,
+    /// 非本机钱包的交易在交易池中停留这么久后由 expire 删除
+    pub expiry: Duration,
+}
+
+impl Default for MempoolOptions {
+    fn default() -> Self {
+        MempoolOptions {
+            max_size: DEFAULT_MAX_MEMPOOL_SIZE,
+            expiry: Duration::from_secs(DEFAULT_MEMPOOL_EXPIRY_HOURS * 60 * 60),
+        }
+    }
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool::with_options(MempoolOptions::default())
+    }
+}
+
+impl Mempool {
+    /// WithOptions 按 options 创建空交易池
+    pub fn with_options(options: MempoolOptions) -> Mempool {
+        let MempoolOptions { max_size, expiry } = options;
+        Mempool {
+            entries: HashMap::new(),
+            spent: HashMap::new(),
+            next_sequence: 0,
+            total_size: 0,
+            max_size,
+            rejected: HashMap::new(),
+            full: false,
+            orphans: OrphanTxPool::default(),
+            expiry,
+            wallet_txs: HashSet::new(),
+        }
+    }
+
+    /// Add 对照UTXO集合完整验证交易后加入交易池
+    ///
+    /// 每个输入须为集合中已成熟的未花费输出或池中其他交易的输出，且没有被池中其他交易
+    /// 花费，签名和金额由 Transaction::prepare_verify 检查。锁定高度未到的交易也可以加入，
+    /// 等到可以打包时再挖出。与池中交易花费同一输出时按 plan_replacement 替换它们。
+    /// 放不下时按 plan_eviction 逐出费率更低的交易或拒绝新交易，
+    /// 被逐出和因费率过低被拒绝的交易在 REJECT_TIMEOUT 内再收到时直接拒绝
+    pub fn add(&mut self, tx: Transaction, utxo: &UTXOSet) -> Result<(), MempoolError> {
+        self.add_at(tx, utxo, unix_time())
+    }
+
+    /// 同 add，以 time 为交易加入交易池的时间
+    fn add_at(&mut self, tx: Transaction, utxo: &UTXOSet, time: u64) -> Result<(), MempoolError> {
+        let txid = tx.id.clone();
+        if tx.is_coinbase() {
+            return Err(MempoolError::Coinbase { txid });
+        }
+        if self.entries.contains_key(&txid) {
+            return Err(MempoolError::AlreadyKnown { txid });
+        }
+        if self.recently_rejected(&txid) {
+            return Err(MempoolError::RecentlyRejected { txid });
+        }
+
+        let (_, tip) = utxo.tip()?;
+        // 池中的前序交易还不在链上，从交易池取出
+        let mut pool_parents = HashMap::new();
+        // 与 tx 花费同一输出的池中交易
+        let mut conflicts = HashSet::new();
+        for outpoint in tx.outpoints() {
+            if let Some(spent_by) = self.spent.get(outpoint) {
+                conflicts.insert(spent_by.clone());
+            }
+            if let Some(parent) = self.entries.get(&outpoint.txid) {
+                let spendable = parent
+                    .tx
+                    .vout
+                    .get(outpoint.vout as usize)
+                    .is_some_and(|output| !output.is_data());
+                if !spendable {
+                    return Err(MempoolError::MissingInput {
+                        txid,
+                        outpoint: outpoint.clone(),
+                    });
+                }
+                pool_parents.insert(parent.tx.id.clone(), parent.tx.clone());
+                continue;
+            }
+            let Some(entry) = utxo.get_entry(outpoint)? else {
+                return Err(MempoolError::MissingInput {
+                    txid,
+                    outpoint: outpoint.clone(),
+                });
+            };
+            if !entry.is_mature(tip) {
+                return Err(MempoolError::ImmatureInput {
+                    txid,
+                    outpoint: outpoint.clone(),
+                });
+            }
+        }
+
+        let mut prev_txs = utxo.blockchain.get_prev_txs(&tx)?;
+        prev_txs.extend(pool_parents);
+        let invalid = |error| MempoolError::InvalidTransaction {
+            txid: txid.clone(),
+            error,
+        };
+        let checks = tx.prepare_verify(&prev_txs).map_err(invalid)?;
+        let fee = tx.fee(&prev_txs)?;
+        let size = tx.size()?;
+        let fee_rate = FeeRate { fee, size };
+        let replaced = self.plan_replacement(&tx, fee_rate, &conflicts)?;
+
+        // 确定放得下之后才验证最耗时的签名
+        let evicted = match self.plan_eviction(&tx, fee_rate, &replaced) {
+            Ok(evicted) => evicted,
+            Err(err) => {
+                if let MempoolError::FeeRateTooLow { .. } = err {
+                    self.full = true;
+                    self.reject(txid.clone());
+                }
+                return Err(err);
+            }
+        };
+        verify_signatures(&checks, false).map_err(|check| invalid(check.error()))?;
+        // 全部检查通过后才移除被替换的交易，替换要么完整生效，要么交易池不变
+        for replaced in replaced {
+            info!("replace transaction {} with {}", replaced, txid);
+            self.remove(&replaced);
+        }
+        for evicted in evicted {
+            info!("evict transaction {} to make room for {}", evicted, txid);
+            self.remove(&evicted);
+            self.reject(evicted);
+            self.full = true;
+        }
+
+        for outpoint in tx.outpoints() {
+            self.spent.insert(outpoint.clone(), txid.clone());
+        }
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.total_size += size;
+        self.entries.insert(
+            txid,
+            MempoolEntry {
+                tx,
+                fee,
+                size,
+                sequence,
+                time,
+            },
+        );
+        Ok(())
+    }
+
+    /// Get 按 txid 查找池中的交易
+    pub fn get(&self, txid: &str) -> Option<&Transaction> {
+        self.entries.get(txid).map(|entry| &entry.tx)
+    }
+
+    #[cfg(test)]
+    pub fn fee(&self, txid: &str) -> Option<u64> {
+        self.entries.get(txid).map(|entry| entry.fee)
+    }
+
+    /// Accept 把节点 peer 发来的交易加入交易池，返回因此加入交易池的全部交易，tx 在最前面
+    ///
+    /// 只因输入所属的交易既不在交易池也不在UTXO集合中而无法加入时，tx 暂存为孤立交易并
+    /// 返回 MempoolError::Orphan，节点的孤立交易已达上限时不暂存。交易加入后按 retry_orphans
+    /// 重试等待它的孤立交易
+    pub fn accept(
+        &mut self,
+        tx: Transaction,
+        peer: &str,
+        utxo: &UTXOSet,
+    ) -> Result<Vec<Txid>, MempoolError> {
+        let txid = tx.id.clone();
+        match self.add(tx.clone(), utxo) {
+            Ok(()) => {
+                let mut added = vec![txid.clone()];
+                added.extend(self.retry_orphans(vec![txid], utxo));
+                Ok(added)
+            }
+            Err(err @ MempoolError::MissingInput { .. }) => {
+                let parents = self.missing_parents(&tx, utxo)?;
+                if parents.is_empty() || tx.size()? > MAX_ORPHAN_TX_SIZE {
+                    return Err(err);
+                }
+                let orphan = OrphanTx {
+                    tx,
+                    peer: peer.to_string(),
+                    parents: parents.clone(),
+                    received: Instant::now(),
+                };
+                if !self.orphans.insert(orphan, Instant::now()) {
+                    return Err(err);
+                }
+                Err(MempoolError::Orphan { txid, parents })
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// RetryOrphans 重试等待 parents 中交易的孤立交易，返回加入交易池的孤立交易
+    ///
+    /// parents 为刚加入交易池或被区块确认的交易。加入的孤立交易又会重试等待它的孤立交易；
+    /// 仍缺少其他前序交易的重新暂存，其余失败的被丢弃
+    pub fn retry_orphans(&mut self, parents: Vec<Txid>, utxo: &UTXOSet) -> Vec<Txid> {
+        let mut added = Vec::new();
+        let mut pending = parents;
+        while let Some(parent) = pending.pop() {
+            for mut orphan in self.orphans.take_waiting(&parent) {
+                let txid = orphan.tx.id.clone();
+                match self.add(orphan.tx.clone(), utxo) {
+                    Ok(()) => {
+                        info!("accept orphan transaction {}", txid);
+                        added.push(txid.clone());
+                        pending.push(txid);
+                    }
+                    Err(MempoolError::MissingInput { .. }) => {
+                        match self.missing_parents(&orphan.tx, utxo) {
+                            Ok(parents) if !parents.is_empty() => {
+                                orphan.parents = parents;
+                                self.orphans.insert(orphan, Instant::now());
+                            }
+                            _ => info!("drop orphan transaction {}", txid),
+                        }
+                    }
+                    Err(err) => info!("drop orphan transaction {}: {}", txid, err),
+                }
+            }
+        }
+        added
+    }
+
+    /// Knows 交易是否在交易池或孤立交易池中，或最近被拒绝，收到它的 inv 时不必下载
+    pub fn knows(&self, txid: &str) -> bool {
+        self.entries.contains_key(txid)
+            || self.orphans.txs.contains_key(txid)
+            || self.recently_rejected(txid)
+    }
+
+    /// 返回 tx 的输入所属的、既不在交易池也不在UTXO集合中的交易
+    fn missing_parents(&self, tx: &Transaction, utxo: &UTXOSet) -> Result<Vec<Txid>, MempoolError> {
+        let mut parents = Vec::new();
+        for outpoint in tx.outpoints() {
+            if self.entries.contains_key(&outpoint.txid) || parents.contains(&outpoint.txid) {
+                continue;
+            }
+            if utxo.get_entry(outpoint)?.is_none() {
+                parents.push(outpoint.txid.clone());
+            }
+        }
+        Ok(parents)
+    }
+
+    /// RecentlyRejected 交易是否在 REJECT_TIMEOUT 内被逐出或因费率过低被拒绝
+    pub fn recently_rejected(&self, txid: &str) -> bool {
+        self.rejected
+            .get(txid)
+            .is_some_and(|at| at.elapsed() < REJECT_TIMEOUT)
+    }
+
+    /// Save 把池中的交易按加入的顺序连同加入的时间和是否为本机钱包的交易写入 path，
+    /// 返回写入的交易数
+    ///
+    /// 文件的第一个字节是格式版本，之后是交易列表。先写入临时文件再改名，
+    /// 写到一半中断时保留上一次保存的文件
+    pub fn save(&self, path: &Path) -> crate::errors::Result<usize> {
+        let txs: Vec<SavedTx> = self
+            .entries_in_order()
+            .into_iter()
+            .map(|entry| SavedTx {
+                tx: entry.tx.clone(),
+                time: entry.time,
+                wallet: self.wallet_txs.contains(&entry.tx.id),
+            })
+            .collect();
+        let mut data = vec![MEMPOOL_FILE_VERSION];
+        data.extend(serialize(&txs)?);
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(txs.len())
+    }
+
+    /// Load 读取 save 写出的文件，对照UTXO集合按原来的顺序重新加入其中的交易，
+    /// 返回加入的交易数；文件不存在时返回 0
+    ///
+    /// 已被确认、与链上交易冲突或不再有效的交易被丢弃，每笔记录一行日志。版本 1 的文件中的
+    /// 交易以现在为加入的时间；格式版本不认识的文件返回错误，不会被当作交易读取
+    pub fn load(&mut self, path: &Path, utxo: &UTXOSet) -> crate::errors::Result<usize> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        let corrupt = |e| format_err!("Mempool file {} is corrupt: {}", path.display(), e);
+        let txs: Vec<SavedTx> = match data.first() {
+            Some(1) => {
+                let txs: Vec<Transaction> = deserialize(&data[1..]).map_err(corrupt)?;
+                let time = unix_time();
+                txs.into_iter()
+                    .map(|tx| SavedTx {
+                        tx,
+                        time,
+                        wallet: false,
+                    })
+                    .collect()
+            }
+            Some(&MEMPOOL_FILE_VERSION) => deserialize(&data[1..]).map_err(corrupt)?,
+            Some(version) => {
+                return Err(format_err!(
+                    "Mempool file {} has unknown version {}",
+                    path.display(),
+                    version
+                ));
+            }
+            None => return Err(format_err!("Mempool file {} is empty", path.display())),
+        };
+        let mut added = 0;
+        for saved in txs {
+            let txid = saved.tx.id.clone();
+            match self.add_at(saved.tx, utxo, saved.time) {
+                Ok(()) => {
+                    added += 1;
+                    if saved.wallet {
+                        self.wallet_txs.insert(txid);
+                    }
+                }
+                Err(err) => info!("drop saved transaction {}: {}", txid, err),
+            }
+        }
+        Ok(added)
+    }
+
+    /// IsEmpty 交易池是否为空
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Info 返回交易池中的交易数、总字节数、上限和新交易须超过的费率
+    pub fn info(&self) -> MempoolInfo {
+        MempoolInfo {
+            transactions: self.entries.len(),
+            size: self.total_size,
+            max_size: self.max_size,
+            min_fee_rate: self.min_fee_rate().map_or(0.0, |rate| rate.per_byte()),
+        }
+    }
+
+    /// EntriesInfo 按加入的顺序返回池中每笔交易的大小、手续费、加入的时间和依赖的池中交易
+    pub fn entries_info(&self) -> Vec<MempoolEntryInfo> {
+        self.entries_in_order()
+            .into_iter()
+            .map(|entry| self.info_of(entry))
+            .collect()
+    }
+
+    /// EntryInfo 返回池中一笔交易的信息，不在池中时返回 None
+    pub fn entry_info(&self, txid: &str) -> Option<MempoolEntryInfo> {
+        self.entries.get(txid).map(|entry| self.info_of(entry))
+    }
+
+    fn info_of(&self, entry: &MempoolEntry) -> MempoolEntryInfo {
+        let mut depends: Vec<Txid> = Vec::new();
+        for outpoint in entry.tx.outpoints() {
+            if self.entries.contains_key(&outpoint.txid) && !depends.contains(&outpoint.txid) {
+                depends.push(outpoint.txid.clone());
+            }
+        }
+        MempoolEntryInfo {
+            txid: entry.tx.id.clone(),
+            size: entry.size,
+            fee: entry.fee,
+            fee_rate: entry.fee_rate().per_byte(),
+            time: entry.time,
+            depends,
+            wallet: self.wallet_txs.contains(&entry.tx.id),
+        }
+    }
+
+    /// MarkWalletTx 把池中的交易记为本机钱包广播的交易，它不再过期，并由节点定期重新广播
+    pub fn mark_wallet_tx(&mut self, txid: &str) {
+        if self.entries.contains_key(txid) {
+            self.wallet_txs.insert(txid.to_string());
+        }
+    }
+
+    /// WalletTxs 按加入的顺序返回池中本机钱包广播的交易，前序交易在前，重新广播时依次发送
+    pub fn wallet_txs(&self) -> Vec<Txid> {
+        self.entries_in_order()
+            .into_iter()
+            .filter(|entry| self.wallet_txs.contains(&entry.tx.id))
+            .map(|entry| entry.tx.id.clone())
+            .collect()
+    }
+
+    /// Expire 删除在 now 之前 expiry 以上加入交易池、不属于本机钱包的交易及其后代，
+    /// 返回删除的交易数。now 为 unix_time 的秒数
+    pub fn expire(&mut self, now: u64) -> usize {
+        let expiry = self.expiry.as_secs();
+        let mut expired: Vec<&MempoolEntry> = self
+            .entries
+            .values()
+            .filter(|entry| {
+                !self.wallet_txs.contains(&entry.tx.id) && now.saturating_sub(entry.time) >= expiry
+            })
+            .collect();
+        expired.sort_by_key(|entry| entry.sequence);
+        let expired: Vec<Txid> = expired.iter().map(|entry| entry.tx.id.clone()).collect();
+        let before = self.entries.len();
+        for txid in expired {
+            if self.entries.contains_key(&txid) {
+                info!("transaction {} expired from the mempool", txid);
+                self.remove_with_descendants(&txid);
+            }
+        }
+        before - self.entries.len()
+    }
+
+    fn entries_in_order(&self) -> Vec<&MempoolEntry> {
+        let mut entries: Vec<&MempoolEntry> = self.entries.values().collect();
+        entries.sort_by_key(|entry| entry.sequence);
+        entries
+    }
+
+    /// 交易池满过之后为池中最低的费率，否则为 None
+    fn min_fee_rate(&self) -> Option<FeeRate> {
+        if !self.full {
+            return None;
+        }
+        self.entries.values().map(MempoolEntry::fee_rate).min()
+    }
+
+    /// RemoveConfirmed 区块连接到最新区块之后，删除区块中的交易，以及与它们花费同一输出的
+    /// 交易和这些交易在池中的后代
+    ///
+    /// 区块中交易在池中的后代仍然有效，留在交易池中
+    pub fn remove_confirmed(&mut self, block: &Block) {
+        for tx in block.get_transaction() {
+            self.remove(&tx.id);
+            for outpoint in tx.outpoints() {
+                if let Some(txid) = self.spent.get(outpoint).cloned() {
+                    info!(
+                        "drop transaction {}: block {} spends {}",
+                        txid,
+                        block.get_hash(),
+                        outpoint
+                    );
+                    self.remove_with_descendants(&txid);
+                }
+            }
+        }
+    }
+
+    /// Readd 重组之后对照新的UTXO集合重建交易池
+    ///
+    /// disconnected 为按断开顺序排列的旧分支区块。先按区块中的顺序放回其中的交易，再按
+    /// 加入的顺序重新加入原有的交易，前序交易总在前面；不再有效的交易被丢弃
+    pub fn readd(&mut self, disconnected: &[Block], utxo: &UTXOSet) {
+        let previous = std::mem::take(&mut self.entries);
+        self.spent.clear();
+        self.total_size = 0;
+        let returned = disconnected
+            .iter()
+            .rev()
+            .flat_map(Block::get_transaction)
+            .filter(|tx| !tx.is_coinbase())
+            .cloned();
+        let mut previous: Vec<MempoolEntry> = previous.into_values().collect();
+        previous.sort_by_key(|entry| entry.sequence);
+        // 原有的交易保留加入的时间，不因重组推迟过期
+        let now = unix_time();
+        let returned = returned.map(|tx| (tx, now));
+        let previous = previous.into_iter().map(|entry| (entry.tx, entry.time));
+        for (tx, time) in returned.chain(previous) {
+            let txid = tx.id.clone();
+            if let Err(err) = self.add_at(tx, utxo, time) {
+                info!("drop transaction {} after reorganization: {}", txid, err);
+                self.wallet_txs.remove(&txid);
+            }
+        }
+    }
+
+    /// TakeForBlock 按每字节手续费从高到低选取打包进高度为 height 的区块的交易，返回
+    /// 按打包顺序排列的交易及其手续费总额，交易池不变
+    ///
+    /// 交易连同池中尚未选取的祖先一起加入，祖先排在前面；锁定高度未到的交易及其后代跳过。
+    /// 一起加入会使交易的总大小超过 max_bytes 字节，或使区块超过 MAX_BLOCK_TXS 笔交易
+    /// （含创币交易）时跳过这笔交易，继续尝试后面更小的交易
+    pub fn take_for_block(&self, height: i32, max_bytes: usize) -> (Vec<Transaction>, u64) {
+        self.take_limited(height, max_bytes, MAX_BLOCK_TXS - 1)
+    }
+
+    fn take_limited(
+        &self,
+        height: i32,
+        max_bytes: usize,
+        max_txs: usize,
+    ) -> (Vec<Transaction>, u64) {
+        // 费率相同时按 txid 排序，各节点在同样的交易池上选出同样的交易
+        let mut candidates: Vec<&MempoolEntry> = self.entries.values().collect();
+        candidates.sort_by(|a, b| {
+            b.fee_rate()
+                .cmp(&a.fee_rate())
+                .then_with(|| a.tx.id.cmp(&b.tx.id))
+        });
+        let mut selected = HashSet::new();
+        let mut txs = Vec::new();
+        let mut size = 0;
+        let mut fees: u64 = 0;
+        for entry in candidates {
+            if selected.contains(entry.tx.id.as_str()) {
+                continue;
+            }
+            let mut package = Vec::new();
+            if !self.with_ancestors(entry, height, &selected, &mut package) {
+                continue;
+            }
+            let package_size: usize = package.iter().map(|entry| entry.size).sum();
+            if txs.len() + package.len() > max_txs || size + package_size > max_bytes {
+                continue;
+            }
+            for entry in package {
+                selected.insert(entry.tx.id.as_str());
+                size += entry.size;
+                fees = fees.saturating_add(entry.fee);
+                txs.push(entry.tx.clone());
+            }
+        }
+        info!(
+            "select {} transactions, {} bytes, {} in fees",
+            txs.len(),
+            size,
+            fees
+        );
+        (txs, fees)
+    }
+
+    /// 把 entry 和它在池中尚未选取的祖先按祖先在前的顺序放入 package，
+    /// 其中有锁定高度未到的交易时返回 false
+    fn with_ancestors<'a>(
+        &'a self,
+        entry: &'a MempoolEntry,
+        height: i32,
+        selected: &HashSet<&str>,
+        package: &mut Vec<&'a MempoolEntry>,
+    ) -> bool {
+        let txid = entry.tx.id.as_str();
+        if selected.contains(txid) || package.iter().any(|added| added.tx.id == txid) {
+            return true;
+        }
+        if !entry.tx.is_final(height) {
+            return false;
+        }
+        for outpoint in entry.tx.outpoints() {
+            if let Some(parent) = self.entries.get(&outpoint.txid)
+                && !self.with_ancestors(parent, height, selected, package)
+            {
+                return false;
+            }
+        }
+        package.push(entry);
+        true
+    }
+
+    /// 计算费率为 fee_rate 的交易 tx 替换与它冲突的池中交易 conflicts 时需要移除的交易，
+    /// 即被替换的交易及其后代，没有冲突时为空
+    ///
+    /// tx 须花费每笔被替换交易的全部输入，费率高于每笔被替换的交易，手续费比移除的交易
+    /// 之和至少多 MIN_REPLACEMENT_FEE_INCREMENT，且最多移除 MAX_REPLACED_TXS 笔交易
+    fn plan_replacement(
+        &self,
+        tx: &Transaction,
+        fee_rate: FeeRate,
+        conflicts: &HashSet<Txid>,
+    ) -> Result<Vec<Txid>, MempoolError> {
+        // 按 txid 检查，有多笔冲突交易时各节点报告同样的错误
+        let mut conflicts: Vec<&Txid> = conflicts.iter().collect();
+        conflicts.sort();
+        let outpoints: HashSet<&OutPoint> = tx.outpoints().collect();
+        for replaced in &conflicts {
+            let entry = &self.entries[*replaced];
+            if let Some(outpoint) = entry.tx.outpoints().find(|o| !outpoints.contains(o)) {
+                return Err(MempoolError::ReplacementMissesInput {
+                    txid: tx.id.clone(),
+                    replaced: entry.tx.id.clone(),
+                    outpoint: outpoint.clone(),
+                });
+            }
+            if entry.fee_rate() >= fee_rate {
+                return Err(MempoolError::ReplacementFeeRateTooLow {
+                    txid: tx.id.clone(),
+                    fee_rate,
+                    replaced_fee_rate: entry.fee_rate(),
+                });
+            }
+        }
+
+        let mut removed = Vec::new();
+        let mut seen = HashSet::new();
+        for replaced in conflicts {
+            for txid in self.descendants(replaced) {
+                if seen.insert(txid.clone()) {
+                    removed.push(txid);
+                }
+            }
+        }
+        if removed.len() > MAX_REPLACED_TXS {
+            return Err(MempoolError::TooManyReplacements {
+                txid: tx.id.clone(),
+                count: removed.len(),
+                max: MAX_REPLACED_TXS,
+            });
+        }
+        // tx 花费的输出在替换之后必须仍然存在
+        for outpoint in tx.outpoints() {
+            if seen.contains(&outpoint.txid) {
+                return Err(MempoolError::MissingInput {
+                    txid: tx.id.clone(),
+                    outpoint: outpoint.clone(),
+                });
+            }
+        }
+        let required = removed
+            .iter()
+            .map(|txid| self.entries[txid].fee)
+            .fold(MIN_REPLACEMENT_FEE_INCREMENT, u64::saturating_add);
+        if !removed.is_empty() && fee_rate.fee < required {
+            return Err(MempoolError::ReplacementFeeTooLow {
+                txid: tx.id.clone(),
+                fee: fee_rate.fee,
+                required,
+            });
+        }
+        Ok(removed)
+    }
+
+    /// 计算放入费率为 fee_rate 的交易 tx 需要逐出的交易，replaced 为 tx 替换掉的交易
+    ///
+    /// 从费率最低的交易开始连同其后代逐出，直到放得下 tx，tx 在池中的祖先不逐出。
+    /// 将被逐出的交易费率不低于 tx 时拒绝 tx；交易池满过之后即使放得下，tx 的费率也须
+    /// 高于池中最低的费率
+    fn plan_eviction(
+        &self,
+        tx: &Transaction,
+        fee_rate: FeeRate,
+        replaced: &[Txid],
+    ) -> Result<Vec<Txid>, MempoolError> {
+        let ancestors = self.ancestors(tx);
+        let size = fee_rate.size
+            + ancestors
+                .iter()
+                .map(|txid| self.entries[txid].size)
+                .sum::<usize>();
+        if size > self.max_size {
+            return Err(MempoolError::TooLarge {
+                txid: tx.id.clone(),
+                size,
+                max_size: self.max_size,
+            });
+        }
+        let replaced_size: usize = replaced.iter().map(|txid| self.entries[txid].size).sum();
+        let needed =
+            (self.total_size - replaced_size + fee_rate.size).saturating_sub(self.max_size);
+        if needed == 0 && !self.full {
+            return Ok(Vec::new());
+        }
+
+        // 费率相同时先逐出 txid 较大的交易，各节点逐出同样的交易
+        let mut candidates: Vec<&MempoolEntry> = self
+            .entries
+            .values()
+            .filter(|entry| !ancestors.contains(&entry.tx.id) && !replaced.contains(&entry.tx.id))
+            .collect();
+        candidates.sort_by(|a, b| {
+            a.fee_rate()
+                .cmp(&b.fee_rate())
+                .then_with(|| b.tx.id.cmp(&a.tx.id))
+        });
+        let too_low = |entry: &MempoolEntry| MempoolError::FeeRateTooLow {
+            txid: tx.id.clone(),
+            fee_rate,
+            min_fee_rate: entry.fee_rate(),
+        };
+        if let Some(lowest) = candidates.first()
+            && lowest.fee_rate() >= fee_rate
+        {
+            return Err(too_low(lowest));
+        }
+
+        let mut evicted = Vec::new();
+        let mut seen: HashSet<Txid> = replaced.iter().cloned().collect();
+        let mut freed = 0;
+        for entry in candidates {
+            if freed >= needed {
+                break;
+            }
+            if seen.contains(&entry.tx.id) {
+                continue;
+            }
+            if entry.fee_rate() >= fee_rate {
+                return Err(too_low(entry));
+            }
+            for txid in self.descendants(&entry.tx.id) {
+                if seen.insert(txid.clone()) {
+                    freed += self.entries[&txid].size;
+                    evicted.push(txid);
+                }
+            }
+        }
+        Ok(evicted)
+    }
+
+    /// 返回 tx 在池中的全部祖先
+    fn ancestors(&self, tx: &Transaction) -> HashSet<Txid> {
+        let mut ancestors = HashSet::new();
+        let mut pending: Vec<&Transaction> = vec![tx];
+        while let Some(tx) = pending.pop() {
+            for outpoint in tx.outpoints() {
+                if let Some(parent) = self.entries.get(&outpoint.txid)
+                    && ancestors.insert(parent.tx.id.clone())
+                {
+                    pending.push(&parent.tx);
+                }
+            }
+        }
+        ancestors
+    }
+
+    /// 返回交易本身及池中花费它的输出的全部后代
+    fn descendants(&self, txid: &str) -> Vec<Txid> {
+        let mut descendants = vec![txid.to_string()];
+        let mut next = 0;
+        while let Some(txid) = descendants.get(next).cloned() {
+            next += 1;
+            let Some(entry) = self.entries.get(&txid) else {
+                continue;
+            };
+            for index in 0..entry.tx.vout.len() {
+                if let Some(child) = self.spent.get(&OutPoint::new(&txid, index as u32))
+                    && !descendants.contains(child)
+                {
+                    descendants.push(child.clone());
+                }
+            }
+        }
+        descendants
+    }
+
+    /// 删除交易以及池中花费它的输出的全部后代
+    fn remove_with_descendants(&mut self, txid: &str) {
+        for txid in self.descendants(txid) {
+            self.remove(&txid);
+        }
+    }
+
+    fn remove(&mut self, txid: &str) {
+        if let Some(entry) = self.entries.remove(txid) {
+            for outpoint in entry.tx.outpoints() {
+                self.spent.remove(outpoint);
+            }
+            self.total_size -= entry.size;
+            if self.total_size < self.max_size / 2 {
+                self.full = false;
+            }
+            self.wallet_txs.remove(txid);
+        }
+    }
+
+    /// 把交易记入拒绝缓存，顺便清除过期的记录；缓存已满时丢弃最早的记录
+    fn reject(&mut self, txid: Txid) {
+        let now = Instant::now();
+        self.rejected
+            .retain(|_, at| now.duration_since(*at) < REJECT_TIMEOUT);
+        if self.rejected.len() >= MAX_REJECTED
+            && let Some(oldest) = self
+                .rejected
+                .iter()
+                .min_by_key(|(_, at)| **at)
+                .map(|(txid, _)| txid.clone())
+        {
+            self.rejected.remove(&oldest);
+        }
+        self.rejected.insert(txid, now);
+    }
+}
+
+/// OrphanTxPool 输入所属的交易还没收到的孤立交易，按缺少的前序交易索引
+#[derive(Default)]
+struct OrphanTxPool {
+    txs: HashMap<Txid, OrphanTx>,
+    /// 缺少的前序交易 -> 等待它的孤立交易
+    waiting: HashMap<Txid, HashSet<Txid>>,
+    /// 节点 -> 它发来的孤立交易数
+    per_peer: HashMap<String, usize>,
+}
+
+struct OrphanTx {
+    tx: Transaction,
+    /// 发来交易的节点
+    peer: String,
+    /// 缺少的前序交易
+    parents: Vec<Txid>,
+    received: Instant,
+}
+
+impl OrphanTxPool {
+    /// Insert 保存一笔孤立交易，先丢弃超时的孤立交易；发来它的节点已有 MAX_ORPHAN_TXS_PER_PEER
+    /// 笔时不保存并返回 false，总数超出 MAX_ORPHAN_TXS 时随机丢弃一笔
+    ///
+    /// 随机丢弃使攻击者无法预测哪些孤立交易留下，不能靠持续发送把别人的孤立交易挤出去
+    fn insert(&mut self, orphan: OrphanTx, now: Instant) -> bool {
+        self.expire(now);
+        let txid = orphan.tx.id.clone();
+        if self.txs.contains_key(&txid) {
+            return true;
+        }
+        let count = self.per_peer.get(&orphan.peer).copied().unwrap_or(0);
+        if count >= MAX_ORPHAN_TXS_PER_PEER {
+            return false;
+        }
+        while self.txs.len() >= MAX_ORPHAN_TXS {
+            let Some(victim) = self.txs.keys().choose(&mut rand::thread_rng()).cloned() else {
+                break;
+            };
+            info!("evict orphan transaction {}", victim);
+            self.remove(&victim);
+        }
+        for parent in &orphan.parents {
+            self.waiting
+                .entry(parent.clone())
+                .or_default()
+                .insert(txid.clone());
+        }
+        *self.per_peer.entry(orphan.peer.clone()).or_default() += 1;
+        self.txs.insert(txid, orphan);
+        true
+    }
+
+    /// Expire 丢弃在 now 之前 ORPHAN_TX_TIMEOUT 以上收到的孤立交易
+    fn expire(&mut self, now: Instant) {
+        let expired: Vec<Txid> = self
+            .txs
+            .iter()
+            .filter(|(_, orphan)| now.duration_since(orphan.received) >= ORPHAN_TX_TIMEOUT)
+            .map(|(txid, _)| txid.clone())
+            .collect();
+        for txid in expired {
+            info!("orphan transaction {} expired", txid);
+            self.remove(&txid);
+        }
+    }
+
+    /// TakeWaiting 取出等待 parent 的全部孤立交易
+    fn take_waiting(&mut self, parent: &str) -> Vec<OrphanTx> {
+        let txids = self.waiting.remove(parent).unwrap_or_default();
+        txids.iter().filter_map(|txid| self.remove(txid)).collect()
+    }
+
+    fn remove(&mut self, txid: &str) -> Option<OrphanTx> {
+        let orphan = self.txs.remove(txid)?;
+        for parent in &orphan.parents {
+            if let Some(waiting) = self.waiting.get_mut(parent) {
+                waiting.remove(txid);
+                if waiting.is_empty() {
+                    self.waiting.remove(parent);
+                }
+            }
+        }
+        if let Some(count) = self.per_peer.get_mut(&orphan.peer) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_peer.remove(&orphan.peer);
+            }
+        }
+        Some(orphan)
+    }
+}
+
+impl SharedMempool {
+    /// NewSharedMempool 共享 mempool
+    pub fn new(mempool: Mempool) -> SharedMempool {
+        SharedMempool(Arc::new(Mutex::new(mempool)))
+    }
+
+    /// Lock 获取交易池的锁
+    pub fn lock(&self) -> MutexGuard<'_, Mempool> {
+        self.0.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::network::Network;
+    use crate::wallets::{Wallet, Wallets};
+    use crate::walletstorage::memory::MemoryStorage;
+
+    /// 用 wallet 花费 prev 的第一个输出，前序交易可以还在交易池中
+    fn spend(wallet: &Wallet, prev: &Transaction, to: &str, value: u64) -> Transaction {
+        spend_all(wallet, &[prev], to, value)
+    }
+
+    /// 用 wallet 花费 prevs 中每笔交易的第一个输出
+    fn spend_all(wallet: &Wallet, prevs: &[&Transaction], to: &str, value: u64) -> Transaction {
+        let mut tx = Transaction {
+            version: TX_VERSION,
+            id: String::new(),
+            vin: prevs
+                .iter()
+                .map(|prev| TXInput {
+                    outpoint: OutPoint::new(&prev.id, 0),
+                    signature: Vec::new(),
+                    pub_key: wallet.public_key.clone(),
+                    condition: None,
+                    signatures: Vec::new(),
+                })
+                .collect(),
+            vout: vec![TXOutput::new(value, to.to_string(), Network::Mainnet).unwrap()],
+            memo: None,
+            lock_until: 0,
+        };
+        let prev_txs = prevs
+            .iter()
+            .map(|prev| (prev.id.clone(), (*prev).clone()))
+            .collect();
+        wallet
+            .sign_transaction(&mut tx, prev_txs, SigHashType::All)
+            .unwrap();
+        tx.id = tx.compute_id();
+        tx
+    }
+
+    #[test]
+    fn test_mempool() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let receiver = ws.create_wallet();
+        let wallet = ws.get_wallet(&miner).unwrap().clone();
+        let mut bc = Blockchain::in_memory();
+        let coinbases: Vec<Transaction> = (0..3)
+            .map(|n| {
+                Transaction::new_coinbase(
+                    miner.clone(),
+                    format!("output {}", n),
+                    0,
+                    0,
+                    Network::Mainnet,
+                )
+                .unwrap()
+            })
+            .collect();
+        let genesis = Block::new_unmined_block(coinbases.clone(), String::new(), 0).unwrap();
+        bc.add_block(genesis.clone()).unwrap();
+        let utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        let spend = |coinbase: &Transaction, amount| {
+            let input = [OutPoint::new(&coinbase.id, 0)];
+            Transaction::new_utxo_from_inputs(
+                &wallet,
+                &input,
+                &receiver,
+                amount,
+                &TxOptions::default(),
+                &utxo_set,
+            )
+            .unwrap()
+        };
+
+        let mut pool = Mempool::default();
+        let tx = spend(&coinbases[0], 3);
+        pool.add(tx.clone(), &utxo_set).unwrap();
+        assert_eq!(pool.get(&tx.id), Some(&tx));
+        assert_eq!(pool.fee(&tx.id), Some(10 - 3 - tx.vout[1].value));
+        assert_eq!(
+            pool.add(tx.clone(), &utxo_set).unwrap_err(),
+            MempoolError::AlreadyKnown {
+                txid: tx.id.clone()
+            }
+        );
+
+        // 第二笔花费同一输出、手续费没有提高的交易被拒绝
+        let conflict = spend(&coinbases[0], 4);
+        assert!(matches!(
+            pool.add(conflict.clone(), &utxo_set).unwrap_err(),
+            MempoolError::ReplacementFeeRateTooLow { txid, .. } if txid == conflict.id
+        ));
+        assert_eq!(pool.get(&tx.id), Some(&tx));
+        let mut forged = spend(&coinbases[1], 3);
+        forged.vin[0].signature[0] ^= 1;
+        assert!(matches!(
+            pool.add(forged, &utxo_set).unwrap_err(),
+            MempoolError::InvalidTransaction {
+                error: TxVerifyError::BadSignature { input: 0 },
+                ..
+            }
+        ));
+        let coinbase =
+            Transaction::new_coinbase(miner.clone(), String::new(), 1, 0, Network::Mainnet)
+                .unwrap();
+        assert!(matches!(
+            pool.add(coinbase, &utxo_set).unwrap_err(),
+            MempoolError::Coinbase { .. }
+        ));
+        let other = spend(&coinbases[1], 5);
+        pool.add(other.clone(), &utxo_set).unwrap();
+        assert_eq!(pool.len(), 2);
+
+        let mut sorted = vec![tx.clone(), other.clone()];
+        sorted.sort();
+        let (mut taken, fees) = pool.take_for_block(1, usize::MAX);
+        taken.sort();
+        assert_eq!(taken, sorted);
+        assert_eq!(
+            fees,
+            pool.fee(&tx.id).unwrap() + pool.fee(&other.id).unwrap()
+        );
+
+        // 挖出的交易和与区块冲突的交易离开交易池
+        let cbtx = Transaction::new_coinbase(miner.clone(), String::new(), 1, 0, Network::Mainnet)
+            .unwrap();
+        let replacement = spend(&coinbases[1], 6);
+        let block = Block::new_unmined_block(
+            vec![cbtx, tx.clone(), replacement.clone()],
+            genesis.get_hash(),
+            1,
+        )
+        .unwrap();
+        utxo_set.connect_block(&block).unwrap();
+        pool.remove_confirmed(&block);
+        assert!(pool.is_empty());
+        assert_eq!(pool.take_for_block(2, usize::MAX), (Vec::new(), 0));
+
+        // 重组后旧分支上的交易回到交易池，原有的交易重新验证后保留
+        let third = spend(&coinbases[2], 3);
+        pool.add(third.clone(), &utxo_set).unwrap();
+        let undo = utxo_set.get_block_undo(&block.get_hash()).unwrap();
+        utxo_set.disconnect_block(&block, &undo).unwrap();
+        pool.readd(std::slice::from_ref(&block), &utxo_set);
+        assert_eq!(pool.len(), 3);
+        for txid in [&tx.id, &replacement.id, &third.id] {
+            assert!(pool.get(txid).is_some());
+        }
+        assert!(pool.spent.contains_key(&OutPoint::new(&coinbases[2].id, 0)));
+    }
+
+    #[test]
+    fn test_take_for_block_limits() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let receiver = ws.create_wallet();
+        let wallet = ws.get_wallet(&miner).unwrap().clone();
+        let mut bc = Blockchain::in_memory();
+        let coinbases: Vec<Transaction> = (0..6)
+            .map(|n| {
+                Transaction::new_coinbase(
+                    miner.clone(),
+                    format!("output {}", n),
+                    0,
+                    0,
+                    Network::Mainnet,
+                )
+                .unwrap()
+            })
+            .collect();
+        let genesis = Block::new_unmined_block(coinbases.clone(), String::new(), 0).unwrap();
+        bc.add_block(genesis).unwrap();
+        let utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        let mut pool = Mempool::default();
+        // 手续费各不相同，按费率从高到低为 txs 的顺序
+        let txs: Vec<Transaction> = coinbases
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(n, cb)| {
+                let input = [OutPoint::new(&cb.id, 0)];
+                let options = TxOptions {
+                    fee: n as u64 + 1,
+                    ..TxOptions::default()
+                };
+                let tx = Transaction::new_utxo_from_inputs(
+                    &wallet, &input, &receiver, 2, &options, &utxo_set,
+                )
+                .unwrap();
+                pool.add(tx.clone(), &utxo_set).unwrap();
+                tx
+            })
+            .collect();
+
+        // 交易数上限不包括创币交易
+        assert_eq!(
+            pool.take_limited(1, usize::MAX, 3),
+            (txs[..3].to_vec(), 6 + 5 + 4)
+        );
+        assert_eq!(pool.take_limited(1, usize::MAX, 0), (Vec::new(), 0));
+        assert_eq!(pool.take_for_block(1, usize::MAX), (txs.clone(), 21));
+
+        // 恰好等于大小上限时仍可加入，再少一个字节时放不下第二笔交易
+        let coinbase =
+            Transaction::new_coinbase(miner.clone(), String::new(), 1, 1, Network::Mainnet)
+                .unwrap();
+        let limit = txs[0].size().unwrap() + txs[1].size().unwrap();
+        let (selected, fees) = pool.take_for_block(1, limit);
+        assert_eq!((selected.as_slice(), fees), (&txs[..2], 11));
+        let (smaller, _) = pool.take_for_block(1, limit - 1);
+        assert_eq!(smaller[0], txs[0]);
+        assert!(!smaller.contains(&txs[1]));
+
+        let mut block_txs = selected;
+        block_txs.insert(
+            0,
+            Transaction::new_coinbase(miner.clone(), String::new(), 1, 0, Network::Mainnet)
+                .unwrap(),
+        );
+        let template = utxo_set.block_template(block_txs).unwrap();
+        assert!(template.size().unwrap() <= Block::base_size(&coinbase).unwrap() + limit);
+    }
+
+    #[test]
+    fn test_take_for_block_fee_rate() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let receiver = ws.create_wallet();
+        let wallet = ws.get_wallet(&miner).unwrap().clone();
+        let mut bc = Blockchain::in_memory();
+        let coinbases: Vec<Transaction> = (0..3)
+            .map(|n| {
+                Transaction::new_coinbase(
+                    miner.clone(),
+                    format!("output {}", n),
+                    0,
+                    0,
+                    Network::Mainnet,
+                )
+                .unwrap()
+            })
+            .collect();
+        let genesis = Block::new_unmined_block(coinbases.clone(), String::new(), 0).unwrap();
+        bc.add_block(genesis.clone()).unwrap();
+        let utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        let spend = |prev: &Transaction, to: &str, value| spend(&wallet, prev, to, value);
+
+        // 手续费：parent 1，child 6，high 4，mid 2；child 花费 parent 的输出
+        let parent = spend(&coinbases[0], &miner, 9);
+        let child = spend(&parent, &receiver, 3);
+        let high = spend(&coinbases[1], &receiver, 6);
+        let mid = spend(&coinbases[2], &receiver, 8);
+        let mut pool = Mempool::default();
+        assert!(matches!(
+            pool.add(child.clone(), &utxo_set).unwrap_err(),
+            MempoolError::MissingInput { .. }
+        ));
+        for tx in [&parent, &child, &high, &mid] {
+            pool.add(tx.clone(), &utxo_set).unwrap();
+        }
+        assert_eq!(pool.fee(&child.id), Some(6));
+
+        // child 费率最高，带着 parent 排在最前面
+        let (txs, fees) = pool.take_for_block(1, usize::MAX);
+        let expected = vec![parent.clone(), child.clone(), high.clone(), mid.clone()];
+        assert_eq!((&txs, fees), (&expected, 13));
+        let block_txs = |txs: &[Transaction], fees| {
+            let coinbase =
+                Transaction::new_coinbase(miner.clone(), String::new(), 1, fees, Network::Mainnet);
+            let mut block_txs = vec![coinbase.unwrap()];
+            block_txs.extend_from_slice(txs);
+            block_txs
+        };
+        let template = utxo_set.block_template(block_txs(&txs, fees)).unwrap();
+        utxo_set
+            .blockchain
+            .validate_block(&template, &utxo_set)
+            .unwrap();
+        let reversed = vec![child.clone(), parent.clone()];
+        assert!(utxo_set.block_template(block_txs(&reversed, 7)).is_err());
+
+        // 只放得下两笔交易时，parent 和 child 一起的手续费高于按 txid 或按单笔手续费
+        // 选取、跳过前序交易未选中的交易等简单做法
+        let limit = parent.size().unwrap() + child.size().unwrap();
+        let (txs, fees) = pool.take_for_block(1, limit);
+        assert_eq!((txs, fees), (vec![parent.clone(), child.clone()], 7));
+        let greedy = |order: &[&Transaction]| {
+            let mut taken: Vec<&str> = Vec::new();
+            let (mut size, mut fees) = (0, 0);
+            for tx in order {
+                let orphan = tx.outpoints().any(|outpoint| {
+                    pool.get(&outpoint.txid).is_some() && !taken.contains(&outpoint.txid.as_str())
+                });
+                let tx_size = tx.size().unwrap();
+                if orphan || size + tx_size > limit {
+                    continue;
+                }
+                taken.push(&tx.id);
+                size += tx_size;
+                fees += pool.fee(&tx.id).unwrap();
+            }
+            fees
+        };
+        let mut by_txid = vec![&parent, &child, &high, &mid];
+        by_txid.sort();
+        let by_fee = [&child, &high, &mid, &parent];
+        assert!(greedy(&by_txid) <= 7);
+        assert!(greedy(&by_fee) < 7);
+
+        // 放不下 parent 和 child 时 child 不会单独进入区块
+        let (txs, fees) = pool.take_for_block(1, high.size().unwrap());
+        assert_eq!((txs, fees), (vec![high.clone()], 4));
+
+        // 与 parent 冲突的交易被挖出后，parent 和它的后代 child 都离开交易池
+        let conflict = spend(&coinbases[0], &receiver, 9);
+        let block = Block::new_unmined_block(
+            block_txs(std::slice::from_ref(&conflict), 1),
+            genesis.get_hash(),
+            1,
+        )
+        .unwrap();
+        utxo_set.connect_block(&block).unwrap();
+        pool.remove_confirmed(&block);
+        assert_eq!(pool.len(), 2);
+        assert!(pool.get(&parent.id).is_none() && pool.get(&child.id).is_none());
+    }
+
+    #[test]
+    fn test_orphan_transactions() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let receiver = ws.create_wallet();
+        let wallet = ws.get_wallet(&miner).unwrap().clone();
+        let mut bc = Blockchain::in_memory();
+        let coinbases: Vec<Transaction> = (0..2)
+            .map(|n| {
+                Transaction::new_coinbase(
+                    miner.clone(),
+                    format!("output {}", n),
+                    0,
+                    0,
+                    Network::Mainnet,
+                )
+                .unwrap()
+            })
+            .collect();
+        let genesis = Block::new_unmined_block(coinbases.clone(), String::new(), 0).unwrap();
+        bc.add_block(genesis.clone()).unwrap();
+        let mut utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        let spend = |prev: &Transaction, to: &str, value| spend(&wallet, prev, to, value);
+
+        // 后代先于前序交易到达，前序交易加入后依次加入
+        let parent = spend(&coinbases[0], &miner, 9);
+        let child = spend(&parent, &miner, 8);
+        let grandchild = spend(&child, &receiver, 7);
+        let mut pool = Mempool::default();
+        assert_eq!(
+            pool.accept(grandchild.clone(), "peer", &utxo_set),
+            Err(MempoolError::Orphan {
+                txid: grandchild.id.clone(),
+                parents: vec![child.id.clone()],
+            })
+        );
+        assert!(matches!(
+            pool.accept(child.clone(), "peer", &utxo_set),
+            Err(MempoolError::Orphan { .. })
+        ));
+        assert!(pool.knows(&child.id) && pool.is_empty());
+        assert_eq!(
+            pool.accept(parent.clone(), "other peer", &utxo_set)
+                .unwrap(),
+            vec![parent.id.clone(), child.id.clone(), grandchild.id.clone()]
+        );
+        assert_eq!(pool.len(), 3);
+        assert!(pool.orphans.txs.is_empty() && pool.orphans.per_peer.is_empty());
+
+        // 前序交易直接被区块确认时同样重试
+        let confirmed = spend(&coinbases[1], &miner, 9);
+        let waiting = spend(&confirmed, &receiver, 8);
+        assert!(pool.accept(waiting.clone(), "peer", &utxo_set).is_err());
+        let cbtx = Transaction::new_coinbase(miner.clone(), String::new(), 1, 1, Network::Mainnet)
+            .unwrap();
+        let block =
+            Block::new_unmined_block(vec![cbtx, confirmed.clone()], genesis.get_hash(), 1).unwrap();
+        utxo_set.blockchain.add_block(block.clone()).unwrap();
+        utxo_set.connect_block(&block).unwrap();
+        pool.remove_confirmed(&block);
+        assert_eq!(
+            pool.retry_orphans(vec![confirmed.id.clone()], &utxo_set),
+            vec![waiting.id.clone()]
+        );
+
+        // 每个节点的孤立交易数和总数都有上限，超时的孤立交易被丢弃
+        let fake = |n: usize| {
+            let mut tx = Transaction {
+                version: TX_VERSION,
+                id: String::new(),
+                vin: vec![TXInput {
+                    outpoint: OutPoint::new(&format!("{:064x}", n), 0),
+                    signature: Vec::new(),
+                    pub_key: wallet.public_key.clone(),
+                    condition: None,
+                    signatures: Vec::new(),
+                }],
+                vout: vec![TXOutput::new(5, receiver.clone(), Network::Mainnet).unwrap()],
+                memo: None,
+                lock_until: 0,
+            };
+            tx.id = tx.compute_id();
+            tx
+        };
+        let mut pool = Mempool::default();
+        for n in 0..MAX_ORPHAN_TXS_PER_PEER {
+            assert!(matches!(
+                pool.accept(fake(n), "flooder", &utxo_set),
+                Err(MempoolError::Orphan { .. })
+            ));
+        }
+        assert!(matches!(
+            pool.accept(fake(1000), "flooder", &utxo_set),
+            Err(MempoolError::MissingInput { .. })
+        ));
+        assert!(matches!(
+            pool.accept(fake(1000), "honest", &utxo_set),
+            Err(MempoolError::Orphan { .. })
+        ));
+        for n in 0..2 * MAX_ORPHAN_TXS {
+            let peer = format!(
+                "peer {}",
+                n % (2 * MAX_ORPHAN_TXS / MAX_ORPHAN_TXS_PER_PEER)
+            );
+            pool.accept(fake(2000 + n), &peer, &utxo_set).unwrap_err();
+        }
+        assert_eq!(pool.orphans.txs.len(), MAX_ORPHAN_TXS);
+        let total: usize = pool.orphans.per_peer.values().sum();
+        assert_eq!(total, MAX_ORPHAN_TXS);
+        pool.orphans.expire(Instant::now() + ORPHAN_TX_TIMEOUT);
+        assert!(pool.orphans.txs.is_empty());
+        assert!(pool.orphans.waiting.is_empty() && pool.orphans.per_peer.is_empty());
+    }
+
+    #[test]
+    fn test_save_load() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let receiver = ws.create_wallet();
+        let wallet = ws.get_wallet(&miner).unwrap().clone();
+        let mut bc = Blockchain::in_memory();
+        let coinbases: Vec<Transaction> = (0..2)
+            .map(|n| {
+                Transaction::new_coinbase(
+                    miner.clone(),
+                    format!("output {}", n),
+                    0,
+                    0,
+                    Network::Mainnet,
+                )
+                .unwrap()
+            })
+            .collect();
+        let genesis = Block::new_unmined_block(coinbases.clone(), String::new(), 0).unwrap();
+        bc.add_block(genesis.clone()).unwrap();
+        let utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        let spend = |prev: &Transaction, to: &str, value| spend(&wallet, prev, to, value);
+
+        let parent = spend(&coinbases[0], &miner, 9);
+        let child = spend(&parent, &receiver, 8);
+        let other = spend(&coinbases[1], &receiver, 9);
+        let mut pool = Mempool::default();
+        for (time, tx) in [&parent, &child, &other].into_iter().enumerate() {
+            pool.add_at(tx.clone(), &utxo_set, time as u64).unwrap();
+        }
+        pool.mark_wallet_tx(&child.id);
+        let path = std::env::temp_dir().join(format!("rustchain-mempool-{}", std::process::id()));
+        assert_eq!(pool.save(&path).unwrap(), 3);
+
+        // 重启前挖出与 other 冲突的交易，恢复时 other 被丢弃，child 仍排在 parent 之后
+        let conflict = spend(&coinbases[1], &miner, 9);
+        let cbtx = Transaction::new_coinbase(miner.clone(), String::new(), 1, 1, Network::Mainnet)
+            .unwrap();
+        let block = Block::new_unmined_block(vec![cbtx, conflict], genesis.get_hash(), 1).unwrap();
+        utxo_set.connect_block(&block).unwrap();
+        let mut restored = Mempool::default();
+        assert_eq!(restored.load(&path, &utxo_set).unwrap(), 2);
+        assert_eq!(restored.get(&parent.id), Some(&parent));
+        assert_eq!(restored.get(&child.id), Some(&child));
+        assert!(restored.get(&other.id).is_none());
+        // 加入的时间和本机钱包的交易一起恢复
+        let times: Vec<(u64, bool)> = restored
+            .entries_info()
+            .iter()
+            .map(|pending| (pending.time, pending.wallet))
+            .collect();
+        assert_eq!(times, vec![(0, false), (1, true)]);
+
+        // 版本 1 的文件只有交易
+        let txs = vec![parent.clone()];
+        let mut data = vec![1];
+        data.extend(serialize(&txs).unwrap());
+        std::fs::write(&path, data).unwrap();
+        let mut old = Mempool::default();
+        assert_eq!(old.load(&path, &utxo_set).unwrap(), 1);
+        assert!(old.entries_info()[0].time > 1 && old.wallet_txs().is_empty());
+
+        // 不认识的格式版本不读取
+        let mut data = std::fs::read(&path).unwrap();
+        data[0] = MEMPOOL_FILE_VERSION + 1;
+        std::fs::write(&path, data).unwrap();
+        assert!(Mempool::default().load(&path, &utxo_set).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Mempool::default().load(&path, &utxo_set).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_mempool_size_cap() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let receiver = ws.create_wallet();
+        let wallet = ws.get_wallet(&miner).unwrap().clone();
+        let mut bc = Blockchain::in_memory();
+        let coinbases: Vec<Transaction> = (0..6)
+            .map(|n| {
+                Transaction::new_coinbase(
+                    miner.clone(),
+                    format!("output {}", n),
+                    0,
+                    0,
+                    Network::Mainnet,
+                )
+                .unwrap()
+            })
+            .collect();
+        let genesis = Block::new_unmined_block(coinbases.clone(), String::new(), 0).unwrap();
+        bc.add_block(genesis).unwrap();
+        let utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        let spend = |prev: &Transaction, to: &str, value| spend(&wallet, prev, to, value);
+
+        // 交易的大小只因签名长度相差几个字节，手续费依次为 1、2、3
+        let a = spend(&coinbases[0], &receiver, 9);
+        let b = spend(&coinbases[1], &miner, 8);
+        let c = spend(&coinbases[2], &receiver, 7);
+        let max_size = a.size().unwrap() + b.size().unwrap() + c.size().unwrap() + 8;
+        let mut pool = Mempool::with_options(MempoolOptions {
+            max_size,
+            ..MempoolOptions::default()
+        });
+        for tx in [&a, &b, &c] {
+            pool.add(tx.clone(), &utxo_set).unwrap();
+        }
+        let info = pool.info();
+        assert_eq!((info.transactions, info.max_size), (3, max_size));
+        assert_eq!(info.size, max_size - 8);
+        assert_eq!(info.min_fee_rate, 0.0);
+
+        // 放不下时逐出费率最低的交易，被逐出的交易不再重新验证
+        let d = spend(&coinbases[3], &receiver, 6);
+        pool.add(d.clone(), &utxo_set).unwrap();
+        assert!(pool.get(&a.id).is_none());
+        assert_eq!(
+            pool.add(a.clone(), &utxo_set).unwrap_err(),
+            MempoolError::RecentlyRejected { txid: a.id.clone() }
+        );
+        let b_rate = pool.entries[&b.id].fee_rate();
+        assert_eq!(pool.info().min_fee_rate, b_rate.per_byte());
+
+        // 费率低于池中最低费率的新交易被拒绝，交易池不变
+        let e = spend(&coinbases[4], &receiver, 9);
+        assert!(matches!(
+            pool.add(e.clone(), &utxo_set).unwrap_err(),
+            MempoolError::FeeRateTooLow { min_fee_rate, .. } if min_fee_rate == b_rate
+        ));
+        assert!(pool.recently_rejected(&e.id));
+        assert_eq!(pool.len(), 3);
+
+        // 新交易的祖先不被逐出：b 的后代逐出 c 而不是费率更低的 b
+        let child = spend(&b, &receiver, 3);
+        pool.add(child.clone(), &utxo_set).unwrap();
+        assert!(pool.get(&b.id).is_some() && pool.get(&c.id).is_none());
+
+        // 逐出 b 时它的后代一起离开交易池
+        let f = spend(&coinbases[5], &receiver, 6);
+        pool.add(f.clone(), &utxo_set).unwrap();
+        let mut left: Vec<&str> = pool.entries.keys().map(String::as_str).collect();
+        left.sort();
+        let mut expected = vec![d.id.as_str(), f.id.as_str()];
+        expected.sort();
+        assert_eq!(left, expected);
+        assert!(pool.recently_rejected(&b.id) && pool.recently_rejected(&child.id));
+        assert_eq!(pool.info().size, d.size().unwrap() + f.size().unwrap());
+
+        let mut tiny = Mempool::with_options(MempoolOptions {
+            max_size: 10,
+            ..MempoolOptions::default()
+        });
+        assert!(matches!(
+            tiny.add(f, &utxo_set).unwrap_err(),
+            MempoolError::TooLarge { max_size: 10, .. }
+        ));
+    }
+
+    #[test]
+    fn test_entries_info() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let receiver = ws.create_wallet();
+        let wallet = ws.get_wallet(&miner).unwrap().clone();
+        let mut bc = Blockchain::in_memory();
+        let coinbase =
+            Transaction::new_coinbase(miner.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        let genesis = Block::new_unmined_block(vec![coinbase.clone()], String::new(), 0).unwrap();
+        bc.add_block(genesis).unwrap();
+        let utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+
+        let parent = spend(&wallet, &coinbase, &miner, 9);
+        let child = spend(&wallet, &parent, &receiver, 7);
+        let mut pool = Mempool::default();
+        pool.add_at(parent.clone(), &utxo_set, 100).unwrap();
+        pool.add_at(child.clone(), &utxo_set, 200).unwrap();
+
+        let entries = pool.entries_info();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].txid, parent.id);
+        assert!(entries[0].depends.is_empty());
+        assert_eq!(entries[1].depends, vec![parent.id.clone()]);
+        let size = child.size().unwrap();
+        assert_eq!(
+            pool.entry_info(&child.id),
+            Some(MempoolEntryInfo {
+                txid: child.id.clone(),
+                size,
+                fee: 2,
+                fee_rate: 2.0 / size as f64,
+                time: 200,
+                depends: vec![parent.id.clone()],
+                wallet: false,
+            })
+        );
+        assert!(pool.entry_info(&coinbase.id).is_none());
+    }
+
+    #[test]
+    fn test_expiry_and_wallet_txs() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let receiver = ws.create_wallet();
+        let wallet = ws.get_wallet(&miner).unwrap().clone();
+        let mut bc = Blockchain::in_memory();
+        let coinbases: Vec<Transaction> = (0..3)
+            .map(|n| {
+                Transaction::new_coinbase(
+                    miner.clone(),
+                    format!("output {}", n),
+                    0,
+                    0,
+                    Network::Mainnet,
+                )
+                .unwrap()
+            })
+            .collect();
+        let genesis = Block::new_unmined_block(coinbases.clone(), String::new(), 0).unwrap();
+        bc.add_block(genesis.clone()).unwrap();
+        let mut utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        let spend = |prev: &Transaction, to: &str, value| spend(&wallet, prev, to, value);
+
+        // 别人的交易在时刻 0 加入，它的后代和本机钱包的交易晚一些加入
+        let hour = 60 * 60;
+        let foreign = spend(&coinbases[0], &miner, 9);
+        let child = spend(&foreign, &receiver, 8);
+        let mine = spend(&coinbases[1], &receiver, 9);
+        let later = spend(&coinbases[2], &receiver, 9);
+        let mut pool = Mempool::with_options(MempoolOptions {
+            expiry: Duration::from_secs(72 * hour),
+            ..MempoolOptions::default()
+        });
+        pool.add_at(foreign.clone(), &utxo_set, 0).unwrap();
+        pool.add_at(child.clone(), &utxo_set, hour).unwrap();
+        pool.add_at(mine.clone(), &utxo_set, 0).unwrap();
+        pool.add_at(later.clone(), &utxo_set, 2 * hour).unwrap();
+        pool.mark_wallet_tx(&mine.id);
+        pool.mark_wallet_tx("unknown");
+        assert_eq!(pool.wallet_txs(), vec![mine.id.clone()]);
+
+        assert_eq!(pool.expire(72 * hour - 1), 0);
+        // 过期的交易连同后代删除，本机钱包的交易不过期
+        assert_eq!(pool.expire(72 * hour), 2);
+        let left: Vec<Txid> = pool.entries_info().into_iter().map(|p| p.txid).collect();
+        assert_eq!(left, vec![mine.id.clone(), later.id.clone()]);
+        assert_eq!(pool.expire(100 * hour), 1);
+        assert_eq!(pool.len(), 1);
+
+        // 确认之后不再重新广播
+        let cbtx = Transaction::new_coinbase(miner.clone(), String::new(), 1, 0, Network::Mainnet)
+            .unwrap();
+        let block = Block::new_unmined_block(vec![cbtx, mine], genesis.get_hash(), 1).unwrap();
+        utxo_set.blockchain.add_block(block.clone()).unwrap();
+        utxo_set.connect_block(&block).unwrap();
+        pool.remove_confirmed(&block);
+        assert!(pool.is_empty() && pool.wallet_txs().is_empty());
+    }
+
+    #[test]
+    fn test_replace_by_fee() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let receiver = ws.create_wallet();
+        let wallet = ws.get_wallet(&miner).unwrap().clone();
+        let mut bc = Blockchain::in_memory();
+        let coinbases: Vec<Transaction> = (0..3)
+            .map(|n| {
+                Transaction::new_coinbase(
+                    miner.clone(),
+                    format!("output {}", n),
+                    0,
+                    0,
+                    Network::Mainnet,
+                )
+                .unwrap()
+            })
+            .collect();
+        let genesis = Block::new_unmined_block(coinbases.clone(), String::new(), 0).unwrap();
+        bc.add_block(genesis).unwrap();
+        let utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        let (cb0, cb1, cb2) = (&coinbases[0], &coinbases[1], &coinbases[2]);
+
+        // 原交易和它的后代各付手续费 1
+        let mut pool = Mempool::default();
+        let original = spend_all(&wallet, &[cb0, cb1], &miner, 19);
+        let child = spend(&wallet, &original, &receiver, 18);
+        pool.add(original.clone(), &utxo_set).unwrap();
+        pool.add(child.clone(), &utxo_set).unwrap();
+
+        // 少花费原交易的一个输入时不能替换，交易池不变
+        let partial = spend_all(&wallet, &[cb0], &miner, 5);
+        assert_eq!(
+            pool.add(partial.clone(), &utxo_set).unwrap_err(),
+            MempoolError::ReplacementMissesInput {
+                txid: partial.id.clone(),
+                replaced: original.id.clone(),
+                outpoint: OutPoint::new(&cb1.id, 0),
+            }
+        );
+        assert_eq!(pool.len(), 2);
+
+        // 手续费须超过原交易及其后代的手续费之和
+        let cheap = spend_all(&wallet, &[cb0, cb1], &miner, 18);
+        assert_eq!(
+            pool.add(cheap.clone(), &utxo_set).unwrap_err(),
+            MempoolError::ReplacementFeeTooLow {
+                txid: cheap.id.clone(),
+                fee: 2,
+                required: 3,
+            }
+        );
+        assert_eq!(pool.len(), 2);
+
+        // 花费原交易全部输入且多付手续费的交易替换原交易，原交易的后代一起移除
+        let bump = spend_all(&wallet, &[cb0, cb1, cb2], &miner, 26);
+        pool.add(bump.clone(), &utxo_set).unwrap();
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.fee(&bump.id), Some(4));
+        assert_eq!(pool.info().size, bump.size().unwrap());
+        for outpoint in bump.outpoints() {
+            assert_eq!(pool.spent.get(outpoint), Some(&bump.id));
+        }
+        assert!(!pool.spent.contains_key(&OutPoint::new(&original.id, 0)));
+        assert_eq!(pool.take_for_block(1, usize::MAX), (vec![bump.clone()], 4));
+
+        // 被替换的交易不能反过来替换费率更高的交易
+        assert!(matches!(
+            pool.add(original, &utxo_set).unwrap_err(),
+            MempoolError::ReplacementMissesInput { .. }
+        ));
+        let back = spend_all(&wallet, &[cb0, cb1, cb2], &miner, 27);
+        assert!(matches!(
+            pool.add(back, &utxo_set).unwrap_err(),
+            MempoolError::ReplacementFeeRateTooLow { .. }
+        ));
+    }
+}
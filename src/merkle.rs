@@ -0,0 +1,181 @@
+//! merkle tree over transaction ids
+
+use super::*;
+use bincode::{DefaultOptions, Options, serialize};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+/// MerkleProof 证明一笔交易包含在区块中，只需要区块头中的默克尔根即可验证
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub block_hash: String,
+    pub txid: String,
+    /// 交易在区块中的位置，每一位决定对应层的兄弟节点在左还是在右
+    pub index: u32,
+    /// 从叶子到根每一层的兄弟节点
+    pub siblings: Vec<[u8; 32]>,
+}
+
+/// MerkleRoot 计算交易 id 的默克尔根，返回十六进制字符串
+///
+/// 叶子为 SHA-256(0x00 || txid)，内部节点为 SHA-256(0x01 || 左 || 右)。
+/// 某一层节点数为奇数时复制最后一个节点与自身配对，因此 [a, b, c] 与 [a, b, c, c]
+/// 的默克尔根相同，区块中的交易 id 不能重复。没有交易时默克尔根全为 0
+pub fn merkle_root(txids: &[&str]) -> String {
+    let mut level: Vec<[u8; 32]> = txids.iter().map(|txid| leaf_hash(txid)).collect();
+    if level.is_empty() {
+        return hex::encode([0u8; 32]);
+    }
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    hex::encode(level[0])
+}
+
+impl MerkleProof {
+    /// New 为 txids 中第 index 笔交易生成包含证明
+    pub fn new(block_hash: &str, txids: &[&str], index: usize) -> Result<MerkleProof> {
+        let txid = txids
+            .get(index)
+            .ok_or_else(|| format_err!("Transaction index {} is out of range", index))?;
+        let mut level: Vec<[u8; 32]> = txids.iter().map(|txid| leaf_hash(txid)).collect();
+        let mut position = index;
+        let mut siblings = Vec::new();
+        while level.len() > 1 {
+            // 奇数层的最后一个节点与自身配对
+            let sibling = (position ^ 1).min(level.len() - 1);
+            siblings.push(level[sibling]);
+            level = next_level(&level);
+            position /= 2;
+        }
+        Ok(MerkleProof {
+            block_hash: block_hash.to_string(),
+            txid: txid.to_string(),
+            index: index as u32,
+            siblings,
+        })
+    }
+
+    /// Verify 检查证明能否从 txid 推出十六进制的默克尔根 root
+    pub fn verify(&self, root: &str, txid: &str) -> bool {
+        let mut node = leaf_hash(txid);
+        let mut position = self.index;
+        for sibling in &self.siblings {
+            node = if position & 1 == 0 {
+                node_hash(&node, sibling)
+            } else {
+                node_hash(sibling, &node)
+            };
+            position >>= 1;
+        }
+        // 位置超出证明的深度时，多出的高位没有被任何一层使用
+        position == 0 && hex::encode(node) == root
+    }
+
+    /// ToHex 将证明编码为十六进制字符串
+    pub fn to_hex(&self) -> Result<String> {
+        Ok(hex::encode(serialize(self)?))
+    }
+
+    /// FromHex 解析 ToHex 生成的十六进制字符串，拒绝非法字符、截断数据和多余字节
+    pub fn from_hex(raw: &str) -> Result<MerkleProof> {
+        let bytes = hex::decode(raw.trim()).map_err(|e| format_err!("Invalid proof hex: {}", e))?;
+        DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes()
+            .deserialize(&bytes)
+            .map_err(|e| format_err!("Invalid merkle proof: {}", e))
+    }
+}
+
+fn leaf_hash(txid: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.input(&[0]);
+    hasher.input(txid.as_bytes());
+    let mut hash = [0; 32];
+    hasher.result(&mut hash);
+    hash
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.input(&[1]);
+    hasher.input(left);
+    hasher.input(right);
+    let mut hash = [0; 32];
+    hasher.result(&mut hash);
+    hash
+}
+
+/// 两两合并一层节点，节点数为奇数时最后一个节点与自身合并
+fn next_level(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level
+        .chunks(2)
+        .map(|pair| node_hash(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_merkle_root() {
+        let [a, b, c, d] = ["a", "b", "c", "d"].map(leaf_hash);
+        let root = |txids: &[&str]| merkle_root(txids);
+        assert_eq!(root(&["a"]), hex::encode(a));
+        assert_eq!(root(&["a", "b"]), hex::encode(node_hash(&a, &b)));
+        // 三笔交易时 c 与自身配对
+        let cc = node_hash(&c, &c);
+        assert_eq!(
+            root(&["a", "b", "c"]),
+            hex::encode(node_hash(&node_hash(&a, &b), &cc))
+        );
+        assert_eq!(root(&["a", "b", "c"]), root(&["a", "b", "c", "c"]));
+        assert_eq!(
+            root(&["a", "b", "c", "d"]),
+            hex::encode(node_hash(&node_hash(&a, &b), &node_hash(&c, &d)))
+        );
+        assert_ne!(root(&["a", "b"]), root(&["b", "a"]));
+        assert_eq!(root(&[]), hex::encode([0u8; 32]));
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        let txids = ["a", "b", "c", "d", "e"];
+        for count in 1..=txids.len() {
+            let txids = &txids[..count];
+            let root = merkle_root(txids);
+            for (index, txid) in txids.iter().enumerate() {
+                let proof = MerkleProof::new("block", txids, index).unwrap();
+                assert_eq!(proof.txid, *txid);
+                assert!(proof.verify(&root, txid), "{} of {}", index, count);
+                assert!(!proof.verify(&root, "x"));
+                assert!(!proof.verify(&merkle_root(&["x"]), txid));
+
+                let decoded = MerkleProof::from_hex(&proof.to_hex().unwrap()).unwrap();
+                assert_eq!(decoded, proof);
+            }
+        }
+        assert!(MerkleProof::new("block", &txids, 5).is_err());
+
+        // 改动位置或兄弟节点后证明无效
+        let root = merkle_root(&txids);
+        let proof = MerkleProof::new("block", &txids, 2).unwrap();
+        let mut moved = proof.clone();
+        moved.index = 3;
+        assert!(!moved.verify(&root, "c"));
+        moved.index = 2 + (1 << proof.siblings.len());
+        assert!(!moved.verify(&root, "c"));
+        let mut tampered = proof.clone();
+        tampered.siblings[0][0] ^= 1;
+        assert!(!tampered.verify(&root, "c"));
+
+        let hex = proof.to_hex().unwrap();
+        assert!(MerkleProof::from_hex(&hex[..hex.len() - 2]).is_err());
+        assert!(MerkleProof::from_hex(&format!("{}00", hex)).is_err());
+        assert!(MerkleProof::from_hex("zz").is_err());
+    }
+}
@@ -0,0 +1,285 @@
+//! network parameters
+
+use super::*;
+use crate::block::{INITIAL_BITS, REGTEST_BITS};
+use bitcoincash_addr::HashType;
+use failure::format_err;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+/// 命令行未指定 --network 时从该环境变量读取网络
+pub const NETWORK_ENV: &str = "RUSTCHAIN_NETWORK";
+
+/// 数据库被其他进程或线程锁定时的重试次数和间隔
+const DB_LOCK_RETRIES: u32 = 100;
+const DB_LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(20);
+
+/// 主网和测试网的检查点，每个网络还没有公认的主链，暂时为空
+const MAINNET_CHECKPOINTS: &[(i32, &str)] = &[];
+const TESTNET_CHECKPOINTS: &[(i32, &str)] = &[];
+
+/// Network 区块链网络，各网络的地址前缀、创世区块和数据目录互不相同
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+impl Network {
+    /// DataPath 返回数据目录 dir 中该网络下名为 name 的数据库路径，主网的数据库直接放在数据目录下
+    pub fn data_path(self, dir: &Path, name: &str) -> PathBuf {
+        match self {
+            Network::Mainnet => dir.join(name),
+            _ => dir.join(self.to_string()).join(name),
+        }
+    }
+
+    /// OpenDbAt 打开 path 处的数据库，数据库正被其他句柄锁定时稍后重试
+    ///
+    /// UTXO 集合和钱包数据库只在读写时短暂打开，同时运行的命令只需等待对方关闭
+    pub fn open_db_at(path: &Path) -> Result<sled::Db> {
+        let mut retries = 0;
+        loop {
+            match sled::open(path) {
+                Err(sled::Error::Io(_)) if retries < DB_LOCK_RETRIES && db_locked(path) => {
+                    retries += 1;
+                    thread::sleep(DB_LOCK_RETRY_INTERVAL);
+                }
+                db => return Ok(db?),
+            }
+        }
+    }
+
+    /// Base58 地址的网络和类型，决定地址的版本字节
+    ///
+    /// 主网为 0x05，测试网为 0xc4，回归测试网为 0x6f
+    pub fn base58_params(self) -> (bitcoincash_addr::Network, HashType) {
+        match self {
+            Network::Mainnet => (bitcoincash_addr::Network::Main, HashType::Script),
+            Network::Testnet => (bitcoincash_addr::Network::Test, HashType::Script),
+            Network::Regtest => (bitcoincash_addr::Network::Test, HashType::Key),
+        }
+    }
+
+    /// FromBase58Params 由解码出的地址网络和类型确定所属网络
+    pub fn from_base58_params(
+        network: &bitcoincash_addr::Network,
+        hash_type: &HashType,
+    ) -> Option<Network> {
+        Network::all().into_iter().find(|n| {
+            let (net, ht) = n.base58_params();
+            net == *network && ht == *hash_type
+        })
+    }
+
+    /// WifVersion 返回导出私钥时使用的版本字节
+    pub fn wif_version(self) -> u8 {
+        match self {
+            Network::Mainnet => 0x80,
+            Network::Testnet | Network::Regtest => 0xef,
+        }
+    }
+
+    /// Bech32Hrp 返回 bech32 地址的前缀
+    pub fn bech32_hrp(self) -> &'static str {
+        match self {
+            Network::Mainnet => "rtc",
+            Network::Testnet => "trtc",
+            Network::Regtest => "rtcrt",
+        }
+    }
+
+    /// GenesisCoinbaseData 返回创世区块创币交易携带的数据，使各网络的创世区块不同
+    pub fn genesis_coinbase_data(self) -> &'static str {
+        match self {
+            Network::Mainnet => {
+                "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks"
+            }
+            Network::Testnet => "Rustchain testnet genesis block",
+            Network::Regtest => "Rustchain regtest genesis block",
+        }
+    }
+
+    /// InitialBits 返回创世区块的难度，回归测试网的目标值几乎接受任意 nonce
+    pub fn initial_bits(self) -> u32 {
+        match self {
+            Network::Mainnet | Network::Testnet => INITIAL_BITS,
+            Network::Regtest => REGTEST_BITS,
+        }
+    }
+
+    /// Retargets 是否按出块时间调整难度，回归测试网的难度固定不变
+    pub fn retargets(self) -> bool {
+        self != Network::Regtest
+    }
+
+    /// DefaultPort 返回节点默认监听的端口，也是新节点连接的已知节点的端口
+    pub fn default_port(self) -> &'static str {
+        match self {
+            Network::Mainnet => "3000",
+            Network::Testnet => "13000",
+            Network::Regtest => "23000",
+        }
+    }
+
+    /// Magic 返回节点握手时交换的网络标识，标识不同的节点不属于同一网络
+    pub fn magic(self) -> [u8; 4] {
+        match self {
+            Network::Mainnet => [0xd9, 0x52, 0x43, 0x01],
+            Network::Testnet => [0xd9, 0x52, 0x43, 0x02],
+            Network::Regtest => [0xd9, 0x52, 0x43, 0x03],
+        }
+    }
+
+    /// Checkpoints 返回编入程序的检查点：(高度, 该高度上主链区块的哈希)，按高度排列
+    ///
+    /// 重组不能断开检查点上的区块；回归测试网随时重新开始，没有检查点
+    pub fn checkpoints(self) -> &'static [(i32, &'static str)] {
+        match self {
+            Network::Mainnet => MAINNET_CHECKPOINTS,
+            Network::Testnet => TESTNET_CHECKPOINTS,
+            Network::Regtest => &[],
+        }
+    }
+
+    /// CoinbaseHeightActivation 返回创币交易必须记录区块高度的起始高度
+    ///
+    /// 旧版本的节点挖出的创币交易不记录高度，主网和测试网在此之前的区块仍然有效；
+    /// 回归测试网随时重新开始，从创世区块起就要求记录
+    pub fn coinbase_height_activation(self) -> i32 {
+        match self {
+            Network::Mainnet | Network::Testnet => 10_000,
+            Network::Regtest => 0,
+        }
+    }
+
+    /// BitsActivation 返回区块头必须记录难度的起始高度
+    ///
+    /// 旧版本的节点挖出的区块头不记录难度，主网和测试网在此之前的区块仍然有效；
+    /// 回归测试网从创世区块起就要求记录
+    pub fn bits_activation(self) -> i32 {
+        match self {
+            Network::Mainnet | Network::Testnet => 10_000,
+            Network::Regtest => 0,
+        }
+    }
+
+    /// All 返回全部网络
+    pub fn all() -> [Network; 3] {
+        [Network::Mainnet, Network::Testnet, Network::Regtest]
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Regtest => "regtest",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Network {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "mainnet" | "main" => Ok(Network::Mainnet),
+            "testnet" | "test" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format_err!("Unknown network: {}", s)),
+        }
+    }
+}
+
+/// path 处的数据库正被其他句柄锁定时返回 true
+///
+/// sled 打开数据库时对目录中的 db 文件加排他锁，加锁失败的错误不区分原因，
+/// 这里对同一文件试加锁，按 io::ErrorKind::WouldBlock 判断
+fn db_locked(path: &Path) -> bool {
+    let Ok(file) = File::open(path.join("db")) else {
+        return false;
+    };
+    // 加锁成功时文件关闭后锁随之释放
+    file.try_lock()
+        .map_err(io::Error::from)
+        .is_err_and(|err| err.kind() == io::ErrorKind::WouldBlock)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_open_locked_db() {
+        let path = std::env::temp_dir().join(format!("rustchain-lock-{}", std::process::id()));
+        std::fs::remove_dir_all(&path).ok();
+        assert!(!db_locked(&path));
+        let db = Network::open_db_at(&path).unwrap();
+        assert!(db_locked(&path));
+        // 另一个句柄关闭数据库之前等待
+        let holder = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            drop(db);
+        });
+        Network::open_db_at(&path).unwrap();
+        holder.join().unwrap();
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_network_params() {
+        for network in Network::all() {
+            assert_eq!(network.to_string().parse::<Network>().unwrap(), network);
+            let (net, hash_type) = network.base58_params();
+            assert_eq!(Network::from_base58_params(&net, &hash_type), Some(network));
+        }
+        assert!("moonnet".parse::<Network>().is_err());
+        assert_eq!(
+            Network::from_base58_params(&bitcoincash_addr::Network::Main, &HashType::Key),
+            None
+        );
+
+        assert_eq!(
+            Network::Mainnet.data_path(Path::new("data"), "blocks"),
+            PathBuf::from("data/blocks")
+        );
+        assert_eq!(
+            Network::Testnet.data_path(Path::new("data"), "blocks"),
+            PathBuf::from("data/testnet/blocks")
+        );
+        assert_ne!(
+            Network::Mainnet.genesis_coinbase_data(),
+            Network::Testnet.genesis_coinbase_data()
+        );
+        assert_eq!(
+            Network::Regtest.data_path(Path::new("data"), "blocks"),
+            PathBuf::from("data/regtest/blocks")
+        );
+        assert_eq!(Network::Mainnet.initial_bits(), INITIAL_BITS);
+        assert!(Network::Mainnet.retargets() && !Network::Regtest.retargets());
+        let ports: HashSet<_> = Network::all().map(Network::default_port).into();
+        assert_eq!(ports.len(), 3);
+        let magics: HashSet<_> = Network::all().map(Network::magic).into();
+        assert_eq!(magics.len(), 3);
+        for network in Network::all() {
+            let checkpoints = network.checkpoints();
+            assert!(checkpoints.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        }
+        assert!(Network::Regtest.checkpoints().is_empty());
+        assert_eq!(Network::Regtest.coinbase_height_activation(), 0);
+        assert!(Network::Mainnet.coinbase_height_activation() > 0);
+        assert_eq!(Network::Regtest.bits_activation(), 0);
+        assert!(Network::Mainnet.bits_activation() > 0);
+    }
+}
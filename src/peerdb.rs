@@ -0,0 +1,295 @@
+//! database of known node addresses
+
+use bincode::{deserialize, serialize};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// PEERS_FILE 网络数据目录中保存地址库的文件
+pub const PEERS_FILE: &str = "peers.dat";
+/// 地址库文件的格式版本，写在文件的第一个字节
+const PEERS_FILE_VERSION: u8 = 1;
+/// 地址库最多记录的地址数，已满时替换最久没有活跃的地址
+const MAX_ADDRESSES: usize = 1000;
+/// 地址库中最多有这么多地址来自同一个节点，一个节点不能占满整个地址库
+const MAX_ADDRESSES_PER_SOURCE: usize = MAX_ADDRESSES / 10;
+/// 每个节点在 ADDR_RATE_PERIOD 秒内最多让地址库记录这么多新地址
+const MAX_NEW_ADDRESSES_PER_PERIOD: usize = 50;
+const ADDR_RATE_PERIOD: u64 = 10 * 60;
+/// 连续连接失败这么多次的地址被忘记
+const MAX_FAILURES: u32 = 3;
+
+/// PeerAddr addr 消息中的一个节点地址和它最后活跃的时间，见 mempool::unix_time
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerAddr {
+    pub addr: String,
+    pub last_seen: u64,
+}
+
+/// 地址库中的一个地址
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct AddrEntry {
+    last_seen: u64,
+    /// 最近连续连接失败的次数
+    failures: u32,
+    /// 告诉本节点这个地址的节点，本节点连接过的地址为空
+    source: String,
+}
+
+/// PeerDb 本节点知道的节点地址，连接管理从中选择要连接的节点
+#[derive(Default)]
+pub struct PeerDb {
+    addrs: HashMap<String, AddrEntry>,
+    /// 来源节点 -> (当前周期开始的时间, 当前周期内记录的新地址数)
+    learned: HashMap<String, (u64, usize)>,
+}
+
+impl PeerDb {
+    /// Len 地址库中的地址数
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    /// Contains 地址库中是否有 addr
+    #[cfg(test)]
+    pub fn contains(&self, addr: &str) -> bool {
+        self.addrs.contains_key(addr)
+    }
+
+    /// Add 记录节点 source 告诉本节点的地址，返回是否为新地址
+    ///
+    /// 已有的地址只更新最后活跃的时间，晚于 now 的时间按 now 计。新地址受来源节点的速率和
+    /// 数量限制；地址库已满时替换最久没有活跃的地址，新地址更旧时不记录
+    pub fn add(&mut self, peer: PeerAddr, source: &str, now: u64) -> bool {
+        let last_seen = peer.last_seen.min(now);
+        if let Some(entry) = self.addrs.get_mut(&peer.addr) {
+            entry.last_seen = entry.last_seen.max(last_seen);
+            return false;
+        }
+
+        let (start, count) = self.learned.entry(source.to_string()).or_insert((now, 0));
+        if now.saturating_sub(*start) >= ADDR_RATE_PERIOD {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= MAX_NEW_ADDRESSES_PER_PERIOD {
+            return false;
+        }
+        let from_source = self.addrs.values().filter(|e| e.source == source).count();
+        if from_source >= MAX_ADDRESSES_PER_SOURCE {
+            return false;
+        }
+        if self.addrs.len() >= MAX_ADDRESSES {
+            let Some((oldest, entry)) = self
+                .addrs
+                .iter()
+                .min_by(|a, b| a.1.last_seen.cmp(&b.1.last_seen).then(a.0.cmp(b.0)))
+            else {
+                return false;
+            };
+            if entry.last_seen >= last_seen {
+                return false;
+            }
+            let oldest = oldest.clone();
+            self.addrs.remove(&oldest);
+        }
+        *count += 1;
+        self.addrs.insert(
+            peer.addr,
+            AddrEntry {
+                last_seen,
+                failures: 0,
+                source: source.to_string(),
+            },
+        );
+        true
+    }
+
+    /// Seen 记录与 addr 的节点成功通信，更新最后活跃的时间并清除失败次数
+    pub fn seen(&mut self, addr: &str, now: u64) {
+        let entry = self.addrs.entry(addr.to_string()).or_insert(AddrEntry {
+            last_seen: now,
+            failures: 0,
+            source: String::new(),
+        });
+        entry.last_seen = now;
+        entry.failures = 0;
+        // 连接过的地址不再计入来源节点的数量限制
+        entry.source.clear();
+    }
+
+    /// Failed 记录连接 addr 失败，连续失败 MAX_FAILURES 次时忘记这个地址
+    pub fn failed(&mut self, addr: &str) {
+        let Some(entry) = self.addrs.get_mut(addr) else {
+            return;
+        };
+        entry.failures += 1;
+        if entry.failures >= MAX_FAILURES {
+            self.addrs.remove(addr);
+        }
+    }
+
+    /// Candidates 返回至多 n 个满足 filter 的地址，最近活跃的在前
+    pub fn candidates(&self, n: usize, filter: impl Fn(&str) -> bool) -> Vec<String> {
+        let mut addrs: Vec<(&String, &AddrEntry)> =
+            self.addrs.iter().filter(|(addr, _)| filter(addr)).collect();
+        addrs.sort_by(|a, b| b.1.last_seen.cmp(&a.1.last_seen).then(a.0.cmp(b.0)));
+        addrs
+            .into_iter()
+            .take(n)
+            .map(|(addr, _)| addr.clone())
+            .collect()
+    }
+
+    /// Addresses 返回至多 max 个地址和它们最后活跃的时间，最近活跃的在前，用于回复 getaddr
+    pub fn addresses(&self, max: usize) -> Vec<PeerAddr> {
+        self.candidates(max, |_| true)
+            .into_iter()
+            .map(|addr| PeerAddr {
+                last_seen: self.addrs[&addr].last_seen,
+                addr,
+            })
+            .collect()
+    }
+
+    /// Save 把地址库写入 path，返回写入的地址数
+    ///
+    /// 文件的第一个字节是格式版本，之后是地址列表。先写入临时文件再改名，
+    /// 写到一半中断时保留上一次保存的文件
+    pub fn save(&self, path: &Path) -> crate::errors::Result<usize> {
+        let mut addrs: Vec<(&String, &AddrEntry)> = self.addrs.iter().collect();
+        addrs.sort_by(|a, b| a.0.cmp(b.0));
+        let mut data = vec![PEERS_FILE_VERSION];
+        data.extend(serialize(&addrs)?);
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(addrs.len())
+    }
+
+    /// Load 读取 save 写出的文件，返回读取的地址数；文件不存在时返回 0
+    pub fn load(&mut self, path: &Path) -> crate::errors::Result<usize> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        let addrs: Vec<(String, AddrEntry)> = match data.first() {
+            Some(&PEERS_FILE_VERSION) => deserialize(&data[1..])
+                .map_err(|e| format_err!("Peers file {} is corrupt: {}", path.display(), e))?,
+            Some(version) => {
+                return Err(format_err!(
+                    "Peers file {} has unknown version {}",
+                    path.display(),
+                    version
+                ));
+            }
+            None => return Err(format_err!("Peers file {} is empty", path.display())),
+        };
+        let count = addrs.len();
+        self.addrs.extend(addrs);
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn peer(n: usize, last_seen: u64) -> PeerAddr {
+        PeerAddr {
+            addr: format!("node{}:3000", n),
+            last_seen,
+        }
+    }
+
+    #[test]
+    fn test_add_and_candidates() {
+        let mut db = PeerDb::default();
+        let now = 1_000_000;
+        assert!(db.add(peer(1, now - 100), "source", now));
+        assert!(db.add(peer(2, now - 10), "source", now));
+        // 晚于现在的时间按现在计
+        assert!(db.add(peer(3, now + 1000), "source", now));
+        assert!(!db.add(peer(1, now - 50), "other", now));
+        assert_eq!(db.len(), 3);
+        assert_eq!(
+            db.candidates(10, |addr| addr != "node2:3000"),
+            vec!["node3:3000", "node1:3000"]
+        );
+        assert_eq!(db.addresses(2), vec![peer(3, now), peer(2, now - 10)]);
+        assert_eq!(db.addresses(3)[2], peer(1, now - 50));
+
+        // 连续失败的地址被忘记，成功通信后重新计数
+        db.failed("node1:3000");
+        db.failed("node1:3000");
+        db.seen("node1:3000", now);
+        db.failed("node1:3000");
+        db.failed("node1:3000");
+        assert!(db.contains("node1:3000"));
+        db.failed("node1:3000");
+        assert!(!db.contains("node1:3000"));
+    }
+
+    #[test]
+    fn test_rate_limit_per_source() {
+        let mut db = PeerDb::default();
+        let now = 1_000_000;
+        let learned = (0..MAX_NEW_ADDRESSES_PER_PERIOD * 2)
+            .filter(|n| db.add(peer(*n, now), "spammer", now))
+            .count();
+        assert_eq!(learned, MAX_NEW_ADDRESSES_PER_PERIOD);
+        assert!(db.add(peer(1000, now), "honest", now));
+
+        // 下一个周期继续接受，但一个节点给出的地址不超过 MAX_ADDRESSES_PER_SOURCE
+        let mut time = now;
+        for _ in 0..MAX_ADDRESSES_PER_SOURCE / MAX_NEW_ADDRESSES_PER_PERIOD + 1 {
+            time += ADDR_RATE_PERIOD;
+            for n in 0..MAX_NEW_ADDRESSES_PER_PERIOD {
+                db.add(peer(time as usize + n, time), "spammer", time);
+            }
+        }
+        assert_eq!(db.len(), MAX_ADDRESSES_PER_SOURCE + 1);
+        assert!(db.contains("node1000:3000"));
+    }
+
+    #[test]
+    fn test_replace_oldest_when_full() {
+        let mut db = PeerDb::default();
+        for n in 0..MAX_ADDRESSES {
+            db.seen(&peer(n, 0).addr, 100 + n as u64);
+        }
+        assert!(!db.add(peer(MAX_ADDRESSES, 50), "source", 10_000));
+        assert!(db.add(peer(MAX_ADDRESSES, 5_000), "source", 10_000));
+        assert_eq!(db.len(), MAX_ADDRESSES);
+        assert!(!db.contains("node0:3000"));
+        assert!(db.contains("node1:3000"));
+    }
+
+    #[test]
+    fn test_save_load() {
+        let mut db = PeerDb::default();
+        db.seen("node1:3000", 100);
+        db.add(peer(2, 50), "node1:3000", 100);
+        db.failed("node1:3000");
+        let path = std::env::temp_dir().join(format!("rustchain-peers-{}", std::process::id()));
+        assert_eq!(db.save(&path).unwrap(), 2);
+
+        let mut loaded = PeerDb::default();
+        assert_eq!(loaded.load(&path).unwrap(), 2);
+        assert_eq!(loaded.addresses(10), db.addresses(10));
+        // 失败次数也被保存
+        loaded.failed("node1:3000");
+        loaded.failed("node1:3000");
+        assert!(!loaded.contains("node1:3000"));
+
+        let mut data = std::fs::read(&path).unwrap();
+        data[0] = PEERS_FILE_VERSION + 1;
+        std::fs::write(&path, data).unwrap();
+        assert!(PeerDb::default().load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(PeerDb::default().load(&path).unwrap(), 0);
+    }
+}
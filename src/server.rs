@@ -1,29 +1,77 @@
 //! server
 
 use super::*;
+use crate::banlist::{BanEntry, BanList, BanScores, Misbehavior};
 use crate::block::*;
+use crate::blockfetch::{BlockFetcher, Progress, ReadyBlock};
+use crate::blockchain::BlockValidationError;
+use crate::mempool::{
+    Mempool, MempoolEntryInfo, MempoolError, MempoolInfo, MempoolOptions, SharedMempool, unix_time,
+};
 use crate::transaction::*;
+use crate::network::Network;
+use crate::peerdb::{PeerAddr, PeerDb};
 use crate::utxoset::*;
-use bincode::{deserialize, serialize};
+use crate::base58;
+use bincode::{DefaultOptions, Options, serialize};
 use failure::format_err;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::*;
-use std::thread;
-use std::time::Duration;
-use log::{debug, info};
+use std::time::{Duration, Instant};
+use bytes::{Buf, BytesMut};
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, error, info};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{self, AbortHandle, JoinSet};
+use tokio::{runtime, time};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tokio_util::sync::CancellationToken;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 enum Message {
-    Addr(Vec<String>),
+    Addr(Addrmsg),
+    GetAddr(GetAddrmsg),
     Version(Versionmsg),
+    Ping(Pingmsg),
     Tx(Txmsg),
     GetData(GetDatamsg),
+    NotFound(NotFoundmsg),
     GetBlock(GetBlocksmsg),
     Inv(Invmsg),
     Block(Blockmsg),
+    GetHeaders(GetHeadersmsg),
+    Headers(Headersmsg),
+    MempoolInfo,
+    MempoolTx(String),
+    WalletTx(Transaction),
+    RawMempool,
+    MempoolEntry(String),
+    PeerInfo,
+    BlockCount,
+    SetBan(IpAddr, bool),
+    ListBanned,
+    ClearBanned,
+    Stop,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Addrmsg {
+    addr_from: String,
+    addrs: Vec<PeerAddr>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GetAddrmsg {
+    addr_from: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -37,6 +85,19 @@ struct GetBlocksmsg {
     addr_from: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GetHeadersmsg {
+    addr_from: String,
+    /// 见 Blockchain::header_locator
+    locator: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Headersmsg {
+    addr_from: String,
+    headers: Vec<BlockHeader>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct GetDatamsg {
     addr_from: String,
@@ -44,6 +105,14 @@ struct GetDatamsg {
     id: String,
 }
 
+/// NotFoundmsg 回复 getdata：请求的区块或交易不在本节点，例如交易已被挖出或从交易池中删除
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct NotFoundmsg {
+    addr_from: String,
+    kind: String,
+    id: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Invmsg {
     addr_from: String,
@@ -62,89 +131,580 @@ struct Versionmsg {
     addr_from: String,
     version: i32,
     best_height: i32,
+    /// 程序名和版本，见 user_agent
+    user_agent: String,
+    /// 发送方所在网络的标识，见 Network::magic
+    magic: [u8; 4],
+    /// 发送方启动时随机选取，收到与自己相同的值说明连接到了自己
+    nonce: u64,
+}
+
+/// Pingmsg 检查对方是否仍在响应，对方在同一连接上回复带有相同 nonce 的 pong
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Pingmsg {
+    addr_from: String,
+    nonce: u64,
+}
+
+/// PeerInfo 与本节点完成握手的节点
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerInfo {
+    pub addr: String,
+    /// 双方协议版本中较低的一个
+    pub version: i32,
+    pub user_agent: String,
+    /// 对方握手时的高度
+    pub best_height: i32,
+    /// 从对方收到完整内容的交易数和区块数
+    pub txs_received: usize,
+    pub blocks_received: usize,
+    /// 最近一次 ping 的往返时间，毫秒；还没有收到过 pong 时为 None
+    pub ping_ms: Option<u64>,
+    /// 距离最近一次收到对方消息的秒数
+    pub idle_secs: u64,
+    /// 对方的不当行为分数，达到 ConnectionOptions::bans 的 ban_score 时被封禁
+    pub ban_score: u32,
 }
 
+#[derive(Clone)]
 pub struct Server {
+    /// 节点所在的网络，决定消息帧的网络标识和默认的已知节点
+    network: Network,
     node_address: String,
     mining_address: String,
+    utxo: SharedUTXOSet,
+    mempool: SharedMempool,
+    /// 保存交易池的文件，为 None 时交易池不保存
+    mempool_file: Option<PathBuf>,
+    /// 保存地址库的文件，为 None 时地址库不保存
+    peers_file: Option<PathBuf>,
+    /// 保存封禁列表的文件，为 None 时封禁列表不保存
+    banlist_file: Option<PathBuf>,
+    options: ConnectionOptions,
+    /// 本节点的 version 消息携带的随机数
+    nonce: u64,
     inner: Arc<Mutex<ServerInner>>,
+    /// 收到 stop 消息时取消，start_server 停止接受连接并中止全部任务
+    shutdown: CancellationToken,
 }
 
 struct ServerInner {
     known_nodes: HashSet<String>,
-    utxo: UTXOSet,
     blocks_in_transit: Vec<String>,
-    mempool: HashMap<String, Transaction>,
+    /// 正在进行的挖矿的中止标志，最新区块改变时置位并换成新的标志
+    mining_abort: Arc<AtomicBool>,
+    orphans: OrphanPool,
+    /// 正在握手或已完成握手的节点
+    peers: HashMap<String, Peer>,
+    /// 本节点知道的节点地址，向外的连接不足时从中选择节点连接
+    addrs: PeerDb,
+    /// 已经请求但还没有收到的区块和交易：哈希 -> (请求的节点, 请求的时间)
+    requested: HashMap<String, (String, Instant)>,
+    bans: BanList,
+    /// 不当行为分数：连接另一端的 IP 地址 -> 分数，最多 MAX_MISBEHAVING_ADDRS 个，见 Server::misbehaving
+    misbehavior: HashMap<IpAddr, u32>,
+    /// 正在处理的接受的连接数
+    open_connections: usize,
+    /// 按区块头同步时要下载的区块
+    downloads: BlockFetcher,
+    /// 正在挖矿时为 true，挖矿线程的结果交回处理任务后清除
+    mining: bool,
+    /// 每个节点的任务读取的发送队列和中止任务的句柄，见 Server::enqueue
+    outboxes: HashMap<String, (mpsc::Sender<Outbound>, AbortHandle)>,
+    /// 处理任务的队列，节点没有运行时为 None
+    events: Option<mpsc::Sender<Event>>,
+    /// 节点运行所在的 tokio 运行时，没有运行时为 None
+    runtime: Option<runtime::Handle>,
+}
+
+/// ConnectionOptions 节点的连接数限制、只与指定节点通信的模式、封禁节点的分数，以及回复区块前的等待
+#[derive(Clone, Debug)]
+pub struct ConnectionOptions {
+    /// 最多接受这么多个由对方发起握手的节点，超出时不回复 verack
+    pub max_inbound: usize,
+    /// 连接管理希望保持的向外连接数
+    pub max_outbound: usize,
+    /// 最多同时处理这么多个接受的连接，超出时接受后立即关闭
+    pub max_connections: usize,
+    /// 不为空时只连接这些节点、只接受它们的握手，不从地址库中选择节点
+    pub connect: Vec<String>,
+    /// 不当行为的分数和封禁节点的分数，见 Server::misbehaving
+    pub bans: BanScores,
+    /// 发送对方请求的区块之前等待这么久，测试时模拟慢速的连接
+    pub block_delay: Duration,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        ConnectionOptions {
+            max_inbound: DEFAULT_MAX_INBOUND,
+            max_outbound: DEFAULT_MAX_OUTBOUND,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            connect: Vec::new(),
+            bans: BanScores::default(),
+            block_delay: Duration::ZERO,
+        }
+    }
+}
+
+/// Peer 与一个节点的握手状态，双方都收到对方的 version 并回复 verack 后握手完成
+///
+/// 每条消息使用单独的连接，verack 在 version 的连接上回复
+#[derive(Default)]
+struct Peer {
+    /// 接受的对方的 version 消息
+    version: Option<Versionmsg>,
+    /// 已向对方发送本节点的 version 消息
+    version_sent: bool,
+    /// 对方已回复本节点的 version 消息
+    verack_received: bool,
+    /// 是否由本节点发起连接
+    outbound: bool,
+    /// 对方已经有的区块和交易，不再向它通告
+    known: KnownInventory,
+    txs_received: usize,
+    blocks_received: usize,
+    /// 最近一次收到对方消息的时间
+    last_message: Option<Instant>,
+    /// 最近一次 ping 的往返时间
+    ping: Option<Duration>,
+    /// 连续没有回复的 ping 数
+    missed_pings: u32,
+    /// 对方的 version 消息到达的连接另一端的 IP 地址，按它记不当行为分数
+    ip: Option<IpAddr>,
+    /// 已接受对方的 version、还没有收到本节点 version 的 verack 时对方发来的消息和
+    /// 它们到达的连接，握手完成后依次处理，最多 MAX_WAITING_MESSAGES 条
+    waiting: Vec<(Message, SocketAddr, Reply)>,
+}
+
+/// KnownInventory 一个节点通告过、发来过或本节点通告给它的区块和交易的哈希
+#[derive(Default)]
+struct KnownInventory {
+    ids: HashSet<String>,
+    /// 按记住的顺序排列的哈希
+    order: VecDeque<String>,
+}
+
+/// OrphanPool 父区块尚未收到的区块，父区块连接后再依次连接
+#[derive(Default)]
+struct OrphanPool {
+    /// 区块哈希 -> (区块, 字节数, 收到的时间)
+    blocks: HashMap<String, (Block, usize, Instant)>,
+    /// 父区块哈希 -> 等待它的区块哈希
+    children: HashMap<String, Vec<String>>,
+    /// 按收到的顺序排列的区块哈希
+    order: VecDeque<String>,
+    size: usize,
 }
 
-const KNOWN_NODE1: &str = "localhost:3000";
 const CMD_LEN: usize = 12;
-const VERSION: i32 = 1;
+const MAGIC_LEN: usize = 4;
+const CHECKSUM_LEN: usize = 4;
+/// 消息帧头：网络标识、以零补齐的命令、小端序的内容字节数和内容的校验和
+const HEADER_LEN: usize = MAGIC_LEN + CMD_LEN + 4 + CHECKSUM_LEN;
+/// 消息内容的最大字节数，即最大区块加上发送方地址；帧头声明更长的内容时不读取
+const MAX_PAYLOAD_SIZE: usize = MAX_BLOCK_SIZE + 1024;
+/// 连接上超过这么长时间没有收到数据时关闭连接
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+/// 向节点发送消息时连接和写入的时限，停止响应的节点不会让它的任务一直等待
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+/// 每隔这么长时间向握手完成的节点发送 ping
+const PING_INTERVAL: Duration = Duration::from_secs(2 * 60);
+/// 超过这么长时间没有收到 pong 时记为一次没有回复
+const PING_TIMEOUT: Duration = Duration::from_secs(20);
+/// 连续这么多次没有回复 ping 的节点被断开
+const MAX_MISSED_PINGS: u32 = 2;
+/// 本节点的协议版本，握手时双方使用较低的版本；从版本 4 开始 addr 消息带有地址最后活跃的时间
+const PROTOCOL_VERSION: i32 = 4;
+/// 协议版本低于这个版本的节点不支持握手或不能解析 addr 消息，连接后即断开
+const MIN_PROTOCOL_VERSION: i32 = 4;
+/// 等待对方回复 verack 的最长时间，握手未完成的节点的消息最多等待这么久
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// 从这个版本开始节点支持 getheaders，同步时先下载区块头
+const HEADERS_VERSION: i32 = 2;
+/// 一条 headers 消息最多包含的区块头数，收到这么多时继续请求
+const MAX_HEADERS: usize = 2000;
+/// 孤块池最多保存的区块数
+const MAX_ORPHANS: usize = 100;
+/// 孤块池中区块的总字节数上限
+const MAX_ORPHAN_BYTES: usize = 10_000_000;
+/// 父区块在这段时间内没有到达的孤块被丢弃
+const ORPHAN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// 每隔这么长时间保存一次交易池，节点被强行停止时最多丢失这段时间内收到的交易
+const MEMPOOL_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// 每隔这么长时间删除交易池中过期的交易
+const MEMPOOL_EXPIRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// 每隔这么长时间向已知节点重新广播本机钱包尚未确认的交易，新节点连接时也向它广播
+const WALLET_REBROADCAST_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// 默认最多接受的由对方发起握手的节点数
+pub const DEFAULT_MAX_INBOUND: usize = 32;
+/// 连接管理默认希望保持的向外连接数
+pub const DEFAULT_MAX_OUTBOUND: usize = 8;
+/// 默认最多同时处理的接受的连接数，每条消息使用单独的连接，这个数比节点数大
+pub const DEFAULT_MAX_CONNECTIONS: usize = 125;
+/// 每隔这么长时间检查向外的连接数，不足时从地址库中选择节点连接
+const CONNECT_INTERVAL: Duration = Duration::from_secs(10);
+/// 每隔这么长时间保存一次地址库
+const PEERS_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// 一条 addr 消息最多包含的地址数，超出的部分被忽略
+const MAX_ADDR_PER_MSG: usize = 1000;
+/// 每个节点最多记住它已经有的这么多个区块和交易，超出时忘记最早记住的
+const MAX_KNOWN_INVENTORY: usize = 10_000;
+/// 请求的区块或交易在这段时间内没有到达时，其他节点通告它时再次请求
+const GETDATA_TIMEOUT: Duration = Duration::from_secs(60);
+/// 本机钱包的交易在孤立交易池中记在这个来源下
+const WALLET_PEER: &str = "wallet";
+/// 检查下载超时、给节点补足请求中的区块的间隔
+const BLOCK_DOWNLOAD_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+/// 下载区块期间记录进度的间隔
+const DOWNLOAD_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+/// 交给处理任务、还没有开始处理的消息帧和工作最多这么多，已满时连接任务暂停读取
+const MESSAGE_QUEUE_SIZE: usize = 256;
+/// 处理一帧时还没有写出的回复最多这么多，已满时回复失败，处理任务不等待连接
+const REPLY_QUEUE_SIZE: usize = 16;
+/// 交给一个节点的任务、还没有发出的消息最多这么多，已满时说明对方太慢，断开它
+const PEER_QUEUE_SIZE: usize = 256;
+/// 每个节点等待握手完成的消息最多这么多，超出的消息被忽略
+const MAX_WAITING_MESSAGES: usize = 64;
+/// 停止时等待正在处理的连接和消息的最长时间
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// 最多记录这么多个地址的不当行为分数，已满时忘掉分数最低的地址
+const MAX_MISBEHAVING_ADDRS: usize = 1000;
 
 impl Server {
     pub fn new(port: &str, miner_address: &str, utxo: UTXOSet) -> Result<Server> {
+        let network = utxo.blockchain.network;
         let mut node_set = HashSet::new();
-        node_set.insert(String::from(KNOWN_NODE1));
+        node_set.insert(known_node(network));
         Ok(Server {
+            network,
             node_address: String::from("localhost:") + port,
             mining_address: miner_address.to_string(),
+            utxo: SharedUTXOSet::new(utxo)?,
+            mempool: SharedMempool::default(),
+            mempool_file: None,
+            peers_file: None,
+            banlist_file: None,
+            options: ConnectionOptions::default(),
+            nonce: rand::random(),
+            shutdown: CancellationToken::new(),
             inner: Arc::new(Mutex::new(ServerInner {
                 known_nodes: node_set,
-                utxo,
                 blocks_in_transit: Vec::new(),
-                mempool: HashMap::new(),
+                mining_abort: Arc::new(AtomicBool::new(false)),
+                orphans: OrphanPool::default(),
+                peers: HashMap::new(),
+                addrs: PeerDb::default(),
+                requested: HashMap::new(),
+                bans: BanList::default(),
+                misbehavior: HashMap::new(),
+                open_connections: 0,
+                downloads: BlockFetcher::default(),
+                mining: false,
+                outboxes: HashMap::new(),
+                events: None,
+                runtime: None,
             })),
         })
     }
 
-    pub fn start_server(&self) -> Result<()> {
-        let server1 = Server {
-            node_address: self.node_address.clone(),
-            mining_address: self.mining_address.clone(),
-            inner: Arc::clone(&self.inner),
+    /// SetMempoolOptions 换成按 options 创建的空交易池，须在 persist_mempool 之前调用
+    pub fn set_mempool_options(&mut self, options: MempoolOptions) {
+        self.mempool = SharedMempool::new(Mempool::with_options(options));
+    }
+
+    /// PersistMempool 从 path 恢复上次保存的交易池，之后定期以及收到 stop 消息时保存到 path
+    ///
+    /// 文件无法读取时记录错误，以空的交易池启动
+    pub fn persist_mempool(&mut self, path: PathBuf) {
+        let loaded = {
+            let utxo = self.utxo.read();
+            self.mempool.lock().load(&path, &utxo)
         };
+        match loaded {
+            Ok(count) => info!("load {} transactions from {}", count, path.display()),
+            Err(err) => error!("cannot load the mempool: {}", err),
+        }
+        self.mempool_file = Some(path);
+    }
+
+    /// PersistPeers 从 path 恢复上次保存的地址库，之后定期以及收到 stop 消息时保存到 path
+    ///
+    /// 文件无法读取时记录错误，以空的地址库启动
+    pub fn persist_peers(&mut self, path: PathBuf) {
+        match self.inner.lock().unwrap().addrs.load(&path) {
+            Ok(count) => info!("load {} addresses from {}", count, path.display()),
+            Err(err) => error!("cannot load the peer addresses: {}", err),
+        }
+        self.peers_file = Some(path);
+    }
+
+    /// PersistBans 从 path 恢复封禁列表，之后每次封禁或解除封禁时保存到 path
+    ///
+    /// 文件无法读取时记录错误，以空的封禁列表启动
+    pub fn persist_bans(&mut self, path: PathBuf) {
+        match self.inner.lock().unwrap().bans.load(&path) {
+            Ok(count) => info!("load {} bans from {}", count, path.display()),
+            Err(err) => error!("cannot load the ban list: {}", err),
+        }
+        self.banlist_file = Some(path);
+    }
+
+    /// ListenOn 在 host 上监听，host 也是本节点在消息中告诉其他节点的地址，默认为 localhost
+    pub fn listen_on(&mut self, host: &str) {
+        let port = self
+            .node_address
+            .rsplit_once(':')
+            .map_or("", |(_, port)| port);
+        self.node_address = format!("{}:{}", host, port);
+    }
+
+    /// SetConnectionOptions 设置连接数限制；给出了要连接的节点时不再连接默认的已知节点
+    pub fn set_connection_options(&mut self, options: ConnectionOptions) {
+        if !options.connect.is_empty() {
+            self.inner
+                .lock()
+                .unwrap()
+                .known_nodes
+                .remove(&known_node(self.network));
+        }
+        self.options = options;
+    }
+
+    /// StartServer 监听本节点的地址并处理连接，收到 stop 消息后保存状态并返回
+    ///
+    /// 在内部启动 tokio 运行时，直到节点停止才返回。地址已被占用等无法监听的情况返回错误，
+    /// 此时还没有启动任何任务
+    pub fn start_server(&self) -> Result<()> {
+        let listener = TcpListener::bind(&self.node_address).map_err(|err| {
+            if err.kind() == std::io::ErrorKind::AddrInUse {
+                format_err!(
+                    "Cannot listen on {}: the address is already in use, is another node running on this port?",
+                    self.node_address
+                )
+            } else {
+                format_err!("Cannot listen on {}: {}", self.node_address, err)
+            }
+        })?;
+        listener.set_nonblocking(true)?;
         info!(
             "Start server at {}, minning address: {}",
             &self.node_address, &self.mining_address
         );
 
-        thread::spawn(move || {
-            thread::sleep(Duration::from_millis(1000));
-            if server1.get_best_height()? == -1 {
-                server1.request_blocks()
-            } else {
-                server1.send_version(KNOWN_NODE1)
-            }
-        });
+        let runtime = runtime::Builder::new_multi_thread().enable_all().build()?;
+        let result = runtime.block_on(self.clone().run(listener));
+        // 任务已经中止，仍在处理队列的处理任务和挖矿线程最多再等待 SHUTDOWN_TIMEOUT
+        runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+        result?;
+        // 停止期间处理完的消息的修改也要写入磁盘
+        self.save_state()
+    }
 
-        let listener = TcpListener::bind(&self.node_address).unwrap();
-        info!("Server listen...");
+    /// 接受连接并启动处理任务和后台任务，直到 shutdown 被取消
+    ///
+    /// 停止时不再接受连接，正在处理的连接最多再等待 SHUTDOWN_TIMEOUT，之后中止全部任务；
+    /// 处理任务处理完队列中已有的消息和工作后结束
+    async fn run(self, listener: TcpListener) -> Result<()> {
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        let (events, queue) = mpsc::channel(MESSAGE_QUEUE_SIZE);
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.events = Some(events.clone());
+            inner.runtime = Some(runtime::Handle::current());
+        }
+        let handler = {
+            let server = self.clone();
+            task::spawn_blocking(move || server.handle_events(queue))
+        };
+        let mut tasks = JoinSet::new();
+        self.spawn_background_tasks(&mut tasks, &events);
 
-        for stream in listener.incoming() {
-            let stream = stream?;
-            let server1 = Server {
-                node_address: self.node_address.clone(),
-                mining_address: self.mining_address.clone(),
-                inner: Arc::clone(&self.inner),
+        info!("Server listen...");
+        let mut connections = JoinSet::new();
+        loop {
+            let (stream, peer) = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        // 例如文件描述符用尽，稍等之后继续接受
+                        error!("cannot accept a connection: {}", err);
+                        time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                },
             };
-            thread::spawn(move || server1.handle_connection(stream));
+            // 回环地址上的连接可能是本机的请求，由 handle_frame 按消息检查封禁
+            if !peer.ip().is_loopback() && self.is_banned(peer.ip()) {
+                info!("close a connection from {}: the address is banned", peer);
+                continue;
+            }
+            if !self.open_connection() {
+                info!(
+                    "close a connection from {}: {} connections open",
+                    peer, self.options.max_connections
+                );
+                continue;
+            }
+            connections.spawn(self.clone().serve_connection(stream, peer, events.clone()));
         }
 
+        info!("Server stopping...");
+        drop(listener);
+        let finished = async { while connections.join_next().await.is_some() {} };
+        if time::timeout(SHUTDOWN_TIMEOUT, finished).await.is_err() {
+            info!("abort {} connections", connections.len());
+        }
+        connections.shutdown().await;
+        tasks.shutdown().await;
+        // 不再向其他节点发送消息，处理任务的队列随最后一个发送方关闭
+        {
+            let mut inner = self.inner.lock().unwrap();
+            for (_, (_, task)) in inner.outboxes.drain() {
+                task.abort();
+            }
+            inner.events = None;
+            inner.runtime = None;
+        }
+        self.abort_mining();
+        drop(events);
+        if time::timeout(SHUTDOWN_TIMEOUT, handler).await.is_err() {
+            info!("the message handler is still running");
+        }
         Ok(())
     }
 
-    pub fn send_transaction(tx: &Transaction, utxoset: UTXOSet) -> Result<()> {
-        let server = Server::new("7000", "", utxoset)?;
-        server.send_tx(KNOWN_NODE1, tx)?;
-        Ok(())
+    /// 定期连接节点、ping 节点、检查区块下载、删除过期的交易以及保存交易池和地址库的任务
+    ///
+    /// ping 在阻塞线程中等待各节点的 pong，其他工作交给处理任务运行
+    fn spawn_background_tasks(&self, tasks: &mut JoinSet<()>, events: &mpsc::Sender<Event>) {
+        let bootstrap = events.clone();
+        let connect = events.clone();
+        tasks.spawn(async move {
+            time::sleep(Duration::from_millis(1000)).await;
+            if bootstrap
+                .send(Event::Job(Box::new(Server::bootstrap)))
+                .await
+                .is_err()
+            {
+                return;
+            }
+            handle_every(connect, CONNECT_INTERVAL, |server| {
+                if let Err(err) = server.connect_to_peers() {
+                    error!("cannot connect to peers: {}", err);
+                }
+            })
+            .await
+        });
+
+        let server = self.clone();
+        tasks.spawn(every(PING_INTERVAL, move || {
+            server.ping_peers(PING_TIMEOUT)
+        }));
+
+        let mut progress = Instant::now();
+        tasks.spawn(handle_every(
+            events.clone(),
+            BLOCK_DOWNLOAD_CHECK_INTERVAL,
+            move |server| {
+                server.check_block_download();
+                if progress.elapsed() >= DOWNLOAD_PROGRESS_INTERVAL {
+                    server.log_download_progress();
+                    progress = Instant::now();
+                }
+            },
+        ));
+
+        let mut rebroadcast = Instant::now();
+        tasks.spawn(handle_every(
+            events.clone(),
+            MEMPOOL_EXPIRY_INTERVAL,
+            move |server| {
+                let expired = server.mempool.lock().expire(unix_time());
+                if expired > 0 {
+                    info!("{} transactions expired from the mempool", expired);
+                }
+                if rebroadcast.elapsed() >= WALLET_REBROADCAST_INTERVAL {
+                    rebroadcast = Instant::now();
+                    if let Err(err) = server.rebroadcast_wallet_txs(None) {
+                        error!("cannot rebroadcast wallet transactions: {}", err);
+                    }
+                }
+            },
+        ));
+
+        if let Some(path) = self.mempool_file.clone() {
+            tasks.spawn(handle_every(
+                events.clone(),
+                MEMPOOL_SAVE_INTERVAL,
+                move |server| {
+                    if let Err(err) = server.mempool.lock().save(&path) {
+                        error!("cannot save the mempool: {}", err);
+                    }
+                },
+            ));
+        }
+
+        if let Some(path) = self.peers_file.clone() {
+            tasks.spawn(handle_every(
+                events.clone(),
+                PEERS_SAVE_INTERVAL,
+                move |server| {
+                    if let Err(err) = server.inner.lock().unwrap().addrs.save(&path) {
+                        error!("cannot save the peer addresses: {}", err);
+                    }
+                },
+            ));
+        }
     }
 
-    /* ------------------- inner halp functions ----------------------------------*/
+    /// 启动后连接默认的已知节点，给出了要连接的节点时连接它们
+    fn bootstrap(&self) {
+        let bootstrap = if self.options.connect.is_empty() {
+            vec![known_node(self.network)]
+        } else {
+            self.options.connect.clone()
+        };
+        for addr in bootstrap {
+            if let Err(err) = self.connect(&addr) {
+                error!("cannot connect to {}: {}", addr, err);
+            }
+        }
+    }
+
+    /// HandleEvents 中心的消息处理任务：按到达的顺序逐个处理连接任务交来的消息帧和其他任务交来的工作，
+    /// 队列的全部发送方关闭后返回
+    ///
+    /// 只有这个任务修改区块链、UTXO集合和交易池。处理时不等待网络：发给其他节点的消息交给节点的任务，
+    /// 工作量证明在挖矿线程中进行，握手和挖矿的结果作为工作交回这里
+    fn handle_events(&self, mut events: mpsc::Receiver<Event>) {
+        while let Some(event) = events.blocking_recv() {
+            match event {
+                Event::Frame(Request {
+                    frame,
+                    peer,
+                    mut reply,
+                }) => {
+                    let cmd = frame.cmd.clone();
+                    if let Err(err) = self.handle_frame(frame, peer, &mut reply) {
+                        error!("cannot handle a {} message: {}", cmd, err);
+                        reply.close();
+                    }
+                }
+                Event::Job(job) => job(self),
+            }
+        }
+    }
 
-    fn remove_node(&self, addr: &str) {
-        self.inner.lock().unwrap().known_nodes.remove(addr);
+    /// 处理任务的队列，节点没有运行时为 None
+    fn events(&self) -> Option<mpsc::Sender<Event>> {
+        self.inner.lock().unwrap().events.clone()
     }
 
+    /* ------------------- inner halp functions ----------------------------------*/
+
     fn add_nodes(&self, addr: &str) {
         self.inner
             .lock()
@@ -157,417 +717,3827 @@ impl Server {
         self.inner.lock().unwrap().known_nodes.clone()
     }
 
-    fn node_is_known(&self, addr: &str) -> bool {
-        self.inner.lock().unwrap().known_nodes.get(addr).is_some()
+    /// 与 addr 的连接失败或被拒绝时断开与它的握手，不再把它当作已知节点，并在地址库中记录一次失败；
+    /// 向它请求中的区块改由其他节点下载，它的任务中还没有发出的消息随之丢弃
+    fn forget_peer(&self, addr: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.peers.remove(addr);
+        inner.downloads.release(addr);
+        inner.known_nodes.remove(addr);
+        inner.addrs.failed(addr);
+        if let Some((_, task)) = inner.outboxes.remove(addr) {
+            task.abort();
+        }
     }
 
-    fn replace_in_transit(&self, hashs: Vec<String>) {
-        let bit = &mut self.inner.lock().unwrap().blocks_in_transit;
-        bit.clone_from(&hashs);
+    /// 为刚接受的连接计数，已有 max_connections 个连接时返回 false
+    fn open_connection(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.open_connections >= self.options.max_connections {
+            return false;
+        }
+        inner.open_connections += 1;
+        true
     }
 
-    fn get_in_transit(&self) -> Vec<String> {
-        self.inner.lock().unwrap().blocks_in_transit.clone()
+    /// 记录刚收到 addr 的节点的消息
+    fn peer_active(&self, addr: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.addrs.seen(addr, unix_time());
+        if let Some(peer) = inner.peers.get_mut(addr) {
+            peer.last_message = Some(Instant::now());
+        }
     }
 
-    fn get_mempool_tx(&self, addr: &str) -> Option<Transaction> {
-        match self.inner.lock().unwrap().mempool.get(addr) {
-            Some(tx) => Some(tx.clone()),
-            None => None,
+    /// 给连接另一端的 IP 地址 addr 记一次不当行为，分数达到 ban_score 时封禁它并断开它上面的节点
+    ///
+    /// 消息中的发送方地址是对方自己声明的，分数按发来消息的连接的地址记；回环地址不记分数
+    fn misbehaving(&self, addr: IpAddr, offense: Misbehavior, reason: &dyn fmt::Display) {
+        if addr.is_loopback() {
+            info!("misbehavior of {}: {}: {}", addr, offense.name(), reason);
+            return;
         }
+        {
+            let mut inner = self.inner.lock().unwrap();
+            // 分数高的地址不会被大量只记了一两次的新地址挤掉
+            if !inner.misbehavior.contains_key(&addr)
+                && inner.misbehavior.len() >= MAX_MISBEHAVING_ADDRS
+                && let Some(lowest) = inner
+                    .misbehavior
+                    .iter()
+                    .min_by_key(|(_, score)| **score)
+                    .map(|(addr, _)| *addr)
+            {
+                inner.misbehavior.remove(&lowest);
+            }
+            let score = inner.misbehavior.entry(addr).or_default();
+            *score = score.saturating_add(self.options.bans.score(offense));
+            info!(
+                "misbehavior of {}: {}: {}, score {}",
+                addr,
+                offense.name(),
+                reason,
+                score
+            );
+            if *score < self.options.bans.ban_score {
+                return;
+            }
+        }
+        error!(
+            "disconnect and ban {}: misbehavior score reached {}",
+            addr, self.options.bans.ban_score
+        );
+        self.ban(addr);
+        self.save_bans();
     }
 
-    fn get_mempool(&self) -> HashMap<String, Transaction> {
-        self.inner.lock().unwrap().mempool.clone()
+    /// 封禁 addr，断开与它上面的节点的握手；回环地址上是本机的钱包和命令行，不会被封禁
+    fn ban(&self, addr: IpAddr) {
+        if addr.is_loopback() {
+            return;
+        }
+        let peers: Vec<String> = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.bans.ban(addr, unix_time());
+            inner.misbehavior.remove(&addr);
+            inner
+                .peers
+                .iter()
+                .filter(|(_, peer)| peer.ip == Some(addr))
+                .map(|(peer, _)| peer.clone())
+                .collect()
+        };
+        for peer in peers {
+            self.forget_peer(&peer);
+        }
     }
 
-    fn insert_mempool(&self, tx: Transaction) {
-        self.inner.lock().unwrap().mempool.insert(tx.id.clone(), tx);
+    /// 封禁列表改变后立即保存，重启后封禁仍然有效
+    fn save_bans(&self) {
+        if let Some(path) = &self.banlist_file
+            && let Err(err) = self.inner.lock().unwrap().bans.save(path)
+        {
+            error!("cannot save the ban list: {}", err);
+        }
     }
 
-    fn clear_mempool(&self) {
-        self.inner.lock().unwrap().mempool.clear()
+    fn is_banned(&self, addr: IpAddr) -> bool {
+        self.inner.lock().unwrap().bans.is_banned(addr, unix_time())
     }
 
-    fn get_best_height(&self) -> Result<i32> {
-        self.inner.lock().unwrap().utxo.blockchain.get_best_height()
+    /// 节点地址 addr 解析出的某个 IP 地址被封禁时返回 true
+    fn is_banned_node(&self, addr: &str) -> bool {
+        addr.to_socket_addrs()
+            .is_ok_and(|mut addrs| addrs.any(|addr| self.is_banned(addr.ip())))
     }
 
-    fn get_block_hashs(&self) -> Vec<String> {
-        self.inner.lock().unwrap().utxo.blockchain.get_block_hashs()
+    fn peer_info(&self, addr: &str) -> Option<PeerInfo> {
+        let inner = self.inner.lock().unwrap();
+        inner.peers.get(addr)?.info(addr, &inner.misbehavior)
     }
 
-    fn get_block(&self, block_hash: &str) -> Result<Block> {
-        self.inner
-            .lock()
-            .unwrap()
-            .utxo
-            .blockchain
-            .get_block(block_hash)
+    fn peers_info(&self) -> Vec<PeerInfo> {
+        let inner = self.inner.lock().unwrap();
+        let mut peers: Vec<PeerInfo> = inner
+            .peers
+            .iter()
+            .filter_map(|(addr, peer)| peer.info(addr, &inner.misbehavior))
+            .collect();
+        peers.sort_by(|a, b| a.addr.cmp(&b.addr));
+        peers
     }
 
-    fn verify_tx(&self, tx: &Transaction) -> Result<bool> {
-        self.inner
-            .lock()
-            .unwrap()
-            .utxo
-            .blockchain
-            .verify_transacton(tx)
+    /// 记录 addr 的节点已经有 ids 中的区块或交易
+    fn peer_knows(&self, addr: &str, ids: &[String]) {
+        if let Some(peer) = self.inner.lock().unwrap().peers.get_mut(addr) {
+            for id in ids {
+                peer.known.insert(id);
+            }
+        }
     }
 
-    fn add_block(&self, block: Block) -> Result<()> {
-        self.inner.lock().unwrap().utxo.blockchain.add_block(block)
+    /// 记录从 addr 收到了 id 的完整内容，对 id 的请求随之结束
+    fn record_received(&self, addr: &str, kind: &str, id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.requested.remove(id);
+        if let Some(peer) = inner.peers.get_mut(addr) {
+            peer.known.insert(id);
+            if kind == "block" {
+                peer.blocks_received += 1;
+            } else {
+                peer.txs_received += 1;
+            }
+        }
     }
 
-    fn mine_block(&self, txs: Vec<Transaction>) -> Result<Block> {
-        self.inner.lock().unwrap().utxo.blockchain.mine_block(txs)
+    /// 向握手完成、还不知道 id 的节点通告它，对方需要时用 getdata 请求完整的内容
+    fn announce(&self, kind: &str, id: &str) -> Result<()> {
+        let peers: Vec<String> = {
+            let mut inner = self.inner.lock().unwrap();
+            inner
+                .peers
+                .iter_mut()
+                .filter(|(_, peer)| peer.handshake_complete() && !peer.known.contains(id))
+                .map(|(addr, peer)| {
+                    peer.known.insert(id);
+                    addr.clone()
+                })
+                .collect()
+        };
+        for addr in peers {
+            self.send_inv(&addr, kind, vec![id.to_string()])?;
+        }
+        Ok(())
     }
 
-    fn utxo_reindex(&self) -> Result<()> {
-        self.inner.lock().unwrap().utxo.reindex()
+    /// 向通告了 id 的 addr 请求完整的内容；已向其他节点请求且未超时时不重复请求，
+    /// 每个对象只下载一次
+    fn request_announced(&self, addr: &str, kind: &str, id: &str) -> Result<()> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner
+                .requested
+                .retain(|_, (_, at)| at.elapsed() < GETDATA_TIMEOUT);
+            if let Some((from, _)) = inner.requested.get(id) {
+                debug!("{} {} is already requested from {}", kind, id, from);
+                return Ok(());
+            }
+            inner
+                .requested
+                .insert(id.to_string(), (addr.to_string(), Instant::now()));
+        }
+        self.send_get_data(addr, kind, id)
     }
 
-    /* -----------------------------------------------------*/
-
-    fn send_data(&self, addr: &str, data: &[u8]) -> Result<()> {
-        if addr == &self.node_address {
+    /// 把最好的区块头所在分支上缺少的区块加入下载队列，分给握手完成的节点同时下载
+    fn download_missing_blocks(&self) -> Result<()> {
+        let (first_height, missing) = {
+            let utxo = self.utxo.read();
+            let missing = utxo.blockchain.missing_blocks()?;
+            let best_height = utxo.blockchain.best_header()?.1.height;
+            (best_height + 1 - missing.len() as i32, missing)
+        };
+        if missing.is_empty() {
             return Ok(());
         }
-        let mut stream = match TcpStream::connect(addr) {
-            Ok(s) => s,
-            Err(_) => {
-                self.remove_node(addr);
-                return Ok(());
-            }
-        };
-
-        stream.write(data)?;
-
-        info!("data send successfully");
+        let added = self
+            .inner
+            .lock()
+            .unwrap()
+            .downloads
+            .add(first_height, missing, Instant::now());
+        if added > 0 {
+            info!("queue {} blocks for download", added);
+        }
+        self.request_blocks();
         Ok(())
     }
 
-    fn request_blocks(&self) -> Result<()> {
-        for node in self.get_known_nodes() {
-            self.send_get_blocks(&node)?
+    /// 给握手完成的节点补足请求中的区块，见 BlockFetcher::schedule
+    fn request_blocks(&self) {
+        let requests = {
+            let mut inner = self.inner.lock().unwrap();
+            let mut peers: Vec<(String, i32)> = inner
+                .peers
+                .iter()
+                .filter(|(_, peer)| peer.handshake_complete())
+                .filter_map(|(addr, peer)| Some((addr.clone(), peer.version.as_ref()?.best_height)))
+                .collect();
+            peers.sort();
+            inner.downloads.schedule(&peers, Instant::now())
+        };
+        for (addr, hash) in requests {
+            if let Err(err) = self.send_get_data(&addr, "block", &hash) {
+                error!("cannot request block {} from {}: {}", hash, addr, err);
+            }
+        }
+    }
+
+    /// 收下正在下载的区块，按高度顺序连接队列最前面已经收到的区块，再给节点补足请求；
+    /// 全部连接后通告新的最新区块并开始挖矿
+    fn receive_downloaded(&self, block: Block, from: &str) -> Result<()> {
+        self.inner.lock().unwrap().downloads.receive(block, from);
+        let finished = self.connect_downloaded();
+        self.request_blocks();
+        if let Some(progress) = finished {
+            info!(
+                "downloaded {} blocks in {:.1}s",
+                progress.connected,
+                progress.elapsed.as_secs_f64()
+            );
+            self.utxo_update()?;
+            let tip = self.utxo.read().blockchain.tip.clone();
+            self.announce("block", &tip)?;
+            self.mine_mempool()?;
         }
         Ok(())
     }
 
-    fn send_block(&self, addr: &str, b: &Block) -> Result<()> {
-        info!("send block data to: {} block hash: {}", addr, b.get_hash());
-        let data = Blockmsg {
-            addr_from: self.node_address.clone(),
-            block: b.clone(),
-        };
-        let data = serialize(&(cmd_to_bytes("block"), data))?;
-        self.send_data(addr, &data)
+    /// 请求超时的区块改向其他节点请求，新完成握手的节点也分到要下载的区块
+    fn check_block_download(&self) {
+        let expired = self
+            .inner
+            .lock()
+            .unwrap()
+            .downloads
+            .timed_out(Instant::now());
+        for (addr, hash) in expired {
+            info!(
+                "block {} from {} timed out, request it from another peer",
+                hash, addr
+            );
+        }
+        self.request_blocks();
     }
 
-    fn send_addr(&self, addr: &str) -> Result<()> {
-        info!("send address info to: {}", addr);
-        let nodes = self.get_known_nodes();
-        let data = serialize(&(cmd_to_bytes("addr"), nodes))?;
-        self.send_data(addr, &data)
+    /// 按高度顺序连接下载队列最前面已经收到的区块，直到队列最前面的区块还没有收到；
+    /// 这一轮下载的区块全部连接后返回它的进度
+    ///
+    /// 未通过验证的区块改向其他节点请求，送来它的节点被记一次不当行为
+    fn connect_downloaded(&self) -> Option<Progress> {
+        loop {
+            let ready = self.inner.lock().unwrap().downloads.take_ready();
+            if ready.is_empty() {
+                break;
+            }
+            let mut ready = ready.into_iter();
+            while let Some(ReadyBlock {
+                height,
+                block,
+                from,
+            }) = ready.next()
+            {
+                let hash = block.get_hash();
+                if let Err(err) = self.accept_block(block) {
+                    error!("reject downloaded block {} from {}: {}", hash, from, err);
+                    let ip = self
+                        .inner
+                        .lock()
+                        .unwrap()
+                        .peers
+                        .get(&from)
+                        .and_then(|peer| peer.ip);
+                    if is_invalid_block(&err)
+                        && let Some(ip) = ip
+                    {
+                        self.misbehaving(ip, Misbehavior::InvalidBlock, &err);
+                    }
+                    self.inner.lock().unwrap().downloads.reject(
+                        hash,
+                        height,
+                        from,
+                        ready.collect(),
+                        Instant::now(),
+                    );
+                    break;
+                }
+            }
+        }
+        self.inner.lock().unwrap().downloads.finish(Instant::now())
     }
 
-    fn send_inv(&self, addr: &str, kind: &str, items: Vec<String>) -> Result<()> {
+    /// 记录区块下载的进度：当前高度、每秒连接的区块数和估计的剩余时间
+    fn log_download_progress(&self) {
+        let Some(progress) = self
+            .inner
+            .lock()
+            .unwrap()
+            .downloads
+            .progress(Instant::now())
+        else {
+            return;
+        };
+        let height = match self.get_best_height() {
+            Ok(height) => height,
+            Err(err) => {
+                error!("cannot read the best height: {}", err);
+                return;
+            }
+        };
+        let remaining = progress
+            .remaining_time()
+            .map_or("unknown".to_string(), |time| format!("{}s", time.as_secs()));
         info!(
-            "send inv message to: {} kind: {} data: {:?}",
-            addr, kind, items
+            "block download: height {}, {:.1} blocks/s, {} blocks left, about {} remaining",
+            height,
+            progress.rate(),
+            progress.remaining,
+            remaining
         );
-        let data = Invmsg {
-            addr_from: self.node_address.clone(),
-            kind: kind.to_string(),
-            items,
-        };
-        let data = serialize(&(cmd_to_bytes("inv"), data))?;
-        self.send_data(addr, &data)
     }
 
-    fn send_get_blocks(&self, addr: &str) -> Result<()> {
-        info!("send get blocks message to: {}", addr);
-        let data = GetBlocksmsg {
-            addr_from: self.node_address.clone(),
-        };
-        let data = serialize(&(cmd_to_bytes("getblocks"), data))?;
-        self.send_data(addr, &data)
+    fn replace_in_transit(&self, hashs: Vec<String>) {
+        let bit = &mut self.inner.lock().unwrap().blocks_in_transit;
+        bit.clone_from(&hashs);
     }
 
-    fn send_get_data(&self, addr: &str, kind: &str, id: &str) -> Result<()> {
-        info!(
-            "send get data message to: {} kind: {} id: {}",
-            addr, kind, id
-        );
-        let data = GetDatamsg {
-            addr_from: self.node_address.clone(),
-            kind: kind.to_string(),
-            id: id.to_string(),
-        };
-        let data = serialize(&(cmd_to_bytes("getdata"), data))?;
-        self.send_data(addr, &data)
+    fn get_in_transit(&self) -> Vec<String> {
+        self.inner.lock().unwrap().blocks_in_transit.clone()
     }
 
-    pub fn send_tx(&self, addr: &str, tx: &Transaction) -> Result<()> {
-        info!("send tx to: {} txid: {}", addr, &tx.id);
-        let data = Txmsg {
-            addr_from: self.node_address.clone(),
-            transaction: tx.clone(),
-        };
-        let data = serialize(&(cmd_to_bytes("tx"), data))?;
-        self.send_data(addr, &data)
+    fn get_mempool_tx(&self, txid: &str) -> Option<Transaction> {
+        self.mempool.lock().get(txid).cloned()
     }
 
-    fn send_version(&self, addr: &str) -> Result<()> {
-        info!("send version info to: {}", addr);
-        let data = Versionmsg {
-            addr_from: self.node_address.clone(),
-            best_height: self.get_best_height()?,
-            version: VERSION,
-        };
-        let data = serialize(&(cmd_to_bytes("version"), data))?;
-        self.send_data(addr, &data)
+    fn get_best_height(&self) -> Result<i32> {
+        self.utxo.read().blockchain.get_best_height()
     }
 
-    fn handle_version(&self, msg: Versionmsg) -> Result<()> {
-        info!("receive version msg: {:#?}", msg);
-        let my_best_height = self.get_best_height()?;
-        if my_best_height < msg.best_height {
-            self.send_get_blocks(&msg.addr_from)?;
-        } else if my_best_height > msg.best_height {
-            self.send_version(&msg.addr_from)?;
-        }
+    fn get_block_hashs(&self) -> Vec<String> {
+        self.utxo.read().blockchain.get_block_hashs()
+    }
 
-        self.send_addr(&msg.addr_from)?;
+    fn get_block(&self, block_hash: &str) -> Result<Block> {
+        self.utxo.read().blockchain.get_block(block_hash)
+    }
+
+    /// 完整验证并连接收到的区块，最新区块因此改变时中止正在进行的挖矿
+    ///
+    /// 接在最新区块上的区块先对照UTXO集合验证再保存。侧链上的区块只保存，侧链的累计
+    /// 工作量超过主链时重组：UTXO集合切换到侧链，途中逐个验证侧链上的区块，成功后才
+    /// 更新最新区块，失败时集合回到原来的最新区块；旧分支上仍然有效的交易回到交易池
+    fn add_block(&self, block: Block) -> Result<()> {
+        let mut utxo = self.utxo.write();
+        utxo.blockchain.check_checkpoints(&block)?;
+        utxo.reorganize()?;
+        let tip = utxo.blockchain.tip.clone();
+        let hash = block.get_hash();
+        let extends_tip = block.get_prev_hash() == tip;
+        let disconnected = if extends_tip {
+            if let Err(err) = utxo.blockchain.validate_block(&block, &utxo) {
+                error!("reject block {}: {}", hash, err);
+                return Err(err.into());
+            }
+            utxo.blockchain.store_block(&block)?;
+            utxo.connect_block(&block)?;
+            Vec::new()
+        } else {
+            utxo.blockchain.store_block(&block)?;
+            if utxo.blockchain.chain_work(&hash)? <= utxo.blockchain.chain_work(&tip)? {
+                return Ok(());
+            }
+            info!("reorganize from {} to {}", tip, hash);
+            match utxo.switch_to(&hash) {
+                Ok(disconnected) => disconnected,
+                Err(err) => {
+                    error!("reject branch {}: {}", hash, err);
+                    utxo.reorganize()?;
+                    return Err(err);
+                }
+            }
+        };
+        utxo.blockchain.set_tip(&hash)?;
+        self.abort_mining();
 
-        if !self.node_is_known(&msg.addr_from) {
-            self.add_nodes(&msg.addr_from);
+        // 重组可能连接了多个区块，整个交易池对照新的集合重建
+        let mut mempool = self.mempool.lock();
+        if extends_tip {
+            mempool.remove_confirmed(&block);
+        } else {
+            mempool.readd(&disconnected, &utxo);
         }
+        let confirmed = block
+            .get_transaction()
+            .iter()
+            .map(|tx| tx.id.clone())
+            .collect();
+        mempool.retry_orphans(confirmed, &utxo);
         Ok(())
     }
 
-    fn handle_addr(&self, msg: Vec<String>) -> Result<()> {
-        info!("receive address msg: {:#?}", msg);
-        for node in msg {
-            self.add_nodes(&node);
-        }
-        //self.request_blocks()?;
-        Ok(())
+    /// 中止使用当前标志的挖矿，之后开始的挖矿使用新的标志
+    fn abort_mining(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.mining_abort.store(true, Ordering::Relaxed);
+        inner.mining_abort = Arc::new(AtomicBool::new(false));
     }
 
-    fn handle_block(&self, msg: Blockmsg) -> Result<()> {
-        info!(
-            "receive block msg: {}, {}",
-            msg.addr_from,
-            msg.block.get_hash()
-        );
-        self.add_block(msg.block)?;
-
-        let mut in_transit = self.get_in_transit();
-        if in_transit.len() > 0 {
-            let block_hash = &in_transit[0];
-            self.send_get_data(&msg.addr_from, "block", block_hash)?;
-            in_transit.remove(0);
-            self.replace_in_transit(in_transit);
-        } else {
-            self.utxo_reindex()?;
+    /// ReceiveMined 在处理任务中连接挖矿线程挖出的区块并通告，之后接着打包交易池中的交易
+    ///
+    /// 挖矿期间最新区块改变时放弃已做的工作，在新的最新区块上重新选取交易；
+    /// 保存区块和更新集合持有同一个写锁，查询不会看到只完成一半的状态
+    fn receive_mined(&self, mined: Result<Block>, aborted: bool) -> Result<()> {
+        self.inner.lock().unwrap().mining = false;
+        let block = match mined {
+            Ok(block) => block,
+            Err(_) if aborted => {
+                info!("new tip arrived while mining, restart on the new tip");
+                return self.mine_mempool();
+            }
+            Err(err) => return Err(err),
+        };
+        {
+            let mut utxo = self.utxo.write();
+            // 中止标志在读取之后才换新时，这里仍能发现区块已经过时
+            if utxo.blockchain.tip != block.get_prev_hash() {
+                drop(utxo);
+                info!("new tip arrived while mining, restart on the new tip");
+                return self.mine_mempool();
+            }
+            utxo.blockchain.add_block(block.clone())?;
+            utxo.reorganize()?;
+            self.abort_mining();
+            self.mempool.lock().remove_confirmed(&block);
         }
+        self.announce("block", &block.get_hash())?;
+        self.mine_mempool()
+    }
 
+    /// 区块头中的 UTXO 承诺与本节点在其父区块上的UTXO集合不同时，报告两个节点的分歧
+    #[cfg(feature = "utxo-commitment")]
+    fn check_utxo_commitment(&self, block: &Block) -> Result<()> {
+        let utxo = self.utxo.read();
+        let Some(commitment) = block.get_utxo_commitment() else {
+            return Ok(());
+        };
+        // 本节点的集合不在父区块上时无法比较
+        if utxo.tip()?.0 != block.get_prev_hash() {
+            return Ok(());
+        }
+        let local = utxo.commitment()?;
+        if local != commitment {
+            error!(
+                "UTXO set diverges from block {}: local commitment {}, block commitment {}",
+                block.get_hash(),
+                hex::encode(local),
+                hex::encode(commitment)
+            );
+        }
         Ok(())
     }
 
-    fn handle_inv(&self, msg: Invmsg) -> Result<()> {
-        info!("receive inv msg: {:#?}", msg);
-        if msg.kind == "block" {
-            let block_hash = &msg.items[0];
-            self.send_get_data(&msg.addr_from, "block", block_hash)?;
+    /// 把UTXO集合更新到最新区块，最新区块切换到另一分支时先回滚旧分支
+    fn utxo_update(&self) -> Result<()> {
+        self.utxo.write().reorganize()
+    }
 
-            let mut new_in_transit = Vec::new();
-            for b in &msg.items {
-                if b != block_hash {
-                    new_in_transit.push(b.clone());
+    /* -----------------------------------------------------*/
+
+    /// 把发给 addr 的一帧交给它的任务，不等待发送完成
+    fn send_data(&self, addr: &str, data: &[u8]) -> Result<()> {
+        self.enqueue(addr, Outbound::Frame(data.to_vec()), Duration::ZERO);
+        Ok(())
+    }
+
+    /// 把 outbound 放进 addr 的任务的发送队列，需要时先启动这个任务；delay 不为零时等待之后再放入
+    ///
+    /// 队列已满时说明对方跟不上，断开它而不是让处理任务等待。节点没有运行时丢弃消息
+    fn enqueue(&self, addr: &str, outbound: Outbound, delay: Duration) {
+        if addr == self.node_address {
+            return;
+        }
+        let (outbox, runtime) = {
+            let mut inner = self.inner.lock().unwrap();
+            let Some(runtime) = inner.runtime.clone() else {
+                debug!("drop a message to {}: the node is not running", addr);
+                return;
+            };
+            let outbox = match inner.outboxes.get(addr) {
+                Some((outbox, _)) if !outbox.is_closed() => outbox.clone(),
+                _ => {
+                    let (outbox, queue) = mpsc::channel(PEER_QUEUE_SIZE);
+                    let task = runtime.spawn(self.clone().serve_peer(addr.to_string(), queue));
+                    inner
+                        .outboxes
+                        .insert(addr.to_string(), (outbox.clone(), task.abort_handle()));
+                    outbox
                 }
+            };
+            (outbox, runtime)
+        };
+        if !delay.is_zero() {
+            runtime.spawn(async move {
+                time::sleep(delay).await;
+                let _ = outbox.send(outbound).await;
+            });
+            return;
+        }
+        match outbox.try_send(outbound) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                error!(
+                    "disconnect {}: {} messages waiting to be sent",
+                    addr, PEER_QUEUE_SIZE
+                );
+                self.forget_peer(addr);
             }
-            self.replace_in_transit(new_in_transit);
-        } else if msg.kind == "tx" {
-            let txid = &msg.items[0];
-            match self.get_mempool_tx(txid) {
-                Some(tx) => {
-                    if tx.id.is_empty() {
-                        self.send_get_data(&msg.addr_from, "tx", txid)?
+            // 任务刚因为发送失败结束，对方已被忘记
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+
+    /// ServePeer 一个节点的任务：按交给它的顺序把消息发给 addr 上的节点，每条消息使用单独的连接
+    ///
+    /// 连接或写入失败时忘记这个节点并结束，队列中剩下的消息随之丢弃；发送方全部关闭时结束
+    async fn serve_peer(self, addr: String, mut outbox: mpsc::Receiver<Outbound>) {
+        while let Some(outbound) = outbox.recv().await {
+            match outbound {
+                Outbound::Frame(data) => {
+                    if let Err(err) = send_frame(&addr, &data, SEND_TIMEOUT).await {
+                        error!("cannot send to {}: {}", addr, err);
+                        self.forget_peer(&addr);
+                        return;
+                    }
+                    info!("data send successfully");
+                }
+                Outbound::Version(data) => {
+                    let verack = match exchange(&addr, &data, HANDSHAKE_TIMEOUT, self.network).await
+                    {
+                        Ok(Some(frame)) if frame.cmd == "verack" => Ok(()),
+                        Ok(_) => Err(format_err!("the node rejected the version message")),
+                        Err(err) => Err(format_err!("no verack: {}", err)),
+                    };
+                    let Some(events) = self.events() else {
+                        return;
+                    };
+                    let from = addr.clone();
+                    let job = move |server: &Server| {
+                        if let Err(err) = server.receive_verack(&from, verack) {
+                            error!("cannot complete the handshake with {}: {}", from, err);
+                        }
+                    };
+                    if events.send(Event::Job(Box::new(job))).await.is_err() {
+                        return;
                     }
                 }
-                None => self.send_get_data(&msg.addr_from, "tx", txid)?,
+                Outbound::Ping {
+                    data,
+                    nonce,
+                    timeout,
+                    pong,
+                } => {
+                    let start = Instant::now();
+                    let reply = async {
+                        let frame = exchange(&addr, &data, timeout, self.network)
+                            .await?
+                            .ok_or_else(|| format_err!("the node closed the connection"))?;
+                        if frame.cmd != "pong" || decode::<u64>(&frame.payload)? != nonce {
+                            return Err(format_err!("unexpected {} reply", frame.cmd));
+                        }
+                        Ok(start.elapsed())
+                    };
+                    let _ = pong.send(reply.await);
+                }
             }
         }
-        Ok(())
     }
 
-    fn handle_get_blocks(&self, msg: GetBlocksmsg) -> Result<()> {
-        info!("receive get blocks msg: {:#?}", msg);
-        let block_hashs = self.get_block_hashs();
-        self.send_inv(&msg.addr_from, "block", block_hashs)?;
+    /// 对方请求的区块等待 block_delay 之后才交给它的任务
+    fn send_block(&self, addr: &str, b: &Block) -> Result<()> {
+        info!("send block data to: {} block hash: {}", addr, b.get_hash());
+        let data = Blockmsg {
+            addr_from: self.node_address.clone(),
+            block: b.clone(),
+        };
+        let data = encode_message(self.network, "block", &data)?;
+        self.enqueue(addr, Outbound::Frame(data), self.options.block_delay);
         Ok(())
     }
 
-    fn handle_get_data(&self, msg: GetDatamsg) -> Result<()> {
-        info!("receive get data msg: {:#?}", msg);
-        if msg.kind == "block" {
-            let block = self.get_block(&msg.id)?;
-            self.send_block(&msg.addr_from, &block)?;
-        } else if msg.kind == "tx" {
-            let tx = self.get_mempool_tx(&msg.id).unwrap();
-            self.send_tx(&msg.addr_from, &tx)?;
-        }
-        Ok(())
+    fn send_addr(&self, addr: &str) -> Result<()> {
+        info!("send address info to: {}", addr);
+        let data = Addrmsg {
+            addr_from: self.node_address.clone(),
+            addrs: self.inner.lock().unwrap().addrs.addresses(MAX_ADDR_PER_MSG),
+        };
+        let data = encode_message(self.network, "addr", &data)?;
+        self.send_data(addr, &data)
     }
 
-    fn handle_tx(&self, msg: Txmsg) -> Result<()> {
-        info!("receive tx msg: {} {}", msg.addr_from, &msg.transaction.id);
-        self.insert_mempool(msg.transaction.clone());
+    fn send_get_addr(&self, addr: &str) -> Result<()> {
+        info!("send get addr message to: {}", addr);
+        let data = GetAddrmsg {
+            addr_from: self.node_address.clone(),
+        };
+        let data = encode_message(self.network, "getaddr", &data)?;
+        self.send_data(addr, &data)
+    }
 
-        let known_nodes = self.get_known_nodes();
-        if self.node_address == KNOWN_NODE1 {
-            for node in known_nodes {
-                if node != self.node_address && node != msg.addr_from {
-                    self.send_inv(&node, "tx", vec![msg.transaction.id.clone()])?;
-                }
-            }
-        } else {
-            let mut mempool = self.get_mempool();
-            debug!("Current mempool: {:#?}", &mempool);
-            if mempool.len() >= 1 && !self.mining_address.is_empty() {
-                loop {
-                    let mut txs = Vec::new();
-
-                    for (_, tx) in &mempool {
-                        if self.verify_tx(tx)? {
-                            txs.push(tx.clone());
-                        }
-                    }
+    fn send_inv(&self, addr: &str, kind: &str, items: Vec<String>) -> Result<()> {
+        info!(
+            "send inv message to: {} kind: {} data: {:?}",
+            addr, kind, items
+        );
+        let data = Invmsg {
+            addr_from: self.node_address.clone(),
+            kind: kind.to_string(),
+            items,
+        };
+        let data = encode_message(self.network, "inv", &data)?;
+        self.send_data(addr, &data)
+    }
 
-                    if txs.is_empty() {
-                        return Ok(());
-                    }
+    fn send_get_blocks(&self, addr: &str) -> Result<()> {
+        info!("send get blocks message to: {}", addr);
+        let data = GetBlocksmsg {
+            addr_from: self.node_address.clone(),
+        };
+        let data = encode_message(self.network, "getblocks", &data)?;
+        self.send_data(addr, &data)
+    }
 
-                    let cbtx =
-                        Transaction::new_coinbase(self.mining_address.clone(), String::new())?;
-                    txs.push(cbtx);
+    fn send_get_headers(&self, addr: &str) -> Result<()> {
+        info!("send get headers message to: {}", addr);
+        let data = GetHeadersmsg {
+            addr_from: self.node_address.clone(),
+            locator: self.utxo.read().blockchain.header_locator()?,
+        };
+        let data = encode_message(self.network, "getheaders", &data)?;
+        self.send_data(addr, &data)
+    }
 
-                    for tx in &txs {
-                        mempool.remove(&tx.id);
-                    }
+    fn send_headers(&self, addr: &str, headers: Vec<BlockHeader>) -> Result<()> {
+        info!("send {} headers to: {}", headers.len(), addr);
+        let data = Headersmsg {
+            addr_from: self.node_address.clone(),
+            headers,
+        };
+        let data = encode_message(self.network, "headers", &data)?;
+        self.send_data(addr, &data)
+    }
 
-                    let new_block = self.mine_block(txs)?;
-                    self.utxo_reindex()?;
+    fn send_get_data(&self, addr: &str, kind: &str, id: &str) -> Result<()> {
+        info!(
+            "send get data message to: {} kind: {} id: {}",
+            addr, kind, id
+        );
+        self.inner
+            .lock()
+            .unwrap()
+            .requested
+            .insert(id.to_string(), (addr.to_string(), Instant::now()));
+        let data = GetDatamsg {
+            addr_from: self.node_address.clone(),
+            kind: kind.to_string(),
+            id: id.to_string(),
+        };
+        let data = encode_message(self.network, "getdata", &data)?;
+        self.send_data(addr, &data)
+    }
 
-                    for node in self.get_known_nodes() {
-                        if node != self.node_address {
-                            self.send_inv(&node, "block", vec![new_block.get_hash()])?;
-                        }
-                    }
+    fn send_not_found(&self, addr: &str, kind: &str, id: &str) -> Result<()> {
+        info!(
+            "send not found message to: {} kind: {} id: {}",
+            addr, kind, id
+        );
+        let data = NotFoundmsg {
+            addr_from: self.node_address.clone(),
+            kind: kind.to_string(),
+            id: id.to_string(),
+        };
+        let data = encode_message(self.network, "notfound", &data)?;
+        self.send_data(addr, &data)
+    }
 
-                    if mempool.len() == 0 {
-                        break;
-                    }
-                }
-                self.clear_mempool();
-            }
-        }
+    pub fn send_tx(&self, addr: &str, tx: &Transaction) -> Result<()> {
+        info!("send tx to: {} txid: {}", addr, &tx.id);
+        let data = Txmsg {
+            addr_from: self.node_address.clone(),
+            transaction: tx.clone(),
+        };
+        let data = encode_message(self.network, "tx", &data)?;
+        self.send_data(addr, &data)
+    }
 
+    /// 向 addr 发送本节点的 version 消息，它的任务在同一连接上等待对方回复 verack，
+    /// 结果交给 receive_verack
+    fn send_version(&self, addr: &str) -> Result<()> {
+        if addr == self.node_address {
+            return Ok(());
+        }
+        info!("send version info to: {}", addr);
+        let data = Versionmsg {
+            addr_from: self.node_address.clone(),
+            best_height: self.get_best_height()?,
+            version: PROTOCOL_VERSION,
+            user_agent: user_agent(),
+            magic: self.network.magic(),
+            nonce: self.nonce,
+        };
+        let data = encode_message(self.network, "version", &data)?;
+        self.inner
+            .lock()
+            .unwrap()
+            .peers
+            .entry(addr.to_string())
+            .or_default()
+            .version_sent = true;
+        self.enqueue(addr, Outbound::Version(data), Duration::ZERO);
         Ok(())
     }
 
-    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
-        let mut buffer = Vec::new();
-        let count = stream.read_to_end(&mut buffer)?;
-        info!("Accept request: length {}", count);
+    /// ReceiveVerack 在处理任务中处理 addr 对本节点的 version 的回复，握手随之完成时开始同步，
+    /// 之后依次处理等待握手完成的消息
+    ///
+    /// 对方没有回复 verack 就关闭连接时说明它拒绝了握手，不再把它当作已知节点
+    fn receive_verack(&self, addr: &str, verack: Result<()>) -> Result<()> {
+        if let Err(err) = verack {
+            error!("disconnect {}: {}", addr, err);
+            self.forget_peer(addr);
+            return Ok(());
+        }
+        let (complete, waiting) = {
+            let mut inner = self.inner.lock().unwrap();
+            let Some(peer) = inner.peers.get_mut(addr) else {
+                return Ok(());
+            };
+            let complete = !peer.verack_received && peer.version.is_some();
+            peer.verack_received = true;
+            peer.last_message = Some(Instant::now());
+            (complete, std::mem::take(&mut peer.waiting))
+        };
+        let result = if complete {
+            self.handshake_complete(addr)
+        } else {
+            Ok(())
+        };
+        for (cmd, from, mut reply) in waiting {
+            if let Err(err) = self.handle_message(cmd, from, &mut reply) {
+                error!("cannot handle a message from {}: {}", addr, err);
+                reply.close();
+            }
+        }
+        result
+    }
 
-        let cmd = bytes_to_cmd(&buffer)?;
+    /// HandleVersion 处理从 IP 地址 from 收到的握手消息
+    ///
+    /// 接受对方的 version 消息时在同一连接上回复 verack，还没有向对方发送 version 时随后发送
+    /// 网络标识不同、连接到了自己或对方的协议版本过低时不回复，关闭连接并忘记对方
+    fn handle_version(&self, msg: Versionmsg, from: IpAddr, reply: &mut Reply) -> Result<()> {
+        info!("receive version msg: {:#?}", msg);
+        let addr = msg.addr_from.clone();
+        if let Err(err) = self.check_version(&msg) {
+            error!("disconnect {}: {}", addr, err);
+            self.forget_peer(&addr);
+            return Ok(());
+        }
 
-        match cmd {
-            Message::Addr(data) => self.handle_addr(data)?,
-            Message::Block(data) => self.handle_block(data)?,
-            Message::Inv(data) => self.handle_inv(data)?,
-            Message::GetBlock(data) => self.handle_get_blocks(data)?,
-            Message::GetData(data) => self.handle_get_data(data)?,
-            Message::Tx(data) => self.handle_tx(data)?,
-            Message::Version(data) => self.handle_version(data)?,
+        let (send_version, complete) = {
+            let mut inner = self.inner.lock().unwrap();
+            let peer = inner.peers.entry(addr.clone()).or_default();
+            if peer.version.is_some() {
+                // 对方重新握手，例如重启之后
+                *peer = Peer {
+                    outbound: peer.outbound,
+                    ..Peer::default()
+                };
+            }
+            peer.version = Some(msg);
+            peer.ip = Some(from);
+            peer.last_message = Some(Instant::now());
+            let send_version = !peer.version_sent;
+            peer.version_sent = true;
+            (send_version, peer.verack_received)
+        };
+        reply.send("verack", Vec::new())?;
+        reply.close();
+        if send_version {
+            self.send_version(&addr)?;
+        }
+        if complete {
+            self.handshake_complete(&addr)?;
         }
+        Ok(())
+    }
 
+    fn check_version(&self, msg: &Versionmsg) -> Result<()> {
+        let magic = self.network.magic();
+        if msg.magic != magic {
+            return Err(format_err!(
+                "network magic {} does not match {}",
+                hex::encode(msg.magic),
+                hex::encode(magic)
+            ));
+        }
+        if msg.nonce == self.nonce {
+            return Err(format_err!("connected to itself"));
+        }
+        let connect = &self.options.connect;
+        if !connect.is_empty() && !connect.contains(&msg.addr_from) {
+            return Err(format_err!(
+                "the address is not one of the peers to connect to"
+            ));
+        }
+        {
+            let inner = self.inner.lock().unwrap();
+            // 本节点发起的连接在发送 version 之前已经记录，没有记录的是对方发起的新连接
+            if !inner.peers.contains_key(&msg.addr_from) {
+                let inbound = inner.peers.values().filter(|peer| !peer.outbound).count();
+                if inbound >= self.options.max_inbound {
+                    return Err(format_err!("{} inbound peers already connected", inbound));
+                }
+            }
+        }
+        if msg.version < MIN_PROTOCOL_VERSION {
+            return Err(format_err!(
+                "protocol version {} is below the minimum {}",
+                msg.version,
+                MIN_PROTOCOL_VERSION
+            ));
+        }
         Ok(())
     }
-}
 
-fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
-    let mut data = [0; CMD_LEN];
-    for (i, d) in cmd.as_bytes().iter().enumerate() {
-        data[i] = *d;
+    /// 握手完成后高度较低的一方开始同步，之后请求对方知道的地址并重新广播本机钱包的交易
+    fn handshake_complete(&self, addr: &str) -> Result<()> {
+        let Some(peer) = self.peer_info(addr) else {
+            return Ok(());
+        };
+        self.inner.lock().unwrap().addrs.seen(addr, unix_time());
+        info!(
+            "handshake with {} complete, version: {} user agent: {}",
+            addr, peer.version, peer.user_agent
+        );
+        let my_best_height = self.get_best_height()?;
+        if my_best_height < peer.best_height {
+            // 没有区块时无法给出定位哈希，下载全部区块
+            if my_best_height >= 0 && peer.version >= HEADERS_VERSION {
+                self.send_get_headers(addr)?;
+            } else {
+                self.send_get_blocks(addr)?;
+            }
+        }
+
+        self.send_get_addr(addr)?;
+        self.add_nodes(addr);
+        self.rebroadcast_wallet_txs(Some(addr))
     }
-    data
-}
 
-fn bytes_to_cmd(bytes: &[u8]) -> Result<Message> {
-    let mut cmd = Vec::new();
-    let cmd_bytes = &bytes[..CMD_LEN];
-    let data = &bytes[CMD_LEN..];
-    for b in cmd_bytes {
-        if 0 as u8 != *b {
-            cmd.push(*b);
+    /// PingPeers 同时向握手完成的节点发送 ping，在 timeout 内等待 pong 并记录往返时间
+    ///
+    /// 连续 MAX_MISSED_PINGS 次没有回复的节点被断开，例如机器已经消失、连接只剩一半的节点。
+    /// 等待各节点的任务送回结果，不能在处理任务中调用
+    fn ping_peers(&self, timeout: Duration) {
+        let peers: Vec<String> = {
+            let inner = self.inner.lock().unwrap();
+            inner
+                .peers
+                .iter()
+                .filter(|(_, peer)| peer.handshake_complete())
+                .map(|(addr, _)| addr.clone())
+                .collect()
+        };
+        let pongs: Vec<_> = peers
+            .into_iter()
+            .map(|addr| {
+                let pong = self.ping(&addr, timeout);
+                (addr, pong)
+            })
+            .collect();
+        for (addr, pong) in pongs {
+            let pong = pong
+                .blocking_recv()
+                .unwrap_or_else(|_| Err(format_err!("the ping was not sent")));
+            let mut inner = self.inner.lock().unwrap();
+            let Some(peer) = inner.peers.get_mut(&addr) else {
+                continue;
+            };
+            match pong {
+                Ok(latency) => {
+                    debug!("ping {}: {} ms", addr, latency.as_millis());
+                    peer.ping = Some(latency);
+                    peer.missed_pings = 0;
+                    peer.last_message = Some(Instant::now());
+                }
+                Err(err) => {
+                    peer.missed_pings += 1;
+                    info!("no pong from {}: {}", addr, err);
+                    if peer.missed_pings >= MAX_MISSED_PINGS {
+                        drop(inner);
+                        error!(
+                            "disconnect {}: {} pings without pong",
+                            addr, MAX_MISSED_PINGS
+                        );
+                        self.forget_peer(&addr);
+                    }
+                }
+            }
         }
     }
-    info!("cmd: {}", String::from_utf8(cmd.clone())?);
 
-    if cmd == "addr".as_bytes() {
-        let data: Vec<String> = deserialize(data)?;
-        Ok(Message::Addr(data))
-    } else if cmd == "block".as_bytes() {
-        let data: Blockmsg = deserialize(data)?;
-        Ok(Message::Block(data))
-    } else if cmd == "inv".as_bytes() {
-        let data: Invmsg = deserialize(data)?;
-        Ok(Message::Inv(data))
-    } else if cmd == "getblocks".as_bytes() {
-        let data: GetBlocksmsg = deserialize(data)?;
-        Ok(Message::GetBlock(data))
-    } else if cmd == "getdata".as_bytes() {
-        let data: GetDatamsg = deserialize(data)?;
-        Ok(Message::GetData(data))
-    } else if cmd == "tx".as_bytes() {
-        let data: Txmsg = deserialize(data)?;
-        Ok(Message::Tx(data))
-    } else if cmd == "version".as_bytes() {
-        let data: Versionmsg = deserialize(data)?;
-        Ok(Message::Version(data))
-    } else {
-        Err(format_err!("Unknown command in the server"))
+    /// 把发给 addr 的 ping 交给它的任务，返回的一端收到匹配的 pong 所用的时间
+    fn ping(&self, addr: &str, timeout: Duration) -> oneshot::Receiver<Result<Duration>> {
+        let (pong, receiver) = oneshot::channel();
+        let nonce = rand::random();
+        let data = Pingmsg {
+            addr_from: self.node_address.clone(),
+            nonce,
+        };
+        match encode_message(self.network, "ping", &data) {
+            Ok(data) => {
+                let ping = Outbound::Ping {
+                    data,
+                    nonce,
+                    timeout,
+                    pong,
+                };
+                self.enqueue(addr, ping, Duration::ZERO);
+            }
+            Err(err) => {
+                let _ = pong.send(Err(err));
+            }
+        }
+        receiver
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::blockchain::*;
-    use crate::wallets::*;
+    /// 在同一连接上回复 pong
+    fn handle_ping(&self, msg: Pingmsg, reply: &mut Reply) -> Result<()> {
+        debug!("receive ping msg: {:#?}", msg);
+        reply.send("pong", serialize(&msg.nonce)?)?;
+        Ok(())
+    }
 
-    #[test]
-    fn test_cmd() {
-        let mut ws = Wallets::new().unwrap();
-        let wa1 = ws.create_wallet();
-        let bc = Blockchain::create_blockchain(wa1).unwrap();
-        let utxo_set = UTXOSet { blockchain: bc };
-        let server = Server::new("7878", "localhost:3001", utxo_set).unwrap();
+    /// Connect 与 addr 握手，计为向外的连接；已在握手或已完成握手的节点不重复连接，
+    /// 不连接被封禁的节点
+    fn connect(&self, addr: &str) -> Result<()> {
+        if addr == self.node_address || self.is_banned_node(addr) {
+            return Ok(());
+        }
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.peers.contains_key(addr) {
+                return Ok(());
+            }
+            inner.peers.insert(
+                addr.to_string(),
+                Peer {
+                    outbound: true,
+                    ..Peer::default()
+                },
+            );
+        }
+        self.send_version(addr)
+    }
 
-        let vmsg = Versionmsg {
-            addr_from: server.node_address.clone(),
-            best_height: server.get_best_height().unwrap(),
-            version: VERSION,
+    /// ConnectToPeers 向外的连接少于 max_outbound 个时，从地址库中按最近活跃的顺序连接新的节点
+    ///
+    /// 给出了要连接的节点时只重新连接其中断开的节点
+    fn connect_to_peers(&self) -> Result<()> {
+        let candidates = {
+            let inner = self.inner.lock().unwrap();
+            let now = unix_time();
+            let usable = |addr: &str| {
+                addr != self.node_address
+                    && !inner.peers.contains_key(addr)
+                    && !addr
+                        .parse::<SocketAddr>()
+                        .is_ok_and(|addr| inner.bans.is_banned(addr.ip(), now))
+            };
+            if !self.options.connect.is_empty() {
+                self.options
+                    .connect
+                    .iter()
+                    .filter(|addr| usable(addr))
+                    .cloned()
+                    .collect()
+            } else {
+                let outbound = inner.peers.values().filter(|peer| peer.outbound).count();
+                let max_outbound = self.options.max_outbound;
+                if outbound >= max_outbound {
+                    return Ok(());
+                }
+                inner.addrs.candidates(max_outbound - outbound, usable)
+            }
         };
-        let data = serialize(&(cmd_to_bytes("version"), vmsg.clone())).unwrap();
-        if let Message::Version(v) = bytes_to_cmd(&data).unwrap() {
-            assert_eq!(v, vmsg);
-        } else {
-            panic!("wrong!");
+        for addr in candidates {
+            info!("connect to {} from the peer addresses", addr);
+            self.connect(&addr)?;
         }
+        Ok(())
+    }
+
+    /// 把对方告诉的地址记入地址库，每个节点能记入的新地址数受地址库的限制
+    fn handle_addr(&self, msg: Addrmsg) -> Result<()> {
+        info!(
+            "receive address msg: {}, {} addresses",
+            msg.addr_from,
+            msg.addrs.len()
+        );
+        let now = unix_time();
+        let mut inner = self.inner.lock().unwrap();
+        let mut added = 0;
+        for peer in msg.addrs.into_iter().take(MAX_ADDR_PER_MSG) {
+            if peer.addr != self.node_address && inner.addrs.add(peer, &msg.addr_from, now) {
+                added += 1;
+            }
+        }
+        info!("learn {} new addresses from {}", added, msg.addr_from);
+        Ok(())
+    }
+
+    fn handle_get_addr(&self, msg: GetAddrmsg) -> Result<()> {
+        info!("receive get addr msg: {:#?}", msg);
+        self.send_addr(&msg.addr_from)
+    }
+
+    /// HandleBlock 处理从 IP 地址 from 收到的区块
+    fn handle_block(&self, msg: Blockmsg, from: IpAddr) -> Result<()> {
+        info!(
+            "receive block msg: {}, {}",
+            msg.addr_from,
+            msg.block.get_hash()
+        );
+        self.record_received(&msg.addr_from, "block", &msg.block.get_hash());
+        // 在做任何哈希和签名检查之前拒绝过大的区块
+        let checked = msg
+            .block
+            .check_size()
+            .and_then(|_| msg.block.verify_merkle_root())
+            .and_then(|_| self.utxo.read().blockchain.check_against_header(&msg.block));
+        let hash = msg.block.get_hash();
+        if let Err(err) = checked {
+            self.misbehaving(from, Misbehavior::InvalidBlock, &err);
+            let now = Instant::now();
+            if self
+                .inner
+                .lock()
+                .unwrap()
+                .downloads
+                .failed(&hash, &msg.addr_from, now)
+            {
+                self.request_blocks();
+            }
+            return Err(err);
+        }
+        if self.inner.lock().unwrap().downloads.contains(&hash) {
+            return self.receive_downloaded(msg.block, &msg.addr_from);
+        }
+        let prev_hash = msg.block.get_prev_hash();
+        if !prev_hash.is_empty() && self.get_block(&prev_hash).is_err() {
+            // 父区块未知时无法检查难度，只检查区块满足它声称的难度
+            if let Err(err) = msg.block.check_proof_of_work() {
+                self.misbehaving(from, Misbehavior::InvalidProofOfWork, &err);
+                return Err(err);
+            }
+            info!(
+                "block {} is an orphan, request its parent {}",
+                msg.block.get_hash(),
+                prev_hash
+            );
+            let size = msg.block.size()?;
+            let mut inner = self.inner.lock().unwrap();
+            inner.orphans.insert(msg.block, size, Instant::now());
+            drop(inner);
+            self.send_get_data(&msg.addr_from, "block", &prev_hash)?;
+        } else {
+            let difficulty = self
+                .utxo
+                .read()
+                .blockchain
+                .check_block_difficulty(&msg.block);
+            if let Err(err) = difficulty {
+                self.misbehaving(from, Misbehavior::InvalidProofOfWork, &err);
+                return Err(err);
+            }
+            if let Err(err) = self.accept_block(msg.block) {
+                if is_invalid_block(&err) {
+                    self.misbehaving(from, Misbehavior::InvalidBlock, &err);
+                }
+                return Err(err);
+            }
+        }
+
+        let mut in_transit = self.get_in_transit();
+        if !in_transit.is_empty() {
+            let block_hash = &in_transit[0];
+            self.send_get_data(&msg.addr_from, "block", block_hash)?;
+            in_transit.remove(0);
+            self.replace_in_transit(in_transit);
+        } else {
+            self.utxo_update()?;
+            let tip = self.utxo.read().blockchain.tip.clone();
+            self.announce("block", &tip)?;
+            self.mine_mempool()?;
+        }
+
+        Ok(())
+    }
+
+    /// 检查并连接父区块已知的区块，之后连接等待它的孤块，孤块的子孙依次跟上
+    fn accept_block(&self, block: Block) -> Result<()> {
+        let mut parents = vec![block.get_hash()];
+        self.check_and_add_block(block)?;
+        while let Some(parent) = parents.pop() {
+            let children = self.inner.lock().unwrap().orphans.take_children(&parent);
+            for child in children {
+                let hash = child.get_hash();
+                match self.check_and_add_block(child) {
+                    Ok(()) => parents.push(hash),
+                    Err(err) => error!("reject orphan block {}: {}", hash, err),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_and_add_block(&self, block: Block) -> Result<()> {
+        {
+            let utxo = self.utxo.read();
+            utxo.blockchain.check_block_difficulty(&block)?;
+            utxo.blockchain
+                .check_block_timestamp(&block, now_millis()?)?;
+        }
+        #[cfg(feature = "utxo-commitment")]
+        self.check_utxo_commitment(&block)?;
+        self.add_block(block)
+    }
+
+    /// 单个区块的清单是对方通告的新区块，多个区块的清单是对 getblocks 的回复
+    fn handle_inv(&self, msg: Invmsg) -> Result<()> {
+        info!("receive inv msg: {:#?}", msg);
+        self.peer_knows(&msg.addr_from, &msg.items);
+        if msg.kind == "block" {
+            if let [block_hash] = msg.items.as_slice() {
+                // 不改变正在下载的区块
+                if self.get_block(block_hash).is_err() {
+                    self.request_announced(&msg.addr_from, "block", block_hash)?;
+                }
+                return Ok(());
+            }
+            // 清单从最新区块开始，按从旧到新的顺序请求，收到区块时父区块已在本地，可以检查难度
+            let items: Vec<&String> = msg.items.iter().rev().collect();
+            let Some(&block_hash) = items.first() else {
+                return Ok(());
+            };
+            self.send_get_data(&msg.addr_from, "block", block_hash)?;
+
+            let mut new_in_transit = Vec::new();
+            for b in items {
+                if b != block_hash {
+                    new_in_transit.push(b.clone());
+                }
+            }
+            self.replace_in_transit(new_in_transit);
+        } else if msg.kind == "tx" {
+            for txid in &msg.items {
+                // 已有的、在等待前序交易的和最近被拒绝的交易不再下载验证
+                if !self.mempool.lock().knows(txid) {
+                    self.request_announced(&msg.addr_from, "tx", txid)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_get_blocks(&self, msg: GetBlocksmsg) -> Result<()> {
+        info!("receive get blocks msg: {:#?}", msg);
+        let block_hashs = self.get_block_hashs();
+        self.send_inv(&msg.addr_from, "block", block_hashs)?;
+        Ok(())
+    }
+
+    /// 回复主链上接在对方定位哈希之后的至多 MAX_HEADERS 个区块头
+    fn handle_get_headers(&self, msg: GetHeadersmsg) -> Result<()> {
+        info!("receive get headers msg: {:#?}", msg);
+        let headers = self
+            .utxo
+            .read()
+            .blockchain
+            .headers_after(&msg.locator, MAX_HEADERS)?;
+        self.send_headers(&msg.addr_from, headers)
+    }
+
+    /// 逐个验证并保存收到的区块头；收满 MAX_HEADERS 个时继续请求，否则从握手完成的节点
+    /// 同时下载最好的区块头所在分支上缺少的区块
+    ///
+    /// 区块头不能单独验证时，例如对方的区块是记录默克尔根之前创建的，改为下载全部区块
+    fn handle_headers(&self, msg: Headersmsg) -> Result<()> {
+        info!(
+            "receive headers msg: {}, {} headers",
+            msg.addr_from,
+            msg.headers.len()
+        );
+        let added = {
+            let utxo = self.utxo.write();
+            let now = now_millis()?;
+            msg.headers
+                .iter()
+                .try_for_each(|header| utxo.blockchain.add_header(header, now).map(|_| ()))
+        };
+        if let Err(err) = added {
+            error!("reject headers from {}: {}", msg.addr_from, err);
+            return self.send_get_blocks(&msg.addr_from);
+        }
+        if msg.headers.len() == MAX_HEADERS {
+            return self.send_get_headers(&msg.addr_from);
+        }
+        self.download_missing_blocks()
+    }
+
+    /// 发送请求的区块或交易，已经没有时回复 notfound
+    fn handle_get_data(&self, msg: GetDatamsg) -> Result<()> {
+        info!("receive get data msg: {:#?}", msg);
+        if msg.kind == "block" {
+            let Ok(block) = self.get_block(&msg.id) else {
+                return self.send_not_found(&msg.addr_from, "block", &msg.id);
+            };
+            self.peer_knows(&msg.addr_from, std::slice::from_ref(&msg.id));
+            self.send_block(&msg.addr_from, &block)?;
+        } else if msg.kind == "tx" {
+            let Some(tx) = self.get_mempool_tx(&msg.id) else {
+                return self.send_not_found(&msg.addr_from, "tx", &msg.id);
+            };
+            self.peer_knows(&msg.addr_from, std::slice::from_ref(&msg.id));
+            self.send_tx(&msg.addr_from, &tx)?;
+        }
+        Ok(())
+    }
+
+    /// 向另一个通告过这个对象的节点请求；对方不再被当作有这个对象。正在下载的区块交给下载队列
+    /// 改向其他节点请求
+    fn handle_not_found(&self, msg: NotFoundmsg) -> Result<()> {
+        info!("receive not found msg: {:#?}", msg);
+        let now = Instant::now();
+        if msg.kind == "block"
+            && self
+                .inner
+                .lock()
+                .unwrap()
+                .downloads
+                .failed(&msg.id, &msg.addr_from, now)
+        {
+            self.request_blocks();
+            return Ok(());
+        }
+        let other = {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(peer) = inner.peers.get_mut(&msg.addr_from) {
+                peer.known.remove(&msg.id);
+            }
+            if inner
+                .requested
+                .get(&msg.id)
+                .is_none_or(|(from, _)| *from != msg.addr_from)
+            {
+                return Ok(());
+            }
+            inner.requested.remove(&msg.id);
+            inner
+                .peers
+                .iter()
+                .find(|(_, peer)| peer.handshake_complete() && peer.known.contains(&msg.id))
+                .map(|(addr, _)| addr.clone())
+        };
+        if let Some(addr) = other {
+            self.send_get_data(&addr, &msg.kind, &msg.id)?;
+        }
+        Ok(())
+    }
+
+    /// HandleTx 处理从 IP 地址 from 收到的交易
+    fn handle_tx(&self, msg: Txmsg, from: IpAddr) -> Result<()> {
+        info!("receive tx msg: {} {}", msg.addr_from, &msg.transaction.id);
+        self.record_received(&msg.addr_from, "tx", &msg.transaction.id);
+        let added = {
+            let utxo = self.utxo.read();
+            self.mempool
+                .lock()
+                .accept(msg.transaction.clone(), &msg.addr_from, &utxo)
+        };
+        // 孤立交易等前序交易到达后随它一起加入
+        let added = match added {
+            Ok(added) => added,
+            Err(err @ MempoolError::Orphan { .. }) => {
+                info!("{}", err);
+                return Ok(());
+            }
+            Err(err) => {
+                error!("reject transaction {}: {}", msg.transaction.id, err);
+                if matches!(
+                    err,
+                    MempoolError::InvalidTransaction { .. } | MempoolError::Coinbase { .. }
+                ) {
+                    self.misbehaving(from, Misbehavior::InvalidTransaction, &err);
+                }
+                return Ok(());
+            }
+        };
+        self.relay_transactions(&added)
+    }
+
+    /// 把加入交易池的交易通告给还不知道它们的节点，挖矿节点随后把它们挖出
+    ///
+    /// 发来交易的节点已被记为知道这笔交易，不会收到通告
+    fn relay_transactions(&self, txids: &[String]) -> Result<()> {
+        for txid in txids {
+            self.announce("tx", txid)?;
+        }
+        self.mine_mempool()
+    }
+
+    /// 本机钱包发来的交易，接受后记为钱包的交易并立即保存交易池，在同一连接上回复拒绝的原因
+    fn handle_wallet_tx(&self, tx: Transaction, reply: &mut Reply) -> Result<()> {
+        info!("receive wallet tx: {}", tx.id);
+        let added = {
+            let utxo = self.utxo.read();
+            let mut mempool = self.mempool.lock();
+            let added = mempool.accept(tx.clone(), WALLET_PEER, &utxo);
+            if added.is_ok() {
+                mempool.mark_wallet_tx(&tx.id);
+                if let Some(path) = &self.mempool_file
+                    && let Err(err) = mempool.save(path)
+                {
+                    error!("cannot save the mempool: {}", err);
+                }
+            }
+            added
+        };
+        let added = match added {
+            Ok(added) => added,
+            Err(err) => {
+                error!("reject wallet transaction {}: {}", tx.id, err);
+                write_reply(reply, &Some(err.to_string()))?;
+                return Ok(());
+            }
+        };
+        write_reply(reply, &None::<String>)?;
+        // 回复之后再转发，挖矿节点把交易挖出之前钱包不必等待
+        reply.close();
+        self.relay_transactions(&added)
+    }
+
+    /// 向 addr，为 None 时向全部已知节点发送本机钱包尚未确认的交易的 inv
+    fn rebroadcast_wallet_txs(&self, addr: Option<&str>) -> Result<()> {
+        let txids = self.mempool.lock().wallet_txs();
+        if txids.is_empty() {
+            return Ok(());
+        }
+        let nodes = match addr {
+            Some(addr) => vec![addr.to_string()],
+            None => self.get_known_nodes().into_iter().collect(),
+        };
+        info!("rebroadcast {} wallet transactions", txids.len());
+        for node in nodes {
+            if node != self.node_address {
+                for txid in &txids {
+                    self.send_inv(&node, "tx", vec![txid.clone()])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// MineMempool 没有在挖矿时把交易池中的交易打包进新区块，在挖矿线程中做工作量证明，
+    /// 锁定高度未到的交易留在交易池中等待
+    ///
+    /// 工作量证明期间不持有UTXO集合的锁，挖矿线程把结果交回处理任务，由 receive_mined 连接区块、
+    /// 从交易池中删除其中的交易，再接着打包剩下的交易
+    fn mine_mempool(&self) -> Result<()> {
+        if self.mining_address.is_empty()
+            || self.shutdown.is_cancelled()
+            || self.mempool.lock().is_empty()
+        {
+            return Ok(());
+        }
+        let abort = {
+            let inner = self.inner.lock().unwrap();
+            if inner.mining {
+                return Ok(());
+            }
+            Arc::clone(&inner.mining_abort)
+        };
+
+        let height = self.get_best_height()? + 1;
+        // 创币交易的大小与金额无关，带上手续费使其一定有输出
+        let coinbase = Transaction::new_coinbase(
+            self.mining_address.clone(),
+            String::new(),
+            height,
+            1,
+            self.network,
+        )?;
+        let max_bytes = MAX_BLOCK_SIZE - Block::base_size(&coinbase)?;
+        let (mut txs, fees) = self.mempool.lock().take_for_block(height, max_bytes);
+        if txs.is_empty() {
+            return Ok(());
+        }
+        debug!("Mining {} transactions from the mempool", txs.len());
+
+        let cbtx = Transaction::new_coinbase(
+            self.mining_address.clone(),
+            String::new(),
+            height,
+            fees,
+            self.network,
+        )?;
+        // 保持交易池给出的顺序，池中的前序交易排在花费它的交易前面
+        txs.insert(0, cbtx);
+        let (template, threads) = {
+            let utxo = self.utxo.read();
+            (utxo.block_template(txs)?, utxo.blockchain.mining_threads)
+        };
+
+        self.inner.lock().unwrap().mining = true;
+        let server = self.clone();
+        std::thread::spawn(move || {
+            let mined = template.mine_with_abort(threads, &abort);
+            let aborted = abort.load(Ordering::Relaxed);
+            let job = move |server: &Server| {
+                if let Err(err) = server.receive_mined(mined, aborted) {
+                    error!("cannot mine a block: {}", err);
+                }
+            };
+            match server.events() {
+                Some(events) => {
+                    let _ = events.blocking_send(Event::Job(Box::new(job)));
+                }
+                None => server.inner.lock().unwrap().mining = false,
+            }
+        });
+        Ok(())
+    }
+
+    fn handle_mempool_info(&self, reply: &mut Reply) -> Result<()> {
+        let info = self.mempool.lock().info();
+        write_reply(reply, &info)?;
+        Ok(())
+    }
+
+    fn handle_block_count(&self, reply: &mut Reply) -> Result<()> {
+        write_reply(reply, &self.get_best_height()?)
+    }
+
+    fn handle_raw_mempool(&self, reply: &mut Reply) -> Result<()> {
+        let entries = self.mempool.lock().entries_info();
+        write_reply(reply, &entries)?;
+        Ok(())
+    }
+
+    fn handle_mempool_entry(&self, txid: &str, reply: &mut Reply) -> Result<()> {
+        let entry = self.mempool.lock().entry_info(txid);
+        write_reply(reply, &entry)?;
+        Ok(())
+    }
+
+    fn handle_peer_info(&self, reply: &mut Reply) -> Result<()> {
+        write_reply(reply, &self.peers_info())?;
+        Ok(())
+    }
+
+    fn handle_mempool_tx(&self, txid: &str, reply: &mut Reply) -> Result<()> {
+        let tx = self.mempool.lock().get(txid).cloned();
+        write_reply(reply, &tx)?;
+        Ok(())
+    }
+
+    /// 封禁 addr 并断开与它的连接，add 为 false 时解除封禁，在同一连接上回复失败的原因
+    fn handle_set_ban(&self, addr: IpAddr, add: bool, reply: &mut Reply) -> Result<()> {
+        let failure = if add && addr.is_loopback() {
+            Some(format!("cannot ban the loopback address {}", addr))
+        } else if add {
+            info!("ban {}", addr);
+            self.ban(addr);
+            None
+        } else if self.inner.lock().unwrap().bans.unban(addr) {
+            info!("unban {}", addr);
+            None
+        } else {
+            Some(format!("{} is not banned", addr))
+        };
+        self.save_bans();
+        write_reply(reply, &failure)
+    }
+
+    fn handle_list_banned(&self, reply: &mut Reply) -> Result<()> {
+        let bans = self.inner.lock().unwrap().bans.list(unix_time());
+        write_reply(reply, &bans)
+    }
+
+    fn handle_clear_banned(&self, reply: &mut Reply) -> Result<()> {
+        let count = self.inner.lock().unwrap().bans.clear();
+        info!("unban {} addresses", count);
+        self.save_bans();
+        write_reply(reply, &count)
+    }
+
+    /// 保存状态后回复请求方，然后停止节点：不再接受连接并中止全部任务，start_server 随后返回
+    fn handle_stop(&self, reply: &mut Reply) -> Result<()> {
+        info!("receive stop msg");
+        self.save_state()?;
+        write_reply(reply, &"stopped")?;
+        self.shutdown.cancel();
+        Ok(())
+    }
+
+    /// 保存交易池和地址库，把区块链和UTXO集合写入磁盘
+    fn save_state(&self) -> Result<()> {
+        // 持有写锁直到写完，其间没有线程能修改区块链和UTXO集合
+        let utxo = self.utxo.write();
+        if let Some(path) = &self.mempool_file {
+            let count = self.mempool.lock().save(path)?;
+            info!("save {} transactions to {}", count, path.display());
+        }
+        if let Some(path) = &self.peers_file {
+            let count = self.inner.lock().unwrap().addrs.save(path)?;
+            info!("save {} addresses to {}", count, path.display());
+        }
+        utxo.flush()
+    }
+
+    /// ServeConnection 一个接受的连接的任务：依次把连接上的消息帧交给处理任务，
+    /// 把处理时的回复写回连接，直到对方关闭连接。peer 是连接另一端的地址
+    ///
+    /// 一帧处理完之前不读取下一帧，同一连接上的消息按发送的顺序处理。网络标识不对、
+    /// 声明的长度过大、在帧中间断开或超过 READ_TIMEOUT 没有数据时，记录原因后关闭连接；
+    /// 校验和不符时还给 peer 记一次不当行为
+    async fn serve_connection(
+        self,
+        stream: tokio::net::TcpStream,
+        peer: SocketAddr,
+        events: mpsc::Sender<Event>,
+    ) {
+        let mut framed = Framed::new(
+            stream,
+            FrameCodec {
+                network: self.network,
+            },
+        );
+        loop {
+            let frame = match time::timeout(READ_TIMEOUT, framed.next()).await {
+                Ok(Some(Ok(frame))) => frame,
+                Ok(None) => break,
+                Ok(Some(Err(err))) => {
+                    error!("drop the connection: {}", err);
+                    if let FrameError::BadChecksum(_) = err {
+                        self.misbehaving(peer.ip(), Misbehavior::MalformedMessage, &err);
+                    }
+                    break;
+                }
+                Err(_) => {
+                    error!("drop the connection: no data for {:?}", READ_TIMEOUT);
+                    break;
+                }
+            };
+            info!("Accept request: length {}", frame.payload.len());
+            let (reply, mut replies) = Reply::new();
+            let request = Request { frame, peer, reply };
+            if events.send(Event::Frame(request)).await.is_err() {
+                break;
+            }
+            let mut closed = false;
+            while let Some(outgoing) = replies.recv().await {
+                let frame = match outgoing {
+                    Outgoing::Frame(frame) => frame,
+                    Outgoing::Close => {
+                        closed = true;
+                        break;
+                    }
+                };
+                match time::timeout(SEND_TIMEOUT, framed.send(frame)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => {
+                        error!("cannot reply: {}", err);
+                        closed = true;
+                        break;
+                    }
+                    Err(_) => {
+                        error!("cannot reply: no progress for {:?}", SEND_TIMEOUT);
+                        closed = true;
+                        break;
+                    }
+                }
+            }
+            if closed {
+                break;
+            }
+        }
+        self.inner.lock().unwrap().open_connections -= 1;
+    }
+
+    /// 解析并处理连接任务交来的一帧，无法解析时给连接另一端的 peer 记一次不当行为；
+    /// peer 在连接期间被封禁时关闭连接，回环地址发来的本机请求除外
+    fn handle_frame(&self, frame: Frame, peer: SocketAddr, reply: &mut Reply) -> Result<()> {
+        let message = decode_message(&frame);
+        // 旧的封禁列表中可能有回环地址，本机的请求仍然要处理
+        let local = peer.ip().is_loopback() && message.as_ref().is_ok_and(Message::local_request);
+        if !local && self.is_banned(peer.ip()) {
+            info!("close the connection from {}: the address is banned", peer);
+            reply.close();
+            return Ok(());
+        }
+        match message {
+            Ok(cmd) => self.handle_message(cmd, peer, reply),
+            Err(err) => {
+                error!("skip a {} frame: {}", frame.cmd, err);
+                let offense = if err.downcast_ref::<UnknownCommand>().is_some() {
+                    Misbehavior::UnknownMessage
+                } else {
+                    Misbehavior::MalformedMessage
+                };
+                self.misbehaving(peer.ip(), offense, &err);
+                Ok(())
+            }
+        }
+    }
+
+    /// HandleMessage 处理从 peer 收到的消息，本机的请求只接受来自回环地址的连接
+    fn handle_message(&self, cmd: Message, peer: SocketAddr, reply: &mut Reply) -> Result<()> {
+        if cmd.local_request() && !peer.ip().is_loopback() {
+            info!("refuse a local request from {}", peer);
+            reply.close();
+            return Ok(());
+        }
+        if let Some(addr) = cmd.peer_address() {
+            let addr = addr.to_string();
+            let mut inner = self.inner.lock().unwrap();
+            match inner.peers.get_mut(&addr) {
+                Some(peer) if peer.handshake_complete() => {}
+                // 每条消息使用单独的连接，对方完成握手后立即发送的消息可能先于它的 verack 到达，
+                // 已接受对方的 version 时留到 verack 到达之后处理
+                Some(waiting)
+                    if waiting.version.is_some()
+                        && waiting.waiting.len() < MAX_WAITING_MESSAGES =>
+                {
+                    let reply = Reply {
+                        replies: reply.replies.take(),
+                    };
+                    waiting.waiting.push((cmd, peer, reply));
+                    return Ok(());
+                }
+                _ => {
+                    info!("ignore message from {}: handshake not complete", addr);
+                    return Ok(());
+                }
+            }
+            drop(inner);
+            self.peer_active(&addr);
+        }
+
+        match cmd {
+            Message::Addr(data) => self.handle_addr(data)?,
+            Message::GetAddr(data) => self.handle_get_addr(data)?,
+            Message::Block(data) => self.handle_block(data, peer.ip())?,
+            Message::Inv(data) => self.handle_inv(data)?,
+            Message::GetBlock(data) => self.handle_get_blocks(data)?,
+            Message::GetData(data) => self.handle_get_data(data)?,
+            Message::NotFound(data) => self.handle_not_found(data)?,
+            Message::Tx(data) => self.handle_tx(data, peer.ip())?,
+            Message::Version(data) => self.handle_version(data, peer.ip(), reply)?,
+            Message::Ping(data) => self.handle_ping(data, reply)?,
+            Message::GetHeaders(data) => self.handle_get_headers(data)?,
+            Message::Headers(data) => self.handle_headers(data)?,
+            Message::MempoolInfo => self.handle_mempool_info(reply)?,
+            Message::MempoolTx(txid) => self.handle_mempool_tx(&txid, reply)?,
+            Message::WalletTx(tx) => self.handle_wallet_tx(tx, reply)?,
+            Message::RawMempool => self.handle_raw_mempool(reply)?,
+            Message::MempoolEntry(txid) => self.handle_mempool_entry(&txid, reply)?,
+            Message::PeerInfo => self.handle_peer_info(reply)?,
+            Message::BlockCount => self.handle_block_count(reply)?,
+            Message::SetBan(addr, add) => self.handle_set_ban(addr, add, reply)?,
+            Message::ListBanned => self.handle_list_banned(reply)?,
+            Message::ClearBanned => self.handle_clear_banned(reply)?,
+            Message::Stop => self.handle_stop(reply)?,
+        }
+
+        Ok(())
+    }
+}
+
+impl Message {
+    /// PeerAddress 返回节点之间消息的发送方，这些消息在握手完成后才处理；握手消息和本机的请求返回 None
+    fn peer_address(&self) -> Option<&str> {
+        match self {
+            Message::Addr(msg) => Some(&msg.addr_from),
+            Message::Ping(msg) => Some(&msg.addr_from),
+            Message::GetAddr(msg) => Some(&msg.addr_from),
+            Message::Tx(msg) => Some(&msg.addr_from),
+            Message::GetData(msg) => Some(&msg.addr_from),
+            Message::NotFound(msg) => Some(&msg.addr_from),
+            Message::GetBlock(msg) => Some(&msg.addr_from),
+            Message::Inv(msg) => Some(&msg.addr_from),
+            Message::Block(msg) => Some(&msg.addr_from),
+            Message::GetHeaders(msg) => Some(&msg.addr_from),
+            Message::Headers(msg) => Some(&msg.addr_from),
+            Message::Version(_)
+            | Message::MempoolInfo
+            | Message::MempoolTx(_)
+            | Message::WalletTx(_)
+            | Message::RawMempool
+            | Message::MempoolEntry(_)
+            | Message::PeerInfo
+            | Message::BlockCount
+            | Message::SetBan(..)
+            | Message::ListBanned
+            | Message::ClearBanned
+            | Message::Stop => None,
+        }
+    }
+
+    /// LocalRequest 判断消息是否是本机的钱包和命令行发给节点的请求
+    fn local_request(&self) -> bool {
+        matches!(
+            self,
+            Message::MempoolInfo
+                | Message::MempoolTx(_)
+                | Message::WalletTx(_)
+                | Message::RawMempool
+                | Message::MempoolEntry(_)
+                | Message::PeerInfo
+                | Message::BlockCount
+                | Message::SetBan(..)
+                | Message::ListBanned
+                | Message::ClearBanned
+                | Message::Stop
+        )
+    }
+}
+
+impl Peer {
+    fn handshake_complete(&self) -> bool {
+        self.version.is_some() && self.verack_received
+    }
+
+    /// Info 握手完成后返回节点的信息，misbehavior 是各个 IP 地址的不当行为分数
+    fn info(&self, addr: &str, misbehavior: &HashMap<IpAddr, u32>) -> Option<PeerInfo> {
+        if !self.handshake_complete() {
+            return None;
+        }
+        let version = self.version.as_ref()?;
+        Some(PeerInfo {
+            addr: addr.to_string(),
+            version: version.version.min(PROTOCOL_VERSION),
+            user_agent: version.user_agent.clone(),
+            best_height: version.best_height,
+            txs_received: self.txs_received,
+            blocks_received: self.blocks_received,
+            ping_ms: self.ping.map(|ping| ping.as_millis() as u64),
+            idle_secs: self
+                .last_message
+                .map_or(0, |last_message| last_message.elapsed().as_secs()),
+            ban_score: self
+                .ip
+                .and_then(|ip| misbehavior.get(&ip).copied())
+                .unwrap_or(0),
+        })
+    }
+}
+
+impl KnownInventory {
+    /// Insert 记住 id，超出 MAX_KNOWN_INVENTORY 个时忘记最早记住的
+    fn insert(&mut self, id: &str) {
+        if !self.ids.insert(id.to_string()) {
+            return;
+        }
+        self.order.push_back(id.to_string());
+        while self.order.len() > MAX_KNOWN_INVENTORY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+    }
+
+    fn remove(&mut self, id: &str) {
+        if self.ids.remove(id) {
+            self.order.retain(|known| known != id);
+        }
+    }
+
+    fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+}
+
+impl OrphanPool {
+    /// Insert 保存一个孤块，先丢弃超时的孤块；超出数量或字节数上限时丢弃最早收到的孤块
+    fn insert(&mut self, block: Block, size: usize, now: Instant) {
+        self.expire(now);
+        let hash = block.get_hash();
+        if self.blocks.contains_key(&hash) {
+            return;
+        }
+        self.children
+            .entry(block.get_prev_hash())
+            .or_default()
+            .push(hash.clone());
+        self.order.push_back(hash.clone());
+        self.size += size;
+        self.blocks.insert(hash, (block, size, now));
+        while self.blocks.len() > MAX_ORPHANS || self.size > MAX_ORPHAN_BYTES {
+            let Some(oldest) = self.order.front().cloned() else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+    }
+
+    /// Expire 丢弃在 now 之前 ORPHAN_TIMEOUT 以上收到的孤块
+    fn expire(&mut self, now: Instant) {
+        while let Some(hash) = self.order.front().cloned() {
+            if now.duration_since(self.blocks[&hash].2) < ORPHAN_TIMEOUT {
+                break;
+            }
+            self.remove(&hash);
+        }
+    }
+
+    /// TakeChildren 取出父区块为 prev_hash 的全部孤块
+    fn take_children(&mut self, prev_hash: &str) -> Vec<Block> {
+        let hashes = self.children.remove(prev_hash).unwrap_or_default();
+        hashes.iter().filter_map(|hash| self.remove(hash)).collect()
+    }
+
+    fn remove(&mut self, hash: &str) -> Option<Block> {
+        let (block, size, _) = self.blocks.remove(hash)?;
+        self.size -= size;
+        self.order.retain(|h| h != hash);
+        let prev_hash = block.get_prev_hash();
+        if let Some(siblings) = self.children.get_mut(&prev_hash) {
+            siblings.retain(|h| h != hash);
+            if siblings.is_empty() {
+                self.children.remove(&prev_hash);
+            }
+        }
+        Some(block)
+    }
+}
+
+/// Frame 一条消息的命令和内容
+#[derive(Debug, Clone, PartialEq)]
+struct Frame {
+    cmd: String,
+    payload: Vec<u8>,
+}
+
+/// Event 交给处理任务的一项工作
+enum Event {
+    /// 连接任务收到的一帧
+    Frame(Request),
+    /// 其他任务交回、要在处理任务中运行的工作，例如握手和挖矿的结果
+    Job(Box<dyn FnOnce(&Server) + Send>),
+}
+
+/// Outbound 交给一个节点的任务、发给这个节点的消息
+enum Outbound {
+    /// 在单独的连接上发送的一帧，对方不在这个连接上回复
+    Frame(Vec<u8>),
+    /// 本节点的 version 消息，在同一连接上等待对方回复 verack，结果交给处理任务
+    Version(Vec<u8>),
+    /// 带有 nonce 的 ping 消息，在 timeout 内等待对方回复 pong，往返时间交给 pong
+    Ping {
+        data: Vec<u8>,
+        nonce: u64,
+        timeout: Duration,
+        pong: oneshot::Sender<Result<Duration>>,
+    },
+}
+
+/// Request 连接任务交给处理任务的一帧和回复它的方式
+struct Request {
+    frame: Frame,
+    /// 连接另一端的地址
+    peer: SocketAddr,
+    reply: Reply,
+}
+
+/// Reply 处理一帧时在它的连接上回复，回复由连接任务写出
+///
+/// 处理任务不等待连接，回复已满 REPLY_QUEUE_SIZE 帧时回复失败
+struct Reply {
+    replies: Option<mpsc::Sender<Outgoing>>,
+}
+
+/// 处理任务交给连接任务的回复
+enum Outgoing {
+    Frame(Frame),
+    /// 写完之前的回复后关闭连接
+    Close,
+}
+
+impl Reply {
+    /// New 返回回复和连接任务读取回复的一端，处理完这一帧、回复被丢弃时读取结束
+    fn new() -> (Reply, mpsc::Receiver<Outgoing>) {
+        let (replies, receiver) = mpsc::channel(REPLY_QUEUE_SIZE);
+        (
+            Reply {
+                replies: Some(replies),
+            },
+            receiver,
+        )
+    }
+
+    /// Send 在连接上回复命令 cmd 和内容 payload 组成的一帧
+    fn send(&mut self, cmd: &str, payload: Vec<u8>) -> Result<()> {
+        let frame = Frame {
+            cmd: cmd.to_string(),
+            payload,
+        };
+        self.replies
+            .as_ref()
+            .ok_or_else(|| format_err!("the connection is closed"))?
+            .try_send(Outgoing::Frame(frame))
+            .map_err(|err| match err {
+                TrySendError::Full(_) => format_err!("{} replies are waiting", REPLY_QUEUE_SIZE),
+                TrySendError::Closed(_) => format_err!("the connection is closed"),
+            })
+    }
+
+    /// Close 写完已有的回复后关闭连接，对方不必等待之后的处理
+    fn close(&mut self) {
+        if let Some(replies) = self.replies.take() {
+            let _ = replies.try_send(Outgoing::Close);
+        }
+    }
+}
+
+/// FrameCodec 连接任务读写消息帧的编解码器，帧的格式与 encode_frame 和 read_frame 相同
+///
+/// 收到整个帧头、检查网络标识和声明的长度之后才等待内容；校验和不符时返回
+/// FrameError::BadChecksum，由连接任务给对方记一次不当行为
+struct FrameCodec {
+    /// 帧头中的网络标识属于该网络
+    network: Network,
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = FrameError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> std::result::Result<Option<Frame>, FrameError> {
+        if buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let header = parse_header(&buf[..HEADER_LEN], self.network)?;
+        if buf.len() < HEADER_LEN + header.len {
+            buf.reserve(HEADER_LEN + header.len - buf.len());
+            return Ok(None);
+        }
+        buf.advance(HEADER_LEN);
+        let payload = buf.split_to(header.len).to_vec();
+        if base58::checksum(&payload) != header.checksum {
+            return Err(FrameError::BadChecksum(header.cmd));
+        }
+        Ok(Some(Frame {
+            cmd: header.cmd,
+            payload,
+        }))
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> std::result::Result<Option<Frame>, FrameError> {
+        match self.decode(buf)? {
+            Some(frame) => Ok(Some(frame)),
+            None if buf.is_empty() => Ok(None),
+            None => Err(FrameError::Truncated),
+        }
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = FrameError;
+
+    fn encode(&mut self, frame: Frame, buf: &mut BytesMut) -> std::result::Result<(), FrameError> {
+        buf.extend_from_slice(&encode_frame(self.network, &frame.cmd, &frame.payload));
+        Ok(())
+    }
+}
+
+/// 每隔 period 把 job 交给处理任务运行一次，上一次运行结束之前不交下一次；处理任务停止时返回
+async fn handle_every(
+    events: mpsc::Sender<Event>,
+    period: Duration,
+    mut job: impl FnMut(&Server) + Send + 'static,
+) {
+    let mut interval = time::interval_at(time::Instant::now() + period, period);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let (done, finished) = oneshot::channel();
+        let run = move |server: &Server| {
+            job(server);
+            let _ = done.send(job);
+        };
+        if events.send(Event::Job(Box::new(run))).await.is_err() {
+            return;
+        }
+        job = match finished.await {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+    }
+}
+
+/// 连接 addr 上的节点，发送 data 后关闭写入的一侧；连接和写入最多等待 timeout
+async fn send_frame(addr: &str, data: &[u8], timeout: Duration) -> Result<tokio::net::TcpStream> {
+    let send = async {
+        let mut stream = tokio::net::TcpStream::connect(addr).await?;
+        stream.write_all(data).await?;
+        stream.shutdown().await?;
+        Ok::<_, std::io::Error>(stream)
+    };
+    match time::timeout(timeout, send).await {
+        Ok(stream) => Ok(stream?),
+        Err(_) => Err(format_err!("no progress for {:?}", timeout)),
+    }
+}
+
+/// 向 addr 上的节点发送 data，在同一连接上读取对方的回复，对方不回复就关闭连接时返回 None；
+/// 发送和读取各最多等待 timeout
+async fn exchange(
+    addr: &str,
+    data: &[u8],
+    timeout: Duration,
+    network: Network,
+) -> Result<Option<Frame>> {
+    let stream = send_frame(addr, data, timeout).await?;
+    let mut framed = Framed::new(stream, FrameCodec { network });
+    match time::timeout(timeout, framed.next()).await {
+        Ok(Some(frame)) => Ok(Some(frame?)),
+        Ok(None) => Ok(None),
+        Err(_) => Err(format_err!("no reply for {:?}", timeout)),
+    }
+}
+
+/// 每隔 period 在阻塞线程中运行一次 job，上一次运行结束之前不开始下一次
+async fn every(period: Duration, mut job: impl FnMut() + Send + 'static) {
+    let mut interval = time::interval_at(time::Instant::now() + period, period);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        job = match task::spawn_blocking(move || {
+            job();
+            job
+        })
+        .await
+        {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+    }
+}
+
+/// FrameError 读取消息帧失败的原因
+#[derive(Debug)]
+enum FrameError {
+    /// 帧头中的网络标识不是本节点所在的网络，后者为本节点所在的网络
+    WrongMagic([u8; MAGIC_LEN], Network),
+    /// 帧头声明的内容字节数超过 MAX_PAYLOAD_SIZE
+    TooLarge(usize),
+    /// 内容与帧头中的校验和不符，连接任务给对方记一次不当行为后关闭连接
+    BadChecksum(String),
+    /// 连接在一帧的中间关闭
+    Truncated,
+    Io(std::io::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::WrongMagic(magic, network) => write!(
+                f,
+                "network magic {} does not match {}",
+                hex::encode(magic),
+                hex::encode(network.magic())
+            ),
+            FrameError::TooLarge(len) => write!(
+                f,
+                "payload of {} bytes is larger than {} bytes",
+                len, MAX_PAYLOAD_SIZE
+            ),
+            FrameError::BadChecksum(cmd) => {
+                write!(f, "checksum of the {} payload does not match", cmd)
+            }
+            FrameError::Truncated => write!(f, "connection closed in the middle of a frame"),
+            FrameError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<std::io::Error> for FrameError {
+    fn from(err: std::io::Error) -> Self {
+        FrameError::Io(err)
+    }
+}
+
+/// UnknownCommand 消息帧的命令不是本节点支持的命令
+#[derive(Debug)]
+struct UnknownCommand(String);
+
+impl fmt::Display for UnknownCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown command {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownCommand {}
+
+/// 区块本身无效，而不是本节点的UTXO集合或存储出了问题
+fn is_invalid_block(err: &failure::Error) -> bool {
+    err.downcast_ref::<BlockValidationError>()
+        .is_some_and(|err| {
+            !matches!(
+                err,
+                BlockValidationError::UtxoTipMismatch { .. } | BlockValidationError::Storage { .. }
+            )
+        })
+}
+
+/// 用 cmd 和 data 序列化后的内容组成 network 上的一帧
+fn encode_message(network: Network, cmd: &str, data: &impl Serialize) -> Result<Vec<u8>> {
+    Ok(encode_frame(network, cmd, &serialize(data)?))
+}
+
+fn encode_frame(network: Network, cmd: &str, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend(network.magic());
+    frame.extend(cmd_to_bytes(cmd));
+    frame.extend((payload.len() as u32).to_le_bytes());
+    frame.extend(base58::checksum(payload));
+    frame.extend(payload);
+    frame
+}
+
+/// ReadFrame 读取 network 上的一帧，对方在帧与帧之间关闭连接时返回 None
+///
+/// 先读完整个帧头，检查网络标识和声明的长度之后才按长度分配内容的空间，
+/// 内容的校验和在解析之前检查。每次读取的字节可能不足一帧，读满为止
+fn read_frame(
+    reader: &mut impl Read,
+    network: Network,
+) -> std::result::Result<Option<Frame>, FrameError> {
+    let mut header = [0; HEADER_LEN];
+    let mut read = 0;
+    while read < HEADER_LEN {
+        match reader.read(&mut header[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(FrameError::Truncated),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(FrameError::Io(err)),
+        }
+    }
+    let FrameHeader { cmd, len, checksum } = parse_header(&header, network)?;
+
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).map_err(|err| {
+        if err.kind() == std::io::ErrorKind::UnexpectedEof {
+            FrameError::Truncated
+        } else {
+            FrameError::Io(err)
+        }
+    })?;
+    if base58::checksum(&payload) != checksum {
+        return Err(FrameError::BadChecksum(cmd));
+    }
+    Ok(Some(Frame { cmd, payload }))
+}
+
+/// 帧头中的命令、内容的字节数和校验和
+struct FrameHeader {
+    cmd: String,
+    len: usize,
+    checksum: [u8; CHECKSUM_LEN],
+}
+
+/// 解析 HEADER_LEN 字节的帧头，检查网络标识属于 network 和声明的长度
+fn parse_header(header: &[u8], network: Network) -> std::result::Result<FrameHeader, FrameError> {
+    let (magic, rest) = header.split_at(MAGIC_LEN);
+    let (cmd, rest) = rest.split_at(CMD_LEN);
+    let (len, checksum) = rest.split_at(4);
+    let magic: [u8; MAGIC_LEN] = magic.try_into().unwrap();
+    if magic != network.magic() {
+        return Err(FrameError::WrongMagic(magic, network));
+    }
+    let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+    if len > MAX_PAYLOAD_SIZE {
+        return Err(FrameError::TooLarge(len));
+    }
+    let cmd: Vec<u8> = cmd.iter().copied().filter(|b| *b != 0).collect();
+    Ok(FrameHeader {
+        cmd: String::from_utf8_lossy(&cmd).into_owned(),
+        len,
+        checksum: checksum.try_into().unwrap(),
+    })
+}
+
+/// 解析消息内容，内容中声明的长度不能让解析读取或分配超过内容本身的字节数
+fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T> {
+    Ok(DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+        .with_limit(payload.len() as u64)
+        .deserialize(payload)?)
+}
+
+/// 在请求的连接上回复 data
+fn write_reply(reply: &mut Reply, data: &impl Serialize) -> Result<()> {
+    reply.send("reply", serialize(data)?)
+}
+
+/// LocalNode 本机的命令连接的节点
+#[derive(Clone, Debug)]
+pub struct LocalNode {
+    /// 节点的 host:port 地址
+    pub addr: String,
+    /// 节点所在的网络
+    pub network: Network,
+}
+
+impl LocalNode {
+    /// SendTransaction 把本机钱包的交易交给本机的节点，节点拒绝时返回原因
+    ///
+    /// 节点接受后把它记为本机钱包的交易，定期重新广播直到被确认
+    pub fn send_transaction(&self, tx: &Transaction) -> Result<()> {
+        let reply: Option<String> = decode(&self.request("wallettx", &serialize(tx)?)?)?;
+        match reply {
+            Some(err) => Err(format_err!(
+                "The node rejected transaction {}: {}",
+                tx.id,
+                err
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// GetRawMempool 向本机的节点查询其交易池中的全部交易，按加入的顺序排列
+    pub fn get_raw_mempool(&self) -> Result<Vec<MempoolEntryInfo>> {
+        decode(&self.request("rawmempool", &[])?)
+    }
+
+    /// GetMempoolEntry 向本机的节点查询其交易池中的一笔交易，不在池中时返回 None
+    pub fn get_mempool_entry(&self, txid: &str) -> Result<Option<MempoolEntryInfo>> {
+        let payload = serialize(&txid.to_string())?;
+        decode(&self.request("mempoolentry", &payload)?)
+    }
+
+    /// GetMempoolInfo 向本机的节点查询其交易池概况
+    pub fn get_mempool_info(&self) -> Result<MempoolInfo> {
+        decode(&self.request("mempoolinfo", &[])?)
+    }
+
+    /// GetMempoolTransaction 向本机的节点查询其交易池中的交易，不在池中时返回 None
+    pub fn get_mempool_transaction(&self, txid: &str) -> Result<Option<Transaction>> {
+        let payload = serialize(&txid.to_string())?;
+        decode(&self.request("mempooltx", &payload)?)
+    }
+
+    /// GetPeerInfo 向本机的节点查询与它完成握手的节点，按地址排列
+    pub fn get_peer_info(&self) -> Result<Vec<PeerInfo>> {
+        decode(&self.request("peerinfo", &[])?)
+    }
+
+    /// GetBlockCount 向本机的节点查询其最新区块的高度
+    pub fn get_block_count(&self) -> Result<i32> {
+        decode(&self.request("blockcount", &[])?)
+    }
+
+    /// SetBan 让本机的节点封禁 IP 地址 addr 并断开它上面的节点，add 为 false 时解除封禁
+    pub fn set_ban(&self, addr: IpAddr, add: bool) -> Result<()> {
+        let payload = serialize(&(addr, add))?;
+        let reply: Option<String> = decode(&self.request("setban", &payload)?)?;
+        match reply {
+            Some(err) => Err(format_err!("{}", err)),
+            None => Ok(()),
+        }
+    }
+
+    /// ListBanned 向本机的节点查询仍在封禁中的地址，按地址排列
+    pub fn list_banned(&self) -> Result<Vec<BanEntry>> {
+        decode(&self.request("listbanned", &[])?)
+    }
+
+    /// ClearBanned 让本机的节点解除全部封禁，返回解除的地址数
+    pub fn clear_banned(&self) -> Result<usize> {
+        decode(&self.request("clearbanned", &[])?)
+    }
+
+    /// StopNode 让本机的节点保存交易池、把数据写入磁盘后退出
+    pub fn stop_node(&self) -> Result<()> {
+        self.request("stop", &[])?;
+        Ok(())
+    }
+
+    /// 向本机的节点发送命令和内容 payload，返回节点在同一连接上回复的内容
+    fn request(&self, cmd: &str, payload: &[u8]) -> Result<Vec<u8>> {
+        let addr = &self.addr;
+        let mut stream = connect_peer(addr, READ_TIMEOUT)
+            .map_err(|e| format_err!("Cannot connect to the node at {}: {}", addr, e))?;
+        stream.write_all(&encode_frame(self.network, cmd, payload))?;
+        // 关闭写入的一侧，节点处理完这一帧后在同一连接上回复
+        stream.shutdown(Shutdown::Write)?;
+        match read_frame(&mut stream, self.network)? {
+            Some(frame) => Ok(frame.payload),
+            None => Err(format_err!(
+                "The node at {} closed the connection without a reply",
+                addr
+            )),
+        }
+    }
+}
+
+/// 连接 addr 上的节点，连接以及之后的每次读取和写入最多等待 timeout
+fn connect_peer(addr: &str, timeout: Duration) -> std::io::Result<TcpStream> {
+    let mut last_err = None;
+    for socket in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket, timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok(stream);
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            format!("{} has no address", addr),
+        )
+    }))
+}
+
+/// 本节点在握手时报告的用户代理：程序名和版本
+fn user_agent() -> String {
+    format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+/// 新节点首先连接的已知节点，监听 network 的默认端口
+fn known_node(network: Network) -> String {
+    format!("localhost:{}", network.default_port())
+}
+
+fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
+    let mut data = [0; CMD_LEN];
+    for (i, d) in cmd.as_bytes().iter().enumerate() {
+        data[i] = *d;
+    }
+    data
+}
+
+fn decode_message(frame: &Frame) -> Result<Message> {
+    let cmd = frame.cmd.as_str();
+    let data = &frame.payload;
+    info!("cmd: {}", cmd);
+
+    if cmd == "addr" {
+        let data: Addrmsg = decode(data)?;
+        Ok(Message::Addr(data))
+    } else if cmd == "getaddr" {
+        let data: GetAddrmsg = decode(data)?;
+        Ok(Message::GetAddr(data))
+    } else if cmd == "block" {
+        let data: Blockmsg = decode(data)?;
+        Ok(Message::Block(data))
+    } else if cmd == "inv" {
+        let data: Invmsg = decode(data)?;
+        Ok(Message::Inv(data))
+    } else if cmd == "getblocks" {
+        let data: GetBlocksmsg = decode(data)?;
+        Ok(Message::GetBlock(data))
+    } else if cmd == "getheaders" {
+        let data: GetHeadersmsg = decode(data)?;
+        Ok(Message::GetHeaders(data))
+    } else if cmd == "headers" {
+        let data: Headersmsg = decode(data)?;
+        Ok(Message::Headers(data))
+    } else if cmd == "getdata" {
+        let data: GetDatamsg = decode(data)?;
+        Ok(Message::GetData(data))
+    } else if cmd == "notfound" {
+        let data: NotFoundmsg = decode(data)?;
+        Ok(Message::NotFound(data))
+    } else if cmd == "tx" {
+        let data: Txmsg = decode(data)?;
+        Ok(Message::Tx(data))
+    } else if cmd == "version" {
+        let data: Versionmsg = decode(data)?;
+        Ok(Message::Version(data))
+    } else if cmd == "ping" {
+        let data: Pingmsg = decode(data)?;
+        Ok(Message::Ping(data))
+    } else if cmd == "mempoolinfo" {
+        Ok(Message::MempoolInfo)
+    } else if cmd == "mempooltx" {
+        let txid: String = decode(data)?;
+        Ok(Message::MempoolTx(txid))
+    } else if cmd == "wallettx" {
+        let tx: Transaction = decode(data)?;
+        Ok(Message::WalletTx(tx))
+    } else if cmd == "rawmempool" {
+        Ok(Message::RawMempool)
+    } else if cmd == "mempoolentry" {
+        let txid: String = decode(data)?;
+        Ok(Message::MempoolEntry(txid))
+    } else if cmd == "peerinfo" {
+        Ok(Message::PeerInfo)
+    } else if cmd == "blockcount" {
+        Ok(Message::BlockCount)
+    } else if cmd == "setban" {
+        let (addr, add): (IpAddr, bool) = decode(data)?;
+        Ok(Message::SetBan(addr, add))
+    } else if cmd == "listbanned" {
+        Ok(Message::ListBanned)
+    } else if cmd == "clearbanned" {
+        Ok(Message::ClearBanned)
+    } else if cmd == "stop" {
+        Ok(Message::Stop)
+    } else {
+        Err(UnknownCommand(cmd.to_string()).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::banlist::DEFAULT_BAN_SCORE;
+    use crate::walletstorage::memory::MemoryStorage;
+    use crate::blockchain::*;
+    use crate::wallets::*;
+    use std::collections::BTreeMap;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::thread;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn test_cmd() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let mut bc = Blockchain::in_memory();
+        let cbtx = Transaction::new_coinbase(wa1, String::new(), 0, 0, Network::Mainnet).unwrap();
+        bc.add_block(Block::new_genesis_block(cbtx)).unwrap();
+        let utxo_set = UTXOSet::in_memory(bc);
+        let server = Server::new("7878", "localhost:3001", utxo_set).unwrap();
+
+        let vmsg = Versionmsg {
+            addr_from: server.node_address.clone(),
+            best_height: server.get_best_height().unwrap(),
+            version: PROTOCOL_VERSION,
+            user_agent: user_agent(),
+            magic: Network::Mainnet.magic(),
+            nonce: server.nonce,
+        };
+        let read = |data: Vec<u8>| {
+            read_frame(&mut data.as_slice(), Network::Mainnet)
+                .unwrap()
+                .unwrap()
+        };
+        let frame = read(encode_message(Network::Mainnet, "version", &vmsg).unwrap());
+        if let Message::Version(v) = decode_message(&frame).unwrap() {
+            assert_eq!(v, vmsg);
+        } else {
+            panic!("wrong!");
+        }
+
+        let headers = server
+            .utxo
+            .read()
+            .blockchain
+            .headers_after(&[], MAX_HEADERS)
+            .unwrap();
+        let hmsg = Headersmsg {
+            addr_from: server.node_address.clone(),
+            headers: headers.clone(),
+        };
+        let frame = read(encode_message(Network::Mainnet, "headers", &hmsg).unwrap());
+        match decode_message(&frame).unwrap() {
+            Message::Headers(h) => assert_eq!(h.headers, headers),
+            _ => panic!("wrong!"),
+        }
+        let frame = read(encode_message(Network::Mainnet, "nosuchcmd", &hmsg).unwrap());
+        assert!(decode_message(&frame).is_err());
+    }
+
+    /// 每次最多读出一个字节，模拟分成多次到达的数据
+    struct OneByteReader<'a>(&'a [u8]);
+
+    impl Read for OneByteReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if buf.is_empty() || self.0.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn test_local_requests() {
+        assert!(Message::MempoolInfo.local_request());
+        assert!(Message::MempoolTx(String::new()).local_request());
+        assert!(Message::RawMempool.local_request());
+        assert!(Message::MempoolEntry(String::new()).local_request());
+        assert!(Message::PeerInfo.local_request());
+        assert!(Message::SetBan(IpAddr::from([192, 0, 2, 1]), true).local_request());
+        assert!(Message::ListBanned.local_request());
+        assert!(Message::ClearBanned.local_request());
+        assert!(Message::Stop.local_request());
+        let getblocks = GetBlocksmsg {
+            addr_from: String::new(),
+        };
+        assert!(!Message::GetBlock(getblocks).local_request());
+    }
+
+    #[test]
+    fn test_read_frame() {
+        let frame = |cmd: &str, payload: &[u8]| Frame {
+            cmd: cmd.to_string(),
+            payload: payload.to_vec(),
+        };
+        let largest = vec![7; MAX_PAYLOAD_SIZE];
+        let mut data = encode_frame(Network::Mainnet, "block", &largest);
+        data.extend(encode_frame(Network::Mainnet, "getaddr", b"payload"));
+        let mut reader = data.as_slice();
+        assert_eq!(
+            read_frame(&mut reader, Network::Mainnet).unwrap(),
+            Some(frame("block", &largest))
+        );
+        assert_eq!(
+            read_frame(&mut reader, Network::Mainnet).unwrap(),
+            Some(frame("getaddr", b"payload"))
+        );
+        assert_eq!(read_frame(&mut reader, Network::Mainnet).unwrap(), None);
+
+        let data = encode_frame(Network::Mainnet, "tx", b"some payload");
+        let mut reader = OneByteReader(&data);
+        assert_eq!(
+            read_frame(&mut reader, Network::Mainnet).unwrap(),
+            Some(frame("tx", b"some payload"))
+        );
+        assert_eq!(read_frame(&mut reader, Network::Mainnet).unwrap(), None);
+
+        // 在帧中间断开
+        for len in 1..data.len() {
+            let err = read_frame(&mut &data[..len], Network::Mainnet).unwrap_err();
+            assert!(matches!(err, FrameError::Truncated), "{}", err);
+        }
+
+        // 声明的长度过大时不读取内容
+        let mut oversized = encode_frame(Network::Mainnet, "block", &[]);
+        let len = MAGIC_LEN + CMD_LEN;
+        oversized[len..len + 4].copy_from_slice(&(MAX_PAYLOAD_SIZE as u32 + 1).to_le_bytes());
+        let err = read_frame(&mut oversized.as_slice(), Network::Mainnet).unwrap_err();
+        assert!(matches!(err, FrameError::TooLarge(_)), "{}", err);
+
+        let mut wrong_network = data.clone();
+        wrong_network[0] ^= 1;
+        let err = read_frame(&mut wrong_network.as_slice(), Network::Mainnet).unwrap_err();
+        assert!(matches!(err, FrameError::WrongMagic(..)), "{}", err);
+
+        let mut corrupt = data.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        let err = read_frame(&mut corrupt.as_slice(), Network::Mainnet).unwrap_err();
+        assert!(matches!(err, FrameError::BadChecksum(_)), "{}", err);
+    }
+
+    #[test]
+    fn test_frame_codec() {
+        let frame = |cmd: &str, payload: &[u8]| Frame {
+            cmd: cmd.to_string(),
+            payload: payload.to_vec(),
+        };
+        let mut data = encode_frame(Network::Mainnet, "tx", b"some payload");
+        let mut corrupt = encode_frame(Network::Mainnet, "block", b"corrupt");
+        *corrupt.last_mut().unwrap() ^= 1;
+        data.extend(corrupt);
+        data.extend(encode_frame(Network::Mainnet, "inv", b"next"));
+
+        // 每次只收到一个字节，校验和不符的帧返回错误
+        let mut codec = FrameCodec {
+            network: Network::Mainnet,
+        };
+        let mut buf = BytesMut::new();
+        let mut frames = Vec::new();
+        let mut bytes = data.iter();
+        let err = loop {
+            buf.extend_from_slice(&[*bytes.next().unwrap()]);
+            match codec.decode(&mut buf) {
+                Ok(Some(frame)) => frames.push(frame),
+                Ok(None) => {}
+                Err(err) => break err,
+            }
+        };
+        assert!(
+            matches!(&err, FrameError::BadChecksum(cmd) if cmd == "block"),
+            "{}",
+            err
+        );
+        assert_eq!(frames, vec![frame("tx", b"some payload")]);
+
+        // 在帧中间断开，或者网络标识不对
+        let mut truncated = BytesMut::from(&data[..HEADER_LEN + 1]);
+        assert!(matches!(
+            codec.decode_eof(&mut truncated),
+            Err(FrameError::Truncated)
+        ));
+        let mut wrong_network = BytesMut::from(&data[..]);
+        wrong_network[0] ^= 1;
+        assert!(matches!(
+            codec.decode(&mut wrong_network),
+            Err(FrameError::WrongMagic(..))
+        ));
+
+        let mut encoded = BytesMut::new();
+        codec.encode(frames.remove(0), &mut encoded).unwrap();
+        assert_eq!(
+            &encoded[..],
+            &encode_frame(Network::Mainnet, "tx", b"some payload")[..]
+        );
+    }
+
+    #[test]
+    fn test_bad_checksum_is_misbehavior() {
+        let server = test_server("7972");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut corrupt = encode_frame(Network::Mainnet, "tx", b"some payload");
+        *corrupt.last_mut().unwrap() ^= 1;
+        client.write_all(&corrupt).unwrap();
+
+        // 连接另一端按不是回环地址的 peer 计分
+        let peer: SocketAddr = "192.0.2.9:40000".parse().unwrap();
+        server.inner.lock().unwrap().open_connections = 1;
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            let (requests, _) = mpsc::channel(1);
+            server
+                .clone()
+                .serve_connection(stream, peer, requests)
+                .await;
+        });
+        assert_eq!(
+            server.inner.lock().unwrap().misbehavior.get(&peer.ip()),
+            Some(&Misbehavior::MalformedMessage.default_score())
+        );
+        // 连接已被关闭
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_decode_random_bytes() {
+        use rand::Rng;
+        let mut rng = rand::thread_rng();
+        let cmds = [
+            "addr",
+            "getaddr",
+            "block",
+            "inv",
+            "getblocks",
+            "getheaders",
+            "headers",
+            "getdata",
+            "notfound",
+            "tx",
+            "version",
+            "ping",
+            "mempoolinfo",
+            "mempooltx",
+            "wallettx",
+            "rawmempool",
+            "mempoolentry",
+            "peerinfo",
+            "blockcount",
+            "setban",
+            "listbanned",
+            "clearbanned",
+            "stop",
+            "unknown",
+        ];
+        for n in 0..5000 {
+            let len = rng.gen_range(0..300);
+            let mut payload: Vec<u8> = (0..len).map(|_| rng.r#gen()).collect();
+            // 随机的字节不是有效的帧，读取返回错误而不是 panic
+            assert!(
+                read_frame(&mut payload.as_slice(), Network::Mainnet).is_err()
+                    || payload.is_empty()
+            );
+
+            // 校验和正确但内容随机的帧无法解析时返回错误
+            let cmd = cmds[n % cmds.len()];
+            if n % 3 == 0 {
+                // 像长度前缀那样的大数
+                payload.splice(0..0, u64::MAX.to_le_bytes());
+            }
+            let data = encode_frame(Network::Mainnet, cmd, &payload);
+            let frame = read_frame(&mut data.as_slice(), Network::Mainnet)
+                .unwrap()
+                .unwrap();
+            let _ = decode_message(&frame);
+            let cut = rng.gen_range(0..data.len());
+            assert!(
+                read_frame(&mut &data[..cut], Network::Mainnet)
+                    .unwrap_or(None)
+                    .is_none()
+            );
+        }
+    }
+
+    #[test]
+    fn test_mining_restarts_on_new_tip() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let receiver = ws.create_wallet();
+        let wallet = ws.get_wallet(&miner).unwrap().clone();
+        let mut bc = Blockchain::in_memory();
+        let cbtx = |height| {
+            Transaction::new_coinbase(miner.clone(), String::new(), height, 0, Network::Mainnet)
+        };
+        // 创世区块的难度极高，接在它之后的区块几乎不可能挖出
+        let genesis =
+            Block::new_unmined_block_at(vec![cbtx(0).unwrap()], String::new(), 0, 0, 0x1a00_ffff)
+                .unwrap();
+        bc.add_block(genesis.clone()).unwrap();
+        let utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        let tx =
+            Transaction::new_utxo(&wallet, &receiver, 3, &TxOptions::default(), &utxo_set).unwrap();
+        // 挖矿线程把结果交回运行中节点的处理任务
+        let server = spawn_node(Server::new("7879", &miner, utxo_set).unwrap());
+        let utxo = server.utxo.read();
+        server.mempool.lock().add(tx.clone(), &utxo).unwrap();
+        drop(utxo);
+
+        server.mine_mempool().unwrap();
+        thread::sleep(Duration::from_millis(200));
+        // 同一高度上难度很低的竞争区块，挖矿应改为接在它之后
+        let competing = Block::new_unmined_block_at(
+            vec![cbtx(1).unwrap()],
+            genesis.get_hash(),
+            1,
+            1,
+            REGTEST_BITS,
+        )
+        .unwrap()
+        .mine()
+        .unwrap();
+        server.add_block(competing.clone()).unwrap();
+        server.utxo_update().unwrap();
+        assert!(wait_until(|| server.get_best_height().unwrap() == 2));
+
+        let utxo = server.utxo.read();
+        let tip = utxo.blockchain.get_block(&utxo.blockchain.tip).unwrap();
+        assert_eq!(tip.get_prev_hash(), competing.get_hash());
+        assert_eq!(tip.get_height(), 2);
+        assert!(tip.get_transaction().iter().any(|t| t.id == tx.id));
+        assert!(server.mempool.lock().is_empty());
+    }
+
+    #[test]
+    fn test_add_block_rejects_invalid_blocks() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let receiver = ws.create_wallet();
+        let wallet = ws.get_wallet(&miner).unwrap().clone();
+        let mut bc = Blockchain::in_memory();
+        let cbtx = |height, data: &str| {
+            Transaction::new_coinbase(miner.clone(), data.to_string(), height, 0, Network::Mainnet)
+                .unwrap()
+        };
+        let genesis = Block::new_unmined_block(vec![cbtx(0, "")], String::new(), 0).unwrap();
+        bc.add_block(genesis.clone()).unwrap();
+        let utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        let tx =
+            Transaction::new_utxo(&wallet, &receiver, 3, &TxOptions::default(), &utxo_set).unwrap();
+        let mut forged = tx.clone();
+        forged.vin[0].signature[0] ^= 1;
+        let server = Server::new("7881", &miner, utxo_set).unwrap();
+        let tip = || server.utxo.read().tip().unwrap();
+
+        let main = Block::new_unmined_block(vec![cbtx(1, "")], genesis.get_hash(), 1).unwrap();
+        server.add_block(main.clone()).unwrap();
+        assert_eq!(tip(), (main.get_hash(), 1));
+
+        // 接在最新区块上的无效区块不会保存
+        let invalid = Block::new_unmined_block(
+            vec![cbtx(2, ""), tx.clone(), tx.clone()],
+            main.get_hash(),
+            2,
+        )
+        .unwrap();
+        assert!(server.add_block(invalid.clone()).is_err());
+        assert!(server.get_block(&invalid.get_hash()).is_err());
+        assert_eq!(tip(), (main.get_hash(), 1));
+
+        // 含有无效区块的侧链超过主链时回到原来的最新区块
+        let side =
+            Block::new_unmined_block(vec![cbtx(1, "side"), forged], genesis.get_hash(), 1).unwrap();
+        server.add_block(side.clone()).unwrap();
+        let longer = Block::new_unmined_block(vec![cbtx(2, "side 2")], side.get_hash(), 2).unwrap();
+        assert!(server.add_block(longer).is_err());
+        assert_eq!(tip(), (main.get_hash(), 1));
+        assert_eq!(server.get_best_height().unwrap(), 1);
+        let utxo = server.utxo.read();
+        assert_eq!(utxo.blockchain.tip, main.get_hash());
+        assert!(utxo.blockchain.get_block_by_height(2).is_err());
+        assert!(utxo.verify_against_chain().unwrap().is_consistent());
+    }
+
+    #[test]
+    fn test_reorganize_to_more_work() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let receiver = ws.create_wallet();
+        let wallet = ws.get_wallet(&miner).unwrap().clone();
+        let mut bc = Blockchain::in_memory();
+        let cbtx = |height, data: &str| {
+            Transaction::new_coinbase(miner.clone(), data.to_string(), height, 0, Network::Mainnet)
+                .unwrap()
+        };
+        let genesis = Block::new_unmined_block(vec![cbtx(0, "")], String::new(), 0).unwrap();
+        bc.add_block(genesis.clone()).unwrap();
+        let utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        let tx =
+            Transaction::new_utxo(&wallet, &receiver, 3, &TxOptions::default(), &utxo_set).unwrap();
+        let server = Server::new("7882", &miner, utxo_set).unwrap();
+        let balance = || server.utxo.read().get_balance(&receiver).unwrap();
+
+        let main =
+            Block::new_unmined_block(vec![cbtx(1, ""), tx.clone()], genesis.get_hash(), 1).unwrap();
+        server.add_block(main.clone()).unwrap();
+        assert_eq!(balance(), 3);
+
+        // 工作量相同的侧链只保存
+        let side = Block::new_unmined_block(vec![cbtx(1, "side")], genesis.get_hash(), 1).unwrap();
+        server.add_block(side.clone()).unwrap();
+        assert_eq!(server.utxo.read().tip().unwrap().0, main.get_hash());
+        assert!(server.get_block(&side.get_hash()).is_ok());
+
+        // 侧链更长时切换过去，旧分支上的交易回到交易池
+        let longer = Block::new_unmined_block(vec![cbtx(2, "side 2")], side.get_hash(), 2).unwrap();
+        server.add_block(longer.clone()).unwrap();
+        let utxo = server.utxo.read();
+        assert_eq!(utxo.blockchain.tip, longer.get_hash());
+        assert_eq!(utxo.tip().unwrap(), (longer.get_hash(), 2));
+        assert!(utxo.verify_against_chain().unwrap().is_consistent());
+        drop(utxo);
+        assert_eq!(balance(), 0);
+        let mempool = server.mempool.lock();
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.get(&tx.id), Some(&tx));
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let receiver = ws.create_wallet();
+        let wallet = ws.get_wallet(&miner).unwrap().clone();
+        let cbtx = |height, data: &str| {
+            Transaction::new_coinbase(miner.clone(), data.to_string(), height, 0, Network::Mainnet)
+                .unwrap()
+        };
+        let genesis = Block::new_unmined_block(vec![cbtx(0, "")], String::new(), 0).unwrap();
+        let new_server = |port: &str| {
+            let mut bc = Blockchain::in_memory();
+            bc.add_block(genesis.clone()).unwrap();
+            let utxo_set = UTXOSet::in_memory(bc);
+            utxo_set.reindex().unwrap();
+            Server::new(port, &miner, utxo_set).unwrap()
+        };
+        let server = new_server("7884");
+        let tx = Transaction::new_utxo(
+            &wallet,
+            &receiver,
+            3,
+            &TxOptions::default(),
+            &server.utxo.read(),
+        )
+        .unwrap();
+        let main1 = Block::new_unmined_block(vec![cbtx(1, ""), tx], genesis.get_hash(), 1).unwrap();
+        let main2 = Block::new_unmined_block(vec![cbtx(2, "")], main1.get_hash(), 2).unwrap();
+        let side1 =
+            Block::new_unmined_block(vec![cbtx(1, "side 1")], genesis.get_hash(), 1).unwrap();
+        let side2 = Block::new_unmined_block(vec![cbtx(2, "side 2")], side1.get_hash(), 2).unwrap();
+        let side3 = Block::new_unmined_block(vec![cbtx(3, "side 3")], side2.get_hash(), 3).unwrap();
+        for block in [&main1, &main2, &side1, &side2] {
+            server.add_block(block.clone()).unwrap();
+        }
+        let checkpoints = BTreeMap::from([(1, main1.get_hash())]);
+        server.utxo.write().blockchain.checkpoints = checkpoints.clone();
+
+        // 断开检查点上的区块的重组被拒绝
+        let err = server.add_block(side3).unwrap_err();
+        assert!(err.to_string().contains("checkpoint"), "{}", err);
+        assert_eq!(server.utxo.read().tip().unwrap(), (main2.get_hash(), 2));
+        assert_eq!(server.utxo.read().blockchain.tip, main2.get_hash());
+        let other =
+            Block::new_unmined_block(vec![cbtx(1, "other")], genesis.get_hash(), 1).unwrap();
+        let err = server.add_block(other).unwrap_err();
+        assert!(err.to_string().contains("does not match"), "{}", err);
+        let fork = Block::new_unmined_block(vec![cbtx(0, "fork")], String::new(), 0).unwrap();
+        let err = server.add_block(fork).unwrap_err();
+        assert!(err.to_string().contains("below the checkpoint"), "{}", err);
+
+        // 同步时检查点之前的区块不验证签名，之后的区块照常验证
+        let syncing = new_server("7885");
+        syncing.utxo.write().blockchain.checkpoints = checkpoints;
+        let verifications = || SIGNATURE_VERIFICATIONS.with(|count| count.get());
+        let before = verifications();
+        syncing.add_block(main1).unwrap();
+        assert_eq!(verifications(), before);
+        syncing.add_block(main2).unwrap();
+        assert_eq!(verifications(), before + 1);
+    }
+
+    #[test]
+    fn test_orphan_pool() {
+        let block = |prev: &str| Block::new_unmined_block(Vec::new(), prev.to_string(), 1).unwrap();
+        let now = Instant::now();
+        let mut pool = OrphanPool::default();
+        for n in 0..=MAX_ORPHANS {
+            pool.insert(block(&format!("parent {}", n)), 1, now);
+        }
+        // 超出数量上限时丢弃最早收到的孤块
+        assert_eq!(pool.blocks.len(), MAX_ORPHANS);
+        assert!(pool.take_children("parent 0").is_empty());
+        let children = pool.take_children("parent 1");
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].get_prev_hash(), "parent 1");
+        assert_eq!(pool.blocks.len(), MAX_ORPHANS - 1);
+
+        // 超时的孤块在下次保存时丢弃
+        pool.insert(block("late"), 1, now + ORPHAN_TIMEOUT);
+        assert_eq!(pool.blocks.len(), 1);
+        assert_eq!(pool.size, 1);
+
+        pool.insert(block("large"), MAX_ORPHAN_BYTES / 2, now + ORPHAN_TIMEOUT);
+        pool.insert(block("larger"), MAX_ORPHAN_BYTES / 2, now + ORPHAN_TIMEOUT);
+        assert!(pool.take_children("late").is_empty());
+        assert_eq!(pool.take_children("larger").len(), 1);
+    }
+
+    #[test]
+    fn test_orphan_blocks_out_of_order() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let mut bc = Blockchain::in_memory();
+        let start = now_millis().unwrap() - 10_000;
+        let cbtx = |height| {
+            Transaction::new_coinbase(miner.clone(), String::new(), height, 0, Network::Mainnet)
+        };
+        let genesis = Block::new_unmined_block_at(
+            vec![cbtx(0).unwrap()],
+            String::new(),
+            0,
+            start,
+            INITIAL_BITS,
+        )
+        .unwrap();
+        bc.add_block(genesis.clone()).unwrap();
+        let utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        let server = Server::new("7883", &miner, utxo_set).unwrap();
+
+        let mut blocks = vec![genesis];
+        for height in 1..=5 {
+            let prev = blocks.last().unwrap().get_hash();
+            let timestamp = start + height as u128;
+            let block = Block::new_unmined_block_at(
+                vec![cbtx(height).unwrap()],
+                prev,
+                height,
+                timestamp,
+                INITIAL_BITS,
+            )
+            .unwrap()
+            .mine()
+            .unwrap();
+            blocks.push(block);
+        }
+        for block in blocks[1..].iter().rev() {
+            let msg = Blockmsg {
+                addr_from: "localhost:1".to_string(),
+                block: block.clone(),
+            };
+            server.handle_block(msg, LOCALHOST).unwrap();
+        }
+        assert_eq!(server.get_best_height().unwrap(), 5);
+        assert_eq!(server.utxo.read().tip().unwrap().0, blocks[5].get_hash());
+        assert!(server.inner.lock().unwrap().orphans.blocks.is_empty());
+    }
+
+    /// 在本进程中启动只有创世区块的节点
+    fn start_test_node(port: &str) -> Arc<Server> {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let cbtx = Transaction::new_coinbase(miner, String::new(), 0, 0, Network::Mainnet).unwrap();
+        start_node_with_genesis(port, &Block::new_genesis_block(cbtx))
+    }
+
+    fn start_node_with_genesis(port: &str, genesis: &Block) -> Arc<Server> {
+        spawn_node(server_with_genesis(port, genesis))
+    }
+
+    /// 使用内存中的区块链、还没有启动的节点
+    fn test_server(port: &str) -> Server {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let cbtx =
+            Transaction::new_coinbase(ws.create_wallet(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        server_with_genesis(port, &Block::new_genesis_block(cbtx))
+    }
+
+    fn server_with_genesis(port: &str, genesis: &Block) -> Server {
+        let mut bc = Blockchain::in_memory();
+        bc.add_block(genesis.clone()).unwrap();
+        let utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        Server::new(port, "", utxo_set).unwrap()
+    }
+
+    fn spawn_node(server: Server) -> Arc<Server> {
+        let server = Arc::new(server);
+        let node = Arc::clone(&server);
+        thread::spawn(move || node.start_server());
+        thread::sleep(Duration::from_millis(200));
+        server
+    }
+
+    fn wait_until(condition: impl Fn() -> bool) -> bool {
+        for _ in 0..100 {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    /// 向 addr 发送一条消息，返回对方在同一连接上的回复
+    fn send_raw<T: Serialize>(addr: &str, cmd: &str, msg: &T) -> Option<Frame> {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(&encode_message(Network::Mainnet, cmd, msg).unwrap())
+            .unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        read_frame(&mut stream, Network::Mainnet).unwrap()
+    }
+
+    #[test]
+    fn test_version_handshake() {
+        let a = start_test_node("7886");
+        let b = start_test_node("7887");
+        a.connect(&b.node_address).unwrap();
+        assert!(wait_until(
+            || a.peers_info().len() == 1 && b.peers_info().len() == 1
+        ));
+
+        let peer = |addr: &str| PeerInfo {
+            addr: addr.to_string(),
+            version: PROTOCOL_VERSION,
+            user_agent: user_agent(),
+            best_height: 0,
+            txs_received: 0,
+            blocks_received: 0,
+            ping_ms: None,
+            idle_secs: 0,
+            ban_score: 0,
+        };
+        assert_eq!(a.peers_info(), vec![peer(&b.node_address)]);
+        assert_eq!(b.peers_info(), vec![peer(&a.node_address)]);
+        assert!(user_agent().starts_with("rust_camp_project_blockchain/"));
+        assert!(b.get_known_nodes().contains(&a.node_address));
+
+        // 协议版本较低的节点使用双方都支持的版本
+        let mut inner = b.inner.lock().unwrap();
+        let state = inner.peers.get_mut(&a.node_address).unwrap();
+        state.version.as_mut().unwrap().version = PROTOCOL_VERSION + 1;
+        drop(inner);
+        assert_eq!(
+            b.peer_info(&a.node_address).unwrap().version,
+            PROTOCOL_VERSION
+        );
+    }
+
+    #[test]
+    fn test_addr_gossip() {
+        let a = start_test_node("7891");
+        let b = start_test_node("7892");
+        let c = start_test_node("7893");
+        a.connect(&b.node_address).unwrap();
+        assert!(wait_until(|| b.peer_info(&a.node_address).is_some()));
+
+        // C 只连接了 B，从 B 回复的 addr 消息得知 A，连接管理随后连接 A
+        c.connect(&b.node_address).unwrap();
+        assert!(wait_until(|| c
+            .inner
+            .lock()
+            .unwrap()
+            .addrs
+            .contains(&a.node_address)));
+        assert!(c.peer_info(&a.node_address).is_none());
+        c.connect_to_peers().unwrap();
+        assert!(wait_until(
+            || c.peer_info(&a.node_address).is_some() && a.peer_info(&c.node_address).is_some()
+        ));
+        let outbound = c
+            .inner
+            .lock()
+            .unwrap()
+            .peers
+            .values()
+            .filter(|p| p.outbound)
+            .count();
+        assert_eq!(outbound, 2);
+        assert!(!c.inner.lock().unwrap().addrs.contains(&c.node_address));
+    }
+
+    #[test]
+    fn test_transaction_relay() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let owner = ws.create_wallet();
+        let receiver = ws.create_wallet();
+        let wallet = ws.get_wallet(&owner).unwrap().clone();
+        let cbtx = Transaction::new_coinbase(owner, String::new(), 0, 0, Network::Mainnet).unwrap();
+        let genesis = Block::new_genesis_block(cbtx);
+        let nodes = ["7894", "7895", "7896"].map(|port| start_node_with_genesis(port, &genesis));
+        let [a, b, c] = &nodes;
+        b.connect(&a.node_address).unwrap();
+        c.connect(&a.node_address).unwrap();
+        c.connect(&b.node_address).unwrap();
+        assert!(wait_until(|| nodes
+            .iter()
+            .all(|n| n.peers_info().len() == 2)));
+
+        let tx =
+            Transaction::new_utxo(&wallet, &receiver, 3, &TxOptions::default(), &a.utxo.read())
+                .unwrap();
+        let reply = send_raw(&a.node_address, "wallettx", &tx).unwrap();
+        assert_eq!(decode::<Option<String>>(&reply.payload).unwrap(), None);
+        assert!(wait_until(|| nodes
+            .iter()
+            .all(|n| n.get_mempool_tx(&tx.id).is_some())));
+        // 等待可能重复的通告和下载
+        thread::sleep(Duration::from_millis(500));
+
+        // B 和 C 都只下载了一次完整的交易，A 没有收到它自己发出的交易
+        let received = |node: &Server| -> Vec<usize> {
+            node.peers_info().iter().map(|p| p.txs_received).collect()
+        };
+        assert_eq!(received(a), vec![0, 0]);
+        for node in [b, c] {
+            let received = received(node);
+            assert!(received.iter().all(|n| *n <= 1), "{:?}", received);
+            assert_eq!(received.iter().sum::<usize>(), 1);
+        }
+        let inner = a.inner.lock().unwrap();
+        assert!(inner.peers.values().all(|peer| peer.known.contains(&tx.id)));
+        drop(inner);
+
+        // 请求的对象不存在时对方回复 notfound，请求随之结束
+        b.send_get_data(&a.node_address, "tx", "missing").unwrap();
+        assert!(wait_until(|| !b
+            .inner
+            .lock()
+            .unwrap()
+            .requested
+            .contains_key("missing")));
+    }
+
+    #[test]
+    fn test_ping_disconnects_dead_peers() {
+        let a = start_test_node("7897");
+        let b = start_test_node("7898");
+        a.connect(&b.node_address).unwrap();
+        assert!(wait_until(|| b.peer_info(&a.node_address).is_some()));
+        a.ping_peers(Duration::from_secs(5));
+        let info = a.peer_info(&b.node_address).unwrap();
+        assert!(info.ping_ms.is_some());
+        assert_eq!(info.idle_secs, 0);
+
+        // 只接受连接、从不回复的节点
+        let dead = "localhost:7899";
+        let _listener = TcpListener::bind(dead).unwrap();
+        let version = Versionmsg {
+            addr_from: dead.to_string(),
+            version: PROTOCOL_VERSION,
+            best_height: 0,
+            user_agent: "other/1.0.0".to_string(),
+            magic: Network::Mainnet.magic(),
+            nonce: 0,
+        };
+        a.inner.lock().unwrap().peers.insert(
+            dead.to_string(),
+            Peer {
+                version: Some(version),
+                version_sent: true,
+                verack_received: true,
+                ..Peer::default()
+            },
+        );
+        let timeout = Duration::from_millis(200);
+        let start = Instant::now();
+        a.ping_peers(timeout);
+        assert_eq!(a.inner.lock().unwrap().peers[dead].missed_pings, 1);
+        assert!(a.peer_info(dead).is_some());
+        a.ping_peers(timeout);
+        assert!(a.peer_info(dead).is_none());
+        assert!(start.elapsed() < Duration::from_secs(2));
+        // 回复 pong 的节点不受影响
+        assert_eq!(
+            a.inner.lock().unwrap().peers[&b.node_address].missed_pings,
+            0
+        );
+        assert!(a.peer_info(&b.node_address).is_some());
+    }
+
+    /// 在 addr 上监听、只回复 verack 的节点
+    fn spawn_mock_peer(addr: &str) {
+        let listener = TcpListener::bind(addr).unwrap();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                if let Ok(Some(frame)) = read_frame(&mut stream, Network::Mainnet)
+                    && frame.cmd == "version"
+                {
+                    let _ = stream.write_all(&encode_frame(Network::Mainnet, "verack", &[]));
+                }
+            }
+        });
+    }
+
+    /// 地址为 addr 的节点发给 server 的 version 消息
+    fn mock_version(server: &Server, addr: &str) -> Versionmsg {
+        Versionmsg {
+            addr_from: addr.to_string(),
+            version: PROTOCOL_VERSION,
+            best_height: 0,
+            user_agent: "mock/1.0.0".to_string(),
+            magic: Network::Mainnet.magic(),
+            nonce: server.nonce.wrapping_add(1),
+        }
+    }
+
+    /// peer 发给 server 的一帧得到的回复，连接被关闭之后的回复不计入；
+    /// 处理出错时像 serve_connection 一样关闭连接
+    fn handle_from(server: &Server, cmd: &str, payload: Vec<u8>, peer: &str) -> Vec<String> {
+        let frame = Frame {
+            cmd: cmd.to_string(),
+            payload,
+        };
+        let (mut reply, mut replies) = Reply::new();
+        if server
+            .handle_frame(frame, peer.parse().unwrap(), &mut reply)
+            .is_err()
+        {
+            reply.close();
+        }
+        drop(reply);
+        let mut frames = Vec::new();
+        while let Ok(Outgoing::Frame(frame)) = replies.try_recv() {
+            frames.push(frame.cmd);
+        }
+        frames
+    }
+
+    #[test]
+    fn test_misbehaving_peer_is_banned() {
+        let server = start_test_node("7900");
+        let mock = "localhost:7901";
+        spawn_mock_peer(mock);
+        // 回环地址不记分数，消息按来自另一个地址的连接处理
+        let peer = "192.0.2.7:40000";
+        let version = serialize(&mock_version(&server, mock)).unwrap();
+        assert_eq!(
+            handle_from(&server, "version", version.clone(), peer),
+            vec!["verack"]
+        );
+        assert!(wait_until(|| server.peer_info(mock).is_some()));
+        let score = || server.peer_info(mock).map(|peer| peer.ban_score);
+
+        let sender = serialize(&GetAddrmsg {
+            addr_from: mock.to_string(),
+        })
+        .unwrap();
+        for _ in 0..3 {
+            handle_from(&server, "nosuchcmd", sender.clone(), peer);
+        }
+        let unknown = Misbehavior::UnknownMessage.default_score();
+        assert_eq!(score(), Some(3 * unknown));
+        // 命令已知但内容无法解析
+        handle_from(&server, "block", sender, peer);
+        let malformed = Misbehavior::MalformedMessage.default_score();
+        assert_eq!(score(), Some(3 * unknown + malformed));
+
+        // 难度不对的区块
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let cbtx =
+            Transaction::new_coinbase(ws.create_wallet(), String::new(), 1, 0, Network::Mainnet)
+                .unwrap();
+        let tip = server.utxo.read().blockchain.tip.clone();
+        let block =
+            Block::new_unmined_block_at(vec![cbtx], tip, 1, now_millis().unwrap(), 0x1a00_ffff)
+                .unwrap();
+        let msg = Blockmsg {
+            addr_from: mock.to_string(),
+            block,
+        };
+        handle_from(&server, "block", serialize(&msg).unwrap(), peer);
+        assert!(server.peer_info(mock).is_none());
+        let bans = server.inner.lock().unwrap().bans.list(unix_time());
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].addr, "192.0.2.7".parse::<IpAddr>().unwrap());
+
+        // 封禁按连接的地址而不是声明的地址：同一 IP 地址再发来的消息不被处理，连接被关闭，
+        // 换一个声明的地址也不能握手；本节点也不主动连接这个地址上的节点
+        let other = serialize(&mock_version(&server, "localhost:7968")).unwrap();
+        for msg in [version, other] {
+            assert!(handle_from(&server, "version", msg, peer).is_empty());
+        }
+        assert!(server.peer_info("localhost:7968").is_none());
+        server.connect("192.0.2.7:7901").unwrap();
+        assert!(
+            !server
+                .inner
+                .lock()
+                .unwrap()
+                .peers
+                .contains_key("192.0.2.7:7901")
+        );
+    }
+
+    #[test]
+    fn test_misbehavior_addresses_are_bounded() {
+        let server = test_server("7971");
+        let first = IpAddr::from([198, 51, 100, 1]);
+        server.misbehaving(first, Misbehavior::InvalidBlock, &"test");
+        for i in 0..MAX_MISBEHAVING_ADDRS as u32 {
+            let addr = IpAddr::from((0x0a00_0000 + i).to_be_bytes());
+            server.misbehaving(addr, Misbehavior::UnknownMessage, &"test");
+        }
+        let inner = server.inner.lock().unwrap();
+        assert_eq!(inner.misbehavior.len(), MAX_MISBEHAVING_ADDRS);
+        assert_eq!(
+            inner.misbehavior.get(&first),
+            Some(&Misbehavior::InvalidBlock.default_score())
+        );
+    }
+
+    #[test]
+    fn test_loopback_is_never_banned() {
+        let server = test_server("7969");
+        let peer = "127.0.0.1:40000";
+        let banned = || server.inner.lock().unwrap().bans.list(unix_time());
+        for _ in 0..2 * DEFAULT_BAN_SCORE {
+            handle_from(&server, "nosuchcmd", Vec::new(), peer);
+        }
+        server.misbehaving(LOCALHOST, Misbehavior::InvalidProofOfWork, &"test");
+        server.ban(IpAddr::V6(Ipv6Addr::LOCALHOST));
+        assert!(server.inner.lock().unwrap().misbehavior.is_empty());
+        assert!(banned().is_empty());
+
+        // setban 拒绝封禁回环地址
+        for addr in [LOCALHOST, IpAddr::V6(Ipv6Addr::LOCALHOST)] {
+            let ban = serialize(&(addr, true)).unwrap();
+            assert_eq!(handle_from(&server, "setban", ban, peer), vec!["reply"]);
+        }
+        assert!(banned().is_empty());
+
+        // 旧的封禁列表中的回环地址仍然可以发来本机的请求，其他消息被拒绝
+        server
+            .inner
+            .lock()
+            .unwrap()
+            .bans
+            .ban(LOCALHOST, unix_time());
+        assert_eq!(
+            handle_from(&server, "peerinfo", Vec::new(), peer),
+            vec!["reply"]
+        );
+        let version = serialize(&mock_version(&server, "localhost:7970")).unwrap();
+        assert!(handle_from(&server, "version", version, peer).is_empty());
+    }
+
+    #[test]
+    fn test_local_requests_from_loopback_only() {
+        let server = test_server("7966");
+        let handle =
+            |cmd: &str, payload: Vec<u8>, peer: &str| handle_from(&server, cmd, payload, peer);
+        let ban = serialize(&("192.0.2.1".parse::<IpAddr>().unwrap(), true)).unwrap();
+
+        for peer in ["10.0.0.1:40000", "[2001:db8::1]:40000"] {
+            assert!(handle("blockcount", Vec::new(), peer).is_empty());
+            assert!(handle("setban", ban.clone(), peer).is_empty());
+            assert!(handle("stop", Vec::new(), peer).is_empty());
+        }
+        assert!(
+            server
+                .inner
+                .lock()
+                .unwrap()
+                .bans
+                .list(unix_time())
+                .is_empty()
+        );
+        assert!(!server.shutdown.is_cancelled());
+
+        assert_eq!(
+            handle("blockcount", Vec::new(), "127.0.0.1:40000"),
+            vec!["reply"]
+        );
+        assert_eq!(handle("setban", ban, "127.0.0.1:40000"), vec!["reply"]);
+        assert_eq!(server.inner.lock().unwrap().bans.list(unix_time()).len(), 1);
+        assert_eq!(handle("stop", Vec::new(), "[::1]:40000"), vec!["reply"]);
+        assert!(server.shutdown.is_cancelled());
+    }
+
+    #[test]
+    fn test_inbound_limit_and_connect_list() {
+        let (first, second) = ("localhost:7903", "localhost:7904");
+        spawn_mock_peer(first);
+        spawn_mock_peer(second);
+
+        let mut server = test_server("7902");
+        server.set_connection_options(ConnectionOptions {
+            max_inbound: 1,
+            ..ConnectionOptions::default()
+        });
+        let server = spawn_node(server);
+        let verack = send_raw(
+            &server.node_address,
+            "version",
+            &mock_version(&server, first),
+        );
+        assert_eq!(verack.unwrap().cmd, "verack");
+        assert!(wait_until(|| server.peer_info(first).is_some()));
+        // 已有一个对方发起的节点，不再接受新的
+        let version = mock_version(&server, second);
+        assert!(send_raw(&server.node_address, "version", &version).is_none());
+        assert!(server.peer_info(second).is_none());
+        // 已经握手的节点可以重新握手
+        let verack = send_raw(
+            &server.node_address,
+            "version",
+            &mock_version(&server, first),
+        );
+        assert_eq!(verack.unwrap().cmd, "verack");
+
+        // 只接受和连接指定的节点，不从地址库中选择节点
+        let mut server = test_server("7905");
+        server.set_connection_options(ConnectionOptions {
+            connect: vec![first.to_string()],
+            ..ConnectionOptions::default()
+        });
+        assert!(server.get_known_nodes().is_empty());
+        let server = spawn_node(server);
+        let version = mock_version(&server, second);
+        assert!(send_raw(&server.node_address, "version", &version).is_none());
+        server.inner.lock().unwrap().addrs.seen(second, unix_time());
+        // 本节点发起连接，模拟的节点只回复 verack，握手停在等待它的 version
+        server.connect_to_peers().unwrap();
+        assert!(wait_until(|| {
+            let inner = server.inner.lock().unwrap();
+            inner
+                .peers
+                .get(first)
+                .is_some_and(|peer| peer.outbound && peer.verack_received)
+        }));
+        assert!(!server.inner.lock().unwrap().peers.contains_key(second));
+    }
+
+    #[test]
+    fn test_connection_limit_and_port_in_use() {
+        let mut server = test_server("7906");
+        server.set_connection_options(ConnectionOptions {
+            max_connections: 1,
+            ..ConnectionOptions::default()
+        });
+        let server = spawn_node(server);
+        // 占用唯一的连接，之后的连接被接受后立即关闭
+        let idle = TcpStream::connect(&server.node_address).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let mut closed = TcpStream::connect(&server.node_address).unwrap();
+        closed
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert!(matches!(closed.read(&mut [0]), Ok(0)));
+        drop(idle);
+        assert!(wait_until(
+            || server.inner.lock().unwrap().open_connections == 0
+        ));
+        assert!(send_raw(&server.node_address, "peerinfo", &()).is_some());
+
+        let err = test_server("7906").start_server().unwrap_err();
+        assert!(err.to_string().contains("already in use"), "{}", err);
+    }
+
+    #[test]
+    fn test_many_concurrent_peers() {
+        const PEERS: usize = 50;
+        let mut server = test_server("7907");
+        server.set_connection_options(ConnectionOptions {
+            max_inbound: PEERS,
+            ..ConnectionOptions::default()
+        });
+        let server = spawn_node(server);
+        let mocks: Vec<String> = (0..PEERS)
+            .map(|n| format!("localhost:{}", 7910 + n))
+            .collect();
+        // 空闲的连接只占用一个任务，不妨碍处理其他连接上的消息
+        let idle: Vec<TcpStream> = (0..PEERS)
+            .map(|_| TcpStream::connect(&server.node_address).unwrap())
+            .collect();
+
+        // 全部节点同时握手
+        let handshakes: Vec<_> = mocks
+            .iter()
+            .map(|mock| {
+                spawn_mock_peer(mock);
+                let version = mock_version(&server, mock);
+                let addr = server.node_address.clone();
+                thread::spawn(move || send_raw(&addr, "version", &version).map(|frame| frame.cmd))
+            })
+            .collect();
+        for handshake in handshakes {
+            assert_eq!(handshake.join().unwrap().as_deref(), Some("verack"));
+        }
+        assert!(wait_until(|| server.peers_info().len() == PEERS));
+
+        // 全部节点同时 ping，每个节点收到自己的 pong
+        let pings: Vec<_> = mocks
+            .iter()
+            .enumerate()
+            .map(|(n, mock)| {
+                let ping = Pingmsg {
+                    addr_from: mock.clone(),
+                    nonce: n as u64,
+                };
+                let addr = server.node_address.clone();
+                thread::spawn(move || {
+                    let pong = send_raw(&addr, "ping", &ping).unwrap();
+                    assert_eq!(pong.cmd, "pong");
+                    decode::<u64>(&pong.payload).unwrap()
+                })
+            })
+            .collect();
+        for (n, ping) in pings.into_iter().enumerate() {
+            assert_eq!(ping.join().unwrap(), n as u64);
+        }
+        // 发送 ping 的连接关闭之后只剩空闲的连接
+        assert!(wait_until(
+            || server.inner.lock().unwrap().open_connections == PEERS
+        ));
+        drop(idle);
+        assert!(wait_until(
+            || server.inner.lock().unwrap().open_connections == 0
+        ));
+    }
+
+    #[test]
+    fn test_known_inventory() {
+        let mut known = KnownInventory::default();
+        for n in 0..=MAX_KNOWN_INVENTORY {
+            known.insert(&n.to_string());
+        }
+        known.insert("1");
+        assert!(!known.contains("0"));
+        assert!(known.contains("1"));
+        assert!(known.contains(&MAX_KNOWN_INVENTORY.to_string()));
+        assert_eq!(known.order.len(), MAX_KNOWN_INVENTORY);
+        known.remove("1");
+        assert!(!known.contains("1"));
+        assert_eq!(known.order.len(), MAX_KNOWN_INVENTORY - 1);
+    }
+
+    #[test]
+    fn test_handshake_rejections() {
+        let server = start_test_node("7888");
+        let peer = "localhost:7889";
+        let version = Versionmsg {
+            addr_from: peer.to_string(),
+            version: PROTOCOL_VERSION,
+            best_height: 0,
+            user_agent: "other/1.0.0".to_string(),
+            magic: Network::Mainnet.magic(),
+            nonce: server.nonce.wrapping_add(1),
+        };
+        let rejected = [
+            Versionmsg {
+                magic: Network::Testnet.magic(),
+                ..version.clone()
+            },
+            Versionmsg {
+                nonce: server.nonce,
+                ..version.clone()
+            },
+            Versionmsg {
+                version: MIN_PROTOCOL_VERSION - 1,
+                ..version.clone()
+            },
+        ];
+        // 拒绝时不回复 verack 就关闭连接
+        for msg in rejected {
+            assert!(send_raw(&server.node_address, "version", &msg).is_none());
+            assert!(!server.inner.lock().unwrap().peers.contains_key(peer));
+        }
+
+        // 握手完成之前的其他消息不处理
+        let addr = Addrmsg {
+            addr_from: peer.to_string(),
+            addrs: vec![PeerAddr {
+                addr: "localhost:7890".to_string(),
+                last_seen: unix_time(),
+            }],
+        };
+        assert!(send_raw(&server.node_address, "addr", &addr).is_none());
+        assert!(
+            !server
+                .inner
+                .lock()
+                .unwrap()
+                .peers
+                .get(peer)
+                .is_some_and(Peer::handshake_complete)
+        );
+        assert!(
+            !server
+                .inner
+                .lock()
+                .unwrap()
+                .addrs
+                .contains("localhost:7890")
+        );
+        assert!(server.peers_info().is_empty());
+    }
+
+    /// 从创世区块开始共 n + 1 个区块的链，难度为 POW_LIMIT_BITS，时间戳间隔 TARGET_BLOCK_TIME，
+    /// 难度不会调整，挖矿几乎不耗时
+    fn easy_chain(n: usize) -> Vec<Block> {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let miner = ws.create_wallet();
+        let start = now_millis().unwrap() - (n as u128 + 1) * TARGET_BLOCK_TIME;
+        let mut blocks: Vec<Block> = Vec::new();
+        for height in 0..=n as i32 {
+            let prev = blocks.last().map_or(String::new(), Block::get_hash);
+            let cbtx = Transaction::new_coinbase(
+                miner.clone(),
+                String::new(),
+                height,
+                0,
+                Network::Mainnet,
+            )
+            .unwrap();
+            let timestamp = start + height as u128 * TARGET_BLOCK_TIME;
+            let block =
+                Block::new_unmined_block_at(vec![cbtx], prev, height, timestamp, POW_LIMIT_BITS)
+                    .unwrap();
+            blocks.push(if height == 0 {
+                block
+            } else {
+                block.mine().unwrap()
+            });
+        }
+        blocks
+    }
+
+    /// 在 addr 上监听、有 blocks 中全部区块的节点：回复 version 和 getheaders，
+    /// 收到 getdata 后等待 latency 再送出区块，模拟网络的往返时间
+    fn spawn_serving_peer(addr: &str, blocks: Arc<Vec<Block>>, latency: Duration) {
+        let listener = TcpListener::bind(addr).unwrap();
+        let addr = addr.to_string();
+        let deliver = |to: &str, frame: Vec<u8>| {
+            if let Ok(mut stream) = TcpStream::connect(to) {
+                let _ = stream.write_all(&frame);
+            }
+        };
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let Ok(Some(frame)) = read_frame(&mut stream, Network::Mainnet) else {
+                    continue;
+                };
+                match frame.cmd.as_str() {
+                    "version" => {
+                        let _ = stream.write_all(&encode_frame(Network::Mainnet, "verack", &[]));
+                    }
+                    "getheaders" => {
+                        let msg: GetHeadersmsg = decode(&frame.payload).unwrap();
+                        let headers = Headersmsg {
+                            addr_from: addr.clone(),
+                            headers: blocks[1..].iter().map(|b| b.header().clone()).collect(),
+                        };
+                        deliver(
+                            &msg.addr_from,
+                            encode_message(Network::Mainnet, "headers", &headers).unwrap(),
+                        );
+                    }
+                    "getdata" => {
+                        let msg: GetDatamsg = decode(&frame.payload).unwrap();
+                        let Some(block) = blocks.iter().find(|b| b.get_hash() == msg.id) else {
+                            continue;
+                        };
+                        let block = Blockmsg {
+                            addr_from: addr.clone(),
+                            block: block.clone(),
+                        };
+                        thread::spawn(move || {
+                            thread::sleep(latency);
+                            deliver(
+                                &msg.addr_from,
+                                encode_message(Network::Mainnet, "block", &block).unwrap(),
+                            );
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    /// 只有创世区块的节点从 mocks 上的节点下载 blocks，返回用时和各节点送来的区块数
+    fn timed_sync(port: &str, mocks: &[&str], blocks: &Arc<Vec<Block>>) -> (Duration, Vec<usize>) {
+        let latency = Duration::from_millis(200);
+        let node = start_node_with_genesis(port, &blocks[0]);
+        let best_height = blocks.len() as i32 - 1;
+        let start = Instant::now();
+        for mock in mocks {
+            spawn_serving_peer(mock, Arc::clone(blocks), latency);
+            let version = Versionmsg {
+                best_height,
+                ..mock_version(&node, mock)
+            };
+            let verack = send_raw(&node.node_address, "version", &version).unwrap();
+            assert_eq!(verack.cmd, "verack");
+        }
+        while node.get_best_height().unwrap() < best_height {
+            assert!(
+                start.elapsed() < Duration::from_secs(120),
+                "only {} of {} blocks downloaded",
+                node.get_best_height().unwrap(),
+                best_height
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+        let elapsed = start.elapsed();
+        assert_eq!(
+            node.utxo.read().blockchain.tip,
+            blocks.last().unwrap().get_hash()
+        );
+        let served = mocks
+            .iter()
+            .map(|mock| node.peer_info(mock).unwrap().blocks_received)
+            .collect();
+        (elapsed, served)
+    }
+
+    #[test]
+    fn test_parallel_block_download() {
+        const BLOCKS: usize = 500;
+        let blocks = Arc::new(easy_chain(BLOCKS));
+        let (single, served) = timed_sync("7960", &["localhost:7961"], &blocks);
+        assert_eq!(served, vec![BLOCKS]);
+
+        // 三个节点各下载一部分，请求中的区块是单个节点的三倍
+        let mocks = ["localhost:7963", "localhost:7964", "localhost:7965"];
+        let (parallel, served) = timed_sync("7962", &mocks, &blocks);
+        assert!(served.iter().all(|count| *count > 0), "{:?}", served);
+        assert_eq!(served.iter().sum::<usize>(), BLOCKS);
+        assert!(
+            parallel < single,
+            "three peers took {:?}, one peer took {:?}",
+            parallel,
+            single
+        );
     }
 }
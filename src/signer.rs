@@ -0,0 +1,154 @@
+//! transaction signers
+
+use super::*;
+use crate::wallets::verify_signature;
+use failure::format_err;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Signer 持有私钥、能对消息签名的一方，私钥不必在本进程中
+pub trait Signer {
+    /// PublicKey 返回签名者的 ed25519 公钥
+    fn public_key(&self) -> &[u8];
+
+    /// Sign 对消息进行 ed25519 签名，签名者拒绝签名时返回错误
+    fn sign(&self, msg: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// ExternalSigner 由外部命令签名，私钥可以保存在硬件令牌、远程服务或另一个钱包中
+///
+/// 命令后附加一个参数表示请求：pubkey 时输出十六进制公钥；sign 时从标准输入读取消息，
+/// 输出十六进制签名。命令以非零状态退出表示拒绝，标准错误的内容作为拒绝原因
+pub struct ExternalSigner {
+    program: String,
+    args: Vec<String>,
+    public_key: Vec<u8>,
+}
+
+impl ExternalSigner {
+    /// New 按空白拆分命令行，并向外部命令查询公钥
+    pub fn new(command: &str) -> Result<ExternalSigner> {
+        let mut parts = command.split_whitespace().map(String::from);
+        let program = parts
+            .next()
+            .ok_or_else(|| format_err!("External signer command is empty"))?;
+        let mut signer = ExternalSigner {
+            program,
+            args: parts.collect(),
+            public_key: Vec::new(),
+        };
+        let output = signer.request("pubkey", b"")?;
+        let public_key = hex::decode(output.trim())
+            .ok()
+            .filter(|key| key.len() == 32)
+            .ok_or_else(|| format_err!("External signer returned an invalid public key"))?;
+        signer.public_key = public_key;
+        Ok(signer)
+    }
+
+    /// 运行外部命令完成一次请求，返回标准输出
+    fn request(&self, request: &str, input: &[u8]) -> Result<String> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .arg(request)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format_err!("Cannot run external signer {}: {}", self.program, e))?;
+        // 写完后关闭标准输入，外部命令读到文件结束即得到完整消息
+        child.stdin.take().unwrap().write_all(input)?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            let reason = String::from_utf8_lossy(&output.stderr);
+            let reason = reason.trim();
+            return Err(format_err!(
+                "External signer refused the {} request: {}",
+                request,
+                if reason.is_empty() {
+                    output.status.to_string()
+                } else {
+                    reason.to_string()
+                }
+            ));
+        }
+        Ok(String::from_utf8(output.stdout)?)
+    }
+}
+
+impl Signer for ExternalSigner {
+    fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Sign 把消息交给外部命令签名，并用公钥验证返回的签名
+    fn sign(&self, msg: &[u8]) -> Result<Vec<u8>> {
+        let output = self.request("sign", msg)?;
+        let signature = hex::decode(output.trim())
+            .map_err(|e| format_err!("External signer returned a malformed signature: {}", e))?;
+        if !verify_signature(&self.public_key, msg, &signature) {
+            return Err(format_err!(
+                "External signer returned a signature that does not match its public key"
+            ));
+        }
+        Ok(signature)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// 写入一个按请求参数执行不同分支的签名脚本
+    fn script(name: &str, pubkey: &str, sign: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("rustchain-{}-{}.sh", name, std::process::id()));
+        let body = format!(
+            "#!/bin/sh\ncase \"$*\" in\n*pubkey) {} ;;\n*sign) cat >/dev/null; {} ;;\nesac\n",
+            pubkey, sign
+        );
+        std::fs::write(&path, body).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_external_signer() {
+        let key = "11".repeat(32);
+        let refusing = script(
+            "refusing",
+            &format!("echo {}", key),
+            "echo 'not today' >&2; exit 1",
+        );
+        let signer = ExternalSigner::new(&format!("{} --verbose", refusing)).unwrap();
+        assert_eq!(signer.public_key(), [0x11; 32]);
+        assert_eq!(signer.args, ["--verbose"]);
+        let err = signer.sign(b"message").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("refused the sign request: not today"),
+            "{}",
+            err
+        );
+
+        // 返回的签名与公钥不符时不使用
+        let forging = script(
+            "forging",
+            &format!("echo {}", key),
+            &format!("echo {}", "00".repeat(64)),
+        );
+        let err = ExternalSigner::new(&forging)
+            .unwrap()
+            .sign(b"message")
+            .unwrap_err();
+        assert!(err.to_string().contains("does not match"), "{}", err);
+
+        let no_key = script("no-key", "echo zz", "exit 0");
+        assert!(ExternalSigner::new(&no_key).is_err());
+        assert!(ExternalSigner::new("  ").is_err());
+        for path in [refusing, forging, no_key] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
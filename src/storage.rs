@@ -0,0 +1,277 @@
+//! key-value storage backends
+
+use super::*;
+use sled::Transactional;
+use sled::transaction::TransactionError;
+#[cfg(test)]
+use std::collections::{BTreeMap, HashMap};
+#[cfg(test)]
+use std::sync::RwLock;
+
+/// 默认树的名称，sled 后端中即数据库的默认树，与旧版本保存的数据兼容
+pub const DEFAULT_TREE: &str = "";
+
+/// KvIter 按键的字节序返回键值对的迭代器
+pub type KvIter = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>>;
+
+/// KvStore 区块和 UTXO 集合的键值存储后端
+///
+/// 数据分为按名称区分的多棵树，每棵树中的键按字节序排列。单个键的读写是原子的，
+/// 需要同时修改多个键时使用 batch
+pub trait KvStore: Send + Sync {
+    /// Get 读取 tree 中 key 的值
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Put 写入一个键值对，覆盖原有的值
+    fn put(&self, tree: &str, key: &[u8], value: &[u8]) -> Result<()>;
+
+    /// Delete 删除一个键，键不存在时什么也不做
+    fn delete(&self, tree: &str, key: &[u8]) -> Result<()>;
+
+    /// IterPrefix 按键的顺序遍历 tree 中以 prefix 开头的键值对，prefix 为空时遍历整棵树
+    fn iter_prefix(&self, tree: &str, prefix: &[u8]) -> KvIter;
+
+    /// Batch 原子地执行一组修改，修改可以涉及多棵树
+    fn batch(&self, batch: Batch) -> Result<()>;
+
+    /// Clear 删除 tree 中的全部键
+    fn clear(&self, tree: &str) -> Result<()>;
+
+    /// SizeOnDisk 返回数据占用的大致字节数
+    fn size_on_disk(&self) -> Result<u64>;
+
+    /// Flush 把已完成的修改持久化
+    fn flush(&self) -> Result<()>;
+}
+
+/// Batch 一组按顺序原子执行的修改
+#[derive(Default)]
+pub struct Batch {
+    /// (树, 键, 值)，值为 None 表示删除
+    ops: Vec<(String, Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl Batch {
+    /// Put 在批次中写入一个键值对
+    pub fn put(&mut self, tree: &str, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) {
+        self.ops.push((
+            tree.to_string(),
+            key.as_ref().to_vec(),
+            Some(value.as_ref().to_vec()),
+        ));
+    }
+
+    /// Delete 在批次中删除一个键
+    pub fn delete(&mut self, tree: &str, key: impl AsRef<[u8]>) {
+        self.ops
+            .push((tree.to_string(), key.as_ref().to_vec(), None));
+    }
+}
+
+/// SledStore 保存在 sled 数据库中的存储，树对应 sled 的树
+pub struct SledStore {
+    db: sled::Db,
+}
+
+impl SledStore {
+    /// New 使用已打开的 sled 数据库
+    pub fn new(db: sled::Db) -> SledStore {
+        SledStore { db }
+    }
+
+    fn tree(&self, name: &str) -> Result<sled::Tree> {
+        if name == DEFAULT_TREE {
+            Ok((*self.db).clone())
+        } else {
+            Ok(self.db.open_tree(name)?)
+        }
+    }
+}
+
+impl KvStore for SledStore {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.tree(tree)?.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn put(&self, tree: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        self.tree(tree)?.insert(key, value)?;
+        Ok(())
+    }
+
+    fn delete(&self, tree: &str, key: &[u8]) -> Result<()> {
+        self.tree(tree)?.remove(key)?;
+        Ok(())
+    }
+
+    fn iter_prefix(&self, tree: &str, prefix: &[u8]) -> KvIter {
+        match self.tree(tree) {
+            Ok(tree) => Box::new(tree.scan_prefix(prefix).map(|kv| {
+                let (k, v) = kv?;
+                Ok((k.to_vec(), v.to_vec()))
+            })),
+            Err(err) => Box::new(std::iter::once(Err(err))),
+        }
+    }
+
+    /// Batch 在一个跨越涉及的全部树的 sled 事务中执行
+    fn batch(&self, batch: Batch) -> Result<()> {
+        // sled 不支持不涉及任何树的事务
+        if batch.ops.is_empty() {
+            return Ok(());
+        }
+        let mut names: Vec<&str> = batch.ops.iter().map(|op| op.0.as_str()).collect();
+        names.sort_unstable();
+        names.dedup();
+        let trees = names
+            .iter()
+            .map(|name| self.tree(name))
+            .collect::<Result<Vec<_>>>()?;
+        trees
+            .as_slice()
+            .transaction(|views| {
+                for (tree, key, value) in &batch.ops {
+                    let view = &views[names.binary_search(&tree.as_str()).unwrap()];
+                    match value {
+                        Some(value) => view.insert(key.as_slice(), value.as_slice())?,
+                        None => view.remove(key.as_slice())?,
+                    };
+                }
+                Ok(())
+            })
+            .map_err(|err: TransactionError<()>| match err {
+                TransactionError::Storage(err) => err.into(),
+                TransactionError::Abort(()) => unreachable!("the batch never aborts"),
+            })
+    }
+
+    fn clear(&self, tree: &str) -> Result<()> {
+        self.tree(tree)?.clear()?;
+        Ok(())
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        Ok(self.db.size_on_disk()?)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+/// 内存中的一棵树
+#[cfg(test)]
+type MemoryTree = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// MemoryStore 保存在内存中的存储，丢弃后数据随之消失
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStore {
+    trees: RwLock<HashMap<String, MemoryTree>>,
+}
+
+#[cfg(test)]
+impl KvStore for MemoryStore {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let trees = self.trees.read().unwrap();
+        Ok(trees.get(tree).and_then(|tree| tree.get(key)).cloned())
+    }
+
+    fn put(&self, tree: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut batch = Batch::default();
+        batch.put(tree, key, value);
+        self.batch(batch)
+    }
+
+    fn delete(&self, tree: &str, key: &[u8]) -> Result<()> {
+        let mut batch = Batch::default();
+        batch.delete(tree, key);
+        self.batch(batch)
+    }
+
+    /// IterPrefix 返回调用时的快照，之后的修改不影响遍历
+    fn iter_prefix(&self, tree: &str, prefix: &[u8]) -> KvIter {
+        let trees = self.trees.read().unwrap();
+        let items: Vec<_> = trees
+            .get(tree)
+            .into_iter()
+            .flat_map(|tree| tree.range(prefix.to_vec()..))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect();
+        Box::new(items.into_iter())
+    }
+
+    fn batch(&self, batch: Batch) -> Result<()> {
+        let mut trees = self.trees.write().unwrap();
+        for (tree, key, value) in batch.ops {
+            let tree = trees.entry(tree).or_default();
+            match value {
+                Some(value) => tree.insert(key, value),
+                None => tree.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn clear(&self, tree: &str) -> Result<()> {
+        self.trees.write().unwrap().remove(tree);
+        Ok(())
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        let trees = self.trees.read().unwrap();
+        Ok(trees
+            .values()
+            .flatten()
+            .map(|(key, value)| (key.len() + value.len()) as u64)
+            .sum())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// 两个后端对同一组操作给出相同的结果
+    #[test]
+    fn test_backends() {
+        let sled_store = SledStore::new(sled::Config::new().temporary(true).open().unwrap());
+        let stores: [&dyn KvStore; 2] = [&sled_store, &MemoryStore::default()];
+        for store in stores {
+            store.put(DEFAULT_TREE, b"b", b"2").unwrap();
+            store.put("other", b"b", b"other").unwrap();
+            let mut batch = Batch::default();
+            batch.put(DEFAULT_TREE, b"a1", b"1");
+            batch.put(DEFAULT_TREE, b"a2", b"x");
+            batch.put(DEFAULT_TREE, b"a2", b"3");
+            batch.delete(DEFAULT_TREE, b"b");
+            batch.put("other", b"a", b"other");
+            store.batch(batch).unwrap();
+
+            assert_eq!(store.get(DEFAULT_TREE, b"a2").unwrap(), Some(b"3".to_vec()));
+            assert_eq!(store.get(DEFAULT_TREE, b"b").unwrap(), None);
+            let keys = |tree: &str, prefix: &[u8]| -> Vec<Vec<u8>> {
+                store
+                    .iter_prefix(tree, prefix)
+                    .map(|kv| kv.unwrap().0)
+                    .collect()
+            };
+            assert_eq!(keys(DEFAULT_TREE, b"a"), [b"a1".to_vec(), b"a2".to_vec()]);
+            assert_eq!(keys("other", b""), [b"a".to_vec(), b"b".to_vec()]);
+            assert!(keys("missing", b"").is_empty());
+
+            store.delete(DEFAULT_TREE, b"a1").unwrap();
+            store.delete(DEFAULT_TREE, b"none").unwrap();
+            store.clear("other").unwrap();
+            assert_eq!(keys(DEFAULT_TREE, b""), [b"a2".to_vec()]);
+            assert!(keys("other", b"").is_empty());
+            store.batch(Batch::default()).unwrap();
+            store.flush().unwrap();
+        }
+    }
+}
@@ -1,281 +1,3566 @@
 //! transaction
 
 use super::*;
+use crate::network::Network;
+use crate::signer::Signer;
 use crate::utxoset::*;
 use crate::wallets::*;
-use bincode::serialize;
-use bitcoincash_addr::Address;
+use bincode::{serialize, serialized_size, DefaultOptions, Options};
 use crypto::digest::Digest;
-use crypto::ed25519;
+use crypto::ripemd160::Ripemd160;
 use crypto::sha2::Sha256;
 use failure::format_err;
 use rand::RngCore;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
 use log::{debug, error, info};
 use rand::rngs::OsRng;
 
-const SUBSIDY: i32 = 10;
+/// 创世时每个区块的挖矿奖励，不含手续费
+pub const SUBSIDY: u64 = 10;
+/// 每隔这么多个区块挖矿奖励减半
+pub const HALVING_INTERVAL: i32 = 210_000;
+/// 创币交易的输出需要在其区块之上再有这么多个区块后才能花费
+pub const COINBASE_MATURITY: i32 = 10;
+/// 当前交易格式版本，签名或序列化方式变化时递增
+///
+/// 0：引入版本号之前的旧交易；1：金额为 i32；2：金额改为 u64；
+/// 3：签名末尾附加签名类型字节；4：交易哈希改用规范编码，不再依赖 bincode；
+/// 5：输入可携带多签条件和多个签名
+pub const TX_VERSION: u32 = 5;
+/// 引入版本号之前的旧交易统一视为版本 0
+const LEGACY_TX_VERSION: u32 = 0;
+/// 金额改为 u64 的第一个版本，之前的金额为 i32
+const U64_TX_VERSION: u32 = 2;
+/// 签名末尾附加签名类型字节的第一个版本
+const SIGHASH_TX_VERSION: u32 = 3;
+/// 交易哈希改用规范编码的第一个版本
+const CANONICAL_TX_VERSION: u32 = 4;
+/// 输入可携带多签条件的第一个版本
+const MULTISIG_TX_VERSION: u32 = 5;
+/// 低于该金额的输出花费成本高于其价值（创币交易除外）
+pub const DUST_LIMIT: u64 = 2;
+/// 估算交易大小时使用的占位长度
+const TXID_HEX_LEN: usize = 64;
+const ED25519_SIG_LEN: usize = 64;
+/// 输入签名由 ed25519 签名和 1 字节签名类型组成
+const SIGNATURE_LEN: usize = ED25519_SIG_LEN + 1;
+const PUB_KEY_LEN: usize = 32;
+const PUB_KEY_HASH_LEN: usize = 20;
+/// 数据输出可携带的最大字节数
+pub const MAX_DATA_LEN: usize = 80;
+/// 交易备注的最大字节数
+pub const MAX_MEMO_LEN: usize = 256;
+/// 创币交易输入中携带数据的字节数范围
+pub const MIN_COINBASE_DATA_LEN: usize = 2;
+pub const MAX_COINBASE_DATA_LEN: usize = 100;
+/// 创币交易数据开头记录区块高度的字节数，高度按小端序编码
+pub const COINBASE_HEIGHT_LEN: usize = 4;
+/// 多签条件最多列出的公钥哈希数量
+pub const MAX_MULTISIG_KEYS: usize = 16;
+
+/// Txid 交易 id，即交易哈希的十六进制字符串
+pub type Txid = String;
+
+/// OutPoint 指向某笔交易的某个输出
+///
+/// 字段顺序与旧版 TXInput 的 txid、vout 相同，vout 的编码宽度也不变，
+/// 已保存的区块无需迁移；旧版创币交易的 vout -1 读出后即为 u32::MAX
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OutPoint {
+    pub txid: Txid,
+    pub vout: u32,
+}
+
+impl OutPoint {
+    /// NewOutPoint 创建指向 txid 第 vout 个输出的 OutPoint
+    pub fn new(txid: &str, vout: u32) -> Self {
+        OutPoint {
+            txid: txid.to_string(),
+            vout,
+        }
+    }
+
+    /// Null 返回创币交易输入使用的空 OutPoint
+    pub fn null() -> Self {
+        OutPoint {
+            txid: Txid::new(),
+            vout: u32::MAX,
+        }
+    }
+
+    /// IsNull 检查是否为创币交易输入使用的空 OutPoint
+    pub fn is_null(&self) -> bool {
+        self.txid.is_empty() && self.vout == u32::MAX
+    }
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.txid, self.vout)
+    }
+}
+
+impl FromStr for OutPoint {
+    type Err = failure::Error;
+
+    /// 解析 "txid:n" 格式的字符串
+    fn from_str(s: &str) -> Result<Self> {
+        let (txid, vout) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format_err!("Invalid outpoint '{}': expected txid:n", s))?;
+        if txid.is_empty() {
+            return Err(format_err!("Invalid outpoint '{}': empty txid", s));
+        }
+        let vout = vout
+            .parse()
+            .map_err(|e| format_err!("Invalid outpoint '{}': {}", s, e))?;
+        Ok(OutPoint::new(txid, vout))
+    }
+}
 
 /// TXInput 表示交易输入
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TXInput {
-    pub txid: String,
-    pub vout: i32,
+    /// 被花费的输出，创币交易为 OutPoint::null()
+    pub outpoint: OutPoint,
     pub signature: Vec<u8>,
     pub pub_key: Vec<u8>,
+    /// 花费多签输出时揭示的解锁条件，普通输入为 None
+    pub condition: Option<LockingCondition>,
+    /// 多签输入中各签名者的 (公钥, 签名)，普通输入为空
+    pub signatures: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// LockingCondition 输出的解锁条件
+///
+/// 输出的 pub_key_hash 只保存条件的标识，多签输入在花费时揭示完整条件
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum LockingCondition {
+    /// 由该公钥哈希对应的私钥签名
+    SingleKey(Vec<u8>),
+    /// 列出的公钥哈希中至少 m 个不同的私钥签名
+    MultiSig { m: u8, key_hashes: Vec<Vec<u8>> },
+}
+
+impl LockingCondition {
+    /// NewMultiSig 由 network 上的地址列表创建 m-of-n 多签条件
+    pub fn new_multisig(m: u8, addresses: &[String], network: Network) -> Result<Self> {
+        let mut key_hashes = Vec::new();
+        for address in addresses {
+            key_hashes.push(decode_address(address, network)?);
+        }
+        let condition = LockingCondition::MultiSig { m, key_hashes };
+        if !condition.is_valid() {
+            return Err(format_err!(
+                "Invalid {}-of-{} multisig: need 1 <= m <= n <= {} distinct keys",
+                m,
+                addresses.len(),
+                MAX_MULTISIG_KEYS
+            ));
+        }
+        Ok(condition)
+    }
+
+    /// IsValid 检查公钥哈希长度，多签还要求 1 <= m <= n <= MAX_MULTISIG_KEYS 且公钥哈希互不相同
+    fn is_valid(&self) -> bool {
+        match self {
+            LockingCondition::SingleKey(key_hash) => key_hash.len() == PUB_KEY_HASH_LEN,
+            LockingCondition::MultiSig { m, key_hashes } => {
+                let unique: HashSet<&Vec<u8>> = key_hashes.iter().collect();
+                *m >= 1
+                    && *m as usize <= key_hashes.len()
+                    && key_hashes.len() <= MAX_MULTISIG_KEYS
+                    && unique.len() == key_hashes.len()
+                    && key_hashes.iter().all(|h| h.len() == PUB_KEY_HASH_LEN)
+            }
+        }
+    }
+
+    /// Identifier 返回写入输出 pub_key_hash 的标识，多签条件取其规范编码的 SHA-256 加 RIPEMD-160 哈希
+    pub fn identifier(&self) -> Vec<u8> {
+        match self {
+            LockingCondition::SingleKey(key_hash) => key_hash.clone(),
+            LockingCondition::MultiSig { .. } => {
+                let mut buf = Vec::new();
+                self.encode(&mut buf);
+                let mut sha = Sha256::new();
+                sha.input(&buf);
+                let mut digest = [0u8; 32];
+                sha.result(&mut digest);
+                let mut ripemd = Ripemd160::new();
+                ripemd.input(&digest);
+                let mut identifier = vec![0u8; PUB_KEY_HASH_LEN];
+                ripemd.result(&mut identifier);
+                identifier
+            }
+        }
+    }
+
+    /// Address 返回条件在 network 上对应的 Base58 地址，向该地址转账即锁定到该条件
+    pub fn address(&self, network: Network) -> String {
+        address_from_pub_key_hash(&self.identifier(), network)
+    }
+
+    /// Encode 写入条件的规范编码：类型字节（0 单密钥，1 多签），随后为公钥哈希，
+    /// 多签依次为 m(u8)、公钥哈希个数(u64) 和各公钥哈希
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            LockingCondition::SingleKey(key_hash) => {
+                buf.push(0);
+                put_bytes(buf, key_hash);
+            }
+            LockingCondition::MultiSig { m, key_hashes } => {
+                buf.push(1);
+                buf.push(*m);
+                buf.extend_from_slice(&(key_hashes.len() as u64).to_le_bytes());
+                for key_hash in key_hashes {
+                    put_bytes(buf, key_hash);
+                }
+            }
+        }
+    }
 }
 
 /// TXOutput 表示交易输出
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TXOutput {
-    pub value: i32,
+    pub value: u64,
     pub pub_key_hash: Vec<u8>,
+    /// 数据输出携带的任意数据，普通输出为 None
+    pub data: Option<Vec<u8>>,
 }
 
-/// TXOutputs 收集 TXOutput
+/// UTXOEntry UTXO 集合中的一条记录，即一个未花费的 TXOutput
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct TXOutputs {
-    pub outputs: Vec<TXOutput>,
+pub struct UTXOEntry {
+    pub output: TXOutput,
+    /// 所属交易是否为创币交易
+    pub coinbase: bool,
+    /// 所属交易所在区块的高度
+    pub height: i32,
+}
+
+impl UTXOEntry {
+    /// IsMature 检查在最新高度为 tip 的链上该输出是否可以花费
+    ///
+    /// 创世区块不会被回滚，其奖励无需等待，否则新链上没有任何可花费的输出
+    pub fn is_mature(&self, tip: i32) -> bool {
+        !self.coinbase || self.height == 0 || tip - self.height >= COINBASE_MATURITY
+    }
+
+    /// Confirmations 返回在最新高度为 tip 的链上该输出的确认数，最新区块中的输出有 1 个确认
+    pub fn confirmations(&self, tip: i32) -> i32 {
+        tip - self.height + 1
+    }
 }
 
 /// Transaction 表示比特币交易
+///
+/// 相等和哈希比较 id 及规范编码，伪造 id 的交易与原交易不相等。
+/// 排序时创币交易在前，其余按 txid 排列，用于确定区块内交易的顺序
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
+    pub version: u32,
     pub id: String,
     pub vin: Vec<TXInput>,
     pub vout: Vec<TXOutput>,
+    /// 交易备注，参与交易哈希，签名后不可修改
+    pub memo: Option<String>,
+    /// 交易只能被打包进不低于该高度的区块，0 表示不锁定
+    pub lock_until: i32,
 }
 
-impl Transaction {
-    /// NewUTXO 创建新的交易
-    pub fn new_utxo(wallet: &Wallet, to: &str, amount: i32, utxo: &UTXOSet) -> Result<Transaction> {
-        info!(
-            "new UTXO Transaction from: {} to: {}",
-            wallet.get_address(),
-            to
-        );
-        let mut vin = Vec::new();
+impl PartialEq for Transaction {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id && self.canonical_bytes() == other.canonical_bytes()
+    }
+}
 
-        let mut pub_key_hash = wallet.public_key.clone();
-        hash_pub_key(&mut pub_key_hash);
+impl Eq for Transaction {}
 
-        let acc_v = utxo.find_spendable_outputs(&pub_key_hash, amount)?;
+impl Hash for Transaction {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+        self.canonical_bytes().hash(state);
+    }
+}
 
-        if acc_v.0 < amount {
-            error!("Not Enough balance");
-            return Err(format_err!(
-                "Not Enough balance: current balance {}",
-                acc_v.0
-            ));
-        }
+impl Ord for Transaction {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .is_coinbase()
+            .cmp(&self.is_coinbase())
+            .then_with(|| self.id.cmp(&other.id))
+            .then_with(|| self.canonical_bytes().cmp(&other.canonical_bytes()))
+    }
+}
 
-        for tx in acc_v.1 {
-            for out in tx.1 {
-                let input = TXInput {
-                    txid: tx.0.clone(),
-                    vout: out,
-                    signature: Vec::new(),
-                    pub_key: wallet.public_key.clone(),
-                };
-                vin.push(input);
-            }
+impl PartialOrd for Transaction {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// LegacyTXInput 多签引入之前的交易输入格式
+#[derive(Deserialize, Debug, Clone)]
+pub struct LegacyTXInput {
+    pub txid: String,
+    pub vout: i32,
+    pub signature: Vec<u8>,
+    pub pub_key: Vec<u8>,
+}
+
+impl From<LegacyTXInput> for TXInput {
+    fn from(input: LegacyTXInput) -> Self {
+        TXInput {
+            // 旧版创币交易的 vout 为 -1，按位转换后正好是 OutPoint::null()
+            outpoint: OutPoint {
+                txid: input.txid,
+                vout: input.vout as u32,
+            },
+            signature: input.signature,
+            pub_key: input.pub_key,
+            condition: None,
+            signatures: Vec::new(),
         }
+    }
+}
+
+/// LegacyTXOutput 数据输出引入之前的交易输出格式
+#[derive(Deserialize, Debug, Clone)]
+pub struct LegacyTXOutput {
+    pub value: i32,
+    pub pub_key_hash: Vec<u8>,
+}
+
+/// LegacyTransaction 版本号引入之前的交易格式，用于读取旧区块，读出后版本为 0
+#[derive(Deserialize, Debug, Clone)]
+pub struct LegacyTransaction {
+    pub id: String,
+    pub vin: Vec<LegacyTXInput>,
+    pub vout: Vec<LegacyTXOutput>,
+}
+
+impl TryFrom<LegacyTransaction> for Transaction {
+    type Error = failure::Error;
 
-        let mut vout = vec![TXOutput::new(amount, to.to_string())?];
-        if acc_v.0 > amount {
-            vout.push(TXOutput::new(acc_v.0 - amount, wallet.get_address())?)
+    fn try_from(tx: LegacyTransaction) -> Result<Self> {
+        let mut vout = Vec::new();
+        for out in tx.vout {
+            let value = u64::try_from(out.value)
+                .map_err(|_| format_err!("negative output value {} in {}", out.value, tx.id))?;
+            vout.push(TXOutput {
+                value,
+                pub_key_hash: out.pub_key_hash,
+                data: None,
+            });
         }
 
-        let mut tx = Transaction {
-            id: String::new(),
-            vin,
+        Ok(Transaction {
+            version: LEGACY_TX_VERSION,
+            id: tx.id,
+            vin: tx.vin.into_iter().map(TXInput::from).collect(),
             vout,
-        };
-        tx.id = tx.hash()?;
-        utxo.blockchain
-            .sign_transacton(&mut tx, &wallet.secret_key)?;
-        Ok(tx)
+            memo: None,
+            lock_until: 0,
+        })
     }
+}
 
-    /// NewCoinbaseTX 创建新的创币交易
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
-        info!("new coinbase Transaction to: {}", to);
-        let mut key: [u8; 32] = [0; 32];
-        if data.is_empty() {
-            let mut rand = OsRng::default();
-            rand.fill_bytes(&mut key);
-            data = format!("Reward to '{}'", to);
-        }
-        let mut pub_key = Vec::from(data.as_bytes());
-        pub_key.append(&mut Vec::from(key));
+/// TXInputJson TXInput 的 JSON 视图，字节字段编码为十六进制
+///
+/// 与旧版格式保持一致，coinbase 输入的 vout 仍显示为 -1
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TXInputJson {
+    pub txid: String,
+    pub vout: i64,
+    pub signature: String,
+    pub pub_key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub condition: Option<LockingConditionJson>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signatures: Vec<SignatureJson>,
+}
 
-        let mut tx = Transaction {
-            id: String::new(),
-            vin: vec![TXInput {
-                txid: String::new(),
-                vout: -1,
-                signature: Vec::new(),
-                pub_key,
-            }],
-            vout: vec![TXOutput::new(SUBSIDY, to)?],
-        };
-        tx.id = tx.hash()?;
-        Ok(tx)
+/// LockingConditionJson LockingCondition 的 JSON 视图
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LockingConditionJson {
+    SingleKey { key_hash: String },
+    MultiSig { m: u8, key_hashes: Vec<String> },
+}
+
+/// SignatureJson 多签输入中一个签名者的公钥和签名
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SignatureJson {
+    pub pub_key: String,
+    pub signature: String,
+}
+
+/// TXOutputJson TXOutput 的 JSON 视图，普通输出附带 Base58 地址
+///
+/// address 只供阅读，反序列化时以 pub_key_hash 为准
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TXOutputJson {
+    pub value: u64,
+    pub pub_key_hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+}
+
+/// TransactionJson Transaction 的 JSON 视图，用于命令行输出和 RPC
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TransactionJson {
+    pub version: u32,
+    pub id: String,
+    pub vin: Vec<TXInputJson>,
+    pub vout: Vec<TXOutputJson>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memo: Option<String>,
+    pub lock_until: i32,
+}
+
+impl From<&TXInput> for TXInputJson {
+    fn from(input: &TXInput) -> Self {
+        TXInputJson {
+            txid: input.outpoint.txid.clone(),
+            vout: match input.outpoint.vout {
+                u32::MAX => -1,
+                vout => i64::from(vout),
+            },
+            signature: hex::encode(&input.signature),
+            pub_key: hex::encode(&input.pub_key),
+            condition: input.condition.as_ref().map(LockingConditionJson::from),
+            signatures: input
+                .signatures
+                .iter()
+                .map(|(pub_key, signature)| SignatureJson {
+                    pub_key: hex::encode(pub_key),
+                    signature: hex::encode(signature),
+                })
+                .collect(),
+        }
     }
+}
 
-    /// IsCoinbase 检查交易是否为创币交易
-    pub fn is_coinbase(&self) -> bool {
-        self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
+impl From<&LockingCondition> for LockingConditionJson {
+    fn from(condition: &LockingCondition) -> Self {
+        match condition {
+            LockingCondition::SingleKey(key_hash) => LockingConditionJson::SingleKey {
+                key_hash: hex::encode(key_hash),
+            },
+            LockingCondition::MultiSig { m, key_hashes } => LockingConditionJson::MultiSig {
+                m: *m,
+                key_hashes: key_hashes.iter().map(hex::encode).collect(),
+            },
+        }
     }
+}
 
-    /// Verify 验证交易输入的签名
-    pub fn verify(&self, prev_txs: HashMap<String, Transaction>) -> Result<bool> {
-        if self.is_coinbase() {
-            return Ok(true);
+impl TXOutputJson {
+    /// NewTXOutputJson 转换输出，address 为锁定公钥哈希在 network 上的地址
+    pub fn new(output: &TXOutput, network: Network) -> Self {
+        TXOutputJson {
+            value: output.value,
+            pub_key_hash: hex::encode(&output.pub_key_hash),
+            address: (!output.is_data())
+                .then(|| address_from_pub_key_hash(&output.pub_key_hash, network)),
+            data: output.data.as_ref().map(hex::encode),
         }
+    }
+}
 
-        for vin in &self.vin {
-            if prev_txs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previous transaction is not correct"));
-            }
+impl TransactionJson {
+    /// NewTransactionJson 转换交易，输出的地址按 network 编码
+    pub fn new(tx: &Transaction, network: Network) -> Self {
+        TransactionJson {
+            version: tx.version,
+            id: tx.id.clone(),
+            vin: tx.vin.iter().map(TXInputJson::from).collect(),
+            vout: tx
+                .vout
+                .iter()
+                .map(|output| TXOutputJson::new(output, network))
+                .collect(),
+            memo: tx.memo.clone(),
+            lock_until: tx.lock_until,
         }
+    }
+}
 
-        let mut tx_copy = self.trim_copy();
+/// decode_hex 解析 JSON 中的十六进制字段，出错时指明字段名
+fn decode_hex(field: &str, value: &str) -> Result<Vec<u8>> {
+    hex::decode(value).map_err(|e| format_err!("Invalid hex in {}: {}", field, e))
+}
 
-        for in_id in 0..self.vin.len() {
-            let prev_tx = prev_txs.get(&self.vin[in_id].txid).unwrap();
-            tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_tx.vout[self.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[in_id].pub_key = Vec::new();
+impl TryFrom<TXInputJson> for TXInput {
+    type Error = failure::Error;
 
-            if !ed25519::verify(
-                &tx_copy.id.as_bytes(),
-                &self.vin[in_id].pub_key,
-                &self.vin[in_id].signature,
-            ) {
-                return Ok(false);
-            }
+    fn try_from(input: TXInputJson) -> Result<Self> {
+        let mut signatures = Vec::new();
+        for sig in &input.signatures {
+            signatures.push((
+                decode_hex("signatures.pub_key", &sig.pub_key)?,
+                decode_hex("signatures.signature", &sig.signature)?,
+            ));
         }
-
-        Ok(true)
+        let vout = match input.vout {
+            -1 => u32::MAX,
+            vout => u32::try_from(vout).map_err(|_| format_err!("Invalid vout {}", vout))?,
+        };
+        Ok(TXInput {
+            signature: decode_hex("signature", &input.signature)?,
+            pub_key: decode_hex("pub_key", &input.pub_key)?,
+            condition: input
+                .condition
+                .map(LockingCondition::try_from)
+                .transpose()?,
+            signatures,
+            outpoint: OutPoint {
+                txid: input.txid,
+                vout,
+            },
+        })
     }
+}
 
-    /// Sign 对交易的每个输入进行签名
-    pub fn sign(
-        &mut self,
-        private_key: &[u8],
-        prev_txs: HashMap<String, Transaction>,
-    ) -> Result<()> {
-        if self.is_coinbase() {
-            return Ok(());
-        }
+impl TryFrom<LockingConditionJson> for LockingCondition {
+    type Error = failure::Error;
 
-        for vin in &self.vin {
-            if prev_txs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previous transaction is not correct"));
+    fn try_from(condition: LockingConditionJson) -> Result<Self> {
+        Ok(match condition {
+            LockingConditionJson::SingleKey { key_hash } => {
+                LockingCondition::SingleKey(decode_hex("key_hash", &key_hash)?)
             }
-        }
-
-        let mut tx_copy = self.trim_copy();
+            LockingConditionJson::MultiSig { m, key_hashes } => LockingCondition::MultiSig {
+                m,
+                key_hashes: key_hashes
+                    .iter()
+                    .map(|key_hash| decode_hex("key_hashes", key_hash))
+                    .collect::<Result<_>>()?,
+            },
+        })
+    }
+}
 
-        for in_id in 0..tx_copy.vin.len() {
-            let prev_tx = prev_txs.get(&tx_copy.vin[in_id].txid).unwrap();
-            tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_tx.vout[tx_copy.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[in_id].pub_key = Vec::new();
-            let signature = ed25519::signature(tx_copy.id.as_bytes(), private_key);
-            self.vin[in_id].signature = signature.to_vec();
-        }
+impl TryFrom<TXOutputJson> for TXOutput {
+    type Error = failure::Error;
 
-        Ok(())
+    fn try_from(output: TXOutputJson) -> Result<Self> {
+        Ok(TXOutput {
+            value: output.value,
+            pub_key_hash: decode_hex("pub_key_hash", &output.pub_key_hash)?,
+            data: match output.data {
+                Some(data) => Some(decode_hex("data", &data)?),
+                None => None,
+            },
+        })
     }
+}
 
-    /// Hash 返回交易的哈希
-    pub fn hash(&self) -> Result<String> {
-        let mut copy = self.clone();
-        copy.id = String::new();
-        let data = serialize(&copy)?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        Ok(hasher.result_str())
+impl TryFrom<TransactionJson> for Transaction {
+    type Error = failure::Error;
+
+    fn try_from(tx: TransactionJson) -> Result<Self> {
+        Ok(Transaction {
+            version: tx.version,
+            id: tx.id,
+            vin: tx
+                .vin
+                .into_iter()
+                .map(TXInput::try_from)
+                .collect::<Result<_>>()?,
+            vout: tx
+                .vout
+                .into_iter()
+                .map(TXOutput::try_from)
+                .collect::<Result<_>>()?,
+            memo: tx.memo,
+            lock_until: tx.lock_until,
+        })
     }
+}
 
-    /// TrimmedCopy 创建用于签名的精简交易副本
-    fn trim_copy(&self) -> Transaction {
-        let mut vin = Vec::new();
-        let mut vout = Vec::new();
+/// SigHashType 签名覆盖的交易范围，记录在每个输入签名的最后一个字节
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SigHashType {
+    /// 覆盖全部输入和输出
+    #[default]
+    All,
+    /// 覆盖全部输入，以及与该输入下标相同的一个输出
+    Single,
+    /// 只覆盖正在签名的输入和全部输出，其他人可以继续追加输入
+    AnyoneCanPay,
+}
 
-        for v in &self.vin {
-            vin.push(TXInput {
-                txid: v.txid.clone(),
-                vout: v.vout.clone(),
-                signature: Vec::new(),
-                pub_key: Vec::new(),
-            })
+impl SigHashType {
+    /// ToByte 返回签名类型的编码，取值与比特币的 SIGHASH 标志一致
+    fn to_byte(self) -> u8 {
+        match self {
+            SigHashType::All => 0x01,
+            SigHashType::Single => 0x03,
+            SigHashType::AnyoneCanPay => 0x81,
         }
+    }
 
-        for v in &self.vout {
-            vout.push(TXOutput {
-                value: v.value,
-                pub_key_hash: v.pub_key_hash.clone(),
-            })
+    /// FromByte 解析签名类型编码，无法识别时返回 None
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0x01 => Some(SigHashType::All),
+            0x03 => Some(SigHashType::Single),
+            0x81 => Some(SigHashType::AnyoneCanPay),
+            _ => None,
         }
+    }
+}
 
-        Transaction {
-            id: self.id.clone(),
-            vin,
-            vout,
+impl FromStr for SigHashType {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "all" => Ok(SigHashType::All),
+            "single" => Ok(SigHashType::Single),
+            "anyonecanpay" => Ok(SigHashType::AnyoneCanPay),
+            _ => Err(format_err!("Unknown sighash type: {}", s)),
         }
     }
 }
 
-impl TXOutput {
-    /// IsLockedWithKey 检查输出是否由指定公钥哈希锁定
-    pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
-        self.pub_key_hash == pub_key_hash
+/// TxVerifyError 交易验证失败的具体原因，input 和 output 为交易内的下标
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxVerifyError {
+    UnsupportedVersion {
+        version: u32,
+    },
+    IdMismatch {
+        id: Txid,
+        expected: Txid,
+    },
+    MemoTooLong {
+        len: usize,
+    },
+    BadCoinbaseData {
+        len: usize,
+    },
+    MultipleDataOutputs,
+    InvalidDataOutput {
+        output: usize,
+    },
+    DustOutput {
+        output: usize,
+    },
+    DuplicateInput {
+        outpoint: OutPoint,
+    },
+    MissingPrevTx {
+        txid: Txid,
+    },
+    InputIndexOutOfRange {
+        input: usize,
+        vout: u32,
+    },
+    ValueOverflow,
+    OutputsExceedInputs {
+        inputs: u64,
+        outputs: u64,
+    },
+    KeyMismatch {
+        input: usize,
+    },
+    BadCondition {
+        input: usize,
+    },
+    BadSigner {
+        input: usize,
+    },
+    NotEnoughSignatures {
+        input: usize,
+        signers: usize,
+        required: u8,
+    },
+    MalformedSignature {
+        input: usize,
+    },
+    UnknownSigHash {
+        input: usize,
+    },
+    NoOutputForSigHashSingle {
+        input: usize,
+    },
+    BadSignature {
+        input: usize,
+    },
+}
+
+impl fmt::Display for TxVerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TxVerifyError::UnsupportedVersion { version } => {
+                write!(f, "unsupported transaction version {}", version)
+            }
+            TxVerifyError::IdMismatch { id, expected } => {
+                write!(f, "id {} does not match the content hash {}", id, expected)
+            }
+            TxVerifyError::MemoTooLong { len } => {
+                write!(
+                    f,
+                    "memo of {} bytes exceeds the limit {}",
+                    len, MAX_MEMO_LEN
+                )
+            }
+            TxVerifyError::BadCoinbaseData { len } => write!(
+                f,
+                "coinbase carries {} bytes of data, expected {} to {}",
+                len, MIN_COINBASE_DATA_LEN, MAX_COINBASE_DATA_LEN
+            ),
+            TxVerifyError::MultipleDataOutputs => write!(f, "more than one data output"),
+            TxVerifyError::InvalidDataOutput { output } => {
+                write!(f, "output {} is an invalid data output", output)
+            }
+            TxVerifyError::DustOutput { output } => write!(f, "output {} is dust", output),
+            TxVerifyError::DuplicateInput { outpoint } => {
+                write!(f, "output {} is spent twice", outpoint)
+            }
+            TxVerifyError::MissingPrevTx { txid } => {
+                write!(f, "referenced transaction {} not found", txid)
+            }
+            TxVerifyError::InputIndexOutOfRange { input, vout } => {
+                write!(f, "input {} references missing output {}", input, vout)
+            }
+            TxVerifyError::ValueOverflow => write!(f, "value overflows"),
+            TxVerifyError::OutputsExceedInputs { inputs, outputs } => {
+                write!(f, "outputs {} exceed inputs {}", outputs, inputs)
+            }
+            TxVerifyError::KeyMismatch { input } => {
+                write!(f, "input {} is not owned by its key", input)
+            }
+            TxVerifyError::BadCondition { input } => {
+                write!(f, "input {} has a bad locking condition", input)
+            }
+            TxVerifyError::BadSigner { input } => write!(f, "input {} has a bad signer", input),
+            TxVerifyError::NotEnoughSignatures {
+                input,
+                signers,
+                required,
+            } => write!(
+                f,
+                "input {} has {} of {} signatures",
+                input, signers, required
+            ),
+            TxVerifyError::MalformedSignature { input } => {
+                write!(f, "input {} has a malformed signature", input)
+            }
+            TxVerifyError::UnknownSigHash { input } => {
+                write!(f, "input {} has an unknown sighash type", input)
+            }
+            TxVerifyError::NoOutputForSigHashSingle { input } => {
+                write!(f, "no output at index {} for SIGHASH_SINGLE", input)
+            }
+            TxVerifyError::BadSignature { input } => {
+                write!(f, "input {} has an invalid signature", input)
+            }
+        }
     }
-    /// Lock 对输出进行签名锁定
-    fn lock(&mut self, address: &str) -> Result<()> {
-        let pub_key_hash = Address::decode(address).unwrap().body;
-        debug!("lock: {}", address);
-        self.pub_key_hash = pub_key_hash;
-        Ok(())
+}
+
+impl std::error::Error for TxVerifyError {}
+
+pub type VerifyResult<T> = std::result::Result<T, TxVerifyError>;
+
+/// SignatureCheck 一个输入的签名验证任务
+#[derive(Debug, Clone)]
+pub struct SignatureCheck {
+    txid: String,
+    input: usize,
+    message: String,
+    pub_key: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl SignatureCheck {
+    fn verify(&self) -> bool {
+        verify_signature(&self.pub_key, self.message.as_bytes(), &self.signature)
     }
 
-    pub fn new(value: i32, address: String) -> Result<Self> {
-        let mut txo = TXOutput {
-            value,
-            pub_key_hash: Vec::new(),
-        };
-        txo.lock(&address)?;
-        Ok(txo)
+    /// Txid 返回签名所属交易的 id
+    pub fn txid(&self) -> &str {
+        &self.txid
+    }
+
+    /// Error 返回该签名无效时的验证错误
+    pub fn error(&self) -> TxVerifyError {
+        TxVerifyError::BadSignature { input: self.input }
     }
 }
 
 #[cfg(test)]
-mod test {
-    use super::*;
+thread_local! {
+    /// 本线程调用 verify_signatures 的次数，测试用来确认跳过了签名验证
+    pub static SIGNATURE_VERIFICATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
 
-    #[test]
-    fn test_signature() {
-        let mut ws = Wallets::new().unwrap();
-        let wa1 = ws.create_wallet();
-        let w = ws.get_wallet(&wa1).unwrap().clone();
-        ws.save_all().unwrap();
-        drop(ws);
+/// VerifySignatures 验证全部签名，返回第一个无效的签名
+///
+/// parallel 为 true 时用 rayon 并行验证，结果与串行验证一致
+pub fn verify_signatures(
+    checks: &[SignatureCheck],
+    parallel: bool,
+) -> std::result::Result<(), &SignatureCheck> {
+    #[cfg(test)]
+    SIGNATURE_VERIFICATIONS.with(|count| count.set(count.get() + 1));
+    let invalid = if parallel {
+        checks.par_iter().find_first(|check| !check.verify())
+    } else {
+        checks.iter().find(|check| !check.verify())
+    };
+    match invalid {
+        Some(check) => Err(check),
+        None => Ok(()),
+    }
+}
 
-        let data = String::from("test");
-        let tx = Transaction::new_coinbase(wa1, data).unwrap();
-        assert!(tx.is_coinbase());
+/// TxOptions 创建转账交易时的可选参数
+#[derive(Debug, Clone, Default)]
+pub struct TxOptions {
+    /// 支付给矿工的手续费
+    pub fee: u64,
+    /// 选币策略
+    pub strategy: CoinSelection,
+    /// 找零地址，为空时找零回到发送方地址
+    pub change_address: Option<String>,
+    /// 附加到交易末尾的数据输出内容
+    pub data: Option<Vec<u8>>,
+    /// 交易备注
+    pub memo: Option<String>,
+    /// 锁定到的区块高度，0 表示不锁定
+    pub lock_until: i32,
+    /// 签名类型
+    pub sighash: SigHashType,
+    /// 选币时只选用至少有这么多确认的输出，0 和 1 都不限制
+    pub min_conf: i32,
+}
 
-        let signature = ed25519::signature(tx.id.as_bytes(), &w.secret_key);
-        assert!(ed25519::verify(tx.id.as_bytes(), &w.public_key, &signature));
+/// UnsignedBundle 未签名交易及其前序交易，用于带到离线机器上签名
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnsignedBundle {
+    pub tx: Transaction,
+    pub prev_txs: HashMap<String, Transaction>,
+}
+
+impl UnsignedBundle {
+    /// Save 将交易包写入文件
+    pub fn save(&self, path: &str) -> Result<()> {
+        std::fs::write(path, serialize(self)?)?;
+        Ok(())
+    }
+
+    /// Load 从文件读取交易包，并检查前序交易的 id 与索引是否一致
+    pub fn load(path: &str) -> Result<UnsignedBundle> {
+        let bytes = std::fs::read(path)?;
+        let bundle: UnsignedBundle = DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes()
+            .deserialize(&bytes)
+            .map_err(|e| format_err!("Invalid transaction bundle: {}", e))?;
+        for (txid, prev_tx) in &bundle.prev_txs {
+            if *txid != prev_tx.id {
+                return Err(format_err!(
+                    "Invalid transaction bundle: previous transaction {} is stored as {}",
+                    prev_tx.id,
+                    txid
+                ));
+            }
+        }
+        Ok(bundle)
+    }
+
+    /// Sign 用 signer 签名交易包中的交易，返回可以广播的交易
+    pub fn sign(self, signer: &dyn Signer, sighash: SigHashType) -> Result<Transaction> {
+        let mut tx = self.tx;
+        tx.sign(signer, self.prev_txs, sighash)?;
+        Ok(tx)
+    }
+}
+
+impl Transaction {
+    /// NewUTXO 创建新的交易
+    pub fn new_utxo(
+        wallet: &Wallet,
+        to: &str,
+        amount: u64,
+        options: &TxOptions,
+        utxo: &UTXOSet,
+    ) -> Result<Transaction> {
+        Transaction::new_utxo_multi(wallet, &[(to.to_string(), amount)], options, utxo)
+    }
+
+    /// NewUTXOFromInputs 花费调用者指定的输出创建交易，不进行选币
+    ///
+    /// 每个输出都必须在UTXO集合中、由钱包的公钥哈希锁定且已经成熟，冻结的输出也可以指定；
+    /// 总额扣除金额和手续费后照常找零
+    pub fn new_utxo_from_inputs(
+        wallet: &Wallet,
+        inputs: &[OutPoint],
+        to: &str,
+        amount: u64,
+        options: &TxOptions,
+        utxo: &UTXOSet,
+    ) -> Result<Transaction> {
+        info!(
+            "new UTXO Transaction from: {} to {} with {} chosen input(s)",
+            wallet.get_address(utxo.blockchain.network),
+            to,
+            inputs.len()
+        );
+        let mut pub_key_hash = wallet.public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        let spendable = Transaction::chosen_inputs(&pub_key_hash, inputs, utxo)?;
+        let outputs = [(to.to_string(), amount)];
+        let mut tx = Transaction::new_unsigned(
+            &wallet.public_key,
+            &outputs,
+            options,
+            spendable,
+            utxo.blockchain.network,
+        )?;
+        let prev_txs = utxo.blockchain.get_prev_txs(&tx)?;
+        wallet.sign_transaction(&mut tx, prev_txs, options.sighash)?;
+        Ok(tx)
+    }
+
+    /// ChosenInputs 校验调用者指定的输入，返回其总额
+    fn chosen_inputs(
+        pub_key_hash: &[u8],
+        inputs: &[OutPoint],
+        utxo: &UTXOSet,
+    ) -> Result<(u64, Vec<OutPoint>)> {
+        if inputs.is_empty() {
+            return Err(format_err!("No input given"));
+        }
+        let tip = utxo.blockchain.get_best_height()?;
+        let mut seen = HashSet::new();
+        let mut accumulated: u64 = 0;
+        for outpoint in inputs {
+            if !seen.insert(outpoint) {
+                return Err(format_err!("Input {} is given more than once", outpoint));
+            }
+            let entry = utxo
+                .get_entry(outpoint)?
+                .ok_or_else(|| format_err!("Input {} is not in the UTXO set", outpoint))?;
+            if !entry.output.is_locked_with_key(pub_key_hash) {
+                return Err(format_err!("Input {} is not owned by the wallet", outpoint));
+            }
+            if !entry.is_mature(tip) {
+                return Err(format_err!(
+                    "Input {} is a coinbase reward that is not mature yet",
+                    outpoint
+                ));
+            }
+            accumulated = accumulated
+                .checked_add(entry.output.value)
+                .ok_or_else(|| format_err!("Spendable amount overflows"))?;
+        }
+        Ok((accumulated, inputs.to_vec()))
+    }
+
+    /// NewConsolidation 把钱包中面额最小的至多 max_inputs 个输出合并为一个发回钱包地址的输出
+    ///
+    /// 尚未成熟的创币交易奖励和冻结的输出不参与合并；可以合并的输出少于两个时返回 None
+    pub fn new_consolidation(
+        wallet: &Wallet,
+        utxo: &UTXOSet,
+        max_inputs: usize,
+        fee: u64,
+    ) -> Result<Option<Transaction>> {
+        if max_inputs < 2 {
+            return Err(format_err!(
+                "Consolidation needs at least 2 inputs, got a limit of {}",
+                max_inputs
+            ));
+        }
+        let mut pub_key_hash = wallet.public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        let mut candidates = utxo.find_candidates(&pub_key_hash, 1)?;
+        if candidates.len() < 2 {
+            return Ok(None);
+        }
+        candidates.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        candidates.truncate(max_inputs);
+
+        let mut accumulated: u64 = 0;
+        for (_, value) in &candidates {
+            accumulated = accumulated
+                .checked_add(*value)
+                .ok_or_else(|| format_err!("Spendable amount overflows"))?;
+        }
+        let amount = accumulated
+            .checked_sub(fee)
+            .filter(|amount| *amount > 0)
+            .ok_or_else(|| {
+                format_err!(
+                    "Consolidated amount {} does not cover the fee {}",
+                    accumulated,
+                    fee
+                )
+            })?;
+        info!(
+            "consolidate {} outputs of {} into one",
+            candidates.len(),
+            wallet.get_address(utxo.blockchain.network)
+        );
+
+        let options = TxOptions {
+            fee,
+            ..TxOptions::default()
+        };
+        let outputs = [(wallet.get_address(utxo.blockchain.network), amount)];
+        let unspent = candidates
+            .into_iter()
+            .map(|(outpoint, _)| outpoint)
+            .collect();
+        let mut tx = Transaction::new_unsigned(
+            &wallet.public_key,
+            &outputs,
+            &options,
+            (accumulated, unspent),
+            utxo.blockchain.network,
+        )?;
+        let prev_txs = utxo.blockchain.get_prev_txs(&tx)?;
+        wallet.sign_transaction(&mut tx, prev_txs, options.sighash)?;
+        Ok(Some(tx))
+    }
+
+    /// NewUTXOMulti 创建支付给多个接收方的交易，找零合并为一个输出
+    pub fn new_utxo_multi(
+        wallet: &Wallet,
+        outputs: &[(String, u64)],
+        options: &TxOptions,
+        utxo: &UTXOSet,
+    ) -> Result<Transaction> {
+        info!(
+            "new UTXO Transaction from: {} to {} recipient(s)",
+            wallet.get_address(utxo.blockchain.network),
+            outputs.len()
+        );
+        let (mut tx, prev_txs) =
+            Transaction::build_unsigned_multi(&wallet.public_key, outputs, options, utxo)?;
+        wallet.sign_transaction(&mut tx, prev_txs, options.sighash)?;
+        Ok(tx)
+    }
+
+    /// BuildUnsigned 只凭公钥选币并构造未签名交易，同时返回签名所需的前序交易
+    ///
+    /// 私钥可以留在离线机器上，用 Transaction::sign 完成签名
+    pub fn build_unsigned(
+        pub_key: &[u8],
+        to: &str,
+        amount: u64,
+        options: &TxOptions,
+        utxo: &UTXOSet,
+    ) -> Result<(Transaction, HashMap<String, Transaction>)> {
+        Transaction::build_unsigned_multi(pub_key, &[(to.to_string(), amount)], options, utxo)
+    }
+
+    fn build_unsigned_multi(
+        pub_key: &[u8],
+        outputs: &[(String, u64)],
+        options: &TxOptions,
+        utxo: &UTXOSet,
+    ) -> Result<(Transaction, HashMap<String, Transaction>)> {
+        let total = Transaction::required_amount(outputs, options.fee)?;
+
+        let mut pub_key_hash = pub_key.to_vec();
+        hash_pub_key(&mut pub_key_hash);
+
+        let acc_v = utxo.find_spendable_outputs_with(
+            &pub_key_hash,
+            total,
+            options.strategy,
+            options.min_conf,
+        )?;
+
+        if acc_v.0 < total {
+            error!("Not Enough balance");
+            return Err(format_err!(
+                "Not Enough balance: requested {}, available {}",
+                total,
+                acc_v.0
+            ));
+        }
+
+        let network = utxo.blockchain.network;
+        let tx = Transaction::new_unsigned(pub_key, outputs, options, acc_v, network)?;
+        let prev_txs = utxo.blockchain.get_prev_txs(&tx)?;
+        Ok((tx, prev_txs))
+    }
+
+    /// RequiredAmount 校验各接收金额与手续费，返回需要花费的总额
+    fn required_amount(outputs: &[(String, u64)], fee: u64) -> Result<u64> {
+        if outputs.is_empty() {
+            return Err(format_err!("No recipient given"));
+        }
+
+        let mut total = fee;
+        for (to, amount) in outputs {
+            if *amount == 0 {
+                return Err(format_err!(
+                    "Invalid amount {} for recipient {}",
+                    amount,
+                    to
+                ));
+            }
+            total = total
+                .checked_add(*amount)
+                .ok_or_else(|| format_err!("Total amount overflows"))?;
+        }
+        Ok(total)
+    }
+
+    /// NewUnsigned 用选中的输出构造未签名交易，剩余部分扣除手续费后找零，地址属于 network
+    ///
+    /// 分支定界选出的精确组合不找零，容差内的差额计入手续费；
+    /// 低于粉尘限制的找零同样并入手续费
+    fn new_unsigned(
+        pub_key: &[u8],
+        outputs: &[(String, u64)],
+        options: &TxOptions,
+        spendable: (u64, Vec<OutPoint>),
+        network: Network,
+    ) -> Result<Transaction> {
+        let total = Transaction::required_amount(outputs, options.fee)?;
+        if let Some(memo) = &options.memo
+            && memo.len() > MAX_MEMO_LEN
+        {
+            return Err(format_err!(
+                "Memo size {} exceeds the limit {}",
+                memo.len(),
+                MAX_MEMO_LEN
+            ));
+        }
+        let (accumulated, unspent) = spendable;
+
+        let mut vin = Vec::new();
+        for outpoint in unspent {
+            vin.push(TXInput {
+                outpoint,
+                signature: Vec::new(),
+                pub_key: pub_key.to_vec(),
+                condition: None,
+                signatures: Vec::new(),
+            });
+        }
+
+        let mut vout = Vec::new();
+        for (to, amount) in outputs {
+            vout.push(TXOutput::new(*amount, to.clone(), network)?);
+        }
+        let change = accumulated.checked_sub(total).ok_or_else(|| {
+            format_err!(
+                "Not Enough balance: requested {}, available {}",
+                total,
+                accumulated
+            )
+        })?;
+        let exact = options.strategy == CoinSelection::BranchAndBound && change <= BNB_TOLERANCE;
+        if change >= DUST_LIMIT && !exact {
+            let change_address = match &options.change_address {
+                Some(address) => address.clone(),
+                None => {
+                    let mut pub_key_hash = pub_key.to_vec();
+                    hash_pub_key(&mut pub_key_hash);
+                    address_from_pub_key_hash(&pub_key_hash, network)
+                }
+            };
+            vout.push(TXOutput::new(change, change_address, network)?)
+        }
+        if let Some(data) = &options.data {
+            vout.push(TXOutput::new_data(data.clone())?);
+        }
+
+        let mut tx = Transaction {
+            version: TX_VERSION,
+            id: String::new(),
+            vin,
+            vout,
+            memo: options.memo.clone(),
+            lock_until: options.lock_until,
+        };
+        tx.id = tx.compute_id();
+        Ok(tx)
+    }
+
+    /// NewCoinbaseTX 创建新的创币交易，fee 为区块内交易手续费之和，奖励发往 network 上的地址 to
+    ///
+    /// 输入数据以区块高度 height 开头，不同高度上发往同一地址的创币交易 id 不同
+    pub fn new_coinbase(
+        to: String,
+        data: String,
+        height: i32,
+        fee: u64,
+        network: Network,
+    ) -> Result<Transaction> {
+        info!("new coinbase Transaction to: {}", to);
+        // 未指定数据时附加随机字节，使同一高度上发往同一地址的创币交易 id 也不同
+        let data = if data.is_empty() {
+            let mut key: [u8; 32] = [0; 32];
+            let mut rand = OsRng;
+            rand.fill_bytes(&mut key);
+            let mut pub_key = format!("Reward to '{}'", to).into_bytes();
+            pub_key.extend_from_slice(&key);
+            pub_key
+        } else {
+            data.into_bytes()
+        };
+        let max_len = MAX_COINBASE_DATA_LEN - COINBASE_HEIGHT_LEN;
+        if !(MIN_COINBASE_DATA_LEN..=max_len).contains(&data.len()) {
+            return Err(format_err!(
+                "Coinbase data must be {} to {} bytes, got {}",
+                MIN_COINBASE_DATA_LEN,
+                max_len,
+                data.len()
+            ));
+        }
+        let mut pub_key = height.to_le_bytes().to_vec();
+        pub_key.extend_from_slice(&data);
+        let reward = block_subsidy(height)
+            .checked_add(fee)
+            .ok_or_else(|| format_err!("Coinbase reward overflows"))?;
+        // 奖励减半到零且没有手续费时，创币交易不产生输出
+        let vout = if reward == 0 {
+            Vec::new()
+        } else {
+            vec![TXOutput::new_reward(reward, to, network)?]
+        };
+
+        let mut tx = Transaction {
+            version: TX_VERSION,
+            id: String::new(),
+            vin: vec![TXInput {
+                outpoint: OutPoint::null(),
+                signature: Vec::new(),
+                pub_key,
+                condition: None,
+                signatures: Vec::new(),
+            }],
+            vout,
+            memo: None,
+            lock_until: 0,
+        };
+        tx.id = tx.compute_id();
+        Ok(tx)
+    }
+
+    /// NewMultiSigSpend 创建花费 prev 第 vout 个多签输出的未签名交易，找零仍锁定到该条件
+    pub fn new_multisig_spend(
+        prev: &Transaction,
+        vout: u32,
+        condition: &LockingCondition,
+        to: &str,
+        amount: u64,
+        fee: u64,
+        network: Network,
+    ) -> Result<Transaction> {
+        let prev_out = prev
+            .vout
+            .get(vout as usize)
+            .ok_or_else(|| format_err!("referenced output {}:{} not found", prev.id, vout))?;
+        if prev_out.is_data() || prev_out.pub_key_hash != condition.identifier() {
+            return Err(format_err!(
+                "Output {}:{} is not locked by the given condition",
+                prev.id,
+                vout
+            ));
+        }
+        let total = Transaction::required_amount(&[(to.to_string(), amount)], fee)?;
+        let change = prev_out.value.checked_sub(total).ok_or_else(|| {
+            format_err!(
+                "Not Enough balance: requested {}, available {}",
+                total,
+                prev_out.value
+            )
+        })?;
+
+        let mut outputs = vec![TXOutput::new(amount, to.to_string(), network)?];
+        if change >= DUST_LIMIT {
+            outputs.push(TXOutput::new_multisig(change, condition)?);
+        }
+        let mut tx = Transaction {
+            version: TX_VERSION,
+            id: String::new(),
+            vin: vec![TXInput {
+                outpoint: OutPoint::new(&prev.id, vout),
+                signature: Vec::new(),
+                pub_key: Vec::new(),
+                condition: Some(condition.clone()),
+                signatures: Vec::new(),
+            }],
+            vout: outputs,
+            memo: None,
+            lock_until: 0,
+        };
+        tx.id = tx.compute_id();
+        Ok(tx)
+    }
+
+    /// Size 返回交易序列化后的字节数，即交易在区块中占用的字节数
+    pub fn size(&self) -> Result<usize> {
+        Ok(serialized_size(self)? as usize)
+    }
+
+    /// EstimateSize 估算交易序列化后的字节数，签名和公钥用占位数据填充
+    pub fn estimate_size(num_inputs: usize, num_outputs: usize) -> usize {
+        let input = TXInput {
+            outpoint: OutPoint::new(&"0".repeat(TXID_HEX_LEN), 0),
+            signature: vec![0; SIGNATURE_LEN],
+            pub_key: vec![0; PUB_KEY_LEN],
+            condition: None,
+            signatures: Vec::new(),
+        };
+        let output = TXOutput {
+            value: 0,
+            pub_key_hash: vec![0; PUB_KEY_HASH_LEN],
+            data: None,
+        };
+        let tx = Transaction {
+            version: TX_VERSION,
+            id: "0".repeat(TXID_HEX_LEN),
+            vin: vec![input; num_inputs],
+            vout: vec![output; num_outputs],
+            memo: None,
+            lock_until: 0,
+        };
+        serialized_size(&tx).unwrap_or_default() as usize
+    }
+
+    /// ComputeId 计算交易 id，即去掉全部签名后的交易哈希
+    ///
+    /// 签名摘要不包含 id，id 也就不能依赖签名，签名前后计算结果相同
+    pub fn compute_id(&self) -> String {
+        let mut unsigned = self.clone();
+        for vin in &mut unsigned.vin {
+            vin.signature.clear();
+            vin.signatures.clear();
+        }
+        let mut hasher = Sha256::new();
+        hasher.input(&unsigned.encode());
+        hasher.result_str()
+    }
+
+    /// ValidateId 重新计算交易 id 并检查与 id 字段一致
+    pub fn validate_id(&self) -> VerifyResult<()> {
+        let expected = self.compute_id();
+        if expected != self.id {
+            return Err(TxVerifyError::IdMismatch {
+                id: self.id.clone(),
+                expected,
+            });
+        }
+        Ok(())
+    }
+
+    /// IsCoinbase 检查交易是否为创币交易
+    pub fn is_coinbase(&self) -> bool {
+        self.vin.len() == 1 && self.vin[0].outpoint.is_null() && self.vin[0].signature.is_empty()
+    }
+
+    /// CoinbaseHeight 返回创币交易数据开头记录的区块高度，不是创币交易时返回 None
+    ///
+    /// 旧版本的创币交易不记录高度，返回的是数据开头几个字节的解读
+    pub fn coinbase_height(&self) -> Option<i32> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        let prefix = self.vin[0].pub_key.get(..COINBASE_HEIGHT_LEN)?;
+        Some(i32::from_le_bytes(prefix.try_into().ok()?))
+    }
+
+    /// CoinbaseMessage 返回高度为 height 的区块中创币交易携带的数据，不含开头的高度
+    ///
+    /// 旧版本的创币交易不记录高度，开头与 height 不符时原样返回全部数据
+    pub fn coinbase_message(&self, height: i32) -> &[u8] {
+        let data = &self.vin[0].pub_key;
+        if self.coinbase_height() == Some(height) {
+            &data[COINBASE_HEIGHT_LEN..]
+        } else {
+            data
+        }
+    }
+
+    /// Outpoints 返回交易花费的全部输出，创币交易没有
+    pub fn outpoints(&self) -> impl Iterator<Item = &OutPoint> {
+        let vin = if self.is_coinbase() {
+            &[][..]
+        } else {
+            &self.vin[..]
+        };
+        vin.iter().map(|vin| &vin.outpoint)
+    }
+
+    /// IsFinal 检查交易能否打包进高度为 height 的区块
+    pub fn is_final(&self, height: i32) -> bool {
+        self.lock_until <= height
+    }
+
+    /// Verify 验证交易，失败时返回具体原因
+    pub fn verify(&self, prev_txs: HashMap<String, Transaction>) -> VerifyResult<()> {
+        let checks = self.prepare_verify(&prev_txs)?;
+        verify_signatures(&checks, false).map_err(SignatureCheck::error)
+    }
+
+    /// PrepareVerify 完成签名以外的全部检查，返回每个输入待验证的签名
+    ///
+    /// 签名留给调用方验证，整个区块的签名可以一起并行验证。
+    /// 旧版本的交易按该版本当时的规则检查，已保存的旧链仍能通过验证
+    pub fn prepare_verify(
+        &self,
+        prev_txs: &HashMap<String, Transaction>,
+    ) -> VerifyResult<Vec<SignatureCheck>> {
+        if self.version > TX_VERSION {
+            return Err(TxVerifyError::UnsupportedVersion {
+                version: self.version,
+            });
+        }
+        // 金额为 i32 的版本无法编码更大的金额
+        if self.version < U64_TX_VERSION
+            && self
+                .vout
+                .iter()
+                .any(|out| i32::try_from(out.value).is_err())
+        {
+            return Err(TxVerifyError::ValueOverflow);
+        }
+        self.validate_id()?;
+
+        if let Some(memo) = &self.memo
+            && memo.len() > MAX_MEMO_LEN
+        {
+            return Err(TxVerifyError::MemoTooLong { len: memo.len() });
+        }
+
+        if self.is_coinbase() {
+            // 版本 0 的旧创币交易携带的数据没有长度限制
+            let len = self.vin[0].pub_key.len();
+            if self.version != LEGACY_TX_VERSION
+                && !(MIN_COINBASE_DATA_LEN..=MAX_COINBASE_DATA_LEN).contains(&len)
+            {
+                return Err(TxVerifyError::BadCoinbaseData { len });
+            }
+            return Ok(Vec::new());
+        }
+
+        // 版本 0 的旧交易没有数据输出，也没有粉尘限制
+        if self.version != LEGACY_TX_VERSION {
+            self.verify_outputs()?;
+        }
+
+        let mut seen = HashSet::new();
+        for outpoint in self.outpoints() {
+            if !seen.insert(outpoint) {
+                return Err(TxVerifyError::DuplicateInput {
+                    outpoint: outpoint.clone(),
+                });
+            }
+        }
+
+        for (in_id, vin) in self.vin.iter().enumerate() {
+            Transaction::prev_output(prev_txs, in_id, vin)?;
+        }
+
+        let inputs = self.input_value(prev_txs)?;
+        let outputs = self.output_value()?;
+        if outputs > inputs {
+            return Err(TxVerifyError::OutputsExceedInputs { inputs, outputs });
+        }
+
+        let mut checks = Vec::new();
+        for (in_id, vin) in self.vin.iter().enumerate() {
+            let prev_out = Transaction::prev_output(prev_txs, in_id, vin)?;
+            match &vin.condition {
+                None => {
+                    // 公钥必须与被花费输出锁定的公钥哈希一致
+                    let mut pub_key_hash = vin.pub_key.clone();
+                    hash_pub_key(&mut pub_key_hash);
+                    if pub_key_hash != prev_out.pub_key_hash || !vin.signatures.is_empty() {
+                        return Err(TxVerifyError::KeyMismatch { input: in_id });
+                    }
+                    checks.push(self.signature_check(
+                        in_id,
+                        prev_txs,
+                        &vin.pub_key,
+                        &vin.signature,
+                    )?);
+                }
+                Some(condition) => {
+                    let LockingCondition::MultiSig { m, key_hashes } = condition else {
+                        return Err(TxVerifyError::BadCondition { input: in_id });
+                    };
+                    if self.version < MULTISIG_TX_VERSION
+                        || !condition.is_valid()
+                        || condition.identifier() != prev_out.pub_key_hash
+                        || !vin.signature.is_empty()
+                        || !vin.pub_key.is_empty()
+                    {
+                        return Err(TxVerifyError::BadCondition { input: in_id });
+                    }
+
+                    // 每个列出的公钥最多计一次，至少需要 m 个不同的签名者
+                    let mut signers = HashSet::new();
+                    for (pub_key, signature) in &vin.signatures {
+                        let mut pub_key_hash = pub_key.clone();
+                        hash_pub_key(&mut pub_key_hash);
+                        if !key_hashes.contains(&pub_key_hash) || !signers.insert(pub_key_hash) {
+                            return Err(TxVerifyError::BadSigner { input: in_id });
+                        }
+                        checks.push(self.signature_check(in_id, prev_txs, pub_key, signature)?);
+                    }
+                    if signers.len() < *m as usize {
+                        return Err(TxVerifyError::NotEnoughSignatures {
+                            input: in_id,
+                            signers: signers.len(),
+                            required: *m,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(checks)
+    }
+
+    /// SignatureCheck 解析第 in_id 个输入的一个签名
+    fn signature_check(
+        &self,
+        in_id: usize,
+        prev_txs: &HashMap<String, Transaction>,
+        pub_key: &[u8],
+        signature: &[u8],
+    ) -> VerifyResult<SignatureCheck> {
+        // 签名由 ed25519 签名和类型字节组成，长度不符的直接拒绝；
+        // 类型字节引入之前的签名只有 ed25519 签名，总是覆盖整个交易
+        let signature_len = if self.version >= SIGHASH_TX_VERSION {
+            SIGNATURE_LEN
+        } else {
+            ED25519_SIG_LEN
+        };
+        if signature.len() != signature_len || pub_key.len() != PUB_KEY_LEN {
+            return Err(TxVerifyError::MalformedSignature { input: in_id });
+        }
+
+        let (signature, sighash) = signature.split_at(ED25519_SIG_LEN);
+        let sighash = match sighash.first() {
+            Some(&byte) => SigHashType::from_byte(byte)
+                .ok_or(TxVerifyError::UnknownSigHash { input: in_id })?,
+            None => SigHashType::All,
+        };
+
+        Ok(SignatureCheck {
+            txid: self.id.clone(),
+            input: in_id,
+            message: self.signature_hash(in_id, prev_txs, sighash)?,
+            pub_key: pub_key.to_vec(),
+            signature: signature.to_vec(),
+        })
+    }
+
+    /// VerifyOutputs 检查普通输出不低于粉尘限制，数据输出最多一个且合法
+    ///
+    /// 数据输出不进入UTXO集合，必须位于最后，否则会打乱可花费输出在集合中的下标
+    fn verify_outputs(&self) -> VerifyResult<()> {
+        if self.vout.iter().filter(|out| out.is_data()).count() > 1 {
+            return Err(TxVerifyError::MultipleDataOutputs);
+        }
+
+        for (idx, out) in self.vout.iter().enumerate() {
+            if let Some(data) = out.get_data() {
+                if idx != self.vout.len() - 1
+                    || out.value != 0
+                    || !out.pub_key_hash.is_empty()
+                    || data.len() > MAX_DATA_LEN
+                {
+                    return Err(TxVerifyError::InvalidDataOutput { output: idx });
+                }
+            } else if out.value < DUST_LIMIT {
+                return Err(TxVerifyError::DustOutput { output: idx });
+            }
+        }
+        Ok(())
+    }
+
+    /// Fee 返回交易手续费，即输入总额减去输出总额，输出超过输入时返回错误
+    pub fn fee(&self, prev_txs: &HashMap<String, Transaction>) -> Result<u64> {
+        if self.is_coinbase() {
+            return Ok(0);
+        }
+
+        let inputs = self.input_value(prev_txs)?;
+        let outputs = self.output_value()?;
+        inputs
+            .checked_sub(outputs)
+            .ok_or(TxVerifyError::OutputsExceedInputs { inputs, outputs }.into())
+    }
+
+    /// InputValue 返回交易引用的全部输出的金额之和，溢出时返回错误
+    fn input_value(&self, prev_txs: &HashMap<String, Transaction>) -> VerifyResult<u64> {
+        let mut input_value: u64 = 0;
+        for (in_id, vin) in self.vin.iter().enumerate() {
+            input_value = input_value
+                .checked_add(Transaction::prev_output(prev_txs, in_id, vin)?.value)
+                .ok_or(TxVerifyError::ValueOverflow)?;
+        }
+        Ok(input_value)
+    }
+
+    /// OutputValue 返回交易全部输出的金额之和，溢出时返回错误
+    pub fn output_value(&self) -> VerifyResult<u64> {
+        self.vout
+            .iter()
+            .try_fold(0u64, |acc, out| acc.checked_add(out.value))
+            .ok_or(TxVerifyError::ValueOverflow)
+    }
+
+    /// PrevOutput 返回第 in_id 个输入引用的前序交易输出，交易或输出不存在时返回错误
+    fn prev_output<'a>(
+        prev_txs: &'a HashMap<String, Transaction>,
+        in_id: usize,
+        vin: &TXInput,
+    ) -> VerifyResult<&'a TXOutput> {
+        let outpoint = &vin.outpoint;
+        let missing = || TxVerifyError::MissingPrevTx {
+            txid: outpoint.txid.clone(),
+        };
+        let prev_tx = prev_txs.get(&outpoint.txid).ok_or_else(missing)?;
+        if prev_tx.id.is_empty() {
+            return Err(missing());
+        }
+        prev_tx
+            .vout
+            .get(outpoint.vout as usize)
+            .ok_or(TxVerifyError::InputIndexOutOfRange {
+                input: in_id,
+                vout: outpoint.vout,
+            })
+    }
+
+    /// Sign 按 sighash 指定的范围用 signer 对交易的每个输入进行签名
+    pub fn sign(
+        &mut self,
+        signer: &dyn Signer,
+        prev_txs: HashMap<String, Transaction>,
+        sighash: SigHashType,
+    ) -> Result<()> {
+        if self.is_coinbase() {
+            return Ok(());
+        }
+
+        for (in_id, vin) in self.vin.iter().enumerate() {
+            Transaction::prev_output(&prev_txs, in_id, vin)?;
+        }
+
+        for in_id in 0..self.vin.len() {
+            let message = self.signature_hash(in_id, &prev_txs, sighash)?;
+            let mut signature = signer.sign(message.as_bytes())?;
+            if self.version >= SIGHASH_TX_VERSION {
+                signature.push(sighash.to_byte());
+            }
+
+            let vin = &mut self.vin[in_id];
+            if let Some(LockingCondition::MultiSig { key_hashes, .. }) = &vin.condition {
+                // 多签输入只替换本签名者的签名，其余签名者可以继续签名
+                let pub_key = signer.public_key().to_vec();
+                let mut pub_key_hash = pub_key.clone();
+                hash_pub_key(&mut pub_key_hash);
+                if !key_hashes.contains(&pub_key_hash) {
+                    return Err(format_err!(
+                        "Key is not listed in the multisig condition of input {}",
+                        in_id
+                    ));
+                }
+                vin.signatures.retain(|(key, _)| *key != pub_key);
+                vin.signatures.push((pub_key, signature));
+            } else {
+                vin.signature = signature;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// SignatureHash 返回第 in_id 个输入的签名消息
+    ///
+    /// 签名类型决定精简副本中保留哪些输入和输出，类型本身也参与哈希，
+    /// 防止有人改写签名末尾的类型字节；类型字节引入之前的版本只哈希精简副本
+    fn signature_hash(
+        &self,
+        in_id: usize,
+        prev_txs: &HashMap<String, Transaction>,
+        sighash: SigHashType,
+    ) -> VerifyResult<String> {
+        let mut tx_copy = self.trim_copy();
+        tx_copy.id = String::new();
+        tx_copy.vin[in_id].pub_key = Transaction::prev_output(prev_txs, in_id, &self.vin[in_id])?
+            .pub_key_hash
+            .clone();
+
+        match sighash {
+            SigHashType::All => {}
+            SigHashType::Single => {
+                if in_id >= tx_copy.vout.len() {
+                    return Err(TxVerifyError::NoOutputForSigHashSingle { input: in_id });
+                }
+                // 保留输出下标，之前的输出置空，之后的输出不参与签名
+                tx_copy.vout.truncate(in_id + 1);
+                for out in &mut tx_copy.vout[..in_id] {
+                    *out = TXOutput {
+                        value: 0,
+                        pub_key_hash: Vec::new(),
+                        data: None,
+                    };
+                }
+            }
+            SigHashType::AnyoneCanPay => {
+                tx_copy.vin = vec![tx_copy.vin.swap_remove(in_id)];
+            }
+        }
+
+        let mut data = tx_copy.encode();
+        if self.version >= SIGHASH_TX_VERSION {
+            data.push(sighash.to_byte());
+        }
+        let mut hasher = Sha256::new();
+        hasher.input(&data[..]);
+        Ok(hasher.result_str())
+    }
+
+    /// Hash 返回包含签名的交易哈希，旧区块的默克尔树由它构建
+    pub fn hash(&self) -> Result<String> {
+        let mut hasher = Sha256::new();
+        hasher.input(&self.encode());
+        Ok(hasher.result_str())
+    }
+
+    /// ToHex 将完整交易（含 id、签名和公钥）编码为十六进制字符串，用于离线签名和调试
+    pub fn to_hex(&self) -> Result<String> {
+        Ok(hex::encode(serialize(self)?))
+    }
+
+    /// FromHex 解析 ToHex 生成的十六进制字符串，拒绝非法字符、截断数据和多余字节
+    pub fn from_hex(raw: &str) -> Result<Transaction> {
+        let bytes =
+            hex::decode(raw.trim()).map_err(|e| format_err!("Invalid transaction hex: {}", e))?;
+        DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes()
+            .deserialize(&bytes)
+            .map_err(|e| format_err!("Invalid raw transaction: {}", e))
+    }
+
+    /// Encode 返回按交易版本计算 id 和签名摘要所用的编码，id 不参与编码
+    ///
+    /// 版本 4 之前沿用当时的 bincode 布局：id 为空字符串，输入为 (txid, vout, signature, pub_key)。
+    /// 版本 0 只有 id、输入和 (value, pub_key_hash) 输出；版本 1 起依次为 version、id、输入、
+    /// (value, pub_key_hash, data) 输出、memo 和 lock_until；版本 0 和 1 的金额为 i32
+    fn encode(&self) -> Vec<u8> {
+        let vin: Vec<_> = self
+            .vin
+            .iter()
+            .map(|vin| {
+                let outpoint = &vin.outpoint;
+                (
+                    &outpoint.txid,
+                    outpoint.vout as i32,
+                    &vin.signature,
+                    &vin.pub_key,
+                )
+            })
+            .collect();
+        let encoded = match self.version {
+            LEGACY_TX_VERSION => {
+                let vout: Vec<_> = self
+                    .vout
+                    .iter()
+                    .map(|out| (out.value as i32, &out.pub_key_hash))
+                    .collect();
+                serialize(&("", vin, vout))
+            }
+            version if version < U64_TX_VERSION => {
+                let vout: Vec<_> = self
+                    .vout
+                    .iter()
+                    .map(|out| (out.value as i32, &out.pub_key_hash, &out.data))
+                    .collect();
+                serialize(&(version, "", vin, vout, &self.memo, self.lock_until))
+            }
+            version if version < CANONICAL_TX_VERSION => {
+                let vout: Vec<_> = self
+                    .vout
+                    .iter()
+                    .map(|out| (out.value, &out.pub_key_hash, &out.data))
+                    .collect();
+                serialize(&(version, "", vin, vout, &self.memo, self.lock_until))
+            }
+            _ => return self.canonical_bytes(),
+        };
+        encoded.unwrap_or_default()
+    }
+
+    /// CanonicalBytes 返回计算交易哈希和签名摘要所用的规范编码
+    ///
+    /// 整数为固定宽度小端序，字节串前置 u64 长度，可选字段前置 1 字节标记（0 无，1 有）。
+    /// 字段顺序：version(u32)、输入个数(u64)，每个输入的 txid、vout(u32)、signature、pub_key、
+    /// condition（见 LockingCondition::encode）、签名对个数(u64) 及各 (公钥, 签名)，
+    /// 输出个数(u64)，每个输出的 value(u64)、pub_key_hash、data，最后是 memo 和 lock_until(i32)。
+    /// id 由该编码计算得出，不参与编码
+    fn canonical_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.version.to_le_bytes());
+        buf.extend_from_slice(&(self.vin.len() as u64).to_le_bytes());
+        for input in &self.vin {
+            put_bytes(&mut buf, input.outpoint.txid.as_bytes());
+            buf.extend_from_slice(&input.outpoint.vout.to_le_bytes());
+            put_bytes(&mut buf, &input.signature);
+            put_bytes(&mut buf, &input.pub_key);
+            match &input.condition {
+                Some(condition) => {
+                    buf.push(1);
+                    condition.encode(&mut buf);
+                }
+                None => buf.push(0),
+            }
+            buf.extend_from_slice(&(input.signatures.len() as u64).to_le_bytes());
+            for (pub_key, signature) in &input.signatures {
+                put_bytes(&mut buf, pub_key);
+                put_bytes(&mut buf, signature);
+            }
+        }
+        buf.extend_from_slice(&(self.vout.len() as u64).to_le_bytes());
+        for output in &self.vout {
+            buf.extend_from_slice(&output.value.to_le_bytes());
+            put_bytes(&mut buf, &output.pub_key_hash);
+            put_option(&mut buf, output.data.as_deref());
+        }
+        put_option(&mut buf, self.memo.as_ref().map(|memo| memo.as_bytes()));
+        buf.extend_from_slice(&self.lock_until.to_le_bytes());
+        buf
+    }
+
+    /// TrimmedCopy 创建用于签名的精简交易副本
+    fn trim_copy(&self) -> Transaction {
+        let mut vin = Vec::new();
+        let mut vout = Vec::new();
+
+        for v in &self.vin {
+            vin.push(TXInput {
+                outpoint: v.outpoint.clone(),
+                signature: Vec::new(),
+                pub_key: Vec::new(),
+                condition: v.condition.clone(),
+                signatures: Vec::new(),
+            })
+        }
+
+        for v in &self.vout {
+            vout.push(TXOutput {
+                value: v.value,
+                pub_key_hash: v.pub_key_hash.clone(),
+                data: v.data.clone(),
+            })
+        }
+
+        Transaction {
+            version: self.version,
+            id: self.id.clone(),
+            vin,
+            vout,
+            memo: self.memo.clone(),
+            lock_until: self.lock_until,
+        }
+    }
+}
+
+/// BlockSubsidy 返回高度为 height 的区块的挖矿奖励，每 HALVING_INTERVAL 个区块减半直到为零
+pub fn block_subsidy(height: i32) -> u64 {
+    let halvings = height.max(0) / HALVING_INTERVAL;
+    SUBSIDY.checked_shr(halvings as u32).unwrap_or(0)
+}
+
+/// put_bytes 写入 u64 长度前缀和字节串
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// put_option 写入存在标记，存在时再写入字节串
+fn put_option(buf: &mut Vec<u8>, bytes: Option<&[u8]>) {
+    match bytes {
+        Some(bytes) => {
+            buf.push(1);
+            put_bytes(buf, bytes);
+        }
+        None => buf.push(0),
+    }
+}
+
+impl TXOutput {
+    /// IsLockedWithKey 检查输出是否由指定公钥哈希锁定
+    pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
+        !self.is_data() && self.pub_key_hash == pub_key_hash
+    }
+
+    /// IsData 检查输出是否为不可花费的数据输出
+    pub fn is_data(&self) -> bool {
+        self.data.is_some()
+    }
+
+    /// GetData 返回数据输出携带的数据
+    pub fn get_data(&self) -> Option<&[u8]> {
+        self.data.as_deref()
+    }
+
+    /// Lock 把输出锁定到 network 上的地址 address
+    fn lock(&mut self, address: &str, network: Network) -> Result<()> {
+        let pub_key_hash = decode_address(address, network)?;
+        debug!("lock: {}", address);
+        self.pub_key_hash = pub_key_hash;
+        Ok(())
+    }
+
+    /// NewTXOutput 创建锁定到 network 上的地址 address 的输出，金额不得低于粉尘限制
+    pub fn new(value: u64, address: String, network: Network) -> Result<Self> {
+        if value < DUST_LIMIT {
+            return Err(format_err!(
+                "Output value {} is below the dust limit {}",
+                value,
+                DUST_LIMIT
+            ));
+        }
+        TXOutput::new_reward(value, address, network)
+    }
+
+    /// NewMultiSig 创建锁定到多签条件的输出，pub_key_hash 为条件的标识
+    pub fn new_multisig(value: u64, condition: &LockingCondition) -> Result<Self> {
+        if value < DUST_LIMIT {
+            return Err(format_err!(
+                "Output value {} is below the dust limit {}",
+                value,
+                DUST_LIMIT
+            ));
+        }
+        Ok(TXOutput {
+            value,
+            pub_key_hash: condition.identifier(),
+            data: None,
+        })
+    }
+
+    /// NewData 创建携带数据的输出，金额为 0 且没有锁定公钥哈希，任何人都无法花费
+    pub fn new_data(data: Vec<u8>) -> Result<Self> {
+        if data.len() > MAX_DATA_LEN {
+            return Err(format_err!(
+                "Data size {} exceeds the limit {}",
+                data.len(),
+                MAX_DATA_LEN
+            ));
+        }
+        Ok(TXOutput {
+            value: 0,
+            pub_key_hash: Vec::new(),
+            data: Some(data),
+        })
+    }
+
+    /// NewReward 创建创币交易的奖励输出，不受粉尘限制，但金额不能为 0
+    fn new_reward(value: u64, address: String, network: Network) -> Result<Self> {
+        if value == 0 {
+            return Err(format_err!("Output value must not be zero"));
+        }
+        let mut txo = TXOutput {
+            value,
+            pub_key_hash: Vec::new(),
+            data: None,
+        };
+        txo.lock(&address, network)?;
+        Ok(txo)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::walletstorage::memory::MemoryStorage;
+    use crypto::ed25519;
+    use rand::Rng;
+    use crate::block::Block;
+    use crate::blockchain::Blockchain;
+
+    fn temp_blockchain(address: &str) -> Blockchain {
+        let mut bc = Blockchain::in_memory();
+        let cbtx =
+            Transaction::new_coinbase(address.to_string(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        bc.add_block(Block::new_genesis_block(cbtx)).unwrap();
+        bc
+    }
+
+    fn spend(wallet: &Wallet, prev: &Transaction, to: &str, amount: u64, fee: u64) -> Transaction {
+        let unspent = vec![OutPoint::new(&prev.id, 0)];
+        let options = TxOptions {
+            fee,
+            ..TxOptions::default()
+        };
+        let mut tx = Transaction::new_unsigned(
+            &wallet.public_key,
+            &[(to.to_string(), amount)],
+            &options,
+            (prev.vout[0].value, unspent),
+            Network::Mainnet,
+        )
+        .unwrap();
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev.id.clone(), prev.clone());
+        tx.sign(wallet, prev_txs, SigHashType::All).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_wallet_sign_transaction() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        let bc = temp_blockchain(&wa1);
+        let prev = bc.iter().next().unwrap().get_transaction()[0].clone();
+        let unsigned = Transaction::new_unsigned(
+            &w.public_key,
+            &[(wa2, 4)],
+            &TxOptions::default(),
+            (SUBSIDY, vec![OutPoint::new(&prev.id, 0)]),
+            Network::Mainnet,
+        )
+        .unwrap();
+
+        let mut signed = unsigned.clone();
+        let prev_txs = bc.get_prev_txs(&signed).unwrap();
+        w.sign_transaction(&mut signed, prev_txs, SigHashType::All)
+            .unwrap();
+        bc.verify_transacton(&signed).unwrap();
+
+        // 与直接用私钥签名摘要的结果逐字节相同
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev.id.clone(), prev);
+        let mut raw = unsigned;
+        let message = raw.signature_hash(0, &prev_txs, SigHashType::All).unwrap();
+        raw.vin[0].signature = sign_message(&w.secret_key, message.as_bytes()).unwrap();
+        raw.vin[0].signature.push(SigHashType::All.to_byte());
+        assert_eq!(signed.to_hex().unwrap(), raw.to_hex().unwrap());
+    }
+
+    #[test]
+    fn test_signature() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        ws.save_all().unwrap();
+        drop(ws);
+
+        let data = String::from("test");
+        let tx = Transaction::new_coinbase(wa1, data, 0, 0, Network::Mainnet).unwrap();
+        assert!(tx.is_coinbase());
+
+        let signature = ed25519::signature(tx.id.as_bytes(), &w.secret_key);
+        assert!(ed25519::verify(tx.id.as_bytes(), &w.public_key, &signature));
+    }
+
+    #[test]
+    fn test_new_utxo_multi_rejects_bad_amount() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        let bc = Blockchain::in_memory();
+        let utxo_set = UTXOSet::in_memory(bc);
+
+        let options = TxOptions::default();
+        let outputs = vec![(wa2.clone(), 3), (wa2.clone(), 0)];
+        let err = Transaction::new_utxo_multi(&w, &outputs, &options, &utxo_set).unwrap_err();
+        assert!(err.to_string().contains("Invalid amount 0"));
+
+        let outputs = vec![(wa2.clone(), u64::MAX), (wa2.clone(), 1)];
+        let err = Transaction::new_utxo_multi(&w, &outputs, &options, &utxo_set).unwrap_err();
+        assert!(err.to_string().contains("overflows"));
+        assert!(Transaction::new_utxo_multi(&w, &[], &options, &utxo_set).is_err());
+        let outputs = vec![(wa2, 1)];
+        let options = TxOptions {
+            fee: u64::MAX,
+            ..TxOptions::default()
+        };
+        assert!(Transaction::new_utxo_multi(&w, &outputs, &options, &utxo_set).is_err());
+    }
+
+    #[test]
+    fn test_new_utxo_from_inputs() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w1 = ws.get_wallet(&wa1).unwrap().clone();
+        let w2 = ws.get_wallet(&wa2).unwrap().clone();
+        drop(ws);
+
+        let mut utxo_set = UTXOSet::in_memory(temp_blockchain(&wa1));
+        utxo_set.reindex().unwrap();
+        let options = TxOptions::default();
+        let paid = Transaction::new_utxo(&w1, &wa2, 4, &options, &utxo_set).unwrap();
+        let cbtx =
+            Transaction::new_coinbase(wa2.clone(), String::new(), 1, 0, Network::Mainnet).unwrap();
+        let block = utxo_set
+            .blockchain
+            .mine_block(vec![cbtx.clone(), paid.clone()])
+            .unwrap();
+        utxo_set.connect_block(&block).unwrap();
+        let payment = OutPoint::new(&paid.id, 0);
+        let change = OutPoint::new(&paid.id, 1);
+
+        // 指定的输出被冻结时也可以花费，总额扣除金额后照常找零
+        utxo_set.freeze(&change).unwrap();
+        let tx = Transaction::new_utxo_from_inputs(
+            &w1,
+            std::slice::from_ref(&change),
+            &wa2,
+            3,
+            &options,
+            &utxo_set,
+        )
+        .unwrap();
+        assert_eq!(tx.vin[0].outpoint, change);
+        assert_eq!(tx.vin.len(), 1);
+        assert_eq!(tx.vout[1].value, SUBSIDY - 4 - 3);
+        utxo_set.blockchain.verify_transacton(&tx).unwrap();
+        assert!(Transaction::new_utxo(&w1, &wa2, 3, &options, &utxo_set).is_err());
+
+        let from = |inputs: &[OutPoint], wallet: &Wallet, amount| {
+            Transaction::new_utxo_from_inputs(wallet, inputs, &wa1, amount, &options, &utxo_set)
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(from(std::slice::from_ref(&payment), &w1, 1).contains("not owned by the wallet"));
+        assert!(from(std::slice::from_ref(&change), &w1, SUBSIDY).contains("Not Enough balance"));
+        assert!(from(&[OutPoint::new(&paid.id, 7)], &w1, 1).contains("not in the UTXO set"));
+        assert!(from(&[change.clone(), change.clone()], &w1, 1).contains("more than once"));
+        assert!(from(&[OutPoint::new(&cbtx.id, 0)], &w2, 1).contains("not mature"));
+        assert!(from(&[], &w1, 1).contains("No input"));
+    }
+
+    #[test]
+    fn test_new_consolidation() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w1 = ws.get_wallet(&wa1).unwrap().clone();
+        let w2 = ws.get_wallet(&wa2).unwrap().clone();
+        drop(ws);
+
+        let mut utxo_set = UTXOSet::in_memory(temp_blockchain(&wa1));
+        utxo_set.reindex().unwrap();
+        assert!(
+            Transaction::new_consolidation(&w1, &utxo_set, 10, 0)
+                .unwrap()
+                .is_none()
+        );
+
+        // 创世奖励拆成 4 个 2 和找零 2，区块奖励尚未成熟
+        let outputs = vec![(wa1.clone(), 2); 4];
+        let split =
+            Transaction::new_utxo_multi(&w1, &outputs, &TxOptions::default(), &utxo_set).unwrap();
+        let cbtx =
+            Transaction::new_coinbase(wa1.clone(), String::new(), 1, 0, Network::Mainnet).unwrap();
+        let block = utxo_set
+            .blockchain
+            .mine_block(vec![cbtx.clone(), split.clone()])
+            .unwrap();
+        utxo_set.connect_block(&block).unwrap();
+        let frozen = OutPoint::new(&split.id, 0);
+        utxo_set.freeze(&frozen).unwrap();
+
+        let tx = Transaction::new_consolidation(&w1, &utxo_set, 3, 1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(tx.vin.len(), 3);
+        assert!(tx.vin.iter().all(|vin| vin.outpoint.txid == split.id));
+        assert!(tx.vin.iter().all(|vin| vin.outpoint != frozen));
+        assert_eq!(tx.vout.len(), 1);
+        assert_eq!(tx.vout[0].value, 5);
+        assert!(tx.vout[0].is_locked_with_key(&decode_address(&wa1, Network::Mainnet).unwrap()));
+        utxo_set.blockchain.verify_transacton(&tx).unwrap();
+
+        let tx = Transaction::new_consolidation(&w1, &utxo_set, 10, 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(tx.vin.len(), 4);
+        assert!(Transaction::new_consolidation(&w1, &utxo_set, 1, 0).is_err());
+        let err = Transaction::new_consolidation(&w1, &utxo_set, 2, 4).unwrap_err();
+        assert!(
+            err.to_string().contains("does not cover the fee"),
+            "{}",
+            err
+        );
+        assert!(
+            Transaction::new_consolidation(&w2, &utxo_set, 10, 0)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_transaction_fee() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        let prev = Transaction::new_coinbase(wa1, String::new(), 0, 0, Network::Mainnet).unwrap();
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev.id.clone(), prev.clone());
+
+        let tx = spend(&w, &prev, &wa2, 4, 0);
+        assert_eq!(tx.vout.len(), 2);
+        assert_eq!(tx.fee(&prev_txs).unwrap(), 0);
+        tx.verify(prev_txs.clone()).unwrap();
+
+        // 手续费恰好用完找零时不再产生找零输出
+        let tx = spend(&w, &prev, &wa2, SUBSIDY - 3, 3);
+        assert_eq!(tx.vout.len(), 1);
+        assert_eq!(tx.fee(&prev_txs).unwrap(), 3);
+        tx.verify(prev_txs.clone()).unwrap();
+
+        let mut tx = spend(&w, &prev, &wa2, 4, 0);
+        tx.vout[0].value = SUBSIDY;
+        tx.id = tx.compute_id();
+        tx.sign(&w, prev_txs.clone(), SigHashType::All).unwrap();
+        assert!(tx.fee(&prev_txs).is_err());
+        assert_eq!(
+            tx.verify(prev_txs),
+            Err(TxVerifyError::OutputsExceedInputs {
+                inputs: SUBSIDY,
+                outputs: SUBSIDY + 6
+            })
+        );
+    }
+
+    #[test]
+    fn test_exact_selection_skips_change() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        let prev = Transaction::new_coinbase(wa1, String::new(), 0, 0, Network::Mainnet).unwrap();
+        let outputs = [(wa2, SUBSIDY - 1 - BNB_TOLERANCE)];
+        let spendable = || {
+            let unspent = vec![OutPoint::new(&prev.id, 0)];
+            (SUBSIDY, unspent)
+        };
+
+        let mut options = TxOptions {
+            fee: 1,
+            strategy: CoinSelection::BranchAndBound,
+            ..TxOptions::default()
+        };
+        let tx = Transaction::new_unsigned(
+            &w.public_key,
+            &outputs,
+            &options,
+            spendable(),
+            Network::Mainnet,
+        )
+        .unwrap();
+        assert_eq!(tx.vout.len(), 1);
+
+        options.strategy = CoinSelection::Accumulate;
+        let tx = Transaction::new_unsigned(
+            &w.public_key,
+            &outputs,
+            &options,
+            spendable(),
+            Network::Mainnet,
+        )
+        .unwrap();
+        assert_eq!(tx.vout.len(), 2);
+    }
+
+    #[test]
+    fn test_change_address() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let change = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        let prev = Transaction::new_coinbase(wa1, String::new(), 0, 0, Network::Mainnet).unwrap();
+        let unspent = vec![OutPoint::new(&prev.id, 0)];
+        let options = TxOptions {
+            change_address: Some(change.clone()),
+            ..TxOptions::default()
+        };
+        let tx = Transaction::new_unsigned(
+            &w.public_key,
+            &[(wa2, 4)],
+            &options,
+            (SUBSIDY, unspent),
+            Network::Mainnet,
+        )
+        .unwrap();
+
+        let change_hash = decode_address(&change, Network::Mainnet).unwrap();
+        assert!(tx.vout[1].is_locked_with_key(&change_hash));
+        assert_eq!(tx.vout[1].value, SUBSIDY - 4);
+    }
+
+    #[test]
+    fn test_data_output() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        assert!(TXOutput::new_data(vec![0; MAX_DATA_LEN + 1]).is_err());
+
+        let prev = Transaction::new_coinbase(wa1, String::new(), 0, 0, Network::Mainnet).unwrap();
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev.id.clone(), prev.clone());
+        let new_tx = |data: Vec<u8>| {
+            let unspent = vec![OutPoint::new(&prev.id, 0)];
+            let options = TxOptions {
+                data: Some(data),
+                ..TxOptions::default()
+            };
+            Transaction::new_unsigned(
+                &w.public_key,
+                &[(wa2.clone(), 4)],
+                &options,
+                (SUBSIDY, unspent),
+                Network::Mainnet,
+            )
+            .unwrap()
+        };
+
+        let mut tx = new_tx(b"hello".to_vec());
+        tx.sign(&w, prev_txs.clone(), SigHashType::All).unwrap();
+        assert_eq!(tx.vout.len(), 3);
+        assert_eq!(tx.vout[2].get_data(), Some(&b"hello"[..]));
+        assert!(!tx.vout[2].is_locked_with_key(&[]));
+        assert_eq!(tx.fee(&prev_txs).unwrap(), 0);
+        tx.verify(prev_txs.clone()).unwrap();
+
+        // 签名覆盖数据内容
+        let mut forged = tx.clone();
+        forged.vout[2].data = Some(b"world".to_vec());
+        forged.id = forged.compute_id();
+        assert_eq!(
+            forged.verify(prev_txs.clone()),
+            Err(TxVerifyError::BadSignature { input: 0 })
+        );
+
+        let mut tx = new_tx(vec![0; MAX_DATA_LEN]);
+        tx.vout[2].data = Some(vec![0; MAX_DATA_LEN + 1]);
+        tx.id = tx.compute_id();
+        tx.sign(&w, prev_txs.clone(), SigHashType::All).unwrap();
+        assert_eq!(
+            tx.verify(prev_txs.clone()),
+            Err(TxVerifyError::InvalidDataOutput { output: 2 })
+        );
+
+        let mut tx = new_tx(b"a".to_vec());
+        tx.vout.push(TXOutput::new_data(b"b".to_vec()).unwrap());
+        tx.id = tx.compute_id();
+        tx.sign(&w, prev_txs.clone(), SigHashType::All).unwrap();
+        assert_eq!(
+            tx.verify(prev_txs.clone()),
+            Err(TxVerifyError::MultipleDataOutputs)
+        );
+
+        let mut tx = new_tx(b"a".to_vec());
+        tx.vout.swap(1, 2);
+        tx.id = tx.compute_id();
+        tx.sign(&w, prev_txs.clone(), SigHashType::All).unwrap();
+        assert_eq!(
+            tx.verify(prev_txs),
+            Err(TxVerifyError::InvalidDataOutput { output: 1 })
+        );
+    }
+
+    #[test]
+    fn test_memo() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        let prev = Transaction::new_coinbase(wa1, String::new(), 0, 0, Network::Mainnet).unwrap();
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev.id.clone(), prev.clone());
+        let new_tx = |memo: String| {
+            let unspent = vec![OutPoint::new(&prev.id, 0)];
+            let options = TxOptions {
+                memo: Some(memo),
+                ..TxOptions::default()
+            };
+            Transaction::new_unsigned(
+                &w.public_key,
+                &[(wa2.clone(), 4)],
+                &options,
+                (SUBSIDY, unspent),
+                Network::Mainnet,
+            )
+        };
+
+        assert!(new_tx("x".repeat(MAX_MEMO_LEN + 1)).is_err());
+
+        let mut tx = new_tx(String::from("rent for may")).unwrap();
+        tx.sign(&w, prev_txs.clone(), SigHashType::All).unwrap();
+        assert_eq!(tx.memo.as_deref(), Some("rent for may"));
+        tx.verify(prev_txs.clone()).unwrap();
+
+        let mut forged = tx.clone();
+        forged.memo = Some(String::from("rent for june"));
+        forged.id = forged.compute_id();
+        assert_ne!(forged.hash().unwrap(), tx.hash().unwrap());
+        assert_eq!(
+            forged.verify(prev_txs.clone()),
+            Err(TxVerifyError::BadSignature { input: 0 })
+        );
+
+        let mut tx = new_tx(String::new()).unwrap();
+        tx.memo = Some("x".repeat(MAX_MEMO_LEN + 1));
+        tx.id = tx.compute_id();
+        tx.sign(&w, prev_txs.clone(), SigHashType::All).unwrap();
+        assert_eq!(
+            tx.verify(prev_txs),
+            Err(TxVerifyError::MemoTooLong {
+                len: MAX_MEMO_LEN + 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_lock_until() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        let mut bc = temp_blockchain(&wa1);
+        let cbtx =
+            Transaction::new_coinbase(wa1.clone(), String::new(), 0, 0, Network::Mainnet).unwrap();
+        bc.mine_block(vec![cbtx]).unwrap();
+        let height = bc.get_best_height().unwrap();
+        assert_eq!(height, 1);
+
+        let prev = bc.iter().next().unwrap().get_transaction()[0].clone();
+        let unspent = vec![OutPoint::new(&prev.id, 0)];
+        let options = TxOptions {
+            lock_until: height + 2,
+            ..TxOptions::default()
+        };
+        let mut tx = Transaction::new_unsigned(
+            &w.public_key,
+            &[(wa2, 4)],
+            &options,
+            (SUBSIDY, unspent),
+            Network::Mainnet,
+        )
+        .unwrap();
+        let prev_txs = bc.get_prev_txs(&tx).unwrap();
+        w.sign_transaction(&mut tx, prev_txs, SigHashType::All)
+            .unwrap();
+        assert!(!tx.is_final(height + 1));
+
+        // 修改锁定高度会使签名失效
+        let mut forged = tx.clone();
+        forged.lock_until = 0;
+        forged.id = forged.compute_id();
+        assert_eq!(
+            bc.verify_transacton(&forged)
+                .unwrap_err()
+                .downcast::<TxVerifyError>()
+                .unwrap(),
+            TxVerifyError::BadSignature { input: 0 }
+        );
+
+        let cbtx =
+            Transaction::new_coinbase(wa1.clone(), String::new(), 0, 0, Network::Mainnet).unwrap();
+        assert!(bc.mine_block(vec![cbtx.clone(), tx.clone()]).is_err());
+        assert_eq!(bc.get_best_height().unwrap(), height);
+
+        bc.mine_block(vec![cbtx]).unwrap();
+        let cbtx = Transaction::new_coinbase(wa1, String::new(), 0, 0, Network::Mainnet).unwrap();
+        let block = bc.mine_block(vec![cbtx, tx.clone()]).unwrap();
+        assert_eq!(block.get_height(), height + 2);
+        assert_eq!(block.get_transaction()[1].id, tx.id);
+    }
+
+    #[test]
+    fn test_version() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        let prev = Transaction::new_coinbase(wa1, String::new(), 0, 0, Network::Mainnet).unwrap();
+        assert_eq!(prev.version, TX_VERSION);
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev.id.clone(), prev.clone());
+
+        let tx = spend(&w, &prev, &wa2, 4, 0);
+        assert_eq!(tx.version, TX_VERSION);
+        tx.verify(prev_txs.clone()).unwrap();
+
+        // 每个已知版本的交易都按该版本的编码和签名方式通过验证
+        for version in 0..TX_VERSION {
+            let mut old = tx.clone();
+            old.version = version;
+            old.id = old.compute_id();
+            assert_ne!(old.id, tx.id);
+            old.sign(&w, prev_txs.clone(), SigHashType::All).unwrap();
+            let signature_len = if version < SIGHASH_TX_VERSION { 64 } else { 65 };
+            assert_eq!(old.vin[0].signature.len(), signature_len);
+            old.verify(prev_txs.clone()).unwrap();
+
+            let mut forged = old.clone();
+            forged.vin[0].signature = tx.vin[0].signature.clone();
+            assert!(forged.verify(prev_txs.clone()).is_err());
+        }
+
+        // 金额为 i32 的版本不能有更大的金额
+        let mut large = prev.clone();
+        large.vout[0].value = i32::MAX as u64 + 10;
+        large.id = large.compute_id();
+        prev_txs.insert(large.id.clone(), large.clone());
+        let mut old = spend(&w, &large, &wa2, i32::MAX as u64 + 1, 0);
+        old.version = 1;
+        old.id = old.compute_id();
+        old.sign(&w, prev_txs.clone(), SigHashType::All).unwrap();
+        assert_eq!(
+            old.verify(prev_txs.clone()),
+            Err(TxVerifyError::ValueOverflow)
+        );
+
+        let mut tx = tx;
+        tx.version = TX_VERSION + 1;
+        tx.sign(&w, prev_txs.clone(), SigHashType::All).unwrap();
+        assert_eq!(
+            tx.verify(prev_txs),
+            Err(TxVerifyError::UnsupportedVersion {
+                version: TX_VERSION + 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_verify_bad_reference() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        let prev = Transaction::new_coinbase(wa1, String::new(), 0, 0, Network::Mainnet).unwrap();
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev.id.clone(), prev.clone());
+        let tx = spend(&w, &prev, &wa2, 4, 0);
+
+        let mut bogus = tx.clone();
+        bogus.vin[0].outpoint.txid = String::from("bogus");
+        bogus.id = bogus.compute_id();
+        assert_eq!(
+            bogus.verify(prev_txs.clone()),
+            Err(TxVerifyError::MissingPrevTx {
+                txid: String::from("bogus")
+            })
+        );
+        assert!(bogus.sign(&w, prev_txs.clone(), SigHashType::All).is_err());
+
+        for vout in [999, u32::MAX] {
+            let mut bogus = tx.clone();
+            bogus.vin[0].outpoint.vout = vout;
+            bogus.id = bogus.compute_id();
+            assert_eq!(
+                bogus.verify(prev_txs.clone()),
+                Err(TxVerifyError::InputIndexOutOfRange { input: 0, vout })
+            );
+            assert!(bogus.sign(&w, prev_txs.clone(), SigHashType::All).is_err());
+            assert!(bogus.fee(&prev_txs).is_err());
+        }
+
+        let mut bogus = tx;
+        bogus.vin[0].signature.truncate(10);
+        assert_eq!(
+            bogus.verify(prev_txs),
+            Err(TxVerifyError::MalformedSignature { input: 0 })
+        );
+    }
+
+    #[test]
+    fn test_reject_overspend() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        let mut bc = temp_blockchain(&wa1);
+        let prev = bc.iter().next().unwrap().get_transaction()[0].clone();
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev.id.clone(), prev.clone());
+
+        let mut tx = spend(&w, &prev, &wa2, 4, 0);
+        tx.vout[0].value = 1000;
+        tx.id = tx.compute_id();
+        tx.sign(&w, prev_txs.clone(), SigHashType::All).unwrap();
+        assert_eq!(
+            tx.verify(prev_txs.clone()),
+            Err(TxVerifyError::OutputsExceedInputs {
+                inputs: SUBSIDY,
+                outputs: 1006
+            })
+        );
+        let cbtx =
+            Transaction::new_coinbase(wa2.clone(), String::new(), 1, 0, Network::Mainnet).unwrap();
+        assert!(bc.mine_block(vec![cbtx, tx]).is_err());
+
+        // 输出之和溢出时不能绕回成小数值通过校验
+        let mut tx = spend(&w, &prev, &wa2, 4, 0);
+        tx.vout[0].value = u64::MAX;
+        tx.vout[1].value = u64::MAX;
+        tx.id = tx.compute_id();
+        tx.sign(&w, prev_txs.clone(), SigHashType::All).unwrap();
+        assert_eq!(tx.output_value(), Err(TxVerifyError::ValueOverflow));
+        assert_eq!(tx.verify(prev_txs), Err(TxVerifyError::ValueOverflow));
+
+        // 创币交易不得领取超过补贴加手续费的金额
+        let tx = spend(&w, &prev, &wa2, 4, 2);
+        let cbtx =
+            Transaction::new_coinbase(wa2.clone(), String::new(), 0, 3, Network::Mainnet).unwrap();
+        assert!(bc.mine_block(vec![cbtx, tx.clone()]).is_err());
+        let cbtx = Transaction::new_coinbase(wa2, String::new(), 0, 2, Network::Mainnet).unwrap();
+        assert!(bc.mine_block(vec![cbtx, tx]).is_ok());
+    }
+
+    #[test]
+    fn test_reject_duplicate_outpoint() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        let mut bc = temp_blockchain(&wa1);
+        let prev = bc.iter().next().unwrap().get_transaction()[0].clone();
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev.id.clone(), prev.clone());
+
+        // 同一输出在一笔交易中花费两次，输入总额被重复计算
+        let mut tx = spend(&w, &prev, &wa2, 4, 0);
+        tx.vin.push(tx.vin[0].clone());
+        tx.vout[1].value += SUBSIDY;
+        tx.id = tx.compute_id();
+        tx.sign(&w, prev_txs.clone(), SigHashType::All).unwrap();
+        assert_eq!(tx.fee(&prev_txs).unwrap(), 0);
+        assert_eq!(
+            tx.verify(prev_txs),
+            Err(TxVerifyError::DuplicateInput {
+                outpoint: OutPoint::new(&prev.id, 0)
+            })
+        );
+        let cbtx =
+            Transaction::new_coinbase(wa2.clone(), String::new(), 1, 0, Network::Mainnet).unwrap();
+        assert!(bc.mine_block(vec![cbtx.clone(), tx]).is_err());
+
+        // 同一区块中两笔交易花费同一输出
+        let tx1 = spend(&w, &prev, &wa2, 4, 0);
+        let tx2 = spend(&w, &prev, &wa2, 5, 0);
+        let err = bc
+            .mine_block(vec![cbtx.clone(), tx1.clone(), tx2])
+            .unwrap_err();
+        assert!(err.to_string().contains("double spends"));
+        assert!(bc.mine_block(vec![cbtx, tx1]).is_ok());
+    }
+
+    #[test]
+    fn test_sighash_anyonecanpay() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let wa3 = ws.create_wallet();
+        let w1 = ws.get_wallet(&wa1).unwrap().clone();
+        let w2 = ws.get_wallet(&wa2).unwrap().clone();
+        drop(ws);
+
+        let prev1 = Transaction::new_coinbase(wa1, String::new(), 0, 0, Network::Mainnet).unwrap();
+        let prev2 = Transaction::new_coinbase(wa2, String::new(), 0, 0, Network::Mainnet).unwrap();
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev1.id.clone(), prev1.clone());
+        prev_txs.insert(prev2.id.clone(), prev2.clone());
+
+        // 双方各自只放入自己的输入并签名，输出都是向 wa3 众筹的同一个输出
+        let part = |w: &Wallet, prev: &Transaction, sighash: SigHashType| {
+            let mut tx = Transaction {
+                version: TX_VERSION,
+                id: String::new(),
+                vin: vec![TXInput {
+                    outpoint: OutPoint::new(&prev.id, 0),
+                    signature: Vec::new(),
+                    pub_key: w.public_key.clone(),
+                    condition: None,
+                    signatures: Vec::new(),
+                }],
+                vout: vec![TXOutput::new(2 * SUBSIDY, wa3.clone(), Network::Mainnet).unwrap()],
+                memo: None,
+                lock_until: 0,
+            };
+            let mut own = HashMap::new();
+            own.insert(prev.id.clone(), prev.clone());
+            tx.sign(w, own, sighash).unwrap();
+            tx
+        };
+        let merge = |a: Transaction, b: Transaction| {
+            let mut tx = a;
+            tx.vin.extend(b.vin);
+            tx.id = tx.compute_id();
+            tx
+        };
+
+        let tx1 = part(&w1, &prev1, SigHashType::AnyoneCanPay);
+        let tx2 = part(&w2, &prev2, SigHashType::AnyoneCanPay);
+        let merged = merge(tx1, tx2);
+        merged.verify(prev_txs.clone()).unwrap();
+
+        // 篡改签名类型字节后签名失效
+        let mut forged = merged.clone();
+        *forged.vin[0].signature.last_mut().unwrap() = SigHashType::All.to_byte();
+        assert_eq!(
+            forged.verify(prev_txs.clone()),
+            Err(TxVerifyError::BadSignature { input: 0 })
+        );
+
+        let tx1 = part(&w1, &prev1, SigHashType::All);
+        let tx2 = part(&w2, &prev2, SigHashType::All);
+        assert_eq!(
+            merge(tx1, tx2).verify(prev_txs),
+            Err(TxVerifyError::BadSignature { input: 0 })
+        );
+    }
+
+    #[test]
+    fn test_sighash_single() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        let prev = Transaction::new_coinbase(wa1, String::new(), 0, 0, Network::Mainnet).unwrap();
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev.id.clone(), prev.clone());
+
+        let mut tx = spend(&w, &prev, &wa2, 4, 0);
+        tx.sign(&w, prev_txs.clone(), SigHashType::Single).unwrap();
+        tx.verify(prev_txs.clone()).unwrap();
+
+        // 只有下标相同的输出受签名保护
+        let mut changed = tx.clone();
+        changed.vout[1].value -= 1;
+        changed.id = changed.compute_id();
+        changed.verify(prev_txs.clone()).unwrap();
+        changed.vout[0].value += 1;
+        changed.id = changed.compute_id();
+        assert_eq!(
+            changed.verify(prev_txs.clone()),
+            Err(TxVerifyError::BadSignature { input: 0 })
+        );
+
+        let mut tx = spend(&w, &prev, &wa2, 4, 0);
+        tx.vin.push(tx.vin[0].clone());
+        tx.vin[1].outpoint.vout = 1;
+        assert!(tx.sign(&w, prev_txs, SigHashType::Single).is_err());
+    }
+
+    #[test]
+    fn test_parallel_verify() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        let prev = Transaction::new_coinbase(wa1, String::new(), 0, 0, Network::Mainnet).unwrap();
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev.id.clone(), prev.clone());
+
+        let mut checks = Vec::new();
+        for i in 0..24 {
+            let tx = spend(&w, &prev, &wa2, 2 + i % 7, 0);
+            checks.extend(tx.prepare_verify(&prev_txs).unwrap());
+        }
+        assert!(verify_signatures(&checks, false).is_ok());
+        assert!(verify_signatures(&checks, true).is_ok());
+
+        let message = checks[17].message.clone();
+        checks[17].signature[0] ^= 1;
+        assert_eq!(checks[17].message, message);
+        // 并行验证同样报告第一个无效的签名
+        for parallel in [false, true] {
+            let invalid = verify_signatures(&checks, parallel).unwrap_err();
+            assert!(std::ptr::eq(invalid, &checks[17]));
+            assert_eq!(invalid.error(), TxVerifyError::BadSignature { input: 0 });
+        }
+    }
+
+    #[test]
+    fn test_dust_change_becomes_fee() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        assert!(TXOutput::new(DUST_LIMIT - 1, wa2.clone(), Network::Mainnet).is_err());
+        assert!(TXOutput::new(0, wa2.clone(), Network::Mainnet).is_err());
+        assert!(TXOutput::new_reward(0, wa2.clone(), Network::Mainnet).is_err());
+        assert!(
+            Transaction::new_coinbase(wa2.clone(), String::new(), 0, u64::MAX, Network::Mainnet)
+                .is_err()
+        );
+
+        let prev = Transaction::new_coinbase(wa1, String::new(), 0, 0, Network::Mainnet).unwrap();
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev.id.clone(), prev.clone());
+
+        // 找零比粉尘限制少 1，不生成找零输出而是并入手续费
+        let fee = 1;
+        let amount = SUBSIDY - fee - (DUST_LIMIT - 1);
+        let tx = spend(&w, &prev, &wa2, amount, fee);
+        assert_eq!(tx.vout.len(), 1);
+        assert_eq!(tx.fee(&prev_txs).unwrap(), SUBSIDY - amount);
+        tx.verify(prev_txs.clone()).unwrap();
+
+        let mut tx = spend(&w, &prev, &wa2, 4, 0);
+        tx.vout[1].value = DUST_LIMIT - 1;
+        tx.id = tx.compute_id();
+        tx.sign(&w, prev_txs.clone(), SigHashType::All).unwrap();
+        assert_eq!(
+            tx.verify(prev_txs),
+            Err(TxVerifyError::DustOutput { output: 1 })
+        );
+    }
+
+    fn golden_transaction() -> Transaction {
+        Transaction {
+            version: 4,
+            id: String::from("ignored"),
+            vin: vec![TXInput {
+                outpoint: OutPoint::new("ab", 1),
+                signature: vec![1, 2],
+                pub_key: vec![3],
+                condition: None,
+                signatures: Vec::new(),
+            }],
+            vout: vec![
+                TXOutput {
+                    value: 5,
+                    pub_key_hash: vec![9, 9],
+                    data: None,
+                },
+                TXOutput {
+                    value: 0,
+                    pub_key_hash: Vec::new(),
+                    data: Some(b"hi".to_vec()),
+                },
+            ],
+            memo: Some(String::from("m")),
+            lock_until: -2,
+        }
+    }
+
+    #[test]
+    fn test_lock_invalid_address() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let mut address = ws.create_wallet();
+        drop(ws);
+        assert!(TXOutput::new(5, address.clone(), Network::Mainnet).is_ok());
+
+        // 地址有误时返回错误而不是 panic
+        let last = if address.ends_with('1') { '2' } else { '1' };
+        address.pop();
+        address.push(last);
+        let err = TXOutput::new(5, address.clone(), Network::Mainnet).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with(&format!("Invalid address {}", address))
+        );
+        assert!(TXOutput::new(5, String::new(), Network::Mainnet).is_err());
+        assert!(
+            Transaction::new_coinbase(String::from("bogus"), String::new(), 0, 0, Network::Mainnet)
+                .is_err()
+        );
+        assert!(
+            LockingCondition::new_multisig(1, &[String::from("bogus")], Network::Mainnet).is_err()
+        );
+    }
+
+    #[test]
+    fn test_transaction_identity() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        let prev = Transaction::new_coinbase(wa1, String::new(), 0, 0, Network::Mainnet).unwrap();
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev.id.clone(), prev.clone());
+        let tx = spend(&w, &prev, &wa2, 4, 0);
+        let other = spend(&w, &prev, &wa2, 5, 0);
+
+        // 签名不影响 id
+        assert_eq!(tx.compute_id(), tx.id);
+        prev.validate_id().unwrap();
+        tx.validate_id().unwrap();
+
+        // id 与内容不符的交易不能通过验证
+        let mut forged = tx.clone();
+        forged.id = other.id.clone();
+        assert_eq!(
+            forged.verify(prev_txs.clone()),
+            Err(TxVerifyError::IdMismatch {
+                id: other.id.clone(),
+                expected: tx.id.clone(),
+            })
+        );
+        let mut forged = tx.clone();
+        forged.vout[0].value = 5;
+        assert!(matches!(
+            forged.verify(prev_txs),
+            Err(TxVerifyError::IdMismatch { .. })
+        ));
+
+        assert_eq!(tx, tx.clone());
+        assert_ne!(tx, forged);
+        let mut same_id = other.clone();
+        same_id.id = tx.id.clone();
+        assert_ne!(tx, same_id);
+
+        let set: HashSet<Transaction> = [tx.clone(), tx.clone(), other.clone()].into();
+        assert_eq!(set.len(), 2);
+
+        // 创币交易排在最前，其余按 txid 排序
+        let mut txs = [tx, prev.clone(), other];
+        txs.sort();
+        assert_eq!(txs[0], prev);
+        assert!(txs[1].id < txs[2].id);
+    }
+
+    #[test]
+    fn test_canonical_hash() {
+        let tx = golden_transaction();
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&[4, 0, 0, 0]);
+        expected.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0, b'a', b'b']);
+        expected.extend_from_slice(&[1, 0, 0, 0]);
+        expected.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0, 1, 2]);
+        expected.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 3]);
+        expected.push(0);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(&[5, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0, 9, 9]);
+        expected.push(0);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(&[1, 2, 0, 0, 0, 0, 0, 0, 0, b'h', b'i']);
+        expected.extend_from_slice(&[1, 1, 0, 0, 0, 0, 0, 0, 0, b'm']);
+        expected.extend_from_slice(&[0xfe, 0xff, 0xff, 0xff]);
+        assert_eq!(tx.canonical_bytes(), expected);
+
+        // id 不参与哈希
+        assert_eq!(
+            tx.hash().unwrap(),
+            "27399762d6654910ab48bfcdb2f71a1aba50cbfeaec92a4c825344c6220c6298"
+        );
+        let mut other = tx.clone();
+        other.id = String::new();
+        assert_eq!(
+            other.hash().unwrap(),
+            "27399762d6654910ab48bfcdb2f71a1aba50cbfeaec92a4c825344c6220c6298"
+        );
+
+        let coinbase = Transaction {
+            version: 4,
+            id: String::new(),
+            vin: vec![TXInput {
+                outpoint: OutPoint::null(),
+                signature: Vec::new(),
+                pub_key: b"reward".to_vec(),
+                condition: None,
+                signatures: Vec::new(),
+            }],
+            vout: vec![TXOutput {
+                value: SUBSIDY,
+                pub_key_hash: vec![7; 20],
+                data: None,
+            }],
+            memo: None,
+            lock_until: 0,
+        };
+        assert_eq!(
+            coinbase.hash().unwrap(),
+            "88e1cdbe36f00bc1cf83cfa8200add5dbbe0b9e156dcbe7ce6b8942857a9adce"
+        );
+    }
+
+    #[test]
+    fn test_canonical_signature_hash() {
+        let tx = golden_transaction();
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(
+            String::from("ab"),
+            Transaction {
+                version: 4,
+                id: String::from("ab"),
+                vin: Vec::new(),
+                vout: vec![
+                    TXOutput {
+                        value: 1,
+                        pub_key_hash: vec![1],
+                        data: None,
+                    },
+                    TXOutput {
+                        value: 8,
+                        pub_key_hash: vec![4; 20],
+                        data: None,
+                    },
+                ],
+                memo: None,
+                lock_until: 0,
+            },
+        );
+
+        assert_eq!(
+            tx.signature_hash(0, &prev_txs, SigHashType::All).unwrap(),
+            "7832e93a802b1bc7bbbdfa4a1fca2f48a5212cd129eb85739302fee37707c6bb"
+        );
+        assert_eq!(
+            tx.signature_hash(0, &prev_txs, SigHashType::Single)
+                .unwrap(),
+            "02d847e1fa1f543b523e4a914c0909cec16b0319882282235583909fd23d9695"
+        );
+        assert_eq!(
+            tx.signature_hash(0, &prev_txs, SigHashType::AnyoneCanPay)
+                .unwrap(),
+            "a9d625f07d73b3d44b95c9c0ec95ca96a35a8ff2a437184992d4aab3d1ef98de"
+        );
+    }
+
+    fn random_bytes(rng: &mut impl Rng, max_len: usize) -> Vec<u8> {
+        let len = rng.gen_range(0..=max_len);
+        (0..len).map(|_| rng.r#gen()).collect()
+    }
+
+    fn random_transaction(rng: &mut impl Rng) -> Transaction {
+        let vin = (0..rng.gen_range(0..4))
+            .map(|_| TXInput {
+                outpoint: OutPoint::new(&hex::encode(random_bytes(rng, 32)), rng.r#gen()),
+                signature: random_bytes(rng, SIGNATURE_LEN),
+                pub_key: random_bytes(rng, PUB_KEY_LEN),
+                condition: rng.gen_bool(0.3).then(|| LockingCondition::MultiSig {
+                    m: rng.r#gen(),
+                    key_hashes: (0..rng.gen_range(0..4))
+                        .map(|_| random_bytes(rng, PUB_KEY_HASH_LEN))
+                        .collect(),
+                }),
+                signatures: (0..rng.gen_range(0..3))
+                    .map(|_| {
+                        (
+                            random_bytes(rng, PUB_KEY_LEN),
+                            random_bytes(rng, SIGNATURE_LEN),
+                        )
+                    })
+                    .collect(),
+            })
+            .collect();
+        let vout = (0..rng.gen_range(0..4))
+            .map(|_| TXOutput {
+                value: rng.r#gen(),
+                pub_key_hash: random_bytes(rng, PUB_KEY_HASH_LEN),
+                data: rng.gen_bool(0.3).then(|| random_bytes(rng, MAX_DATA_LEN)),
+            })
+            .collect();
+        Transaction {
+            version: rng.r#gen(),
+            id: hex::encode(random_bytes(rng, 32)),
+            vin,
+            vout,
+            memo: rng
+                .gen_bool(0.5)
+                .then(|| String::from_utf8_lossy(&random_bytes(rng, 16)).into_owned()),
+            lock_until: rng.r#gen(),
+        }
+    }
+
+    #[test]
+    fn test_raw_hex_round_trip() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..200 {
+            let tx = random_transaction(&mut rng);
+            let raw = tx.to_hex().unwrap();
+            assert_eq!(Transaction::from_hex(&raw).unwrap(), tx);
+            let json = serde_json::to_string(&TransactionJson::new(&tx, Network::Mainnet)).unwrap();
+            let parsed: TransactionJson = serde_json::from_str(&json).unwrap();
+            assert_eq!(Transaction::try_from(parsed).unwrap(), tx);
+            assert_eq!(Transaction::from_hex(&raw.to_uppercase()).unwrap(), tx);
+        }
+
+        let raw = golden_transaction().to_hex().unwrap();
+        let err = Transaction::from_hex(&raw[1..]).unwrap_err();
+        assert!(err.to_string().contains("Invalid transaction hex"));
+        let err = Transaction::from_hex(&format!("zz{}", raw)).unwrap_err();
+        assert!(err.to_string().contains("Invalid transaction hex"));
+        let err = Transaction::from_hex(&format!("{}00", raw)).unwrap_err();
+        assert!(err.to_string().contains("Invalid raw transaction"));
+        let err = Transaction::from_hex(&raw[..raw.len() - 2]).unwrap_err();
+        assert!(err.to_string().contains("Invalid raw transaction"));
+    }
+
+    #[test]
+    fn test_transaction_json() {
+        let coinbase = Transaction {
+            version: 4,
+            id: String::from("c0ffee"),
+            vin: vec![TXInput {
+                outpoint: OutPoint::null(),
+                signature: Vec::new(),
+                pub_key: b"reward".to_vec(),
+                condition: None,
+                signatures: Vec::new(),
+            }],
+            vout: vec![
+                TXOutput {
+                    value: SUBSIDY,
+                    pub_key_hash: vec![7; 20],
+                    data: None,
+                },
+                TXOutput {
+                    value: 0,
+                    pub_key_hash: Vec::new(),
+                    data: Some(b"hi".to_vec()),
+                },
+            ],
+            memo: None,
+            lock_until: 0,
+        };
+
+        let json = serde_json::to_value(TransactionJson::new(&coinbase, Network::Mainnet)).unwrap();
+        let expected = serde_json::json!({
+            "version": 4,
+            "id": "c0ffee",
+            "vin": [{
+                "txid": "",
+                "vout": -1,
+                "signature": "",
+                "pub_key": "726577617264",
+            }],
+            "vout": [
+                {
+                    "value": 10,
+                    "pub_key_hash": "0707070707070707070707070707070707070707",
+                    "address": "32LB5ANdvjNPoW3DXLXUt6ra7Lih9WeBJN",
+                },
+                {
+                    "value": 0,
+                    "pub_key_hash": "",
+                    "data": "6869",
+                },
+            ],
+            "lock_until": 0,
+        });
+        assert_eq!(json, expected);
+
+        let parsed: TransactionJson = serde_json::from_value(json).unwrap();
+        assert_eq!(Transaction::try_from(parsed).unwrap(), coinbase);
+
+        let mut bad = TransactionJson::new(&coinbase, Network::Mainnet);
+        bad.vin[0].pub_key = String::from("xyz");
+        let err = Transaction::try_from(bad).unwrap_err();
+        assert!(err.to_string().contains("Invalid hex in pub_key"));
+
+        let mut bad = TransactionJson::new(&coinbase, Network::Mainnet);
+        bad.vin[0].vout = -2;
+        assert!(Transaction::try_from(bad).is_err());
+    }
+
+    #[test]
+    fn test_multisig() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let addresses: Vec<String> = (0..3).map(|_| ws.create_wallet()).collect();
+        let keys: Vec<Wallet> = addresses
+            .iter()
+            .map(|address| ws.get_wallet(address).unwrap().clone())
+            .collect();
+        let outsider = ws.create_wallet();
+        let outsider_key = ws.get_wallet(&outsider).unwrap().clone();
+        drop(ws);
+
+        let condition = LockingCondition::new_multisig(2, &addresses, Network::Mainnet).unwrap();
+        assert!(LockingCondition::new_multisig(0, &addresses, Network::Mainnet).is_err());
+        assert!(LockingCondition::new_multisig(4, &addresses, Network::Mainnet).is_err());
+        let repeated = vec![addresses[0].clone(), addresses[0].clone()];
+        assert!(LockingCondition::new_multisig(1, &repeated, Network::Mainnet).is_err());
+
+        let golden = LockingCondition::MultiSig {
+            m: 2,
+            key_hashes: vec![vec![1; 20], vec![2; 20], vec![3; 20]],
+        };
+        assert_eq!(
+            hex::encode(golden.identifier()),
+            "ea8e1fd36872f323d58fe92b12bf176ee57a3953"
+        );
+
+        let mut prev = Transaction {
+            version: TX_VERSION,
+            id: String::new(),
+            vin: Vec::new(),
+            vout: vec![TXOutput::new_multisig(SUBSIDY, &condition).unwrap()],
+            memo: None,
+            lock_until: 0,
+        };
+        prev.id = prev.hash().unwrap();
+        assert_eq!(prev.vout[0].pub_key_hash, condition.identifier());
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev.id.clone(), prev.clone());
+
+        let unsigned = Transaction::new_multisig_spend(
+            &prev,
+            0,
+            &condition,
+            &outsider,
+            4,
+            1,
+            Network::Mainnet,
+        )
+        .unwrap();
+        assert_eq!(unsigned.vout[1].pub_key_hash, condition.identifier());
+        assert_eq!(
+            unsigned.verify(prev_txs.clone()),
+            Err(TxVerifyError::NotEnoughSignatures {
+                input: 0,
+                signers: 0,
+                required: 2
+            })
+        );
+
+        // 任意两把密钥都可以花费，只有一个签名时不够
+        for (a, b) in [(0, 1), (0, 2), (2, 1)] {
+            let mut tx = unsigned.clone();
+            tx.sign(&keys[a], prev_txs.clone(), SigHashType::All)
+                .unwrap();
+            assert_eq!(
+                tx.verify(prev_txs.clone()),
+                Err(TxVerifyError::NotEnoughSignatures {
+                    input: 0,
+                    signers: 1,
+                    required: 2
+                })
+            );
+            tx.sign(&keys[b], prev_txs.clone(), SigHashType::All)
+                .unwrap();
+            tx.verify(prev_txs.clone()).unwrap();
+            let tx = Transaction::from_hex(&tx.to_hex().unwrap()).unwrap();
+            tx.verify(prev_txs.clone()).unwrap();
+        }
+
+        // 同一密钥签名两次只保留一个签名，手工重复的签名被拒绝
+        let mut tx = unsigned.clone();
+        tx.sign(&keys[0], prev_txs.clone(), SigHashType::All)
+            .unwrap();
+        tx.sign(&keys[0], prev_txs.clone(), SigHashType::All)
+            .unwrap();
+        assert_eq!(tx.vin[0].signatures.len(), 1);
+        let signature = tx.vin[0].signatures[0].clone();
+        tx.vin[0].signatures.push(signature);
+        assert_eq!(
+            tx.verify(prev_txs.clone()),
+            Err(TxVerifyError::BadSigner { input: 0 })
+        );
+
+        let mut tx = unsigned.clone();
+        assert!(
+            tx.sign(&outsider_key, prev_txs.clone(), SigHashType::All)
+                .is_err()
+        );
+
+        // 揭示的条件必须与输出锁定的标识一致
+        let mut tx = unsigned.clone();
+        tx.vin[0].condition =
+            Some(LockingCondition::new_multisig(1, &addresses, Network::Mainnet).unwrap());
+        tx.id = tx.compute_id();
+        tx.sign(&keys[0], prev_txs.clone(), SigHashType::All)
+            .unwrap();
+        assert_eq!(
+            tx.verify(prev_txs),
+            Err(TxVerifyError::BadCondition { input: 0 })
+        );
+    }
+
+    #[test]
+    fn test_reject_foreign_key() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let thief = ws.get_wallet(&wa2).unwrap().clone();
+        drop(ws);
+
+        // 用自己的密钥签名别人的输出
+        let prev = Transaction::new_coinbase(wa1, String::new(), 0, 0, Network::Mainnet).unwrap();
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev.id.clone(), prev.clone());
+        let tx = spend(&thief, &prev, &wa2, 4, 0);
+        assert_eq!(
+            tx.verify(prev_txs),
+            Err(TxVerifyError::KeyMismatch { input: 0 })
+        );
+    }
+
+    #[test]
+    fn test_outpoint() {
+        let outpoint: OutPoint = "ab:3".parse().unwrap();
+        assert_eq!(outpoint, OutPoint::new("ab", 3));
+        assert_eq!(outpoint.to_string(), "ab:3");
+        for bad in ["ab", ":3", "ab:-1", "ab:x", "ab:4294967296"] {
+            assert!(bad.parse::<OutPoint>().is_err(), "{}", bad);
+        }
+        assert!(OutPoint::new("ab", 3) < OutPoint::new("ab", 4));
+        assert!(OutPoint::new("ab", 9) < OutPoint::new("b", 0));
+
+        // 编码与旧版 TXInput 中的 (txid, vout: i32) 相同
+        assert!(OutPoint::null().is_null());
+        assert_eq!(
+            serialize(&OutPoint::null()).unwrap(),
+            serialize(&(String::new(), -1i32)).unwrap()
+        );
+        assert_eq!(
+            serialize(&OutPoint::new("ab", 3)).unwrap(),
+            serialize(&(String::from("ab"), 3i32)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_unsigned_bundle() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        // 联网节点只知道公钥
+        let bc = temp_blockchain(&wa1);
+        let prev = bc.iter().next().unwrap().get_transaction()[0].clone();
+        let unspent = vec![OutPoint::new(&prev.id, 0)];
+        let tx = Transaction::new_unsigned(
+            &w.public_key,
+            &[(wa2, 4)],
+            &TxOptions::default(),
+            (SUBSIDY, unspent),
+            Network::Mainnet,
+        )
+        .unwrap();
+        assert_eq!(tx.vout[1].pub_key_hash, prev.vout[0].pub_key_hash);
+        let prev_txs = bc.get_prev_txs(&tx).unwrap();
+        let file = std::env::temp_dir().join(format!("bundle-{}", tx.id));
+        let path = file.to_str().unwrap();
+        UnsignedBundle {
+            tx: tx.clone(),
+            prev_txs: prev_txs.clone(),
+        }
+        .save(path)
+        .unwrap();
+
+        // 离线机器只需要交易包和私钥
+        let bundle = UnsignedBundle::load(path).unwrap();
+        let signed = bundle.sign(&w, SigHashType::All).unwrap();
+        assert_eq!(signed.id, tx.id);
+        bc.verify_transacton(&signed).unwrap();
+        assert_eq!(
+            bc.verify_transacton(&tx)
+                .unwrap_err()
+                .downcast::<TxVerifyError>()
+                .unwrap(),
+            TxVerifyError::MalformedSignature { input: 0 }
+        );
+
+        // 前序交易的索引与 id 不一致
+        let mut tampered = prev_txs.clone();
+        let mut fake = prev.clone();
+        fake.id = String::from("fake");
+        tampered.insert(prev.id.clone(), fake);
+        UnsignedBundle {
+            tx,
+            prev_txs: tampered,
+        }
+        .save(path)
+        .unwrap();
+        assert!(UnsignedBundle::load(path).is_err());
+
+        std::fs::write(path, b"garbage").unwrap();
+        assert!(UnsignedBundle::load(path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_coinbase_rules() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        let mut bc = temp_blockchain(&wa1);
+        let data = "x".repeat(MAX_COINBASE_DATA_LEN + 1);
+        assert!(Transaction::new_coinbase(wa2.clone(), data, 1, 0, Network::Mainnet).is_err());
+        assert!(
+            Transaction::new_coinbase(wa2.clone(), String::from("x"), 1, 0, Network::Mainnet)
+                .is_err()
+        );
+        let data = "x".repeat(MAX_COINBASE_DATA_LEN - COINBASE_HEIGHT_LEN);
+        let cbtx =
+            Transaction::new_coinbase(wa2.clone(), data.clone(), 1, 0, Network::Mainnet).unwrap();
+        assert_eq!(cbtx.vin[0].pub_key.len(), MAX_COINBASE_DATA_LEN);
+        assert_eq!(cbtx.coinbase_height(), Some(1));
+        assert_eq!(cbtx.coinbase_message(1), data.as_bytes());
+
+        // 数据固定时，相邻两个区块发往同一地址的创币交易 id 也不同
+        let fixed = |height| {
+            Transaction::new_coinbase(wa2.clone(), "fixed".into(), height, 0, Network::Mainnet)
+        };
+        let (first, second) = (fixed(5).unwrap(), fixed(6).unwrap());
+        assert_eq!(first.id, fixed(5).unwrap().id);
+        assert_ne!(first.id, second.id);
+        assert_eq!(second.coinbase_height(), Some(6));
+        assert_eq!(first.coinbase_message(6), first.vin[0].pub_key.as_slice());
+
+        // 手工构造携带过多数据的创币交易
+        let mut cbtx =
+            Transaction::new_coinbase(wa2.clone(), String::new(), 1, 0, Network::Mainnet).unwrap();
+        cbtx.vin[0].pub_key = vec![0; MAX_COINBASE_DATA_LEN + 1];
+        cbtx.id = cbtx.hash().unwrap();
+        assert!(cbtx.is_coinbase());
+        assert!(bc.mine_block(vec![cbtx.clone()]).is_err());
+        cbtx.vin[0].pub_key = vec![0; MIN_COINBASE_DATA_LEN - 1];
+        cbtx.id = cbtx.hash().unwrap();
+        assert!(bc.mine_block(vec![cbtx]).is_err());
+
+        // 第二个创币交易藏在下标 3
+        let prev = bc.iter().next().unwrap().get_transaction()[0].clone();
+        let tx1 = spend(&w, &prev, &wa2, 4, 0);
+        let tx2 = spend(&w, &prev, &wa2, 5, 0);
+        let cbtx =
+            Transaction::new_coinbase(wa2.clone(), String::new(), 1, 0, Network::Mainnet).unwrap();
+        let hidden =
+            Transaction::new_coinbase(wa2.clone(), String::new(), 1, 0, Network::Mainnet).unwrap();
+        let err = bc
+            .mine_block(vec![cbtx.clone(), tx1, tx2, hidden])
+            .unwrap_err();
+        assert!(err.to_string().contains("at index 3"));
+
+        let tx1 = spend(&w, &prev, &wa2, 4, 0);
+        let err = bc.mine_block(vec![tx1.clone(), cbtx.clone()]).unwrap_err();
+        assert!(err.to_string().contains("is not a coinbase"));
+        assert!(bc.mine_block(vec![]).is_err());
+        assert!(bc.mine_block(vec![cbtx, tx1]).is_ok());
+    }
+
+    #[test]
+    fn test_block_subsidy() {
+        assert_eq!(block_subsidy(0), SUBSIDY);
+        assert_eq!(block_subsidy(HALVING_INTERVAL - 1), SUBSIDY);
+        assert_eq!(block_subsidy(HALVING_INTERVAL), SUBSIDY / 2);
+        assert_eq!(block_subsidy(2 * HALVING_INTERVAL - 1), SUBSIDY / 2);
+        assert_eq!(block_subsidy(2 * HALVING_INTERVAL), SUBSIDY / 4);
+
+        // 10 -> 5 -> 2 -> 1 -> 0
+        assert_eq!(block_subsidy(4 * HALVING_INTERVAL - 1), 1);
+        assert_eq!(block_subsidy(4 * HALVING_INTERVAL), 0);
+        assert_eq!(block_subsidy(i32::MAX), 0);
+
+        let address = Wallets::in_memory(&MemoryStorage::default()).create_wallet();
+        let height = 4 * HALVING_INTERVAL;
+        let tx =
+            Transaction::new_coinbase(address.clone(), String::new(), height, 0, Network::Mainnet)
+                .unwrap();
+        assert!(tx.vout.is_empty());
+        let tx =
+            Transaction::new_coinbase(address, String::new(), height, 3, Network::Mainnet).unwrap();
+        assert_eq!(tx.vout[0].value, 3);
+    }
+
+    #[test]
+    fn test_estimate_size() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let wa2 = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        let prev = Transaction::new_coinbase(wa1, String::new(), 0, 0, Network::Mainnet).unwrap();
+        let tx = spend(&w, &prev, &wa2, 4, 0);
+        assert_eq!(
+            serialize(&tx).unwrap().len(),
+            Transaction::estimate_size(1, 2)
+        );
+        assert!(Transaction::estimate_size(2, 2) > Transaction::estimate_size(1, 2));
+    }
+
+    #[test]
+    fn test_miner_collects_fees() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let wa1 = ws.create_wallet();
+        let miner = ws.create_wallet();
+        let w = ws.get_wallet(&wa1).unwrap().clone();
+        drop(ws);
+
+        let mut bc = temp_blockchain(&wa1);
+        let cbtx =
+            Transaction::new_coinbase(wa1.clone(), String::new(), 1, 0, Network::Mainnet).unwrap();
+        bc.mine_block(vec![cbtx]).unwrap();
+
+        let coinbases: Vec<Transaction> =
+            bc.iter().map(|b| b.get_transaction()[0].clone()).collect();
+        let tx1 = spend(&w, &coinbases[0], &miner, 5, 2);
+        let tx2 = spend(&w, &coinbases[1], &miner, 5, 3);
+
+        let fees = bc.get_tx_fee(&tx1).unwrap() + bc.get_tx_fee(&tx2).unwrap();
+        assert_eq!(fees, 5);
+        let cbtx =
+            Transaction::new_coinbase(miner, String::new(), 2, fees, Network::Mainnet).unwrap();
+        let block = bc.mine_block(vec![cbtx, tx1, tx2]).unwrap();
+        assert_eq!(block.get_transaction()[0].vout[0].value, SUBSIDY + 5);
     }
 }
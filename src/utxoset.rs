@@ -3,128 +3,2798 @@
 use super::*;
 use crate::block::*;
 use crate::blockchain::*;
+use crate::network::Network;
+#[cfg(test)]
+use crate::storage::MemoryStore;
+use crate::storage::{Batch, DEFAULT_TREE, KvStore, SledStore};
 use crate::transaction::*;
-use bincode::{deserialize, serialize};
-use sled;
-use std::collections::HashMap;
+use crate::wallets::{decode_address, hash_pub_key};
+use bincode::{Options, deserialize, serialize};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use failure::format_err;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// 分支定界选币时，选中金额超出目标不多于该值即视为精确匹配，差额计入手续费
+pub const BNB_TOLERANCE: u64 = DUST_LIMIT;
+/// 分支定界搜索的最大尝试次数，超出后退回累加策略
+const BNB_MAX_TRIES: usize = 100_000;
+/// UTXO 数据库中保存区块撤销数据的树
+const UNDO_TREE: &str = "undo";
+/// UTXO 数据库中按公钥哈希索引输出的树，键为 4 字节大端长度、公钥哈希和 "txid:n"
+const INDEX_TREE: &str = "owners";
+/// 索引树中的标记键，存在时索引覆盖了整个集合；旧版本创建的集合没有该标记
+const INDEX_COMPLETE: &[u8] = b"";
+/// UTXO 数据库中记录冻结输出的树，键为 "txid:n"
+const FROZEN_TREE: &str = "frozen";
+/// UTXO 数据库中保存元数据的树
+const META_TREE: &str = "meta";
+/// 元数据树中的键，值为集合对应的最新区块哈希及其高度
+const TIP_KEY: &[u8] = b"tip";
+/// 元数据中的重建标记，存在时表示上次重建未完成，集合不完整
+const REINDEX_MARKER: &[u8] = b"reindexing";
+/// 重建UTXO集合时累积到这么多条修改后写入一批
+const REINDEX_BATCH: usize = 4096;
+/// UTXO 快照文件开头的魔数，最后一个字节为格式版本
+const SNAPSHOT_MAGIC: [u8; 8] = *b"RCUTXO\0\x01";
 
 /// UTXOSet 表示未使用的交易输出集合
 pub struct UTXOSet {
     pub blockchain: Blockchain,
+    /// 保持打开的存储，为 None 时每次读写打开 path 处的数据库
+    store: Option<Arc<dyn KvStore>>,
+    path: PathBuf,
+}
+
+/// SharedUTXOSet 在线程间共享的UTXO集合及其区块链
+///
+/// 查询持有读锁，连接区块、重组和挖矿等修改持有写锁：查询看到的总是
+/// 某次修改之前或之后的完整集合，不会读到一半连接的区块
+#[derive(Clone)]
+pub struct SharedUTXOSet(Arc<RwLock<UTXOSet>>);
+
+/// BlockUndo 连接区块时从UTXO集合中移除的输出，按交易及其输入的顺序排列
+///
+/// 断开区块时据此恢复被花费的输出
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BlockUndo {
+    pub spent: Vec<(OutPoint, UTXOEntry)>,
+}
+
+/// SnapshotMeta UTXO 快照的描述信息
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotMeta {
+    /// 快照对应的最新区块哈希
+    pub tip: String,
+    /// 最新区块的高度，集合为空时为 -1
+    pub height: i32,
+    /// 快照中的输出数量
+    pub outputs: u64,
+    /// 规范序列化的最新区块、高度和全部输出的 SHA-256，十六进制
+    pub hash: String,
+}
+
+/// UTXO 快照文件的内容，输出按 "txid:n" 键的顺序排列
+#[derive(Serialize, Deserialize)]
+struct SnapshotFile {
+    magic: [u8; 8],
+    meta: SnapshotMeta,
+    entries: Vec<(OutPoint, UTXOEntry)>,
+}
+
+/// ConsistencyReport UTXO 集合与区块链推导出的集合之间的差异
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ConsistencyReport {
+    /// 区块链中未花费、集合中缺少的输出
+    pub missing: Vec<OutPoint>,
+    /// 集合中存在、但在区块链中已花费或从未出现的输出
+    pub stale: Vec<OutPoint>,
+    /// 两边都有但记录不同的输出，包括无法解码的记录
+    pub mismatched: Vec<OutPoint>,
+}
+
+impl ConsistencyReport {
+    /// IsConsistent 没有任何差异时返回 true
+    pub fn is_consistent(&self) -> bool {
+        self.missing.is_empty() && self.stale.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+/// CompactReport 压缩UTXO数据库前后的记录数
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompactReport {
+    /// 压缩前的记录数
+    pub before: usize,
+    /// 压缩后的记录数
+    pub after: usize,
+    /// 删除的指向不存在记录的索引项数
+    pub index_removed: usize,
+}
+
+/// Balance 按能否自动花费分类的余额
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Balance {
+    /// 可以自动选用的余额
+    pub spendable: u64,
+    /// 尚未成熟的创币交易奖励
+    pub immature: u64,
+    /// 已冻结、不参与自动选币的余额
+    pub frozen: u64,
+}
+
+/// UtxoStats UTXO 集合的统计信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UtxoStats {
+    /// 未花费输出的数量
+    pub outputs: usize,
+    /// 仍有未花费输出的交易数量
+    pub transactions: usize,
+    /// 全部未花费输出的金额之和，即流通量
+    pub total_amount: u64,
+    /// 数据库在磁盘上的大致大小，单位为字节
+    pub disk_size: u64,
+}
+
+/// CoinSelection 选择待花费输出的策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CoinSelection {
+    /// 优先花费面额最大的输出，使输入数量最少
+    LargestFirst,
+    /// 优先花费面额最小的输出，顺带清理零碎输出
+    SmallestFirst,
+    /// 按 txid 和输出序号的顺序累加
+    #[default]
+    Accumulate,
+    /// 分支定界搜索恰好凑足金额的组合，避免找零；找不到时退回累加
+    BranchAndBound,
+}
+
+impl FromStr for CoinSelection {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "largest" => Ok(CoinSelection::LargestFirst),
+            "smallest" => Ok(CoinSelection::SmallestFirst),
+            "accumulate" => Ok(CoinSelection::Accumulate),
+            "bnb" => Ok(CoinSelection::BranchAndBound),
+            _ => Err(format_err!("Unknown coin selection strategy: {}", s)),
+        }
+    }
 }
 
 impl UTXOSet {
-    /// FindSpendableOutputs 返回包含未使用输出的交易列表
+    /// NewUTXOSet 创建区块链的 UTXO 集合，保存在数据目录 data_dir 中
+    pub fn new(blockchain: Blockchain, data_dir: &Path) -> UTXOSet {
+        UTXOSet {
+            path: blockchain.network.data_path(data_dir, "utxos"),
+            blockchain,
+            store: None,
+        }
+    }
+
+    /// NewUTXOSetWithStore 创建保存在指定存储后端中的 UTXO 集合
+    pub fn with_store(blockchain: Blockchain, store: Arc<dyn KvStore>) -> UTXOSet {
+        UTXOSet {
+            blockchain,
+            store: Some(store),
+            path: PathBuf::new(),
+        }
+    }
+
+    /// InMemory 创建保存在内存中的 UTXO 集合，丢弃后不留下文件
+    #[cfg(test)]
+    pub fn in_memory(blockchain: Blockchain) -> UTXOSet {
+        UTXOSet::with_store(blockchain, Arc::new(MemoryStore::default()))
+    }
+
+    /// 返回 UTXO 存储，上次重建被中断时返回错误
+    fn store(&self) -> Result<Arc<dyn KvStore>> {
+        let store = self.open_store()?;
+        if store.get(META_TREE, REINDEX_MARKER)?.is_some() {
+            return Err(format_err!(
+                "A previous reindex of the UTXO set was interrupted, run reindex"
+            ));
+        }
+        Ok(store)
+    }
+
+    /// Flush 把UTXO集合和区块链尚未写入磁盘的修改写入磁盘，节点退出之前调用
+    pub fn flush(&self) -> Result<()> {
+        self.open_store()?.flush()?;
+        self.blockchain.flush()
+    }
+
+    /// 打开 UTXO 存储，不检查重建标记，磁盘上的数据库只在读写时短暂打开
+    fn open_store(&self) -> Result<Arc<dyn KvStore>> {
+        match &self.store {
+            Some(store) => Ok(store.clone()),
+            None => Ok(Arc::new(SledStore::new(Network::open_db_at(&self.path)?))),
+        }
+    }
+
+    /// FindSpendableOutputs 返回选中的未使用输出及其总额，只选用至少有 min_conf 个确认的输出
     pub fn find_spendable_outputs(
         &self,
         pub_key_hash: &[u8],
-        amount: i32,
-    ) -> Result<(i32, HashMap<String, Vec<i32>>)> {
-        let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
-        let mut accumulated = 0;
+        amount: u64,
+        min_conf: i32,
+    ) -> Result<(u64, Vec<OutPoint>)> {
+        self.find_spendable_outputs_with(pub_key_hash, amount, CoinSelection::default(), min_conf)
+    }
 
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter() {
-            let (k, v) = kv?;
-            let txid = String::from_utf8(k.to_vec())?;
-            let outs: TXOutputs = deserialize(&v.to_vec())?;
-
-            for out_idx in 0..outs.outputs.len() {
-                if outs.outputs[out_idx].is_locked_with_key(pub_key_hash) && accumulated < amount {
-                    accumulated += outs.outputs[out_idx].value;
-                    match unspent_outputs.get_mut(&txid) {
-                        Some(v) => v.push(out_idx as i32),
-                        None => {
-                            unspent_outputs.insert(txid.clone(), vec![out_idx as i32]);
-                        }
-                    }
-                }
+    /// FindSpendableOutputsWith 按指定策略选择足够支付 amount 的未使用输出
+    pub fn find_spendable_outputs_with(
+        &self,
+        pub_key_hash: &[u8],
+        amount: u64,
+        strategy: CoinSelection,
+        min_conf: i32,
+    ) -> Result<(u64, Vec<OutPoint>)> {
+        select_coins(
+            self.find_candidates(pub_key_hash, min_conf)?,
+            amount,
+            strategy,
+        )
+    }
+
+    /// FindCandidates 返回可以自动选用的输出及其面额，即已成熟、未冻结且至少有 min_conf 个确认的输出
+    pub fn find_candidates(
+        &self,
+        pub_key_hash: &[u8],
+        min_conf: i32,
+    ) -> Result<Vec<(OutPoint, u64)>> {
+        let tip = self.blockchain.get_best_height()?;
+        let frozen = read_frozen(&*self.store()?)?;
+        Ok(self
+            .find_entries(&[pub_key_hash])?
+            .into_iter()
+            .filter(|(outpoint, entry)| {
+                entry.is_mature(tip)
+                    && !frozen.contains(outpoint)
+                    && entry.confirmations(tip) >= min_conf
+            })
+            .map(|(outpoint, entry)| (outpoint, entry.output.value))
+            .collect())
+    }
+
+    /// EstimateFee 按 fee_rate（每字节手续费）估算一笔转账的手续费
+    ///
+    /// 手续费增加可能需要多选一个输出，交易大小随之变化，因此反复选币直到手续费不再增长；
+    /// 与发送时一样只选用至少有 min_conf 个确认的输出
+    pub fn estimate_fee(
+        &self,
+        pub_key_hash: &[u8],
+        amount: u64,
+        fee_rate: u64,
+        min_conf: i32,
+    ) -> Result<u64> {
+        let mut fee = 0;
+        loop {
+            let required = amount
+                .checked_add(fee)
+                .ok_or_else(|| format_err!("Total amount overflows"))?;
+            let (accumulated, unspent) =
+                self.find_spendable_outputs(pub_key_hash, required, min_conf)?;
+            if accumulated < required {
+                return Err(format_err!(
+                    "Not Enough balance: requested {}, available {}",
+                    required,
+                    accumulated
+                ));
+            }
+
+            let num_inputs = unspent.len();
+            let num_outputs = if accumulated - required >= DUST_LIMIT {
+                2
+            } else {
+                1
+            };
+            let size = Transaction::estimate_size(num_inputs, num_outputs) as u64;
+            let new_fee = size
+                .checked_mul(fee_rate)
+                .ok_or_else(|| format_err!("Fee overflows"))?;
+            if new_fee <= fee {
+                return Ok(fee);
             }
+            fee = new_fee;
+        }
+    }
+
+    /// GetBalance 返回地址的可花费余额，不含尚未成熟的创币交易奖励和冻结的输出
+    ///
+    /// 地址无效时返回错误而不是 0，输错的地址不会被当作空钱包
+    pub fn get_balance(&self, address: &str) -> Result<u64> {
+        self.get_balance_with_conf(address, 1)
+    }
+
+    /// GetBalanceWithConf 返回地址的可花费余额中至少有 min_conf 个确认的部分
+    ///
+    /// 最新区块中的输出有 1 个确认，min_conf 不大于 1 时与 get_balance 相同
+    pub fn get_balance_with_conf(&self, address: &str, min_conf: i32) -> Result<u64> {
+        let mut balance: u64 = 0;
+        for (_, value) in
+            self.find_candidates(&decode_address(address, self.blockchain.network)?, min_conf)?
+        {
+            balance = balance
+                .checked_add(value)
+                .ok_or_else(|| format_err!("Balance of {} overflows", address))?;
         }
+        Ok(balance)
+    }
+
+    /// GetAddressBalance 返回地址分类后的余额，地址无效时返回错误
+    pub fn get_address_balance(&self, address: &str) -> Result<Balance> {
+        let pub_key_hash = decode_address(address, self.blockchain.network)?;
+        let balances = self.find_balances(&HashSet::from([pub_key_hash.clone()]))?;
+        Ok(balances.get(&pub_key_hash).copied().unwrap_or_default())
+    }
+
+    /// GetUTXOs 返回地址的全部已成熟输出及其位置，包括冻结的输出，地址无效时返回错误
+    #[cfg(test)]
+    pub fn get_utxos(&self, address: &str) -> Result<Vec<(OutPoint, TXOutput)>> {
+        self.find_utxo_by_maturity(&decode_address(address, self.blockchain.network)?, true)
+    }
 
-        Ok((accumulated, unspent_outputs))
+    /// GetUTXO 按位置查找一个未花费输出，已花费或不存在时返回 None
+    pub fn get_utxo(&self, outpoint: &OutPoint) -> Result<Option<TXOutput>> {
+        Ok(self.get_entry(outpoint)?.map(|entry| entry.output))
     }
 
-    /// FindUTXO 查找公钥哈希对应的未使用交易输出
-    pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Result<TXOutputs> {
-        let mut utxos = TXOutputs {
-            outputs: Vec::new(),
+    /// VerifyTransactionInputs 检查交易的每个输入都花费集合中的未花费输出，且由锁定该输出的密钥签名
+    ///
+    /// 普通输入的公钥、多签输入揭示的条件须与输出的公钥哈希一致；签名本身由
+    /// Blockchain::verify_transacton 验证。创币交易没有需要检查的输入
+    pub fn verify_transaction_inputs(&self, tx: &Transaction) -> Result<()> {
+        if tx.is_coinbase() {
+            return Ok(());
+        }
+        for (input, vin) in tx.vin.iter().enumerate() {
+            let Some(output) = self.get_utxo(&vin.outpoint)? else {
+                return Err(self.missing_input(input, &vin.outpoint));
+            };
+            let owner = match &vin.condition {
+                Some(condition) => condition.identifier(),
+                None => {
+                    let mut pub_key_hash = vin.pub_key.clone();
+                    hash_pub_key(&mut pub_key_hash);
+                    pub_key_hash
+                }
+            };
+            if !output.is_locked_with_key(&owner) {
+                return Err(format_err!(
+                    "Input {} spends {}, which is locked to another key",
+                    input,
+                    vin.outpoint
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// 查找区块链，说明输入引用的输出为何不在集合中
+    fn missing_input(&self, input: usize, outpoint: &OutPoint) -> failure::Error {
+        let reason = match self.blockchain.find_transacton(&outpoint.txid) {
+            Err(_) => "does not exist",
+            Ok(tx) => match tx.vout.get(outpoint.vout as usize) {
+                None => "is out of range",
+                Some(out) if out.is_data() => "is an unspendable data output",
+                Some(_) => "is already spent",
+            },
         };
-        let db = sled::open("data/utxos")?;
+        format_err!("Input {} spends {}, which {}", input, outpoint, reason)
+    }
+
+    /// GetEntry 按位置查找一条未花费输出的记录，不在集合中时返回 None
+    pub fn get_entry(&self, outpoint: &OutPoint) -> Result<Option<UTXOEntry>> {
+        let key = outpoint.to_string();
+        match self.store()?.get(DEFAULT_TREE, key.as_bytes())? {
+            Some(value) => Ok(Some(decode_entry(key.as_bytes(), &value)?.1)),
+            None => Ok(None),
+        }
+    }
 
-        for kv in db.iter() {
-            let (_, v) = kv?;
-            let outs: TXOutputs = deserialize(&v.to_vec())?;
+    /// FindImmatureUTXO 查找公钥哈希对应的、尚未成熟的创币交易输出
+    pub fn find_immature_utxo(&self, pub_key_hash: &[u8]) -> Result<Vec<TXOutput>> {
+        Ok(self
+            .find_utxo_by_maturity(pub_key_hash, false)?
+            .into_iter()
+            .map(|(_, out)| out)
+            .collect())
+    }
 
-            for out in outs.outputs {
-                if out.is_locked_with_key(pub_key_hash) {
-                    utxos.outputs.push(out.clone())
+    fn find_utxo_by_maturity(
+        &self,
+        pub_key_hash: &[u8],
+        mature: bool,
+    ) -> Result<Vec<(OutPoint, TXOutput)>> {
+        let tip = self.blockchain.get_best_height()?;
+        Ok(self
+            .find_entries(&[pub_key_hash])?
+            .into_iter()
+            .filter(|(_, entry)| entry.is_mature(tip) == mature)
+            .map(|(outpoint, entry)| (outpoint, entry.output))
+            .collect())
+    }
+
+    /// 返回锁定到任一公钥哈希的全部记录
+    ///
+    /// 索引完整时只读取索引指向的记录，否则遍历一次整个集合
+    fn find_entries(&self, pub_key_hashes: &[&[u8]]) -> Result<Vec<(OutPoint, UTXOEntry)>> {
+        let store = self.store()?;
+        let mut entries = Vec::new();
+        if store.get(INDEX_TREE, INDEX_COMPLETE)?.is_some() {
+            for pub_key_hash in pub_key_hashes {
+                let prefix = index_key(pub_key_hash, "");
+                for kv in store.iter_prefix(INDEX_TREE, &prefix) {
+                    let (key, _) = kv?;
+                    let outpoint = &key[prefix.len()..];
+                    let value = store.get(DEFAULT_TREE, outpoint)?.ok_or_else(|| {
+                        format_err!(
+                            "UTXO index refers to missing output {}, run reindex",
+                            String::from_utf8_lossy(outpoint)
+                        )
+                    })?;
+                    entries.push(decode_entry(outpoint, &value)?);
+                }
+            }
+        } else {
+            let owners: HashSet<&[u8]> = pub_key_hashes.iter().copied().collect();
+            for item in decode_entries(&*store) {
+                let (outpoint, entry) = item?;
+                if owners.contains(&entry.output.pub_key_hash[..]) {
+                    entries.push((outpoint, entry));
                 }
             }
         }
+        entries.retain(|(_, entry)| !entry.output.is_data());
+        Ok(entries)
+    }
 
-        Ok(utxos)
+    /// FindBalances 返回每个公钥哈希分类后的余额，冻结的输出尚未成熟时计入未成熟余额
+    ///
+    /// 没有任何输出的公钥哈希不出现在结果中，金额累加溢出时返回错误
+    pub fn find_balances(
+        &self,
+        pub_key_hashes: &HashSet<Vec<u8>>,
+    ) -> Result<HashMap<Vec<u8>, Balance>> {
+        let tip = self.blockchain.get_best_height()?;
+        let frozen = read_frozen(&*self.store()?)?;
+        let mut balances: HashMap<Vec<u8>, Balance> = HashMap::new();
+        let owners: Vec<&[u8]> = pub_key_hashes.iter().map(Vec::as_slice).collect();
+        for (outpoint, entry) in self.find_entries(&owners)? {
+            let mature = entry.is_mature(tip);
+            let classified = balances.entry(entry.output.pub_key_hash).or_default();
+            let balance = if !mature {
+                &mut classified.immature
+            } else if frozen.contains(&outpoint) {
+                &mut classified.frozen
+            } else {
+                &mut classified.spendable
+            };
+            *balance = balance
+                .checked_add(entry.output.value)
+                .ok_or_else(|| format_err!("Balance overflows"))?;
+        }
+        Ok(balances)
     }
 
-    /// CountTransactions 返回UTXO集合中的交易数量
-    pub fn count_transactions(&self) -> Result<i32> {
-        let mut counter = 0;
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter() {
-            kv?;
-            counter += 1;
+    /// FindPubKeyHashes 返回UTXO集合中全部可花费输出的公钥哈希，包括尚未成熟的输出
+    pub fn find_pub_key_hashes(&self) -> Result<HashSet<Vec<u8>>> {
+        let mut pub_key_hashes = HashSet::new();
+        for item in self.iter() {
+            let (_, output) = item?;
+            if !output.is_data() {
+                pub_key_hashes.insert(output.pub_key_hash);
+            }
         }
-        Ok(counter)
+        Ok(pub_key_hashes)
     }
 
-    /// Reindex 重新构建UTXO集合
-    pub fn reindex(&self) -> Result<()> {
-        std::fs::remove_dir_all("data/utxos").ok();
-        let db = sled::open("data/utxos")?;
+    /// Iter 按 "txid:n" 的顺序惰性遍历全部未花费输出
+    ///
+    /// 每条记录单独解码，无法解码的记录产生一个错误项，遍历继续进行。遍历不是快照：
+    /// 遍历期间并发写入的修改可能出现也可能不出现，但每一项都是完整的记录；
+    /// 需要一致的结果时通过 SharedUTXOSet 持有读锁遍历
+    pub fn iter(&self) -> impl Iterator<Item = Result<(OutPoint, TXOutput)>> + use<> {
+        let (items, error) = match self.store() {
+            Ok(store) => (Some(decode_entries(&*store)), None),
+            Err(err) => (None, Some(Err(err))),
+        };
+        error
+            .into_iter()
+            .chain(items.into_iter().flatten())
+            .map(|item| item.map(|(outpoint, entry)| (outpoint, entry.output)))
+    }
 
-        let utxos = self.blockchain.find_utxo();
+    /// Stats 遍历一次UTXO集合，统计输出数量、交易数量和流通量
+    pub fn stats(&self) -> Result<UtxoStats> {
+        let mut stats = UtxoStats::default();
+        let mut txids = HashSet::new();
+        for item in self.iter() {
+            let (outpoint, output) = item?;
+            stats.outputs += 1;
+            stats.total_amount = stats
+                .total_amount
+                .checked_add(output.value)
+                .ok_or_else(|| format_err!("Total amount of the UTXO set overflows"))?;
+            txids.insert(outpoint.txid);
+        }
+        stats.transactions = txids.len();
+        stats.disk_size = self.store()?.size_on_disk()?;
+        Ok(stats)
+    }
 
-        for (txid, outs) in utxos {
-            db.insert(txid.as_bytes(), serialize(&outs)?)?;
+    /// Commitment 返回全部未花费输出的承诺哈希，集合相同的节点得到相同的结果
+    ///
+    /// 输出按 (txid, n) 排序，依次以 bincode 编码 (输出位置, 输出) 后计算 SHA-256。
+    /// 数据库按 "txid:n" 的顺序流式读取，同一交易的输出才需要在内存中重新排序
+    pub fn commitment(&self) -> Result<[u8; 32]> {
+        fn feed(hasher: &mut Sha256, group: &mut Vec<(OutPoint, TXOutput)>) -> Result<()> {
+            group.sort_by(|a, b| a.0.cmp(&b.0));
+            for item in group.drain(..) {
+                hasher.input(&serialize(&item)?);
+            }
+            Ok(())
         }
 
+        let mut hasher = Sha256::new();
+        let mut group = Vec::new();
+        for item in self.iter() {
+            let (outpoint, output) = item?;
+            if group
+                .first()
+                .is_some_and(|(first, _): &(OutPoint, _)| first.txid != outpoint.txid)
+            {
+                feed(&mut hasher, &mut group)?;
+            }
+            group.push((outpoint, output));
+        }
+        feed(&mut hasher, &mut group)?;
+        let mut commitment = [0; 32];
+        hasher.result(&mut commitment);
+        Ok(commitment)
+    }
+
+    /// MineBlock 在区块链上挖出新区块，不更新UTXO集合
+    ///
+    /// 启用 utxo-commitment 特性且集合与最新区块一致时，区块头包含集合的承诺哈希
+    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
+        #[cfg(feature = "utxo-commitment")]
+        if self.tip()?.0 == self.blockchain.tip {
+            let commitment = self.commitment()?;
+            return self
+                .blockchain
+                .mine_committed_block(transactions, commitment);
+        }
+        self.blockchain.mine_block(transactions)
+    }
+
+    /// BlockTemplate 返回接在最新区块之后、尚未做工作量证明的区块，见 Blockchain::block_template
+    pub fn block_template(&self, transactions: Vec<Transaction>) -> Result<Block> {
+        #[cfg(feature = "utxo-commitment")]
+        if self.tip()?.0 == self.blockchain.tip {
+            let commitment = self.commitment()?;
+            return self
+                .blockchain
+                .committed_block_template(transactions, commitment);
+        }
+        self.blockchain.block_template(transactions)
+    }
+
+    /// Freeze 冻结一个未花费的输出，冻结的输出不参与自动选币
+    ///
+    /// 冻结记录在重启和重建索引后保留；交易明确指定时仍可花费该输出
+    pub fn freeze(&self, outpoint: &OutPoint) -> Result<()> {
+        let store = self.store()?;
+        let key = outpoint.to_string();
+        if store.get(DEFAULT_TREE, key.as_bytes())?.is_none() {
+            return Err(format_err!("Output {} is not in the UTXO set", outpoint));
+        }
+        store.put(FROZEN_TREE, key.as_bytes(), &[])?;
         Ok(())
     }
 
-    /// Update 使用区块中的交易更新UTXO集合
-    pub fn update(&self, block: &Block) -> Result<()> {
-        let db = sled::open("data/utxos")?;
+    /// Unfreeze 解除输出的冻结，输出未冻结时返回错误
+    pub fn unfreeze(&self, outpoint: &OutPoint) -> Result<()> {
+        let store = self.store()?;
+        let key = outpoint.to_string();
+        if store.get(FROZEN_TREE, key.as_bytes())?.is_none() {
+            return Err(format_err!("Output {} is not frozen", outpoint));
+        }
+        store.delete(FROZEN_TREE, key.as_bytes())
+    }
 
-        for tx in block.get_transaction() {
-            if !tx.is_coinbase() {
-                for vin in &tx.vin {
-                    let mut update_outputs = TXOutputs {
-                        outputs: Vec::new(),
+    /// ListFrozen 返回仍未花费的冻结输出
+    pub fn list_frozen(&self) -> Result<Vec<(OutPoint, TXOutput)>> {
+        let store = self.store()?;
+        let mut frozen = Vec::new();
+        for outpoint in read_frozen(&*store)? {
+            if let Some(value) = store.get(DEFAULT_TREE, outpoint.to_string().as_bytes())? {
+                let (outpoint, entry) = decode_entry(outpoint.to_string().as_bytes(), &value)?;
+                frozen.push((outpoint, entry.output));
+            }
+        }
+        frozen.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(frozen)
+    }
+
+    /// Reindex 重新构建UTXO集合，保留冻结记录
+    pub fn reindex(&self) -> Result<()> {
+        self.reindex_with(|_, _| {}, &AtomicBool::new(false))
+    }
+
+    /// ReindexWith 从创世区块开始逐个读取区块重建UTXO集合，
+    /// 每处理完一个区块调用 progress(已处理的区块数, 区块总数)
+    ///
+    /// 修改累积到 REINDEX_BATCH 条左右写入一批。重建期间集合带有重建标记，
+    /// cancel 被置位或进程中断后集合不能使用，下次 reindex 或 reorganize 时从头重建
+    pub fn reindex_with(&self, progress: impl Fn(usize, usize), cancel: &AtomicBool) -> Result<()> {
+        let store = self.reset()?;
+        store.put(META_TREE, REINDEX_MARKER, b"")?;
+
+        let mut hashes = self.blockchain.get_block_hashs();
+        hashes.reverse();
+        let total = hashes.len();
+        progress(0, total);
+        let mut batch = Batch::default();
+        // 尚未写入的输出，None 表示已被花费
+        let mut pending: HashMap<String, Option<UTXOEntry>> = HashMap::new();
+        for (done, hash) in hashes.iter().enumerate() {
+            if cancel.load(Ordering::Relaxed) {
+                store.batch(batch)?;
+                return Err(format_err!(
+                    "Reindex cancelled after {} of {} blocks, run reindex again",
+                    done,
+                    total
+                ));
+            }
+            let block = self.blockchain.get_block(hash)?;
+            for tx in block.get_transaction() {
+                for outpoint in tx.outpoints() {
+                    let key = outpoint.to_string();
+                    let spent = match pending.insert(key.clone(), None) {
+                        Some(entry) => entry,
+                        None => match store.get(DEFAULT_TREE, key.as_bytes())? {
+                            Some(value) => Some(decode_entry(key.as_bytes(), &value)?.1),
+                            None => None,
+                        },
                     };
-                    let outs: TXOutputs = deserialize(&db.get(&vin.txid)?.unwrap().to_vec())?;
-                    for out_idx in 0..outs.outputs.len() {
-                        if out_idx != vin.vout as usize {
-                            update_outputs.outputs.push(outs.outputs[out_idx].clone());
-                        }
+                    // 与 find_utxo 一样忽略花费了不存在的输出的输入
+                    if let Some(entry) = spent {
+                        batch.delete(DEFAULT_TREE, &key);
+                        batch.delete(INDEX_TREE, index_key(&entry.output.pub_key_hash, &key));
                     }
+                }
+                for (index, out) in tx.vout.iter().enumerate() {
+                    if out.is_data() {
+                        continue;
+                    }
+                    let entry = UTXOEntry {
+                        output: out.clone(),
+                        coinbase: tx.is_coinbase(),
+                        height: block.get_height(),
+                    };
+                    let key = OutPoint::new(&tx.id, index as u32).to_string();
+                    batch.put(DEFAULT_TREE, &key, serialize(&entry)?);
+                    batch.put(INDEX_TREE, index_key(&out.pub_key_hash, &key), b"");
+                    pending.insert(key, Some(entry));
+                }
+            }
+            if pending.len() >= REINDEX_BATCH {
+                store.batch(std::mem::take(&mut batch))?;
+                pending.clear();
+            }
+            progress(done + 1, total);
+        }
+        batch.put(INDEX_TREE, INDEX_COMPLETE, b"");
+        let tip = (&self.blockchain.tip, self.blockchain.get_best_height()?);
+        batch.put(META_TREE, TIP_KEY, serialize(&tip)?);
+        batch.delete(META_TREE, REINDEX_MARKER);
+        store.batch(batch)
+    }
+
+    /// 清空UTXO存储，只保留冻结记录，返回打开的存储
+    fn reset(&self) -> Result<Arc<dyn KvStore>> {
+        let frozen = read_frozen(&*self.open_store()?)?;
+        match &self.store {
+            Some(store) => {
+                for tree in [DEFAULT_TREE, UNDO_TREE, INDEX_TREE, META_TREE] {
+                    store.clear(tree)?;
+                }
+            }
+            None => {
+                std::fs::remove_dir_all(&self.path).ok();
+            }
+        }
+        let store = self.open_store()?;
+        let mut batch = Batch::default();
+        for outpoint in frozen {
+            batch.put(FROZEN_TREE, outpoint.to_string(), b"");
+        }
+        store.batch(batch)?;
+        Ok(store)
+    }
+
+    /// Tip 返回UTXO集合对应的最新区块哈希及其高度
+    ///
+    /// 旧版本创建的集合没有记录，非空时视为与区块链的最新区块一致
+    pub fn tip(&self) -> Result<(String, i32)> {
+        let store = self.store()?;
+        if let Some(value) = store.get(META_TREE, TIP_KEY)? {
+            return Ok(deserialize(&value)?);
+        }
+        if is_empty(&*store)? {
+            Ok((String::new(), -1))
+        } else {
+            Ok((
+                self.blockchain.tip.clone(),
+                self.blockchain.get_best_height()?,
+            ))
+        }
+    }
+
+    /// ExportSnapshot 把全部未花费输出和对应的最新区块写入快照文件
+    ///
+    /// 文件先写入临时文件再改名，导出中断时不会留下不完整的快照
+    pub fn export_snapshot(&self, path: &Path) -> Result<SnapshotMeta> {
+        let (tip, height) = self.tip()?;
+        let entries = decode_entries(&*self.store()?).collect::<Result<Vec<_>>>()?;
+        let meta = SnapshotMeta {
+            hash: snapshot_hash(&tip, height, &entries)?,
+            tip,
+            height,
+            outputs: entries.len() as u64,
+        };
+        let file = SnapshotFile {
+            magic: SNAPSHOT_MAGIC,
+            meta,
+            entries,
+        };
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serialize(&file)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(file.meta)
+    }
+
+    /// ImportSnapshot 从快照文件恢复UTXO集合，并记录快照对应的最新区块
+    ///
+    /// 写入前校验整个文件，损坏或不完整的快照不会导入；集合非空时须指定 force 覆盖，
+    /// 冻结记录保留。之后同步到的区块从快照的最新区块开始连接
+    pub fn import_snapshot(&self, path: &Path, force: bool) -> Result<SnapshotMeta> {
+        let data = std::fs::read(path)?;
+        let corrupt =
+            |reason: String| format_err!("UTXO snapshot {} is corrupt: {}", path.display(), reason);
+        let file: SnapshotFile = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes()
+            .deserialize(&data)
+            .map_err(|e| corrupt(e.to_string()))?;
+        if file.magic != SNAPSHOT_MAGIC {
+            return Err(format_err!("{} is not a UTXO snapshot", path.display()));
+        }
+        let meta = file.meta;
+        if file.entries.len() as u64 != meta.outputs
+            || snapshot_hash(&meta.tip, meta.height, &file.entries)? != meta.hash
+        {
+            return Err(corrupt("contents do not match the hash".to_string()));
+        }
+
+        if !force && !is_empty(&*self.store()?)? {
+            return Err(format_err!(
+                "The UTXO set is not empty, use --force to replace it"
+            ));
+        }
+        let mut batch = Batch::default();
+        for (outpoint, entry) in &file.entries {
+            let key = outpoint.to_string();
+            batch.put(DEFAULT_TREE, &key, serialize(entry)?);
+            if !entry.output.is_data() {
+                batch.put(INDEX_TREE, index_key(&entry.output.pub_key_hash, &key), b"");
+            }
+        }
+        batch.put(INDEX_TREE, INDEX_COMPLETE, b"");
+        batch.put(META_TREE, TIP_KEY, serialize(&(&meta.tip, meta.height))?);
+
+        self.reset()?.batch(batch)?;
+        Ok(meta)
+    }
+
+    /// CheckIndex 交叉校验UTXO集合与公钥哈希索引，返回索引中的输出数量
+    ///
+    /// 每个可花费输出在索引中都有一项，索引中的每一项都指向公钥哈希相同的输出
+    pub fn check_index(&self) -> Result<usize> {
+        let store = self.store()?;
+        if store.get(INDEX_TREE, INDEX_COMPLETE)?.is_none() {
+            return Err(format_err!("The UTXO set has no index, run reindex"));
+        }
+        for item in decode_entries(&*store) {
+            let (outpoint, entry) = item?;
+            if !entry.output.is_data()
+                && store
+                    .get(
+                        INDEX_TREE,
+                        &index_key(&entry.output.pub_key_hash, &outpoint.to_string()),
+                    )?
+                    .is_none()
+            {
+                return Err(format_err!(
+                    "Output {} is missing from the UTXO index",
+                    outpoint
+                ));
+            }
+        }
+
+        let mut indexed = 0;
+        for kv in store.iter_prefix(INDEX_TREE, b"") {
+            let (key, _) = kv?;
+            if key == INDEX_COMPLETE {
+                continue;
+            }
+            let (pub_key_hash, outpoint) = split_index_key(&key)?;
+            let value = store.get(DEFAULT_TREE, outpoint)?.ok_or_else(|| {
+                format_err!(
+                    "UTXO index refers to missing output {}",
+                    String::from_utf8_lossy(outpoint)
+                )
+            })?;
+            let (outpoint, entry) = decode_entry(outpoint, &value)?;
+            if !entry.output.is_locked_with_key(pub_key_hash) {
+                return Err(format_err!(
+                    "UTXO index lists output {} under another public key hash",
+                    outpoint
+                ));
+            }
+            indexed += 1;
+        }
+        Ok(indexed)
+    }
+
+    /// Compact 删除不含任何输出的记录和指向不存在记录的索引项，并把修改写入磁盘
+    ///
+    /// 连接区块时被花费的输出连同其键一起删除，空记录只来自旧版本按交易保存的集合。
+    /// sled 没有手动压缩的接口，删除的记录占用的空间由其后台回收
+    pub fn compact(&self) -> Result<CompactReport> {
+        let store = self.store()?;
+        let mut report = CompactReport::default();
+        let mut batch = Batch::default();
+        let mut removed = HashSet::new();
+        for kv in store.iter_prefix(DEFAULT_TREE, b"") {
+            let (key, value) = kv?;
+            report.before += 1;
+            if is_empty_record(&key, &value) {
+                batch.delete(DEFAULT_TREE, &key);
+                removed.insert(key);
+            }
+        }
+        report.after = report.before - removed.len();
+        for kv in store.iter_prefix(INDEX_TREE, b"") {
+            let (key, _) = kv?;
+            if key == INDEX_COMPLETE {
+                continue;
+            }
+            let (_, outpoint) = split_index_key(&key)?;
+            if removed.contains(outpoint) || store.get(DEFAULT_TREE, outpoint)?.is_none() {
+                batch.delete(INDEX_TREE, &key);
+                report.index_removed += 1;
+            }
+        }
+        store.batch(batch)?;
+        store.flush()?;
+        Ok(report)
+    }
+
+    /// VerifyAgainstChain 遍历区块链重新推导UTXO集合，与保存的集合逐条比较
+    pub fn verify_against_chain(&self) -> Result<ConsistencyReport> {
+        Ok(self.diff_against_chain()?.0)
+    }
+
+    /// RepairAgainstChain 只改写与区块链不一致的记录及其索引项，返回修正前的差异
+    ///
+    /// 修改在一个批次中完成，之后集合对应区块链的最新区块
+    pub fn repair_against_chain(&self) -> Result<ConsistencyReport> {
+        let (report, expected) = self.diff_against_chain()?;
+        if report.is_consistent() {
+            return Ok(report);
+        }
+        let touched: HashSet<String> = report
+            .missing
+            .iter()
+            .chain(&report.stale)
+            .chain(&report.mismatched)
+            .map(OutPoint::to_string)
+            .collect();
+        let store = self.store()?;
+        let mut batch = Batch::default();
+        for outpoint in &report.stale {
+            batch.delete(DEFAULT_TREE, outpoint.to_string());
+        }
+        // 改写的输出原有的索引项全部删除，再按区块链中的记录重建
+        for kv in store.iter_prefix(INDEX_TREE, b"") {
+            let (key, _) = kv?;
+            if key == INDEX_COMPLETE {
+                continue;
+            }
+            let (_, outpoint) = split_index_key(&key)?;
+            if touched.contains(std::str::from_utf8(outpoint)?) {
+                batch.delete(INDEX_TREE, &key);
+            }
+        }
+        for outpoint in report.missing.iter().chain(&report.mismatched) {
+            let entry = &expected[outpoint];
+            let key = outpoint.to_string();
+            batch.put(DEFAULT_TREE, &key, serialize(entry)?);
+            batch.put(INDEX_TREE, index_key(&entry.output.pub_key_hash, &key), b"");
+        }
+        let tip = (&self.blockchain.tip, self.blockchain.get_best_height()?);
+        batch.put(META_TREE, TIP_KEY, serialize(&tip)?);
+
+        store.batch(batch)?;
+        Ok(report)
+    }
+
+    /// 比较保存的集合与区块链推导出的集合，同时返回推导出的集合
+    fn diff_against_chain(&self) -> Result<(ConsistencyReport, HashMap<OutPoint, UTXOEntry>)> {
+        let expected = self.blockchain.find_utxo();
+        Ok((self.diff_entries(&expected)?, expected))
+    }
+
+    /// DiffAgainst 比较本集合与集合 expected 中的全部输出，报告本集合相对 expected 的差异
+    pub fn diff_against(&self, expected: &UTXOSet) -> Result<ConsistencyReport> {
+        let expected = decode_entries(&*expected.store()?).collect::<Result<HashMap<_, _>>>()?;
+        self.diff_entries(&expected)
+    }
+
+    fn diff_entries(&self, expected: &HashMap<OutPoint, UTXOEntry>) -> Result<ConsistencyReport> {
+        let mut report = ConsistencyReport::default();
+        let mut stored = HashSet::new();
+        for kv in self.store()?.iter_prefix(DEFAULT_TREE, b"") {
+            let (k, v) = kv?;
+            let outpoint: OutPoint = std::str::from_utf8(&k)?.parse()?;
+            match expected.get(&outpoint) {
+                None => report.stale.push(outpoint.clone()),
+                Some(entry) if serialize(entry)? != *v => report.mismatched.push(outpoint.clone()),
+                Some(_) => {}
+            }
+            stored.insert(outpoint);
+        }
+        report.missing = expected
+            .keys()
+            .filter(|outpoint| !stored.contains(*outpoint))
+            .cloned()
+            .collect();
+        report.missing.sort();
+        report.stale.sort();
+        report.mismatched.sort();
+        Ok(report)
+    }
+
+    /// ConnectBlock 使用区块中的交易更新UTXO集合，并保存区块的撤销数据
+    ///
+    /// 所有修改在一个批次中完成，被花费的输出不在集合中时集合保持不变。
+    /// 区块链有交易索引时随后把区块中的交易加入索引
+    pub fn connect_block(&self, block: &Block) -> Result<()> {
+        let store = self.store()?;
+        let mut batch = Batch::default();
+        let mut undo = BlockUndo::default();
+        // 本区块已修改的输出，None 表示已被本区块的交易花费
+        let mut pending: HashMap<String, Option<UTXOEntry>> = HashMap::new();
+        for tx in block.get_transaction() {
+            for outpoint in tx.outpoints() {
+                let key = outpoint.to_string();
+                let entry = match pending.insert(key.clone(), None) {
+                    Some(entry) => entry,
+                    None => match store.get(DEFAULT_TREE, key.as_bytes())? {
+                        Some(value) => Some(deserialize::<UTXOEntry>(&value).map_err(|e| {
+                            format_err!("Invalid UTXO entry {}, run reindex: {}", outpoint, e)
+                        })?),
+                        None => None,
+                    },
+                }
+                .ok_or_else(|| format_err!("Spent output {} is not in the UTXO set", outpoint))?;
+                batch.delete(DEFAULT_TREE, &key);
+                batch.delete(INDEX_TREE, index_key(&entry.output.pub_key_hash, &key));
+                undo.spent.push((outpoint.clone(), entry));
+            }
+            for (index, out) in tx.vout.iter().enumerate() {
+                if out.is_data() {
+                    continue;
+                }
+                let entry = UTXOEntry {
+                    output: out.clone(),
+                    coinbase: tx.is_coinbase(),
+                    height: block.get_height(),
+                };
+                let key = OutPoint::new(&tx.id, index as u32).to_string();
+                batch.put(DEFAULT_TREE, &key, serialize(&entry)?);
+                batch.put(INDEX_TREE, index_key(&out.pub_key_hash, &key), b"");
+                pending.insert(key, Some(entry));
+            }
+        }
+        // 从创世区块开始连接的集合，索引覆盖全部输出
+        if block.get_prev_hash().is_empty() {
+            batch.put(INDEX_TREE, INDEX_COMPLETE, b"");
+        }
+        batch.put(UNDO_TREE, block.get_hash(), serialize(&undo)?);
+        let tip = (block.get_hash(), block.get_height());
+        batch.put(META_TREE, TIP_KEY, serialize(&tip)?);
+        store.batch(batch)?;
+        self.blockchain.index_transactions(block)
+    }
+
+    /// DisconnectBlock 撤销区块对UTXO集合的修改：删除区块创建的输出，恢复被花费的输出
+    ///
+    /// undo 必须是连接该区块时保存的撤销数据。交易按相反的顺序撤销，
+    /// 同一区块内创建又花费的输出也能正确恢复；修改在一个批次中完成，
+    /// 随后从交易索引中删除区块中的交易
+    pub fn disconnect_block(&self, block: &Block, undo: &BlockUndo) -> Result<()> {
+        let mismatch = || format_err!("Undo data does not match block {}", block.get_hash());
 
-                    if update_outputs.outputs.is_empty() {
-                        db.remove(&vin.txid)?;
-                    } else {
-                        db.insert(vin.txid.as_bytes(), serialize(&update_outputs)?)?;
+        let store = self.store()?;
+        let mut batch = Batch::default();
+        // 本区块已修改的输出此时是否在集合中
+        let mut pending: HashMap<String, bool> = HashMap::new();
+        let mut restore = undo.spent.iter().rev();
+        for tx in block.get_transaction().iter().rev() {
+            for (n, out) in tx.vout.iter().enumerate() {
+                if out.is_data() {
+                    continue;
+                }
+                let key = OutPoint::new(&tx.id, n as u32).to_string();
+                let present = match pending.insert(key.clone(), false) {
+                    Some(present) => present,
+                    None => store.get(DEFAULT_TREE, key.as_bytes())?.is_some(),
+                };
+                if !present {
+                    return Err(format_err!(
+                        "Output {} of block {} is not in the UTXO set",
+                        key,
+                        block.get_hash()
+                    ));
+                }
+                batch.delete(DEFAULT_TREE, &key);
+                batch.delete(INDEX_TREE, index_key(&out.pub_key_hash, &key));
+            }
+            let spent: Vec<&OutPoint> = tx.outpoints().collect();
+            for outpoint in spent.into_iter().rev() {
+                match restore.next() {
+                    Some((undone, entry)) if undone == outpoint => {
+                        let key = outpoint.to_string();
+                        batch.put(DEFAULT_TREE, &key, serialize(entry)?);
+                        batch.put(INDEX_TREE, index_key(&entry.output.pub_key_hash, &key), b"");
+                        pending.insert(key, true);
                     }
+                    _ => return Err(mismatch()),
                 }
             }
+        }
+        if restore.next().is_some() {
+            return Err(mismatch());
+        }
+        batch.delete(UNDO_TREE, block.get_hash());
+        let tip = (block.get_prev_hash(), block.get_height() - 1);
+        batch.put(META_TREE, TIP_KEY, serialize(&tip)?);
+        store.batch(batch)?;
+        self.blockchain.unindex_transactions(block)
+    }
 
-            let mut new_outputs = TXOutputs {
-                outputs: Vec::new(),
+    /// GetBlockUndo 读取连接区块时保存的撤销数据
+    pub fn get_block_undo(&self, block_hash: &str) -> Result<BlockUndo> {
+        let value = self
+            .store()?
+            .get(UNDO_TREE, block_hash.as_bytes())?
+            .ok_or_else(|| format_err!("No undo data for block {}, run reindex", block_hash))?;
+        Ok(deserialize(&value)?)
+    }
+
+    /// Reorganize 把UTXO集合从它记录的最新区块切换到区块链当前的最新区块
+    ///
+    /// 断开旧分支上分叉点之后的区块，再依次连接新分支上的区块；
+    /// 缺少撤销数据或区块等无法增量更新时重建索引，上次重建被中断时从头重建
+    pub fn reorganize(&self) -> Result<()> {
+        if self.open_store()?.get(META_TREE, REINDEX_MARKER)?.is_some() {
+            warn!("the previous reindex of the UTXO set was interrupted, reindexing");
+            return self.reindex();
+        }
+        let (old_tip, _) = self.tip()?;
+        if let Err(err) = self.switch_tip(&old_tip, &self.blockchain.tip, false) {
+            warn!(
+                "cannot update the UTXO set incrementally, reindexing: {}",
+                err
+            );
+            self.reindex()?;
+        }
+        Ok(())
+    }
+
+    /// SwitchTo 把UTXO集合切换到已保存的区块 new_tip，连接新分支上的每个区块之前先用
+    /// Blockchain::validate_block 完整验证，返回按断开顺序排列的旧分支区块
+    ///
+    /// 区块链的最新区块不变，由调用方在切换成功后更新。每次断开或连接区块和集合记录的
+    /// 最新区块在同一批次中修改，切换中途崩溃时 Reorganize 能回到区块链的最新区块；
+    /// 验证失败或需要断开检查点上的区块时集合停在当时的位置并返回错误，不会重建索引
+    pub fn switch_to(&self, new_tip: &str) -> Result<Vec<Block>> {
+        let (old_tip, _) = self.tip()?;
+        self.switch_tip(&old_tip, new_tip, true)
+    }
+
+    fn switch_tip(&self, old_tip: &str, new_tip: &str, validate: bool) -> Result<Vec<Block>> {
+        let find = |hash: &str| -> Result<Option<Block>> {
+            if hash.is_empty() {
+                Ok(None)
+            } else {
+                self.blockchain.get_block(hash).map(Some)
+            }
+        };
+        let height = |block: &Option<Block>| block.as_ref().map_or(-1, Block::get_height);
+
+        let mut old = find(old_tip)?;
+        let mut new = find(new_tip)?;
+        let mut disconnected = Vec::new();
+        let mut connect = Vec::new();
+        while old.as_ref().map(Block::get_hash) != new.as_ref().map(Block::get_hash) {
+            if height(&old) >= height(&new) {
+                // 两侧不同且旧分支不低于新分支，旧分支一定还有区块
+                let block = old.take().unwrap();
+                if validate
+                    && self.blockchain.checkpoints.get(&block.get_height())
+                        == Some(&block.get_hash())
+                {
+                    return Err(format_err!(
+                        "Reorganization would disconnect the checkpoint block {} at height {}",
+                        block.get_hash(),
+                        block.get_height()
+                    ));
+                }
+                self.disconnect_block(&block, &self.get_block_undo(&block.get_hash())?)?;
+                old = find(&block.get_prev_hash())?;
+                disconnected.push(block);
+            } else {
+                let block = new.take().unwrap();
+                new = find(&block.get_prev_hash())?;
+                connect.push(block);
+            }
+        }
+        for block in connect.iter().rev() {
+            if validate {
+                self.blockchain.validate_block(block, self)?;
+            }
+            self.connect_block(block)?;
+        }
+        Ok(disconnected)
+    }
+}
+
+impl SharedUTXOSet {
+    /// New 共享UTXO集合，磁盘上的数据库保持打开，多个线程同时读写时不争用文件锁
+    pub fn new(utxo: UTXOSet) -> Result<SharedUTXOSet> {
+        let store = utxo.open_store()?;
+        let utxo = UTXOSet::with_store(utxo.blockchain, store);
+        Ok(SharedUTXOSet(Arc::new(RwLock::new(utxo))))
+    }
+
+    /// Read 获取读锁，持有期间集合和区块链都不会改变
+    pub fn read(&self) -> RwLockReadGuard<'_, UTXOSet> {
+        self.0.read().unwrap()
+    }
+
+    /// Write 获取写锁，用于连接区块、挖矿等修改
+    pub fn write(&self) -> RwLockWriteGuard<'_, UTXOSet> {
+        self.0.write().unwrap()
+    }
+}
+
+/// 计算快照内容的 SHA-256：按 bincode 序列化最新区块哈希、高度和全部输出
+fn snapshot_hash(tip: &str, height: i32, entries: &[(OutPoint, UTXOEntry)]) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.input(&serialize(&(tip, height, entries))?);
+    Ok(hasher.result_str())
+}
+
+/// 读取全部冻结记录，已花费的输出在断开区块后可能恢复，其记录不删除
+fn read_frozen(store: &dyn KvStore) -> Result<HashSet<OutPoint>> {
+    let mut frozen = HashSet::new();
+    for kv in store.iter_prefix(FROZEN_TREE, b"") {
+        let (key, _) = kv?;
+        let outpoint = std::str::from_utf8(&key)?.parse()?;
+        frozen.insert(outpoint);
+    }
+    Ok(frozen)
+}
+
+/// 索引树的键：4 字节大端长度、公钥哈希和 "txid:n"，outpoint 为空时即为该公钥哈希的前缀
+fn index_key(pub_key_hash: &[u8], outpoint: &str) -> Vec<u8> {
+    let mut key = (pub_key_hash.len() as u32).to_be_bytes().to_vec();
+    key.extend_from_slice(pub_key_hash);
+    key.extend_from_slice(outpoint.as_bytes());
+    key
+}
+
+/// 把索引树的键拆分为公钥哈希和 "txid:n"
+fn split_index_key(key: &[u8]) -> Result<(&[u8], &[u8])> {
+    let invalid = || format_err!("Invalid UTXO index key, run reindex");
+    let len = key.get(..4).ok_or_else(invalid)?;
+    let len = u32::from_be_bytes(len.try_into()?) as usize;
+    let rest = &key[4..];
+    if rest.len() < len {
+        return Err(invalid());
+    }
+    Ok(rest.split_at(len))
+}
+
+/// 不含任何输出的记录：空值，或旧版本以 txid 为键、输出列表已经为空的记录
+fn is_empty_record(key: &[u8], value: &[u8]) -> bool {
+    value.is_empty() || (!key.contains(&b':') && value == 0u64.to_le_bytes())
+}
+
+/// 集合中是否没有任何输出
+fn is_empty(store: &dyn KvStore) -> Result<bool> {
+    Ok(store
+        .iter_prefix(DEFAULT_TREE, b"")
+        .next()
+        .transpose()?
+        .is_none())
+}
+
+/// 惰性解码存储中的全部记录，每条记录产生一个结果
+fn decode_entries(
+    store: &dyn KvStore,
+) -> impl Iterator<Item = Result<(OutPoint, UTXOEntry)>> + use<> {
+    store.iter_prefix(DEFAULT_TREE, b"").map(|kv| {
+        let (k, v) = kv?;
+        decode_entry(&k, &v)
+    })
+}
+
+/// DecodeEntry 反序列化 UTXO 集合中的一条记录
+///
+/// 记录以 "txid:n" 为键、每个输出单独保存；按交易保存的旧格式记录解析失败，需要重建索引
+fn decode_entry(key: &[u8], value: &[u8]) -> Result<(OutPoint, UTXOEntry)> {
+    let invalid = |e: String| format_err!("Invalid UTXO entry, run reindex: {}", e);
+    let outpoint = std::str::from_utf8(key)
+        .map_err(|e| invalid(e.to_string()))?
+        .parse::<OutPoint>()
+        .map_err(|e| invalid(e.to_string()))?;
+    let entry = deserialize(value).map_err(|e| invalid(e.to_string()))?;
+    Ok((outpoint, entry))
+}
+
+/// SelectCoins 从候选输出 (outpoint, value) 中按策略累加，直到金额不少于 amount
+///
+/// 候选输出先按 txid 和输出序号排序，面额相同时也按该顺序选取，
+/// 同样的UTXO集合总是选出同样的输出；金额累加溢出时返回错误
+fn select_coins(
+    mut candidates: Vec<(OutPoint, u64)>,
+    amount: u64,
+    strategy: CoinSelection,
+) -> Result<(u64, Vec<OutPoint>)> {
+    let overflow = || format_err!("Spendable amount overflows");
+
+    candidates.sort();
+    match strategy {
+        CoinSelection::LargestFirst => candidates.sort_by_key(|c| std::cmp::Reverse(c.1)),
+        CoinSelection::SmallestFirst => candidates.sort_by_key(|c| c.1),
+        CoinSelection::Accumulate => {}
+        CoinSelection::BranchAndBound => {
+            let mut sorted = candidates.clone();
+            sorted.sort_by_key(|c| std::cmp::Reverse(c.1));
+            let values: Vec<u64> = sorted.iter().map(|c| c.1).collect();
+            if let Some(picked) = branch_and_bound(&values, amount, BNB_TOLERANCE, BNB_MAX_TRIES) {
+                let mut unspent_outputs = Vec::new();
+                let mut accumulated: u64 = 0;
+                for i in picked {
+                    let (outpoint, value) = &sorted[i];
+                    accumulated = accumulated.checked_add(*value).ok_or_else(overflow)?;
+                    unspent_outputs.push(outpoint.clone());
+                }
+                return Ok((accumulated, unspent_outputs));
+            }
+        }
+    }
+
+    let mut unspent_outputs = Vec::new();
+    let mut accumulated: u64 = 0;
+    for (outpoint, value) in candidates {
+        if accumulated >= amount {
+            break;
+        }
+        accumulated = accumulated.checked_add(value).ok_or_else(overflow)?;
+        unspent_outputs.push(outpoint);
+    }
+
+    Ok((accumulated, unspent_outputs))
+}
+
+/// BranchAndBound 在按面额降序排列的 values 中深度优先搜索总额落在
+/// [target, target + tolerance] 内的组合，返回超出最少的一组下标
+///
+/// 搜索最多尝试 max_tries 个节点，找不到时返回 None
+fn branch_and_bound(
+    values: &[u64],
+    target: u64,
+    tolerance: u64,
+    max_tries: usize,
+) -> Option<Vec<usize>> {
+    // 用 u128 累加，任意多个 u64 面额相加都不会溢出
+    let target = target as u128;
+    let upper = target + tolerance as u128;
+
+    // remaining[i] 为 values[i..] 的总额，用于剪掉凑不够的分支
+    let mut remaining = vec![0u128; values.len() + 1];
+    for i in (0..values.len()).rev() {
+        remaining[i] = remaining[i + 1] + values[i] as u128;
+    }
+
+    let mut best: Option<(u128, Vec<usize>)> = None;
+    let mut picked: Vec<usize> = Vec::new();
+    let mut sum = 0u128;
+    let mut index = 0;
+
+    for _ in 0..max_tries {
+        let backtrack = if sum > upper || sum + remaining[index] < target {
+            true
+        } else if sum >= target {
+            let waste = sum - target;
+            if best.as_ref().is_none_or(|(w, _)| waste < *w) {
+                best = Some((waste, picked.clone()));
+            }
+            if waste == 0 {
+                break;
+            }
+            true
+        } else {
+            false
+        };
+
+        if backtrack {
+            // 撤销最近选入的输出，改走不选它的分支
+            match picked.pop() {
+                Some(last) => {
+                    sum -= values[last] as u128;
+                    index = last + 1;
+                }
+                None => break,
+            }
+        } else {
+            picked.push(index);
+            sum += values[index] as u128;
+            index += 1;
+        }
+    }
+
+    best.map(|(_, picked)| picked)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::walletstorage::memory::MemoryStorage;
+    use crate::wallets::Wallets;
+    use bitcoincash_addr::Address;
+    use std::cell::RefCell;
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let miner = ws.create_wallet();
+        let wallet = ws.get_wallet(&address).unwrap().clone();
+        let pub_key_hash = Address::decode(&address).unwrap().body;
+
+        let mut bc = Blockchain::in_memory();
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        bc.add_block(Block::new_genesis_block(cbtx)).unwrap();
+        let mut utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+
+        // 创世区块的奖励可以立即花费
+        let (accumulated, _) = utxo_set
+            .find_spendable_outputs(&pub_key_hash, 1, 1)
+            .unwrap();
+        assert_eq!(accumulated, SUBSIDY);
+        assert_eq!(utxo_set.get_balance(&address).unwrap(), SUBSIDY);
+        assert_eq!(
+            utxo_set
+                .get_balance(&wallet.get_address_bech32(Network::Mainnet))
+                .unwrap(),
+            SUBSIDY
+        );
+        let utxos = utxo_set.get_utxos(&address).unwrap();
+        assert_eq!(
+            utxos[0].0.txid,
+            utxo_set
+                .blockchain
+                .get_block(&utxo_set.blockchain.tip)
+                .unwrap()
+                .get_transaction()[0]
+                .id
+        );
+        assert_eq!(utxo_set.get_balance(&miner).unwrap(), 0);
+        // 无效地址返回错误，而不是余额 0
+        assert!(utxo_set.get_balance("not an address").is_err());
+        assert!(utxo_set.get_utxos(&address[1..]).is_err());
+
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), 1, 0, Network::Mainnet)
+                .unwrap();
+        let block = utxo_set.blockchain.mine_block(vec![cbtx]).unwrap();
+        utxo_set.connect_block(&block).unwrap();
+        let (accumulated, _) = utxo_set
+            .find_spendable_outputs(&pub_key_hash, 1, 1)
+            .unwrap();
+        assert_eq!(accumulated, SUBSIDY);
+        assert_eq!(utxo_set.find_immature_utxo(&pub_key_hash).unwrap().len(), 1);
+        let balances = ws.balances(&utxo_set, false).unwrap();
+        let balance = balances.iter().find(|b| b.address == address).unwrap();
+        assert_eq!((balance.spendable, balance.immature), (SUBSIDY, SUBSIDY));
+        let balance = balances.iter().find(|b| b.address == miner).unwrap();
+        assert_eq!((balance.spendable, balance.immature), (0, 0));
+
+        for height in 2..=COINBASE_MATURITY + 1 {
+            let cbtx = Transaction::new_coinbase(
+                miner.clone(),
+                String::new(),
+                height,
+                0,
+                Network::Mainnet,
+            )
+            .unwrap();
+            let block = utxo_set.blockchain.mine_block(vec![cbtx]).unwrap();
+            utxo_set.connect_block(&block).unwrap();
+            let mature = height > COINBASE_MATURITY;
+            assert_eq!(utxo_set.get_utxos(&address).unwrap().len() == 2, mature);
+        }
+
+        let (accumulated, _) = utxo_set
+            .find_spendable_outputs(&pub_key_hash, 2 * SUBSIDY, 1)
+            .unwrap();
+        assert_eq!(accumulated, 2 * SUBSIDY);
+        assert!(
+            utxo_set
+                .find_immature_utxo(&pub_key_hash)
+                .unwrap()
+                .is_empty()
+        );
+
+        // 每个输出按 OutPoint 单独记录，找零保留在原交易中的下标
+        let tx =
+            Transaction::new_utxo(&wallet, &miner, 4, &TxOptions::default(), &utxo_set).unwrap();
+        let height = utxo_set.blockchain.get_best_height().unwrap() + 1;
+        let cbtx =
+            Transaction::new_coinbase(miner, String::new(), height, 0, Network::Mainnet).unwrap();
+        let block = utxo_set
+            .blockchain
+            .mine_block(vec![cbtx, tx.clone()])
+            .unwrap();
+        utxo_set.connect_block(&block).unwrap();
+        let (accumulated, unspent) = utxo_set
+            .find_spendable_outputs(&pub_key_hash, 2 * SUBSIDY, 1)
+            .unwrap();
+        assert_eq!(accumulated, 2 * SUBSIDY - 4);
+        assert!(unspent.contains(&OutPoint::new(&tx.id, 1)));
+        assert!(!unspent.contains(&tx.vin[0].outpoint));
+
+        utxo_set.reindex().unwrap();
+        let (reindexed, _) = utxo_set
+            .find_spendable_outputs(&pub_key_hash, 2 * SUBSIDY, 1)
+            .unwrap();
+        assert_eq!(reindexed, accumulated);
+        assert!(utxo_set.connect_block(&block).is_err());
+    }
+
+    #[test]
+    fn test_utxo_stats() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let miner = ws.create_wallet();
+        let wallet = ws.get_wallet(&address).unwrap().clone();
+        let bc = Blockchain::in_memory();
+        let mut utxo_set = UTXOSet::in_memory(bc);
+        assert_eq!(utxo_set.stats().unwrap().outputs, 0);
+
+        let cbtx =
+            Transaction::new_coinbase(address, String::new(), 0, 0, Network::Mainnet).unwrap();
+        utxo_set
+            .blockchain
+            .add_block(Block::new_genesis_block(cbtx))
+            .unwrap();
+        utxo_set.reindex().unwrap();
+        for height in 1..3 {
+            let cbtx = Transaction::new_coinbase(
+                miner.clone(),
+                String::new(),
+                height,
+                0,
+                Network::Mainnet,
+            )
+            .unwrap();
+            let block = utxo_set.blockchain.mine_block(vec![cbtx]).unwrap();
+            utxo_set.connect_block(&block).unwrap();
+        }
+        let stats = utxo_set.stats().unwrap();
+        assert_eq!((stats.outputs, stats.transactions), (3, 3));
+        assert_eq!(stats.total_amount, 3 * SUBSIDY);
+        assert!(stats.disk_size > 0);
+
+        // 花费创世区块的奖励：一个输出变为付款和找零两个，手续费归矿工，流通量不变
+        let options = TxOptions {
+            fee: 1,
+            ..TxOptions::default()
+        };
+        let tx = Transaction::new_utxo(&wallet, &miner, 4, &options, &utxo_set).unwrap();
+        let cbtx = Transaction::new_coinbase(miner, String::new(), 3, 1, Network::Mainnet).unwrap();
+        let block = utxo_set.blockchain.mine_block(vec![cbtx, tx]).unwrap();
+        utxo_set.connect_block(&block).unwrap();
+        let stats = utxo_set.stats().unwrap();
+        assert_eq!((stats.outputs, stats.transactions), (5, 4));
+        assert_eq!(stats.total_amount, 4 * SUBSIDY);
+    }
+
+    fn snapshot(utxo_set: &UTXOSet) -> Vec<(Vec<u8>, Vec<u8>)> {
+        utxo_set
+            .store()
+            .unwrap()
+            .iter_prefix(DEFAULT_TREE, b"")
+            .map(|kv| kv.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_connect_disconnect_block() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let miner = ws.create_wallet();
+        let wallet = ws.get_wallet(&address).unwrap().clone();
+        let bc = Blockchain::in_memory();
+        let mut utxo_set = UTXOSet::in_memory(bc);
+        let cbtx =
+            Transaction::new_coinbase(address, String::new(), 0, 0, Network::Mainnet).unwrap();
+        let genesis = Block::new_genesis_block(cbtx);
+        utxo_set.blockchain.add_block(genesis.clone()).unwrap();
+        utxo_set.connect_block(&genesis).unwrap();
+        let cbtx = Transaction::new_coinbase(miner.clone(), String::new(), 1, 0, Network::Mainnet)
+            .unwrap();
+        let block = utxo_set.blockchain.mine_block(vec![cbtx]).unwrap();
+        utxo_set.connect_block(&block).unwrap();
+        let before_tip = snapshot(&utxo_set);
+
+        // 区块 2 花费创世区块的奖励
+        let tx =
+            Transaction::new_utxo(&wallet, &miner, 4, &TxOptions::default(), &utxo_set).unwrap();
+        let cbtx = Transaction::new_coinbase(miner, String::new(), 2, 0, Network::Mainnet).unwrap();
+        let tip = utxo_set
+            .blockchain
+            .mine_block(vec![cbtx, tx.clone()])
+            .unwrap();
+        utxo_set.connect_block(&tip).unwrap();
+        let connected = snapshot(&utxo_set);
+        let undo = utxo_set.get_block_undo(&tip.get_hash()).unwrap();
+        assert_eq!(undo.spent.len(), 1);
+        assert_eq!(undo.spent[0].0, tx.vin[0].outpoint);
+        assert_eq!(undo.spent[0].1.output.value, SUBSIDY);
+
+        // 撤销数据与区块不符时不做任何修改
+        assert!(
+            utxo_set
+                .disconnect_block(&tip, &BlockUndo::default())
+                .is_err()
+        );
+        assert_eq!(snapshot(&utxo_set), connected);
+
+        utxo_set.disconnect_block(&tip, &undo).unwrap();
+        assert_eq!(snapshot(&utxo_set), before_tip);
+        assert_eq!(utxo_set.check_index().unwrap(), 2);
+        assert!(utxo_set.get_block_undo(&tip.get_hash()).is_err());
+        assert!(utxo_set.disconnect_block(&tip, &undo).is_err());
+
+        utxo_set.connect_block(&tip).unwrap();
+        assert_eq!(snapshot(&utxo_set), connected);
+        assert_eq!(utxo_set.check_index().unwrap(), 4);
+        assert_eq!(
+            utxo_set.get_block_undo(&tip.get_hash()).unwrap().spent[0].0,
+            undo.spent[0].0
+        );
+    }
+
+    #[test]
+    fn test_reorganize() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let miner = ws.create_wallet();
+        let bc = Blockchain::in_memory();
+        let mut utxo_set = UTXOSet::in_memory(bc);
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        let genesis = Block::new_genesis_block(cbtx);
+        utxo_set.blockchain.add_block(genesis.clone()).unwrap();
+        utxo_set.reorganize().unwrap();
+        assert_eq!(utxo_set.tip().unwrap(), (genesis.get_hash(), 0));
+        for height in 1..3 {
+            let cbtx = Transaction::new_coinbase(
+                miner.clone(),
+                String::new(),
+                height,
+                0,
+                Network::Mainnet,
+            )
+            .unwrap();
+            utxo_set.blockchain.mine_block(vec![cbtx]).unwrap();
+        }
+        let old_tip = utxo_set.blockchain.tip.clone();
+        utxo_set.reorganize().unwrap();
+        assert_eq!(utxo_set.stats().unwrap().outputs, 3);
+
+        // 从创世区块分叉出更长的分支，最新区块切换到新分支
+        let mut prev = genesis.get_hash();
+        for height in 1..4 {
+            let cbtx = Transaction::new_coinbase(
+                address.clone(),
+                String::new(),
+                height,
+                0,
+                Network::Mainnet,
+            )
+            .unwrap();
+            let block = Block::new_block(vec![cbtx], prev, height, INITIAL_BITS).unwrap();
+            prev = block.get_hash();
+            utxo_set.blockchain.add_block(block).unwrap();
+        }
+        assert_eq!(utxo_set.blockchain.tip, prev);
+        utxo_set.reorganize().unwrap();
+        assert_eq!(utxo_set.tip().unwrap(), (prev.clone(), 3));
+        assert!(utxo_set.get_block_undo(&old_tip).is_err());
+        assert!(utxo_set.get_block_undo(&prev).is_ok());
+        assert_eq!(utxo_set.get_balance(&miner).unwrap(), 0);
+        assert_eq!(utxo_set.stats().unwrap().outputs, 4);
+        assert_eq!(utxo_set.check_index().unwrap(), 4);
+
+        let reorganized = snapshot(&utxo_set);
+        utxo_set.reindex().unwrap();
+        assert_eq!(snapshot(&utxo_set), reorganized);
+    }
+
+    #[test]
+    fn test_utxo_index() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let miner = ws.create_wallet();
+        let wallet = ws.get_wallet(&address).unwrap().clone();
+        let pub_key_hash = Address::decode(&address).unwrap().body;
+        let bc = Blockchain::in_memory();
+        let mut utxo_set = UTXOSet::in_memory(bc);
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        let genesis = Block::new_genesis_block(cbtx);
+        utxo_set.blockchain.add_block(genesis.clone()).unwrap();
+        utxo_set.connect_block(&genesis).unwrap();
+        let tx =
+            Transaction::new_utxo(&wallet, &miner, 4, &TxOptions::default(), &utxo_set).unwrap();
+        let cbtx = Transaction::new_coinbase(miner.clone(), String::new(), 1, 0, Network::Mainnet)
+            .unwrap();
+        let block = utxo_set.blockchain.mine_block(vec![cbtx, tx]).unwrap();
+        utxo_set.connect_block(&block).unwrap();
+        assert_eq!(utxo_set.check_index().unwrap(), 3);
+        let indexed = utxo_set
+            .find_spendable_outputs(&pub_key_hash, SUBSIDY, 1)
+            .unwrap();
+        assert_eq!(indexed.0, SUBSIDY - 4);
+        assert_eq!(utxo_set.get_balance(&miner).unwrap(), 4);
+
+        // 没有索引时遍历整个集合，结果相同
+        let store = utxo_set.store().unwrap();
+        store.delete(INDEX_TREE, INDEX_COMPLETE).unwrap();
+        assert!(utxo_set.check_index().is_err());
+        assert_eq!(
+            utxo_set
+                .find_spendable_outputs(&pub_key_hash, SUBSIDY, 1)
+                .unwrap(),
+            indexed
+        );
+        assert_eq!(utxo_set.get_balance(&miner).unwrap(), 4);
+
+        utxo_set.reindex().unwrap();
+        assert_eq!(utxo_set.check_index().unwrap(), 3);
+        let (outpoint, out) = utxo_set.get_utxos(&miner).unwrap().remove(0);
+        let key = index_key(&out.pub_key_hash, &outpoint.to_string());
+        store.delete(INDEX_TREE, &key).unwrap();
+        let err = utxo_set.check_index().unwrap_err();
+        assert!(
+            err.to_string().contains("missing from the UTXO index"),
+            "{}",
+            err
+        );
+        store.put(INDEX_TREE, &key, b"").unwrap();
+        store
+            .put(
+                INDEX_TREE,
+                &index_key(&pub_key_hash, &outpoint.to_string()),
+                b"",
+            )
+            .unwrap();
+        let err = utxo_set.check_index().unwrap_err();
+        assert!(
+            err.to_string().contains("another public key hash"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let miner = ws.create_wallet();
+        let wallet = ws.get_wallet(&address).unwrap().clone();
+        let bc = Blockchain::in_memory();
+        let mut utxo_set = UTXOSet::in_memory(bc);
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        let genesis = Block::new_genesis_block(cbtx);
+        utxo_set.blockchain.add_block(genesis.clone()).unwrap();
+        utxo_set.connect_block(&genesis).unwrap();
+        let tx =
+            Transaction::new_utxo(&wallet, &miner, 4, &TxOptions::default(), &utxo_set).unwrap();
+        let cbtx = Transaction::new_coinbase(miner.clone(), String::new(), 1, 0, Network::Mainnet)
+            .unwrap();
+        let block = utxo_set.blockchain.mine_block(vec![cbtx, tx]).unwrap();
+        utxo_set.connect_block(&block).unwrap();
+
+        let path = std::env::temp_dir().join(format!("rustchain-snapshot-{}", std::process::id()));
+        let meta = utxo_set.export_snapshot(&path).unwrap();
+        assert_eq!((meta.tip.clone(), meta.height), (block.get_hash(), 1));
+        assert_eq!(meta.outputs, 3);
+
+        // 另一个节点的空集合导入快照后，统计和余额与导出方一致
+        let fresh = || {
+            UTXOSet::in_memory(Blockchain {
+                tip: utxo_set.blockchain.tip.clone(),
+                db: utxo_set.blockchain.db.clone(),
+                checkpoints: Default::default(),
+                coinbase_height_activation: utxo_set.blockchain.coinbase_height_activation,
+                bits_activation: utxo_set.blockchain.bits_activation,
+                mining_threads: utxo_set.blockchain.mining_threads,
+                network: utxo_set.blockchain.network,
+            })
+        };
+        let imported = fresh();
+        assert_eq!(imported.import_snapshot(&path, false).unwrap(), meta);
+        let stats = |set: &UTXOSet| {
+            let stats = set.stats().unwrap();
+            (stats.outputs, stats.transactions, stats.total_amount)
+        };
+        assert_eq!(stats(&imported), stats(&utxo_set));
+        let owners = utxo_set.find_pub_key_hashes().unwrap();
+        assert_eq!(
+            imported.find_balances(&owners).unwrap(),
+            utxo_set.find_balances(&owners).unwrap()
+        );
+        assert_eq!(imported.tip().unwrap(), (block.get_hash(), 1));
+        assert_eq!(imported.check_index().unwrap(), 3);
+
+        // 非空集合须指定 force 才能覆盖
+        let err = imported.import_snapshot(&path, false).unwrap_err();
+        assert!(err.to_string().contains("--force"), "{}", err);
+        imported.import_snapshot(&path, true).unwrap();
+        assert_eq!(stats(&imported), stats(&utxo_set));
+
+        // 损坏或截断的快照被拒绝，集合保持为空
+        let data = std::fs::read(&path).unwrap();
+        let mut corrupted = data.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 1;
+        for bad in [
+            corrupted,
+            data[..data.len() - 10].to_vec(),
+            [&data[..], b"x"].concat(),
+        ] {
+            std::fs::write(&path, bad).unwrap();
+            let target = fresh();
+            let err = target.import_snapshot(&path, false).unwrap_err();
+            assert!(err.to_string().contains("is corrupt"), "{}", err);
+            assert_eq!(target.stats().unwrap().outputs, 0);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_commitment() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let miner = ws.create_wallet();
+        let wallet = ws.get_wallet(&address).unwrap().clone();
+        let new_set = || UTXOSet::in_memory(Blockchain::in_memory());
+        let mut utxo_set = new_set();
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        let genesis = Block::new_genesis_block(cbtx);
+        utxo_set.blockchain.add_block(genesis.clone()).unwrap();
+        utxo_set.connect_block(&genesis).unwrap();
+        let mut blocks = vec![genesis];
+        for height in 1..3 {
+            let cbtx = Transaction::new_coinbase(
+                miner.clone(),
+                String::new(),
+                height,
+                0,
+                Network::Mainnet,
+            )
+            .unwrap();
+            let block = utxo_set.mine_block(vec![cbtx]).unwrap();
+            utxo_set.connect_block(&block).unwrap();
+            blocks.push(block);
+        }
+        let commitment = utxo_set.commitment().unwrap();
+        assert_ne!(commitment, new_set().commitment().unwrap());
+
+        // 另一条独立同步相同区块的链先断开再连接，集合的写入顺序不同，承诺相同
+        let mut other = new_set();
+        for block in &blocks {
+            other.blockchain.add_block(block.clone()).unwrap();
+        }
+        other.reorganize().unwrap();
+        let tip = blocks.last().unwrap();
+        other
+            .disconnect_block(tip, &other.get_block_undo(&tip.get_hash()).unwrap())
+            .unwrap();
+        other.connect_block(tip).unwrap();
+        assert_eq!(other.commitment().unwrap(), commitment);
+
+        let tx =
+            Transaction::new_utxo(&wallet, &miner, 4, &TxOptions::default(), &utxo_set).unwrap();
+        let cbtx = Transaction::new_coinbase(miner.clone(), String::new(), 3, 0, Network::Mainnet)
+            .unwrap();
+        let block = utxo_set.mine_block(vec![cbtx, tx]).unwrap();
+        #[cfg(feature = "utxo-commitment")]
+        assert_eq!(block.get_utxo_commitment(), Some(commitment));
+        utxo_set.connect_block(&block).unwrap();
+        assert_ne!(utxo_set.commitment().unwrap(), commitment);
+        other.blockchain.add_block(block).unwrap();
+        other.reorganize().unwrap();
+        assert_eq!(other.commitment().unwrap(), utxo_set.commitment().unwrap());
+    }
+
+    #[test]
+    fn test_shared_utxo_set() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let miner = ws.create_wallet();
+        let pub_key_hash = Address::decode(&miner).unwrap().body;
+        let mut bc = Blockchain::in_memory();
+        let cbtx =
+            Transaction::new_coinbase(address, String::new(), 0, 0, Network::Mainnet).unwrap();
+        bc.add_block(Block::new_genesis_block(cbtx)).unwrap();
+        let utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        let shared = SharedUTXOSet::new(utxo_set).unwrap();
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        // 一个线程挖矿并连接区块，四个线程同时查询；每次查询看到的余额都与区块高度一致
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let (shared, done, miner) = (shared.clone(), done.clone(), miner.clone());
+                let pub_key_hash = pub_key_hash.clone();
+                std::thread::spawn(move || {
+                    let mut reads = 0;
+                    while !done.load(std::sync::atomic::Ordering::SeqCst) || reads == 0 {
+                        let utxo_set = shared.read();
+                        let height = utxo_set.blockchain.get_best_height().unwrap() as u64;
+                        let balance = utxo_set.get_address_balance(&miner).unwrap();
+                        assert_eq!(balance.spendable + balance.immature, height * SUBSIDY);
+                        assert_eq!(utxo_set.get_balance(&miner).unwrap(), balance.spendable);
+                        let (found, _) = utxo_set
+                            .find_spendable_outputs(&pub_key_hash, u64::MAX, 1)
+                            .unwrap();
+                        assert_eq!(found, balance.spendable);
+                        reads += 1;
+                    }
+                    reads
+                })
+            })
+            .collect();
+        for height in 1..5 {
+            let mut utxo_set = shared.write();
+            let cbtx = Transaction::new_coinbase(
+                miner.clone(),
+                String::new(),
+                height,
+                0,
+                Network::Mainnet,
+            )
+            .unwrap();
+            let block = utxo_set.mine_block(vec![cbtx]).unwrap();
+            utxo_set.connect_block(&block).unwrap();
+        }
+        done.store(true, std::sync::atomic::Ordering::SeqCst);
+        for reader in readers {
+            assert!(reader.join().unwrap() > 0);
+        }
+        let balance = shared.read().get_address_balance(&miner).unwrap();
+        assert_eq!(balance.spendable + balance.immature, 4 * SUBSIDY);
+    }
+
+    #[test]
+    fn test_verify_transaction_inputs() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let miner = ws.create_wallet();
+        let wallet = ws.get_wallet(&address).unwrap().clone();
+        let other = ws.get_wallet(&miner).unwrap().clone();
+        let bc = Blockchain::in_memory();
+        let mut utxo_set = UTXOSet::in_memory(bc);
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        let genesis = Block::new_genesis_block(cbtx.clone());
+        utxo_set.blockchain.add_block(genesis.clone()).unwrap();
+        utxo_set.connect_block(&genesis).unwrap();
+        let coin = OutPoint::new(&cbtx.id, 0);
+        assert_eq!(
+            utxo_set.get_utxo(&coin).unwrap(),
+            Some(cbtx.vout[0].clone())
+        );
+        utxo_set.verify_transaction_inputs(&cbtx).unwrap();
+
+        let tx =
+            Transaction::new_utxo(&wallet, &miner, 4, &TxOptions::default(), &utxo_set).unwrap();
+        utxo_set.verify_transaction_inputs(&tx).unwrap();
+
+        // 公钥与被花费输出的公钥哈希不符
+        let mut forged = tx.clone();
+        forged.vin[0].pub_key = other.public_key.clone();
+        let err = utxo_set.verify_transaction_inputs(&forged).unwrap_err();
+        assert!(err.to_string().contains("locked to another key"), "{}", err);
+
+        let reward =
+            Transaction::new_coinbase(miner.clone(), String::new(), 1, 0, Network::Mainnet)
+                .unwrap();
+        let block = utxo_set.mine_block(vec![reward, tx.clone()]).unwrap();
+        utxo_set.connect_block(&block).unwrap();
+        assert_eq!(utxo_set.get_utxo(&coin).unwrap(), None);
+        let change = OutPoint::new(&tx.id, 1);
+        assert_eq!(
+            utxo_set.get_utxo(&change).unwrap(),
+            Some(tx.vout[1].clone())
+        );
+
+        let check = |outpoint: OutPoint| {
+            let mut spend = tx.clone();
+            spend.vin[0].outpoint = outpoint;
+            utxo_set
+                .verify_transaction_inputs(&spend)
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(check(coin).contains("is already spent"));
+        assert!(check(OutPoint::new(&"00".repeat(32), 0)).contains("does not exist"));
+        assert!(check(OutPoint::new(&tx.id, 9)).contains("is out of range"));
+        assert_eq!(utxo_set.get_utxo(&OutPoint::new(&tx.id, 9)).unwrap(), None);
+
+        let spend =
+            Transaction::new_utxo(&wallet, &miner, 3, &TxOptions::default(), &utxo_set).unwrap();
+        assert_eq!(spend.vin[0].outpoint, change);
+        utxo_set.verify_transaction_inputs(&spend).unwrap();
+    }
+
+    #[test]
+    fn test_verify_against_chain() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let miner = ws.create_wallet();
+        let wallet = ws.get_wallet(&address).unwrap().clone();
+        let bc = Blockchain::in_memory();
+        let mut utxo_set = UTXOSet::in_memory(bc);
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        let genesis = Block::new_genesis_block(cbtx.clone());
+        utxo_set.blockchain.add_block(genesis.clone()).unwrap();
+        utxo_set.connect_block(&genesis).unwrap();
+        let tx =
+            Transaction::new_utxo(&wallet, &miner, 4, &TxOptions::default(), &utxo_set).unwrap();
+        let reward =
+            Transaction::new_coinbase(miner.clone(), String::new(), 1, 0, Network::Mainnet)
+                .unwrap();
+        let block = utxo_set.mine_block(vec![reward, tx.clone()]).unwrap();
+        utxo_set.connect_block(&block).unwrap();
+        assert!(utxo_set.verify_against_chain().unwrap().is_consistent());
+        let consistent = snapshot(&utxo_set);
+
+        // 删除一条记录、恢复一条已花费的输出、改动一条记录的金额
+        let store = utxo_set.store().unwrap();
+        let removed = OutPoint::new(&tx.id, 0);
+        store
+            .delete(DEFAULT_TREE, removed.to_string().as_bytes())
+            .unwrap();
+        let spent = OutPoint::new(&cbtx.id, 0);
+        let entry = UTXOEntry {
+            output: cbtx.vout[0].clone(),
+            coinbase: true,
+            height: 0,
+        };
+        store
+            .put(
+                DEFAULT_TREE,
+                spent.to_string().as_bytes(),
+                &serialize(&entry).unwrap(),
+            )
+            .unwrap();
+        store
+            .put(
+                INDEX_TREE,
+                &index_key(&entry.output.pub_key_hash, &spent.to_string()),
+                b"",
+            )
+            .unwrap();
+        let changed = OutPoint::new(&tx.id, 1);
+        let mut entry = utxo_set.get_entry(&changed).unwrap().unwrap();
+        entry.output.value += 1;
+        store
+            .put(
+                DEFAULT_TREE,
+                changed.to_string().as_bytes(),
+                &serialize(&entry).unwrap(),
+            )
+            .unwrap();
+
+        let expected = ConsistencyReport {
+            missing: vec![removed],
+            stale: vec![spent],
+            mismatched: vec![changed],
+        };
+        assert_eq!(utxo_set.verify_against_chain().unwrap(), expected);
+        assert_eq!(utxo_set.repair_against_chain().unwrap(), expected);
+        assert!(utxo_set.verify_against_chain().unwrap().is_consistent());
+        assert_eq!(utxo_set.check_index().unwrap(), 3);
+        assert_eq!(snapshot(&utxo_set), consistent);
+        assert_eq!(utxo_set.get_balance(&miner).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_freeze() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let miner = ws.create_wallet();
+        let wallet = ws.get_wallet(&address).unwrap().clone();
+        let miner_key = Address::decode(&miner).unwrap().body;
+        let bc = Blockchain::in_memory();
+        let mut utxo_set = UTXOSet::in_memory(bc);
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        let genesis = Block::new_genesis_block(cbtx);
+        utxo_set.blockchain.add_block(genesis.clone()).unwrap();
+        utxo_set.connect_block(&genesis).unwrap();
+        let tx =
+            Transaction::new_utxo(&wallet, &miner, 4, &TxOptions::default(), &utxo_set).unwrap();
+        let cbtx =
+            Transaction::new_coinbase(address, String::new(), 1, 0, Network::Mainnet).unwrap();
+        let block = utxo_set
+            .blockchain
+            .mine_block(vec![cbtx, tx.clone()])
+            .unwrap();
+        utxo_set.connect_block(&block).unwrap();
+
+        let payment = OutPoint::new(&tx.id, 0);
+        utxo_set.freeze(&payment).unwrap();
+        assert_eq!(
+            utxo_set.find_spendable_outputs(&miner_key, 1, 1).unwrap().0,
+            0
+        );
+        assert!(utxo_set.estimate_fee(&miner_key, 1, 1, 1).is_err());
+        assert_eq!(utxo_set.get_balance(&miner).unwrap(), 0);
+        let balance = utxo_set.get_address_balance(&miner).unwrap();
+        assert_eq!((balance.spendable, balance.frozen), (0, 4));
+        assert_eq!(utxo_set.get_utxos(&miner).unwrap().len(), 1);
+        assert_eq!(utxo_set.list_frozen().unwrap()[0].0, payment);
+
+        // 不存在或已花费的输出不能冻结
+        assert!(utxo_set.freeze(&OutPoint::new(&tx.id, 5)).is_err());
+        assert!(utxo_set.freeze(&tx.vin[0].outpoint).is_err());
+
+        // 重建索引后冻结记录仍在
+        utxo_set.reindex().unwrap();
+        assert_eq!(utxo_set.get_address_balance(&miner).unwrap().frozen, 4);
+
+        utxo_set.unfreeze(&payment).unwrap();
+        assert!(utxo_set.unfreeze(&payment).is_err());
+        assert_eq!(
+            utxo_set.find_spendable_outputs(&miner_key, 1, 1).unwrap().0,
+            4
+        );
+        assert!(utxo_set.list_frozen().unwrap().is_empty());
+    }
+
+    /// 反复创建并花光数百笔交易后，集合中只剩仍有未花费输出的交易
+    #[test]
+    fn test_spent_transactions_pruned() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let miner = ws.create_wallet();
+        let wallet = ws.get_wallet(&address).unwrap().clone();
+        let mut utxo_set = UTXOSet::in_memory(Blockchain::in_memory());
+        let connect = |utxo_set: &mut UTXOSet, txs: Vec<Transaction>| {
+            let height = utxo_set.blockchain.get_best_height().unwrap() + 1;
+            let cbtx = Transaction::new_coinbase(
+                miner.clone(),
+                String::new(),
+                height,
+                0,
+                Network::Mainnet,
+            )
+            .unwrap();
+            let txs = [vec![cbtx], txs].concat();
+            let tip = utxo_set.blockchain.tip.clone();
+            let block = Block::new_unmined_block(txs, tip, height).unwrap();
+            utxo_set.blockchain.add_block(block.clone()).unwrap();
+            utxo_set.connect_block(&block).unwrap();
+        };
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        let genesis = Block::new_genesis_block(cbtx);
+        utxo_set.blockchain.add_block(genesis.clone()).unwrap();
+        utxo_set.connect_block(&genesis).unwrap();
+
+        // 拆成 5 个输出，每个区块中每条链花光上一笔交易的唯一输出
+        let outputs = vec![(address.clone(), 2); 5];
+        let split =
+            Transaction::new_utxo_multi(&wallet, &outputs, &TxOptions::default(), &utxo_set)
+                .unwrap();
+        let mut heads: Vec<OutPoint> = (0..5).map(|n| OutPoint::new(&split.id, n)).collect();
+        connect(&mut utxo_set, vec![split]);
+        let blocks = 60;
+        for _ in 0..blocks {
+            let mut txs = Vec::new();
+            for head in &mut heads {
+                let tx = Transaction::new_utxo_from_inputs(
+                    &wallet,
+                    std::slice::from_ref(head),
+                    &address,
+                    2,
+                    &TxOptions::default(),
+                    &utxo_set,
+                )
+                .unwrap();
+                assert_eq!(tx.vout.len(), 1);
+                *head = OutPoint::new(&tx.id, 0);
+                txs.push(tx);
+            }
+            connect(&mut utxo_set, txs);
+        }
+
+        // 每个区块的创币交易加上 5 条链的最后一笔交易
+        let live = blocks + 1 + heads.len();
+        let stats = utxo_set.stats().unwrap();
+        assert_eq!((stats.transactions, stats.outputs), (live, live));
+        assert_eq!(snapshot(&utxo_set).len(), live);
+        let report = utxo_set.compact().unwrap();
+        assert_eq!(
+            report,
+            CompactReport {
+                before: live,
+                after: live,
+                index_removed: 0
+            }
+        );
+
+        // 旧版本留下的空记录和指向不存在记录的索引项被删除
+        let store = utxo_set.store().unwrap();
+        store
+            .put(
+                DEFAULT_TREE,
+                heads[0].txid.as_bytes(),
+                &serialize(&Vec::<TXOutput>::new()).unwrap(),
+            )
+            .unwrap();
+        store.put(DEFAULT_TREE, b"ab:0", b"").unwrap();
+        let pub_key_hash = Address::decode(&address).unwrap().body;
+        store
+            .put(INDEX_TREE, &index_key(&pub_key_hash, "ab:0"), b"")
+            .unwrap();
+        store
+            .put(INDEX_TREE, &index_key(&pub_key_hash, "cd:1"), b"")
+            .unwrap();
+        let report = utxo_set.compact().unwrap();
+        assert_eq!(
+            report,
+            CompactReport {
+                before: live + 2,
+                after: live,
+                index_removed: 2
+            }
+        );
+        assert_eq!(utxo_set.check_index().unwrap(), live);
+        assert!(utxo_set.verify_against_chain().unwrap().is_consistent());
+        assert_eq!(utxo_set.get_balance(&address).unwrap(), 10);
+    }
+
+    /// 按旧方式从整条链一次算出的集合记录，用于比较重建结果
+    fn expected_records(blockchain: &Blockchain) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut records: Vec<_> = blockchain
+            .find_utxo()
+            .into_iter()
+            .map(|(outpoint, entry)| {
+                (
+                    outpoint.to_string().into_bytes(),
+                    serialize(&entry).unwrap(),
+                )
+            })
+            .collect();
+        records.sort();
+        records
+    }
+
+    /// 在链上追加一个只有创币交易和 txs 的未挖矿区块
+    fn push_block(blockchain: &mut Blockchain, to: &str, txs: Vec<Transaction>) {
+        let height = blockchain.get_best_height().unwrap() + 1;
+        let cbtx =
+            Transaction::new_coinbase(to.to_string(), String::new(), height, 0, Network::Mainnet)
+                .unwrap();
+        let tip = blockchain.tip.clone();
+        let block = Block::new_unmined_block([vec![cbtx], txs].concat(), tip, height).unwrap();
+        blockchain.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn test_reindex_progress() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let miner = ws.create_wallet();
+        let wallet = ws.get_wallet(&address).unwrap().clone();
+        let mut utxo_set = UTXOSet::in_memory(Blockchain::in_memory());
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        utxo_set
+            .blockchain
+            .add_block(Block::new_genesis_block(cbtx))
+            .unwrap();
+        // 创币输出超过一批的大小，最后花费写在第一批中的创世输出
+        let blocks = REINDEX_BATCH + 100;
+        for _ in 0..blocks {
+            push_block(&mut utxo_set.blockchain, &miner, vec![]);
+        }
+        utxo_set.reindex().unwrap();
+        let outputs = [(miner.clone(), 4), (address.clone(), 6)];
+        let tx = Transaction::new_utxo_multi(&wallet, &outputs, &TxOptions::default(), &utxo_set)
+            .unwrap();
+        push_block(&mut utxo_set.blockchain, &miner, vec![tx]);
+        let total = blocks + 2;
+
+        let calls = RefCell::new(Vec::new());
+        utxo_set
+            .reindex_with(
+                |done, total| calls.borrow_mut().push((done, total)),
+                &AtomicBool::new(false),
+            )
+            .unwrap();
+        assert_eq!(
+            calls.into_inner(),
+            (0..=total).map(|done| (done, total)).collect::<Vec<_>>()
+        );
+        assert_eq!(snapshot(&utxo_set), expected_records(&utxo_set.blockchain));
+        assert_eq!(utxo_set.check_index().unwrap(), total + 1);
+        assert_eq!(utxo_set.get_balance(&address).unwrap(), 6);
+
+        // 中途取消后集合不能使用，reorganize 从头重建
+        let cancel = AtomicBool::new(false);
+        let err = utxo_set
+            .reindex_with(
+                |done, _| cancel.store(done == REINDEX_BATCH, Ordering::Relaxed),
+                &cancel,
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("cancelled"), "{}", err);
+        let err = utxo_set.get_balance(&address).unwrap_err();
+        assert!(err.to_string().contains("interrupted"), "{}", err);
+        assert!(utxo_set.tip().is_err());
+        utxo_set.reorganize().unwrap();
+        assert_eq!(snapshot(&utxo_set), expected_records(&utxo_set.blockchain));
+        assert_eq!(utxo_set.get_balance(&address).unwrap(), 6);
+        assert_eq!(
+            utxo_set.tip().unwrap(),
+            (utxo_set.blockchain.tip.clone(), total as i32 - 1)
+        );
+    }
+
+    /// 在 5000 个区块的链上比较一次写入全部记录的旧重建方式和分批重建，
+    /// 使用 cargo test --release -- --ignored --nocapture bench_reindex 运行
+    #[test]
+    #[ignore]
+    fn bench_reindex() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let wallet = ws.get_wallet(&address).unwrap().clone();
+        let temporary = || -> Arc<dyn KvStore> {
+            Arc::new(SledStore::new(
+                sled::Config::new().temporary(true).open().unwrap(),
+            ))
+        };
+        let blockchain = Blockchain::with_store(temporary(), Network::Mainnet).unwrap();
+        let mut utxo_set = UTXOSet::with_store(blockchain, temporary());
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        let genesis = Block::new_genesis_block(cbtx);
+        utxo_set.blockchain.add_block(genesis.clone()).unwrap();
+        utxo_set.connect_block(&genesis).unwrap();
+        // 每个区块一笔交易，把上一笔交易的输出拆给自己和找零
+        let mut head = OutPoint::new(&genesis.get_transaction()[0].id, 0);
+        for _ in 1..5000 {
+            let tx = Transaction::new_utxo_from_inputs(
+                &wallet,
+                std::slice::from_ref(&head),
+                &address,
+                2,
+                &TxOptions::default(),
+                &utxo_set,
+            )
+            .unwrap();
+            head = OutPoint::new(&tx.id, tx.vout.len() as u32 - 1);
+            push_block(&mut utxo_set.blockchain, &address, vec![tx]);
+            let tip = utxo_set.blockchain.tip.clone();
+            utxo_set
+                .connect_block(&utxo_set.blockchain.get_block(&tip).unwrap())
+                .unwrap();
+        }
+
+        let start = std::time::Instant::now();
+        let store = utxo_set.reset().unwrap();
+        let mut batch = Batch::default();
+        for (outpoint, entry) in utxo_set.blockchain.find_utxo() {
+            let key = outpoint.to_string();
+            batch.put(DEFAULT_TREE, &key, serialize(&entry).unwrap());
+            batch.put(INDEX_TREE, index_key(&entry.output.pub_key_hash, &key), b"");
+        }
+        store.batch(batch).unwrap();
+        store.flush().unwrap();
+        let old = start.elapsed();
+        let expected = snapshot(&utxo_set);
+
+        let start = std::time::Instant::now();
+        utxo_set.reindex().unwrap();
+        utxo_set.store().unwrap().flush().unwrap();
+        let new = start.elapsed();
+        assert_eq!(snapshot(&utxo_set), expected);
+        println!(
+            "reindex of 5000 blocks: all at once {:?}, batched {:?}",
+            old, new
+        );
+    }
+
+    /// 在约 10 万条记录的集合中比较按索引查找和遍历整个集合查找一个地址的输出
+    ///
+    /// 使用 cargo test --release -- --ignored --nocapture bench_index_lookup 运行
+    #[test]
+    #[ignore]
+    fn bench_index_lookup() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let pub_key_hash = Address::decode(&address).unwrap().body;
+        let store: Arc<dyn KvStore> = Arc::new(SledStore::new(
+            sled::Config::new().temporary(true).open().unwrap(),
+        ));
+        let mut utxo_set = UTXOSet::with_store(Blockchain::in_memory(), store.clone());
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        utxo_set
+            .blockchain
+            .add_block(Block::new_genesis_block(cbtx))
+            .unwrap();
+
+        // 其他地址各有一个输出，查找的地址只有 4 个
+        let mut batch = Batch::default();
+        for n in 0..100_000u64 {
+            let mut owner = vec![0; 20];
+            owner[..8].copy_from_slice(&n.to_be_bytes());
+            if n % 25_000 == 0 {
+                owner = pub_key_hash.clone();
+            }
+            let entry = UTXOEntry {
+                output: TXOutput {
+                    value: 10,
+                    pub_key_hash: owner,
+                    data: None,
+                },
+                coinbase: false,
+                height: 0,
             };
-            for out in &tx.vout {
-                new_outputs.outputs.push(out.clone());
+            let key = OutPoint::new(&format!("{:064x}", n), 0).to_string();
+            batch.put(INDEX_TREE, index_key(&entry.output.pub_key_hash, &key), b"");
+            batch.put(DEFAULT_TREE, &key, serialize(&entry).unwrap());
+        }
+        batch.put(INDEX_TREE, INDEX_COMPLETE, b"");
+        store.batch(batch).unwrap();
+        store.flush().unwrap();
+
+        const ROUNDS: u32 = 10;
+        let lookup = || {
+            let start = std::time::Instant::now();
+            let mut found = None;
+            for _ in 0..ROUNDS {
+                let spendable = utxo_set
+                    .find_spendable_outputs(&pub_key_hash, 40, 1)
+                    .unwrap();
+                let balance = utxo_set.get_balance(&address).unwrap();
+                found = Some((spendable, balance));
             }
+            (start.elapsed() / ROUNDS, found.unwrap())
+        };
+        let (indexed, expected) = lookup();
+        assert_eq!(expected.0.0, 40);
+        assert_eq!(expected.0.1.len(), 4);
+        assert_eq!(expected.1, 40);
+
+        // 去掉索引完成标记后查找退回到遍历整个集合
+        store.delete(INDEX_TREE, INDEX_COMPLETE).unwrap();
+        let (scanned, found) = lookup();
+        assert_eq!(found, expected);
+        println!(
+            "lookup of 4 outputs among 100000: indexed {:?}, full scan {:?}",
+            indexed, scanned
+        );
+    }
+
+    #[test]
+    fn test_min_conf() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let miner = ws.create_wallet();
+        let wallet = ws.get_wallet(&address).unwrap().clone();
+        let miner_key = Address::decode(&miner).unwrap().body;
+        let bc = Blockchain::in_memory();
+        let mut utxo_set = UTXOSet::in_memory(bc);
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        let genesis = Block::new_genesis_block(cbtx);
+        utxo_set.blockchain.add_block(genesis.clone()).unwrap();
+        utxo_set.connect_block(&genesis).unwrap();
+        assert_eq!(
+            utxo_set.get_balance_with_conf(&address, 1).unwrap(),
+            SUBSIDY
+        );
+        assert_eq!(utxo_set.get_balance_with_conf(&address, 2).unwrap(), 0);
+
+        // 最新区块中的输出有 1 个确认
+        let tx =
+            Transaction::new_utxo(&wallet, &miner, 4, &TxOptions::default(), &utxo_set).unwrap();
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), 1, 0, Network::Mainnet)
+                .unwrap();
+        let block = utxo_set.blockchain.mine_block(vec![cbtx, tx]).unwrap();
+        utxo_set.connect_block(&block).unwrap();
+        assert_eq!(utxo_set.get_balance_with_conf(&miner, 0).unwrap(), 4);
+        assert_eq!(utxo_set.get_balance_with_conf(&miner, 1).unwrap(), 4);
+        assert_eq!(utxo_set.get_balance_with_conf(&miner, 2).unwrap(), 0);
+        assert_eq!(utxo_set.get_balance(&miner).unwrap(), 4);
+        assert_eq!(
+            utxo_set.find_spendable_outputs(&miner_key, 1, 2).unwrap().0,
+            0
+        );
+        let options = TxOptions {
+            min_conf: 2,
+            ..TxOptions::default()
+        };
+        let err = Transaction::new_utxo(&wallet, &miner, 2, &options, &utxo_set).unwrap_err();
+        assert!(err.to_string().contains("Not Enough balance"), "{}", err);
 
-            db.insert(tx.id.as_bytes(), serialize(&new_outputs)?)?;
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), 2, 0, Network::Mainnet)
+                .unwrap();
+        let block = utxo_set.blockchain.mine_block(vec![cbtx]).unwrap();
+        utxo_set.connect_block(&block).unwrap();
+        assert_eq!(utxo_set.get_balance_with_conf(&miner, 2).unwrap(), 4);
+        assert_eq!(utxo_set.get_balance_with_conf(&miner, 3).unwrap(), 0);
+        assert_eq!(
+            utxo_set.find_spendable_outputs(&miner_key, 1, 2).unwrap().0,
+            4
+        );
+        Transaction::new_utxo(&wallet, &miner, 2, &options, &utxo_set).unwrap();
+    }
+
+    #[test]
+    fn test_decode_entry() {
+        let entry = UTXOEntry {
+            output: TXOutput {
+                value: 7,
+                pub_key_hash: vec![1; 20],
+                data: None,
+            },
+            coinbase: false,
+            height: 3,
+        };
+        let value = serialize(&entry).unwrap();
+        let (outpoint, decoded) = decode_entry(b"ab:2", &value).unwrap();
+        assert_eq!(outpoint, OutPoint::new("ab", 2));
+        assert_eq!(decoded.output, entry.output);
+
+        // 旧格式以 txid 为键
+        let err = decode_entry(b"ab", &value).unwrap_err();
+        assert!(err.to_string().contains("run reindex"));
+        assert!(decode_entry(b"ab:2", &value[1..]).is_err());
+    }
+
+    #[test]
+    fn test_iter() {
+        let bc = Blockchain::in_memory();
+        let utxo_set = UTXOSet::in_memory(bc);
+        let store = utxo_set.store().unwrap();
+        for (n, value) in [5u64, 6, 7].into_iter().enumerate() {
+            let entry = UTXOEntry {
+                output: TXOutput {
+                    value,
+                    pub_key_hash: vec![1; 20],
+                    data: None,
+                },
+                coinbase: false,
+                height: 0,
+            };
+            store
+                .put(
+                    DEFAULT_TREE,
+                    format!("ab:{}", n).as_bytes(),
+                    &serialize(&entry).unwrap(),
+                )
+                .unwrap();
         }
-        Ok(())
+        // 损坏中间的一条记录，该项产生错误，其余项照常返回
+        store.put(DEFAULT_TREE, b"ab:1", b"corrupt").unwrap();
+        let items: Vec<_> = utxo_set.iter().collect();
+        assert_eq!(items.len(), 3);
+        assert!(items[1].is_err());
+        let values: Vec<u64> = items
+            .into_iter()
+            .filter_map(|item| item.ok())
+            .map(|(_, out)| out.value)
+            .collect();
+        assert_eq!(values, [5, 7]);
+        assert!(utxo_set.stats().is_err());
+    }
+
+    /// 同一组区块分别保存在 sled 和内存后端中，得到相同的集合和余额
+    #[test]
+    fn test_storage_backends() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let miner = ws.create_wallet();
+        let wallet = ws.get_wallet(&address).unwrap().clone();
+
+        let mut bc = Blockchain::in_memory();
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), 0, 0, Network::Mainnet)
+                .unwrap();
+        bc.add_block(Block::new_genesis_block(cbtx)).unwrap();
+        let mut source = UTXOSet::in_memory(bc);
+        source.reindex().unwrap();
+        for height in 1..=2 {
+            let tx =
+                Transaction::new_utxo(&wallet, &miner, 4, &TxOptions::default(), &source).unwrap();
+            let cbtx = Transaction::new_coinbase(
+                miner.clone(),
+                String::new(),
+                height,
+                0,
+                Network::Mainnet,
+            )
+            .unwrap();
+            let block = source.blockchain.mine_block(vec![cbtx, tx]).unwrap();
+            source.connect_block(&block).unwrap();
+        }
+        let mut blocks: Vec<Block> = source.blockchain.iter().collect();
+        blocks.reverse();
+
+        let temporary = || -> Arc<dyn KvStore> {
+            Arc::new(SledStore::new(
+                sled::Config::new().temporary(true).open().unwrap(),
+            ))
+        };
+        let memory = || -> Arc<dyn KvStore> { Arc::new(MemoryStore::default()) };
+        let balances = |utxo_set: &UTXOSet| {
+            [&address, &miner].map(|a| utxo_set.get_address_balance(a).unwrap())
+        };
+        let backends = [[temporary(), temporary()], [memory(), memory()]];
+        let mut results = Vec::new();
+        for [block_store, utxo_store] in backends {
+            let mut utxo_set = UTXOSet::with_store(
+                Blockchain::with_store(block_store, Network::Mainnet).unwrap(),
+                utxo_store,
+            );
+            for block in &blocks {
+                utxo_set.blockchain.add_block(block.clone()).unwrap();
+                utxo_set.connect_block(block).unwrap();
+            }
+            // 重新打开同一存储时读到最新区块
+            let utxo_set = UTXOSet::with_store(
+                Blockchain::with_store(utxo_set.blockchain.db.clone(), Network::Mainnet).unwrap(),
+                utxo_set.store().unwrap(),
+            );
+            assert_eq!(utxo_set.blockchain.tip, source.blockchain.tip);
+            let connected = (
+                balances(&utxo_set),
+                utxo_set.commitment().unwrap(),
+                snapshot(&utxo_set),
+            );
+            assert_eq!(utxo_set.check_index().unwrap(), connected.2.len());
+
+            let last = blocks.last().unwrap();
+            let undo = utxo_set.get_block_undo(&last.get_hash()).unwrap();
+            utxo_set.disconnect_block(last, &undo).unwrap();
+            results.push((connected, balances(&utxo_set), snapshot(&utxo_set)));
+        }
+        assert_eq!(results[0], results[1]);
+        assert_eq!(results[0].0.0, balances(&source));
+    }
+
+    #[test]
+    fn test_select_coins_strategy() {
+        let candidates = vec![
+            (OutPoint::new("a", 0), 1),
+            (OutPoint::new("b", 0), 5),
+            (OutPoint::new("c", 0), 20),
+        ];
+
+        let (accumulated, selected) =
+            select_coins(candidates.clone(), 6, CoinSelection::LargestFirst).unwrap();
+        assert_eq!(accumulated, 20);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected, vec![OutPoint::new("c", 0)]);
+
+        let (accumulated, selected) =
+            select_coins(candidates.clone(), 6, CoinSelection::SmallestFirst).unwrap();
+        assert_eq!(accumulated, 6);
+        assert_eq!(selected.len(), 2);
+        assert!(
+            selected.contains(&OutPoint::new("a", 0)) && selected.contains(&OutPoint::new("b", 0))
+        );
+
+        let (accumulated, _) = select_coins(candidates, 30, CoinSelection::Accumulate).unwrap();
+        assert_eq!(accumulated, 26);
+
+        // 输出序号按数值排序，而不是按 "txid:n" 的字符串顺序
+        let candidates = vec![
+            (OutPoint::new("b", 0), 5),
+            (OutPoint::new("a", 10), 5),
+            (OutPoint::new("a", 2), 5),
+        ];
+        let (_, selected) =
+            select_coins(candidates.clone(), 10, CoinSelection::Accumulate).unwrap();
+        assert_eq!(
+            selected,
+            vec![OutPoint::new("a", 2), OutPoint::new("a", 10)]
+        );
+        let (_, selected) = select_coins(candidates, 5, CoinSelection::LargestFirst).unwrap();
+        assert_eq!(selected, vec![OutPoint::new("a", 2)]);
+    }
+
+    #[test]
+    fn test_deterministic_selection() {
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let miner = ws.create_wallet();
+        let wallet = ws.get_wallet(&address).unwrap().clone();
+        let pub_key_hash = Address::decode(&address).unwrap().body;
+
+        // 两次从头构造同样的UTXO集合：创世奖励拆成 5 个面额相同的输出
+        let build = || {
+            let bc = Blockchain::in_memory();
+            let mut utxo_set = UTXOSet::in_memory(bc);
+            let cbtx = Transaction::new_coinbase(
+                address.clone(),
+                "genesis".into(),
+                0,
+                0,
+                Network::Mainnet,
+            )
+            .unwrap();
+            let genesis = Block::new_genesis_block(cbtx);
+            utxo_set.blockchain.add_block(genesis.clone()).unwrap();
+            utxo_set.connect_block(&genesis).unwrap();
+            let outputs = vec![(address.clone(), 2); 5];
+            let split =
+                Transaction::new_utxo_multi(&wallet, &outputs, &TxOptions::default(), &utxo_set)
+                    .unwrap();
+            let cbtx =
+                Transaction::new_coinbase(miner.clone(), "block 1".into(), 1, 0, Network::Mainnet)
+                    .unwrap();
+            let block = utxo_set.blockchain.mine_block(vec![cbtx, split]).unwrap();
+            utxo_set.connect_block(&block).unwrap();
+            utxo_set
+        };
+        let first = build();
+        let second = build();
+
+        for strategy in [
+            CoinSelection::LargestFirst,
+            CoinSelection::SmallestFirst,
+            CoinSelection::Accumulate,
+            CoinSelection::BranchAndBound,
+        ] {
+            let selected = first
+                .find_spendable_outputs_with(&pub_key_hash, 5, strategy, 1)
+                .unwrap();
+            assert_eq!(selected.1.len(), 3);
+            assert!(selected.1.is_sorted());
+            assert_eq!(
+                second
+                    .find_spendable_outputs_with(&pub_key_hash, 5, strategy, 1)
+                    .unwrap(),
+                selected
+            );
+
+            let options = TxOptions {
+                strategy,
+                ..TxOptions::default()
+            };
+            let unsigned = |utxo_set: &UTXOSet| {
+                Transaction::build_unsigned(&wallet.public_key, &miner, 5, &options, utxo_set)
+                    .unwrap()
+                    .0
+                    .id
+            };
+            assert_eq!(unsigned(&first), unsigned(&second));
+        }
+    }
+
+    #[test]
+    fn test_select_coins_overflow() {
+        let candidates = vec![
+            (OutPoint::new("a", 0), u64::MAX),
+            (OutPoint::new("b", 0), 1),
+        ];
+        assert!(select_coins(candidates, u64::MAX, CoinSelection::SmallestFirst).is_err());
+
+        let values = vec![u64::MAX, u64::MAX];
+        assert!(branch_and_bound(&values, u64::MAX, 0, BNB_MAX_TRIES).is_some());
+    }
+
+    #[test]
+    fn test_branch_and_bound() {
+        let candidates = vec![
+            (OutPoint::new("a", 0), 8),
+            (OutPoint::new("b", 0), 3),
+            (OutPoint::new("c", 0), 4),
+            (OutPoint::new("d", 0), 2),
+        ];
+
+        let (accumulated, selected) =
+            select_coins(candidates.clone(), 6, CoinSelection::BranchAndBound).unwrap();
+        assert_eq!(accumulated, 6);
+        assert!(
+            selected.contains(&OutPoint::new("c", 0)) && selected.contains(&OutPoint::new("d", 0))
+        );
+
+        // 没有落在容差内的组合时退回累加
+        let candidates = vec![(OutPoint::new("a", 0), 10), (OutPoint::new("b", 0), 20)];
+        let (accumulated, selected) =
+            select_coins(candidates, 15, CoinSelection::BranchAndBound).unwrap();
+        assert_eq!(accumulated, 30);
+        assert_eq!(selected.len(), 2);
+    }
+
+    #[test]
+    fn test_branch_and_bound_try_limit() {
+        // 40 个偶数面额加一个 1，凑 41 只能选 1 加 20 个 2，需要回溯很多次
+        let mut values = vec![2; 40];
+        values.push(1);
+        let found = branch_and_bound(&values, 41, 0, BNB_MAX_TRIES).unwrap();
+        assert_eq!(found.len(), 21);
+        assert!(branch_and_bound(&values, 41, 0, 50).is_none());
+
+        let values = vec![10; 60];
+        assert!(branch_and_bound(&values, 55, 0, BNB_MAX_TRIES).is_none());
     }
 }
@@ -1,19 +1,55 @@
 //! bitcoin wallet
 
 use super::*;
+use crate::base58;
+use crate::bech32;
+use crate::bip39;
+use crate::signer;
+use crate::keystore::{self, WalletKey};
+use crate::transaction::{DUST_LIMIT, OutPoint, SigHashType, Transaction, TxOptions};
+use crate::utxoset::UTXOSet;
+use crate::walletstorage::{FileStorage, StoredWallets, WalletStorage};
 use bincode::{deserialize, serialize};
-use bitcoincash_addr::*;
+use crate::network::Network;
+use bitcoincash_addr::{Address, Scheme};
 use crypto::digest::Digest;
-use crypto::ed25519;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
 use crypto::ripemd160::Ripemd160;
-use crypto::sha2::Sha256;
+use crypto::sha2::{Sha256, Sha512};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use failure::format_err;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
-use sled;
-use std::collections::HashMap;
-use log::info;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{info, warn};
 use rand::rngs::OsRng;
 
+/// SLIP-0010 ed25519 主密钥的 HMAC 密钥
+const SLIP10_ED25519_KEY: &[u8] = b"ed25519 seed";
+/// ed25519 只支持强化派生，序号总是带上该位
+const HARDENED: u32 = 0x8000_0000;
+/// 新种子使用 32 字节熵，对应 24 个助记词
+const MNEMONIC_ENTROPY_LEN: usize = 32;
+/// 恢复种子时，连续这么多个地址没有余额即停止扫描
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+/// 地址签名消息的前缀
+const MESSAGE_PREFIX: &[u8] = b"Rustchain Signed Message:\n";
+const PUBLIC_KEY_LEN: usize = 32;
+/// ed25519 私钥种子长度，钱包保存的私钥为种子加公钥
+const PRIVATE_KEY_LEN: usize = 32;
+/// 地址携带的公钥哈希长度（RIPEMD-160）
+const PUB_KEY_HASH_LEN: usize = 20;
+/// 消息签名由公钥和 ed25519 签名组成
+const MESSAGE_SIGNATURE_LEN: usize = PUBLIC_KEY_LEN + 64;
+/// 搜索靓号地址时输出进度的间隔
+const VANITY_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Wallet {
     pub secret_key: Vec<u8>,
@@ -25,29 +61,290 @@ impl Wallet {
     /// NewWallet 创建并返回钱包
     fn new() -> Self {
         let mut key: [u8; 32] = [0; 32];
-        let mut rand = OsRng::default();
+        let mut rand = OsRng;
         rand.fill_bytes(&mut key);
-        let (secret_key, public_key) = ed25519::keypair(&key);
-        let secret_key = secret_key.to_vec();
-        let public_key = public_key.to_vec();
+        Wallet::from_private_key(&key)
+    }
+
+    /// FromPrivateKey 由 32 字节 ed25519 私钥创建钱包
+    fn from_private_key(key: &[u8; 32]) -> Self {
+        let signing_key = SigningKey::from_bytes(key);
+        // 私钥保存为 32 字节种子加 32 字节公钥，与旧版 rust-crypto 生成的钱包格式相同
+        let secret_key = signing_key.to_keypair_bytes().to_vec();
+        let public_key = signing_key.verifying_key().to_bytes().to_vec();
         Wallet {
             secret_key,
             public_key,
         }
     }
 
-    /// GetAddress 返回钱包地址
-    pub fn get_address(&self) -> String {
+    /// GetAddress 返回钱包在 network 上的地址，各网络的版本字节不同
+    pub fn get_address(&self, network: Network) -> String {
+        address_from_pub_key_hash(&self.pub_key_hash(), network)
+    }
+
+    /// GetAddressBech32 返回钱包的 bech32 地址，与 Base58 地址对应同一个公钥哈希
+    pub fn get_address_bech32(&self, network: Network) -> String {
+        bech32::encode(network.bech32_hrp(), &self.pub_key_hash())
+    }
+
+    /// ExportWif 以 Base58Check 导出私钥，版本字节区分网络
+    pub fn export_wif(&self, network: Network) -> String {
+        let mut payload = vec![network.wif_version()];
+        payload.extend_from_slice(&self.secret_key[..PRIVATE_KEY_LEN]);
+        base58::encode_check(&payload)
+    }
+
+    /// FromWif 解码 export_wif 导出的私钥并重建钱包
+    fn from_wif(wif: &str, network: Network) -> Result<Wallet> {
+        let payload =
+            base58::decode_check(wif).map_err(|err| format_err!("Invalid WIF key: {}", err))?;
+        let Some((version, key)) = payload.split_first() else {
+            return Err(format_err!("Invalid WIF key: empty payload"));
+        };
+        if *version != network.wif_version() {
+            return Err(format_err!(
+                "Invalid WIF key: version 0x{:02x} does not belong to {}",
+                version,
+                network
+            ));
+        }
+        let key: &[u8; PRIVATE_KEY_LEN] = key.try_into().map_err(|_| {
+            format_err!(
+                "Invalid WIF key: expected a {}-byte key, got {}",
+                PRIVATE_KEY_LEN,
+                key.len()
+            )
+        })?;
+        Ok(Wallet::from_private_key(key))
+    }
+
+    /// ToKeystoreJson 用口令把私钥加密为 Web3 Secret Storage 格式的 JSON 密钥文件
+    pub fn to_keystore_json(&self, passphrase: &str, network: Network) -> Result<String> {
+        keystore::encrypt_keystore_json(
+            &self.secret_key[..PRIVATE_KEY_LEN],
+            &self.get_address(network),
+            passphrase,
+        )
+    }
+
+    /// FromKeystoreJson 用口令解密 JSON 密钥文件并重建钱包，口令错误时返回错误
+    ///
+    /// 文件中记录了 network 上的地址时，检查它与解出的私钥一致
+    pub fn from_keystore_json(json: &str, passphrase: &str, network: Network) -> Result<Wallet> {
+        let (secret, address) = keystore::decrypt_keystore_json(json, passphrase)?;
+        let key: &[u8; PRIVATE_KEY_LEN] = secret.as_slice().try_into().map_err(|_| {
+            format_err!(
+                "Invalid keystore file: expected a {}-byte key, got {}",
+                PRIVATE_KEY_LEN,
+                secret.len()
+            )
+        })?;
+        let wallet = Wallet::from_private_key(key);
+        if let Some(address) = address
+            && validate_address(&address, network).is_ok()
+            && address != wallet.get_address(network)
+        {
+            return Err(format_err!(
+                "Keystore file is for {} but holds the key of {}",
+                address,
+                wallet.get_address(network)
+            ));
+        }
+        Ok(wallet)
+    }
+
+    fn pub_key_hash(&self) -> Vec<u8> {
         let mut pub_hash: Vec<u8> = self.public_key.clone();
         hash_pub_key(&mut pub_hash);
-        let address = Address {
-            body: pub_hash,
-            scheme: Scheme::Base58,
-            hash_type: HashType::Script,
-            ..Default::default()
-        };
-        address.encode().unwrap()
+        pub_hash
+    }
+
+    /// SignTransaction 用钱包私钥按 sighash 签名交易，prev_txs 由 Blockchain::get_prev_txs 查找
+    ///
+    /// 私钥只在钱包中使用，区块链只负责查找前序交易
+    pub fn sign_transaction(
+        &self,
+        tx: &mut Transaction,
+        prev_txs: HashMap<String, Transaction>,
+        sighash: SigHashType,
+    ) -> Result<()> {
+        tx.sign(self, prev_txs, sighash)
+    }
+
+    /// SignMessage 对任意消息签名以证明持有该地址，返回公钥加签名
+    ///
+    /// 签名的是加了前缀的消息哈希，不会与交易签名摘要混淆
+    pub fn sign_message(&self, msg: &[u8]) -> Vec<u8> {
+        let signature = sign_message(&self.secret_key, &message_digest(msg))
+            .expect("wallet holds a valid secret key");
+        let mut blob = self.public_key.clone();
+        blob.extend_from_slice(&signature);
+        blob
+    }
+}
+
+impl signer::Signer for Wallet {
+    fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    fn sign(&self, msg: &[u8]) -> Result<Vec<u8>> {
+        sign_message(&self.secret_key, msg)
+    }
+}
+
+/// MessageDigest 返回地址签名消息的摘要
+fn message_digest(msg: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(MESSAGE_PREFIX);
+    hasher.input(msg);
+    let mut digest = vec![0u8; 32];
+    hasher.result(&mut digest);
+    digest
+}
+
+/// VerifyMessage 验证 Wallet::sign_message 生成的签名是否由 address 的持有者签出
+///
+/// 地址或签名格式错误时返回错误，公钥与地址不符或签名无效时返回 false
+pub fn verify_message(
+    address: &str,
+    msg: &[u8],
+    signature: &[u8],
+    network: Network,
+) -> Result<bool> {
+    let pub_key_hash = decode_address(address, network)?;
+    if signature.len() != MESSAGE_SIGNATURE_LEN {
+        return Err(format_err!(
+            "Invalid message signature length {}, expected {}",
+            signature.len(),
+            MESSAGE_SIGNATURE_LEN
+        ));
+    }
+    let (public_key, signature) = signature.split_at(PUBLIC_KEY_LEN);
+    let mut key_hash = public_key.to_vec();
+    hash_pub_key(&mut key_hash);
+    Ok(key_hash == pub_key_hash && verify_signature(public_key, &message_digest(msg), signature))
+}
+
+/// Lookup 按地址查找记录，记录以 Base58 地址为键，其他格式的地址先转换
+fn lookup<'a, T>(map: &'a HashMap<String, T>, address: &str, network: Network) -> Option<&'a T> {
+    map.get(address).or_else(|| {
+        let pub_key_hash = decode_address(address, network).ok()?;
+        map.get(&address_from_pub_key_hash(&pub_key_hash, network))
+    })
+}
+
+/// DecodeAddress 解码 network 上的 Base58 或 bech32 地址并返回公钥哈希
+///
+/// 校验和错误、长度不符或属于其他网络时返回错误
+pub fn decode_address(address: &str, network: Network) -> Result<Vec<u8>> {
+    let (body, address_network) = if let Some(hrp_network) = bech32_network(address) {
+        let (_, body) = bech32::decode(address)
+            .map_err(|err| format_err!("Invalid address {}: {}", address, err))?;
+        (body, hrp_network)
+    } else {
+        let decoded = Address::decode(address)
+            .map_err(|(_, err)| format_err!("Invalid address {}: {}", address, err))?;
+        let address_network = Network::from_base58_params(&decoded.network, &decoded.hash_type)
+            .ok_or_else(|| format_err!("Invalid address {}: unknown address version", address))?;
+        (decoded.body, address_network)
+    };
+    if body.len() != PUB_KEY_HASH_LEN {
+        return Err(format_err!(
+            "Invalid address {}: expected a {}-byte hash, got {}",
+            address,
+            PUB_KEY_HASH_LEN,
+            body.len()
+        ));
+    }
+    if address_network != network {
+        return Err(format_err!(
+            "Invalid address {}: it belongs to {}, not {}",
+            address,
+            address_network,
+            network
+        ));
+    }
+    Ok(body)
+}
+
+/// Bech32Network 按前缀判断地址是否为 bech32 格式并返回其网络，Base58 地址不会以这些前缀开头
+fn bech32_network(address: &str) -> Option<Network> {
+    let lower = address.to_ascii_lowercase();
+    Network::all()
+        .into_iter()
+        .find(|network| lower.starts_with(&format!("{}1", network.bech32_hrp())))
+}
+
+/// CheckVanityPrefix 拒绝含有 Base58 字母表以外字符的前缀，以及 network 上的地址不可能具有的前缀
+///
+/// network 上的全部地址位于全零和全 0xff 公钥哈希对应的地址之间，
+/// 前缀补齐后与这两个地址比较即可判断；忽略大小写时只检查字母表
+fn check_vanity_prefix(prefix: &str, ignore_case: bool, network: Network) -> Result<()> {
+    if prefix.is_empty() {
+        return Err(format_err!("Vanity prefix must not be empty"));
+    }
+    let digits =
+        |s: &str| -> Option<Vec<usize>> { s.chars().map(|c| base58::ALPHABET.find(c)).collect() };
+    // 忽略大小写时，字符的大写或小写形式之一在字母表中即可
+    let in_alphabet = |c: char| {
+        base58::ALPHABET.contains(c)
+            || (ignore_case
+                && (base58::ALPHABET.contains(c.to_ascii_uppercase())
+                    || base58::ALPHABET.contains(c.to_ascii_lowercase())))
+    };
+    if !prefix.chars().all(in_alphabet) {
+        return Err(format_err!(
+            "Invalid vanity prefix {}: only Base58 characters are allowed ({})",
+            prefix,
+            base58::ALPHABET
+        ));
+    }
+    if ignore_case {
+        return Ok(());
+    }
+    let wanted = digits(prefix).unwrap();
+
+    let min = digits(&address_from_pub_key_hash(&[0; PUB_KEY_HASH_LEN], network)).unwrap();
+    let max = digits(&address_from_pub_key_hash(
+        &[0xff; PUB_KEY_HASH_LEN],
+        network,
+    ))
+    .unwrap();
+    if min.len() != max.len() {
+        return Ok(());
+    }
+    let padded = |pad: usize| {
+        let mut padded = wanted.clone();
+        padded.resize(min.len(), pad);
+        padded
+    };
+    if wanted.len() > min.len() || padded(base58::ALPHABET.len() - 1) < min || padded(0) > max {
+        return Err(format_err!(
+            "Invalid vanity prefix {}: no {} address starts with it",
+            prefix,
+            network
+        ));
     }
+    Ok(())
+}
+
+/// ValidateAddress 检查地址能否作为收款地址，命令行在选币之前调用
+pub fn validate_address(address: &str, network: Network) -> Result<()> {
+    decode_address(address, network).map(|_| ())
+}
+
+/// AddressFromPubKeyHash 将公钥哈希编码为 network 上的 Base58 地址
+pub fn address_from_pub_key_hash(pub_key_hash: &[u8], network: Network) -> String {
+    let (network, hash_type) = network.base58_params();
+    let address = Address {
+        body: pub_key_hash.to_vec(),
+        scheme: Scheme::Base58,
+        hash_type,
+        network,
+    };
+    address.encode().unwrap()
 }
 
 /// Hashpubkey 哈希化公钥
@@ -61,62 +358,868 @@ pub fn hash_pub_key(pubkey: &mut Vec<u8>) {
     hasher2.result(pubkey);
 }
 
+/// SignMessage 使用钱包私钥对消息进行 ed25519 签名
+pub fn sign_message(secret_key: &[u8], message: &[u8]) -> Result<Vec<u8>> {
+    Ok(signing_key(secret_key)?.sign(message).to_bytes().to_vec())
+}
+
+fn signing_key(secret_key: &[u8]) -> Result<SigningKey> {
+    let keypair: &[u8; 64] = secret_key
+        .try_into()
+        .map_err(|_| format_err!("Invalid secret key length {}", secret_key.len()))?;
+    SigningKey::from_keypair_bytes(keypair).map_err(|e| format_err!("Invalid secret key: {}", e))
+}
+
+/// VerifySignature 验证 ed25519 签名，公钥或签名格式错误时返回 false
+pub fn verify_signature(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let (Ok(public_key), Ok(signature)) = (
+        <&[u8; 32]>::try_from(public_key),
+        <&[u8; 64]>::try_from(signature),
+    ) else {
+        return false;
+    };
+    match VerifyingKey::from_bytes(public_key) {
+        Ok(key) => key
+            .verify(message, &Signature::from_bytes(signature))
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// HdSeed 分层确定性钱包的主种子，以及下一个未分配的派生序号
+///
+/// 派生按 SLIP-0010 的 ed25519 方案进行，序号 i 对应路径 m/i'
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HdSeed {
+    pub seed: Vec<u8>,
+    pub next_index: u32,
+}
+
+impl HdSeed {
+    /// NewHdSeed 校验种子长度并创建序号从 0 开始的 HdSeed
+    pub fn new(seed: Vec<u8>) -> Result<HdSeed> {
+        if !(16..=64).contains(&seed.len()) {
+            return Err(format_err!(
+                "Invalid HD seed length {}, expected 16 to 64 bytes",
+                seed.len()
+            ));
+        }
+        Ok(HdSeed {
+            seed,
+            next_index: 0,
+        })
+    }
+
+    /// Derive 派生序号为 index 的钱包，同一种子和序号总是得到同一个钱包
+    ///
+    /// index 必须小于 2^31
+    pub fn derive(&self, index: u32) -> Wallet {
+        let (master_key, chain_code) = hmac_sha512(SLIP10_ED25519_KEY, &self.seed);
+        let mut data = vec![0u8];
+        data.extend_from_slice(&master_key);
+        data.extend_from_slice(&(index | HARDENED).to_be_bytes());
+        let (key, _) = hmac_sha512(&chain_code, &data);
+        Wallet::from_private_key(&key)
+    }
+}
+
+/// HmacSha512 计算 HMAC-SHA512，返回前后两半
+fn hmac_sha512(key: &[u8], data: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut mac = Hmac::new(Sha512::new(), key);
+    mac.input(data);
+    let code = mac.result();
+    let (left, right) = code.code().split_at(32);
+    (left.try_into().unwrap(), right.try_into().unwrap())
+}
+
+/// WatchOnly 只观察余额、不持有私钥的地址
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WatchOnly {
+    pub label: Option<String>,
+}
+
+/// AddressBalance 钱包中一个地址的余额
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AddressBalance {
+    pub address: String,
+    pub label: Option<String>,
+    pub watch_only: bool,
+    /// 可以花费的余额
+    pub spendable: u64,
+    /// 尚未成熟的创币交易奖励
+    pub immature: u64,
+    /// 已冻结、不参与自动选币的余额
+    pub frozen: u64,
+}
+
+/// WalletSecrets 加密的钱包文件中被加密的部分，只观察地址不含私钥，不加密
+#[derive(Serialize, Deserialize)]
+struct WalletSecrets {
+    wallets: HashMap<String, Wallet>,
+    hd: Option<HdSeed>,
+    mnemonic_entropy: Option<Vec<u8>>,
+}
+
 pub struct Wallets {
     wallets: HashMap<String, Wallet>,
+    watch_only: HashMap<String, WatchOnly>,
+    /// 自有地址的标签，只观察地址的标签保存在 WatchOnly 中
+    labels: HashMap<String, String>,
+    /// 通讯录，联系人名称到地址
+    contacts: HashMap<String, String>,
+    hd: Option<HdSeed>,
+    /// 生成 hd 种子的助记词熵，种子直接导入时为 None
+    mnemonic_entropy: Option<Vec<u8>>,
+    /// 钱包文件加密时为解锁得到的密钥，保存时用它重新加密
+    key: Option<WalletKey>,
+    /// 刚刚加密或更换了口令，保存时需要重写整个钱包文件
+    rewrite: bool,
+    /// 保存钱包集合的存储后端
+    storage: Box<dyn WalletStorage>,
+    /// 由 open_for_write 打开时持有的存储锁
+    lock: Option<Box<dyn Send>>,
+    /// 钱包地址所属的网络
+    network: Network,
 }
 /// Wallets 钱包集合
 impl Wallets {
-    /// NewWallets 创建并加载钱包文件
-    pub fn new() -> Result<Wallets> {
-        let mut wlt = Wallets {
-            wallets: HashMap::<String, Wallet>::new(),
-        };
-        let db = sled::open("data/wallets")?;
+    /// NewWallets 创建并加载数据目录 data_dir 中 network 的钱包文件，钱包文件已加密时返回错误，需改用 unlock
+    pub fn new(network: Network, data_dir: &Path) -> Result<Wallets> {
+        Wallets::open(
+            network,
+            Box::new(FileStorage::in_data_dir(network, data_dir)),
+            None,
+            false,
+        )
+    }
+
+    /// Unlock 用口令解密并加载加密的钱包文件，口令错误时返回错误
+    pub fn unlock(network: Network, data_dir: &Path, passphrase: &str) -> Result<Wallets> {
+        Wallets::open(
+            network,
+            Box::new(FileStorage::in_data_dir(network, data_dir)),
+            Some(passphrase),
+            false,
+        )
+    }
 
-        for item in db.into_iter() {
-            let i = item?;
-            let address = String::from_utf8(i.0.to_vec())?;
-            let wallet = deserialize(&i.1.to_vec())?;
-            wlt.wallets.insert(address, wallet);
+    /// OpenForWrite 锁定并加载钱包文件，直到返回的 Wallets 被丢弃前其他进程都不能读写钱包文件
+    ///
+    /// 先加载、修改再保存的命令应使用它，否则同时运行的另一个命令可能覆盖本次的修改；
+    /// 钱包文件已加密时需要提供口令
+    pub fn open_for_write(
+        network: Network,
+        data_dir: &Path,
+        passphrase: Option<&str>,
+    ) -> Result<Wallets> {
+        Wallets::open(
+            network,
+            Box::new(FileStorage::in_data_dir(network, data_dir)),
+            passphrase,
+            true,
+        )
+    }
+
+    /// Open 从存储后端加载 network 的钱包集合，之后 save_all 保存回同一后端
+    ///
+    /// keep_lock 为 true 时一直持有存储锁，直到返回的 Wallets 被丢弃
+    pub fn open(
+        network: Network,
+        storage: Box<dyn WalletStorage>,
+        passphrase: Option<&str>,
+        keep_lock: bool,
+    ) -> Result<Wallets> {
+        let lock = storage.lock()?;
+        let stored = storage.load()?;
+        let mut wlt = Wallets::from_stored(network, storage, stored, passphrase)?;
+        if keep_lock {
+            wlt.lock = Some(lock);
+        }
+        Ok(wlt)
+    }
+
+    /// InMemory 打开内存存储中的主网钱包集合，供测试使用，同一存储的各个句柄共享内容
+    #[cfg(test)]
+    pub fn in_memory(storage: &crate::walletstorage::memory::MemoryStorage) -> Wallets {
+        Wallets::open(Network::Mainnet, Box::new(storage.clone()), None, false).unwrap()
+    }
+
+    /// IsFileEncrypted 检查 network 的钱包文件是否已加密
+    pub fn is_file_encrypted(network: Network, data_dir: &Path) -> Result<bool> {
+        let storage = FileStorage::in_data_dir(network, data_dir);
+        let _lock = storage.lock()?;
+        Ok(storage.load()?.encrypted.is_some())
+    }
+
+    fn from_stored(
+        network: Network,
+        storage: Box<dyn WalletStorage>,
+        stored: StoredWallets,
+        passphrase: Option<&str>,
+    ) -> Result<Wallets> {
+        let mut wlt = Wallets::empty(network, storage);
+        wlt.watch_only = stored.watch_only;
+        wlt.labels = stored.labels;
+        wlt.contacts = stored.contacts;
+        match (stored.encrypted, passphrase) {
+            (Some(blob), Some(passphrase)) => {
+                let (plaintext, key) = blob.open(passphrase)?;
+                let secrets: WalletSecrets = deserialize(&plaintext)?;
+                wlt.wallets = secrets.wallets;
+                wlt.hd = secrets.hd;
+                wlt.mnemonic_entropy = secrets.mnemonic_entropy;
+                wlt.key = Some(key);
+            }
+            (Some(_), None) => {
+                return Err(format_err!(
+                    "Wallet file is encrypted, a passphrase is required to unlock it"
+                ));
+            }
+            (None, Some(_)) => return Err(format_err!("Wallet file is not encrypted")),
+            (None, None) => {
+                wlt.wallets = stored.wallets;
+                wlt.hd = stored.hd;
+                wlt.mnemonic_entropy = stored.mnemonic_entropy;
+            }
         }
-        drop(db);
         Ok(wlt)
     }
 
+    /// Encrypt 设置口令，此后 save_all 把私钥和种子加密保存，并清除明文
+    ///
+    /// 未加密的旧钱包文件用它升级，已加密时返回错误
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<()> {
+        if self.key.is_some() {
+            return Err(format_err!(
+                "Wallet file is already encrypted, change the passphrase instead"
+            ));
+        }
+        self.key = Some(WalletKey::derive(passphrase)?);
+        self.rewrite = true;
+        Ok(())
+    }
+
+    /// ChangePassphrase 更换口令，下次 save_all 时用新口令重新加密
+    pub fn change_passphrase(&mut self, passphrase: &str) -> Result<()> {
+        if self.key.is_none() {
+            return Err(format_err!("Wallet file is not encrypted"));
+        }
+        self.key = Some(WalletKey::derive(passphrase)?);
+        self.rewrite = true;
+        Ok(())
+    }
+
+    /// ReplaceFile 使下次 save_all 用本钱包集合重写整个钱包文件，丢弃文件中原有的内容
+    pub fn replace_file(&mut self) {
+        self.rewrite = true;
+    }
+
+    /// FromMnemonic 由 BIP-39 助记词和口令重建分层确定性钱包，尚未包含任何地址，之后 save_all 保存到 storage
+    ///
+    /// 同一助记词和口令总是得到同一组钱包，口令不会保存
+    pub fn from_mnemonic(
+        network: Network,
+        storage: Box<dyn WalletStorage>,
+        phrase: &str,
+        passphrase: &str,
+    ) -> Result<Wallets> {
+        let entropy = bip39::mnemonic_to_entropy(phrase)?;
+        let mut wlt = Wallets::empty(network, storage);
+        wlt.hd = Some(HdSeed::new(bip39::mnemonic_to_seed(phrase, passphrase))?);
+        wlt.mnemonic_entropy = Some(entropy);
+        Ok(wlt)
+    }
+
+    fn empty(network: Network, storage: Box<dyn WalletStorage>) -> Wallets {
+        Wallets {
+            wallets: HashMap::<String, Wallet>::new(),
+            watch_only: HashMap::new(),
+            labels: HashMap::new(),
+            contacts: HashMap::new(),
+            hd: None,
+            mnemonic_entropy: None,
+            key: None,
+            rewrite: false,
+            storage,
+            lock: None,
+            network,
+        }
+    }
+
+    /// IsEmpty 检查钱包文件中是否没有任何钱包、只观察地址和种子
+    pub fn is_empty(&self) -> bool {
+        self.wallets.is_empty()
+            && self.watch_only.is_empty()
+            && self.contacts.is_empty()
+            && self.hd.is_none()
+    }
+
     /// CreateWallet 添加新钱包到集合
+    ///
+    /// 启用了分层确定性种子时按序号派生下一个钱包，否则随机生成
     pub fn create_wallet(&mut self) -> String {
-        let wallet = Wallet::new();
-        let address = wallet.get_address();
+        let wallet = match &mut self.hd {
+            Some(hd) => {
+                let wallet = hd.derive(hd.next_index);
+                hd.next_index += 1;
+                wallet
+            }
+            None => Wallet::new(),
+        };
+        let address = wallet.get_address(self.network);
         self.wallets.insert(address.clone(), wallet);
         info!("create wallet: {}", address);
         address
     }
 
-    /// GetAllAddresses 返回所有钱包地址
-    pub fn get_all_addresses(&self) -> Vec<String> {
+    /// CreateChangeAddress 生成新的找零钱包并立即保存，返回其地址
+    pub fn create_change_address(&mut self) -> Result<String> {
+        let address = self.create_wallet();
+        self.save_all()?;
+        Ok(address)
+    }
+
+    /// CreateHdSeed 生成新的分层确定性种子，此后 create_wallet 都从种子派生，返回种子以便备份
+    ///
+    /// 种子由随机的 24 个助记词在空口令下生成，可以用 export_mnemonic 导出。
+    /// 已有的随机钱包保持不变，已经有种子时返回错误
+    pub fn create_hd_seed(&mut self) -> Result<Vec<u8>> {
+        let mut entropy = vec![0u8; MNEMONIC_ENTROPY_LEN];
+        OsRng.fill_bytes(&mut entropy);
+        let phrase = bip39::entropy_to_mnemonic(&entropy)?;
+        let seed = bip39::mnemonic_to_seed(&phrase, "");
+        self.set_hd_seed(HdSeed::new(seed.clone())?)?;
+        self.mnemonic_entropy = Some(entropy);
+        Ok(seed)
+    }
+
+    /// ExportMnemonic 返回生成分层确定性种子的助记词
+    ///
+    /// 种子若是用口令恢复的，恢复时仍需提供同一口令
+    pub fn export_mnemonic(&self) -> Result<String> {
+        match (&self.hd, &self.mnemonic_entropy) {
+            (Some(_), Some(entropy)) => bip39::entropy_to_mnemonic(entropy),
+            (Some(_), None) => Err(format_err!("HD seed was not created from a mnemonic")),
+            (None, _) => Err(format_err!("Wallet file has no HD seed")),
+        }
+    }
+
+    /// HdSeed 返回分层确定性种子，未启用时为 None
+    pub fn hd_seed(&self) -> Option<&HdSeed> {
+        self.hd.as_ref()
+    }
+
+    /// RestoreFromSeed 从种子恢复钱包，扫描 UTXO 集合找回有余额的地址并保存
+    ///
+    /// 连续 gap_limit 个派生地址都没有余额时停止，最后一个有余额的地址及之前的全部地址都会恢复，
+    /// 返回恢复的地址数量
+    pub fn restore_from_seed(
+        &mut self,
+        seed: Vec<u8>,
+        utxo_set: &UTXOSet,
+        gap_limit: u32,
+    ) -> Result<u32> {
+        let used = utxo_set.find_pub_key_hashes()?;
+        let restored = self.recover_from_seed(HdSeed::new(seed)?, &used, gap_limit)?;
+        self.save_all()?;
+        Ok(restored)
+    }
+
+    /// RescanHd 扫描 UTXO 集合，把有余额的派生地址及之前的全部地址加入钱包并保存
+    ///
+    /// 扫描规则与 restore_from_seed 相同，返回恢复的地址数量
+    pub fn rescan_hd(&mut self, utxo_set: &UTXOSet, gap_limit: u32) -> Result<u32> {
+        let used = utxo_set.find_pub_key_hashes()?;
+        let restored = self.scan_hd(&used, gap_limit)?;
+        self.save_all()?;
+        Ok(restored)
+    }
+
+    fn recover_from_seed(
+        &mut self,
+        hd: HdSeed,
+        used: &HashSet<Vec<u8>>,
+        gap_limit: u32,
+    ) -> Result<u32> {
+        self.set_hd_seed(hd)?;
+        self.scan_hd(used, gap_limit)
+    }
+
+    fn scan_hd(&mut self, used: &HashSet<Vec<u8>>, gap_limit: u32) -> Result<u32> {
+        let hd = self
+            .hd
+            .as_mut()
+            .ok_or_else(|| format_err!("Wallet file has no HD seed"))?;
+        let mut gap = 0;
+        let mut index = 0;
+        while gap < gap_limit && index < HARDENED {
+            if used.contains(&hd.derive(index).pub_key_hash()) {
+                hd.next_index = hd.next_index.max(index + 1);
+                gap = 0;
+            } else {
+                gap += 1;
+            }
+            index += 1;
+        }
+        let restored = hd.next_index;
+        for index in 0..restored {
+            let wallet = hd.derive(index);
+            self.wallets
+                .insert(wallet.get_address(self.network), wallet);
+        }
+        info!("restored {} addresses from the HD seed", restored);
+        Ok(restored)
+    }
+
+    fn set_hd_seed(&mut self, hd: HdSeed) -> Result<()> {
+        if self.hd.is_some() {
+            return Err(format_err!("Wallet file already has an HD seed"));
+        }
+        self.hd = Some(hd);
+        Ok(())
+    }
+
+    /// CreateVanityWallet 生成 Base58 地址以 prefix 开头的钱包并保存，返回其地址
+    ///
+    /// 前缀包含网络版本字节决定的首字符，timeout 内未找到时返回错误。
+    /// 靓号钱包总是随机生成，不能从分层确定性种子恢复
+    pub fn create_vanity_wallet(&mut self, prefix: &str, timeout: Duration) -> Result<String> {
+        self.vanity_search(prefix, false, timeout)
+    }
+
+    /// CreateVanityWalletIgnoreCase 与 create_vanity_wallet 相同，但匹配前缀时忽略大小写
+    pub fn create_vanity_wallet_ignore_case(
+        &mut self,
+        prefix: &str,
+        timeout: Duration,
+    ) -> Result<String> {
+        self.vanity_search(prefix, true, timeout)
+    }
+
+    fn vanity_search(
+        &mut self,
+        prefix: &str,
+        ignore_case: bool,
+        timeout: Duration,
+    ) -> Result<String> {
+        check_vanity_prefix(prefix, ignore_case, self.network)?;
+        let matches = |address: &str| {
+            address.get(..prefix.len()).is_some_and(|head| {
+                if ignore_case {
+                    head.eq_ignore_ascii_case(prefix)
+                } else {
+                    head == prefix
+                }
+            })
+        };
+
+        let workers = thread::available_parallelism().map_or(1, |n| n.get());
+        let stop = AtomicBool::new(false);
+        let attempts = AtomicU64::new(0);
+        let found: Mutex<Option<Wallet>> = Mutex::new(None);
+        let start = Instant::now();
+        thread::scope(|s| {
+            for _ in 0..workers {
+                s.spawn(|| {
+                    while !stop.load(Ordering::Relaxed) {
+                        let wallet = Wallet::new();
+                        attempts.fetch_add(1, Ordering::Relaxed);
+                        if matches(&wallet.get_address(self.network)) {
+                            found.lock().unwrap().get_or_insert(wallet);
+                            stop.store(true, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+
+            // 主线程负责输出进度和超时
+            let mut last_report = start;
+            while !stop.load(Ordering::Relaxed) {
+                let elapsed = start.elapsed();
+                if elapsed >= timeout {
+                    stop.store(true, Ordering::Relaxed);
+                    break;
+                }
+                if last_report.elapsed() >= VANITY_PROGRESS_INTERVAL {
+                    let tried = attempts.load(Ordering::Relaxed);
+                    info!(
+                        "vanity search for {}: {} attempts, {:.0} attempts/s",
+                        prefix,
+                        tried,
+                        tried as f64 / elapsed.as_secs_f64()
+                    );
+                    last_report = Instant::now();
+                }
+                thread::sleep(Duration::from_millis(10).min(timeout - elapsed));
+            }
+        });
+
+        let tried = attempts.load(Ordering::Relaxed);
+        let wallet = found.into_inner().unwrap().ok_or_else(|| {
+            format_err!(
+                "No address starting with {} found within {:?} ({} attempts)",
+                prefix,
+                timeout,
+                tried
+            )
+        })?;
+        let address = wallet.get_address(self.network);
+        info!(
+            "found vanity address {} after {} attempts in {:?}",
+            address,
+            tried,
+            start.elapsed()
+        );
+        self.wallets.insert(address.clone(), wallet);
+        self.save_all()?;
+        Ok(address)
+    }
+
+    /// NewUTXOWithFreshChange 从 from 创建交易，找零发往新生成的地址
+    ///
+    /// inputs 为空时选币，否则只花费指定的输出。
+    /// 找零钱包在签名之前就写入钱包文件，进程中途退出也不会丢失找零
+    pub fn new_utxo_with_fresh_change(
+        &mut self,
+        from: &str,
+        inputs: &[OutPoint],
+        to: &str,
+        amount: u64,
+        options: &TxOptions,
+        utxo: &UTXOSet,
+    ) -> Result<Transaction> {
+        self.get_spending_wallet(from)?;
+        let change_address = self.create_change_address()?;
+        info!("send change to new address: {}", change_address);
+
+        let options = TxOptions {
+            change_address: Some(change_address),
+            ..options.clone()
+        };
+        let wallet = self.get_wallet(from).unwrap();
+        if inputs.is_empty() {
+            Transaction::new_utxo(wallet, to, amount, &options, utxo)
+        } else {
+            Transaction::new_utxo_from_inputs(wallet, inputs, to, amount, &options, utxo)
+        }
+    }
+
+    /// BumpFee 把钱包发出的未确认交易 tx 的手续费提高到 fee，返回重新签名的替换交易
+    ///
+    /// 输入保持不变，差额从付给钱包地址的最后一个输出中扣除，扣除后不能低于 DUST_LIMIT。
+    /// tx 的输入须全部由同一个可花费的钱包签名，前序交易都在链上
+    pub fn bump_fee(&self, tx: &Transaction, fee: u64, utxo: &UTXOSet) -> Result<Transaction> {
+        if tx.is_coinbase() {
+            return Err(format_err!("Cannot bump the fee of coinbase {}", tx.id));
+        }
+        let pub_key = &tx.vin[0].pub_key;
+        if tx
+            .vin
+            .iter()
+            .any(|vin| vin.condition.is_some() || vin.pub_key != *pub_key)
+        {
+            return Err(format_err!(
+                "Transaction {} is not signed by a single wallet",
+                tx.id
+            ));
+        }
+        let mut pub_key_hash = pub_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        let wallet =
+            self.get_spending_wallet(&address_from_pub_key_hash(&pub_key_hash, self.network))?;
+
+        let prev_txs = utxo.blockchain.get_prev_txs(tx)?;
+        let old_fee = tx.fee(&prev_txs)?;
+        if fee <= old_fee {
+            return Err(format_err!(
+                "New fee {} must be higher than the current fee {}",
+                fee,
+                old_fee
+            ));
+        }
+        let change = tx
+            .vout
+            .iter()
+            .rposition(|out| {
+                !out.is_data()
+                    && self
+                        .get_wallet(&address_from_pub_key_hash(&out.pub_key_hash, self.network))
+                        .is_some()
+            })
+            .ok_or_else(|| {
+                format_err!(
+                    "Transaction {} has no output to the wallet to pay the fee from",
+                    tx.id
+                )
+            })?;
+        let mut bumped = tx.clone();
+        bumped.vout[change].value = tx.vout[change]
+            .value
+            .checked_sub(fee - old_fee)
+            .filter(|value| *value >= DUST_LIMIT)
+            .ok_or_else(|| {
+                format_err!(
+                    "Output {} of {} is too small to pay {} more in fees",
+                    change,
+                    tx.id,
+                    fee - old_fee
+                )
+            })?;
+        bumped.id = bumped.compute_id();
+        wallet.sign_transaction(&mut bumped, prev_txs, SigHashType::All)?;
+        info!("bump the fee of {} to {}: {}", tx.id, fee, bumped.id);
+        Ok(bumped)
+    }
+
+    /// AddWatchOnly 添加只观察的地址，可以查询余额但不能花费
+    pub fn add_watch_only(&mut self, address: &str, label: Option<String>) -> Result<()> {
+        let address =
+            address_from_pub_key_hash(&decode_address(address, self.network)?, self.network);
+        if self.wallets.contains_key(&address) {
+            return Err(format_err!("Address {} already has a wallet", address));
+        }
+        info!("watch address: {}", address);
+        self.watch_only.insert(address, WatchOnly { label });
+        Ok(())
+    }
+
+    /// GetAllAddresses 返回所有钱包地址，include_watch_only 为 true 时包括只观察的地址
+    pub fn get_all_addresses(&self, include_watch_only: bool) -> Vec<String> {
         let mut addresses = Vec::<String>::new();
-        for (address, _) in &self.wallets {
+        for address in self.wallets.keys() {
             addresses.push(address.clone());
         }
+        if include_watch_only {
+            addresses.extend(self.watch_only.keys().cloned());
+        }
         addresses
     }
 
-    /// GetWallet 通过地址获取钱包
+    /// GetAllAddressesLabeled 与 get_all_addresses 相同，但同时返回每个地址的标签和是否只观察
+    pub fn get_all_addresses_labeled(
+        &self,
+        include_watch_only: bool,
+    ) -> Vec<(String, Option<String>, bool)> {
+        self.get_all_addresses(include_watch_only)
+            .into_iter()
+            .map(|address| {
+                let label = self.get_label(&address).map(String::from);
+                let watch_only = self.watch_only.contains_key(&address);
+                (address, label, watch_only)
+            })
+            .collect()
+    }
+
+    /// Balances 返回钱包中每个地址的余额，按地址排序，include_watch_only 为 true 时包括只观察的地址
+    ///
+    /// 只遍历一次UTXO集合，而不是每个地址遍历一次
+    pub fn balances(
+        &self,
+        utxo_set: &UTXOSet,
+        include_watch_only: bool,
+    ) -> Result<Vec<AddressBalance>> {
+        let addresses = self.get_all_addresses_labeled(include_watch_only);
+        let mut pub_key_hashes = HashSet::new();
+        for (address, _, _) in &addresses {
+            pub_key_hashes.insert(decode_address(address, self.network)?);
+        }
+        let found = utxo_set.find_balances(&pub_key_hashes)?;
+
+        let mut balances = Vec::new();
+        for (address, label, watch_only) in addresses {
+            let balance = found
+                .get(&decode_address(&address, self.network)?)
+                .copied()
+                .unwrap_or_default();
+            balances.push(AddressBalance {
+                address,
+                label,
+                watch_only,
+                spendable: balance.spendable,
+                immature: balance.immature,
+                frozen: balance.frozen,
+            });
+        }
+        balances.sort_by(|a, b| a.address.cmp(&b.address));
+        Ok(balances)
+    }
+
+    /// SetLabel 设置钱包中地址的标签，标签为空时删除标签
+    pub fn set_label(&mut self, address: &str, label: &str) -> Result<()> {
+        let address =
+            address_from_pub_key_hash(&decode_address(address, self.network)?, self.network);
+        let label = (!label.is_empty()).then(|| label.to_string());
+        if let Some(entry) = self.watch_only.get_mut(&address) {
+            entry.label = label;
+        } else if self.wallets.contains_key(&address) {
+            match label {
+                Some(label) => self.labels.insert(address, label),
+                None => self.labels.remove(&address),
+            };
+        } else {
+            return Err(format_err!("Address {} is not in the wallet", address));
+        }
+        Ok(())
+    }
+
+    /// GetLabel 返回地址的标签，没有标签或不在钱包中时返回 None
+    pub fn get_label(&self, address: &str) -> Option<&str> {
+        match self.get_watch_only(address) {
+            Some(entry) => entry.label.as_deref(),
+            None => lookup(&self.labels, address, self.network).map(String::as_str),
+        }
+    }
+
+    /// AddContact 把外部地址以 name 加入通讯录，同名联系人被覆盖
+    ///
+    /// 名称不能为空，也不能是地址，否则无法与地址区分
+    pub fn add_contact(&mut self, name: &str, address: &str) -> Result<()> {
+        if name.trim().is_empty() {
+            return Err(format_err!("Contact name must not be empty"));
+        }
+        if validate_address(name, self.network).is_ok() {
+            return Err(format_err!("Contact name {} is an address", name));
+        }
+        validate_address(address, self.network)?;
+        self.contacts.insert(name.to_string(), address.to_string());
+        Ok(())
+    }
+
+    /// GetContacts 返回按名称排序的通讯录
+    pub fn get_contacts(&self) -> Vec<(&str, &str)> {
+        let mut contacts: Vec<(&str, &str)> = self
+            .contacts
+            .iter()
+            .map(|(name, address)| (name.as_str(), address.as_str()))
+            .collect();
+        contacts.sort();
+        contacts
+    }
+
+    /// ResolveAddress 把地址或通讯录中的联系人名称解析为地址
+    pub fn resolve_address(&self, name_or_address: &str) -> Result<String> {
+        if validate_address(name_or_address, self.network).is_ok() {
+            return Ok(name_or_address.to_string());
+        }
+        match self.contacts.get(name_or_address) {
+            Some(address) => Ok(address.clone()),
+            None => Err(format_err!(
+                "'{}' is neither a valid address nor a contact",
+                name_or_address
+            )),
+        }
+    }
+
+    /// GetWallet 通过地址获取钱包，钱包以 Base58 地址保存，其他格式的地址先转换
     pub fn get_wallet(&self, address: &str) -> Option<&Wallet> {
-        self.wallets.get(address)
+        lookup(&self.wallets, address, self.network)
+    }
+
+    /// ImportWif 导入 export_wif 导出的私钥并保存，返回其地址
+    ///
+    /// 钱包中已有该私钥时只记录警告，不做任何修改；该地址原为只观察地址时转为可花费
+    pub fn import_wif(&mut self, wif: &str) -> Result<String> {
+        self.import_wallet(Wallet::from_wif(wif, self.network)?)
+    }
+
+    /// ImportKeystore 解密 JSON 密钥文件并把私钥加入钱包，返回其地址
+    pub fn import_keystore(&mut self, json: &str, passphrase: &str) -> Result<String> {
+        self.import_wallet(Wallet::from_keystore_json(json, passphrase, self.network)?)
+    }
+
+    /// 加入导入的私钥并保存，返回其地址
+    fn import_wallet(&mut self, wallet: Wallet) -> Result<String> {
+        let address = wallet.get_address(self.network);
+        if self.wallets.contains_key(&address) {
+            warn!(
+                "key for {} is already in the wallet, not importing",
+                address
+            );
+            return Ok(address);
+        }
+        if let Some(WatchOnly { label: Some(label) }) = self.watch_only.remove(&address) {
+            self.labels.insert(address.clone(), label);
+        }
+        self.wallets.insert(address.clone(), wallet);
+        self.save_all()?;
+        info!("imported key for {}", address);
+        Ok(address)
+    }
+
+    /// GetWatchOnly 返回只观察地址的记录，持有私钥或未知的地址返回 None
+    pub fn get_watch_only(&self, address: &str) -> Option<&WatchOnly> {
+        lookup(&self.watch_only, address, self.network)
+    }
+
+    /// GetSpendingWallet 返回用于花费或签名的钱包，只观察的地址在选币之前报错
+    pub fn get_spending_wallet(&self, address: &str) -> Result<&Wallet> {
+        match self.get_wallet(address) {
+            Some(wallet) => Ok(wallet),
+            None if self.get_watch_only(address).is_some() => Err(format_err!(
+                "Cannot spend from {}: address is watch-only",
+                address
+            )),
+            None => Err(format_err!("Wallet {} not found", address)),
+        }
     }
 
     /// SaveAll 保存钱包到文件
+    ///
+    /// 没有通过 open_for_write 持有锁时，只在保存期间锁定钱包文件
     pub fn save_all(&self) -> Result<()> {
-        let db = sled::open("data/wallets")?;
-
-        for (address, wallet) in &self.wallets {
-            let data = serialize(wallet)?;
-            db.insert(address, data)?;
+        let _lock = match self.lock {
+            Some(_) => None,
+            None => Some(self.storage.lock()?),
+        };
+        if self.rewrite {
+            let mut stored = StoredWallets::default();
+            self.merge_into(&mut stored)?;
+            return self.storage.rewrite(&stored);
         }
+        let mut stored = self.storage.load()?;
+        self.merge_into(&mut stored)?;
+        self.storage.save(&stored)
+    }
 
-        db.flush()?;
-        drop(db);
+    /// MergeInto 把本钱包集合合并到存储中已有的内容
+    ///
+    /// 钱包和只观察地址只增不减，其他进程新建的不会丢失；标签和联系人可以删除，以内存中的为准
+    fn merge_into(&self, stored: &mut StoredWallets) -> Result<()> {
+        if self.key.is_none() && stored.encrypted.is_some() {
+            return Err(format_err!(
+                "Wallet file is encrypted, unlock it before saving"
+            ));
+        }
+        stored.watch_only.extend(self.watch_only.clone());
+        // 导入私钥后原来的只观察记录作废
+        for address in self.wallets.keys() {
+            stored.watch_only.remove(address);
+        }
+        stored.labels = self.labels.clone();
+        stored.contacts = self.contacts.clone();
+        match &self.key {
+            Some(key) => {
+                let secrets = WalletSecrets {
+                    wallets: self.wallets.clone(),
+                    hd: self.hd.clone(),
+                    mnemonic_entropy: self.mnemonic_entropy.clone(),
+                };
+                // 保存密文的同时清除明文私钥和种子
+                stored.encrypted = Some(key.seal(&serialize(&secrets)?)?);
+                stored.wallets.clear();
+                stored.hd = None;
+                stored.mnemonic_entropy = None;
+            }
+            None => {
+                stored.wallets.extend(self.wallets.clone());
+                if self.hd.is_some() {
+                    stored.hd = self.hd.clone();
+                }
+                if self.mnemonic_entropy.is_some() {
+                    stored.mnemonic_entropy = self.mnemonic_entropy.clone();
+                }
+            }
+        }
         Ok(())
     }
 }
@@ -124,39 +1227,796 @@ impl Wallets {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::transaction::TXOutput;
+    use crate::walletstorage::memory::MemoryStorage;
+    use crypto::ed25519;
+    use std::path::{Path, PathBuf};
+
+    /// 临时目录中的钱包文件，不影响其他测试
+    fn temp_file(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustchain-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        dir.join("wallets")
+    }
+
+    fn open_file(path: &Path, passphrase: Option<&str>) -> Result<Wallets> {
+        Wallets::open(
+            Network::Mainnet,
+            Box::new(FileStorage::new(path.to_path_buf())),
+            passphrase,
+            false,
+        )
+    }
 
     #[test]
     fn test_create_wallet_and_hash() {
         let w1 = Wallet::new();
         let w2 = Wallet::new();
         assert_ne!(w1, w2);
-        assert_ne!(w1.get_address(), w2.get_address());
+        assert_ne!(
+            w1.get_address(Network::Mainnet),
+            w2.get_address(Network::Mainnet)
+        );
 
         let mut p2 = w2.public_key.clone();
         hash_pub_key(&mut p2);
         assert_eq!(p2.len(), 20);
-        let pub_key_hash = Address::decode(&w2.get_address()).unwrap().body;
+        let pub_key_hash = Address::decode(&w2.get_address(Network::Mainnet))
+            .unwrap()
+            .body;
         assert_eq!(pub_key_hash, p2);
     }
 
     #[test]
     fn test_wallets() {
-        let mut ws = Wallets::new().unwrap();
+        let storage = MemoryStorage::default();
+        let mut ws = Wallets::in_memory(&storage);
         let wa1 = ws.create_wallet();
         let w1 = ws.get_wallet(&wa1).unwrap().clone();
         ws.save_all().unwrap();
 
-        let ws2 = Wallets::new().unwrap();
+        let ws2 = Wallets::in_memory(&storage);
         let w2 = ws2.get_wallet(&wa1).unwrap();
         assert_eq!(&w1, w2);
     }
 
+    #[test]
+    fn test_create_change_address() {
+        let storage = MemoryStorage::default();
+        let mut ws = Wallets::in_memory(&storage);
+        let change = ws.create_change_address().unwrap();
+
+        let ws2 = Wallets::in_memory(&storage);
+        assert!(ws2.get_wallet(&change).is_some());
+    }
+
+    #[test]
+    fn test_decode_address() {
+        let w = Wallet::new();
+        let address = w.get_address(Network::Mainnet);
+        let mut pub_key_hash = w.public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        assert_eq!(
+            decode_address(&address, Network::Mainnet).unwrap(),
+            pub_key_hash
+        );
+        validate_address(&address, Network::Mainnet).unwrap();
+
+        // 改动最后一个字符会使校验和失效
+        let mut mangled = address.clone();
+        let last = if mangled.ends_with('1') { '2' } else { '1' };
+        mangled.pop();
+        mangled.push(last);
+        let err = validate_address(&mangled, Network::Mainnet).unwrap_err();
+        assert!(err.to_string().contains("checksum"), "{}", err);
+
+        assert!(validate_address("", Network::Mainnet).is_err());
+        assert!(validate_address("0OIl", Network::Mainnet).is_err());
+        assert!(validate_address(&address[..address.len() - 1], Network::Mainnet).is_err());
+        let short = address_from_pub_key_hash(&pub_key_hash[..19], Network::Mainnet);
+        assert!(validate_address(&short, Network::Mainnet).is_err());
+    }
+
+    #[test]
+    fn test_bech32_address() {
+        let w = Wallet::new();
+        let bech32 = w.get_address_bech32(Network::Mainnet);
+        assert!(bech32.starts_with("rtc1"));
+        assert_eq!(
+            decode_address(&bech32, Network::Mainnet).unwrap(),
+            decode_address(&w.get_address(Network::Mainnet), Network::Mainnet).unwrap()
+        );
+        assert_eq!(
+            decode_address(&bech32.to_ascii_uppercase(), Network::Mainnet).unwrap(),
+            decode_address(&bech32, Network::Mainnet).unwrap()
+        );
+
+        let pub_key_hash = decode_address(&bech32, Network::Mainnet).unwrap();
+        let testnet = bech32::encode("trtc", &pub_key_hash);
+        assert_eq!(
+            decode_address(&testnet, Network::Testnet).unwrap(),
+            pub_key_hash
+        );
+
+        // 大小写混用、校验和错误和长度不符的地址都被拒绝
+        let mut mixed = bech32.clone();
+        mixed.replace_range(..1, "R");
+        let err = decode_address(&mixed, Network::Mainnet).unwrap_err();
+        assert!(err.to_string().contains("mixed-case"), "{}", err);
+        let mut mangled = bech32.clone();
+        let last = if mangled.ends_with('q') { 'p' } else { 'q' };
+        mangled.pop();
+        mangled.push(last);
+        let err = decode_address(&mangled, Network::Mainnet).unwrap_err();
+        assert!(err.to_string().contains("checksum"), "{}", err);
+        assert!(
+            decode_address(
+                &bech32::encode("rtc", &pub_key_hash[..19]),
+                Network::Mainnet
+            )
+            .is_err()
+        );
+        assert!(decode_address(&bech32::encode("xrtc", &pub_key_hash), Network::Mainnet).is_err());
+
+        // 两种格式的地址都能锁定输出和验证消息签名
+        let output = TXOutput::new(5, bech32.clone(), Network::Mainnet).unwrap();
+        assert_eq!(output.pub_key_hash, pub_key_hash);
+        let signature = w.sign_message(b"hi");
+        assert!(verify_message(&bech32, b"hi", &signature, Network::Mainnet).unwrap());
+
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let address = ws.create_wallet();
+        let bech32 = ws
+            .get_wallet(&address)
+            .unwrap()
+            .get_address_bech32(Network::Mainnet);
+        assert_eq!(ws.get_wallet(&bech32), ws.get_wallet(&address));
+    }
+
+    #[test]
+    fn test_network_addresses() {
+        let w = Wallet::new();
+        let pub_key_hash = w.pub_key_hash();
+        let mainnet = w.get_address(Network::Mainnet);
+        let testnet = w.get_address(Network::Testnet);
+        let regtest = w.get_address(Network::Regtest);
+        assert!(mainnet.starts_with('3'));
+        assert!(testnet.starts_with('2'));
+        assert_ne!(testnet, regtest);
+
+        for (address, network) in [
+            (&mainnet, Network::Mainnet),
+            (&testnet, Network::Testnet),
+            (&regtest, Network::Regtest),
+        ] {
+            assert_eq!(decode_address(address, network).unwrap(), pub_key_hash);
+            for other in Network::all().into_iter().filter(|n| *n != network) {
+                let err = decode_address(address, other).unwrap_err();
+                assert!(err.to_string().contains(&format!("belongs to {}", network)));
+            }
+        }
+
+        // 主网节点上向测试网地址转账在选币之前就失败
+        assert!(validate_address(&testnet, Network::Mainnet).is_err());
+        assert!(TXOutput::new(5, testnet.clone(), Network::Mainnet).is_err());
+        assert!(TXOutput::new(5, regtest, Network::Mainnet).is_err());
+        assert!(TXOutput::new(5, mainnet.clone(), Network::Mainnet).is_ok());
+        let testnet_bech32 = bech32::encode(Network::Testnet.bech32_hrp(), &pub_key_hash);
+        assert!(TXOutput::new(5, testnet_bech32.clone(), Network::Mainnet).is_err());
+        assert!(decode_address(&testnet_bech32, Network::Testnet).is_ok());
+
+        // 同一进程中的钱包集合按各自的网络生成和解析地址
+        let storage = Box::new(MemoryStorage::default());
+        let mut ws = Wallets::open(Network::Testnet, storage, None, false).unwrap();
+        assert!(ws.create_wallet().starts_with('2'));
+        assert!(ws.resolve_address(&testnet).is_ok());
+        assert!(ws.resolve_address(&mainnet).is_err());
+        assert!(
+            Wallets::in_memory(&MemoryStorage::default())
+                .resolve_address(&mainnet)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_sign_message() {
+        let w = Wallet::new();
+        let other = Wallet::new();
+        let address = w.get_address(Network::Mainnet);
+
+        let signature = w.sign_message(b"I control this address");
+        assert!(
+            verify_message(
+                &address,
+                b"I control this address",
+                &signature,
+                Network::Mainnet
+            )
+            .unwrap()
+        );
+        assert!(
+            !verify_message(
+                &address,
+                b"I control this address!",
+                &signature,
+                Network::Mainnet
+            )
+            .unwrap()
+        );
+        assert!(
+            !verify_message(
+                &other.get_address(Network::Mainnet),
+                b"I control this address",
+                &signature,
+                Network::Mainnet
+            )
+            .unwrap()
+        );
+
+        let empty = w.sign_message(b"");
+        assert!(verify_message(&address, b"", &empty, Network::Mainnet).unwrap());
+        assert!(!verify_message(&address, b"", &signature, Network::Mainnet).unwrap());
+
+        // 换上别人的公钥或篡改签名都无法通过
+        let mut forged = other.sign_message(b"I control this address");
+        forged[..PUBLIC_KEY_LEN].copy_from_slice(&w.public_key);
+        assert!(
+            !verify_message(
+                &address,
+                b"I control this address",
+                &forged,
+                Network::Mainnet
+            )
+            .unwrap()
+        );
+        let mut forged = signature.clone();
+        forged[PUBLIC_KEY_LEN] ^= 1;
+        assert!(
+            !verify_message(
+                &address,
+                b"I control this address",
+                &forged,
+                Network::Mainnet
+            )
+            .unwrap()
+        );
+
+        // 与交易签名使用不同的摘要
+        let raw = sign_message(&w.secret_key, b"I control this address").unwrap();
+        let mut blob = w.public_key.clone();
+        blob.extend_from_slice(&raw);
+        assert!(
+            !verify_message(&address, b"I control this address", &blob, Network::Mainnet).unwrap()
+        );
+
+        assert!(verify_message(&address, b"", &signature[1..], Network::Mainnet).is_err());
+        assert!(verify_message("not an address", b"", &signature, Network::Mainnet).is_err());
+    }
+
+    #[test]
+    fn test_watch_only() {
+        let cold = Wallet::new();
+        let storage = MemoryStorage::default();
+        let mut ws = Wallets::in_memory(&storage);
+        let own = ws.create_wallet();
+        ws.add_watch_only(
+            &cold.get_address_bech32(Network::Mainnet),
+            Some(String::from("cold storage")),
+        )
+        .unwrap();
+        assert!(ws.add_watch_only(&own, None).is_err());
+        assert!(ws.add_watch_only("bogus", None).is_err());
+        ws.save_all().unwrap();
+
+        let ws = Wallets::in_memory(&storage);
+        let address = cold.get_address(Network::Mainnet);
+        assert_eq!(
+            ws.get_watch_only(&address).unwrap().label.as_deref(),
+            Some("cold storage")
+        );
+        assert!(ws.get_wallet(&address).is_none());
+        assert!(ws.get_watch_only(&own).is_none());
+        assert!(ws.get_all_addresses(true).contains(&address));
+        assert!(!ws.get_all_addresses(false).contains(&address));
+        assert!(ws.get_all_addresses(false).contains(&own));
+
+        let err = ws.get_spending_wallet(&address).unwrap_err();
+        assert!(err.to_string().contains("address is watch-only"));
+        assert!(ws.get_spending_wallet(&own).is_ok());
+        assert!(
+            ws.get_spending_wallet(&Wallet::new().get_address(Network::Mainnet))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_vanity_wallet() {
+        let timeout = Duration::from_secs(30);
+        let sample = Wallet::new().get_address(Network::Mainnet);
+        let storage = MemoryStorage::default();
+        let mut ws = Wallets::in_memory(&storage);
+
+        // 首字符由网络版本字节决定，总能立即找到
+        let address = ws.create_vanity_wallet(&sample[..1], timeout).unwrap();
+        assert!(address.starts_with(&sample[..1]));
+        let address = ws.create_vanity_wallet(&sample[..2], timeout).unwrap();
+        assert!(address.starts_with(&sample[..2]));
+        let address = ws
+            .create_vanity_wallet_ignore_case(&sample[..2].to_ascii_lowercase(), timeout)
+            .unwrap();
+        assert!(address[..2].eq_ignore_ascii_case(&sample[..2]));
+        assert!(Wallets::in_memory(&storage).get_wallet(&address).is_some());
+
+        for invalid in ["", "30", "3O", "3I", "3l", "3 "] {
+            let err = ws.create_vanity_wallet(invalid, timeout).unwrap_err();
+            assert!(!err.to_string().contains("found within"), "{}", invalid);
+        }
+        for _ in 0..20 {
+            let sample = Wallet::new().get_address(Network::Mainnet);
+            for len in 1..=sample.len() {
+                check_vanity_prefix(&sample[..len], false, Network::Mainnet).unwrap();
+            }
+        }
+        check_vanity_prefix("3l", true, Network::Mainnet).unwrap();
+        assert!(check_vanity_prefix("30", true, Network::Mainnet).is_err());
+        // 主网地址总以 3 开头
+        assert!(ws.create_vanity_wallet("4", timeout).is_err());
+        assert!(ws.create_vanity_wallet(&"3".repeat(40), timeout).is_err());
+
+        let err = ws
+            .create_vanity_wallet_ignore_case("3zzzzzzzzz", Duration::from_millis(100))
+            .unwrap_err();
+        assert!(err.to_string().contains("found within"));
+    }
+
+    #[test]
+    fn test_hd_derivation() {
+        // SLIP-0010 ed25519 测试向量 1 中路径 m/0H 的密钥
+        let hd = HdSeed::new(hex("000102030405060708090a0b0c0d0e0f")).unwrap();
+        let child = hd.derive(0);
+        assert_eq!(
+            child.secret_key[..32],
+            hex("68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3")
+        );
+        assert_eq!(
+            child.public_key,
+            hex("8c8a13df77a28f3445213a0f432fde644acaa215fc72dcdf300d5efaa85d350c")
+        );
+        assert_eq!(hd.derive(0), child);
+        assert_ne!(hd.derive(1), child);
+        assert!(HdSeed::new(vec![0; 15]).is_err());
+        assert!(HdSeed::new(vec![0; 65]).is_err());
+    }
+
+    #[test]
+    fn test_hd_wallets() {
+        // 不保存到共享的钱包数据库，以免其他测试开始派生同样的地址
+        let mut ws = Wallets::empty(Network::Mainnet, Box::new(MemoryStorage::default()));
+        let random = ws.create_wallet();
+        let seed = ws.create_hd_seed().unwrap();
+        assert!(ws.create_hd_seed().is_err());
+        let first = ws.create_wallet();
+        let second = ws.create_wallet();
+        assert_eq!(ws.hd_seed().unwrap().next_index, 2);
+
+        let hd = HdSeed::new(seed.clone()).unwrap();
+        assert_eq!(first, hd.derive(0).get_address(Network::Mainnet));
+        assert_eq!(second, hd.derive(1).get_address(Network::Mainnet));
+        assert!(ws.get_wallet(&random).is_some());
+
+        // 序号 1、5 和 10 有余额，间隔限制为 4 时扫描到序号 9 停止，恢复到序号 5 为止
+        let used: HashSet<Vec<u8>> = [1, 5, 10]
+            .iter()
+            .map(|i| hd.derive(*i).pub_key_hash())
+            .collect();
+        let mut restored = Wallets::empty(Network::Mainnet, Box::new(MemoryStorage::default()));
+        assert_eq!(restored.recover_from_seed(hd.clone(), &used, 4).unwrap(), 6);
+        assert_eq!(restored.get_all_addresses(false).len(), 6);
+        assert!(restored.get_wallet(&second).is_some());
+        assert!(
+            restored
+                .get_wallet(&hd.derive(10).get_address(Network::Mainnet))
+                .is_none()
+        );
+        assert_eq!(
+            restored.create_wallet(),
+            hd.derive(6).get_address(Network::Mainnet)
+        );
+        assert!(restored.recover_from_seed(hd.clone(), &used, 4).is_err());
+
+        let mut empty = Wallets::empty(Network::Mainnet, Box::new(MemoryStorage::default()));
+        assert_eq!(
+            empty
+                .recover_from_seed(hd.clone(), &HashSet::new(), 20)
+                .unwrap(),
+            0
+        );
+        assert_eq!(empty.create_wallet(), first);
+    }
+
+    #[test]
+    fn test_mnemonic_backup() {
+        let mut ws = Wallets::empty(Network::Mainnet, Box::new(MemoryStorage::default()));
+        assert!(ws.export_mnemonic().is_err());
+        ws.create_hd_seed().unwrap();
+        let address = ws.create_wallet();
+        let phrase = ws.export_mnemonic().unwrap();
+        assert_eq!(phrase.split(' ').count(), 24);
+
+        let mut restored = Wallets::from_mnemonic(
+            Network::Mainnet,
+            Box::new(MemoryStorage::default()),
+            &phrase,
+            "",
+        )
+        .unwrap();
+        assert_eq!(restored.export_mnemonic().unwrap(), phrase);
+        assert_eq!(restored.create_wallet(), address);
+        // 口令不同则派生出另一组钱包
+        let mut other = Wallets::from_mnemonic(
+            Network::Mainnet,
+            Box::new(MemoryStorage::default()),
+            &phrase,
+            "secret",
+        )
+        .unwrap();
+        assert_ne!(other.create_wallet(), address);
+
+        // 用固定的助记词，保证交换单词后校验和一定不匹配
+        let phrase = bip39::entropy_to_mnemonic(&[0x7f; 32]).unwrap();
+        assert!(
+            Wallets::from_mnemonic(
+                Network::Mainnet,
+                Box::new(MemoryStorage::default()),
+                &phrase,
+                ""
+            )
+            .is_ok()
+        );
+        let mut words: Vec<&str> = phrase.split(' ').collect();
+        words.swap(0, 1);
+        let err = Wallets::from_mnemonic(
+            Network::Mainnet,
+            Box::new(MemoryStorage::default()),
+            &words.join(" "),
+            "",
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("checksum"), "{}", err);
+        words[0] = "bitcoin";
+        let err = Wallets::from_mnemonic(
+            Network::Mainnet,
+            Box::new(MemoryStorage::default()),
+            &words.join(" "),
+            "",
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("unknown word 'bitcoin'"));
+
+        let mut raw = Wallets::empty(Network::Mainnet, Box::new(MemoryStorage::default()));
+        raw.set_hd_seed(HdSeed::new(vec![7; 32]).unwrap()).unwrap();
+        assert!(raw.export_mnemonic().is_err());
+    }
+
+    #[test]
+    fn test_labels_and_contacts() {
+        let storage = MemoryStorage::default();
+        let mut ws = Wallets::in_memory(&storage);
+        let own = ws.create_wallet();
+        let cold = Wallet::new();
+        ws.add_watch_only(
+            &cold.get_address(Network::Mainnet),
+            Some(String::from("cold")),
+        )
+        .unwrap();
+        let friend = Wallet::new().get_address(Network::Mainnet);
+
+        ws.set_label(&own, "savings").unwrap();
+        assert!(ws.set_label(&friend, "friend").is_err());
+        ws.add_contact("alice", &friend).unwrap();
+        assert!(ws.add_contact("", &friend).is_err());
+        assert!(ws.add_contact(&own, &friend).is_err());
+        assert!(ws.add_contact("bob", "bogus").is_err());
+        ws.save_all().unwrap();
+
+        // 旧钱包文件中没有标签和通讯录
+        let path = temp_file("legacy-wallets");
+        let legacy = sled::open(&path).unwrap();
+        legacy
+            .insert(&own, serialize(ws.get_wallet(&own).unwrap()).unwrap())
+            .unwrap();
+        drop(legacy);
+        let old = open_file(&path, None).unwrap();
+        assert!(old.get_wallet(&own).is_some());
+        assert_eq!(old.get_label(&own), None);
+        assert!(old.get_contacts().is_empty());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        let mut ws = Wallets::in_memory(&storage);
+        assert_eq!(ws.get_label(&own), Some("savings"));
+        assert_eq!(
+            ws.get_label(&cold.get_address_bech32(Network::Mainnet)),
+            Some("cold")
+        );
+        assert_eq!(ws.get_contacts(), vec![("alice", friend.as_str())]);
+        assert_eq!(ws.resolve_address("alice").unwrap(), friend);
+        assert_eq!(ws.resolve_address(&own).unwrap(), own);
+        assert!(ws.resolve_address("bob").is_err());
+        let mut labeled = ws.get_all_addresses_labeled(true);
+        labeled.sort();
+        let mut expected = vec![
+            (own.clone(), Some(String::from("savings")), false),
+            (
+                cold.get_address(Network::Mainnet),
+                Some(String::from("cold")),
+                true,
+            ),
+        ];
+        expected.sort();
+        assert_eq!(labeled, expected);
+
+        // 空标签删除标签
+        ws.set_label(&own, "").unwrap();
+        ws.save_all().unwrap();
+        let ws = Wallets::in_memory(&storage);
+        assert_eq!(ws.get_label(&own), None);
+    }
+
+    #[test]
+    fn test_bump_fee() {
+        use crate::block::Block;
+        use crate::blockchain::Blockchain;
+
+        let mut ws = Wallets::empty(Network::Mainnet, Box::new(MemoryStorage::default()));
+        let from = ws.create_wallet();
+        let to =
+            Wallets::empty(Network::Mainnet, Box::new(MemoryStorage::default())).create_wallet();
+        let mut bc = Blockchain::in_memory();
+        let coinbase =
+            Transaction::new_coinbase(from.clone(), String::new(), 0, 0, Network::Mainnet).unwrap();
+        bc.add_block(Block::new_unmined_block(vec![coinbase], String::new(), 0).unwrap())
+            .unwrap();
+        let utxo_set = UTXOSet::in_memory(bc);
+        utxo_set.reindex().unwrap();
+        let wallet = ws.get_wallet(&from).unwrap();
+        let options = TxOptions {
+            fee: 1,
+            ..TxOptions::default()
+        };
+        let tx = Transaction::new_utxo(wallet, &to, 3, &options, &utxo_set).unwrap();
+
+        // 差额从找零中扣除，输入和付款不变
+        let bumped = ws.bump_fee(&tx, 3, &utxo_set).unwrap();
+        assert_ne!(bumped.id, tx.id);
+        assert_eq!(bumped.vin.len(), 1);
+        assert_eq!(bumped.vin[0].outpoint, tx.vin[0].outpoint);
+        assert_eq!(bumped.vout[0], tx.vout[0]);
+        assert_eq!(bumped.vout[1].value, tx.vout[1].value - 2);
+        let prev_txs = utxo_set.blockchain.get_prev_txs(&bumped).unwrap();
+        assert_eq!(bumped.fee(&prev_txs).unwrap(), 3);
+        bumped.verify(prev_txs).unwrap();
+
+        assert!(ws.bump_fee(&tx, 1, &utxo_set).is_err());
+        assert!(ws.bump_fee(&tx, 6, &utxo_set).is_err());
+        // 没有找零的交易无处扣除手续费，别人的交易不能替换
+        let all = Transaction::new_utxo(wallet, &to, 9, &options, &utxo_set).unwrap();
+        assert!(ws.bump_fee(&all, 2, &utxo_set).is_err());
+        assert!(
+            Wallets::empty(Network::Mainnet, Box::new(MemoryStorage::default()))
+                .bump_fee(&tx, 3, &utxo_set)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_encrypted_wallets() {
+        // 检查磁盘上的内容，使用临时目录中的钱包文件
+        let path = temp_file("encrypted-wallets");
+        let mut ws = open_file(&path, None).unwrap();
+        let legacy = ws.create_wallet();
+        ws.create_hd_seed().unwrap();
+        let derived = ws.create_wallet();
+        let cold = Wallet::new().get_address(Network::Mainnet);
+        ws.add_watch_only(&cold, None).unwrap();
+        ws.save_all().unwrap();
+        assert!(open_file(&path, Some("pass")).is_err());
+
+        // 升级未加密的钱包文件
+        let mut ws = open_file(&path, None).unwrap();
+        ws.encrypt("pass").unwrap();
+        assert!(ws.encrypt("pass").is_err());
+        ws.save_all().unwrap();
+        let secret = ws.get_wallet(&legacy).unwrap().secret_key.clone();
+        let db = sled::open(&path).unwrap();
+        for name in db.tree_names() {
+            for item in db.open_tree(name).unwrap().iter() {
+                let (_, value) = item.unwrap();
+                assert!(!value.windows(32).any(|w| w == &secret[..32]));
+            }
+        }
+        assert!(db.is_empty());
+        drop(db);
+
+        let err = open_file(&path, None).err().unwrap();
+        assert!(err.to_string().contains("encrypted"));
+        let err = open_file(&path, Some("wrong")).err().unwrap();
+        assert!(err.to_string().contains("Wrong passphrase"));
+        let blank = Wallets::empty(Network::Mainnet, Box::new(FileStorage::new(path.clone())));
+        assert!(blank.save_all().is_err());
+
+        let mut ws = open_file(&path, Some("pass")).unwrap();
+        assert!(ws.get_watch_only(&cold).is_some());
+        let signature = ws.get_wallet(&legacy).unwrap().sign_message(b"unlocked");
+        assert!(verify_message(&legacy, b"unlocked", &signature, Network::Mainnet).unwrap());
+        assert!(ws.get_wallet(&derived).is_some());
+        let mnemonic = ws.export_mnemonic().unwrap();
+
+        // 解锁后新建的钱包保存时仍然加密
+        let fresh = ws.create_wallet();
+        ws.change_passphrase("new pass").unwrap();
+        ws.save_all().unwrap();
+        assert!(open_file(&path, Some("pass")).is_err());
+        let ws = open_file(&path, Some("new pass")).unwrap();
+        assert!(ws.get_wallet(&fresh).is_some());
+        assert_eq!(ws.export_mnemonic().unwrap(), mnemonic);
+        assert_eq!(ws.hd_seed().unwrap().next_index, 2);
+
+        assert!(
+            Wallets::empty(Network::Mainnet, Box::new(MemoryStorage::default()))
+                .change_passphrase("x")
+                .is_err()
+        );
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_wif() {
+        let wallet = Wallet::new();
+        let wif = wallet.export_wif(Network::Mainnet);
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        ws.add_watch_only(&wallet.get_address(Network::Mainnet), None)
+            .unwrap();
+        let address = ws.import_wif(&wif).unwrap();
+        assert_eq!(address, wallet.get_address(Network::Mainnet));
+        assert_eq!(ws.get_wallet(&address), Some(&wallet));
+        assert!(ws.get_watch_only(&address).is_none());
+
+        // 重复导入不做修改
+        assert_eq!(ws.import_wif(&wif).unwrap(), address);
+        assert_eq!(ws.get_all_addresses(true).len(), 1);
+
+        let last = wif.chars().last().unwrap();
+        let replacement = if last == '2' { '3' } else { '2' };
+        let corrupted = format!("{}{}", &wif[..wif.len() - 1], replacement);
+        let err = ws.import_wif(&corrupted).unwrap_err();
+        assert!(err.to_string().contains("checksum"), "{}", err);
+
+        let mut payload = vec![Network::Testnet.wif_version()];
+        payload.extend_from_slice(&wallet.secret_key[..32]);
+        let err = ws.import_wif(&base58::encode_check(&payload)).unwrap_err();
+        assert!(err.to_string().contains("does not belong to"));
+        payload[0] = Network::Mainnet.wif_version();
+        payload.pop();
+        assert!(ws.import_wif(&base58::encode_check(&payload)).is_err());
+        assert!(ws.import_wif("not a key").is_err());
+    }
+
+    #[test]
+    fn test_keystore_json() {
+        let wallet = Wallet::new();
+        let json = wallet.to_keystore_json("pass", Network::Mainnet).unwrap();
+        assert!(json.contains(&wallet.get_address(Network::Mainnet)));
+        assert_eq!(
+            Wallet::from_keystore_json(&json, "pass", Network::Mainnet).unwrap(),
+            wallet
+        );
+        let err = Wallet::from_keystore_json(&json, "wrong", Network::Mainnet).unwrap_err();
+        assert!(err.to_string().contains("Wrong passphrase"), "{}", err);
+
+        // 文件中记录的地址与私钥不符
+        let other = Wallet::new().get_address(Network::Mainnet);
+        let forged = json.replace(&wallet.get_address(Network::Mainnet), &other);
+        let err = Wallet::from_keystore_json(&forged, "pass", Network::Mainnet).unwrap_err();
+        assert!(err.to_string().contains("holds the key of"), "{}", err);
+
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        assert_eq!(
+            ws.import_keystore(&json, "pass").unwrap(),
+            wallet.get_address(Network::Mainnet)
+        );
+        assert_eq!(
+            ws.get_wallet(&wallet.get_address(Network::Mainnet)),
+            Some(&wallet)
+        );
+
+        // 导入 log_n >= 16·r 的 scrypt 参数返回错误，不会 panic
+        let mut invalid: serde_json::Value = serde_json::from_str(&json).unwrap();
+        invalid["crypto"]["kdfparams"]["n"] = 65536.into();
+        invalid["crypto"]["kdfparams"]["r"] = 1.into();
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let err = ws
+            .import_keystore(&invalid.to_string(), "pass")
+            .unwrap_err();
+        assert!(err.to_string().contains("Unsupported"), "{}", err);
+        assert!(ws.get_all_addresses(true).is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_saves() {
+        let storage = MemoryStorage::default();
+        let workers: Vec<_> = (0..2)
+            .map(|_| {
+                let storage = storage.clone();
+                thread::spawn(move || {
+                    let mut created = Vec::new();
+                    for _ in 0..10 {
+                        let mut ws =
+                            Wallets::open(Network::Mainnet, Box::new(storage.clone()), None, true)
+                                .unwrap();
+                        created.push(ws.create_wallet());
+                        ws.save_all().unwrap();
+                    }
+                    created
+                })
+            })
+            .collect();
+        let created: Vec<String> = workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect();
+
+        let ws = Wallets::in_memory(&storage);
+        for address in &created {
+            assert!(ws.get_wallet(address).is_some());
+        }
+    }
+
     #[test]
     #[should_panic]
     fn test_wallets_not_exist() {
         let w3 = Wallet::new();
-        let ws2 = Wallets::new().unwrap();
-        ws2.get_wallet(&w3.get_address()).unwrap();
+        let ws2 = Wallets::in_memory(&MemoryStorage::default());
+        ws2.get_wallet(&w3.get_address(Network::Mainnet)).unwrap();
+    }
+
+    #[test]
+    fn test_ed25519_compat() {
+        // RFC 8032 测试向量 1
+        let seed = hex("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60");
+        let public_key = hex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+        let expected = hex(
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        );
+
+        // 旧版 rust-crypto 生成的密钥对和签名与新实现逐字节一致
+        let (legacy_secret, legacy_public) = ed25519::keypair(&seed);
+        assert_eq!(legacy_public.to_vec(), public_key);
+        assert_eq!(ed25519::signature(b"", &legacy_secret).to_vec(), expected);
+
+        let signature = sign_message(&legacy_secret, b"").unwrap();
+        assert_eq!(signature, expected);
+        assert!(verify_signature(&public_key, b"", &signature));
+
+        // 新钱包签名可被旧实现验证，反之亦然
+        let w = Wallet::new();
+        let signature = sign_message(&w.secret_key, b"test").unwrap();
+        assert!(ed25519::verify(b"test", &w.public_key, &signature));
+        let legacy = ed25519::signature(b"test", &w.secret_key);
+        assert!(verify_signature(&w.public_key, b"test", &legacy));
+
+        assert!(!verify_signature(&w.public_key, b"other", &signature));
+        assert!(!verify_signature(&w.public_key[..31], b"test", &signature));
+        assert!(!verify_signature(&w.public_key, b"test", &signature[..63]));
+        assert!(sign_message(&w.secret_key[..32], b"test").is_err());
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
     }
 
     #[test]
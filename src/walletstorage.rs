@@ -0,0 +1,390 @@
+//! wallet storage backends
+
+use super::*;
+use crate::keystore::EncryptedBlob;
+use crate::network::Network;
+use crate::wallets::{HdSeed, Wallet, WatchOnly};
+use bincode::{deserialize, serialize};
+use failure::format_err;
+use log::warn;
+use sled::transaction::ConflictableTransactionResult;
+use sled::Transactional;
+use std::collections::HashMap;
+use std::fs::{File, TryLockError};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// 只观察的地址保存在钱包数据库的独立树中，旧版本只读取默认树，不受影响
+const WATCH_ONLY_TREE: &str = "watch_only";
+/// 自有地址的标签和通讯录保存在独立的树中，不加密，旧钱包文件中没有这两棵树时视为空
+const LABELS_TREE: &str = "labels";
+const CONTACTS_TREE: &str = "contacts";
+/// 分层确定性钱包的种子保存在独立树中的 HD_SEED_KEY 键下，生成种子的助记词熵保存在 HD_MNEMONIC_KEY 键下
+const HD_TREE: &str = "hd";
+const HD_SEED_KEY: &str = "seed";
+const HD_MNEMONIC_KEY: &str = "mnemonic";
+/// 加密的钱包文件把全部私钥和种子加密后保存在独立树中，默认树和 hd 树留空
+const ENCRYPTED_TREE: &str = "encrypted";
+const ENCRYPTED_KEY: &str = "secrets";
+/// 钱包文件被其他进程锁定时的重试次数和间隔
+const WALLET_LOCK_RETRIES: u32 = 250;
+const WALLET_LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(20);
+
+/// StoredWallets 存储后端中保存的钱包集合
+///
+/// 加密的钱包集合只在 encrypted 中保存私钥和种子的密文，wallets、hd 和 mnemonic_entropy 为空
+#[derive(Default, Clone)]
+pub struct StoredWallets {
+    pub wallets: HashMap<String, Wallet>,
+    pub watch_only: HashMap<String, WatchOnly>,
+    pub labels: HashMap<String, String>,
+    pub contacts: HashMap<String, String>,
+    pub hd: Option<HdSeed>,
+    pub mnemonic_entropy: Option<Vec<u8>>,
+    pub encrypted: Option<EncryptedBlob>,
+}
+
+/// WalletStorage 钱包集合的存储后端
+pub trait WalletStorage: Send {
+    /// Lock 排他地锁定存储，其他持有者释放前等待，超时后返回错误；返回的守卫被丢弃时释放
+    fn lock(&self) -> Result<Box<dyn Send>>;
+
+    /// Load 读取存储中的全部内容，调用前需持有锁
+    fn load(&self) -> Result<StoredWallets>;
+
+    /// Save 把存储中的内容原子地替换为 wallets，调用前需持有锁
+    fn save(&self, wallets: &StoredWallets) -> Result<()>;
+
+    /// Rewrite 与 save 相同，但不在存储中留下被覆盖的旧数据，调用前需持有锁
+    fn rewrite(&self, wallets: &StoredWallets) -> Result<()>;
+}
+
+/// FileStorage 保存在 sled 数据库中的钱包文件
+pub struct FileStorage {
+    path: PathBuf,
+}
+
+impl FileStorage {
+    /// InDataDir 返回数据目录 data_dir 中 network 的钱包文件
+    pub fn in_data_dir(network: Network, data_dir: &Path) -> FileStorage {
+        FileStorage::new(network.data_path(data_dir, "wallets"))
+    }
+
+    /// New 返回 path 处的钱包文件，文件在第一次读写时创建
+    pub fn new(path: PathBuf) -> FileStorage {
+        FileStorage { path }
+    }
+
+    /// 重写时使用的新、旧数据库路径
+    fn rewrite_paths(&self) -> (PathBuf, PathBuf) {
+        (
+            self.path.with_extension("new"),
+            self.path.with_extension("old"),
+        )
+    }
+
+    /// Open 打开钱包数据库，调用前需持有锁
+    ///
+    /// 重写钱包文件时在旧文件移走之后、新文件移入之前中断，新文件已经完整写入，此时先把它移入
+    fn open(&self) -> Result<sled::Db> {
+        let (fresh, old) = self.rewrite_paths();
+        if !self.path.exists() && fresh.exists() {
+            warn!(
+                "finishing an interrupted rewrite of {}",
+                self.path.display()
+            );
+            std::fs::rename(&fresh, &self.path)?;
+            std::fs::remove_dir_all(&old).ok();
+        }
+        Network::open_db_at(&self.path)
+    }
+
+    fn read(db: &sled::Db) -> Result<StoredWallets> {
+        let hd_tree = db.open_tree(HD_TREE)?;
+        let hd = match hd_tree.get(HD_SEED_KEY)? {
+            Some(hd) => Some(deserialize(&hd)?),
+            None => None,
+        };
+        let encrypted = match db.open_tree(ENCRYPTED_TREE)?.get(ENCRYPTED_KEY)? {
+            Some(blob) => Some(deserialize(&blob)?),
+            None => None,
+        };
+        Ok(StoredWallets {
+            wallets: read_map(db, |value| Ok(deserialize(value)?))?,
+            watch_only: read_map(&db.open_tree(WATCH_ONLY_TREE)?, |value| {
+                Ok(deserialize(value)?)
+            })?,
+            labels: read_map(&db.open_tree(LABELS_TREE)?, |value| {
+                Ok(String::from_utf8(value.to_vec())?)
+            })?,
+            contacts: read_map(&db.open_tree(CONTACTS_TREE)?, |value| {
+                Ok(String::from_utf8(value.to_vec())?)
+            })?,
+            hd,
+            mnemonic_entropy: hd_tree
+                .get(HD_MNEMONIC_KEY)?
+                .map(|entropy| entropy.to_vec()),
+            encrypted,
+        })
+    }
+
+    /// Write 在一个事务中写入全部数据，中途崩溃不会留下只写了一部分的钱包文件
+    fn write(db: &sled::Db, wallets: &StoredWallets) -> Result<()> {
+        let default_tree: &sled::Tree = db;
+        let trees = [
+            default_tree.clone(),
+            db.open_tree(WATCH_ONLY_TREE)?,
+            db.open_tree(LABELS_TREE)?,
+            db.open_tree(CONTACTS_TREE)?,
+            db.open_tree(ENCRYPTED_TREE)?,
+            db.open_tree(HD_TREE)?,
+        ];
+        let mut hd = Vec::new();
+        if let Some(seed) = &wallets.hd {
+            hd.push((HD_SEED_KEY.as_bytes().to_vec(), serialize(seed)?));
+        }
+        if let Some(entropy) = &wallets.mnemonic_entropy {
+            hd.push((HD_MNEMONIC_KEY.as_bytes().to_vec(), entropy.clone()));
+        }
+        let mut encrypted = Vec::new();
+        if let Some(blob) = &wallets.encrypted {
+            encrypted.push((ENCRYPTED_KEY.as_bytes().to_vec(), serialize(blob)?));
+        }
+        let contents = [
+            encode_map(&wallets.wallets, |wallet| Ok(serialize(wallet)?))?,
+            encode_map(&wallets.watch_only, |entry| Ok(serialize(entry)?))?,
+            encode_map(&wallets.labels, |label| Ok(label.as_bytes().to_vec()))?,
+            encode_map(&wallets.contacts, |address| Ok(address.as_bytes().to_vec()))?,
+            encrypted,
+            hd,
+        ];
+
+        // 事务中不能遍历，先找出每棵树中需要删除的键
+        let mut changes = Vec::new();
+        for (tree, entries) in trees.iter().zip(contents) {
+            let mut stale = Vec::new();
+            for key in tree.iter().keys() {
+                let key = key?;
+                if !entries.iter().any(|(k, _)| k[..] == key[..]) {
+                    stale.push(key);
+                }
+            }
+            changes.push((entries, stale));
+        }
+        trees[..].transaction(|views| -> ConflictableTransactionResult<(), sled::Error> {
+            for (view, (entries, stale)) in views.iter().zip(&changes) {
+                for key in stale {
+                    view.remove(key)?;
+                }
+                for (key, value) in entries {
+                    view.insert(key.as_slice(), value.as_slice())?;
+                }
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+}
+
+impl WalletStorage for FileStorage {
+    /// Lock 锁定钱包数据库旁的 wallets.lock 文件，进程退出时由操作系统释放，不会残留
+    fn lock(&self) -> Result<Box<dyn Send>> {
+        let path = self.path.with_extension("lock");
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        let mut retries = 0;
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(Box::new(file)),
+                Err(TryLockError::WouldBlock) if retries < WALLET_LOCK_RETRIES => {
+                    retries += 1;
+                    thread::sleep(WALLET_LOCK_RETRY_INTERVAL);
+                }
+                Err(TryLockError::WouldBlock) => {
+                    return Err(format_err!(
+                        "Wallet is locked by another process ({})",
+                        path.display()
+                    ));
+                }
+                Err(TryLockError::Error(err)) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn load(&self) -> Result<StoredWallets> {
+        let db = self.open()?;
+        let wallets = FileStorage::read(&db)?;
+        drop(db);
+        Ok(wallets)
+    }
+
+    fn save(&self, wallets: &StoredWallets) -> Result<()> {
+        let db = self.open()?;
+        FileStorage::write(&db, wallets)?;
+        db.flush()?;
+        drop(db);
+        Ok(())
+    }
+
+    /// Rewrite 把钱包写入新数据库后替换旧的钱包文件
+    ///
+    /// sled 的日志会保留被覆盖的旧数据，加密或更换口令后原地写入会在磁盘上留下明文私钥或旧口令加密的数据
+    fn rewrite(&self, wallets: &StoredWallets) -> Result<()> {
+        let (fresh, old) = self.rewrite_paths();
+        std::fs::remove_dir_all(&fresh).ok();
+        let db = sled::open(&fresh)?;
+        FileStorage::write(&db, wallets)?;
+        db.flush()?;
+        drop(db);
+
+        std::fs::remove_dir_all(&old).ok();
+        if self.path.exists() {
+            std::fs::rename(&self.path, &old)?;
+        }
+        std::fs::rename(&fresh, &self.path)?;
+        std::fs::remove_dir_all(&old).ok();
+        Ok(())
+    }
+}
+
+/// ReadMap 读取以字符串为键的树
+fn read_map<T>(tree: &sled::Tree, decode: fn(&[u8]) -> Result<T>) -> Result<HashMap<String, T>> {
+    let mut map = HashMap::new();
+    for item in tree.iter() {
+        let (key, value) = item?;
+        map.insert(String::from_utf8(key.to_vec())?, decode(&value)?);
+    }
+    Ok(map)
+}
+
+/// EncodeMap 把以字符串为键的表编码为树中的键值对
+fn encode_map<T>(
+    map: &HashMap<String, T>,
+    encode: fn(&T) -> Result<Vec<u8>>,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut entries = Vec::new();
+    for (key, value) in map {
+        entries.push((key.as_bytes().to_vec(), encode(value)?));
+    }
+    Ok(entries)
+}
+
+/// 只在测试中使用的内存存储后端，测试之间互不影响，也不在磁盘上留下文件
+#[cfg(test)]
+pub mod memory {
+    use super::*;
+    use std::sync::{Arc, Condvar, Mutex};
+
+    /// MemoryStorage 只保存在内存中的钱包集合，克隆得到的句柄共享同一份内容
+    #[derive(Clone, Default)]
+    pub struct MemoryStorage {
+        wallets: Arc<Mutex<StoredWallets>>,
+        locked: Arc<(Mutex<bool>, Condvar)>,
+    }
+
+    /// 丢弃时释放 MemoryStorage 的锁
+    struct MemoryLock(Arc<(Mutex<bool>, Condvar)>);
+
+    impl Drop for MemoryLock {
+        fn drop(&mut self) {
+            let (locked, released) = &*self.0;
+            *locked.lock().unwrap() = false;
+            released.notify_one();
+        }
+    }
+
+    impl WalletStorage for MemoryStorage {
+        fn lock(&self) -> Result<Box<dyn Send>> {
+            let (locked, released) = &*self.locked;
+            let timeout = WALLET_LOCK_RETRY_INTERVAL * WALLET_LOCK_RETRIES;
+            let (mut locked, wait) = released
+                .wait_timeout_while(locked.lock().unwrap(), timeout, |locked| *locked)
+                .unwrap();
+            if wait.timed_out() {
+                return Err(format_err!("Wallet is locked by another holder"));
+            }
+            *locked = true;
+            Ok(Box::new(MemoryLock(self.locked.clone())))
+        }
+
+        fn load(&self) -> Result<StoredWallets> {
+            Ok(self.wallets.lock().unwrap().clone())
+        }
+
+        fn save(&self, wallets: &StoredWallets) -> Result<()> {
+            *self.wallets.lock().unwrap() = wallets.clone();
+            Ok(())
+        }
+
+        fn rewrite(&self, wallets: &StoredWallets) -> Result<()> {
+            self.save(wallets)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wallets::Wallets;
+    use memory::MemoryStorage;
+
+    #[test]
+    fn test_file_storage() {
+        let dir = std::env::temp_dir().join(format!("rustchain-storage-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let storage = FileStorage::new(dir.join("wallets"));
+        let mut ws = Wallets::in_memory(&MemoryStorage::default());
+        let own = ws.create_wallet();
+        let watched = ws.create_wallet();
+        let mut wallets = StoredWallets::default();
+        wallets
+            .wallets
+            .insert(own.clone(), ws.get_wallet(&own).unwrap().clone());
+        wallets.watch_only.insert(
+            watched.clone(),
+            WatchOnly {
+                label: Some(String::from("cold")),
+            },
+        );
+        wallets.labels.insert(own.clone(), String::from("savings"));
+        wallets
+            .contacts
+            .insert(String::from("alice"), watched.clone());
+
+        let _lock = storage.lock().unwrap();
+        storage.save(&wallets).unwrap();
+        let loaded = storage.load().unwrap();
+        assert_eq!(loaded.wallets, wallets.wallets);
+        assert_eq!(loaded.watch_only, wallets.watch_only);
+        assert_eq!(loaded.labels, wallets.labels);
+        assert_eq!(loaded.contacts, wallets.contacts);
+        assert!(loaded.hd.is_none() && loaded.encrypted.is_none());
+
+        // 旧版本只读取默认树，其中仍然全部是钱包
+        let db = storage.open().unwrap();
+        assert_eq!(db.len(), 1);
+        for item in db.iter() {
+            let (_, value) = item.unwrap();
+            deserialize::<Wallet>(&value).unwrap();
+        }
+        drop(db);
+
+        // 不在新内容中的键被删除，重写后不留下临时数据库
+        wallets.labels.clear();
+        storage.save(&wallets).unwrap();
+        assert!(storage.load().unwrap().labels.is_empty());
+        wallets.contacts.clear();
+        storage.rewrite(&wallets).unwrap();
+        assert!(storage.load().unwrap().contacts.is_empty());
+        let (fresh, old) = storage.rewrite_paths();
+        assert!(!fresh.exists() && !old.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,58 @@
+//! setban、listbanned 和 clearbanned 修改本机节点按 IP 地址的封禁列表，封禁在节点重启后仍然有效
+
+mod common;
+
+use common::*;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+fn banned(dir: &Path) -> Vec<String> {
+    let output = run_ok(dir, &["listbanned"]);
+    let bans: serde_json::Value = serde_json::from_str(&output).unwrap();
+    bans.as_array()
+        .unwrap()
+        .iter()
+        .map(|ban| ban["addr"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn test_ban_list_commands() {
+    let dir = temp_dir("ban");
+    let miner = run_ok(&dir, &["createwallet"]);
+    let miner = miner.trim().strip_prefix("address: ").unwrap();
+    run_ok(&dir, &["createblockchain", "--address", miner]);
+
+    let node = start_node(&dir, &[]);
+    thread::sleep(Duration::from_millis(500));
+    run_ok(&dir, &["setban", "192.0.2.10", "add"]);
+    run_ok(&dir, &["setban", "2001:db8::11", "add"]);
+    assert_eq!(banned(&dir), vec!["192.0.2.10", "2001:db8::11"]);
+    run_ok(&dir, &["setban", "192.0.2.10", "remove"]);
+    let output = run(&dir, &["setban", "192.0.2.10", "remove"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not banned"));
+    // 封禁的是 IP 地址，不接受主机名和端口
+    assert!(!run(&dir, &["setban", "localhost", "add"]).status.success());
+    assert!(
+        !run(&dir, &["setban", "192.0.2.12:24002", "add"])
+            .status
+            .success()
+    );
+    assert!(!run(&dir, &["setban", "192.0.2.12", "ban"]).status.success());
+    stop_node(&dir, &[], node);
+
+    assert!(dir.join("regtest").join("banlist.dat").is_file());
+    let node = start_node(&dir, &[]);
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(banned(&dir), vec!["2001:db8::11"]);
+    assert_eq!(
+        run_ok(&dir, &["clearbanned"]).trim(),
+        "unbanned 1 addresses"
+    );
+    assert!(banned(&dir).is_empty());
+    stop_node(&dir, &[], node);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
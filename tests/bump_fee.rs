@@ -0,0 +1,64 @@
+//! bumpfee 用手续费更高的交易替换节点交易池中钱包自己的交易
+
+mod common;
+
+use common::*;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// 从 "success! txid: ..." 中取出 txid
+fn txid(output: &str) -> String {
+    output
+        .trim()
+        .strip_prefix("success! txid: ")
+        .unwrap()
+        .to_string()
+}
+
+fn mempool_transactions(dir: &Path) -> String {
+    let info = run_ok(dir, &["getmempoolinfo"]);
+    info.lines()
+        .find_map(|line| line.strip_prefix("transactions: "))
+        .unwrap()
+        .to_string()
+}
+
+#[test]
+fn test_bump_fee() {
+    let node_dir = temp_dir("bumpfee-node");
+    let client = temp_dir("bumpfee-client");
+    let miner = create_wallet(&node_dir);
+    let receiver = create_wallet(&node_dir);
+    run_ok(&node_dir, &["createblockchain", "--address", &miner]);
+    copy_dir(&node_dir, &client);
+    let raw = run_ok(
+        &client,
+        &["send", &miner, &receiver, "2", "--fee", "1", "--raw"],
+    );
+
+    let mut node = start_node(&node_dir, &[]);
+    thread::sleep(Duration::from_millis(500));
+    let original = txid(&run_ok(&client, &["sendrawtransaction", raw.trim()]));
+    thread::sleep(Duration::from_millis(500));
+    let err = run_err(&client, &["bumpfee", &original, "1"]);
+    assert!(err.contains("must be higher"), "{}", err);
+
+    // 替换交易取代原交易，原交易不再在交易池中
+    let bumped = txid(&run_ok(&client, &["bumpfee", &original, "3"]));
+    assert_ne!(bumped, original);
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(mempool_transactions(&client), "1");
+    let err = run_err(&client, &["bumpfee", &original, "4"]);
+    assert!(err.contains("not in the mempool"), "{}", err);
+    run_ok(&client, &["bumpfee", &bumped, "4"]);
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(mempool_transactions(&client), "1");
+    let err = run_err(&client, &["bumpfee", &bumped, "5"]);
+    assert!(err.contains("not in the mempool"), "{}", err);
+
+    node.kill().ok();
+    node.wait().unwrap();
+    std::fs::remove_dir_all(&node_dir).unwrap();
+    std::fs::remove_dir_all(&client).unwrap();
+}
@@ -0,0 +1,90 @@
+//! 导出区块链后导入到新的数据目录，导入时逐个验证区块
+
+mod common;
+
+use common::*;
+
+/// 导出文件中第 height 个区块数据的起止位置
+fn block_range(data: &[u8], height: usize) -> (usize, usize) {
+    let word = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap()) as usize;
+    // 魔数、格式版本、网络名称长度和名称、区块数
+    let mut at = 8 + 4 + 1 + data[12] as usize + 4;
+    for _ in 0..height {
+        at += 4 + word(at);
+    }
+    (at + 4, at + 4 + word(at))
+}
+
+#[test]
+fn test_export_import_round_trip() {
+    let source = temp_dir("export");
+    let target = temp_dir("import");
+    let tampered = temp_dir("tampered");
+    let miner = create_wallet(&source);
+    let receiver = create_wallet(&source);
+    run_ok(
+        &source,
+        &[
+            "createblockchain",
+            "--address",
+            &miner,
+            "--message",
+            "export test",
+        ],
+    );
+    run_ok(&source, &["send", &miner, &receiver, "3", "-m"]);
+    run_ok(&source, &["send", &receiver, &miner, "2", "-m"]);
+
+    let file = source.join("chain.dat");
+    let file_arg = file.to_str().unwrap();
+    let exported = run_ok(&source, &["exportchain", file_arg]);
+    assert!(exported.contains("exported 3 blocks"), "{}", exported);
+
+    let imported = run_ok(&target, &["importchain", file_arg]);
+    assert!(imported.contains("imported 3 blocks"), "{}", imported);
+    let info = run_ok(&source, &["getblockchaininfo"]);
+    assert!(info.contains("genesis message: export test"), "{}", info);
+    assert_eq!(run_ok(&target, &["getblockchaininfo"]), info);
+    assert_eq!(
+        run_ok(&target, &["getutxocommitment"]),
+        run_ok(&source, &["getutxocommitment"])
+    );
+    run_ok(&target, &["verifyutxo"]);
+
+    // 已有区块链的数据目录不能再导入，其他网络不能导入
+    let stderr = run_err(&target, &["importchain", file_arg]);
+    assert!(stderr.contains("already exists"), "{}", stderr);
+    let stderr = run_err(
+        &tampered,
+        &["--network", "testnet", "importchain", file_arg],
+    );
+    assert!(
+        stderr.contains("belongs to regtest, not testnet"),
+        "{}",
+        stderr
+    );
+
+    // 篡改的区块在其高度上被拒绝，之前的区块保留
+    let mut data = std::fs::read(&file).unwrap();
+    let (start, end) = block_range(&data, 2);
+    data[(start + end) / 2] ^= 1;
+    let bad = tampered.join("bad.dat");
+    std::fs::create_dir_all(&tampered).unwrap();
+    std::fs::write(&bad, &data).unwrap();
+    let stderr = run_err(&tampered, &["importchain", bad.to_str().unwrap()]);
+    assert!(stderr.contains("Block at height 2"), "{}", stderr);
+    let info = run_ok(&tampered, &["getblockchaininfo"]);
+    assert!(info.contains("blocks: 1"), "{}", info);
+
+    // 截断的文件在缺失的区块上报错
+    let truncated = temp_dir("truncated");
+    let short = source.join("short.dat");
+    std::fs::write(&short, &data[..end - 1]).unwrap();
+    let stderr = run_err(&truncated, &["importchain", short.to_str().unwrap()]);
+    assert!(stderr.contains("Block at height 2"), "{}", stderr);
+    assert!(stderr.contains("truncated"), "{}", stderr);
+
+    for dir in [&source, &target, &tampered, &truncated] {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+//! 集成测试共用的辅助函数，在临时数据目录上运行节点程序的命令
+//!
+//! 每个测试文件只用到其中一部分
+
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+
+pub const BIN: &str = env!("CARGO_BIN_EXE_rust_camp_project_blockchain");
+
+/// TempDir 返回测试 name 专用的空临时目录，目录名带上进程号以免并行运行的测试冲突
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rustchain-{}-{}", name, std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    dir
+}
+
+/// Command 在数据目录 dir 上运行 args 的命令
+pub fn command(dir: &Path, args: &[&str]) -> Command {
+    let mut command = Command::new(BIN);
+    command
+        .arg("--datadir")
+        .arg(dir)
+        .args(args)
+        // 回归测试网的挖矿几乎不耗时
+        .env("RUSTCHAIN_NETWORK", "regtest")
+        .env("RUST_BACKTRACE", "0")
+        .stdin(Stdio::null());
+    command
+}
+
+/// Run 运行命令并等待它结束
+pub fn run(dir: &Path, args: &[&str]) -> Output {
+    command(dir, args).output().unwrap()
+}
+
+/// RunOk 运行命令，命令必须成功，返回它的标准输出
+pub fn run_ok(dir: &Path, args: &[&str]) -> String {
+    let output = run(dir, args);
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// RunErr 运行命令，命令必须失败，返回它的标准错误输出
+pub fn run_err(dir: &Path, args: &[&str]) -> String {
+    let output = run(dir, args);
+    assert!(!output.status.success(), "{:?} succeeded", args);
+    String::from_utf8(output.stderr).unwrap()
+}
+
+/// CreateWallet 在 dir 中创建钱包，返回新地址
+pub fn create_wallet(dir: &Path) -> String {
+    let output = run_ok(dir, &["createwallet"]);
+    output.trim().strip_prefix("address: ").unwrap().to_string()
+}
+
+/// CopyDir 把目录 from 递归复制到 to
+pub fn copy_dir(from: &Path, to: &Path) {
+    std::fs::create_dir_all(to).unwrap();
+    for entry in std::fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let target = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &target);
+        } else {
+            std::fs::copy(entry.path(), target).unwrap();
+        }
+    }
+}
+
+/// StartNode 在 dir 上启动节点，args 跟在 startnode 之后，不等待节点开始监听
+pub fn start_node(dir: &Path, args: &[&str]) -> Child {
+    command(dir, &["startnode"])
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap()
+}
+
+/// StopNode 用 stopnode 让节点正常退出，args 指定节点的地址，等待进程结束
+pub fn stop_node(dir: &Path, args: &[&str], mut node: Child) {
+    let mut stop = vec!["stopnode"];
+    stop.extend(args);
+    run_ok(dir, &stop);
+    assert!(node.wait().unwrap().success());
+}
+
+/// KillNode 强行结束节点进程
+pub fn kill_node(mut node: Child) {
+    node.kill().ok();
+    node.wait().unwrap();
+}
@@ -0,0 +1,133 @@
+//! 在两个独立的数据目录中并发运行两个节点
+
+mod common;
+
+use common::{BIN, temp_dir};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::thread;
+
+fn run(dir: &Path, use_env: bool, args: &[&str]) -> Output {
+    let mut command = Command::new(BIN);
+    if use_env {
+        command.env("RUSTCHAIN_DATADIR", dir);
+    } else {
+        command
+            .env_remove("RUSTCHAIN_DATADIR")
+            .arg("--datadir")
+            .arg(dir);
+    }
+    command
+        .args(args)
+        // 回归测试网的挖矿几乎不耗时
+        .env("RUSTCHAIN_NETWORK", "regtest")
+        .env("RUST_BACKTRACE", "0")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap()
+}
+
+fn run_ok(dir: &Path, use_env: bool, args: &[&str]) -> String {
+    let output = run(dir, use_env, args);
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn create_wallet(dir: &Path, use_env: bool) -> String {
+    let output = run_ok(dir, use_env, &["createwallet"]);
+    output.trim().strip_prefix("address: ").unwrap().to_string()
+}
+
+/// 创建钱包和区块链并转账一次，返回两个地址
+fn run_node(dir: PathBuf, use_env: bool) -> (PathBuf, String, String) {
+    let miner = create_wallet(&dir, use_env);
+    let receiver = create_wallet(&dir, use_env);
+    let create = [
+        "createblockchain",
+        "--address",
+        &miner,
+        "--message",
+        "datadir test",
+    ];
+    run_ok(&dir, use_env, &create);
+    run_ok(&dir, use_env, &["send", &miner, &receiver, "3", "-m"]);
+    assert_eq!(
+        run_ok(&dir, use_env, &["getbalance", &receiver]).trim(),
+        "Balance: 3"
+    );
+    (dir, miner, receiver)
+}
+
+#[test]
+fn test_independent_data_dirs() {
+    // 第一次运行时创建多级数据目录
+    let first = temp_dir("node1").join("nested");
+    let second = temp_dir("node2");
+    let node1 = thread::spawn(move || run_node(first, false));
+    let node2 = thread::spawn(move || run_node(second, true));
+    let (first, miner1, receiver1) = node1.join().unwrap();
+    let (second, miner2, receiver2) = node2.join().unwrap();
+
+    for dir in [&first, &second] {
+        for name in ["blocks", "utxos", "wallets"] {
+            assert!(dir.join("regtest").join(name).is_dir());
+            assert!(!dir.join(name).exists());
+        }
+    }
+
+    // 回归测试网的钱包和区块链不会出现在主网中
+    let mainnet = run_ok(&first, false, &["--network", "mainnet", "listaddresses"]);
+    assert!(!mainnet.contains(&miner1), "{}", mainnet);
+    let output = run(
+        &first,
+        false,
+        &["--network", "mainnet", "getblockchaininfo"],
+    );
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("No blockchain found"), "{}", stderr);
+    assert!(!first.join("blocks").exists());
+
+    // 已有区块链时不会重新创建
+    let info = run_ok(&first, false, &["getblockchaininfo"]);
+    assert!(info.contains("genesis message: datadir test"), "{}", info);
+    let output = run(&first, false, &["createblockchain", "--address", &miner1]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("already exists"), "{}", stderr);
+    assert_eq!(run_ok(&first, false, &["getblockchaininfo"]), info);
+
+    // 两个节点的钱包和余额互不影响
+    let addresses1 = run_ok(&first, false, &["listaddresses"]);
+    let addresses2 = run_ok(&second, false, &["listaddresses"]);
+    assert!(addresses1.contains(&miner1) && addresses1.contains(&receiver1));
+    assert!(!addresses1.contains(&miner2) && !addresses1.contains(&receiver2));
+    assert!(addresses2.contains(&miner2) && !addresses2.contains(&miner1));
+    assert_eq!(
+        run_ok(&first, false, &["getbalance", &receiver2]).trim(),
+        "Balance: 0"
+    );
+
+    std::fs::remove_dir_all(first.parent().unwrap()).unwrap();
+    std::fs::remove_dir_all(&second).unwrap();
+}
+
+#[test]
+fn test_unusable_data_dir() {
+    let dir = temp_dir("file");
+    std::fs::write(&dir, b"").unwrap();
+    let output = run(&dir.join("data"), false, &["listaddresses"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Cannot create data directory"),
+        "{}",
+        stderr
+    );
+    std::fs::remove_file(&dir).unwrap();
+}
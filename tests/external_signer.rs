@@ -0,0 +1,59 @@
+//! 用另一个数据目录中的钱包作为外部签名者签名交易
+
+mod common;
+
+use common::*;
+
+#[test]
+fn test_sign_with_external_wallet() {
+    let hot = temp_dir("signer-hot");
+    let cold = temp_dir("signer-cold");
+    let miner = create_wallet(&hot);
+    let cold_address = create_wallet(&cold);
+    run_ok(&hot, &["create", &miner]);
+    run_ok(&hot, &["send", &miner, &cold_address, "5", "-m"]);
+
+    let pubkey = run_ok(&cold, &["signer", &cold_address, "pubkey"]);
+    let bundle = hot.join("unsigned.bin");
+    let bundle = bundle.to_str().unwrap();
+    run_ok(&hot, &["buildunsigned", pubkey.trim(), &miner, "2", bundle]);
+
+    // 热钱包中没有冷钱包的私钥
+    assert!(
+        !run(&hot, &["signbundle", bundle, &cold_address])
+            .status
+            .success()
+    );
+
+    let signer = format!(
+        "{} --datadir {} signer {}",
+        BIN,
+        cold.display(),
+        cold_address
+    );
+    let raw = run_ok(
+        &hot,
+        &["signbundle", bundle, &cold_address, "--signer", &signer],
+    );
+    let decoded = run_ok(&hot, &["decoderawtransaction", raw.trim()]);
+    assert!(decoded.contains(&miner), "{}", decoded);
+
+    // 外部签名者持有的不是该地址的私钥
+    let output = run(&hot, &["signbundle", bundle, &miner, "--signer", &signer]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("does not hold the key"), "{}", stderr);
+
+    // 外部签名者拒绝签名
+    let refusing = format!("{} --datadir {} signer {}", BIN, cold.display(), miner);
+    let output = run(
+        &hot,
+        &["signbundle", bundle, &cold_address, "--signer", &refusing],
+    );
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("External signer refused"), "{}", stderr);
+
+    std::fs::remove_dir_all(&hot).unwrap();
+    std::fs::remove_dir_all(&cold).unwrap();
+}
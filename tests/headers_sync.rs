@@ -0,0 +1,67 @@
+//! 新节点先从已知节点下载并验证全部区块头，再按最好的区块头下载区块
+
+mod common;
+
+use common::*;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+const BLOCKS: usize = 200;
+
+fn info(dir: &Path, key: &str) -> String {
+    let info = run_ok(dir, &["getblockchaininfo"]);
+    info.lines()
+        .find_map(|line| line.strip_prefix(key))
+        .unwrap()
+        .to_string()
+}
+
+#[test]
+fn test_headers_first_sync() {
+    let source = temp_dir("headers-source");
+    let fresh = temp_dir("headers-fresh");
+    let miner = create_wallet(&source);
+    let receiver = create_wallet(&source);
+    run_ok(&source, &["createblockchain", "--address", &miner]);
+    copy_dir(&source, &fresh);
+
+    // 两个地址轮流付款并挖矿，每次挖出一个区块
+    for n in 0..BLOCKS {
+        let (from, to) = if n % 2 == 0 {
+            (&miner, &receiver)
+        } else {
+            (&receiver, &miner)
+        };
+        run_ok(&source, &["send", from, to, "3", "-m", "--reuse-address"]);
+    }
+    assert_eq!(info(&source, "blocks: "), BLOCKS.to_string());
+    let best = info(&source, "best block: ");
+
+    // 已有节点监听回归测试网的默认端口，新节点启动后向它报告自己只有创世区块。
+    // 节点被强行停止时UTXO集合最近的修改可能还没写入磁盘，等待时间留有余量
+    let mut synced = false;
+    for attempt in 1..=4 {
+        let node1 = start_node(&source, &[]);
+        thread::sleep(Duration::from_millis(500));
+        let node2 = start_node(&fresh, &["23001"]);
+        thread::sleep(Duration::from_secs(10 * attempt));
+        kill_node(node2);
+        kill_node(node1);
+        if info(&fresh, "best block: ") == best {
+            synced = true;
+            break;
+        }
+    }
+    assert!(synced, "the fresh node did not download the chain");
+
+    assert_eq!(info(&fresh, "headers: "), BLOCKS.to_string());
+    run_ok(&fresh, &["verifychain"]);
+    run_ok(&fresh, &["verifyutxo"]);
+    assert_eq!(
+        run_ok(&fresh, &["getutxocommitment"]),
+        run_ok(&source, &["getutxocommitment"])
+    );
+    std::fs::remove_dir_all(&source).unwrap();
+    std::fs::remove_dir_all(&fresh).unwrap();
+}
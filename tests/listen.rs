@@ -0,0 +1,107 @@
+//! 两个节点在本机的不同端口上监听，只连接指定节点的一方从另一方同步区块
+
+mod common;
+
+use common::*;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+const BLOCKS: usize = 5;
+const SOURCE: &str = "127.0.0.1:23010";
+const FRESH: &str = "127.0.0.1:23011";
+
+fn best_block(dir: &Path) -> String {
+    let info = run_ok(dir, &["getblockchaininfo"]);
+    info.lines()
+        .find_map(|line| line.strip_prefix("best block: "))
+        .unwrap()
+        .to_string()
+}
+
+/// --bind 和 --port 参数，其他命令用它们连接 addr 上的节点
+fn node_args(addr: &str) -> [&str; 4] {
+    let (host, port) = addr.split_once(':').unwrap();
+    ["--bind", host, "--port", port]
+}
+
+/// 等待 addr 上的节点与 peer 完成握手
+fn wait_for_peer(dir: &Path, addr: &str, peer: &str) -> bool {
+    let mut args = vec!["getpeerinfo"];
+    args.extend(node_args(addr));
+    for _ in 0..40 {
+        let output = command(dir, &args).output().unwrap();
+        if String::from_utf8_lossy(&output.stdout).contains(peer) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+    false
+}
+
+#[test]
+fn test_two_nodes_on_distinct_ports_sync() {
+    let source = temp_dir("listen-source");
+    let fresh = temp_dir("listen-fresh");
+    let other = temp_dir("listen-other");
+    let miner = create_wallet(&source);
+    let receiver = create_wallet(&source);
+    run_ok(&source, &["createblockchain", "--address", &miner]);
+    copy_dir(&source, &fresh);
+    copy_dir(&source, &other);
+    // 两个地址轮流付款并挖矿，每次挖出一个区块
+    for n in 0..BLOCKS {
+        let (from, to) = if n % 2 == 0 {
+            (&miner, &receiver)
+        } else {
+            (&receiver, &miner)
+        };
+        run_ok(&source, &["send", from, to, "3", "-m", "--reuse-address"]);
+    }
+    let best = best_block(&source);
+
+    let mut synced = false;
+    for attempt in 1..=3 {
+        let node1 = start_node(&source, &node_args(SOURCE));
+        thread::sleep(Duration::from_millis(500));
+        let node2 = start_node(
+            &fresh,
+            &[&node_args(FRESH)[..], &["--connect", SOURCE]].concat(),
+        );
+        assert!(
+            wait_for_peer(&fresh, FRESH, SOURCE),
+            "no handshake with {}",
+            SOURCE
+        );
+        assert!(
+            wait_for_peer(&source, SOURCE, FRESH),
+            "no handshake with {}",
+            FRESH
+        );
+
+        if attempt == 1 {
+            // 端口已被占用时报告原因并退出
+            let output = command(&other, &["startnode"])
+                .args(node_args(SOURCE))
+                .output()
+                .unwrap();
+            assert!(!output.status.success());
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert!(stderr.contains("already in use"), "{}", stderr);
+            assert!(!stderr.contains("panicked"), "{}", stderr);
+        }
+
+        thread::sleep(Duration::from_secs(3 * attempt));
+        stop_node(&fresh, &node_args(FRESH), node2);
+        stop_node(&source, &node_args(SOURCE), node1);
+        if best_block(&fresh) == best {
+            synced = true;
+            break;
+        }
+    }
+    assert!(synced, "the node on {} did not download the chain", FRESH);
+
+    std::fs::remove_dir_all(&source).unwrap();
+    std::fs::remove_dir_all(&fresh).unwrap();
+    std::fs::remove_dir_all(&other).unwrap();
+}
@@ -0,0 +1,56 @@
+//! getmempoolinfo 向本机运行的节点查询交易池概况
+
+mod common;
+
+use common::*;
+use std::path::Path;
+use std::process::{Child, Stdio};
+use std::thread;
+use std::time::Duration;
+
+fn mempool_info(dir: &Path, key: &str) -> String {
+    let info = run_ok(dir, &["getmempoolinfo"]);
+    info.lines()
+        .find_map(|line| line.strip_prefix(key))
+        .unwrap()
+        .to_string()
+}
+
+#[test]
+fn test_get_mempool_info() {
+    let node_dir = temp_dir("mempool-node");
+    let client = temp_dir("mempool-client");
+    let miner = create_wallet(&node_dir);
+    let receiver = create_wallet(&node_dir);
+    run_ok(&node_dir, &["createblockchain", "--address", &miner]);
+    // 节点占用自己的数据目录，交易从一份副本发出
+    copy_dir(&node_dir, &client);
+    let raw = run_ok(
+        &client,
+        &["send", &miner, &receiver, "3", "--fee", "1", "--raw"],
+    );
+
+    // 没有挖矿地址的节点把交易留在交易池中
+    let mut node: Child = command(&node_dir, &["startnode", "--maxmempool", "1"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(mempool_info(&client, "transactions: "), "0");
+    assert_eq!(mempool_info(&client, "max size: "), "1000000 bytes");
+
+    run_ok(&client, &["sendrawtransaction", raw.trim()]);
+    thread::sleep(Duration::from_millis(500));
+    let transactions = mempool_info(&client, "transactions: ");
+    let size = mempool_info(&client, "size: ");
+    let min_fee_rate = mempool_info(&client, "min fee rate: ");
+    node.kill().ok();
+    node.wait().unwrap();
+
+    assert_eq!(transactions, "1");
+    assert_ne!(size, "0 bytes");
+    assert_eq!(min_fee_rate, "0.000 per byte");
+    std::fs::remove_dir_all(&node_dir).unwrap();
+    std::fs::remove_dir_all(&client).unwrap();
+}
@@ -0,0 +1,65 @@
+//! 节点正常退出时保存交易池，重启后恢复其中仍然有效的交易
+
+mod common;
+
+use common::*;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+fn mempool_info(dir: &Path, key: &str) -> String {
+    let info = run_ok(dir, &["getmempoolinfo"]);
+    info.lines()
+        .find_map(|line| line.strip_prefix(key))
+        .unwrap()
+        .to_string()
+}
+
+#[test]
+fn test_mempool_survives_restart() {
+    let node_dir = temp_dir("persist-node");
+    let client = temp_dir("persist-client");
+    let miner = create_wallet(&node_dir);
+    let receiver = create_wallet(&node_dir);
+    run_ok(&node_dir, &["createblockchain", "--address", &miner]);
+    // 两个地址各有一个已确认的输出
+    run_ok(
+        &node_dir,
+        &["send", &miner, &receiver, "5", "-m", "--reuse-address"],
+    );
+    copy_dir(&node_dir, &client);
+    let payment = run_ok(
+        &client,
+        &["send", &miner, &receiver, "2", "--fee", "1", "--raw"],
+    );
+    let refund = run_ok(
+        &client,
+        &["send", &receiver, &miner, "2", "--fee", "1", "--raw"],
+    );
+
+    let node = start_node(&node_dir, &[]);
+    thread::sleep(Duration::from_millis(500));
+    run_ok(&client, &["sendrawtransaction", payment.trim()]);
+    run_ok(&client, &["sendrawtransaction", refund.trim()]);
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(mempool_info(&client, "transactions: "), "2");
+    stop_node(&client, &[], node);
+
+    // 节点停止期间挖出花费同一输出的交易，重启后只有仍然有效的交易回到交易池
+    let network_dir = node_dir.join("regtest");
+    assert!(network_dir.join("mempool.dat").is_file());
+    // 地址库也在停止时保存
+    assert!(network_dir.join("peers.dat").is_file());
+    run_ok(
+        &node_dir,
+        &["send", &receiver, &miner, "3", "-m", "--reuse-address"],
+    );
+    let node = start_node(&node_dir, &[]);
+    thread::sleep(Duration::from_millis(500));
+    let transactions = mempool_info(&client, "transactions: ");
+    stop_node(&client, &[], node);
+    assert_eq!(transactions, "1");
+
+    std::fs::remove_dir_all(&node_dir).unwrap();
+    std::fs::remove_dir_all(&client).unwrap();
+}
@@ -0,0 +1,150 @@
+//! 新节点同时从三个节点下载区块，每个节点送来一部分，一次启动就同步到与它们相同的链；
+//! 节点回复每个区块前都等待同样的时间，同时从三个节点下载比只从一个节点下载快得多
+
+mod common;
+
+use common::*;
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const BLOCKS: usize = 500;
+const SERVING: [&str; 3] = ["127.0.0.1:23020", "127.0.0.1:23021", "127.0.0.1:23022"];
+/// 从三个节点和只从一个节点下载的新节点
+const FRESH: &str = "127.0.0.1:23023";
+const SINGLE: &str = "127.0.0.1:23024";
+/// 等待新节点同步到最新区块的最长时间
+const SYNC_TIMEOUT: Duration = Duration::from_secs(300);
+/// 提供区块的节点发送每个区块前等待的毫秒数；本机的节点之间几乎没有延迟，
+/// 没有这个等待时连接区块占去大部分时间，节点数不影响用时
+const BLOCK_DELAY: &str = "1000";
+
+fn best_block(dir: &Path) -> String {
+    let info = run_ok(dir, &["getblockchaininfo"]);
+    info.lines()
+        .find_map(|line| line.strip_prefix("best block: "))
+        .unwrap()
+        .to_string()
+}
+
+/// --bind 和 --port 参数，其他命令用它们连接 addr 上的节点
+fn node_args(addr: &str) -> [&str; 4] {
+    let (host, port) = addr.split_once(':').unwrap();
+    ["--bind", host, "--port", port]
+}
+
+/// addr 上的节点从 peers 中每个节点收到的区块数，节点没有回复或还没有完成握手时为 None
+fn blocks_received(dir: &Path, addr: &str, peers: &[&str]) -> Option<Vec<u64>> {
+    let mut args = vec!["getpeerinfo"];
+    args.extend(node_args(addr));
+    let output = command(dir, &args).output().unwrap();
+    let info: serde_json::Value = serde_json::from_slice(&output.stdout).ok()?;
+    peers
+        .iter()
+        .map(|peer| {
+            info.as_array()?.iter().find(|info| info["addr"] == *peer)?["blocks_received"].as_u64()
+        })
+        .collect()
+}
+
+/// addr 上的节点的最新区块高度，节点没有回复时为 None
+fn block_count(dir: &Path, addr: &str) -> Option<usize> {
+    let mut args = vec!["getblockcount"];
+    args.extend(node_args(addr));
+    let output = command(dir, &args).output().unwrap();
+    String::from_utf8(output.stdout).ok()?.trim().parse().ok()
+}
+
+/// 启动 dir 中只有创世区块、只连接 peers 的节点，等它同步到 BLOCKS 的高度后停止
+///
+/// 返回从启动到同步完成的时间和从每个节点收到的区块数，SYNC_TIMEOUT 内没有同步完时返回 None
+fn sync(dir: &Path, addr: &str, peers: &[&str]) -> Option<(Duration, Vec<u64>)> {
+    let mut args = node_args(addr).to_vec();
+    for peer in peers {
+        args.extend(["--connect", peer]);
+    }
+    let start = Instant::now();
+    let node = start_node(dir, &args);
+    let mut synced = None;
+    while start.elapsed() < SYNC_TIMEOUT {
+        thread::sleep(Duration::from_millis(200));
+        if block_count(dir, addr) == Some(BLOCKS) {
+            let elapsed = start.elapsed();
+            synced = blocks_received(dir, addr, peers).map(|received| (elapsed, received));
+            break;
+        }
+    }
+    stop_node(dir, &node_args(addr), node);
+    synced
+}
+
+#[test]
+fn test_download_from_three_peers() {
+    let source = temp_dir("parallel-source");
+    let fresh = temp_dir("parallel-fresh");
+    let single = temp_dir("parallel-single");
+    let miner = create_wallet(&source);
+    let receiver = create_wallet(&source);
+    run_ok(&source, &["createblockchain", "--address", &miner]);
+    copy_dir(&source, &fresh);
+    copy_dir(&source, &single);
+
+    // 两个地址轮流付款并挖矿，每次挖出一个区块
+    for n in 0..BLOCKS {
+        let (from, to) = if n % 2 == 0 {
+            (&miner, &receiver)
+        } else {
+            (&receiver, &miner)
+        };
+        run_ok(&source, &["send", from, to, "3", "-m", "--reuse-address"]);
+    }
+    let best = best_block(&source);
+    let serving: Vec<PathBuf> = (0..SERVING.len())
+        .map(|n| {
+            let dir = temp_dir(&format!("parallel-serving{}", n));
+            copy_dir(&source, &dir);
+            dir
+        })
+        .collect();
+
+    let nodes: Vec<Child> = serving
+        .iter()
+        .zip(SERVING)
+        .map(|(dir, addr)| {
+            let mut args = node_args(addr).to_vec();
+            args.extend(["--blockdelay", BLOCK_DELAY]);
+            start_node(dir, &args)
+        })
+        .collect();
+    thread::sleep(Duration::from_millis(500));
+    // 先只从一个节点下载同样的区块，再同时从三个节点下载，每个新节点都只启动一次
+    let one = sync(&single, SINGLE, &SERVING[..1]);
+    let three = sync(&fresh, FRESH, &SERVING);
+    for ((dir, addr), node) in serving.iter().zip(SERVING).zip(nodes) {
+        stop_node(dir, &node_args(addr), node);
+    }
+    let (one, _) = one.expect("the node with one peer did not download the chain");
+    let (three, received) = three.expect("the node with three peers did not download the chain");
+    eprintln!(
+        "synced {} blocks from one peer in {:.1?}, from three peers in {:.1?}",
+        BLOCKS, one, three
+    );
+    // 每个节点同时请求的区块数相同，三个节点的下载量是一个节点的三倍
+    assert!(three * 2 < one, "{:.1?} from three peers, {:.1?} from one", three, one);
+    assert!(received.iter().all(|count| *count > 0), "{:?}", received);
+    for dir in [&fresh, &single] {
+        assert_eq!(best_block(dir), best);
+        run_ok(dir, &["verifychain"]);
+        assert_eq!(
+            run_ok(dir, &["getutxocommitment"]),
+            run_ok(&source, &["getutxocommitment"])
+        );
+    }
+    std::fs::remove_dir_all(&source).unwrap();
+    std::fs::remove_dir_all(&fresh).unwrap();
+    std::fs::remove_dir_all(&single).unwrap();
+    for dir in serving {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
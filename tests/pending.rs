@@ -0,0 +1,64 @@
+//! listpending、getrawmempool 和 getmempoolentry 报告本机节点交易池中的交易，本机钱包的交易重启后仍然标记
+
+mod common;
+
+use common::*;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn test_list_pending() {
+    let node_dir = temp_dir("pending-node");
+    let client = temp_dir("pending-client");
+    let miner = create_wallet(&node_dir);
+    let receiver = create_wallet(&node_dir);
+    run_ok(&node_dir, &["createblockchain", "--address", &miner]);
+    copy_dir(&node_dir, &client);
+    let raw = run_ok(
+        &client,
+        &["send", &miner, &receiver, "2", "--fee", "1", "--raw"],
+    );
+
+    let node = start_node(&node_dir, &[]);
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(run_ok(&client, &["listpending"]), "");
+    let output = run_ok(&client, &["sendrawtransaction", raw.trim()]);
+    let txid = output
+        .trim()
+        .strip_prefix("success! txid: ")
+        .unwrap()
+        .to_string();
+    // 节点拒绝的交易报告原因
+    let err = run_err(&client, &["sendrawtransaction", raw.trim()]);
+    assert!(err.contains("already in the mempool"), "{}", err);
+    let pending = run_ok(&client, &["listpending"]);
+    let txids = run_ok(&client, &["getrawmempool"]);
+    let verbose = run_ok(&client, &["getrawmempool", "--verbose"]);
+    let entry = run_ok(&client, &["getmempoolentry", &txid]);
+    let missing = run_err(&client, &["getmempoolentry", "0000"]);
+    stop_node(&client, &[], node);
+    let txids: Vec<String> = serde_json::from_str(&txids).unwrap();
+    assert_eq!(txids, vec![txid.clone()]);
+    let verbose: serde_json::Value = serde_json::from_str(&verbose).unwrap();
+    let entry: serde_json::Value = serde_json::from_str(&entry).unwrap();
+    assert_eq!(verbose[0], entry);
+    assert_eq!(entry["txid"], txid.as_str());
+    assert_eq!(entry["fee"], 1);
+    assert_eq!(entry["depends"], serde_json::json!([]));
+    assert!(missing.contains("not in the mempool"), "{}", missing);
+    let line = format!("{} age: ", txid);
+    assert!(pending.starts_with(&line), "{}", pending);
+    assert!(pending.contains(" fee: 1 size: "), "{}", pending);
+    assert!(pending.trim_end().ends_with(" wallet"), "{}", pending);
+
+    // 重启后仍然是本机钱包的交易
+    let node = start_node(&node_dir, &[]);
+    thread::sleep(Duration::from_millis(500));
+    let pending = run_ok(&client, &["listpending"]);
+    stop_node(&client, &[], node);
+    assert_eq!(pending.lines().count(), 1);
+    assert!(pending.trim_end().ends_with(" wallet"), "{}", pending);
+
+    std::fs::remove_dir_all(&node_dir).unwrap();
+    std::fs::remove_dir_all(&client).unwrap();
+}
@@ -0,0 +1,68 @@
+//! 两个从同一创世区块分叉的节点相连后，较短分支上的节点重组到工作量更大的分支
+
+mod common;
+
+use common::*;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+fn best_block(dir: &Path) -> String {
+    let info = run_ok(dir, &["getblockchaininfo"]);
+    info.lines()
+        .find_map(|line| line.strip_prefix("best block: "))
+        .unwrap()
+        .to_string()
+}
+
+fn balance(dir: &Path, address: &str) -> String {
+    run_ok(dir, &["getbalance", address]).trim().to_string()
+}
+
+#[test]
+fn test_reorganize_to_longer_branch() {
+    let first = temp_dir("reorg1");
+    let second = temp_dir("reorg2");
+    let miner = create_wallet(&first);
+    let receiver1 = create_wallet(&first);
+    let receiver2 = create_wallet(&first);
+    run_ok(&first, &["create", &miner]);
+    copy_dir(&first, &second);
+
+    // 第一个节点在创世区块之后挖出一个区块，第二个节点挖出两个
+    run_ok(&first, &["send", &miner, &receiver1, "3", "-m"]);
+    for _ in 0..2 {
+        run_ok(
+            &second,
+            &["send", &miner, &receiver2, "3", "-m", "--reuse-address"],
+        );
+    }
+    assert_eq!(balance(&first, &receiver1), "Balance: 3");
+    let winner = best_block(&second);
+
+    // 第一个节点是回归测试网默认端口上的已知节点，第二个节点启动后向它报告更高的高度
+    let mut synced = false;
+    for attempt in 1..=5 {
+        let node1 = start_node(&first, &[]);
+        thread::sleep(Duration::from_millis(500));
+        let node2 = start_node(&second, &["23001"]);
+        thread::sleep(Duration::from_secs(2 * attempt));
+        kill_node(node2);
+        kill_node(node1);
+        if best_block(&first) == winner {
+            synced = true;
+            break;
+        }
+    }
+    assert!(synced, "the first node did not switch to the longer branch");
+
+    assert_eq!(balance(&first, &receiver1), "Balance: 0");
+    assert_eq!(balance(&first, &receiver2), "Balance: 6");
+    run_ok(&first, &["verifyutxo"]);
+    assert_eq!(
+        run_ok(&first, &["getutxocommitment"]),
+        run_ok(&second, &["getutxocommitment"])
+    );
+    std::fs::remove_dir_all(&first).unwrap();
+    std::fs::remove_dir_all(&second).unwrap();
+}
@@ -0,0 +1,65 @@
+//! 交易索引建立后 gettransaction 的结果与扫描主链时相同，并随新区块更新
+
+mod common;
+
+use common::*;
+use std::path::Path;
+use std::process::Command;
+
+/// 主链上高度为 height 的区块中的全部交易 id
+fn block_txids(dir: &Path, height: i32) -> Vec<String> {
+    let output = run_ok(dir, &["getblock", "--height", &height.to_string()]);
+    let (_, txids) = output.split_once("transactions:\n").unwrap();
+    txids.lines().map(|line| line.trim().to_string()).collect()
+}
+
+fn field<'a>(output: &'a str, name: &str) -> &'a str {
+    output
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+        .unwrap_or_else(|| panic!("no {} in {}", name, output))
+}
+
+#[test]
+fn test_get_transaction() {
+    let dir = temp_dir("txindex");
+    let miner = create_wallet(&dir);
+    let receiver = create_wallet(&dir);
+    run_ok(&dir, &["createblockchain", "--address", &miner]);
+    run_ok(&dir, &["send", &miner, &receiver, "3", "-m"]);
+    let txids = block_txids(&dir, 1);
+    assert_eq!(txids.len(), 2);
+
+    let scanned: Vec<String> = txids
+        .iter()
+        .map(|txid| run_ok(&dir, &["gettransaction", txid]))
+        .collect();
+    assert_eq!(field(&scanned[0], "height"), "1");
+    assert_eq!(field(&scanned[0], "confirmations"), "1");
+
+    let output = run_ok(&dir, &["reindex-tx"]);
+    assert!(output.contains("Indexed 3 transactions"), "{}", output);
+    for (txid, scanned) in txids.iter().zip(&scanned) {
+        assert_eq!(&run_ok(&dir, &["gettransaction", txid]), scanned);
+    }
+
+    // 新区块连接时加入索引，旧交易的确认数增加
+    run_ok(&dir, &["send", &receiver, &miner, "2", "-m"]);
+    let output = run_ok(&dir, &["gettransaction", &txids[0]]);
+    assert_eq!(field(&output, "confirmations"), "2");
+    let txid = &block_txids(&dir, 2)[0];
+    let output = run_ok(&dir, &["gettransaction", txid]);
+    assert_eq!(field(&output, "txid"), txid);
+    assert_eq!(field(&output, "height"), "2");
+
+    let output = Command::new(BIN)
+        .arg("--datadir")
+        .arg(&dir)
+        .args(["gettransaction", "missing"])
+        .env("RUSTCHAIN_NETWORK", "regtest")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not on the main chain"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
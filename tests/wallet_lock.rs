@@ -0,0 +1,54 @@
+//! 多个进程同时读写同一个钱包文件
+
+mod common;
+
+use common::*;
+use std::fs::File;
+use std::thread;
+
+#[test]
+fn test_concurrent_processes() {
+    let dir = temp_dir("wallet-lock");
+    let workers: Vec<_> = (0..2)
+        .map(|_| {
+            let dir = dir.clone();
+            thread::spawn(move || (0..5).map(|_| create_wallet(&dir)).collect::<Vec<_>>())
+        })
+        .collect();
+    let created: Vec<String> = workers
+        .into_iter()
+        .flat_map(|worker| worker.join().unwrap())
+        .collect();
+
+    let output = run(&dir, &["listaddresses"]);
+    assert!(output.status.success());
+    let addresses = String::from_utf8(output.stdout).unwrap();
+    for address in &created {
+        assert!(addresses.contains(address.as_str()), "{} lost", address);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_locked_by_another_process() {
+    let dir = temp_dir("wallet-locked");
+    create_wallet(&dir);
+
+    let lock = File::options()
+        .write(true)
+        .open(dir.join("regtest").join("wallets.lock"))
+        .unwrap();
+    lock.lock().unwrap();
+    let output = run(&dir, &["createwallet"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Wallet is locked by another process"),
+        "{}",
+        stderr
+    );
+
+    lock.unlock().unwrap();
+    create_wallet(&dir);
+    std::fs::remove_dir_all(&dir).unwrap();
+}